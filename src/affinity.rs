@@ -0,0 +1,109 @@
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+// How the backend is placed on the nodes of a multi-socket machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Numa {
+    // memory interleaved over all the nodes, for models larger than one node's memory
+    Distribute,
+    // threads and memory kept on one node, no traffic between the sockets
+    Isolate,
+}
+impl std::fmt::Display for Numa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Numa::Distribute => f.pad("distribute"),
+            Numa::Isolate => f.pad("isolate"),
+        }
+    }
+}
+
+// Check a CPU list as taskset and numactl take it, e.g. 0-15,32-47
+pub fn parse_cpus(arg: &str) -> anyhow::Result<String> {
+    let cpu = |s: &str| {
+        s.parse::<u32>()
+            .map_err(|_| anyhow!("'{}' is not a CPU number in '{}'", s, arg))
+    };
+    for part in arg.split(',') {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                if cpu(from)? > cpu(to)? {
+                    bail!("'{}' ends before it starts", part);
+                }
+            }
+            None => {
+                cpu(part)?;
+            }
+        }
+    }
+
+    Ok(arg.to_string())
+}
+
+// The program and arguments launching the backend under numactl or taskset, when it is to be
+// placed, otherwise as they are
+pub fn wrap(
+    numa: Option<Numa>,
+    cpus: Option<&str>,
+    program: &Path,
+    args: Vec<String>,
+) -> anyhow::Result<(PathBuf, Vec<String>)> {
+    if numa.is_none() && cpus.is_none() {
+        return Ok((program.to_path_buf(), args));
+    }
+    if !cfg!(target_os = "linux") {
+        bail!("--numa and --cpu-affinity are only supported on Linux");
+    }
+
+    let mut placement = Vec::new();
+    let wrapper = match numa {
+        Some(numa) => {
+            match (numa, cpus) {
+                (Numa::Distribute, _) => placement.push("--interleave=all".to_string()),
+                // the memory follows the CPUs given
+                (Numa::Isolate, Some(_)) => placement.push("--localalloc".to_string()),
+                (Numa::Isolate, None) => {
+                    placement.extend(["--cpunodebind=0".to_string(), "--membind=0".to_string()])
+                }
+            }
+            if let Some(cpus) = cpus {
+                placement.push(format!("--physcpubind={}", cpus));
+            }
+            find("numactl").ok_or(anyhow!(
+                "--numa needs numactl, install it with your package manager, e.g. apt install numactl"
+            ))?
+        }
+        None => {
+            placement.extend(["-c".to_string(), cpus.unwrap_or_default().to_string()]);
+            find("taskset").ok_or(anyhow!(
+                "--cpu-affinity needs taskset, install it with your package manager, e.g. apt install util-linux"
+            ))?
+        }
+    };
+    placement.push(program.display().to_string());
+    placement.extend(args);
+
+    Ok((wrapper, placement))
+}
+
+// e.g. `numactl --interleave=all`, for the preflight summary
+pub fn describe(numa: Option<Numa>, cpus: Option<&str>) -> Option<String> {
+    match (numa, cpus) {
+        (None, None) => None,
+        (Some(numa), None) => Some(format!("numa {}", numa)),
+        (None, Some(cpus)) => Some(format!("cpus {}", cpus)),
+        (Some(numa), Some(cpus)) => Some(format!("numa {}, cpus {}", numa, cpus)),
+    }
+}
+
+fn find(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
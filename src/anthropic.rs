@@ -0,0 +1,401 @@
+use crate::gateway::{self, Gateway, Request, Unrouted};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Whether the request is one of the Anthropic Messages API, the gateway answers it by
+// translating it to OpenAI
+pub fn is_route(request: &Request) -> bool {
+    let path = request.path.split('?').next().unwrap_or_default();
+    request.method == "POST" && path.trim_end_matches('/') == "/v1/messages"
+}
+
+pub fn answer(gateway: &Gateway, request: &Request, client: &mut TcpStream) -> anyhow::Result<()> {
+    let answered = serde_json::from_slice::<Value>(&request.body)
+        .map_err(|e| Unrouted(400, e.to_string()))
+        .and_then(|body| messages(gateway, &body, client));
+    match answered {
+        // streamed replies are written already
+        Ok(Value::Null) => Ok(()),
+        Ok(response) => Ok(gateway::respond(client, 200, &response)?),
+        Err(Unrouted(status, message)) => Ok(respond_error(client, status, &message)?),
+    }
+}
+
+// An Anthropic error response
+fn respond_error(client: &mut TcpStream, status: u16, message: &str) -> std::io::Result<()> {
+    let kind = match status {
+        400 => "invalid_request_error",
+        404 => "not_found_error",
+        _ => "api_error",
+    };
+    let body = json!({ "type": "error", "error": { "type": kind, "message": message } });
+    gateway::respond(client, status, &body)
+}
+
+fn internal(e: impl std::fmt::Display) -> Unrouted {
+    Unrouted(500, e.to_string())
+}
+
+// The text of a string or of the text blocks of a content
+fn text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+// The OpenAI messages of an Anthropic one, tool results becoming tool messages of their own
+fn openai_messages(message: &Value) -> Vec<Value> {
+    let role = message["role"].as_str().unwrap_or("user");
+    let blocks = match &message["content"] {
+        Value::String(text) => return vec![json!({ "role": role, "content": text })],
+        Value::Array(blocks) => blocks,
+        _ => return Vec::new(),
+    };
+
+    let mut messages = Vec::new();
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block["type"].as_str().unwrap_or_default() {
+            "text" => parts.push(json!({ "type": "text", "text": block["text"] })),
+            "image" => {
+                let source = &block["source"];
+                let url = match source["type"].as_str() {
+                    Some("base64") => format!(
+                        "data:{};base64,{}",
+                        source["media_type"].as_str().unwrap_or("image/png"),
+                        source["data"].as_str().unwrap_or_default()
+                    ),
+                    _ => source["url"].as_str().unwrap_or_default().to_string(),
+                };
+                parts.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+            }
+            "tool_use" => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": { "name": block["name"], "arguments": block["input"].to_string() },
+            })),
+            "tool_result" => messages.push(json!({
+                "role": "tool",
+                "tool_call_id": block["tool_use_id"],
+                "content": text(&block["content"]),
+            })),
+            _ => {}
+        }
+    }
+
+    let only_text = parts.iter().all(|part| part["type"] == "text");
+    let content = match only_text {
+        true => json!(text(&Value::Array(parts.clone()))),
+        false => json!(parts),
+    };
+    if !tool_calls.is_empty() {
+        messages.push(json!({ "role": role, "content": content, "tool_calls": tool_calls }));
+    } else if !parts.is_empty() {
+        messages.push(json!({ "role": role, "content": content }));
+    }
+
+    messages
+}
+
+// The OpenAI chat request of an Anthropic one. A model the gateway does not serve, e.g. a Claude
+// model a client is hard-coded to, goes to the api-server of `gaia start`.
+fn chat_request(gateway: &Gateway, body: &Value) -> Result<Value, Unrouted> {
+    let mut messages = Vec::new();
+    let system = text(&body["system"]);
+    if !system.is_empty() {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    for message in body["messages"].as_array().into_iter().flatten() {
+        messages.extend(openai_messages(message));
+    }
+    if messages.is_empty() {
+        return Err(Unrouted(
+            400,
+            "messages: at least one message is required".to_string(),
+        ));
+    }
+
+    let stream = body["stream"].as_bool().unwrap_or(false);
+    let mut request = json!({ "messages": messages, "stream": stream });
+    let listed = gateway.models().map_err(internal)?;
+    if let Some(model) = body["model"].as_str() {
+        if listed["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .any(|served| served["id"] == model)
+        {
+            request["model"] = json!(model);
+        }
+    }
+    for (from, to) in [
+        ("max_tokens", "max_tokens"),
+        ("temperature", "temperature"),
+        ("top_p", "top_p"),
+        ("stop_sequences", "stop"),
+    ] {
+        if !body[from].is_null() {
+            request[to] = body[from].clone();
+        }
+    }
+    if let Some(tools) = body["tools"].as_array() {
+        request["tools"] = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool["name"],
+                        "description": tool["description"],
+                        "parameters": tool["input_schema"],
+                    },
+                })
+            })
+            .collect();
+    }
+    let choice = &body["tool_choice"];
+    match choice["type"].as_str() {
+        Some("auto") => request["tool_choice"] = json!("auto"),
+        Some("any") => request["tool_choice"] = json!("required"),
+        Some("none") => request["tool_choice"] = json!("none"),
+        Some("tool") => {
+            request["tool_choice"] =
+                json!({ "type": "function", "function": { "name": choice["name"] } })
+        }
+        _ => {}
+    }
+    if stream {
+        request["stream_options"] = json!({ "include_usage": true });
+    }
+
+    Ok(request)
+}
+
+// Why the model stopped, as Anthropic tells it
+fn stop_reason(finish_reason: &str) -> &'static str {
+    match finish_reason {
+        "length" => "max_tokens",
+        "tool_calls" => "tool_use",
+        _ => "end_turn",
+    }
+}
+
+fn message_id(id: &Value) -> String {
+    match id.as_str() {
+        Some(id) => format!("msg_{}", id.trim_start_matches("chatcmpl-")),
+        None => format!(
+            "msg_{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_nanos())
+                .unwrap_or_default()
+        ),
+    }
+}
+
+// The reply to /v1/messages, in one response or as server-sent events as it streams
+fn messages(gateway: &Gateway, body: &Value, client: &mut TcpStream) -> Result<Value, Unrouted> {
+    let model = body["model"].as_str().unwrap_or_default().to_string();
+    let request = chat_request(gateway, body)?;
+    let stream = request["stream"].as_bool().unwrap_or(false);
+    let upstream = gateway.send("chat/completions", &request)?;
+
+    if !stream {
+        let response = upstream.json().map_err(internal)?;
+        let choice = &response["choices"][0];
+        let mut content = Vec::new();
+        if let Some(text) = choice["message"]["content"].as_str() {
+            if !text.is_empty() {
+                content.push(json!({ "type": "text", "text": text }));
+            }
+        }
+        for call in choice["message"]["tool_calls"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+            content.push(json!({
+                "type": "tool_use",
+                "id": call["id"],
+                "name": call["function"]["name"],
+                "input": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})),
+            }));
+        }
+        return Ok(json!({
+            "id": message_id(&response["id"]),
+            "type": "message",
+            "role": "assistant",
+            "model": model,
+            "content": content,
+            "stop_reason": stop_reason(choice["finish_reason"].as_str().unwrap_or("stop")),
+            "stop_sequence": null,
+            "usage": {
+                "input_tokens": response["usage"]["prompt_tokens"].as_u64().unwrap_or_default(),
+                "output_tokens": response["usage"]["completion_tokens"].as_u64().unwrap_or_default(),
+            },
+        }));
+    }
+
+    let sent = (|| -> anyhow::Result<()> {
+        write!(
+            client,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n{}Connection: close\r\n\r\n",
+            gateway.streaming().headers()
+        )?;
+        let mut events = Events {
+            client,
+            block: None,
+            blocks: 0,
+        };
+        let mut started = false;
+        let mut finish_reason = "stop".to_string();
+        let mut usage = Value::Null;
+        for line in BufReader::new(upstream.body).lines() {
+            let line = line?;
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                break;
+            }
+            let chunk = serde_json::from_str::<Value>(data)?;
+            if !started {
+                started = true;
+                events.send(
+                    "message_start",
+                    json!({
+                        "message": {
+                            "id": message_id(&chunk["id"]),
+                            "type": "message",
+                            "role": "assistant",
+                            "model": model,
+                            "content": [],
+                            "stop_reason": null,
+                            "stop_sequence": null,
+                            "usage": { "input_tokens": 0, "output_tokens": 0 },
+                        },
+                    }),
+                )?;
+            }
+            if !chunk["usage"].is_null() {
+                usage = chunk["usage"].clone();
+            }
+            for choice in chunk["choices"].as_array().into_iter().flatten() {
+                if let Some(reason) = choice["finish_reason"].as_str() {
+                    finish_reason = reason.to_string();
+                }
+                let delta = &choice["delta"];
+                if let Some(text) = delta["content"].as_str().filter(|text| !text.is_empty()) {
+                    events.open(Block::Text, json!({ "type": "text", "text": "" }))?;
+                    events.delta(json!({ "type": "text_delta", "text": text }))?;
+                }
+                for call in delta["tool_calls"].as_array().into_iter().flatten() {
+                    let index = call["index"].as_u64().unwrap_or_default();
+                    events.open(
+                        Block::Tool(index),
+                        json!({
+                            "type": "tool_use",
+                            "id": call["id"],
+                            "name": call["function"]["name"],
+                            "input": {},
+                        }),
+                    )?;
+                    if let Some(arguments) = call["function"]["arguments"].as_str() {
+                        events.delta(
+                            json!({ "type": "input_json_delta", "partial_json": arguments }),
+                        )?;
+                    }
+                }
+            }
+        }
+        events.close()?;
+        events.send(
+            "message_delta",
+            json!({
+                "delta": { "stop_reason": stop_reason(&finish_reason), "stop_sequence": null },
+                "usage": {
+                    "output_tokens": usage["completion_tokens"].as_u64().unwrap_or_default(),
+                },
+            }),
+        )?;
+        events.send("message_stop", json!({}))?;
+        events.client.shutdown(Shutdown::Write)?;
+
+        Ok(())
+    })();
+    // the status is sent already, the client sees the stream end early
+    if let Err(e) = sent {
+        tracing::warn!("the Anthropic reply stopped: {:#}", e);
+    }
+
+    Ok(Value::Null)
+}
+
+// A content block of a streamed message, text or the call of a tool by its OpenAI index
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Text,
+    Tool(u64),
+}
+
+// The server-sent events of a streamed message
+struct Events<'a> {
+    client: &'a mut TcpStream,
+    // the block open, with its index
+    block: Option<(Block, usize)>,
+    blocks: usize,
+}
+impl Events<'_> {
+    fn send(&mut self, event: &str, fields: Value) -> anyhow::Result<()> {
+        // the type first, as Anthropic sends it
+        let mut data = json!({ "type": event });
+        for (key, value) in fields.as_object().into_iter().flatten() {
+            data[key] = value.clone();
+        }
+        write!(self.client, "event: {}\ndata: {}\n\n", event, data)?;
+        self.client.flush()?;
+        Ok(())
+    }
+
+    // Start the block unless it is the one open, closing the one before
+    fn open(&mut self, block: Block, content_block: Value) -> anyhow::Result<()> {
+        if self.block.is_some_and(|(open, _)| open == block) {
+            return Ok(());
+        }
+        self.close()?;
+        let index = self.blocks;
+        self.blocks += 1;
+        self.block = Some((block, index));
+        self.send(
+            "content_block_start",
+            json!({ "index": index, "content_block": content_block }),
+        )
+    }
+
+    fn delta(&mut self, delta: Value) -> anyhow::Result<()> {
+        let index = self.block.map(|(_, index)| index).unwrap_or_default();
+        self.send(
+            "content_block_delta",
+            json!({ "index": index, "delta": delta }),
+        )
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        match self.block.take() {
+            Some((_, index)) => self.send("content_block_stop", json!({ "index": index })),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::client::{Content, ContentPart, ImageUrl, Message};
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::Args;
+use std::{fs, path::Path, path::PathBuf};
+
+// Largest image the api-server is expected to accept
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct AttachmentArgs {
+    #[arg(
+        long = "file",
+        help = "Text file to inline into the prompt, can be repeated",
+        value_name = "FILE"
+    )]
+    pub files: Vec<PathBuf>,
+    #[arg(
+        long = "image",
+        help = "Image to send to a multimodal model, can be repeated",
+        value_name = "FILE"
+    )]
+    pub images: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Attachment {
+    Text {
+        name: String,
+        mime: &'static str,
+        text: String,
+    },
+    Image {
+        name: String,
+        data_url: String,
+    },
+}
+
+// Load all attachments, checking that the text ones fit into the given context size
+pub fn load(args: &AttachmentArgs, context_size: u64) -> anyhow::Result<Vec<Attachment>> {
+    let mut attachments = Vec::new();
+
+    let mut tokens = 0;
+    for path in &args.files {
+        let bytes = fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let mime = detect_mime(path, &bytes);
+        if !mime.starts_with("text/") && mime != "application/json" {
+            bail!(
+                "{} is not a text file ({}), use --image for images",
+                path.display(),
+                mime
+            );
+        }
+        let text = String::from_utf8(bytes)
+            .map_err(|_| anyhow!("{} is not valid UTF-8", path.display()))?;
+
+        tokens += estimate_tokens(&text);
+        attachments.push(Attachment::Text {
+            name: file_name(path),
+            mime,
+            text,
+        });
+    }
+    // leave a quarter of the context for the prompt and the reply
+    if tokens > context_size * 3 / 4 {
+        bail!(
+            "The attached files need about {} tokens, which does not fit into a context size of {}",
+            tokens,
+            context_size
+        );
+    }
+
+    for path in &args.images {
+        let bytes = fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let mime = detect_mime(path, &bytes);
+        if !mime.starts_with("image/") {
+            bail!("{} is not an image ({})", path.display(), mime);
+        }
+        if bytes.len() > MAX_IMAGE_BYTES {
+            bail!(
+                "{} is {} bytes, images are limited to {} bytes",
+                path.display(),
+                bytes.len(),
+                MAX_IMAGE_BYTES
+            );
+        }
+
+        attachments.push(Attachment::Image {
+            name: file_name(path),
+            data_url: format!("data:{};base64,{}", mime, STANDARD.encode(&bytes)),
+        });
+    }
+
+    Ok(attachments)
+}
+
+// Build a user message from the prompt and its attachments
+pub fn user_message(prompt: &str, attachments: &[Attachment]) -> Message {
+    let mut text = String::new();
+    for attachment in attachments {
+        if let Attachment::Text {
+            name,
+            mime,
+            text: body,
+        } = attachment
+        {
+            text.push_str(&format!(
+                "--- {} ({}) ---\n{}\n--- end of {} ---\n\n",
+                name,
+                mime,
+                body.trim_end(),
+                name
+            ));
+        }
+    }
+    text.push_str(prompt);
+
+    let images = attachments
+        .iter()
+        .filter_map(|attachment| match attachment {
+            Attachment::Image { data_url, .. } => Some(ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: data_url.clone(),
+                },
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if images.is_empty() {
+        return Message::new("user", text);
+    }
+
+    let mut parts = vec![ContentPart::Text { text }];
+    parts.extend(images);
+    Message::new("user", Content::Parts(parts))
+}
+
+pub fn describe(attachment: &Attachment) -> String {
+    match attachment {
+        Attachment::Text { name, mime, text } => {
+            format!("{} ({}, ~{} tokens)", name, mime, estimate_tokens(text))
+        }
+        Attachment::Image { name, .. } => format!("{} (image)", name),
+    }
+}
+
+// Rough token count, about four characters per token for English text and code
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+// Detect the MIME type from the magic bytes, falling back to the file extension
+pub fn detect_mime(path: &Path, bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return "image/png";
+    }
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        return "image/jpeg";
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return "image/gif";
+    }
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if bytes.starts_with(b"BM") && path_has_extension(path, &["bmp"]) {
+        return "image/bmp";
+    }
+    if bytes.starts_with(b"%PDF-") {
+        return "application/pdf";
+    }
+
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    let mime = match ext.as_str() {
+        "md" | "markdown" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "rs" => "text/x-rust",
+        "py" => "text/x-python",
+        "js" | "ts" => "text/javascript",
+        "c" | "h" => "text/x-c",
+        "cpp" | "hpp" | "cc" => "text/x-c++",
+        "go" => "text/x-go",
+        "sh" => "text/x-shellscript",
+        "toml" | "yaml" | "yml" | "txt" | "log" => "text/plain",
+        _ => "",
+    };
+    if !mime.is_empty() {
+        return mime;
+    }
+
+    // NUL bytes do not show up in text files, even when they are valid UTF-8
+    if !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok() {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+fn path_has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("attachment")
+        .to_string()
+}
@@ -0,0 +1,227 @@
+use crate::paths;
+use anyhow::anyhow;
+use regex_automata::meta::Regex;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Patterns redacted by name, the others in `redact` are regular expressions
+pub const NAMED_PATTERNS: [(&str, &str); 2] = [
+    ("emails", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}"),
+    (
+        "keys",
+        r"(?i:bearer\s+[A-Za-z0-9._~+/=-]{8,})|\b(?:sk|pk|rk|hf|ghp|gho|ghs|glpat|xox[abpr])[-_][A-Za-z0-9_-]{8,}|\bAKIA[0-9A-Z]{16}\b",
+    ),
+];
+pub const REDACTED: &str = "[REDACTED]";
+// Characters of a reply kept in the log, the rest is cut
+const MAX_TEXT: usize = 64 * 1024;
+
+// Log of the prompts and replies passing through the gateway, for debugging or audit, from the
+// `gateway.request_log` section of a node file. Off unless the section is there.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestLogConfig {
+    // directory of requests.jsonl and its rotated files, defaults to the logs directory
+    pub dir: Option<PathBuf>,
+    // size requests.jsonl reaches before it is rotated
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    // rotated files kept, requests.jsonl.1 the newest
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    // `emails`, `keys` or regular expressions whose matches are replaced by [REDACTED]
+    #[serde(default = "default_redact")]
+    pub redact: Vec<String>,
+    // API keys whose requests are never logged, as sent in the Authorization header
+    #[serde(default)]
+    pub skip_keys: Vec<String>,
+}
+impl RequestLogConfig {
+    pub fn redactors(&self) -> anyhow::Result<Vec<Regex>> {
+        self.redact
+            .iter()
+            .map(|pattern| {
+                let regex = NAMED_PATTERNS
+                    .iter()
+                    .find(|(name, _)| name == pattern)
+                    .map_or(pattern.as_str(), |(_, regex)| regex);
+                Regex::new(regex)
+                    .map_err(|e| anyhow!("request_log.redact: invalid regex '{}': {}", pattern, e))
+            })
+            .collect()
+    }
+}
+
+fn default_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    5
+}
+
+fn default_redact() -> Vec<String> {
+    vec!["emails".to_string(), "keys".to_string()]
+}
+
+pub struct RequestLog {
+    path: PathBuf,
+    config: RequestLogConfig,
+    redactors: Vec<Regex>,
+    // one request is written at a time, and rotates the files alone
+    writing: Mutex<()>,
+}
+impl RequestLog {
+    pub fn new(config: RequestLogConfig) -> anyhow::Result<Self> {
+        let dir = match &config.dir {
+            Some(dir) => dir.clone(),
+            None => paths::log_dir()?,
+        };
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("{}: {}", dir.display(), e))?;
+        Ok(Self {
+            path: dir.join("requests.jsonl"),
+            redactors: config.redactors()?,
+            config,
+            writing: Mutex::new(()),
+        })
+    }
+
+    // Whether requests sent with the API key are logged
+    pub fn logs(&self, key: Option<&str>) -> bool {
+        !key.is_some_and(|key| self.config.skip_keys.iter().any(|skip| skip == key))
+    }
+
+    // Append the exchange, redacted, rotating the files first when the log is full
+    pub fn record(
+        &self,
+        method: &str,
+        path: &str,
+        status: Option<u16>,
+        secs: f64,
+        request: &[u8],
+        response: Value,
+    ) {
+        let request = serde_json::from_slice::<Value>(request)
+            .unwrap_or_else(|_| json!(String::from_utf8_lossy(request)));
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        let entry = json!({
+            "time": time,
+            "method": method,
+            "path": path,
+            "model": request["model"],
+            "status": status,
+            "secs": secs,
+            "request": self.redact(request),
+            "response": self.redact(response),
+        });
+
+        let _writing = self.writing.lock().unwrap();
+        if let Err(e) = self.rotate().and_then(|_| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            writeln!(file, "{}", entry)?;
+            Ok(())
+        }) {
+            tracing::warn!("failed to log a request to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn rotate(&self) -> anyhow::Result<()> {
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size < self.config.max_bytes {
+            return Ok(());
+        }
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        let _ = fs::remove_file(rotated(self.config.keep.max(1)));
+        for n in (1..self.config.keep.max(1)).rev() {
+            let _ = fs::rename(rotated(n), rotated(n + 1));
+        }
+        match self.config.keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated(1))?,
+        }
+
+        Ok(())
+    }
+
+    // The strings of the value with every match of the patterns replaced
+    fn redact(&self, value: Value) -> Value {
+        match value {
+            Value::String(text) => Value::String(self.redact_text(&text)),
+            Value::Array(items) => items.into_iter().map(|item| self.redact(item)).collect(),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, self.redact(value)))
+                    .collect::<Map<_, _>>(),
+            ),
+            value => value,
+        }
+    }
+
+    fn redact_text(&self, text: &str) -> String {
+        let mut text = match text.char_indices().nth(MAX_TEXT) {
+            Some((end, _)) => format!("{}...", &text[..end]),
+            None => text.to_string(),
+        };
+        for regex in &self.redactors {
+            text = redact_matches(regex, &text);
+        }
+        text
+    }
+}
+
+// The text with every match of the regular expression replaced by [REDACTED]
+pub fn redact_matches(regex: &Regex, text: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut last = 0;
+    for found in regex.find_iter(text) {
+        redacted.push_str(&text[last..found.start()]);
+        redacted.push_str(REDACTED);
+        last = found.end();
+    }
+    redacted.push_str(&text[last..]);
+    redacted
+}
+
+// What a reply said, from the body of a JSON response or the events of a stream
+pub fn response_summary(body: &[u8], event_stream: bool) -> Value {
+    if !event_stream {
+        return serde_json::from_slice::<Value>(body)
+            .unwrap_or_else(|_| json!(String::from_utf8_lossy(body)));
+    }
+    let mut content = String::new();
+    let mut finish_reason = Value::Null;
+    let mut usage = Value::Null;
+    for line in String::from_utf8_lossy(body).lines() {
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            continue;
+        };
+        let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+            continue;
+        };
+        if !chunk["usage"].is_null() {
+            usage = chunk["usage"].clone();
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            content.push_str(choice["delta"]["content"].as_str().unwrap_or_default());
+            if !choice["finish_reason"].is_null() {
+                finish_reason = choice["finish_reason"].clone();
+            }
+        }
+    }
+
+    json!({ "content": content, "finish_reason": finish_reason, "usage": usage })
+}
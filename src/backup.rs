@@ -0,0 +1,354 @@
+use crate::config;
+use crate::crash;
+use crate::events;
+use crate::identity;
+use crate::lock::LOCK_FILE;
+use crate::models::{self, Entry};
+use crate::paths;
+use crate::qdrant::QdrantArgs;
+use crate::rag::{self, RagArgs};
+use crate::signature;
+use crate::store::{self, VectorStoreKind};
+use anyhow::{anyhow, bail};
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Layout of the archive, restoring refuses archives of a newer layout
+const BACKUP_VERSION: u32 = 1;
+// First entry of the archive
+const META: &str = "backup.json";
+// Snapshots of the Qdrant collections, as `qdrant/<collection>.snapshot`
+const QDRANT: &str = "qdrant";
+
+// What the archive holds besides the files
+#[derive(Debug, Serialize, Deserialize)]
+struct Meta {
+    version: u32,
+    created: u64,
+    // models directory of the backed up node, node files naming models in it are pointed at
+    // the models directory of the restored one
+    models_dir: String,
+    collections: Vec<String>,
+}
+
+// Where each entry of the archive comes from and is restored to. Models are left out, the
+// manifest keeps the urls they are downloaded from again.
+fn sources() -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let config_dir = paths::config_dir()?;
+    let mut sources = config::DEFAULT_FILES
+        .iter()
+        .chain([&LOCK_FILE, &identity::NAME_FILE, &crash::SETTINGS_FILE])
+        .map(|name| (format!("config/{}", name), config_dir.join(name)))
+        .collect::<Vec<_>>();
+    sources.extend([
+        ("trusted-keys".to_string(), signature::keys_dir()?),
+        ("identity".to_string(), paths::identity_dir()?),
+        ("presets".to_string(), paths::presets_dir()?),
+        ("prompts".to_string(), paths::prompts_dir()?),
+        ("rag".to_string(), paths::rag_dir()?),
+        ("chat_history".to_string(), paths::history_path()?),
+        ("models/manifest.json".to_string(), models::manifest_path()?),
+    ]);
+
+    Ok(sources)
+}
+
+// A scratch directory for the snapshots passing through, removed by the caller
+fn scratch_dir() -> anyhow::Result<PathBuf> {
+    let dir = env::temp_dir().join(format!("gaia-backup-{}", process::id()));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// Write the node files, keys, presets, prompts, chat history, model manifest and collections
+// into a zstd compressed tar archive
+pub fn command_backup(output: &Path, no_qdrant: bool, qdrant: QdrantArgs) -> anyhow::Result<()> {
+    let collections = match no_qdrant {
+        true => Vec::new(),
+        false => rag::qdrant_collections()?,
+    };
+    let meta = Meta {
+        version: BACKUP_VERSION,
+        created: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        models_dir: paths::models_dir()?.display().to_string(),
+        collections: collections.clone(),
+    };
+
+    // written aside and renamed, so a failed backup does not replace a good one
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let scratch = scratch_dir()?;
+    let written = write_archive(&partial, &meta, &scratch, &qdrant);
+    let _ = fs::remove_dir_all(&scratch);
+    let files = match written {
+        Ok(files) => files,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, output)?;
+
+    let size = fs::metadata(output)?.len();
+    events::emit(
+        "backup-created",
+        json!({ "file": output.display().to_string(), "size": size }),
+    );
+    println!(
+        "Saved {} files and {} collections to {} ({:.1} MB)",
+        files,
+        collections.len(),
+        output.display(),
+        size as f64 / 1_000_000.0
+    );
+    if no_qdrant && !rag::qdrant_collections()?.is_empty() {
+        println!(
+            "{}",
+            style("The Qdrant collections are left out, ingest them again after restoring").dim()
+        );
+    }
+
+    Ok(())
+}
+
+// Returns the number of files written
+fn write_archive(
+    path: &Path,
+    meta: &Meta,
+    scratch: &Path,
+    qdrant: &QdrantArgs,
+) -> anyhow::Result<usize> {
+    let encoder = zstd::Encoder::new(File::create(path)?, 0)?;
+    let mut archive = tar::Builder::new(encoder);
+
+    let content = serde_json::to_vec_pretty(meta)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(meta.created);
+    header.set_cksum();
+    archive.append_data(&mut header, META, content.as_slice())?;
+
+    let mut files = 0;
+    for (name, source) in sources()? {
+        if source.is_dir() {
+            archive.append_dir_all(&name, &source)?;
+            files += count(&source);
+        } else if source.is_file() {
+            archive.append_path_with_name(&source, &name)?;
+            files += 1;
+        }
+    }
+
+    if !meta.collections.is_empty() {
+        let store = store::open(VectorStoreKind::Qdrant, qdrant)?;
+        for collection in &meta.collections {
+            println!("Saving collection '{}'", collection);
+            let snapshot = scratch.join(format!("{}.snapshot", collection));
+            store
+                .export_snapshot(collection, &snapshot)
+                .map_err(|e| anyhow!("{}, use --no-qdrant to back up without it", e))?;
+            archive
+                .append_path_with_name(&snapshot, format!("{}/{}.snapshot", QDRANT, collection))?;
+            fs::remove_file(&snapshot)?;
+        }
+    }
+
+    archive.into_inner()?.finish()?;
+
+    Ok(files)
+}
+
+// Files under the directory
+fn count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .map(|path| match path.is_dir() {
+            true => count(&path),
+            false => 1,
+        })
+        .sum()
+}
+
+// Recreate the node of an archive written by `gaia backup`, on this machine or another
+pub fn command_restore(file: &Path, force: bool, qdrant: QdrantArgs) -> anyhow::Result<()> {
+    let config_dir = paths::config_dir()?;
+    if let Some(existing) = config::DEFAULT_FILES
+        .iter()
+        .map(|name| config_dir.join(name))
+        .find(|path| path.is_file())
+    {
+        if !force {
+            bail!(
+                "A node is set up already in {}, use --force to replace its files",
+                existing.display()
+            );
+        }
+    }
+
+    let decoder =
+        zstd::Decoder::new(File::open(file).map_err(|e| anyhow!("{}: {}", file.display(), e))?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut entries = archive.entries()?;
+    let not_backup = || {
+        anyhow!(
+            "{} is not a backup written by `gaia backup`",
+            file.display()
+        )
+    };
+    let mut first = entries
+        .next()
+        .ok_or_else(not_backup)?
+        .map_err(|_| not_backup())?;
+    if first.path()?.as_ref() != Path::new(META) {
+        return Err(not_backup());
+    }
+    let mut content = String::new();
+    first.read_to_string(&mut content)?;
+    let meta: Meta = serde_json::from_str(&content).map_err(|_| not_backup())?;
+    if meta.version > BACKUP_VERSION {
+        bail!(
+            "{} was written by a newer gaia, upgrade gaia to restore it",
+            file.display()
+        );
+    }
+
+    let sources = sources()?;
+    let models_dir = paths::models_dir()?.display().to_string();
+    let scratch = scratch_dir()?;
+    let mut files = 0;
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        // nothing may land outside the directories it is restored to
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("{}: unexpected entry {}", file.display(), path.display());
+        }
+
+        if let Ok(snapshot) = path.strip_prefix(QDRANT) {
+            let dest = scratch.join(snapshot);
+            entry.unpack(&dest)?;
+            snapshots.push(dest);
+            continue;
+        }
+        let Some(dest) = sources.iter().find_map(|(name, source)| {
+            path.strip_prefix(name)
+                .ok()
+                .map(|rest| match rest.as_os_str().is_empty() {
+                    true => source.clone(),
+                    false => source.join(rest),
+                })
+        }) else {
+            tracing::warn!(entry = %path.display(), "unknown entry in the backup");
+            continue;
+        };
+        if let Some(dir) = dest.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        if dest == models::manifest_path()? {
+            // models downloaded on this machine stay in the manifest
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            let backed_up: BTreeMap<String, Entry> = serde_json::from_str(&content)?;
+            let mut manifest = models::manifest()?;
+            for (name, model) in backed_up {
+                manifest.entry(name).or_insert(model);
+            }
+            fs::create_dir_all(paths::models_dir()?)?;
+            models::save(&manifest)?;
+        } else if path.starts_with("config") || path.starts_with("presets") {
+            // models of the old models directory are found in this one
+            let mut content = String::new();
+            entry.read_to_string(&mut content)?;
+            fs::write(&dest, content.replace(&meta.models_dir, &models_dir))?;
+        } else {
+            entry.unpack(&dest)?;
+        }
+        files += 1;
+    }
+    println!("Restored {} files from {}", files, file.display());
+
+    let restored = restore_collections(&snapshots, &qdrant);
+    let _ = fs::remove_dir_all(&scratch);
+    restored?;
+
+    let missing = models::manifest()?
+        .into_iter()
+        .filter(|(name, _)| !paths::models_dir().is_ok_and(|dir| dir.join(name).exists()))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        println!("Download the models of the node again with:");
+        for (_, model) in missing {
+            println!("  gaia pull {}", model.url);
+        }
+    }
+    events::emit(
+        "backup-restored",
+        json!({ "file": file.display().to_string(), "created": meta.created }),
+    );
+
+    Ok(())
+}
+
+// Load the snapshots into Qdrant. Those that cannot be loaded now are kept in the rag directory
+// with the command to load them later.
+fn restore_collections(snapshots: &[PathBuf], qdrant: &QdrantArgs) -> anyhow::Result<()> {
+    for snapshot in snapshots {
+        let Some(collection) = snapshot.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let rag = RagArgs {
+            collection: collection.to_string(),
+            vector_store: Some(VectorStoreKind::Qdrant),
+            qdrant: qdrant.clone(),
+        };
+        let exists = rag::open_store(&rag).and_then(|store| store.collection_exists(collection));
+        let imported = match exists {
+            Ok(true) => {
+                println!("Collection '{}' is already in Qdrant, kept it", collection);
+                continue;
+            }
+            Ok(false) => rag::command_import_snapshot(rag, snapshot),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = imported {
+            let kept = paths::rag_dir()?.join("snapshots");
+            fs::create_dir_all(&kept)?;
+            let kept = kept.join(format!("{}.snapshot", collection));
+            fs::copy(snapshot, &kept)?;
+            println!(
+                "{} {}",
+                style(format!("Could not load collection '{}':", collection)).yellow(),
+                e
+            );
+            println!(
+                "Load it once Qdrant runs with `gaia rag import-snapshot {} --collection {}`",
+                kept.display(),
+                collection
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use crate::client::{ChatRequest, Client, ClientArgs, Message, SamplingArgs, Throughput};
+use crate::progress::Progress;
+use crate::prompt;
+use crate::throughput::Session;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::style;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Instant,
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct BatchArgs {
+    #[arg(
+        long = "input",
+        help = "JSONL file of rows to complete, each with a prompt or messages and an optional id, or with the placeholders of --prompt-file or --prompt-name",
+        value_name = "FILE",
+        requires = "output",
+        conflicts_with = "prompt"
+    )]
+    pub input: Option<PathBuf>,
+    #[arg(
+        long = "concurrency",
+        help = "Rows of --input sent to the server at once",
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "input"
+    )]
+    pub concurrency: u32,
+}
+
+// A row of the input, its id defaulting to its line number
+struct Row {
+    id: Value,
+    messages: Result<Vec<Message>, String>,
+}
+
+// Where the workers hand in the rows they completed
+struct Sink {
+    file: File,
+    progress: Progress,
+    session: Session,
+    failed: usize,
+}
+
+// Messages of the row, with the template filled from its fields when it has no prompt of its own
+fn messages(
+    row: &Map<String, Value>,
+    template: Option<&str>,
+    vars: &HashMap<String, String>,
+    system_prompt: Option<&str>,
+) -> anyhow::Result<Vec<Message>> {
+    if let Some(messages) = row.get("messages") {
+        return Ok(serde_json::from_value(messages.clone())?);
+    }
+
+    let prompt = match (row.get("prompt"), template) {
+        (Some(Value::String(prompt)), _) => prompt.clone(),
+        (Some(_), _) => bail!("prompt is not a string"),
+        (None, Some(template)) => {
+            let mut vars = vars.clone();
+            vars.extend(row.iter().map(|(key, value)| {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (key.clone(), value)
+            }));
+            prompt::render(template, &vars)?
+        }
+        (None, None) => bail!("no prompt or messages"),
+    };
+    let mut messages = Vec::new();
+    let system_prompt = row
+        .get("system_prompt")
+        .and_then(Value::as_str)
+        .or(system_prompt);
+    if let Some(system_prompt) = system_prompt {
+        messages.push(Message::new("system", system_prompt));
+    }
+    messages.push(Message::new("user", prompt));
+
+    Ok(messages)
+}
+
+// Ids of the rows the output already has a reply for, rows that failed are tried again
+fn done(output: &Path) -> HashSet<String> {
+    let content = fs::read_to_string(output).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|row| row.get("error").is_none())
+        .map(|row| row["id"].to_string())
+        .collect()
+}
+
+// Complete every row of the input, `concurrency` at a time, appending a line per row to the
+// output as soon as it is done so an interrupted run picks up where it stopped
+pub fn command_batch(
+    client_args: ClientArgs,
+    sampling: SamplingArgs,
+    template: Option<String>,
+    vars: HashMap<String, String>,
+    system_prompt: Option<String>,
+    args: BatchArgs,
+    output: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let (Some(input), Some(output)) = (args.input, output) else {
+        bail!("Give the rows to complete with --input and where to write them with --output");
+    };
+    let content = fs::read_to_string(&input).map_err(|e| anyhow!("{}: {}", input.display(), e))?;
+    let done = done(&output);
+
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = serde_json::from_str::<Value>(line)
+            .map_err(|e| anyhow!("{}:{}: {}", input.display(), i + 1, e))?;
+        let Value::Object(row) = row else {
+            bail!("{}:{}: expected a JSON object", input.display(), i + 1);
+        };
+        let id = row.get("id").cloned().unwrap_or(json!(i + 1));
+        if done.contains(&id.to_string()) {
+            skipped += 1;
+            continue;
+        }
+        let messages = messages(&row, template.as_deref(), &vars, system_prompt.as_deref())
+            .map_err(|e| e.to_string());
+        rows.push(Row { id, messages });
+    }
+    if skipped > 0 {
+        println!(
+            "{}",
+            style(format!(
+                "Skipping {} rows already in {}",
+                skipped,
+                output.display()
+            ))
+            .dim()
+        );
+    }
+
+    let client = Client::new(&client_args.base_url)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&output)
+        .map_err(|e| anyhow!("{}: {}", output.display(), e))?;
+    let name = input.display().to_string();
+    let progress = Progress::new("batch", &name, "rows", Some(rows.len() as u64));
+    let total = rows.len();
+    let queue = Mutex::new(rows.into_iter());
+    let sink = Mutex::new(Sink {
+        file,
+        progress,
+        session: Session::new("batch"),
+        failed: 0,
+    });
+
+    thread::scope(|scope| {
+        for _ in 0..args.concurrency {
+            scope.spawn(|| loop {
+                let Some(row) = queue.lock().unwrap().next() else {
+                    return;
+                };
+                let (line, throughput) = complete(&client, &client_args, &sampling, row);
+                let mut sink = sink.lock().unwrap();
+                match throughput {
+                    Some(throughput) => sink.session.add(&throughput),
+                    None => sink.failed += 1,
+                }
+                // one write per row keeps the lines whole
+                if let Err(e) = sink.file.write_all(format!("{}\n", line).as_bytes()) {
+                    tracing::error!("cannot write to the output: {}", e);
+                }
+                sink.progress.advance(1);
+            });
+        }
+    });
+
+    let Sink {
+        progress,
+        session,
+        failed,
+        ..
+    } = sink.into_inner().unwrap();
+    progress.finish();
+    session.finish();
+    println!(
+        "Completed {} of {} rows into {}",
+        total - failed,
+        total,
+        output.display()
+    );
+    if failed > 0 {
+        println!(
+            "{} rows failed, their errors are in the output and they are tried again when run again",
+            failed
+        );
+    }
+
+    Ok(())
+}
+
+// The line of the output for the row, with its error rather than its reply when it failed
+fn complete(
+    client: &Client,
+    client_args: &ClientArgs,
+    sampling: &SamplingArgs,
+    row: Row,
+) -> (Value, Option<Throughput>) {
+    let messages = match row.messages {
+        Ok(messages) => messages,
+        Err(e) => return (json!({ "id": row.id, "error": e }), None),
+    };
+    let request = ChatRequest {
+        model: client_args.model_name.clone(),
+        messages,
+        stream: false,
+        sampling: sampling.clone(),
+        tools: None,
+        response_format: None,
+    };
+    let started = Instant::now();
+    match client.chat(&request) {
+        Ok((reply, throughput)) => {
+            let mut line = json!({
+                "id": row.id,
+                "reply": reply.text(),
+                "prompt_tokens": throughput.prompt_tokens,
+                "completion_tokens": throughput.completion_tokens,
+                "secs": started.elapsed().as_secs_f64(),
+            });
+            // kept to reproduce the reply
+            if let Some(seed) = sampling.seed {
+                line["seed"] = json!(seed);
+            }
+            (line, Some(throughput))
+        }
+        Err(e) => (json!({ "id": row.id, "error": format!("{:#}", e) }), None),
+    }
+}
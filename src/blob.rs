@@ -0,0 +1,989 @@
+use crate::paths;
+use crate::progress::Progress;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    Method, StatusCode, Url,
+};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env, fs,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Objects are fetched in ranged parts of this size, several at once
+const PART_SIZE: u64 = 64 * 1024 * 1024;
+const PARALLEL_PARTS: u64 = 4;
+// attempts at each part, a failed attempt resumes where the last one stopped
+const PART_ATTEMPTS: u32 = 3;
+// metadata services only answer inside the cloud, do not wait long for them elsewhere
+const METADATA_TIMEOUT: Duration = Duration::from_secs(1);
+const AZURE_VERSION: &str = "2021-08-06";
+const GCS_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+
+// Everything but the unreserved characters and `/`, as object keys are encoded when signed
+const KEY: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+pub fn is_blob_url(s: &str) -> bool {
+    ["s3://", "gs://", "az://"]
+        .iter()
+        .any(|scheme| s.starts_with(scheme))
+}
+
+#[derive(Debug, Clone)]
+struct AwsCredentials {
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+}
+
+// How the requests of an object are authorized
+#[derive(Debug, Clone)]
+enum Auth {
+    Anonymous,
+    Bearer(String),
+    // AWS signature version 4
+    S3 {
+        credentials: AwsCredentials,
+        region: String,
+    },
+    // an Azure storage account key
+    SharedKey {
+        account: String,
+        key: Vec<u8>,
+    },
+}
+
+// An object in S3, Google Cloud Storage or Azure Blob Storage
+#[derive(Debug, Clone)]
+struct Blob {
+    url: Url,
+    auth: Auth,
+    headers: Vec<(String, String)>,
+    // Azure range requests are signed, hence its own header
+    range_header: &'static str,
+}
+impl Blob {
+    fn open(client: &Client, url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(url)?;
+        let location = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or(anyhow!("Missing the bucket in {}", url))?
+            .to_string();
+        let key = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8()?;
+        if key.is_empty() {
+            bail!("Missing the object in {}", url);
+        }
+
+        match url.scheme() {
+            "s3" => s3_blob(client, &location, &key),
+            "gs" => gcs_blob(client, &location, &key),
+            "az" => azure_blob(client, &location, &key),
+            scheme => bail!("Unsupported object store '{}'", scheme),
+        }
+    }
+
+    fn request(
+        &self,
+        client: &Client,
+        method: Method,
+        range: Option<(u64, u64)>,
+    ) -> anyhow::Result<RequestBuilder> {
+        let mut headers = self.headers.clone();
+        if let Some((start, end)) = range {
+            headers.push((
+                self.range_header.to_string(),
+                format!("bytes={}-{}", start, end),
+            ));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        match &self.auth {
+            Auth::Anonymous => {}
+            Auth::Bearer(token) => {
+                headers.push(("authorization".to_string(), format!("Bearer {}", token)))
+            }
+            Auth::S3 {
+                credentials,
+                region,
+            } => sign_s3(&method, &self.url, &mut headers, credentials, region, now),
+            Auth::SharedKey { account, key } => {
+                sign_shared_key(&method, &self.url, &mut headers, account, key, now)?
+            }
+        }
+
+        let mut request = client.request(method, self.url.clone());
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+
+        Ok(request)
+    }
+
+    // Size of the object, in bytes
+    fn size(&self, client: &Client) -> anyhow::Result<u64> {
+        let response = check(self.request(client, Method::HEAD, None)?.send()?)?;
+
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .ok_or(anyhow!("The store did not tell the size of {}", self.url))
+    }
+}
+
+// Download the object to the file, in parts fetched in parallel. Returns the size in bytes.
+pub fn download(url: &str, dest: &Path) -> anyhow::Result<u64> {
+    let _span = tracing::info_span!("download", url).entered();
+    // models are large, do not time out the download
+    let client = Client::builder().timeout(None).build()?;
+    let mut blob = Blob::open(&client, url)?;
+    let size = match blob.size(&client) {
+        Ok(size) => size,
+        Err(e) => match s3_region_redirect(&client, &blob, url)? {
+            // the bucket lives in another region than the one configured
+            Some(moved) => {
+                blob = moved;
+                blob.size(&client)?
+            }
+            None => return Err(e),
+        },
+    };
+    tracing::info!(dest = %dest.display(), size, "downloading");
+
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    File::create(&partial)?.set_len(size)?;
+
+    let name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(url.to_string());
+    let progress = Mutex::new(Progress::new("download", &name, "bytes", Some(size)));
+    let parts = size.div_ceil(PART_SIZE);
+    let next = AtomicU64::new(0);
+    thread::scope(|scope| {
+        let workers = (0..PARALLEL_PARTS.min(parts))
+            .map(|_| {
+                scope.spawn(|| -> anyhow::Result<()> {
+                    let mut file = OpenOptions::new().write(true).open(&partial)?;
+                    loop {
+                        let part = next.fetch_add(1, Ordering::SeqCst);
+                        if part >= parts {
+                            return Ok(());
+                        }
+                        let start = part * PART_SIZE;
+                        let end = (start + PART_SIZE).min(size) - 1;
+                        if let Err(e) = fetch_part(&client, &blob, &mut file, start, end, &progress)
+                        {
+                            // no point in the other parts now
+                            next.store(parts, Ordering::SeqCst);
+                            return Err(e);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers.into_iter().try_for_each(|worker| {
+            worker
+                .join()
+                .map_err(|_| anyhow!("A download thread panicked"))?
+        })
+    })
+    .inspect_err(|_| {
+        let _ = fs::remove_file(&partial);
+    })?;
+
+    progress
+        .into_inner()
+        .map_err(|_| anyhow!("A download thread panicked"))?
+        .finish();
+    fs::rename(&partial, dest)?;
+    tracing::info!(bytes = size, "downloaded");
+
+    Ok(size)
+}
+
+// Write the bytes from start to end, inclusive, at their place in the file
+fn fetch_part(
+    client: &Client,
+    blob: &Blob,
+    file: &mut File,
+    start: u64,
+    end: u64,
+    progress: &Mutex<Progress>,
+) -> anyhow::Result<()> {
+    let mut offset = start;
+    let mut attempt = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    while offset <= end {
+        attempt += 1;
+        let result = (|| -> anyhow::Result<()> {
+            let response = blob
+                .request(client, Method::GET, Some((offset, end)))?
+                .send()?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                // only a part was asked for, a whole object would be written at the wrong place
+                return Err(check(response)
+                    .err()
+                    .unwrap_or(anyhow!("The store ignored the range of the request")));
+            }
+            let mut response = response;
+            file.seek(SeekFrom::Start(offset))?;
+            while offset <= end {
+                let n = response.read(&mut buffer)?;
+                if n == 0 {
+                    bail!("The connection closed at byte {}", offset);
+                }
+                file.write_all(&buffer[..n])?;
+                offset += n as u64;
+                if let Ok(mut progress) = progress.lock() {
+                    progress.advance(n as u64);
+                }
+            }
+
+            Ok(())
+        })();
+        match result {
+            Ok(()) => {}
+            Err(e) if attempt < PART_ATTEMPTS && retryable(&e) => {
+                tracing::warn!(offset, attempt, "retrying: {:#}", e);
+                thread::sleep(Duration::from_secs(u64::from(attempt)));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+// Requests refused by the store fail the same when sent again
+fn retryable(e: &anyhow::Error) -> bool {
+    !e.chain().any(|cause| {
+        cause
+            .downcast_ref::<StatusError>()
+            .is_some_and(|e| e.0.is_client_error())
+    })
+}
+
+#[derive(Debug)]
+struct StatusError(StatusCode, String);
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+impl std::error::Error for StatusError {}
+
+fn check(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let url = response.url().clone();
+    let body = response.text().unwrap_or_default();
+    // S3, GCS and Azure answer in XML, with the reason in <Message>
+    let message = body
+        .split_once("<Message>")
+        .and_then(|(_, rest)| rest.split_once("</Message>"))
+        .map(|(message, _)| message.to_string())
+        .unwrap_or(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                "access denied, check the credentials".to_string()
+            }
+            StatusCode::NOT_FOUND => "no such object".to_string(),
+            _ => status.to_string(),
+        });
+
+    Err(StatusError(status, format!("{}: {} ({})", url, message, status)).into())
+}
+
+fn s3_blob(client: &Client, bucket: &str, key: &str) -> anyhow::Result<Blob> {
+    let profile = env::var("AWS_PROFILE").unwrap_or("default".to_string());
+    let auth = match aws_credentials(client, &profile)? {
+        Some(credentials) => Auth::S3 {
+            credentials,
+            region: aws_region(&profile),
+        },
+        None => {
+            tracing::debug!("no AWS credentials found, reading the object anonymously");
+            Auth::Anonymous
+        }
+    };
+
+    Ok(Blob {
+        url: s3_url(bucket, key, &aws_region(&profile))?,
+        auth,
+        headers: Vec::new(),
+        range_header: "range",
+    })
+}
+
+fn s3_url(bucket: &str, key: &str, region: &str) -> anyhow::Result<Url> {
+    let key = utf8_percent_encode(key, KEY);
+    let url = match env::var("AWS_ENDPOINT_URL_S3").or(env::var("AWS_ENDPOINT_URL")) {
+        // S3 compatible stores such as MinIO take the bucket in the path
+        Ok(endpoint) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        // dots in the bucket would not match the certificate of the virtual host
+        Err(_) if bucket.contains('.') => {
+            format!("https://s3.{}.amazonaws.com/{}/{}", region, bucket, key)
+        }
+        Err(_) => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key),
+    };
+
+    Ok(Url::parse(&url)?)
+}
+
+// The blob in the region S3 says the bucket is in, when that is not the one it was asked in
+fn s3_region_redirect(client: &Client, blob: &Blob, url: &str) -> anyhow::Result<Option<Blob>> {
+    let Auth::S3 {
+        credentials,
+        region,
+    } = &blob.auth
+    else {
+        return Ok(None);
+    };
+    let response = blob.request(client, Method::HEAD, None)?.send()?;
+    let Some(actual) = response
+        .headers()
+        .get("x-amz-bucket-region")
+        .and_then(|value| value.to_str().ok())
+        .filter(|actual| actual != region)
+    else {
+        return Ok(None);
+    };
+    tracing::debug!(region = actual, "the bucket is in another region");
+
+    let url = Url::parse(url)?;
+    let key = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8()?;
+
+    Ok(Some(Blob {
+        url: s3_url(url.host_str().unwrap_or_default(), &key, actual)?,
+        auth: Auth::S3 {
+            credentials: credentials.clone(),
+            region: actual.to_string(),
+        },
+        ..blob.clone()
+    }))
+}
+
+// The standard chain: the environment, the shared files, the container and instance metadata
+fn aws_credentials(client: &Client, profile: &str) -> anyhow::Result<Option<AwsCredentials>> {
+    if let (Ok(access_key), Ok(secret_key)) = (
+        env::var("AWS_ACCESS_KEY_ID"),
+        env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Ok(Some(AwsCredentials {
+            access_key,
+            secret_key,
+            token: env::var("AWS_SESSION_TOKEN").ok(),
+        }));
+    }
+
+    let sections = [
+        (
+            aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials")?,
+            profile.to_string(),
+        ),
+        (
+            aws_file("AWS_CONFIG_FILE", "config")?,
+            aws_config_section(profile),
+        ),
+    ];
+    for (path, section) in sections {
+        let Some(values) = ini_section(&path, &section) else {
+            continue;
+        };
+        if let (Some(access_key), Some(secret_key)) = (
+            values.get("aws_access_key_id"),
+            values.get("aws_secret_access_key"),
+        ) {
+            return Ok(Some(AwsCredentials {
+                access_key: access_key.clone(),
+                secret_key: secret_key.clone(),
+                token: values.get("aws_session_token").cloned(),
+            }));
+        }
+    }
+
+    // ECS and EKS containers
+    let container = match (
+        env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+        env::var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+    ) {
+        (Ok(path), _) => Some(format!("http://169.254.170.2{}", path)),
+        (_, Ok(url)) => Some(url),
+        _ => None,
+    };
+    if let Some(url) = container {
+        let mut request = client.get(url).timeout(METADATA_TIMEOUT);
+        if let Ok(token) = env::var("AWS_CONTAINER_AUTHORIZATION_TOKEN") {
+            request = request.header("authorization", token);
+        }
+        let value: Value = check(request.send()?)?.json()?;
+        return aws_metadata_credentials(&value).map(Some);
+    }
+
+    if env::var("AWS_EC2_METADATA_DISABLED").is_ok_and(|value| value == "true") {
+        return Ok(None);
+    }
+    ec2_credentials(client)
+}
+
+// Credentials of the role of the EC2 instance, through IMDSv2
+fn ec2_credentials(client: &Client) -> anyhow::Result<Option<AwsCredentials>> {
+    let base = "http://169.254.169.254/latest";
+    let Ok(response) = client
+        .put(format!("{}/api/token", base))
+        .header("x-aws-ec2-metadata-token-ttl-seconds", "60")
+        .timeout(METADATA_TIMEOUT)
+        .send()
+    else {
+        return Ok(None);
+    };
+    let Ok(token) = check(response).and_then(|response| Ok(response.text()?)) else {
+        return Ok(None);
+    };
+
+    let url = format!("{}/meta-data/iam/security-credentials/", base);
+    let get = |url: String| {
+        client
+            .get(url)
+            .header("x-aws-ec2-metadata-token", &token)
+            .timeout(METADATA_TIMEOUT)
+            .send()
+    };
+    let Ok(role) = check(get(url.clone())?).and_then(|response| Ok(response.text()?)) else {
+        // an instance without a role
+        return Ok(None);
+    };
+    let value: Value = check(get(format!("{}{}", url, role.trim()))?)?.json()?;
+
+    aws_metadata_credentials(&value).map(Some)
+}
+
+fn aws_metadata_credentials(value: &Value) -> anyhow::Result<AwsCredentials> {
+    let field = |name: &str| {
+        value[name].as_str().map(String::from).ok_or(anyhow!(
+            "The credentials of the metadata service lack {}",
+            name
+        ))
+    };
+
+    Ok(AwsCredentials {
+        access_key: field("AccessKeyId")?,
+        secret_key: field("SecretAccessKey")?,
+        token: field("Token").ok(),
+    })
+}
+
+fn aws_region(profile: &str) -> String {
+    env::var("AWS_REGION")
+        .or(env::var("AWS_DEFAULT_REGION"))
+        .ok()
+        .or_else(|| {
+            let path = aws_file("AWS_CONFIG_FILE", "config").ok()?;
+            ini_section(&path, &aws_config_section(profile))?.remove("region")
+        })
+        .unwrap_or("us-east-1".to_string())
+}
+
+fn aws_file(var: &str, name: &str) -> anyhow::Result<PathBuf> {
+    match env::var_os(var) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(paths::home()?.join(".aws").join(name)),
+    }
+}
+
+// Profiles other than the default are `[profile name]` in ~/.aws/config
+fn aws_config_section(profile: &str) -> String {
+    match profile {
+        "default" => profile.to_string(),
+        _ => format!("profile {}", profile),
+    }
+}
+
+// The keys of a section of an INI file, None when the file or the section does not exist
+fn ini_section(path: &Path, section: &str) -> Option<HashMap<String, String>> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut values = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if values.is_some() {
+                break;
+            }
+            if name.trim() == section {
+                values = Some(HashMap::new());
+            }
+        } else if let (Some(values), Some((key, value))) = (values.as_mut(), line.split_once('=')) {
+            if !line.starts_with('#') && !line.starts_with(';') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+
+    values
+}
+
+fn sign_s3(
+    method: &Method,
+    url: &Url,
+    headers: &mut Vec<(String, String)>,
+    credentials: &AwsCredentials,
+    region: &str,
+    now: u64,
+) {
+    let time = Utc::of(now);
+    let date = format!("{:04}{:02}{:02}", time.year, time.month, time.day);
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date, time.hour, time.minute, time.second
+    );
+    headers.push((
+        "x-amz-content-sha256".to_string(),
+        "UNSIGNED-PAYLOAD".to_string(),
+    ));
+    headers.push(("x-amz-date".to_string(), timestamp.clone()));
+    if let Some(token) = &credentials.token {
+        headers.push(("x-amz-security-token".to_string(), token.clone()));
+    }
+
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let mut signed = headers
+        .iter()
+        .filter(|(name, _)| name.starts_with("x-amz-"))
+        .cloned()
+        .chain([("host".to_string(), host)])
+        .collect::<Vec<_>>();
+    signed.sort();
+    let canonical_headers = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    let signed_headers = signed
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\nUNSIGNED-PAYLOAD",
+        method,
+        url.path(),
+        canonical_headers,
+        signed_headers
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+        timestamp,
+        scope,
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let key = [date.as_str(), region, "s3", "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_key).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    let signature = hmac(&key, string_to_sign.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    headers.push((
+        "authorization".to_string(),
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed_headers, signature
+        ),
+    ));
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn gcs_blob(client: &Client, bucket: &str, key: &str) -> anyhow::Result<Blob> {
+    let auth = match gcs_token(client)? {
+        Some(token) => Auth::Bearer(token),
+        None => {
+            tracing::debug!("no Google credentials found, reading the object anonymously");
+            Auth::Anonymous
+        }
+    };
+
+    Ok(Blob {
+        url: Url::parse(&format!(
+            "https://storage.googleapis.com/{}/{}",
+            bucket,
+            utf8_percent_encode(key, KEY)
+        ))?,
+        auth,
+        headers: Vec::new(),
+        range_header: "range",
+    })
+}
+
+#[derive(Serialize)]
+struct JwtClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+// An access token from the application default credentials or the metadata server
+fn gcs_token(client: &Client) -> anyhow::Result<Option<String>> {
+    if let Ok(token) = env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+        return Ok(Some(token));
+    }
+
+    let path = match env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => gcloud_dir()
+            .map(|dir| dir.join("application_default_credentials.json"))
+            .filter(|path| path.is_file()),
+    };
+    if let Some(path) = path {
+        let content =
+            fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let credentials: Value =
+            serde_json::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let field = |name: &str| {
+            credentials[name]
+                .as_str()
+                .ok_or(anyhow!("{}: missing `{}`", path.display(), name))
+        };
+
+        let response = match field("type")? {
+            "authorized_user" => client
+                .post("https://oauth2.googleapis.com/token")
+                .form(&[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", field("client_id")?),
+                    ("client_secret", field("client_secret")?),
+                    ("refresh_token", field("refresh_token")?),
+                ])
+                .send()?,
+            "service_account" => {
+                let token_uri = credentials["token_uri"]
+                    .as_str()
+                    .unwrap_or("https://oauth2.googleapis.com/token");
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let claims = JwtClaims {
+                    iss: field("client_email")?,
+                    scope: GCS_SCOPE,
+                    aud: token_uri,
+                    iat: now,
+                    exp: now + 3600,
+                };
+                let key =
+                    jsonwebtoken::EncodingKey::from_rsa_pem(field("private_key")?.as_bytes())?;
+                let assertion = jsonwebtoken::encode(
+                    &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256),
+                    &claims,
+                    &key,
+                )?;
+                client
+                    .post(token_uri)
+                    .form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", assertion.as_str()),
+                    ])
+                    .send()?
+            }
+            kind => bail!(
+                "{}: unsupported credentials type '{}'",
+                path.display(),
+                kind
+            ),
+        };
+        return access_token(check(response)?).map(Some);
+    }
+
+    // VMs, Cloud Run and GKE
+    let host = env::var("GCE_METADATA_HOST").unwrap_or("metadata.google.internal".to_string());
+    let response = client
+        .get(format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+            host
+        ))
+        .header("metadata-flavor", "Google")
+        .timeout(METADATA_TIMEOUT)
+        .send();
+    match response.map(check) {
+        Ok(Ok(response)) => access_token(response).map(Some),
+        _ => Ok(None),
+    }
+}
+
+// Where gcloud keeps the application default credentials
+fn gcloud_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("CLOUDSDK_CONFIG") {
+        return Some(PathBuf::from(dir));
+    }
+    if cfg!(windows) {
+        return env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("gcloud"));
+    }
+
+    paths::home()
+        .ok()
+        .map(|home| home.join(".config").join("gcloud"))
+}
+
+fn access_token(response: Response) -> anyhow::Result<String> {
+    let value: Value = response.json()?;
+    value["access_token"]
+        .as_str()
+        .map(String::from)
+        .ok_or(anyhow!(
+            "The token endpoint answered without an access token"
+        ))
+}
+
+fn azure_blob(client: &Client, account: &str, key: &str) -> anyhow::Result<Blob> {
+    let connection = env::var("AZURE_STORAGE_CONNECTION_STRING")
+        .ok()
+        .map(|value| {
+            value
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect::<HashMap<_, _>>()
+        })
+        // a connection string for another account is of no use
+        .filter(|values| values.get("AccountName").is_none_or(|name| name == account))
+        .unwrap_or_default();
+
+    let endpoint = match connection.get("BlobEndpoint") {
+        Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+        None => format!(
+            "https://{}.blob.{}",
+            account,
+            connection
+                .get("EndpointSuffix")
+                .map(String::as_str)
+                .unwrap_or("core.windows.net")
+        ),
+    };
+    let mut url = Url::parse(&format!("{}/{}", endpoint, utf8_percent_encode(key, KEY)))?;
+    if url.path().trim_start_matches('/').split_once('/').is_none() {
+        bail!("Expected az://<account>/<container>/<blob>");
+    }
+
+    let account_key = env::var("AZURE_STORAGE_KEY")
+        .ok()
+        .filter(|_| {
+            env::var("AZURE_STORAGE_ACCOUNT")
+                .ok()
+                .is_none_or(|name| name == account)
+        })
+        .or(connection.get("AccountKey").cloned());
+    let sas = env::var("AZURE_STORAGE_SAS_TOKEN")
+        .ok()
+        .or(connection.get("SharedAccessSignature").cloned());
+
+    let auth = if let Some(sas) = sas {
+        url.set_query(Some(sas.trim_start_matches('?')));
+        Auth::Anonymous
+    } else if let Some(key) = account_key {
+        Auth::SharedKey {
+            account: account.to_string(),
+            key: STANDARD
+                .decode(key)
+                .map_err(|e| anyhow!("The Azure storage key is not valid base64: {}", e))?,
+        }
+    } else if let Some(token) = azure_token(client)? {
+        Auth::Bearer(token)
+    } else {
+        tracing::debug!("no Azure credentials found, reading the blob anonymously");
+        Auth::Anonymous
+    };
+
+    Ok(Blob {
+        url,
+        auth,
+        headers: vec![("x-ms-version".to_string(), AZURE_VERSION.to_string())],
+        range_header: "x-ms-range",
+    })
+}
+
+// A token of a service principal from the environment, or of the managed identity of the VM
+fn azure_token(client: &Client) -> anyhow::Result<Option<String>> {
+    let resource = "https://storage.azure.com";
+    if let (Ok(tenant), Ok(client_id), Ok(secret)) = (
+        env::var("AZURE_TENANT_ID"),
+        env::var("AZURE_CLIENT_ID"),
+        env::var("AZURE_CLIENT_SECRET"),
+    ) {
+        let response = client
+            .post(format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                tenant
+            ))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id.as_str()),
+                ("client_secret", secret.as_str()),
+                ("scope", &format!("{}/.default", resource)),
+            ])
+            .send()?;
+        return access_token(check(response)?).map(Some);
+    }
+
+    let response = client
+        .get("http://169.254.169.254/metadata/identity/oauth2/token")
+        .query(&[("api-version", "2018-02-01"), ("resource", resource)])
+        .header("metadata", "true")
+        .timeout(METADATA_TIMEOUT)
+        .send();
+    match response.map(check) {
+        Ok(Ok(response)) => access_token(response).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn sign_shared_key(
+    method: &Method,
+    url: &Url,
+    headers: &mut Vec<(String, String)>,
+    account: &str,
+    key: &[u8],
+    now: u64,
+) -> anyhow::Result<()> {
+    headers.push(("x-ms-date".to_string(), Utc::of(now).http_date()));
+
+    let mut signed = headers
+        .iter()
+        .filter(|(name, _)| name.starts_with("x-ms-"))
+        .collect::<Vec<_>>();
+    signed.sort();
+    let canonical_headers = signed
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    // the verb, eleven standard headers left empty, then the x-ms- ones and the resource
+    let string_to_sign = format!(
+        "{}{}{}/{}{}",
+        method,
+        "\n".repeat(12),
+        canonical_headers,
+        account,
+        url.path()
+    );
+    let signature = STANDARD.encode(hmac(key, string_to_sign.as_bytes()));
+
+    headers.push((
+        "authorization".to_string(),
+        format!("SharedKey {}:{}", account, signature),
+    ));
+
+    Ok(())
+}
+
+// A unix time as a UTC calendar date and time
+pub struct Utc {
+    pub year: i64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    // 0 is Sunday
+    pub weekday: u64,
+}
+impl Utc {
+    pub fn of(secs: u64) -> Self {
+        let days = secs / 86400;
+        let seconds = secs % 86400;
+
+        // days to civil date, counting from 0000-03-01 so the leap day ends the year
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u64;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            minute: seconds % 3600 / 60,
+            second: seconds % 60,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 4) % 7,
+        }
+    }
+
+    // e.g. 2026-10-14 19:29:00
+    pub fn timestamp(&self) -> String {
+        format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    // e.g. 2026-10-14T19:29:00Z
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+
+    // e.g. Wed, 14 Oct 2026 19:29:00 GMT
+    fn http_date(&self) -> String {
+        const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[self.weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
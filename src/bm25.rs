@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+// Usual BM25 parameters: term frequency saturation and length normalization
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// Lowercase words, keeping identifiers such as `ERR_CONN_RESET` or `E1234` whole
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// Score every document against the query, returning the indexes of the matching ones, best first
+pub fn rank(query: &str, documents: &[&str]) -> Vec<(usize, f32)> {
+    let mut terms = tokenize(query);
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() || documents.is_empty() {
+        return Vec::new();
+    }
+
+    let tokenized = documents
+        .iter()
+        .map(|document| tokenize(document))
+        .collect::<Vec<_>>();
+    let average_len = tokenized.iter().map(Vec::len).sum::<usize>() as f32 / tokenized.len() as f32;
+
+    // number of documents each query term appears in
+    let mut document_frequency = HashMap::new();
+    for tokens in &tokenized {
+        for term in &terms {
+            if tokens.contains(term) {
+                *document_frequency.entry(term.as_str()).or_insert(0usize) += 1;
+            }
+        }
+    }
+
+    let total = tokenized.len() as f32;
+    let mut scores = tokenized
+        .iter()
+        .enumerate()
+        .filter_map(|(index, tokens)| {
+            let len = tokens.len() as f32;
+            let score = terms
+                .iter()
+                .filter_map(|term| {
+                    let frequency = tokens.iter().filter(|token| *token == term).count() as f32;
+                    if frequency == 0.0 {
+                        return None;
+                    }
+                    let n = document_frequency[term.as_str()] as f32;
+                    let idf = ((total - n + 0.5) / (n + 0.5) + 1.0).ln();
+                    Some(
+                        idf * frequency * (K1 + 1.0)
+                            / (frequency + K1 * (1.0 - B + B * len / average_len.max(1.0))),
+                    )
+                })
+                .sum::<f32>();
+            (score > 0.0).then_some((index, score))
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    scores
+}
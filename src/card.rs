@@ -0,0 +1,258 @@
+use crate::error::{ErrorKind, Tag};
+use crate::hf;
+use crate::models;
+use anyhow::{anyhow, bail};
+use console::{style, StyledObject, Term};
+use std::{
+    env,
+    io::{self, IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+// The repository of a model given as `owner/name`, a Hugging Face url, or the file name of a
+// model downloaded from one
+fn repo(model: &str) -> anyhow::Result<String> {
+    if let Some(file) = hf::parse_url(model) {
+        return Ok(file.repo);
+    }
+    if let Some(repo) = model
+        .strip_prefix("https://huggingface.co/")
+        .or(model.strip_prefix("hf://"))
+    {
+        let mut parts = repo.split('/');
+        if let (Some(owner), Some(name)) = (parts.next(), parts.next()) {
+            return Ok(format!("{}/{}", owner, name));
+        }
+    }
+    let manifest = models::manifest()?;
+    let name = model.rsplit(['/', '\\']).next().unwrap_or(model);
+    for key in [name.to_string(), format!("{}.gguf", name)] {
+        if let Some(file) = manifest
+            .get(&key)
+            .and_then(|entry| hf::parse_url(&entry.url))
+        {
+            return Ok(file.repo);
+        }
+    }
+    if model.split('/').count() == 2 && !model.ends_with(".gguf") {
+        return Ok(model.to_string());
+    }
+
+    bail!(
+        "Cannot tell the Hugging Face repository of {}, give it as OWNER/NAME",
+        model
+    )
+}
+
+// Show the model card of the repository, through the pager when it does not fit the terminal
+pub fn command_card(model: String, no_pager: bool) -> anyhow::Result<()> {
+    let repo = repo(&model)?;
+    let readme = reqwest::blocking::get(format!(
+        "https://huggingface.co/{}/resolve/main/README.md",
+        repo
+    ))?
+    .error_for_status()
+    .map_err(|e| anyhow!("{} has no model card: {}", repo, e))
+    .tag(ErrorKind::Api)?
+    .text()?;
+    let rendered = render(&readme, io::stdout().is_terminal());
+
+    let term = Term::stdout();
+    let fits = rendered.lines().count() < term.size().0 as usize;
+    if no_pager || !term.is_term() || fits {
+        print!("{}", rendered);
+        return Ok(());
+    }
+    page(&rendered)
+}
+
+// Hand the text to $PAGER, `less -R` keeping the colors, printing it when there is none
+fn page(text: &str) -> anyhow::Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or("less -R".to_string());
+    let mut words = pager.split_whitespace();
+    let program = words.next().unwrap_or("less");
+    let child = Command::new(program)
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        print!("{}", text);
+        return Ok(());
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // the pager closing early is not an error
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()?;
+
+    Ok(())
+}
+
+// Markdown as styled terminal text: the front matter is summed up, headings, emphasis, code
+// and quotes are styled, links keep their url, html tags are dropped
+pub fn render(markdown: &str, colors: bool) -> String {
+    let styled = |text: String, f: Paint| paint(text, colors, f);
+
+    let mut out = String::new();
+    let mut lines = markdown.lines().peekable();
+    // YAML front matter, only its license is of interest
+    if lines.peek().map(|line| line.trim()) == Some("---") {
+        lines.next();
+        for line in lines.by_ref() {
+            if line.trim() == "---" {
+                break;
+            }
+            if let Some(license) = line.strip_prefix("license:") {
+                out.push_str(&styled(format!("License: {}", license.trim()), |s| s.dim()));
+                out.push('\n');
+            }
+        }
+    }
+
+    let mut in_code = false;
+    for line in lines {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            out.push_str(&styled(format!("    {}", line), |s| s.cyan()));
+            out.push('\n');
+            continue;
+        }
+
+        let line = strip_html(line);
+        let trimmed = line.trim_start();
+        let rendered = if let Some(heading) = heading(trimmed) {
+            styled(inline(heading, false), |s| s.bold().underlined())
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            format!("│ {}", styled(inline(quote.trim(), colors), |s| s.italic()))
+        } else if let Some(item) = trimmed
+            .strip_prefix("- ")
+            .or(trimmed.strip_prefix("* "))
+            .or(trimmed.strip_prefix("+ "))
+        {
+            let indent = &line[..line.len() - trimmed.len()];
+            format!("{}• {}", indent, inline(item, colors))
+        } else if trimmed.chars().all(|c| "-*_ ".contains(c)) && trimmed.len() >= 3 {
+            "─".repeat(40)
+        } else {
+            inline(&line, colors)
+        };
+        out.push_str(&rendered);
+        out.push('\n');
+    }
+
+    // html blocks leave runs of blank lines behind
+    while out.contains("\n\n\n") {
+        out = out.replace("\n\n\n", "\n\n");
+    }
+    out
+}
+
+type Paint = fn(StyledObject<String>) -> StyledObject<String>;
+
+// The text styled, unless colors are off as when writing to a file
+fn paint(text: String, colors: bool, f: Paint) -> String {
+    match colors {
+        true => f(style(text)).force_styling(true).to_string(),
+        false => text,
+    }
+}
+
+fn heading(line: &str) -> Option<&str> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    match level {
+        1..=6 => line[level..].strip_prefix(' ').map(str::trim),
+        _ => None,
+    }
+}
+
+fn strip_html(line: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+// Emphasis, code spans, images and links of a line
+fn inline(text: &str, colors: bool) -> String {
+    let mut out = String::new();
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    // the text up to the closing delimiter, and the index after it
+    let until = |from: usize, delimiter: &str| -> Option<(String, usize)> {
+        let rest = chars[from..].iter().collect::<String>();
+        let end = rest.find(delimiter)?;
+        let inner = rest[..end].to_string();
+        Some((
+            inner.clone(),
+            from + inner.chars().count() + delimiter.chars().count(),
+        ))
+    };
+    let paint = |text: String, f: Paint| paint(text, colors, f);
+
+    while i < chars.len() {
+        let rest = &chars[i..];
+        let starts = |s: &str| rest.iter().take(s.len()).copied().eq(s.chars());
+        if starts("`") {
+            if let Some((code, next)) = until(i + 1, "`") {
+                out.push_str(&paint(code, |s| s.cyan()));
+                i = next;
+                continue;
+            }
+        }
+        if starts("**") || starts("__") {
+            let delimiter = if starts("**") { "**" } else { "__" };
+            if let Some((bold, next)) = until(i + 2, delimiter) {
+                out.push_str(&paint(bold, |s| s.bold()));
+                i = next;
+                continue;
+            }
+        }
+        if (starts("*") || starts("_")) && rest.get(1).is_some_and(|c| !c.is_whitespace()) {
+            let delimiter = if starts("*") { "*" } else { "_" };
+            // snake_case words are not emphasis
+            let inside_word = i > 0 && chars[i - 1].is_alphanumeric();
+            if !inside_word {
+                if let Some((italic, next)) = until(i + 1, delimiter) {
+                    out.push_str(&paint(italic, |s| s.italic()));
+                    i = next;
+                    continue;
+                }
+            }
+        }
+        let image = starts("![");
+        if image || starts("[") {
+            let open = if image { i + 2 } else { i + 1 };
+            if let Some((label, after_label)) = until(open, "](") {
+                if let Some((url, next)) = until(after_label, ")") {
+                    let label = match image {
+                        true => format!("[image: {}]", label),
+                        false => label,
+                    };
+                    out.push_str(&paint(label, |s| s.underlined()));
+                    if !image && !url.starts_with('#') {
+                        out.push_str(&paint(format!(" ({})", url), |s| s.dim()));
+                    }
+                    i = next;
+                    continue;
+                }
+            }
+        }
+        out.push(chars[i]);
+        i += 1;
+    }
+    out
+}
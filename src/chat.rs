@@ -0,0 +1,522 @@
+use crate::attachment::{self, Attachment, AttachmentArgs};
+use crate::client::{ChatRequest, Client, ClientArgs, Message, SamplingArgs, Throughput};
+use crate::context::{Retriever, NOT_FOUND_REPLY};
+use crate::error::{fail, ErrorKind};
+use crate::keys::{self, EditMode};
+use crate::overflow::{self, ContextArgs, Fitted, OverflowPolicy};
+use crate::preset::{self, Preset};
+use crate::repl::LineEditor;
+use crate::throughput::Session;
+use crate::tool::{ToolArgs, Tools};
+use anyhow::anyhow;
+use clap::{Args, ValueEnum};
+use console::style;
+use serde_json::json;
+use std::{
+    fs,
+    io::{self, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+};
+
+// Stop following tool calls when the model keeps calling tools without answering
+const MAX_TOOL_ROUNDS: usize = 8;
+const DEFAULT_JSON_RETRIES: usize = 2;
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct OutputArgs {
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "File the reply is written to rather than printed, with --input the JSONL file the replies are appended to, the rows already there are skipped when run again",
+        value_name = "FILE"
+    )]
+    pub output: Option<PathBuf>,
+    #[arg(
+        long = "format",
+        help = "How the reply is written: the text alone, JSON with the model, parameters and timing, or Markdown with the prompt",
+        value_name = "FORMAT",
+        default_value = "text",
+        conflicts_with = "input"
+    )]
+    pub format: OutputFormat,
+    #[arg(
+        long = "json-output",
+        help = "Ask the model for a reply in JSON and check that it is, asking again when it is not",
+        conflicts_with = "input"
+    )]
+    pub json_output: bool,
+    #[arg(
+        long = "json-retries",
+        help = "Times the model is asked again for valid JSON before failing",
+        value_name = "N",
+        default_value_t = DEFAULT_JSON_RETRIES,
+        requires = "json_output"
+    )]
+    pub json_retries: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Markdown,
+}
+
+// Send a single prompt to the api-server and print the reply
+pub fn command_run(
+    client_args: ClientArgs,
+    sampling: SamplingArgs,
+    prompt: String,
+    system_prompt: Option<String>,
+    attachments: Vec<Attachment>,
+    retriever: Option<Retriever>,
+    output: OutputArgs,
+) -> anyhow::Result<()> {
+    let client = Client::new(&client_args.base_url)?;
+    let question = prompt.clone();
+
+    let prompt = match &retriever {
+        Some(retriever) => match retriever.augment(&prompt)? {
+            Some(augmented) => {
+                // keep stdout for the reply
+                eprintln!("{}", style(describe_sources(&augmented.sources)).dim());
+                augmented.prompt
+            }
+            None => {
+                println!("{}", NOT_FOUND_REPLY);
+                return Ok(());
+            }
+        },
+        None => prompt,
+    };
+
+    let mut messages = Vec::new();
+    if let Some(system_prompt) = &system_prompt {
+        messages.push(Message::new("system", system_prompt.as_str()));
+    }
+    messages.push(attachment::user_message(&prompt, &attachments));
+
+    let mut request = ChatRequest {
+        model: client_args.model_name,
+        messages,
+        stream: true,
+        sampling,
+        tools: None,
+        response_format: output.json_output.then(|| json!({ "type": "json_object" })),
+    };
+    // the reply streams to the terminal unless it is written in another form, or checked first
+    let plain = output.output.is_none() && output.format == OutputFormat::Text;
+    let mut session = Session::new("run");
+    let (reply, throughput) = match output.json_output {
+        true => json_reply(&client, &mut request, output.json_retries, &mut session)?,
+        false if plain => client.chat_stream(&request, print_token)?,
+        false => client.chat_stream(&request, |_| ControlFlow::Continue(()))?,
+    };
+    session.add(&throughput);
+    if plain {
+        match output.json_output {
+            true => println!("{}", reply),
+            false => println!(),
+        }
+        session.finish();
+        return Ok(());
+    }
+
+    let content = match output.format {
+        OutputFormat::Text => format!("{}\n", reply),
+        OutputFormat::Json => format!(
+            "{}\n",
+            serde_json::to_string_pretty(&json!({
+                "model": throughput.model,
+                "prompt": question,
+                "system_prompt": system_prompt,
+                "reply": reply,
+                "params": request.sampling,
+                "usage": {
+                    "prompt_tokens": throughput.prompt_tokens,
+                    "completion_tokens": throughput.completion_tokens,
+                },
+                "timing": {
+                    "secs": throughput.elapsed.as_secs_f64(),
+                    "first_token_secs": throughput.first_token.map(|first| first.as_secs_f64()),
+                    "tokens_per_sec": session.tokens_per_sec(),
+                },
+            }))?
+        ),
+        OutputFormat::Markdown => format!(
+            "## Prompt\n\n{}\n\n## Reply\n\n{}\n\n---\n\n*{}, {}, {:.1}s*\n",
+            question.trim(),
+            reply.trim(),
+            throughput.model.as_deref().unwrap_or("unknown model"),
+            session.summary(),
+            throughput.elapsed.as_secs_f64()
+        ),
+    };
+    match &output.output {
+        Some(path) => {
+            fs::write(path, content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            eprintln!("Wrote the reply to {}", path.display());
+        }
+        None => print!("{}", content),
+    }
+    session.finish();
+
+    Ok(())
+}
+
+// A reply that is valid JSON, telling the model what was wrong and asking again up to `retries`
+// times. Code fences around it are taken off.
+fn json_reply(
+    client: &Client,
+    request: &mut ChatRequest,
+    retries: usize,
+    session: &mut Session,
+) -> anyhow::Result<(String, Throughput)> {
+    for attempt in 0..=retries {
+        let (reply, throughput) = client.chat_stream(request, |_| ControlFlow::Continue(()))?;
+        let json = strip_fences(&reply);
+        let e = match serde_json::from_str::<serde_json::Value>(json) {
+            Ok(_) => return Ok((json.to_string(), throughput)),
+            Err(e) => e,
+        };
+        if attempt == retries {
+            let asked = match retries {
+                0 => String::new(),
+                retries => format!(", asked again {} times", retries),
+            };
+            return Err(fail(
+                ErrorKind::Api,
+                anyhow!("The reply is not valid JSON{}: {}", asked, e),
+            ));
+        }
+        // the replies thrown away count too
+        session.add(&throughput);
+        eprintln!(
+            "{}",
+            style(format!("The reply is not valid JSON ({}), asking again", e)).dim()
+        );
+        request
+            .messages
+            .push(Message::new("assistant", reply.as_str()));
+        request.messages.push(Message::new(
+            "user",
+            format!(
+                "That is not valid JSON: {}. Reply with the JSON alone, nothing around it.",
+                e
+            )
+            .as_str(),
+        ));
+    }
+    unreachable!("the last attempt returns")
+}
+
+// The text inside a ```json fence, or all of it
+fn strip_fences(reply: &str) -> &str {
+    let reply = reply.trim();
+    let Some(inner) = reply.strip_prefix("```") else {
+        return reply;
+    };
+    let inner = inner.strip_suffix("```").unwrap_or(inner);
+    // the language after the opening fence
+    let inner = match inner.split_once('\n') {
+        Some((language, rest)) if !language.trim().contains(' ') => rest,
+        _ => inner,
+    };
+    inner.trim()
+}
+
+// Interactive conversation with the api-server, `given` holding the model name, system prompt
+// and sampling parameters of the command line
+pub fn command_chat(
+    client_args: ClientArgs,
+    given: Preset,
+    preset: Option<(String, Preset)>,
+    attachment_args: AttachmentArgs,
+    tool_args: ToolArgs,
+    retriever: Option<Retriever>,
+    context: ContextArgs,
+) -> anyhow::Result<()> {
+    // attachments are sent along with the first message of the conversation
+    let mut attachments = attachment::load(&attachment_args, context.context_size)?;
+    let tools = Tools::load(&tool_args)?;
+    let client = Client::new(&client_args.base_url)?;
+
+    let keys = keys::load()?;
+    println!(
+        "{}",
+        style(format!(
+            "Chatting with the model, type /exit to quit or /clear to start over, {} for a new line, {} stops a reply",
+            keys::first(&keys.chat.newline),
+            keys::first(&keys.chat.cancel)
+        ))
+        .dim()
+    );
+    for attachment in &attachments {
+        println!(
+            "{} {}",
+            style("Attached:").dim(),
+            attachment::describe(attachment)
+        );
+    }
+    if let Some(tools) = &tools {
+        println!("{} {}", style("Tools:").dim(), tools.names().join(", "));
+    }
+    if retriever.is_some() {
+        println!(
+            "{}",
+            style("Answering with the documents of the collection").dim()
+        );
+    }
+
+    // the preset fills what the command line leaves unset, until `/preset` picks another
+    let mut setup = match &preset {
+        Some((name, preset)) => {
+            println!("{}", style(format!("Using the preset '{}'", name)).dim());
+            preset.under(&given)
+        }
+        None => given.clone(),
+    };
+    let mut messages = Vec::new();
+    if let Some(system_prompt) = &setup.system_prompt {
+        messages.push(Message::new("system", system_prompt.as_str()));
+    }
+
+    let mut editor = LineEditor::new(&keys)?;
+    let mut session = Session::new("chat");
+    loop {
+        let Some(input) = editor.read(&style("You").green().bold().to_string())? else {
+            break;
+        };
+
+        match input.trim() {
+            "/exit" | "/quit" => break,
+            "/clear" => {
+                messages.retain(|message| message.role == "system");
+                println!("{}", style("Conversation cleared").dim());
+                continue;
+            }
+            "/vi" | "/emacs" => {
+                let mode = match input.trim() {
+                    "/vi" => EditMode::Vi,
+                    _ => EditMode::Emacs,
+                };
+                editor.set_edit_mode(mode);
+                println!(
+                    "{}",
+                    style(format!("Editing like {}", &input.trim()[1..])).dim()
+                );
+                continue;
+            }
+            "" => continue,
+            command if command.split_whitespace().next() == Some("/preset") => {
+                match command.split_whitespace().nth(1) {
+                    Some(name) => match preset::load(name) {
+                        Ok(preset) => {
+                            // the system prompt comes first, ahead of any summary of the turns
+                            if setup.system_prompt.is_some() {
+                                messages.remove(0);
+                            }
+                            setup = preset.under(&given);
+                            if let Some(system_prompt) = &setup.system_prompt {
+                                messages.insert(0, Message::new("system", system_prompt.as_str()));
+                            }
+                            println!("{}", style(format!("Using the preset '{}'", name)).dim());
+                            if preset.model.is_some() || preset.prompt_template.is_some() {
+                                println!(
+                                    "{}",
+                                    style(format!(
+                                        "Its model and prompt template are loaded with `gaia start --preset {}`",
+                                        name
+                                    ))
+                                    .dim()
+                                );
+                            }
+                        }
+                        Err(e) => eprintln!("{} {}", style("Error:").red(), e),
+                    },
+                    None => println!("{}", style("Pick a preset with /preset NAME").dim()),
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        messages.push(attachment::user_message(&input, &attachments));
+        let budget = overflow::budget(context.context_size, setup.params.max_tokens);
+        match fit(
+            &client,
+            setup.model_name.as_deref(),
+            &mut messages,
+            budget,
+            context.overflow,
+        ) {
+            Ok(Fitted::Whole) => {}
+            Ok(Fitted::Truncated(dropped)) => println!(
+                "{}",
+                style(format!(
+                    "Dropped the {} oldest messages to fit the context",
+                    dropped
+                ))
+                .dim()
+            ),
+            Ok(Fitted::Summarized(summarized)) => println!(
+                "{}",
+                style(format!(
+                    "Summarized the {} oldest messages to fit the context",
+                    summarized
+                ))
+                .dim()
+            ),
+            Err(e) => {
+                eprintln!("{} {}", style("Error:").red(), e);
+                messages.pop();
+                continue;
+            }
+        }
+        let turn = messages.len() - 1;
+
+        // the context is only sent with its question, the conversation keeps the question alone
+        let mut request_messages = messages.clone();
+        if let Some(retriever) = &retriever {
+            match retriever.augment(&input) {
+                Ok(Some(augmented)) => {
+                    println!("{}", style(describe_sources(&augmented.sources)).dim());
+                    request_messages[turn] =
+                        attachment::user_message(&augmented.prompt, &attachments);
+                }
+                Ok(None) => {
+                    println!("{} {}", style("Assistant:").cyan().bold(), NOT_FOUND_REPLY);
+                    messages.push(Message::new("assistant", NOT_FOUND_REPLY));
+                    attachments.clear();
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("{} {}", style("Error:").red(), e);
+                    messages.truncate(turn);
+                    continue;
+                }
+            }
+        }
+        attachments.clear();
+
+        let mut request = ChatRequest {
+            model: setup.model_name.clone(),
+            messages: request_messages,
+            stream: true,
+            sampling: setup.params.clone(),
+            tools: None,
+            response_format: None,
+        };
+        let result = match &tools {
+            Some(tools) => reply_with_tools(&client, &mut request, tools, &mut session),
+            None => {
+                print!("{} ", style("Assistant:").cyan().bold());
+                let watch = editor.watch();
+                let reply = client.chat_stream(&request, |token| watch.print(token));
+                let cancelled = watch.cancelled();
+                drop(watch);
+                println!();
+                if cancelled {
+                    println!("{}", style("Cancelled, the reply is kept as it is").dim());
+                }
+                reply.map(|(reply, throughput)| {
+                    session.add(&throughput);
+                    vec![Message::new("assistant", reply)]
+                })
+            }
+        };
+        match result {
+            Ok(replies) => messages.extend(replies),
+            Err(e) => {
+                eprintln!("{} {}", style("Error:").red(), e);
+                messages.truncate(turn);
+            }
+        }
+    }
+    session.finish();
+
+    Ok(())
+}
+
+// Fit the conversation in the budget as the policy says, the model summarizing it when asked to
+fn fit(
+    client: &Client,
+    model: Option<&str>,
+    messages: &mut Vec<Message>,
+    budget: u64,
+    policy: OverflowPolicy,
+) -> anyhow::Result<Fitted> {
+    let mut conversation = serde_json::to_value(&*messages)?
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let fitted = overflow::fit(&mut conversation, budget, policy, |request| {
+        let request = ChatRequest {
+            model: model.map(String::from),
+            messages: serde_json::from_value(json!(request))?,
+            stream: false,
+            sampling: SamplingArgs::default(),
+            tools: None,
+            response_format: None,
+        };
+        Ok(client.chat(&request)?.0.text())
+    })?;
+    if fitted != Fitted::Whole {
+        *messages = serde_json::from_value(json!(conversation))?;
+    }
+
+    Ok(fitted)
+}
+
+// Get the reply of the model, running the tools it calls until it answers.
+// Returns the messages to append to the conversation.
+fn reply_with_tools(
+    client: &Client,
+    request: &mut ChatRequest,
+    tools: &Tools,
+    session: &mut Session,
+) -> anyhow::Result<Vec<Message>> {
+    request.stream = false;
+    request.tools = Some(tools.definitions());
+
+    let mut replies = Vec::new();
+    for _ in 0..MAX_TOOL_ROUNDS {
+        let (reply, throughput) = client.chat(request)?;
+        session.add(&throughput);
+        let calls = reply.tool_calls.clone().unwrap_or_default();
+        request.messages.push(reply.clone());
+        replies.push(reply.clone());
+
+        if calls.is_empty() {
+            println!("{} {}", style("Assistant:").cyan().bold(), reply.text());
+            return Ok(replies);
+        }
+
+        for call in calls {
+            let result = tools.call(&call)?;
+            let message = Message::tool_result(&call.id, result);
+            request.messages.push(message.clone());
+            replies.push(message);
+        }
+    }
+
+    anyhow::bail!(
+        "The model is still calling tools after {} rounds",
+        MAX_TOOL_ROUNDS
+    )
+}
+
+fn describe_sources(sources: &[String]) -> String {
+    match sources.is_empty() {
+        true => "No relevant documents found".to_string(),
+        false => format!("Sources: {}", sources.join(", ")),
+    }
+}
+
+fn print_token(token: &str) -> ControlFlow<()> {
+    print!("{}", token);
+    let _ = io::stdout().flush();
+    ControlFlow::Continue(())
+}
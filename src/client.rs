@@ -0,0 +1,517 @@
+use crate::error::{fail, ErrorKind};
+use anyhow::{anyhow, bail};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    io::{BufRead, BufReader},
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_BASE_URL: &str = "http://localhost:8080/v1";
+
+#[derive(Debug, Clone, Args)]
+pub struct ClientArgs {
+    #[arg(
+        long = "base-url",
+        help = "Base url of the OpenAI-compatible api-server",
+        default_value = DEFAULT_BASE_URL
+    )]
+    pub base_url: String,
+    #[arg(
+        long = "model-name",
+        help = "Name of the model to request from the server"
+    )]
+    pub model_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Args, Serialize, Deserialize)]
+pub struct SamplingArgs {
+    #[arg(long = "temperature", help = "Sampling temperature")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[arg(long = "top-p", help = "Nucleus sampling probability")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[arg(long = "max-tokens", help = "Maximum number of tokens to generate")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    // the same seed with the same parameters gives the same reply, at temperature 0 always
+    #[arg(
+        long = "seed",
+        help = "Seed of the sampling, to reproduce a reply with the same parameters"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[arg(
+        long = "logit-bias",
+        help = "Bias of a token from -100 to 100, -100 banning it, as TOKEN:WEIGHT or @FILE with one per line; TOKEN is an id, or text for servers that tokenize it",
+        value_name = "TOKEN:WEIGHT",
+        value_parser = parse_logit_bias
+    )]
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_logit_bias",
+        deserialize_with = "deserialize_logit_bias"
+    )]
+    pub logit_bias: Vec<LogitBias>,
+}
+impl SamplingArgs {
+    pub fn is_unset(&self) -> bool {
+        self.temperature.is_none()
+            && self.top_p.is_none()
+            && self.max_tokens.is_none()
+            && self.seed.is_none()
+            && self.logit_bias.is_empty()
+    }
+
+    // Fill the parameters not set here from `defaults`
+    pub fn or(self, defaults: &SamplingArgs) -> SamplingArgs {
+        SamplingArgs {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            seed: self.seed.or(defaults.seed),
+            logit_bias: match self.logit_bias.is_empty() {
+                true => defaults.logit_bias.clone(),
+                false => self.logit_bias,
+            },
+        }
+    }
+}
+
+// Biases of one --logit-bias, several when read from a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogitBias(pub Vec<(String, f64)>);
+
+// `TOKEN:WEIGHT`, or `@FILE` with one per line, # starting a comment
+pub fn parse_logit_bias(arg: &str) -> anyhow::Result<LogitBias> {
+    let parse = |entry: &str| {
+        // the text of a token may hold a colon, the weight cannot
+        let (token, weight) = entry
+            .rsplit_once(':')
+            .ok_or(anyhow!("Expected TOKEN:WEIGHT, got '{}'", entry))?;
+        let weight = weight
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("The weight of '{}' must be a number", entry))?;
+        if !(-100.0..=100.0).contains(&weight) {
+            bail!("The weight of '{}' must be from -100 to 100", entry);
+        }
+        if token.is_empty() {
+            bail!("No token in '{}'", entry);
+        }
+        Ok((token.to_string(), weight))
+    };
+    let Some(path) = arg.strip_prefix('@') else {
+        return Ok(LogitBias(vec![parse(arg)?]));
+    };
+
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        // spaces before a token are part of it
+        .map(|line| parse(line.trim_end()).map_err(|e| anyhow!("{}: {}", path, e)))
+        .collect::<anyhow::Result<_>>()
+        .map(LogitBias)
+}
+
+// As the `logit_bias` object of OpenAI, by token, the last weight given for a token winning
+fn serialize_logit_bias<S: serde::Serializer>(
+    biases: &[LogitBias],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    biases
+        .iter()
+        .flat_map(|bias| bias.0.iter().cloned())
+        .collect::<std::collections::BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+fn deserialize_logit_bias<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<LogitBias>, D::Error> {
+    let biases = std::collections::BTreeMap::<String, f64>::deserialize(deserializer)?;
+    Ok(vec![LogitBias(biases.into_iter().collect())])
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    // absent when the assistant replies with tool calls only
+    #[serde(default)]
+    pub content: Option<Content>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+impl Message {
+    pub fn new(role: &str, content: impl Into<Content>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: Some(content.into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn tool_result(tool_call_id: &str, content: impl Into<Content>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Self::new("tool", content)
+        }
+    }
+
+    // Text of the content, ignoring any non-text parts
+    pub fn text(&self) -> String {
+        match &self.content {
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON-encoded arguments, as sent by the model
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    pub stream: bool,
+    #[serde(flatten)]
+    pub sampling: SamplingArgs,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+    // e.g. {"type": "json_object"} for replies in JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+// Tokens and timing of one reply
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    // as the server names it
+    pub model: Option<String>,
+    // unknown when the server does not report its usage
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: u64,
+    // until the first token arrived, streamed replies only
+    pub first_token: Option<Duration>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    message: Message,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    model: Option<String>,
+    // sent with the last chunk when asked for with `stream_options`
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: Delta,
+}
+
+#[derive(Debug, Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingsRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    input: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct RerankRequest<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    query: &'a str,
+    documents: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+pub struct Client {
+    base_url: String,
+    http: reqwest::blocking::Client,
+}
+impl Client {
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        // generation may take much longer than reqwest's default timeout
+        let http = reqwest::blocking::Client::builder().timeout(None).build()?;
+        Ok(Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http,
+        })
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    fn post(
+        &self,
+        path: &str,
+        body: &impl Serialize,
+    ) -> anyhow::Result<reqwest::blocking::Response> {
+        let url = self.url(path);
+        let _span = tracing::debug_span!("http", method = "POST", %url).entered();
+        let started = Instant::now();
+        let response = self.http.post(&url).json(body).send()?;
+        tracing::debug!(
+            status = response.status().as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "response"
+        );
+
+        check_status(response)
+    }
+
+    // Send a chat request and return the reply of the assistant
+    pub fn chat(&self, request: &ChatRequest) -> anyhow::Result<(Message, Throughput)> {
+        let started = Instant::now();
+        let response = self.post("chat/completions", request)?;
+        let mut response: ChatResponse = response.json()?;
+        if response.choices.is_empty() {
+            bail!("The server returned no choices");
+        }
+        let throughput = Throughput {
+            model: response.model,
+            prompt_tokens: response.usage.as_ref().map(|usage| usage.prompt_tokens),
+            completion_tokens: response
+                .usage
+                .map(|usage| usage.completion_tokens)
+                .unwrap_or_default(),
+            first_token: None,
+            elapsed: started.elapsed(),
+        };
+
+        Ok((response.choices.remove(0).message, throughput))
+    }
+
+    // Compute the embeddings of the inputs, in the same order
+    pub fn embeddings(
+        &self,
+        model: Option<&str>,
+        input: &[String],
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let response = self.post("embeddings", &EmbeddingsRequest { model, input })?;
+        let mut response: EmbeddingsResponse = response.json()?;
+        if response.data.len() != input.len() {
+            bail!(
+                "The server returned {} embeddings for {} inputs",
+                response.data.len(),
+                input.len()
+            );
+        }
+        response.data.sort_by_key(|embedding| embedding.index);
+
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+
+    // Relevance of each document to the query as scored by a reranker model, in input order
+    pub fn rerank(
+        &self,
+        model: Option<&str>,
+        query: &str,
+        documents: &[String],
+    ) -> anyhow::Result<Vec<f32>> {
+        let response = self.post(
+            "rerank",
+            &RerankRequest {
+                model,
+                query,
+                documents,
+            },
+        )?;
+        let response: RerankResponse = response.json()?;
+
+        let mut scores = vec![f32::NEG_INFINITY; documents.len()];
+        for result in response.results {
+            if let Some(score) = scores.get_mut(result.index) {
+                *score = result.relevance_score;
+            }
+        }
+
+        Ok(scores)
+    }
+
+    // Send a streaming chat request, calling `on_token` for every piece of text received
+    pub fn chat_stream(
+        &self,
+        request: &ChatRequest,
+        mut on_token: impl FnMut(&str) -> ControlFlow<()>,
+    ) -> anyhow::Result<(String, Throughput)> {
+        let started = Instant::now();
+        let mut body = serde_json::to_value(request)?;
+        body["stream_options"] = json!({ "include_usage": true });
+        let response = self.post("chat/completions", &body)?;
+
+        let mut reply = String::new();
+        let mut throughput = Throughput::default();
+        // servers that leave the usage out send about a token per chunk
+        let mut chunks = 0;
+        'stream: for line in BufReader::new(response).lines() {
+            let line = line?;
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+            if data == "[DONE]" {
+                break;
+            }
+
+            let chunk: ChatChunk = serde_json::from_str(data)
+                .map_err(|e| anyhow!("Invalid chunk from the server: {}", e))?;
+            throughput.model = chunk.model.or(throughput.model);
+            if let Some(usage) = chunk.usage {
+                throughput.prompt_tokens = Some(usage.prompt_tokens);
+                throughput.completion_tokens = usage.completion_tokens;
+            }
+            for choice in chunk.choices {
+                if let Some(token) = choice.delta.content {
+                    throughput
+                        .first_token
+                        .get_or_insert_with(|| started.elapsed());
+                    chunks += 1;
+                    let flow = on_token(&token);
+                    reply.push_str(&token);
+                    // dropping the response closes the connection, which stops the generation
+                    if flow.is_break() {
+                        break 'stream;
+                    }
+                }
+            }
+        }
+        if throughput.prompt_tokens.is_none() {
+            throughput.completion_tokens = chunks;
+        }
+        throughput.elapsed = started.elapsed();
+
+        Ok((reply, throughput))
+    }
+}
+
+fn check_status(
+    response: reqwest::blocking::Response,
+) -> anyhow::Result<reqwest::blocking::Response> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or_default();
+        return Err(fail(
+            ErrorKind::Api,
+            anyhow!("The server responded with {}: {}", status, body.trim()),
+        ));
+    }
+
+    Ok(response)
+}
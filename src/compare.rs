@@ -0,0 +1,326 @@
+use crate::client::{ChatRequest, Client, ClientArgs, Message, SamplingArgs};
+use crate::throughput::Session;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::{measure_text_width, style, Term};
+use std::{ops::ControlFlow, time::Instant};
+
+// Narrowest a column is made, below that the replies are printed one after the other
+const MIN_COLUMN: usize = 30;
+const JUDGE_PROMPT: &str = "You compare two answers to the same prompt. Reply with the letter of the better answer, A or B, alone on the first line, then explain why in one or two sentences.";
+
+#[derive(Debug, Clone, Args)]
+pub struct JudgeArgs {
+    #[arg(
+        long = "judge",
+        help = "Model asked which reply is better",
+        value_name = "MODEL"
+    )]
+    pub judge: Option<String>,
+    #[arg(
+        long = "judge-base-url",
+        help = "Base url of the server running the judge, defaults to --base-url",
+        requires = "judge"
+    )]
+    pub base_url: Option<String>,
+}
+
+// What one side of the comparison changes from the shared settings
+#[derive(Debug, Clone, Default)]
+pub struct Side {
+    base_url: Option<String>,
+    model: Option<String>,
+    sampling: SamplingArgs,
+}
+impl Side {
+    fn describe(&self, client: &ClientArgs) -> String {
+        let mut parts = vec![self
+            .model
+            .clone()
+            .or(client.model_name.clone())
+            .unwrap_or("default model".to_string())];
+        if let Some(base_url) = &self.base_url {
+            parts.push(base_url.clone());
+        }
+        if let Some(temperature) = self.sampling.temperature {
+            parts.push(format!("temperature {}", temperature));
+        }
+        if let Some(top_p) = self.sampling.top_p {
+            parts.push(format!("top-p {}", top_p));
+        }
+        if let Some(max_tokens) = self.sampling.max_tokens {
+            parts.push(format!("max-tokens {}", max_tokens));
+        }
+        if let Some(seed) = self.sampling.seed {
+            parts.push(format!("seed {}", seed));
+        }
+        parts.join(", ")
+    }
+}
+
+// e.g. `model=llama-q4,temperature=0.2`, the keys being model, base-url, temperature, top-p,
+// max-tokens and seed
+pub fn parse_side(arg: &str) -> anyhow::Result<Side> {
+    let mut side = Side::default();
+    for pair in arg.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or(anyhow!("Expected KEY=VALUE, got '{}'", pair))?;
+        let value = value.trim();
+        let number = || {
+            value
+                .parse::<f64>()
+                .map_err(|_| anyhow!("{} must be a number, got '{}'", key, value))
+        };
+        match key.trim() {
+            "model" => side.model = Some(value.to_string()),
+            "base-url" => side.base_url = Some(value.to_string()),
+            "temperature" => side.sampling.temperature = Some(number()?),
+            "top-p" => side.sampling.top_p = Some(number()?),
+            "max-tokens" => {
+                side.sampling.max_tokens = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("max-tokens must be a count, got '{}'", value))?,
+                )
+            }
+            "seed" => {
+                side.sampling.seed = Some(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow!("seed must be a whole number, got '{}'", value))?,
+                )
+            }
+            key => bail!(
+                "Unknown key '{}', use model, base-url, temperature, top-p, max-tokens or seed",
+                key
+            ),
+        }
+    }
+
+    Ok(side)
+}
+
+struct Reply {
+    text: String,
+    session: Session,
+    secs: f64,
+}
+
+fn ask(
+    side: &Side,
+    client_args: &ClientArgs,
+    sampling: &SamplingArgs,
+    messages: &[Message],
+) -> anyhow::Result<Reply> {
+    let client = Client::new(side.base_url.as_deref().unwrap_or(&client_args.base_url))?;
+    let request = ChatRequest {
+        model: side.model.clone().or(client_args.model_name.clone()),
+        messages: messages.to_vec(),
+        stream: true,
+        sampling: side.sampling.clone().or(sampling),
+        tools: None,
+        response_format: None,
+    };
+    let started = Instant::now();
+    let (text, throughput) = client.chat_stream(&request, |_| ControlFlow::Continue(()))?;
+    let mut session = Session::new("compare");
+    session.add(&throughput);
+
+    Ok(Reply {
+        text,
+        session,
+        secs: started.elapsed().as_secs_f64(),
+    })
+}
+
+// Send the prompt to both sides, one after the other so they do not slow each other down, and
+// print the replies side by side
+pub fn command_compare(
+    client_args: ClientArgs,
+    sampling: SamplingArgs,
+    prompt: String,
+    system_prompt: Option<String>,
+    a: Side,
+    b: Side,
+    judge: JudgeArgs,
+) -> anyhow::Result<()> {
+    let mut messages = Vec::new();
+    if let Some(system_prompt) = system_prompt {
+        messages.push(Message::new("system", system_prompt));
+    }
+    messages.push(Message::new("user", prompt.as_str()));
+
+    let mut replies = Vec::new();
+    for (label, side) in [("A", &a), ("B", &b)] {
+        eprintln!(
+            "{}",
+            style(format!(
+                "Asking {}: {}…",
+                label,
+                side.describe(&client_args)
+            ))
+            .dim()
+        );
+        replies.push(
+            ask(side, &client_args, &sampling, &messages)
+                .map_err(|e| anyhow!("{} ({}): {}", label, side.describe(&client_args), e))?,
+        );
+    }
+
+    let headers = [
+        format!("A: {}", a.describe(&client_args)),
+        format!("B: {}", b.describe(&client_args)),
+    ];
+    print_columns(&headers, [&replies[0].text, &replies[1].text]);
+    for (label, reply) in ["A", "B"].iter().zip(&replies) {
+        println!(
+            "{} {:.1}s, {}",
+            style(format!("{}:", label)).bold(),
+            reply.secs,
+            reply.session.summary()
+        );
+    }
+
+    if let Some(model) = &judge.judge {
+        let verdict = ask_judge(
+            model,
+            judge.base_url.as_deref().unwrap_or(&client_args.base_url),
+            &prompt,
+            &replies[0].text,
+            &replies[1].text,
+        )?;
+        println!();
+        println!(
+            "{} {}",
+            style(format!("Judge ({}):", model)).bold(),
+            verdict
+        );
+    }
+
+    Ok(())
+}
+
+// The judge's pick with its reason
+fn ask_judge(
+    judge: &str,
+    base_url: &str,
+    prompt: &str,
+    a: &str,
+    b: &str,
+) -> anyhow::Result<String> {
+    let client = Client::new(base_url)?;
+    let request = ChatRequest {
+        model: Some(judge.to_string()),
+        messages: vec![
+            Message::new("system", JUDGE_PROMPT),
+            Message::new(
+                "user",
+                format!(
+                    "Prompt:\n{}\n\nAnswer A:\n{}\n\nAnswer B:\n{}",
+                    prompt, a, b
+                ),
+            ),
+        ],
+        stream: false,
+        sampling: SamplingArgs {
+            temperature: Some(0.0),
+            ..SamplingArgs::default()
+        },
+        tools: None,
+        response_format: None,
+    };
+    let (reply, _) = client
+        .chat(&request)
+        .map_err(|e| anyhow!("The judge {} failed: {}", judge, e))?;
+    let verdict = reply.text();
+    let verdict = verdict.trim();
+    let pick = verdict
+        .lines()
+        .next()
+        .map(|line| line.trim().trim_matches(|c: char| !c.is_alphanumeric()))
+        .unwrap_or_default();
+    let reason = verdict.lines().skip(1).collect::<Vec<_>>().join(" ");
+
+    Ok(match pick.to_ascii_uppercase().as_str() {
+        "A" | "B" => format!("{} is better. {}", pick.to_ascii_uppercase(), reason.trim()),
+        _ => verdict.to_string(),
+    })
+}
+
+// Two texts in columns next to each other, wrapped to the width of the terminal
+fn print_columns(headers: &[String; 2], texts: [&String; 2]) {
+    let (_, width) = Term::stdout().size();
+    let column = (width as usize).saturating_sub(3) / 2;
+    if column < MIN_COLUMN {
+        for (header, text) in headers.iter().zip(texts) {
+            println!("{}", style(header).bold());
+            println!("{}\n", text.trim());
+        }
+        return;
+    }
+
+    let separator = style("│").dim();
+    let rule = "─".repeat(column);
+    let headers = headers.clone().map(|header| wrap(&header, column));
+    let texts = texts.map(|text| wrap(text.trim(), column));
+    for (i, rows) in [headers, texts].iter().enumerate() {
+        for row in 0..rows[0].len().max(rows[1].len()) {
+            let left = rows[0].get(row).map(String::as_str).unwrap_or_default();
+            let right = rows[1].get(row).map(String::as_str).unwrap_or_default();
+            let padding = column.saturating_sub(measure_text_width(left));
+            let left = match i {
+                0 => style(left).bold().to_string(),
+                _ => left.to_string(),
+            };
+            let right = match i {
+                0 => style(right).bold().to_string(),
+                _ => right.to_string(),
+            };
+            println!("{}{} {} {}", left, " ".repeat(padding), separator, right);
+        }
+        let joint = match i {
+            0 => "┼",
+            _ => "┴",
+        };
+        println!("{}", style(format!("{}─{}─{}", rule, joint, rule)).dim());
+    }
+}
+
+// Lines of at most `width` columns, broken between words where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let mut word = word.to_string();
+            // words longer than the column are cut
+            while measure_text_width(&word) > width {
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                let cut = word
+                    .char_indices()
+                    .nth(width)
+                    .map(|(i, _)| i)
+                    .unwrap_or(word.len());
+                lines.push(word[..cut].to_string());
+                word = word[cut..].to_string();
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let needed = measure_text_width(&line) + usize::from(!line.is_empty());
+            if !line.is_empty() && needed + measure_text_width(&word) > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&word);
+        }
+        lines.push(line);
+    }
+
+    lines
+}
@@ -0,0 +1,202 @@
+use crate::error::{ErrorKind, Tag};
+use crate::events;
+use crate::paths;
+use anyhow::{anyhow, bail};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Names of the node file read when none is given, in the config directory
+pub const DEFAULT_FILES: [&str; 3] = ["config.toml", "config.yaml", "config.yml"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+impl Format {
+    // Told apart by the extension, both formats mean the same
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => bail!(
+                "{}: unknown config format, use a .toml, .yaml or .yml file",
+                path.display()
+            ),
+        }
+    }
+}
+
+// Read a TOML or YAML file, errors give the line and column of the problem
+pub fn load<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let format = Format::of(path).tag(ErrorKind::Config)?;
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))
+        .tag(ErrorKind::Config)?;
+
+    parse(format, &content)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))
+        .tag(ErrorKind::Config)
+}
+
+pub fn parse<T: DeserializeOwned>(format: Format, content: &str) -> anyhow::Result<T> {
+    Ok(match format {
+        Format::Toml => toml::from_str(content)?,
+        Format::Yaml => serde_yaml::from_str(content)?,
+    })
+}
+
+// The node file in the config directory, used when no file is given
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    find_default().tag(ErrorKind::Config)
+}
+
+fn find_default() -> anyhow::Result<PathBuf> {
+    let dir = paths::config_dir()?;
+    let found = DEFAULT_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    match found.as_slice() {
+        [path] => Ok(path.clone()),
+        [] => bail!(
+            "No config file given and none of {} exists in {}",
+            DEFAULT_FILES.join(", "),
+            dir.display()
+        ),
+        _ => bail!(
+            "Found {}, keep only one of them",
+            found
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        ),
+    }
+}
+
+// The candidate a misspelled name was most likely meant to be
+pub fn closest<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    candidates
+        .iter()
+        .map(|candidate| (distance(&name, candidate), candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+// Levenshtein distance, the number of characters to insert, delete or replace
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, y) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(x != *y);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Write the file in the format its extension tells, its comments are not kept
+pub fn write(path: &Path, value: &Value) -> anyhow::Result<()> {
+    let content = match Format::of(path)? {
+        Format::Toml => toml::to_string_pretty(value)?,
+        Format::Yaml => serde_yaml::to_string(value)?,
+    };
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+// Upgrades a file from one version to the next, migrations[n] takes version n to n + 1
+pub type Migration = fn(&mut Value) -> anyhow::Result<()>;
+
+// The version the file was written for, files from before versioning are version 0
+pub fn version(value: &Value) -> anyhow::Result<u32> {
+    match &value["version"] {
+        Value::Null => Ok(0),
+        version => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or(anyhow!("`version` must be a whole number, got {}", version)),
+    }
+}
+
+// Bring the file to the latest version, in memory
+pub fn migrate(value: &mut Value, migrations: &[Migration]) -> anyhow::Result<u32> {
+    let from = version(value)?;
+    let latest = migrations.len() as u32;
+    if from > latest {
+        bail!(
+            "version {} is newer than this gaia understands ({}), upgrade gaia",
+            from,
+            latest
+        );
+    }
+    for migration in &migrations[from as usize..] {
+        migration(value)?;
+    }
+    if let Value::Object(table) = value {
+        table.shift_remove("version");
+        // keep the version on top, where people look for it
+        let rest = std::mem::take(table);
+        table.insert("version".to_string(), Value::from(latest));
+        table.extend(rest);
+    }
+
+    Ok(from)
+}
+
+// Whether migrating `before` into `after` changed more than the version
+pub fn changed(before: &Value, after: &Value) -> bool {
+    let unversioned = |value: &Value| {
+        let mut value = value.clone();
+        if let Value::Object(table) = &mut value {
+            table.shift_remove("version");
+        }
+        value
+    };
+    unversioned(before) != unversioned(after)
+}
+
+// Upgrade the file on disk when it was written for an older version, keeping the original next
+// to it as `<file>.v<version>.bak`. Returns the version it was upgraded from, if it was. A file
+// the migrations leave as it is, but for its version, is not rewritten.
+pub fn upgrade(path: &Path, migrations: &[Migration]) -> anyhow::Result<Option<u32>> {
+    let original: Value = load(path)?;
+    let mut value = original.clone();
+    let from = migrate(&mut value, migrations).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    if !changed(&original, &value) {
+        return Ok(None);
+    }
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", from));
+    fs::copy(path, &backup)?;
+    write(path, &value)?;
+    events::emit(
+        "config-migrated",
+        json!({
+            "file": path.display().to_string(),
+            "from": from,
+            "to": migrations.len(),
+        }),
+    );
+
+    Ok(Some(from))
+}
@@ -0,0 +1,581 @@
+use crate::client::ClientArgs;
+use crate::error::{fail, ErrorKind};
+use anyhow::anyhow;
+use console::style;
+use reqwest::blocking::{Client, Response};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader},
+    time::{Duration, Instant},
+};
+
+// A generation of a few tokens on a slow machine stays well under this
+const REQUEST_TIMEOUT_SECS: u64 = 300;
+// Model name no server should have, to see how an unknown one is refused
+const UNKNOWN_MODEL: &str = "gaia-check-api-no-such-model";
+const FINISH_REASONS: [&str; 4] = ["stop", "length", "tool_calls", "content_filter"];
+// Characters of a body quoted in a deviation
+const EXCERPT_CHARS: usize = 160;
+
+// What a check found, the deviations from the OpenAI API spelled out
+enum Outcome {
+    Pass,
+    Deviates(Vec<String>),
+    Skipped(String),
+}
+
+// Deviations found while looking through a response
+#[derive(Default)]
+struct Findings(Vec<String>);
+impl Findings {
+    fn expect(&mut self, holds: bool, deviation: impl FnOnce() -> String) {
+        if !holds {
+            // a deviation of every chunk is told once
+            let deviation = deviation();
+            if !self.0.contains(&deviation) {
+                self.0.push(deviation);
+            }
+        }
+    }
+
+    fn outcome(self) -> Outcome {
+        match self.0.is_empty() {
+            true => Outcome::Pass,
+            false => Outcome::Deviates(self.0),
+        }
+    }
+}
+
+struct Api {
+    base_url: String,
+    http: Client,
+    model: Option<String>,
+    embedding_model: Option<String>,
+}
+impl Api {
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    fn chat(&self, body: Value) -> anyhow::Result<Response> {
+        let mut body = body;
+        if let Some(model) = &self.model {
+            body["model"] = json!(model);
+        }
+        Ok(self
+            .http
+            .post(self.url("chat/completions"))
+            .json(&body)
+            .send()?)
+    }
+
+    // GET /models lists the served models, the first is used when no model is given
+    fn models(&mut self) -> anyhow::Result<Outcome> {
+        let response = self.http.get(self.url("models")).send()?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let body = json_body(response)?;
+        findings.expect(body["object"] == "list", || {
+            format!("`object` is {}, not \"list\"", body["object"])
+        });
+        let Some(data) = body["data"].as_array() else {
+            findings
+                .0
+                .push("`data` is not an array of models".to_string());
+            return Ok(findings.outcome());
+        };
+        findings.expect(!data.is_empty(), || "lists no model".to_string());
+        for model in data {
+            findings.expect(model["id"].is_string(), || {
+                format!("a model has no string `id`: {}", model)
+            });
+            findings.expect(model["object"] == "model", || {
+                format!("`object` of {} is not \"model\"", model["id"])
+            });
+        }
+        if self.model.is_none() {
+            self.model = data
+                .first()
+                .and_then(|model| model["id"].as_str())
+                .map(String::from);
+        }
+
+        Ok(findings.outcome())
+    }
+
+    fn chat_completion(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({
+            "messages": [{ "role": "user", "content": "Say hello." }],
+            "max_tokens": 16,
+        }))?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let content_type = header(&response, "content-type");
+        findings.expect(content_type.starts_with("application/json"), || {
+            format!("content type is '{}', not application/json", content_type)
+        });
+        let body = json_body(response)?;
+        findings.expect(body["id"].is_string(), || "no string `id`".to_string());
+        findings.expect(body["object"] == "chat.completion", || {
+            format!("`object` is {}, not \"chat.completion\"", body["object"])
+        });
+        findings.expect(body["created"].is_u64(), || {
+            "`created` is not a unix timestamp".to_string()
+        });
+        findings.expect(body["model"].is_string(), || {
+            "no string `model`".to_string()
+        });
+        let choice = &body["choices"][0];
+        findings.expect(choice.is_object(), || "no choice in `choices`".to_string());
+        findings.expect(choice["index"] == 0, || {
+            format!("`index` of the first choice is {}", choice["index"])
+        });
+        findings.expect(choice["message"]["role"] == "assistant", || {
+            format!(
+                "`role` of the message is {}, not \"assistant\"",
+                choice["message"]["role"]
+            )
+        });
+        findings.expect(choice["message"]["content"].is_string(), || {
+            "the message has no string `content`".to_string()
+        });
+        check_finish_reason(&mut findings, &choice["finish_reason"]);
+        check_usage(&mut findings, &body["usage"]);
+
+        Ok(findings.outcome())
+    }
+
+    fn streaming(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({
+            "messages": [{ "role": "user", "content": "Say hello." }],
+            "max_tokens": 16,
+            "stream": true,
+        }))?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let content_type = header(&response, "content-type");
+        findings.expect(content_type.starts_with("text/event-stream"), || {
+            format!("content type is '{}', not text/event-stream", content_type)
+        });
+        let stream = read_stream(response)?;
+        findings.0.extend(stream.deviations);
+        findings.expect(stream.done, || {
+            "the stream does not end with [DONE]".to_string()
+        });
+        findings.expect(!stream.chunks.is_empty(), || {
+            "no chunk was sent".to_string()
+        });
+        let mut ids = Vec::new();
+        let mut content = String::new();
+        let mut finish_reason = Value::Null;
+        for chunk in &stream.chunks {
+            findings.expect(chunk["object"] == "chat.completion.chunk", || {
+                format!(
+                    "`object` of a chunk is {}, not \"chat.completion.chunk\"",
+                    chunk["object"]
+                )
+            });
+            if !ids.contains(&chunk["id"]) {
+                ids.push(chunk["id"].clone());
+            }
+            for choice in chunk["choices"].as_array().into_iter().flatten() {
+                findings.expect(choice["delta"].is_object(), || {
+                    "a choice of a chunk has no `delta`".to_string()
+                });
+                content.push_str(choice["delta"]["content"].as_str().unwrap_or_default());
+                if !choice["finish_reason"].is_null() {
+                    finish_reason = choice["finish_reason"].clone();
+                }
+            }
+        }
+        findings.expect(ids.len() == 1, || {
+            format!("the chunks carry {} different ids, not one", ids.len())
+        });
+        findings.expect(!content.is_empty(), || {
+            "no chunk has `delta.content`".to_string()
+        });
+        findings.expect(!finish_reason.is_null(), || {
+            "no chunk has a `finish_reason`".to_string()
+        });
+        if !finish_reason.is_null() {
+            check_finish_reason(&mut findings, &finish_reason);
+        }
+
+        Ok(findings.outcome())
+    }
+
+    // With `stream_options.include_usage` the last chunk has the usage and no choices
+    fn stream_usage(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({
+            "messages": [{ "role": "user", "content": "Say hello." }],
+            "max_tokens": 16,
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }))?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let stream = read_stream(response)?;
+        match stream.chunks.iter().find(|chunk| !chunk["usage"].is_null()) {
+            Some(chunk) => {
+                check_usage(&mut findings, &chunk["usage"]);
+                findings.expect(
+                    chunk["choices"]
+                        .as_array()
+                        .is_some_and(|choices| choices.is_empty()),
+                    || "the chunk with the usage has choices".to_string(),
+                );
+                findings.expect(stream.chunks.last() == Some(chunk), || {
+                    "the chunk with the usage is not the last".to_string()
+                });
+            }
+            None => findings.0.push("no chunk has the usage".to_string()),
+        }
+
+        Ok(findings.outcome())
+    }
+
+    fn max_tokens(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({
+            "messages": [{ "role": "user", "content": "Count from 1 to 100, separated by spaces." }],
+            "max_tokens": 4,
+        }))?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let body = json_body(response)?;
+        let completion = body["usage"]["completion_tokens"].as_u64();
+        findings.expect(completion.is_some_and(|tokens| tokens <= 4), || {
+            format!(
+                "{} tokens were generated with max_tokens 4",
+                completion.map_or("an unknown number of".to_string(), |n| n.to_string())
+            )
+        });
+        let finish_reason = &body["choices"][0]["finish_reason"];
+        findings.expect(finish_reason == "length", || {
+            format!(
+                "`finish_reason` is {} when cut at max_tokens, not \"length\"",
+                finish_reason
+            )
+        });
+
+        Ok(findings.outcome())
+    }
+
+    fn stop(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({
+            "messages": [{ "role": "user", "content": "Count from 1 to 10, separated by spaces." }],
+            "max_tokens": 64,
+            "stop": ["5"],
+        }))?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let body = json_body(response)?;
+        let content = body["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap_or_default();
+        findings.expect(!content.contains('5'), || {
+            format!("the reply goes on past the stop sequence: {:?}", content)
+        });
+
+        Ok(findings.outcome())
+    }
+
+    fn embeddings(&self) -> anyhow::Result<Outcome> {
+        let mut body = json!({ "input": ["hello", "world"] });
+        if let Some(model) = &self.embedding_model {
+            body["model"] = json!(model);
+        }
+        let response = self.http.post(self.url("embeddings")).json(&body).send()?;
+        if response.status() == 404 && self.embedding_model.is_none() {
+            return Ok(Outcome::Skipped(
+                "the server has no embeddings endpoint".to_string(),
+            ));
+        }
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status.is_success(), || format!("answered {}", status));
+        let body = json_body(response)?;
+        findings.expect(body["object"] == "list", || {
+            format!("`object` is {}, not \"list\"", body["object"])
+        });
+        let data = body["data"].as_array().cloned().unwrap_or_default();
+        findings.expect(data.len() == 2, || {
+            format!("{} embeddings for 2 inputs", data.len())
+        });
+        let mut dimensions = Vec::new();
+        for (i, embedding) in data.iter().enumerate() {
+            findings.expect(embedding["object"] == "embedding", || {
+                format!("`object` of an embedding is {}", embedding["object"])
+            });
+            findings.expect(embedding["index"] == i, || {
+                format!("embedding {} has `index` {}", i, embedding["index"])
+            });
+            match embedding["embedding"].as_array() {
+                Some(vector) if vector.iter().all(Value::is_number) => {
+                    dimensions.push(vector.len())
+                }
+                _ => findings
+                    .0
+                    .push(format!("embedding {} is not an array of numbers", i)),
+            }
+        }
+        dimensions.dedup();
+        findings.expect(dimensions.len() <= 1, || {
+            format!("the embeddings have different sizes {:?}", dimensions)
+        });
+        findings.expect(body["usage"]["prompt_tokens"].is_u64(), || {
+            "no `usage.prompt_tokens`".to_string()
+        });
+
+        Ok(findings.outcome())
+    }
+
+    fn invalid_json(&self) -> anyhow::Result<Outcome> {
+        let response = self
+            .http
+            .post(self.url("chat/completions"))
+            .header("content-type", "application/json")
+            .body("{\"messages\": [")
+            .send()?;
+        expect_error(response, &[400])
+    }
+
+    fn missing_messages(&self) -> anyhow::Result<Outcome> {
+        let response = self.chat(json!({}))?;
+        expect_error(response, &[400, 422])
+    }
+
+    fn unknown_model(&self) -> anyhow::Result<Outcome> {
+        let response = self
+            .http
+            .post(self.url("chat/completions"))
+            .json(&json!({
+                "model": UNKNOWN_MODEL,
+                "messages": [{ "role": "user", "content": "Say hello." }],
+                "max_tokens": 1,
+            }))
+            .send()?;
+        expect_error(response, &[400, 404])
+    }
+
+    fn unknown_route(&self) -> anyhow::Result<Outcome> {
+        let response = self.http.get(self.url("no-such-endpoint")).send()?;
+        let mut findings = Findings::default();
+        let status = response.status();
+        findings.expect(status == 404, || format!("answered {}, not 404", status));
+
+        Ok(findings.outcome())
+    }
+}
+
+// The data chunks of a server-sent event stream
+struct Stream {
+    chunks: Vec<Value>,
+    done: bool,
+    deviations: Vec<String>,
+}
+
+fn read_stream(response: Response) -> anyhow::Result<Stream> {
+    let mut stream = Stream {
+        chunks: Vec::new(),
+        done: false,
+        deviations: Vec::new(),
+    };
+    for line in BufReader::new(response).lines() {
+        let line = line?;
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if stream.done {
+            stream
+                .deviations
+                .push("data is sent after [DONE]".to_string());
+            break;
+        }
+        if data == "[DONE]" {
+            stream.done = true;
+            continue;
+        }
+        match serde_json::from_str::<Value>(data) {
+            Ok(chunk) => stream.chunks.push(chunk),
+            Err(e) => {
+                stream
+                    .deviations
+                    .push(format!("a chunk is not JSON ({}): {}", e, excerpt(data)))
+            }
+        }
+    }
+
+    Ok(stream)
+}
+
+fn json_body(response: Response) -> anyhow::Result<Value> {
+    let text = response.text()?;
+    serde_json::from_str(&text)
+        .map_err(|e| anyhow!("the body is not JSON ({}): {}", e, excerpt(&text)))
+}
+
+// The start of a body on one line
+fn excerpt(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line,
+    }
+}
+
+fn header(response: &Response, name: &str) -> String {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn check_finish_reason(findings: &mut Findings, finish_reason: &Value) {
+    findings.expect(
+        finish_reason
+            .as_str()
+            .is_some_and(|reason| FINISH_REASONS.contains(&reason)),
+        || {
+            format!(
+                "`finish_reason` is {}, not one of {}",
+                finish_reason,
+                FINISH_REASONS.join(", ")
+            )
+        },
+    );
+}
+
+fn check_usage(findings: &mut Findings, usage: &Value) {
+    let tokens = ["prompt_tokens", "completion_tokens", "total_tokens"].map(|field| {
+        let count = usage[field].as_u64();
+        findings.expect(count.is_some(), || format!("no `usage.{}`", field));
+        count
+    });
+    if let [Some(prompt), Some(completion), Some(total)] = tokens {
+        findings.expect(prompt + completion == total, || {
+            format!(
+                "`usage.total_tokens` is {}, not {} prompt + {} completion tokens",
+                total, prompt, completion
+            )
+        });
+    }
+}
+
+// A refused request answers one of `statuses` with an `error` object holding its message
+fn expect_error(response: Response, statuses: &[u16]) -> anyhow::Result<Outcome> {
+    let mut findings = Findings::default();
+    let status = response.status();
+    findings.expect(statuses.contains(&status.as_u16()), || {
+        let expected = statuses
+            .iter()
+            .map(|status| status.to_string())
+            .collect::<Vec<_>>()
+            .join(" or ");
+        format!("answered {}, not {}", status, expected)
+    });
+    let text = response.text()?;
+    match serde_json::from_str::<Value>(&text) {
+        Ok(body) => findings.expect(body["error"]["message"].is_string(), || {
+            format!(
+                "the body has no `error.message`: {}",
+                excerpt(&body.to_string())
+            )
+        }),
+        Err(_) => findings
+            .0
+            .push(format!("the body is not JSON: {}", excerpt(&text))),
+    }
+
+    Ok(findings.outcome())
+}
+
+// Send the server requests covering the OpenAI API and report where it answers otherwise
+pub fn command_check_api(
+    client_args: ClientArgs,
+    embedding_model: Option<String>,
+) -> anyhow::Result<()> {
+    let mut api = Api {
+        base_url: client_args.base_url.trim_end_matches('/').to_string(),
+        http: Client::builder()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+            .build()?,
+        model: client_args.model_name,
+        embedding_model,
+    };
+    api.http
+        .get(api.url("models"))
+        .send()
+        .map_err(|e| fail(ErrorKind::Unreachable, anyhow!("{}: {}", api.base_url, e)))?;
+
+    type Check = fn(&mut Api) -> anyhow::Result<Outcome>;
+    let checks: [(&str, Check); 11] = [
+        ("models", |api| api.models()),
+        ("chat completion", |api| api.chat_completion()),
+        ("streaming", |api| api.streaming()),
+        ("stream usage", |api| api.stream_usage()),
+        ("max_tokens", |api| api.max_tokens()),
+        ("stop sequence", |api| api.stop()),
+        ("embeddings", |api| api.embeddings()),
+        ("invalid JSON", |api| api.invalid_json()),
+        ("missing messages", |api| api.missing_messages()),
+        ("unknown model", |api| api.unknown_model()),
+        ("unknown route", |api| api.unknown_route()),
+    ];
+    let mut deviating = 0;
+    let mut skipped = 0;
+    for (name, check) in checks {
+        let started = Instant::now();
+        let outcome = check(&mut api).unwrap_or_else(|e| Outcome::Deviates(vec![e.to_string()]));
+        let secs = style(format!("{:.1}s", started.elapsed().as_secs_f64())).dim();
+        match outcome {
+            Outcome::Pass => println!("{} {}  {}", style("pass").green(), style(name).bold(), secs),
+            Outcome::Deviates(deviations) => {
+                deviating += 1;
+                println!("{} {}  {}", style("FAIL").red(), style(name).bold(), secs);
+                for deviation in deviations {
+                    println!("    {}", deviation);
+                }
+            }
+            Outcome::Skipped(reason) => {
+                skipped += 1;
+                println!(
+                    "{} {}  {}",
+                    style("skip").yellow(),
+                    style(name).bold(),
+                    style(reason).dim()
+                );
+            }
+        }
+    }
+
+    let total = checks.len();
+    println!(
+        "{} of {} checks passed{}",
+        total - deviating - skipped,
+        total,
+        match skipped {
+            0 => String::new(),
+            n => format!(", {} skipped", n),
+        }
+    );
+    if deviating > 0 {
+        return Err(fail(
+            ErrorKind::Api,
+            anyhow!(
+                "{} checks found the server deviating from the OpenAI API",
+                deviating
+            ),
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use crate::client::ClientArgs;
+use crate::config;
+use crate::error::{ErrorKind, Tag};
+use crate::prompt;
+use crate::rag::{self, NoHit, PolicyArgs, RagArgs, RerankArgs, RetrievalPolicy, SearchMode};
+use crate::store::{ScoredPoint, VectorStore};
+use anyhow::bail;
+use clap::Args;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+// Placeholders available in each part of the template
+const CHUNK_FIELDS: [&str; 7] = ["n", "source", "text", "score", "page", "title", "chunk"];
+const PROMPT_FIELDS: [&str; 2] = ["context", "question"];
+
+#[derive(Debug, Clone, Args)]
+pub struct DocsArgs {
+    #[arg(
+        long = "with-docs",
+        help = "Add the chunks of a collection most relevant to each prompt"
+    )]
+    pub with_docs: bool,
+    #[arg(
+        long = "docs-template",
+        help = "TOML or YAML file setting how the retrieved chunks are put into the prompt",
+        value_name = "FILE",
+        requires = "with_docs"
+    )]
+    pub docs_template: Option<PathBuf>,
+    #[arg(
+        long = "embedding-model-name",
+        help = "Embedding model to search the collection with, defaults to the one served by `gaia start`",
+        requires = "with_docs"
+    )]
+    pub embedding_model_name: Option<String>,
+    #[command(flatten)]
+    pub rag: RagArgs,
+    #[command(flatten)]
+    pub policy: PolicyArgs,
+    #[command(flatten)]
+    pub rerank: RerankArgs,
+}
+
+// Reply given instead of the model's when the policy of the collection is `not-found`
+pub const NOT_FOUND_REPLY: &str =
+    "I could not find anything about this in the documents, so I cannot answer it.";
+
+// A prompt with its context
+pub struct Augmented {
+    pub prompt: String,
+    pub sources: Vec<String>,
+}
+
+// How retrieved chunks are formatted into the prompt
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContextTemplate {
+    // text before the chunks
+    pub header: String,
+    // one retrieved chunk, with {{n}}, {{source}}, {{text}}, {{score}}, {{page}}, {{title}} and {{chunk}}
+    pub chunk: String,
+    pub separator: String,
+    // the prompt sent to the model, with {{context}} and {{question}}
+    pub prompt: String,
+    pub max_chunks: usize,
+    // chunks longer than this are cut, estimated at 4 characters per token
+    pub chunk_tokens: usize,
+}
+impl Default for ContextTemplate {
+    fn default() -> Self {
+        Self {
+            header: "Answer the question using the context below. Cite the sources you use by their number, e.g. [1].\n\nContext:\n".to_string(),
+            chunk: "[{{n}}] {{source}}\n{{text}}".to_string(),
+            separator: "\n\n".to_string(),
+            prompt: "{{context}}\n\nQuestion: {{question}}".to_string(),
+            max_chunks: 5,
+            chunk_tokens: 512,
+        }
+    }
+}
+impl ContextTemplate {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let template: Self = config::load(path)?;
+
+        // catch typos in placeholders now rather than on the first prompt
+        for (part, text, fields) in [
+            ("chunk", &template.chunk, &CHUNK_FIELDS[..]),
+            ("prompt", &template.prompt, &PROMPT_FIELDS[..]),
+        ] {
+            let unknown = prompt::placeholders(text)
+                .into_iter()
+                .filter(|name| !fields.contains(&name.as_str()))
+                .collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                bail!(
+                    "{}: unknown placeholder {} in `{}`, use {}",
+                    path.display(),
+                    unknown.join(", "),
+                    part,
+                    fields
+                        .iter()
+                        .map(|field| format!("{{{{{}}}}}", field))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        if !template.prompt.contains("{{question}}") {
+            bail!("{}: `prompt` must contain {{{{question}}}}", path.display());
+        }
+
+        Ok(template)
+    }
+
+    // The prompt with the hits as context, or the question alone when nothing was found
+    pub fn render(&self, question: &str, hits: &[ScoredPoint]) -> anyhow::Result<String> {
+        if hits.is_empty() {
+            return Ok(question.to_string());
+        }
+
+        let mut chunks = Vec::new();
+        for (i, hit) in hits.iter().take(self.max_chunks).enumerate() {
+            let field = |key: &str| match &hit.payload[key] {
+                serde_json::Value::String(value) => value.clone(),
+                serde_json::Value::Null => String::new(),
+                value => value.to_string(),
+            };
+            let vars = HashMap::from([
+                ("n".to_string(), (i + 1).to_string()),
+                ("source".to_string(), field("source")),
+                (
+                    "text".to_string(),
+                    truncate(field("text").trim(), self.chunk_tokens * 4),
+                ),
+                ("score".to_string(), format!("{:.3}", hit.score)),
+                ("page".to_string(), field("page")),
+                ("title".to_string(), field("title")),
+                ("chunk".to_string(), field("chunk")),
+            ]);
+            chunks.push(prompt::render(&self.chunk, &vars)?);
+        }
+
+        let context = format!("{}{}", self.header, chunks.join(&self.separator));
+        let vars = HashMap::from([
+            ("context".to_string(), context),
+            ("question".to_string(), question.to_string()),
+        ]);
+
+        prompt::render(&self.prompt, &vars)
+    }
+}
+
+// Adds the relevant chunks of a collection to prompts
+pub struct Retriever {
+    store: Box<dyn VectorStore>,
+    collection: String,
+    client: ClientArgs,
+    rerank: RerankArgs,
+    policy: RetrievalPolicy,
+    template: ContextTemplate,
+}
+impl Retriever {
+    pub fn new(args: &DocsArgs, client: &ClientArgs) -> anyhow::Result<Option<Self>> {
+        if !args.with_docs {
+            return Ok(None);
+        }
+
+        let template = match &args.docs_template {
+            Some(path) => ContextTemplate::load(path).tag(ErrorKind::Config)?,
+            None => ContextTemplate::default(),
+        };
+        let store = rag::open_store(&args.rag)?;
+        if !store.collection_exists(&args.rag.collection)? {
+            bail!(
+                "Collection '{}' does not exist, create it with `gaia rag ingest`",
+                args.rag.collection
+            );
+        }
+
+        Ok(Some(Self {
+            store,
+            collection: args.rag.collection.clone(),
+            // the chat model cannot embed, search with the embedding model instead
+            client: ClientArgs {
+                base_url: client.base_url.clone(),
+                model_name: args.embedding_model_name.clone(),
+            },
+            rerank: args.rerank.clone(),
+            policy: args.policy.apply(rag::policy(&args.rag.collection)?),
+            template,
+        }))
+    }
+
+    // The prompt with the context for the question, or None when nothing relevant was found
+    // and the policy is not to answer then
+    pub fn augment(&self, question: &str) -> anyhow::Result<Option<Augmented>> {
+        let hits = rag::retrieve(
+            self.store.as_ref(),
+            &self.collection,
+            &self.client,
+            question,
+            &[],
+            SearchMode::Vector,
+            &self.policy,
+        )?;
+        if hits.is_empty() && self.policy.no_hit == NoHit::NotFound {
+            return Ok(None);
+        }
+        let hits = rag::rerank(&self.rerank, &self.client, question, hits)?;
+
+        let sources = hits
+            .iter()
+            .take(self.template.max_chunks)
+            .enumerate()
+            .map(|(i, hit)| {
+                let source = hit.payload["source"].as_str().unwrap_or("?");
+                match hit.payload["page"].as_u64() {
+                    Some(page) => format!("[{}] {} (page {})", i + 1, source, page),
+                    None => format!("[{}] {}", i + 1, source),
+                }
+            })
+            .collect();
+
+        Ok(Some(Augmented {
+            prompt: self.template.render(question, &hits)?,
+            sources,
+        }))
+    }
+}
+
+// The first characters of the text, marking the cut
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    format!("{}…", text.chars().take(max_chars).collect::<String>())
+}
@@ -0,0 +1,53 @@
+pub const INSTALL_NOAVX: &str = "curl -sSf https://raw.githubusercontent.com/WasmEdge/WasmEdge/master/utils/install_v2.sh | bash -s -- --noavx";
+
+// Instructions the prebuilt wasi-nn ggml plugin is compiled for that this CPU lacks. Only
+// x86_64 builds need them.
+#[cfg(target_arch = "x86_64")]
+pub fn missing() -> Vec<&'static str> {
+    let mut missing = Vec::new();
+    if !is_x86_feature_detected!("avx") {
+        missing.push("AVX");
+    }
+    if !is_x86_feature_detected!("avx2") {
+        missing.push("AVX2");
+    }
+    if !is_x86_feature_detected!("fma") {
+        missing.push("FMA");
+    }
+    if !is_x86_feature_detected!("f16c") {
+        missing.push("F16C");
+    }
+    missing
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn missing() -> Vec<&'static str> {
+    Vec::new()
+}
+
+// Builds made with GGML_NATIVE on a newer machine also use AVX-512
+#[cfg(target_arch = "x86_64")]
+fn has_avx512() -> bool {
+    is_x86_feature_detected!("avx512f")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn has_avx512() -> bool {
+    true
+}
+
+// Why a backend killed by an illegal instruction crashed
+pub fn explain_crash() -> String {
+    let mut lacks = missing();
+    if !has_avx512() {
+        lacks.push("AVX-512");
+    }
+    match lacks.is_empty() {
+        true => "The backend ran an instruction this CPU does not have, it was built for a newer one".to_string(),
+        false => format!(
+            "The backend ran an instruction this CPU does not have, it lacks {}. Install the wasi-nn plugin built without AVX with: {}",
+            lacks.join(", "),
+            INSTALL_NOAVX
+        ),
+    }
+}
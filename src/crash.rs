@@ -0,0 +1,450 @@
+use crate::audit::{self, NAMED_PATTERNS, REDACTED};
+use crate::blob::Utc;
+use crate::identity;
+use crate::paths;
+use anyhow::{anyhow, bail};
+use console::style;
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    backtrace::Backtrace,
+    cell::Cell,
+    collections::VecDeque,
+    env, fs, io,
+    panic::{self, PanicHookInfo},
+    path::PathBuf,
+    process,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{self, MakeWriter},
+    registry::LookupSpan,
+    Layer,
+};
+
+// File in the config directory keeping whether crashes are reported, set with
+// `gaia crash-reports enable`
+pub const SETTINGS_FILE: &str = "crash-reports.toml";
+
+// Lines of the log kept for the next report
+const RECENT_LINES: usize = 200;
+// Reports kept, the oldest removed first, so that a daemon panicking over and over does not
+// fill the disk
+const MAX_REPORTS: usize = 20;
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The settings, once reporting is on
+static ENABLED: OnceLock<Settings> = OnceLock::new();
+// The last lines logged, whatever the level of the log
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+thread_local! {
+    // the thread is writing a report, a panic then is not reported again
+    static REPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Reporting is off until it is enabled, nothing is written or sent before
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    enabled: bool,
+    // where each report is also posted as JSON
+    upload: Option<String>,
+}
+
+fn settings_path() -> anyhow::Result<PathBuf> {
+    Ok(paths::config_dir()?.join(SETTINGS_FILE))
+}
+
+fn load() -> anyhow::Result<Settings> {
+    let path = settings_path()?;
+    match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Settings::default()),
+        Err(e) => bail!("{}: {}", path.display(), e),
+    }
+}
+
+fn save(settings: &Settings) -> anyhow::Result<()> {
+    let path = settings_path()?;
+    fs::create_dir_all(paths::config_dir()?)?;
+    fs::write(&path, toml::to_string(settings)?).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+// Report the panics of this run when reporting is enabled
+pub fn init() {
+    let settings = match load() {
+        Ok(settings) if settings.enabled => settings,
+        Ok(_) => return,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                style(format!("Crash reports are off: {:#}", e)).yellow()
+            );
+            return;
+        }
+    };
+    let _ = ENABLED.set(settings);
+
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+        if REPORTING.with(|reporting| reporting.replace(true)) {
+            return;
+        }
+        report(info);
+        REPORTING.with(|reporting| reporting.set(false));
+    }));
+}
+
+// The layer keeping the last lines logged for the reports, none while reporting is off
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    ENABLED.get()?;
+    Some(
+        fmt::layer()
+            .with_writer(Recent)
+            .with_ansi(false)
+            .with_filter(filter_fn(|meta| {
+                (meta.target().starts_with("gaia") && *meta.level() <= Level::INFO)
+                    || *meta.level() <= Level::WARN
+            })),
+    )
+}
+
+struct Recent;
+
+impl<'a> MakeWriter<'a> for Recent {
+    type Writer = RecentLine;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecentLine(Vec::new())
+    }
+
+    fn make_writer_for(&'a self, _meta: &Metadata<'_>) -> Self::Writer {
+        RecentLine(Vec::new())
+    }
+}
+
+// A line of the log, kept once it is written whole
+struct RecentLine(Vec<u8>);
+
+impl io::Write for RecentLine {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RecentLine {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.0).trim_end().to_string();
+        let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+// Write the report of the panic, and send it where the settings say
+fn report(info: &PanicHookInfo<'_>) {
+    let Some(settings) = ENABLED.get() else {
+        return;
+    };
+    let report = redact(collect(info));
+    match write(&report) {
+        Ok(path) => eprintln!(
+            "{}",
+            style(format!(
+                "gaia crashed, a report was written to {}, attach it to the bug report",
+                path.display()
+            ))
+            .yellow()
+        ),
+        Err(e) => eprintln!("gaia crashed, and the report could not be written: {:#}", e),
+    }
+    if let Some(url) = &settings.upload {
+        match upload(url, &report) {
+            Ok(()) => eprintln!("The crash report was sent to {}", url),
+            Err(e) => eprintln!("The crash report could not be sent to {}: {:#}", url, e),
+        }
+    }
+}
+
+// What went wrong and where, before it is redacted
+fn collect(info: &PanicHookInfo<'_>) -> Value {
+    let message = info
+        .payload()
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| info.payload().downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked".to_string());
+    // the lock may be held by the thread that panicked, the lines are left out then
+    let log = RECENT
+        .try_lock()
+        .map(|recent| recent.iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    json!({
+        "time": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs()),
+        "version": env!("CARGO_PKG_VERSION"),
+        "os": env::consts::OS,
+        "os_version": os_version(),
+        "arch": env::consts::ARCH,
+        "command": command(),
+        "thread": thread::current().name().unwrap_or("unnamed"),
+        "message": message,
+        "location": info.location().map(|location| location.to_string()),
+        "backtrace": Backtrace::force_capture().to_string(),
+        "log": log,
+    })
+}
+
+fn os_version() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .or_else(|| {
+            process::Command::new("sw_vers")
+                .arg("-productVersion")
+                .output()
+                .ok()
+                .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+        })
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+}
+
+// The arguments gaia was run with, only flags and the words a subcommand or model name is made
+// of, prompts and the values of key flags left out
+fn command() -> Vec<String> {
+    let mut secret = false;
+    env::args()
+        .skip(1)
+        .map(|arg| {
+            if secret {
+                secret = false;
+                return REDACTED.to_string();
+            }
+            if let Some(flag) = arg.strip_prefix('-') {
+                let name = flag.split('=').next().unwrap_or(flag);
+                // the value of `--api-key KEY` is the next argument
+                secret = !flag.contains('=')
+                    && ["key", "token", "secret", "password"]
+                        .iter()
+                        .any(|word| name.contains(word));
+                return format!("-{}", name);
+            }
+            match arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+            {
+                true => arg,
+                false => REDACTED.to_string(),
+            }
+        })
+        .collect()
+}
+
+// The report with the keys, emails, node name and home directory it holds replaced
+fn redact(report: Value) -> Value {
+    let regexes = NAMED_PATTERNS
+        .iter()
+        .filter_map(|(_, pattern)| Regex::new(pattern).ok())
+        .collect::<Vec<_>>();
+    let home = paths::home().ok().map(|home| home.display().to_string());
+    let node = identity::name();
+    let redact_text = |text: &str| {
+        let mut text = text.to_string();
+        for regex in &regexes {
+            text = audit::redact_matches(regex, &text);
+        }
+        if let Some(home) = home.as_deref().filter(|home| home.len() > 1) {
+            text = text.replace(home, "~");
+        }
+        // short names would replace parts of words
+        if node.len() > 3 {
+            text = text.replace(&node, "[NODE]");
+        }
+        text
+    };
+
+    fn walk(value: Value, redact_text: &dyn Fn(&str) -> String) -> Value {
+        match value {
+            Value::String(text) => Value::String(redact_text(&text)),
+            Value::Array(items) => items
+                .into_iter()
+                .map(|item| walk(item, redact_text))
+                .collect(),
+            value => value,
+        }
+    }
+    match report {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, walk(value, &redact_text)))
+                .collect(),
+        ),
+        report => report,
+    }
+}
+
+// Save the report, removing the oldest ones beyond MAX_REPORTS
+fn write(report: &Value) -> anyhow::Result<PathBuf> {
+    let dir = paths::crashes_dir()?;
+    fs::create_dir_all(&dir).map_err(|e| anyhow!("{}: {}", dir.display(), e))?;
+    let path = dir.join(format!(
+        "crash-{}-{}.json",
+        report["time"].as_u64().unwrap_or_default(),
+        process::id()
+    ));
+    fs::write(&path, serde_json::to_string_pretty(report)?)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    let reports = reports()?;
+    for old in reports
+        .iter()
+        .take(reports.len().saturating_sub(MAX_REPORTS))
+    {
+        let _ = fs::remove_file(old);
+    }
+
+    Ok(path)
+}
+
+// Post the report, from a thread of its own as the panic may come from within an async runtime
+fn upload(url: &str, report: &Value) -> anyhow::Result<()> {
+    let (url, report) = (url.to_string(), report.clone());
+    thread::spawn(move || -> anyhow::Result<()> {
+        reqwest::blocking::Client::builder()
+            .timeout(UPLOAD_TIMEOUT)
+            .build()?
+            .post(url)
+            .json(&report)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    })
+    .join()
+    .map_err(|_| anyhow!("the upload panicked"))?
+}
+
+// The reports written, the oldest first
+fn reports() -> anyhow::Result<Vec<PathBuf>> {
+    let dir = paths::crashes_dir()?;
+    let mut reports = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "json")
+            })
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => bail!("{}: {}", dir.display(), e),
+    };
+    reports.sort_by_key(|path| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH)
+    });
+
+    Ok(reports)
+}
+
+pub fn command_enable(upload: Option<String>) -> anyhow::Result<()> {
+    if let Some(url) = &upload {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!("Invalid upload url '{}', use http:// or https://", url);
+        }
+    }
+    save(&Settings {
+        enabled: true,
+        upload: upload.clone(),
+    })?;
+    println!(
+        "Crash reports are on, written to {}{}",
+        paths::crashes_dir()?.display(),
+        upload
+            .map(|url| format!(" and sent to {}", url))
+            .unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+pub fn command_disable() -> anyhow::Result<()> {
+    save(&Settings::default())?;
+    println!("Crash reports are off, the reports written are kept");
+
+    Ok(())
+}
+
+// Whether reporting is on and the reports written, the newest last
+pub fn command_list() -> anyhow::Result<()> {
+    let settings = load()?;
+    match (settings.enabled, &settings.upload) {
+        (false, _) => println!("Crash reports are off, `gaia crash-reports enable` turns them on"),
+        (true, None) => println!("Crash reports are on"),
+        (true, Some(url)) => println!("Crash reports are on, sent to {}", url),
+    }
+
+    let reports = reports()?;
+    if reports.is_empty() {
+        println!("No crash reports in {}", paths::crashes_dir()?.display());
+    }
+    for path in reports {
+        let report = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Value>(&content).ok())
+            .unwrap_or_default();
+        println!(
+            "{}  {}  {} {}",
+            style(Utc::of(report["time"].as_u64().unwrap_or_default()).timestamp()).dim(),
+            style(path.file_stem().unwrap_or_default().to_string_lossy()).bold(),
+            report["version"].as_str().unwrap_or("?"),
+            report["message"]
+                .as_str()
+                .and_then(|message| message.lines().next())
+                .unwrap_or("?")
+        );
+    }
+
+    Ok(())
+}
+
+// Print a report, the newest without an id
+pub fn command_show(id: Option<String>) -> anyhow::Result<()> {
+    let reports = reports()?;
+    let path = match &id {
+        Some(id) => reports
+            .iter()
+            .find(|path| path.file_stem().is_some_and(|stem| stem == id.as_str()))
+            .ok_or(anyhow!(
+                "No crash report {}, see `gaia crash-reports list`",
+                id
+            ))?,
+        None => reports.last().ok_or(anyhow!("No crash reports yet"))?,
+    };
+    print!(
+        "{}",
+        fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+    );
+    println!();
+
+    Ok(())
+}
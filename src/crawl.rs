@@ -0,0 +1,272 @@
+use anyhow::{anyhow, bail};
+use console::style;
+use reqwest::Url;
+use std::{
+    collections::{HashSet, VecDeque},
+    thread,
+    time::Duration,
+};
+
+const USER_AGENT: &str = concat!("gaia-cli/", env!("CARGO_PKG_VERSION"));
+
+// Links to files that are never worth fetching for text
+const SKIPPED_EXTENSIONS: [&str; 16] = [
+    "png", "jpg", "jpeg", "gif", "svg", "webp", "ico", "css", "js", "zip", "gz", "tar", "mp4",
+    "mp3", "woff", "woff2",
+];
+
+// A fetched page
+pub struct Page {
+    pub url: String,
+    pub mime: String,
+    pub bytes: Vec<u8>,
+}
+
+pub struct CrawlResult {
+    pub pages: Vec<Page>,
+    // urls that could not be fetched this time, but were not reported missing
+    pub failed: Vec<String>,
+}
+
+// Crawl the site from the root url, following same-host links up to `depth` hops away.
+// A root ending in `.xml` is read as a sitemap listing the pages to fetch.
+pub fn crawl(root: &str, depth: usize, max_pages: usize) -> anyhow::Result<CrawlResult> {
+    let root = Url::parse(root).map_err(|e| anyhow!("Invalid url '{}': {}", root, e))?;
+    if root.scheme() != "http" && root.scheme() != "https" {
+        bail!("Only http and https urls can be ingested, got '{}'", root);
+    }
+
+    let http = reqwest::blocking::Client::builder()
+        .user_agent(USER_AGENT)
+        .timeout(Duration::from_secs(30))
+        .build()?;
+    let robots = Robots::fetch(&http, &root);
+
+    let mut queue = VecDeque::new();
+    if root.path().ends_with(".xml") {
+        for url in sitemap(&http, &root)? {
+            queue.push_back((url, 0));
+        }
+    } else {
+        queue.push_back((root.clone(), 0));
+    }
+
+    let mut seen = queue
+        .iter()
+        .map(|(url, _)| url.to_string())
+        .collect::<HashSet<_>>();
+    let mut result = CrawlResult {
+        pages: Vec::new(),
+        failed: Vec::new(),
+    };
+    while let Some((url, hops)) = queue.pop_front() {
+        if result.pages.len() >= max_pages {
+            println!(
+                "{} stopped after {} pages, raise --max-pages to crawl more",
+                style("warning:").yellow(),
+                max_pages
+            );
+            break;
+        }
+        if !robots.allows(url.path()) {
+            continue;
+        }
+        if !result.pages.is_empty() || !result.failed.is_empty() {
+            if let Some(delay) = robots.crawl_delay {
+                thread::sleep(delay);
+            }
+        }
+
+        let page = match fetch(&http, &url) {
+            Ok(page) => page,
+            Err(e) => {
+                // the root must be reachable, other pages may fail on their own
+                if result.pages.is_empty() && result.failed.is_empty() && hops == 0 && url == root {
+                    return Err(e);
+                }
+                println!("{} {}: {}", style("skipped").red(), url, e);
+                let gone = e
+                    .downcast_ref::<reqwest::Error>()
+                    .and_then(|e| e.status())
+                    .is_some_and(|status| status == 404 || status == 410);
+                if !gone {
+                    result.failed.push(url.to_string());
+                }
+                continue;
+            }
+        };
+
+        if hops < depth && page.mime == "text/html" {
+            for link in links(&url, &String::from_utf8_lossy(&page.bytes)) {
+                if link.host_str() == root.host_str() && seen.insert(link.to_string()) {
+                    queue.push_back((link, hops + 1));
+                }
+            }
+        }
+        result.pages.push(page);
+    }
+
+    Ok(result)
+}
+
+fn fetch(http: &reqwest::blocking::Client, url: &Url) -> anyhow::Result<Page> {
+    let _span = tracing::debug_span!("http", method = "GET", %url).entered();
+    let response = http.get(url.clone()).send()?;
+    tracing::debug!(status = response.status().as_u16(), "response");
+    let response = response.error_for_status()?;
+    let mime = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or("text/html")
+        .trim()
+        .to_ascii_lowercase();
+    if !(mime.starts_with("text/") || mime == "application/pdf") {
+        bail!("unsupported content type {}", mime);
+    }
+
+    Ok(Page {
+        url: response.url().to_string(),
+        mime,
+        bytes: response.bytes()?.to_vec(),
+    })
+}
+
+// Page urls listed in a sitemap, following nested sitemap indexes
+fn sitemap(http: &reqwest::blocking::Client, url: &Url) -> anyhow::Result<Vec<Url>> {
+    let xml = http.get(url.clone()).send()?.error_for_status()?.text()?;
+
+    let mut urls = Vec::new();
+    let mut rest = xml.as_str();
+    while let Some(start) = rest.find("<loc>") {
+        let after = &rest[start + 5..];
+        let end = after.find("</loc>").unwrap_or(after.len());
+        if let Ok(loc) = Url::parse(after[..end].trim()) {
+            if loc.path().ends_with(".xml") {
+                urls.extend(sitemap(http, &loc)?);
+            } else {
+                urls.push(loc);
+            }
+        }
+        rest = &after[end..];
+    }
+    if urls.is_empty() {
+        bail!("No <loc> entries found in sitemap {}", url);
+    }
+
+    Ok(urls)
+}
+
+// Absolute urls of the links in the page, without fragments
+fn links(base: &Url, html: &str) -> Vec<Url> {
+    let mut links = Vec::new();
+    let lower = html.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(pos) = lower[offset..].find("href=") {
+        let start = offset + pos + 5;
+        offset = start;
+        let quote = match html[start..].chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => continue,
+        };
+        let value = &html[start + 1..];
+        let end = match value.find(quote) {
+            Some(end) => end,
+            None => break,
+        };
+
+        if let Ok(mut link) = base.join(value[..end].trim()) {
+            link.set_fragment(None);
+            let skipped =
+                link.path().rsplit('.').next().is_some_and(|ext| {
+                    SKIPPED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+                });
+            if (link.scheme() == "http" || link.scheme() == "https") && !skipped {
+                links.push(link);
+            }
+        }
+    }
+
+    links
+}
+
+// The rules of robots.txt that apply to gaia
+#[derive(Default)]
+struct Robots {
+    // (allowed, path prefix)
+    rules: Vec<(bool, String)>,
+    crawl_delay: Option<Duration>,
+}
+impl Robots {
+    fn fetch(http: &reqwest::blocking::Client, root: &Url) -> Self {
+        let url = match root.join("/robots.txt") {
+            Ok(url) => url,
+            Err(_) => return Self::default(),
+        };
+        match http.get(url).send().and_then(|r| r.error_for_status()) {
+            Ok(response) => Self::parse(&response.text().unwrap_or_default()),
+            // no robots.txt means everything is allowed
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn parse(content: &str) -> Self {
+        let mut robots = Self::default();
+        let mut applies = false;
+        let mut in_agents = false;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            match key.as_str() {
+                "user-agent" => {
+                    // consecutive user-agent lines start a group together
+                    if !in_agents {
+                        applies = false;
+                    }
+                    in_agents = true;
+                    let agent = value.to_ascii_lowercase();
+                    applies |= agent == "*" || agent.starts_with("gaia");
+                }
+                "allow" | "disallow" if applies => {
+                    in_agents = false;
+                    if !value.is_empty() {
+                        robots.rules.push((key == "allow", value.to_string()));
+                    }
+                }
+                "crawl-delay" if applies => {
+                    in_agents = false;
+                    robots.crawl_delay = value.parse::<f64>().ok().map(Duration::from_secs_f64);
+                }
+                _ => in_agents = false,
+            }
+        }
+
+        robots
+    }
+
+    // The longest matching rule wins, allowing on ties
+    fn allows(&self, path: &str) -> bool {
+        let mut best: Option<(usize, bool)> = None;
+        for (allowed, prefix) in &self.rules {
+            let prefix = prefix.trim_end_matches('*');
+            if path.starts_with(prefix) {
+                let better = match best {
+                    Some((len, best_allowed)) => {
+                        prefix.len() > len || (prefix.len() == len && *allowed && !best_allowed)
+                    }
+                    None => true,
+                };
+                if better {
+                    best = Some((prefix.len(), *allowed));
+                }
+            }
+        }
+
+        best.map(|(_, allowed)| allowed).unwrap_or(true)
+    }
+}
@@ -0,0 +1,637 @@
+use crate::config;
+use crate::discover;
+use crate::events;
+use crate::gateway;
+use crate::identity;
+use crate::idle::{self, IdleArgs};
+use crate::logging;
+use crate::node::NodeConfig;
+use crate::paths;
+use crate::rag;
+use crate::server::{self, ServiceState};
+use crate::start::{self, StartArgs};
+use crate::telemetry;
+use crate::throughput;
+use crate::watchdog::{self, WatchdogArgs};
+use anyhow::{anyhow, bail};
+use console::style;
+use interprocess::local_socket::{prelude::*, ListenerOptions, Name, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// How long `gaia daemon --detach` waits for the daemon to listen
+const DETACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+// A service as the daemon sees it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    #[serde(flatten)]
+    pub state: ServiceState,
+    pub up: bool,
+    // why the daemon stopped it, until it starts it again
+    #[serde(default)]
+    pub parked: Option<String>,
+    // requests being answered, when they come through the gateway
+    #[serde(default)]
+    pub in_flight: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceStats {
+    pub name: String,
+    pub pid: u32,
+    pub port: u16,
+    pub uptime_secs: Option<u64>,
+    // resident memory, where the platform tells
+    pub rss_bytes: Option<u64>,
+    // requests being answered, when they come through the gateway
+    #[serde(default)]
+    pub in_flight: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    // name of the node, labelling the numbers of several nodes; daemons of older gaia lack it
+    #[serde(default)]
+    pub node: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub requests: u64,
+    pub services: Vec<ServiceStats>,
+}
+
+// What `reload` changed, and the settings it cannot change while the node runs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reloaded {
+    pub file: PathBuf,
+    // collections whose retrieval policy changed
+    pub policies: Vec<String>,
+    // the node file sets `log_level`, but -v, -q or GAIA_LOG win over it
+    pub log_level_ignored: bool,
+    pub restart: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+// The socket in the run directory on Unix, a named pipe on Windows
+fn socket_name() -> anyhow::Result<Name<'static>> {
+    #[cfg(unix)]
+    {
+        use interprocess::local_socket::GenericFilePath;
+        Ok(paths::run_dir()?
+            .join("gaia.sock")
+            .to_fs_name::<GenericFilePath>()?
+            .into_owned())
+    }
+    #[cfg(windows)]
+    {
+        use interprocess::local_socket::GenericNamespaced;
+        Ok("gaia.sock".to_ns_name::<GenericNamespaced>()?.into_owned())
+    }
+}
+
+// Call a method of the running daemon, None when no daemon is running
+pub fn call(method: &str, params: Value) -> anyhow::Result<Option<Value>> {
+    let stream = match Stream::connect(socket_name()?) {
+        Ok(stream) => stream,
+        // a socket left behind by a daemon that died refuses connections
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(anyhow!("Failed to reach the daemon: {}", e)),
+    };
+    let _span = tracing::debug_span!("daemon", method).entered();
+
+    let mut stream = BufReader::new(stream);
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(stream.get_mut(), "{}", request)?;
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    if line.is_empty() {
+        bail!("The daemon closed the connection without answering");
+    }
+
+    let mut response: Value = serde_json::from_str(&line)?;
+    if let Some(error) = response.get("error") {
+        bail!(
+            "The daemon answered: {}",
+            error["message"].as_str().unwrap_or("unknown error")
+        );
+    }
+
+    Ok(Some(response["result"].take()))
+}
+
+// Services as reported by the running daemon, None when no daemon is running
+pub fn status() -> anyhow::Result<Option<Vec<ServiceStatus>>> {
+    call("status", json!({}))?
+        .map(|result| Ok(serde_json::from_value(result)?))
+        .transpose()
+}
+
+pub fn command_daemon(
+    detach: bool,
+    file: Option<PathBuf>,
+    watch: WatchdogArgs,
+    idle: IdleArgs,
+    no_mdns: bool,
+) -> anyhow::Result<()> {
+    if let Some(pid) = call("ping", json!({}))? {
+        bail!("The daemon is already running (pid {})", pid);
+    }
+    // a broken node file fails here rather than in the detached daemon
+    let (file, node) = match node_file(file.as_deref())? {
+        Some((file, node)) => (Some(file), Some(node)),
+        None => (None, None),
+    };
+    if detach {
+        return detach_daemon(file.as_deref(), &watch, &idle, no_mdns);
+    }
+
+    fs::create_dir_all(paths::run_dir()?)?;
+    let listener = ListenerOptions::new()
+        .name(socket_name()?)
+        // replace the socket of a daemon that died
+        .try_overwrite(true)
+        .create_sync()?;
+    println!("The daemon is listening (pid {})", std::process::id());
+    telemetry::init(node.as_ref().and_then(|node| node.otlp.as_ref()))?;
+    if let Some(node) = &node {
+        logging::set_filter(node.log_level.as_deref())?;
+        if let Some(gateway) = &node.gateway {
+            gateway::spawn(gateway.clone())?;
+        }
+    }
+    watchdog::spawn(watch);
+    idle::spawn(
+        idle,
+        node.as_ref()
+            .map(|node| node.schedule.clone())
+            .unwrap_or_default(),
+    );
+    #[cfg(unix)]
+    reload_on_hangup()?;
+    if !no_mdns {
+        discover::advertise();
+    }
+
+    let mut daemon = Daemon {
+        started: Instant::now(),
+        requests: 0,
+        file,
+        node,
+        advertise: !no_mdns,
+    };
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        if daemon.serve(stream) {
+            break;
+        }
+    }
+    discover::withdraw();
+    println!("The daemon stopped");
+
+    Ok(())
+}
+
+// The node file given, or the default one when there is one
+fn node_file(file: Option<&Path>) -> anyhow::Result<Option<(PathBuf, NodeConfig)>> {
+    let file = match file {
+        Some(file) => file.to_path_buf(),
+        None => match config::default_path() {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        },
+    };
+    let node = NodeConfig::load(&file)?;
+
+    Ok(Some((file, node)))
+}
+
+// Reload the node file on SIGHUP, as `gaia reload` does
+#[cfg(unix)]
+fn reload_on_hangup() -> anyhow::Result<()> {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            // the daemon answers one client at a time, it is asked like any other
+            match call("reload", json!({})) {
+                Ok(_) => tracing::info!("reloaded the node file on SIGHUP"),
+                Err(e) => tracing::warn!("cannot reload the node file: {:#}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Run the daemon in the background, logging to `daemon.log` in the logs directory
+fn detach_daemon(
+    file: Option<&Path>,
+    watch: &WatchdogArgs,
+    idle: &IdleArgs,
+    no_mdns: bool,
+) -> anyhow::Result<()> {
+    let log_path = server::log_path("daemon")?;
+    fs::create_dir_all(paths::log_dir()?)?;
+    let log = fs::File::create(&log_path)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("daemon")
+        .args(file.map(|file| format!("--file={}", file.display())))
+        .args(watch.to_args())
+        .args(idle.to_args())
+        .args(no_mdns.then_some("--no-mdns"))
+        .args(logging::to_args())
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        // keep the daemon alive when the terminal goes away
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+
+    let started = Instant::now();
+    while started.elapsed() < DETACH_TIMEOUT {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "The daemon exited with {}:\n{}",
+                status,
+                server::log_tail(&log_path, 2048)
+            );
+        }
+        if call("ping", json!({}))?.is_some() {
+            println!(
+                "Started the daemon, pid {}, logging to {}",
+                child.id(),
+                log_path.display()
+            );
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    bail!(
+        "The daemon did not listen within {} seconds, see {}",
+        DETACH_TIMEOUT.as_secs(),
+        log_path.display()
+    )
+}
+
+struct Daemon {
+    started: Instant,
+    requests: u64,
+    // the node file and the node as the daemon started it
+    file: Option<PathBuf>,
+    node: Option<NodeConfig>,
+    // whether the node is advertised with mDNS, again as its models change
+    advertise: bool,
+}
+impl Daemon {
+    // Answer the requests of a client, one JSON-RPC request per line. Returns whether the
+    // daemon was asked to stop.
+    fn serve(&mut self, stream: Stream) -> bool {
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match stream.read_line(&mut line) {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+            self.requests += 1;
+
+            let (response, stop) = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    let stop = request.method == "stop";
+                    let response = match self.handle(&request.method, request.params) {
+                        Ok(result) => {
+                            json!({ "jsonrpc": "2.0", "id": request.id, "result": result })
+                        }
+                        Err((code, message)) => json!({
+                            "jsonrpc": "2.0",
+                            "id": request.id,
+                            "error": { "code": code, "message": message },
+                        }),
+                    };
+                    (response, stop)
+                }
+                Err(e) => (
+                    json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": -32700, "message": e.to_string() },
+                    }),
+                    false,
+                ),
+            };
+            if writeln!(stream.get_mut(), "{}", response).is_err() || stop {
+                return stop;
+            }
+        }
+    }
+
+    fn handle(&mut self, method: &str, params: Value) -> Result<Value, (i64, String)> {
+        let server_error = |e: anyhow::Error| (SERVER_ERROR, format!("{:#}", e));
+        let _span = tracing::info_span!("daemon.request", otel.kind = "server", method).entered();
+        tracing::info!(method, "request");
+
+        match method {
+            "ping" => Ok(json!(std::process::id())),
+            "status" => {
+                let mut services = server::load_all()
+                    .map_err(server_error)?
+                    .into_iter()
+                    .map(|state| {
+                        let up = server::probe(&format!("http://localhost:{}/v1", state.port));
+                        ServiceStatus {
+                            in_flight: gateway::in_flight(state.port),
+                            state,
+                            up,
+                            parked: None,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                services.extend(
+                    idle::parked()
+                        .into_iter()
+                        .map(|(state, reason)| ServiceStatus {
+                            state,
+                            up: false,
+                            parked: Some(reason),
+                            in_flight: None,
+                        }),
+                );
+                Ok(json!(services))
+            }
+            "stats" => Ok(json!(self.stats().map_err(server_error)?)),
+            "reload" => Ok(json!(self.reload().map_err(server_error)?)),
+            "models.load" => {
+                let mut args: StartArgs =
+                    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+                if args.model.is_none() || args.prompt_template.is_none() {
+                    return Err((
+                        INVALID_PARAMS,
+                        "the daemon cannot ask, give the model and its prompt template".to_string(),
+                    ));
+                }
+                // nobody is there to confirm the preflight summary
+                args.yes = true;
+                // the gateway of the node checks the requests to the models loaded
+                if self
+                    .node
+                    .as_ref()
+                    .is_some_and(|node| node.gateway.is_some())
+                {
+                    args.local_only = true;
+                }
+                // the models loaded replace those stopped while idle
+                idle::forget();
+                start::command_start(args).map_err(server_error)?;
+                if self.advertise {
+                    discover::advertise();
+                }
+                Ok(json!(
+                    server::load(server::API_SERVER).map_err(server_error)?
+                ))
+            }
+            "stop" => {
+                watchdog::stop();
+                idle::stop();
+                let mut stopped = Vec::new();
+                for state in server::load_all().map_err(server_error)? {
+                    server::stop(&state).map_err(server_error)?;
+                    stopped.push(json!({ "name": state.name, "pid": state.pid }));
+                }
+                Ok(json!({ "stopped": stopped, "pid": std::process::id() }))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+        }
+    }
+
+    // Apply the settings of the node file that can change while the models stay loaded: the
+    // gateway's keys, quotas, limits and filters, the log level and the retrieval policies
+    fn reload(&self) -> anyhow::Result<Reloaded> {
+        let (Some(file), Some(started)) = (&self.file, &self.node) else {
+            bail!("the daemon runs without a node file, there is nothing to reload");
+        };
+        let node = NodeConfig::load(file)?;
+
+        // the backends keep their models, changing them needs a restart
+        let mut restart = Vec::new();
+        for (changed, setting) in [
+            (node.port != started.port, "port"),
+            (node.runtime != started.runtime, "runtime"),
+            (node.numa != started.numa, "numa"),
+            (node.cpu_affinity != started.cpu_affinity, "cpu_affinity"),
+            (node.chat != started.chat, "chat"),
+            (node.embedding != started.embedding, "embedding"),
+            (node.whisper != started.whisper, "whisper"),
+            (node.schedule != started.schedule, "schedule"),
+            (node.otlp != started.otlp, "otlp"),
+        ] {
+            if changed {
+                restart.push(setting.to_string());
+            }
+        }
+        match (&node.gateway, &started.gateway) {
+            (Some(gateway), Some(_)) => restart.extend(
+                gateway::reload(gateway.clone())?
+                    .into_iter()
+                    .map(String::from),
+            ),
+            (None, None) => {}
+            _ => restart.push("gateway".to_string()),
+        }
+        let log_level_ignored =
+            !logging::set_filter(node.log_level.as_deref())? && node.log_level.is_some();
+
+        let mut policies = Vec::new();
+        for (collection, rag) in node.collections()? {
+            let Some(policy) = collection.policy() else {
+                continue;
+            };
+            match rag::update_policy(&rag.collection, &policy) {
+                Ok((_, true)) => policies.push(rag.collection),
+                Ok(_) => {}
+                Err(e) => tracing::warn!(collection = rag.collection, "{:#}", e),
+            }
+        }
+
+        let reloaded = Reloaded {
+            file: file.clone(),
+            policies,
+            log_level_ignored,
+            restart,
+        };
+        events::emit("node-reloaded", json!(reloaded));
+        if self.advertise {
+            discover::advertise();
+        }
+        tracing::info!(restart = ?reloaded.restart, "reloaded {}", file.display());
+
+        Ok(reloaded)
+    }
+
+    fn stats(&self) -> anyhow::Result<Stats> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let services = server::load_all()?
+            .into_iter()
+            .map(|state| ServiceStats {
+                uptime_secs: (state.started > 0).then(|| now.saturating_sub(state.started)),
+                rss_bytes: rss_bytes(state.pid),
+                in_flight: gateway::in_flight(state.port),
+                name: state.name,
+                pid: state.pid,
+                port: state.port,
+            })
+            .collect();
+
+        Ok(Stats {
+            node: identity::name(),
+            pid: std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            requests: self.requests,
+            services,
+        })
+    }
+}
+
+// Resident memory of a process, read from /proc on Linux
+pub fn rss_bytes(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kb = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kb * 1024)
+}
+
+pub fn command_models_load(args: StartArgs) -> anyhow::Result<()> {
+    // nothing is launched by a dry run, it needs no daemon
+    if args.dry_run {
+        return start::command_start(args);
+    }
+
+    let state = call("models.load", serde_json::to_value(&args)?)?.ok_or(anyhow!(
+        "The daemon is not running, start it with `gaia daemon --detach` or use `gaia start`"
+    ))?;
+    let state: Option<ServiceState> = serde_json::from_value(state)?;
+    match state {
+        Some(state) => {
+            println!(
+                "The daemon started {} at http://localhost:{}/v1, pid {}",
+                state.name, state.port, state.pid
+            );
+            for model in &state.models {
+                println!("  {} model: {} ({})", model.kind, model.name, model.path);
+            }
+        }
+        None => println!("The daemon started the model, but it is no longer running"),
+    }
+
+    Ok(())
+}
+
+pub fn command_reload() -> anyhow::Result<()> {
+    let Some(reloaded) = call("reload", json!({}))? else {
+        bail!("The daemon is not running, the node file is read when it starts");
+    };
+    let reloaded: Reloaded = serde_json::from_value(reloaded)?;
+
+    println!("Reloaded {}", reloaded.file.display());
+    if !reloaded.policies.is_empty() {
+        println!(
+            "  retrieval policy of {} changed",
+            reloaded.policies.join(", ")
+        );
+    }
+    if reloaded.log_level_ignored {
+        println!(
+            "{}",
+            style("  log_level is left alone, the daemon was started with -v, -q or GAIA_LOG")
+                .dim()
+        );
+    }
+    if !reloaded.restart.is_empty() {
+        println!(
+            "{} {}",
+            style("Changed settings that need a restart, with `gaia stop` and `gaia serve`:")
+                .yellow(),
+            reloaded.restart.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn command_stats() -> anyhow::Result<()> {
+    let Some(stats) = call("stats", json!({}))? else {
+        println!("The daemon is not running, start it with `gaia daemon --detach`");
+        return throughput::print_totals();
+    };
+    let stats: Stats = serde_json::from_value(stats)?;
+
+    println!(
+        "{} daemon (pid {}) up {}s, {} requests",
+        stats.node, stats.pid, stats.uptime_secs, stats.requests
+    );
+    for service in &stats.services {
+        let uptime = service
+            .uptime_secs
+            .map(|secs| format!("up {}s", secs))
+            .unwrap_or_else(|| "up ?".to_string());
+        let memory = service
+            .rss_bytes
+            .map(|bytes| format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)))
+            .unwrap_or_else(|| "? MiB".to_string());
+        println!(
+            "{} (pid {}) port {}  {}  {}",
+            service.name, service.pid, service.port, uptime, memory
+        );
+    }
+
+    throughput::print_totals()
+}
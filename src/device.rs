@@ -0,0 +1,153 @@
+use crate::cpu;
+use crate::error::{fail, ErrorKind, Tag};
+use crate::memory;
+use crate::paths;
+use crate::runtime;
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// What the backend computes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Cpu,
+    Cuda,
+    Metal,
+    Rocm,
+    Vulkan,
+}
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Device::Cpu => f.pad("cpu"),
+            Device::Cuda => f.pad("cuda"),
+            Device::Metal => f.pad("metal"),
+            Device::Rocm => f.pad("rocm"),
+            Device::Vulkan => f.pad("vulkan"),
+        }
+    }
+}
+
+// The device the installed plugin picks when none is pinned, the first GPU found
+pub fn detect() -> Device {
+    match memory::gpu() {
+        Some(("CUDA", _)) => Device::Cuda,
+        Some(("ROCm", _)) => Device::Rocm,
+        Some(("Metal", _)) => Device::Metal,
+        _ => Device::Cpu,
+    }
+}
+
+// A build of the wasi-nn ggml plugin, kept in its own directory for $WASMEDGE_PLUGIN_PATH
+pub struct Plugin {
+    pub url: String,
+    pub dir: PathBuf,
+}
+
+// Platform suffix of the WasmEdge release assets
+pub fn platform() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Some("ubuntu20.04_x86_64"),
+        ("linux", "aarch64") => Some("ubuntu20.04_aarch64"),
+        ("macos", "aarch64") => Some("darwin_arm64"),
+        ("macos", "x86_64") => Some("darwin_x86_64"),
+        _ => None,
+    }
+}
+
+pub fn wasmedge_version(wasmedge: &Path) -> anyhow::Result<String> {
+    let output = Command::new(wasmedge).arg("--version").output()?;
+    // `wasmedge version 0.14.1`
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .last()
+        .map(String::from)
+        .ok_or(anyhow!("{} did not tell its version", wasmedge.display()))
+}
+
+// The build of the plugin for the device, none when the default build serves it, and the
+// platform of the release assets. The CPU build is the one without AVX when this CPU lacks the
+// instructions of the default build.
+pub fn build(device: Device) -> anyhow::Result<(Option<&'static str>, &'static str)> {
+    let linux = cfg!(target_os = "linux");
+    let missing = cpu::missing();
+    let (build, supported) = match device {
+        // any build runs on the CPU with no layer offloaded
+        Device::Cpu if missing.is_empty() => (None, true),
+        Device::Cpu => (Some("noavx"), linux),
+        Device::Metal => (
+            None,
+            cfg!(all(target_os = "macos", target_arch = "aarch64")),
+        ),
+        Device::Cuda => (Some("cuda"), linux),
+        Device::Rocm => (Some("rocm"), linux),
+        Device::Vulkan => (Some("vulkan"), linux),
+    };
+    match platform().filter(|_| supported) {
+        Some(platform) => Ok((build, platform)),
+        None if device == Device::Cpu => Err(fail(
+            ErrorKind::BackendCrash,
+            anyhow!(
+                "This CPU lacks {}, which the wasi-nn ggml plugin is built for, and there is no build without AVX for this system",
+                missing.join(", ")
+            ),
+        )),
+        None => Err(fail(
+            ErrorKind::Config,
+            anyhow!(
+                "There is no {} build of the wasi-nn plugin for {} {}",
+                device,
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            ),
+        )),
+    }
+}
+
+pub fn plugin_url(version: &str, build: Option<&str>, platform: &str) -> String {
+    let name = match build {
+        Some(build) => format!("wasi_nn-ggml-{}", build),
+        None => "wasi_nn-ggml".to_string(),
+    };
+    format!(
+        "{0}/{1}/WasmEdge-plugin-{2}-{1}-{3}.tar.gz",
+        runtime::RELEASES_URL,
+        version,
+        name,
+        platform
+    )
+}
+
+// The plugin build for the device, matching the version of wasmedge, or none when the installed
+// plugin serves it
+pub fn plugin(device: Device, version: &str) -> anyhow::Result<Option<Plugin>> {
+    let (Some(build), platform) = build(device)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(Plugin {
+        url: plugin_url(version, Some(build), platform),
+        dir: paths::plugins_dir()?.join(format!("{}-{}", build, version)),
+    }))
+}
+
+// Download and unpack the plugin
+pub fn fetch(plugin: &Plugin) -> anyhow::Result<()> {
+    if let Err(e) = runtime::unpack(&plugin.url, &plugin.dir, "wasi-nn plugin", 0) {
+        let mut message = format!(
+            "The wasi-nn plugin could not be fetched from {}: {}",
+            plugin.url, e
+        );
+        if plugin.url.contains("noavx") {
+            message.push_str(&format!("\nInstall it with: {}", cpu::INSTALL_NOAVX));
+        }
+        return Err(anyhow!(message)).tag(ErrorKind::Download);
+    }
+
+    Ok(())
+}
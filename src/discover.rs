@@ -0,0 +1,197 @@
+use crate::gateway;
+use crate::identity;
+use crate::server;
+use console::style;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Service type gaia nodes are advertised under
+const SERVICE_TYPE: &str = "_gaia._tcp.local.";
+// Longest TXT value, the model list is cut to fit
+const MAX_TXT: usize = 255;
+
+// The mDNS responder of the daemon and the full name of the service it advertises
+static ADVERTISED: Mutex<Option<(ServiceDaemon, String)>> = Mutex::new(None);
+
+// A node answering on the local network
+#[derive(Debug, Serialize)]
+struct Node {
+    name: String,
+    url: String,
+    addresses: Vec<IpAddr>,
+    port: u16,
+    models: Vec<String>,
+    version: Option<String>,
+}
+
+// Advertise the node on the local network with the port answering the OpenAI API and the
+// models it serves, again whenever they change. Failing to is logged, not fatal.
+pub fn advertise() {
+    if let Err(e) = register() {
+        tracing::warn!("cannot advertise the node with mDNS: {:#}", e);
+    }
+}
+
+fn register() -> anyhow::Result<()> {
+    let states = server::load_all()?;
+    // the gateway answers for every model, without one the api-server is connected to
+    let (port, mut models) = match gateway::listening() {
+        Some(listening) => listening,
+        None => match states.iter().find(|state| state.name == server::API_SERVER) {
+            Some(state) => (state.port, Vec::new()),
+            None => return Ok(()),
+        },
+    };
+    models.extend(
+        states
+            .iter()
+            .flat_map(|state| state.models.iter().map(|model| model.name.clone())),
+    );
+    models.sort();
+    models.dedup();
+    let mut listed = String::new();
+    for model in models {
+        if listed.len() + model.len() + 1 > MAX_TXT {
+            break;
+        }
+        if !listed.is_empty() {
+            listed.push(',');
+        }
+        listed.push_str(&model);
+    }
+
+    let name = identity::name();
+    let host = format!("{}.local.", name.replace(['.', '_'], "-"));
+    let properties = [
+        ("path", "/v1".to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("models", listed),
+    ];
+    let info =
+        ServiceInfo::new(SERVICE_TYPE, &name, &host, "", port, &properties[..])?.enable_addr_auto();
+
+    let mut advertised = ADVERTISED.lock().unwrap();
+    let mdns = match advertised.take() {
+        Some((mdns, fullname)) => {
+            // renamed since, the old name goes away
+            if fullname != info.get_fullname() {
+                let _ = mdns.unregister(&fullname);
+            }
+            mdns
+        }
+        None => ServiceDaemon::new()?,
+    };
+    let fullname = info.get_fullname().to_string();
+    mdns.register(info)?;
+    tracing::info!(port, "advertised as {}", fullname);
+    *advertised = Some((mdns, fullname));
+
+    Ok(())
+}
+
+// Tell the network the node is gone
+pub fn withdraw() {
+    if let Some((mdns, fullname)) = ADVERTISED.lock().unwrap().take() {
+        if let Ok(done) = mdns.unregister(&fullname) {
+            let _ = done.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = mdns.shutdown();
+    }
+}
+
+impl Node {
+    fn from_info(info: &ServiceInfo) -> Self {
+        let name = info
+            .get_fullname()
+            .strip_suffix(&format!(".{}", SERVICE_TYPE))
+            .unwrap_or(info.get_fullname())
+            .to_string();
+        let mut addresses = info.get_addresses().iter().copied().collect::<Vec<_>>();
+        // IPv4 first, those are what people type
+        addresses.sort_by_key(|address| (address.is_ipv6(), *address));
+        let host = match addresses.first() {
+            Some(IpAddr::V6(address)) => format!("[{}]", address),
+            Some(address) => address.to_string(),
+            None => info.get_hostname().trim_end_matches('.').to_string(),
+        };
+        let path = info.get_property_val_str("path").unwrap_or("/v1");
+
+        Self {
+            name,
+            url: format!("http://{}:{}{}", host, info.get_port(), path),
+            addresses,
+            port: info.get_port(),
+            models: info
+                .get_property_val_str("models")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|model| !model.is_empty())
+                .map(String::from)
+                .collect(),
+            version: info.get_property_val_str("version").map(String::from),
+        }
+    }
+}
+
+// List the gaia nodes the local network answers for within the timeout
+pub fn command_discover(timeout: u64, json: bool) -> anyhow::Result<()> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(SERVICE_TYPE)?;
+    if !json {
+        eprintln!(
+            "{}",
+            style(format!(
+                "Looking for gaia nodes on the local network for {}s",
+                timeout
+            ))
+            .dim()
+        );
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    let mut nodes = BTreeMap::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let node = Node::from_info(&info);
+                nodes.insert(info.get_fullname().to_string(), node);
+            }
+            Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                nodes.remove(&fullname);
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = mdns.shutdown();
+
+    let nodes = nodes.into_values().collect::<Vec<_>>();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&nodes)?);
+        return Ok(());
+    }
+    if nodes.is_empty() {
+        println!("No gaia node found, nodes are advertised while `gaia daemon` runs");
+        return Ok(());
+    }
+    println!(
+        "{}",
+        style(format!("{:<20} {:<32} {}", "NODE", "URL", "MODELS")).bold()
+    );
+    for node in &nodes {
+        println!(
+            "{:<20} {:<32} {}",
+            node.name,
+            node.url,
+            node.models.join(", ")
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,306 @@
+use crate::attachment;
+use crate::crawl::Page;
+use anyhow::anyhow;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+// A piece of a document chunked on its own, with the payload fields describing where it came from
+pub struct Section {
+    pub text: String,
+    pub metadata: Map<String, Value>,
+}
+
+// Whether the file can be ingested
+pub fn is_supported(path: &Path, bytes: &[u8]) -> bool {
+    let mime = attachment::detect_mime(path, bytes);
+    mime.starts_with("text/") || mime == "application/pdf"
+}
+
+// Tags whose content is navigation or code rather than the text of the page
+const BOILERPLATE_TAGS: [&str; 11] = [
+    "head", "script", "style", "noscript", "template", "svg", "nav", "header", "footer", "aside",
+    "form",
+];
+
+// Tags that start a new paragraph
+const BLOCK_TAGS: [&str; 17] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "li",
+    "tr",
+    "pre",
+    "blockquote",
+    "table",
+    "br",
+];
+
+// Extract the text of a file, one section per page for PDFs
+pub fn load(path: &Path, bytes: &[u8]) -> anyhow::Result<Vec<Section>> {
+    match attachment::detect_mime(path, bytes) {
+        "application/pdf" => load_pdf(path, bytes),
+        mime => {
+            let text = String::from_utf8(bytes.to_vec())
+                .map_err(|_| anyhow!("{} is not valid UTF-8", path.display()))?;
+            Ok(vec![load_text(mime, &text)])
+        }
+    }
+}
+
+// Extract the text of a crawled page
+pub fn load_page(page: &Page) -> anyhow::Result<Vec<Section>> {
+    if page.mime == "application/pdf" {
+        return load_pdf(Path::new(&page.url), &page.bytes);
+    }
+
+    Ok(vec![load_text(
+        &page.mime,
+        &String::from_utf8_lossy(&page.bytes),
+    )])
+}
+
+// Payload fields set by the indexer, which front matter cannot override
+const RESERVED_FIELDS: [&str; 3] = ["source", "chunk", "text"];
+
+fn load_text(mime: &str, text: &str) -> Section {
+    match mime {
+        "text/html" => load_html(text),
+        "text/markdown" => load_markdown(text),
+        _ => Section {
+            text: text.to_string(),
+            metadata: Map::new(),
+        },
+    }
+}
+
+fn load_html(text: &str) -> Section {
+    let mut metadata = Map::new();
+    if let Some(title) = html_title(text) {
+        metadata.insert("title".to_string(), Value::from(title));
+    }
+    Section {
+        text: html_to_text(text),
+        metadata,
+    }
+}
+
+// Markdown text, with the fields of its YAML front matter as metadata
+fn load_markdown(text: &str) -> Section {
+    let (front_matter, body) = match split_front_matter(text) {
+        Some(parts) => parts,
+        None => {
+            return Section {
+                text: text.to_string(),
+                metadata: Map::new(),
+            }
+        }
+    };
+
+    let fields = match serde_yaml::from_str::<Value>(front_matter) {
+        Ok(Value::Object(fields)) => fields,
+        // not front matter after all, e.g. a document starting with a horizontal rule
+        _ => {
+            return Section {
+                text: text.to_string(),
+                metadata: Map::new(),
+            }
+        }
+    };
+
+    let mut metadata = Map::new();
+    for (key, value) in fields {
+        if RESERVED_FIELDS.contains(&key.as_str()) || value.is_null() {
+            continue;
+        }
+        let value = match (key.as_str(), value) {
+            // `tags: a, b` is as common as a list, store both as a list to filter on
+            ("tags", Value::String(tags)) => Value::from(
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .collect::<Vec<_>>(),
+            ),
+            (_, value) => value,
+        };
+        metadata.insert(key, value);
+    }
+
+    Section {
+        text: body.to_string(),
+        metadata,
+    }
+}
+
+// The front matter between the leading `---` lines, and the text after it
+fn split_front_matter(text: &str) -> Option<(&str, &str)> {
+    let rest = text
+        .strip_prefix("\u{feff}")
+        .unwrap_or(text)
+        .strip_prefix("---")?;
+    let rest = rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))?;
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+
+    None
+}
+
+fn html_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<title")?;
+    let start = start + lower[start..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = decode_entities(html[start..end].trim());
+
+    (!title.is_empty()).then_some(title)
+}
+
+// Plain text of an html page, keeping the main content when the page marks it
+pub fn html_to_text(html: &str) -> String {
+    let lower = html.to_ascii_lowercase();
+    let body = ["<main", "<article"]
+        .iter()
+        .find_map(|tag| {
+            let start = lower.find(tag)?;
+            let end = lower.rfind(&format!("</{}", &tag[1..]))?;
+            (end > start).then_some(&html[start..end])
+        })
+        .unwrap_or(html);
+
+    let mut text = String::new();
+    let mut rest = body;
+    let mut skipping: Option<String> = None;
+    while let Some(open) = rest.find('<') {
+        if skipping.is_none() {
+            text.push_str(&decode_entities(&rest[..open]));
+        }
+        rest = &rest[open..];
+
+        if rest.starts_with("<!--") {
+            let end = rest.find("-->").map(|end| end + 3).unwrap_or(rest.len());
+            rest = &rest[end..];
+            continue;
+        }
+
+        let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        let tag = &rest[1..end.saturating_sub(1).max(1)];
+        rest = &rest[end..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        match &skipping {
+            Some(skipped) => {
+                if closing && &name == skipped {
+                    skipping = None;
+                }
+            }
+            None => {
+                if !closing && !tag.ends_with('/') && BOILERPLATE_TAGS.contains(&name.as_str()) {
+                    skipping = Some(name);
+                } else if BLOCK_TAGS.contains(&name.as_str()) {
+                    text.push_str("\n\n");
+                }
+            }
+        }
+    }
+    if skipping.is_none() {
+        text.push_str(&decode_entities(rest));
+    }
+
+    // collapse the whitespace of the markup, keeping paragraph breaks
+    text.split("\n\n")
+        .map(|paragraph| paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .map(|end| &rest[1..end]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+
+    decoded
+}
+
+fn load_pdf(path: &Path, bytes: &[u8]) -> anyhow::Result<Vec<Section>> {
+    let document =
+        lopdf::Document::load_mem(bytes).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let pages = document.get_pages();
+    let total = pages.len();
+
+    let mut sections = Vec::new();
+    for page in pages.keys() {
+        let text = document
+            .extract_text(&[*page])
+            .map_err(|e| anyhow!("{}: page {}: {}", path.display(), page, e))?;
+        if text.trim().is_empty() {
+            // scanned pages have no text layer
+            continue;
+        }
+
+        let mut metadata = Map::new();
+        metadata.insert("page".to_string(), Value::from(*page));
+        metadata.insert("pages".to_string(), Value::from(total));
+        sections.push(Section { text, metadata });
+    }
+
+    Ok(sections)
+}
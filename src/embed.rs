@@ -0,0 +1,202 @@
+use crate::client::{Client, ClientArgs};
+use crate::rag;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+};
+
+// Inputs sent to the server in one request
+const BATCH_SIZE: usize = 32;
+
+// How embeddings are shaped for the vector store they go to, for `gaia embed` and the
+// `gateway.embeddings` section of a node file
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingArgs {
+    #[arg(
+        long = "normalize",
+        help = "Scale the embeddings to unit length, for stores comparing them by dot product"
+    )]
+    #[serde(default)]
+    pub normalize: bool,
+    #[arg(
+        long = "dimensions",
+        help = "Keep the first N dimensions, for models trained to be cut short (Matryoshka)",
+        value_name = "N"
+    )]
+    pub dimensions: Option<usize>,
+    #[arg(
+        long = "dtype",
+        help = "Type of the values: floats, int8 or uint8 scaled from -1..1, or the signs packed 8 per byte as binary (int8) or ubinary (uint8)",
+        value_name = "DTYPE",
+        default_value = "float"
+    )]
+    #[serde(default)]
+    pub dtype: Dtype,
+}
+impl EmbeddingArgs {
+    // The embedding cut to `dimensions`, normalized after it so that a cut one is still unit length
+    pub fn shape(&self, mut embedding: Vec<f32>) -> Vec<f32> {
+        if let Some(dimensions) = self.dimensions {
+            embedding.truncate(dimensions);
+        }
+        if self.normalize {
+            let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                embedding.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+        embedding
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dtype {
+    #[default]
+    Float,
+    Int8,
+    Uint8,
+    Binary,
+    Ubinary,
+}
+impl Dtype {
+    // The values as the type holds them, quantized ones assuming values from -1 to 1
+    pub fn encode(self, embedding: &[f32]) -> Value {
+        let pack = || {
+            embedding
+                .chunks(8)
+                .map(|bits| {
+                    bits.iter()
+                        .enumerate()
+                        .filter(|(_, x)| **x > 0.0)
+                        .fold(0u8, |byte, (i, _)| byte | (0x80 >> i))
+                })
+                .collect::<Vec<_>>()
+        };
+        match self {
+            // by the shortest text of each f32, 0.1 rather than 0.10000000149011612
+            Dtype::Float => json!(embedding
+                .iter()
+                .map(|x| x.to_string().parse::<f64>().unwrap_or(*x as f64))
+                .collect::<Vec<_>>()),
+            Dtype::Int8 => json!(embedding
+                .iter()
+                .map(|x| (x * 127.0).round().clamp(-128.0, 127.0) as i8)
+                .collect::<Vec<_>>()),
+            Dtype::Uint8 => json!(embedding
+                .iter()
+                .map(|x| ((x + 1.0) * 127.5).round().clamp(0.0, 255.0) as u8)
+                .collect::<Vec<_>>()),
+            // offset by 128 into an int8
+            Dtype::Binary => json!(pack()
+                .into_iter()
+                .map(|byte| (byte as i16 - 128) as i8)
+                .collect::<Vec<_>>()),
+            Dtype::Ubinary => json!(pack()),
+        }
+    }
+}
+
+// Shape the embeddings of an OpenAI embeddings response, `dimensions` and `encoding_format` of
+// the request taking over those of the gateway
+pub fn reshape(response: &mut Value, request: &Value, args: &EmbeddingArgs) -> anyhow::Result<()> {
+    let mut args = args.clone();
+    if let Some(dimensions) = request["dimensions"].as_u64() {
+        // cut embeddings are only comparable once normalized again
+        args.dimensions = Some(dimensions as usize);
+        args.normalize = true;
+    }
+    let base64 = request["encoding_format"] == "base64";
+    if base64 && args.dtype != Dtype::Float {
+        bail!("encoding_format base64 is only for float embeddings on this node");
+    }
+
+    for item in response["data"].as_array_mut().into_iter().flatten() {
+        let embedding = serde_json::from_value::<Vec<f32>>(item["embedding"].take())
+            .map_err(|e| anyhow!("invalid embedding: {}", e))?;
+        let embedding = args.shape(embedding);
+        item["embedding"] = match base64 {
+            // little-endian floats, as OpenAI sends them
+            true => json!(STANDARD.encode(
+                embedding
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<_>>()
+            )),
+            false => args.dtype.encode(&embedding),
+        };
+    }
+
+    Ok(())
+}
+
+// Print the embeddings of the texts as JSON lines, `{"input": ..., "embedding": [...]}`
+pub fn command_embed(
+    client_args: ClientArgs,
+    texts: Vec<String>,
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    args: EmbeddingArgs,
+) -> anyhow::Result<()> {
+    let mut texts = texts;
+    if let Some(input) = &input {
+        let content =
+            fs::read_to_string(input).map_err(|e| anyhow!("{}: {}", input.display(), e))?;
+        texts.extend(
+            content
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(String::from),
+        );
+    }
+    if texts.is_empty() {
+        bail!("Nothing to embed, give texts or a file with one per line");
+    }
+
+    let client = Client::new(&client_args.base_url)?;
+    let model = rag::embedding_model(&client_args)?;
+    let mut writer: Box<dyn Write> = match &output {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut dimensions = None;
+    for batch in texts.chunks(BATCH_SIZE) {
+        let embeddings = client.embeddings(model.as_deref(), batch)?;
+        for (text, embedding) in batch.iter().zip(embeddings) {
+            if args.dimensions.is_some_and(|cut| cut > embedding.len()) {
+                bail!(
+                    "The model gives {} dimensions, fewer than the {} asked for",
+                    embedding.len(),
+                    args.dimensions.unwrap_or_default()
+                );
+            }
+            let embedding = args.shape(embedding);
+            dimensions = Some(embedding.len());
+            writeln!(
+                writer,
+                "{}",
+                json!({ "input": text, "embedding": args.dtype.encode(&embedding) })
+            )?;
+        }
+    }
+    writer.flush()?;
+    if let Some(path) = &output {
+        eprintln!(
+            "Wrote {} embeddings of {} dimensions to {}",
+            texts.len(),
+            dimensions.unwrap_or_default(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use crate::paths;
+use crate::store::{CollectionInfo, Condition, Point, ScoredPoint, VectorStore};
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Vector store kept in files and searched by brute force, for machines that cannot run Qdrant
+pub struct EmbeddedStore {
+    dir: PathBuf,
+}
+
+// A collection as stored in `vectors/<collection>.json` of the rag directory
+#[derive(Debug, Serialize, Deserialize)]
+struct Collection {
+    dimension: usize,
+    points: BTreeMap<u64, Point>,
+}
+
+impl EmbeddedStore {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            dir: paths::rag_dir()?.join("vectors"),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn load(&self, name: &str) -> anyhow::Result<Collection> {
+        let path = self.path(name);
+        let content = fs::read_to_string(&path)
+            .map_err(|_| anyhow!("Collection '{}' does not exist", name))?;
+
+        serde_json::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn save(&self, name: &str, collection: &Collection) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // write then rename, so an interrupted write does not lose the collection
+        let path = self.path(name);
+        let partial = path.with_extension("part");
+        fs::write(&partial, serde_json::to_vec(collection)?)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+}
+
+impl VectorStore for EmbeddedStore {
+    fn collection_exists(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.path(name).exists())
+    }
+
+    fn create_collection(&self, name: &str, dimension: usize) -> anyhow::Result<()> {
+        self.save(
+            name,
+            &Collection {
+                dimension,
+                points: BTreeMap::new(),
+            },
+        )
+    }
+
+    fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()> {
+        let mut stored = self.load(collection)?;
+        for point in points {
+            if point.vector.len() != stored.dimension {
+                bail!(
+                    "Collection '{}' holds vectors of dimension {}, got {}",
+                    collection,
+                    stored.dimension,
+                    point.vector.len()
+                );
+            }
+            stored.points.insert(point.id, point);
+        }
+
+        self.save(collection, &stored)
+    }
+
+    fn delete_matching(&self, collection: &str, key: &str, value: &str) -> anyhow::Result<()> {
+        let mut stored = self.load(collection)?;
+        stored
+            .points
+            .retain(|_, point| point.payload[key].as_str() != Some(value));
+
+        self.save(collection, &stored)
+    }
+
+    fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<ScoredPoint>> {
+        let stored = self.load(collection)?;
+        if vector.len() != stored.dimension {
+            bail!(
+                "Collection '{}' holds vectors of dimension {}, got {}",
+                collection,
+                stored.dimension,
+                vector.len()
+            );
+        }
+
+        let mut hits = stored
+            .points
+            .into_values()
+            .filter(|point| {
+                filter
+                    .iter()
+                    .all(|condition| condition.matches(&point.payload))
+            })
+            .map(|point| ScoredPoint {
+                score: cosine(vector, &point.vector),
+                payload: point.payload,
+            })
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(limit);
+
+        Ok(hits)
+    }
+
+    fn info(&self, collection: &str) -> anyhow::Result<CollectionInfo> {
+        let stored = self.load(collection)?;
+
+        Ok(CollectionInfo {
+            points: stored.points.len() as u64,
+            dimension: stored.dimension,
+            disk_bytes: Some(fs::metadata(self.path(collection))?.len()),
+        })
+    }
+
+    fn scroll(
+        &self,
+        collection: &str,
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<Value>> {
+        Ok(self
+            .load(collection)?
+            .points
+            .into_values()
+            .filter(|point| {
+                filter
+                    .iter()
+                    .all(|condition| condition.matches(&point.payload))
+            })
+            .take(limit)
+            .map(|point| point.payload)
+            .collect())
+    }
+
+    fn export_snapshot(&self, collection: &str, dest: &Path) -> anyhow::Result<u64> {
+        if !self.collection_exists(collection)? {
+            bail!("Collection '{}' does not exist", collection);
+        }
+
+        // the collection file is self-contained, it is its own snapshot
+        Ok(fs::copy(self.path(collection), dest)?)
+    }
+
+    fn import_snapshot(&self, collection: &str, src: &Path) -> anyhow::Result<()> {
+        let content = fs::read_to_string(src).map_err(|e| anyhow!("{}: {}", src.display(), e))?;
+        let snapshot: Collection = serde_json::from_str(&content).map_err(|e| {
+            anyhow!(
+                "{}: not a snapshot of the embedded store, {}",
+                src.display(),
+                e
+            )
+        })?;
+
+        self.save(collection, &snapshot)
+    }
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        return 0.0;
+    }
+
+    dot / norms
+}
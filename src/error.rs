@@ -0,0 +1,151 @@
+use clap::ValueEnum;
+use serde_json::json;
+use std::fmt;
+
+// What went wrong, each with its own exit code so scripts can react to it.
+// Usage errors exit with 2, as clap does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    // a config, node or template file is invalid or missing
+    Config,
+    Download,
+    PortInUse,
+    // a service exited or never came up
+    BackendCrash,
+    // a server could not be reached
+    Unreachable,
+    // a server answered with an error
+    Api,
+    // a model is unsigned or its signature does not match a trusted key
+    Untrusted,
+    // cases of an evaluation suite failed
+    EvalFailed,
+}
+impl ErrorKind {
+    pub fn code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Config => 3,
+            ErrorKind::Download => 4,
+            ErrorKind::PortInUse => 5,
+            ErrorKind::BackendCrash => 6,
+            ErrorKind::Unreachable => 7,
+            ErrorKind::Api => 8,
+            ErrorKind::Untrusted => 9,
+            ErrorKind::EvalFailed => 10,
+        }
+    }
+}
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Other => f.pad("other"),
+            ErrorKind::Config => f.pad("config"),
+            ErrorKind::Download => f.pad("download"),
+            ErrorKind::PortInUse => f.pad("port-in-use"),
+            ErrorKind::BackendCrash => f.pad("backend-crash"),
+            ErrorKind::Unreachable => f.pad("unreachable"),
+            ErrorKind::Api => f.pad("api"),
+            ErrorKind::Untrusted => f.pad("untrusted"),
+            ErrorKind::EvalFailed => f.pad("eval-failed"),
+        }
+    }
+}
+
+// An error marked with its kind, displayed as the error it wraps
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    error: anyhow::Error,
+}
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub fn fail(kind: ErrorKind, error: impl Into<anyhow::Error>) -> anyhow::Error {
+    Failure {
+        kind,
+        error: error.into(),
+    }
+    .into()
+}
+
+pub trait Tag<T> {
+    // Mark the error with its kind, unless it already has one
+    fn tag(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+impl<T, E: Into<anyhow::Error>> Tag<T> for Result<T, E> {
+    fn tag(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|e| {
+            let e = e.into();
+            match e.is::<Failure>() {
+                true => e,
+                false => fail(kind, e),
+            }
+        })
+    }
+}
+
+// The kind of an error, from its mark or else from its causes
+pub fn kind(error: &anyhow::Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return failure.kind;
+        }
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_connect() || e.is_timeout() {
+                return ErrorKind::Unreachable;
+            }
+        }
+        if cause.is::<toml::de::Error>() || cause.is::<serde_yaml::Error>() {
+            return ErrorKind::Config;
+        }
+    }
+
+    ErrorKind::Other
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    // one JSON object on stderr, with the kind, exit code, message and causes
+    Json,
+}
+
+// Print the error and return the exit code for it
+pub fn report(error: &anyhow::Error, format: ErrorFormat) -> i32 {
+    let kind = kind(error);
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {:?}", error),
+        ErrorFormat::Json => {
+            let causes = error
+                .chain()
+                .skip(1)
+                .map(|cause| cause.to_string())
+                .collect::<Vec<_>>();
+            eprintln!(
+                "{}",
+                json!({
+                    "error": {
+                        "kind": kind.to_string(),
+                        "code": kind.code(),
+                        "message": error.to_string(),
+                        "causes": causes,
+                    }
+                })
+            );
+        }
+    }
+
+    kind.code()
+}
@@ -0,0 +1,409 @@
+use crate::client::{ChatRequest, Client, ClientArgs, Message, SamplingArgs};
+use crate::config;
+use crate::embedded;
+use crate::error::{fail, ErrorKind, Tag};
+use crate::throughput;
+use anyhow::anyhow;
+use clap::Args;
+use console::style;
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf, time::Instant};
+
+const JUDGE_PROMPT: &str = "You grade an answer against criteria. Reply with PASS or FAIL alone on the first line, then explain why in one sentence.";
+
+#[derive(Debug, Clone, Args)]
+pub struct BaselineArgs {
+    #[arg(
+        long = "record",
+        help = "Record the answers into this baseline file, to compare later runs with",
+        value_name = "FILE",
+        conflicts_with = "against"
+    )]
+    pub record: Option<PathBuf>,
+    #[arg(
+        long = "against",
+        help = "Compare the answers with those of a baseline recorded with --record, failing the cases whose answer changed",
+        value_name = "FILE"
+    )]
+    pub against: Option<PathBuf>,
+    #[arg(
+        long = "min-similarity",
+        help = "Least similarity of the embeddings of an answer and its baseline for a reworded answer to still match, 1 only accepts the same answer",
+        value_name = "SCORE",
+        default_value_t = 0.9,
+        requires = "against"
+    )]
+    pub min_similarity: f32,
+    #[arg(
+        long = "embedding-model",
+        help = "Embedding model comparing the answers that changed with their baseline",
+        requires = "against"
+    )]
+    pub embedding_model: Option<String>,
+}
+
+// Answers recorded by `gaia eval --record`, with what they were produced with
+#[derive(Debug, Serialize, Deserialize)]
+struct Baseline {
+    model: Option<String>,
+    // WasmEdge version of the api-server
+    runtime: Option<String>,
+    seed: Option<u64>,
+    params: SamplingArgs,
+    // by case name
+    answers: BTreeMap<String, String>,
+}
+
+// Prompts with what their answers are expected to be, read from `gaia eval suite.yaml`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Suite {
+    // model to request, the one the server defaults to otherwise
+    model: Option<String>,
+    system_prompt: Option<String>,
+    #[serde(default)]
+    params: SamplingArgs,
+    // model grading the cases with judge criteria
+    judge: Option<String>,
+    // fixed so that a baseline can be reproduced
+    seed: Option<u64>,
+    cases: Vec<Case>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    name: Option<String>,
+    prompt: String,
+    system_prompt: Option<String>,
+    // the whole answer, ignoring surrounding whitespace
+    equals: Option<String>,
+    // texts the answer must contain, ignoring case
+    #[serde(default)]
+    contains: Vec<String>,
+    #[serde(default)]
+    not_contains: Vec<String>,
+    regex: Option<String>,
+    // criteria the judge model grades the answer by
+    judge: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CaseResult {
+    name: String,
+    passed: bool,
+    // share of the checks passed
+    score: f64,
+    failures: Vec<String>,
+    // what is worth knowing without failing the case
+    notes: Vec<String>,
+    answer: String,
+    secs: f64,
+}
+
+// Check the answer against the expectations of the case, returns how many there are and how
+// the answer misses them
+fn check(
+    case: &Case,
+    answer: &str,
+    regex: Option<&Regex>,
+    judge: Option<(&Client, &str)>,
+) -> anyhow::Result<(usize, Vec<String>)> {
+    let mut checks = 0;
+    let mut failures = Vec::new();
+    let lower = answer.to_lowercase();
+
+    if let Some(expected) = &case.equals {
+        checks += 1;
+        if answer.trim() != expected.trim() {
+            failures.push(format!("expected '{}'", expected.trim()));
+        }
+    }
+    for text in &case.contains {
+        checks += 1;
+        if !lower.contains(&text.to_lowercase()) {
+            failures.push(format!("does not contain '{}'", text));
+        }
+    }
+    for text in &case.not_contains {
+        checks += 1;
+        if lower.contains(&text.to_lowercase()) {
+            failures.push(format!("contains '{}'", text));
+        }
+    }
+    if let (Some(regex), Some(pattern)) = (regex, &case.regex) {
+        checks += 1;
+        if !regex.is_match(answer) {
+            failures.push(format!("does not match /{}/", pattern));
+        }
+    }
+    if let (Some(criteria), Some((client, model))) = (&case.judge, judge) {
+        checks += 1;
+        if let Some(reason) = ask_judge(client, model, &case.prompt, answer, criteria)? {
+            failures.push(format!("judge: {}", reason));
+        }
+    }
+
+    Ok((checks, failures))
+}
+
+// Why the judge failed the answer, None when it passed it
+fn ask_judge(
+    client: &Client,
+    model: &str,
+    prompt: &str,
+    answer: &str,
+    criteria: &str,
+) -> anyhow::Result<Option<String>> {
+    let request = ChatRequest {
+        model: Some(model.to_string()),
+        messages: vec![
+            Message::new("system", JUDGE_PROMPT),
+            Message::new(
+                "user",
+                format!(
+                    "Criteria:\n{}\n\nPrompt:\n{}\n\nAnswer:\n{}",
+                    criteria, prompt, answer
+                ),
+            ),
+        ],
+        stream: false,
+        sampling: SamplingArgs {
+            temperature: Some(0.0),
+            ..SamplingArgs::default()
+        },
+        tools: None,
+        response_format: None,
+    };
+    let (reply, _) = client
+        .chat(&request)
+        .map_err(|e| anyhow!("The judge {} failed: {}", model, e))?;
+    let verdict = reply.text();
+    let mut lines = verdict.trim().lines();
+    let pick = lines.next().unwrap_or_default().to_uppercase();
+    let reason = lines.collect::<Vec<_>>().join(" ").trim().to_string();
+
+    Ok(match pick.contains("PASS") && !pick.contains("FAIL") {
+        true => None,
+        false if reason.is_empty() => Some(verdict.trim().to_string()),
+        false => Some(reason),
+    })
+}
+
+// Run every case of the suite against the running model and fail when any of them fails
+pub fn command_eval(
+    file: PathBuf,
+    client_args: ClientArgs,
+    sampling: SamplingArgs,
+    judge: Option<String>,
+    report: Option<PathBuf>,
+    baseline_args: BaselineArgs,
+) -> anyhow::Result<()> {
+    let suite: Suite = config::load(&file)?;
+    let baseline = baseline_args
+        .against
+        .as_ref()
+        .map(|path| -> anyhow::Result<Baseline> {
+            let content =
+                fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            serde_json::from_str(&content)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))
+                .tag(ErrorKind::Config)
+        })
+        .transpose()?;
+    if let Some(baseline) = &baseline {
+        println!(
+            "{}",
+            style(format!(
+                "Comparing with the answers of {} on WasmEdge {}",
+                baseline.model.as_deref().unwrap_or("an unknown model"),
+                baseline.runtime.as_deref().unwrap_or("?")
+            ))
+            .dim()
+        );
+    }
+    let judge = judge.or(suite.judge.clone());
+    let mut regexes = Vec::new();
+    for (i, case) in suite.cases.iter().enumerate() {
+        let name = case_name(case, i);
+        if case.judge.is_some() && judge.is_none() {
+            return Err(fail(
+                ErrorKind::Config,
+                anyhow!(
+                    "{}: case '{}' has judge criteria but no judge model is set, add `judge` to the suite or use --judge",
+                    file.display(),
+                    name
+                ),
+            ));
+        }
+        let regex = case
+            .regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| anyhow!("{}: case '{}': invalid regex: {}", file.display(), name, e))
+            .tag(ErrorKind::Config)?;
+        regexes.push(regex);
+    }
+
+    let client = Client::new(&client_args.base_url)?;
+    let mut sampling = sampling.or(&suite.params);
+    sampling.seed = sampling.seed.or(suite.seed);
+    let mut results = Vec::new();
+    let mut recorded = Baseline {
+        model: None,
+        runtime: throughput::runtime(),
+        seed: sampling.seed,
+        params: sampling.clone(),
+        answers: BTreeMap::new(),
+    };
+    for (i, (case, regex)) in suite.cases.iter().zip(&regexes).enumerate() {
+        let name = case_name(case, i);
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = case.system_prompt.as_ref().or(suite.system_prompt.as_ref()) {
+            messages.push(Message::new("system", system_prompt.as_str()));
+        }
+        messages.push(Message::new("user", case.prompt.as_str()));
+        let request = ChatRequest {
+            model: client_args.model_name.clone().or(suite.model.clone()),
+            messages,
+            stream: false,
+            sampling: sampling.clone(),
+            tools: None,
+            response_format: None,
+        };
+
+        let started = Instant::now();
+        let (reply, throughput) = client
+            .chat(&request)
+            .map_err(|e| anyhow!("Case '{}': {}", name, e))?;
+        let secs = started.elapsed().as_secs_f64();
+        let answer = reply.text();
+        let (mut checks, mut failures) = check(
+            case,
+            &answer,
+            regex.as_ref(),
+            judge.as_deref().map(|judge| (&client, judge)),
+        )?;
+        let mut notes = Vec::new();
+        if let Some(baseline) = &baseline {
+            match baseline.answers.get(&name) {
+                Some(expected) => {
+                    checks += 1;
+                    match compare(&client, &baseline_args, expected, &answer) {
+                        Change::Same => {}
+                        Change::Reworded(similarity) => notes.push(format!(
+                            "reworded from the baseline (similarity {:.2})",
+                            similarity
+                        )),
+                        Change::Changed(change) => failures.push(change),
+                    }
+                }
+                None => notes.push("not in the baseline".to_string()),
+            }
+        }
+        recorded.model = throughput.model.or(recorded.model);
+        recorded.answers.insert(name.clone(), answer.clone());
+        let score = match checks {
+            0 => 1.0,
+            _ => (checks - failures.len()) as f64 / checks as f64,
+        };
+
+        let result = CaseResult {
+            name,
+            passed: failures.is_empty(),
+            score,
+            failures,
+            notes,
+            answer,
+            secs,
+        };
+        print_result(&result);
+        results.push(result);
+    }
+
+    let passed = results.iter().filter(|result| result.passed).count();
+    let mean = results.iter().map(|result| result.score).sum::<f64>() / results.len().max(1) as f64;
+    println!(
+        "{} of {} cases passed, mean score {:.2}",
+        passed,
+        results.len(),
+        mean
+    );
+    if let Some(report) = report {
+        fs::write(&report, serde_json::to_string_pretty(&results)?)?;
+        println!("Wrote the results to {}", report.display());
+    }
+    if let Some(path) = &baseline_args.record {
+        fs::write(path, serde_json::to_string_pretty(&recorded)?)?;
+        println!("Recorded the answers as the baseline in {}", path.display());
+    }
+    if passed < results.len() {
+        return Err(fail(
+            ErrorKind::EvalFailed,
+            anyhow!("{} cases failed", results.len() - passed),
+        ));
+    }
+
+    Ok(())
+}
+
+// How an answer compares with its baseline
+enum Change {
+    Same,
+    // as similar by meaning as the embeddings tell
+    Reworded(f32),
+    Changed(String),
+}
+
+fn compare(client: &Client, args: &BaselineArgs, expected: &str, answer: &str) -> Change {
+    if expected.trim() == answer.trim() {
+        return Change::Same;
+    }
+    if args.min_similarity >= 1.0 {
+        return Change::Changed("differs from the baseline".to_string());
+    }
+
+    let input = [expected.to_string(), answer.to_string()];
+    match client.embeddings(args.embedding_model.as_deref(), &input) {
+        Ok(embeddings) => {
+            let similarity = embedded::cosine(&embeddings[0], &embeddings[1]);
+            match similarity >= args.min_similarity {
+                true => Change::Reworded(similarity),
+                false => Change::Changed(format!(
+                    "differs from the baseline (similarity {:.2})",
+                    similarity
+                )),
+            }
+        }
+        Err(e) => Change::Changed(format!(
+            "differs from the baseline, and could not be compared by meaning: {}",
+            e
+        )),
+    }
+}
+
+fn case_name(case: &Case, i: usize) -> String {
+    case.name.clone().unwrap_or(format!("#{}", i + 1))
+}
+
+fn print_result(result: &CaseResult) {
+    let mark = match result.passed {
+        true => style("pass").green(),
+        false => style("FAIL").red(),
+    };
+    println!(
+        "{} {}  score {:.2}  {}",
+        mark,
+        style(&result.name).bold(),
+        result.score,
+        style(format!("{:.1}s", result.secs)).dim()
+    );
+    for failure in &result.failures {
+        println!("    {}", failure);
+    }
+    for note in &result.notes {
+        println!("    {}", style(note).dim());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::blob::Utc;
+use crate::identity;
+use crate::paths;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::style;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+static WEBHOOKS: OnceLock<Vec<String>> = OnceLock::new();
+
+#[derive(Debug, Clone, Args)]
+pub struct EventArgs {
+    #[arg(
+        long = "webhook",
+        env = "GAIA_WEBHOOKS",
+        help = "URL to POST a JSON payload to on each event `gaia events` lists, e.g. a service starting, stopping or crashing, a model downloaded or a WasmEdge upgrade available, repeat or separate with commas for several",
+        value_name = "URL",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub webhooks: Vec<String>,
+}
+
+pub fn init(args: &EventArgs) {
+    let _ = WEBHOOKS.set(args.webhooks.clone());
+}
+
+// Every event, one JSON object per line, only ever appended to
+fn log_path() -> anyhow::Result<PathBuf> {
+    Ok(paths::log_dir()?.join("events.jsonl"))
+}
+
+// Who ran gaia, several people may share the machine
+fn actor() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or("unknown".to_string())
+}
+
+// Something that happened to the node, e.g. `started` with the service and its port. It is
+// appended to the event log and the webhooks are told of it, neither failing the command.
+pub fn emit(event: &str, fields: Value) {
+    let mut payload = json!({
+        "event": event,
+        "time": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default(),
+        "actor": actor(),
+        "node": identity::name(),
+    });
+    if let (Some(payload), Value::Object(fields)) = (payload.as_object_mut(), fields) {
+        payload.extend(fields);
+    }
+    if let Err(e) = record(&payload) {
+        tracing::warn!("cannot record the {} event: {}", event, e);
+    }
+
+    let webhooks = WEBHOOKS.get().map(Vec::as_slice).unwrap_or_default();
+    if webhooks.is_empty() {
+        return;
+    }
+    let http = match reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(http) => http,
+        Err(e) => {
+            tracing::warn!("cannot send the {} event: {}", event, e);
+            return;
+        }
+    };
+    for url in webhooks {
+        let sent = http
+            .post(url)
+            .json(&payload)
+            .send()
+            .and_then(|response| response.error_for_status());
+        match sent {
+            Ok(_) => tracing::debug!(url, event, "sent the event"),
+            Err(e) => tracing::warn!(url, "failed to send the {} event: {}", event, e),
+        }
+    }
+}
+
+fn record(payload: &Value) -> anyhow::Result<()> {
+    fs::create_dir_all(paths::log_dir()?)?;
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path()?)?;
+    // a single write keeps the lines of concurrent gaia processes whole
+    log.write_all(format!("{}\n", payload).as_bytes())?;
+
+    Ok(())
+}
+
+// A duration such as 90s, 30m, 24h or 7d
+pub fn parse_age(arg: &str) -> anyhow::Result<Duration> {
+    let arg = arg.trim();
+    let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let (number, unit) = arg.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Expected a duration such as 30m, 24h or 7d, got '{}'", arg))?;
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => bail!("Unknown unit '{}' in '{}', use s, m, h, d or w", unit, arg),
+    };
+
+    Ok(Duration::from_secs(number * secs))
+}
+
+// Print the recorded events, the oldest first
+pub fn command_events(
+    since: Option<Duration>,
+    event: Option<String>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let path = log_path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => bail!("{}: {}", path.display(), e),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let after = since.map(|since| now.saturating_sub(since.as_secs()));
+
+    let mut shown = 0;
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        // a line cut short by a crash is skipped rather than hiding the others
+        let Ok(payload) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        let time = payload["time"].as_u64().unwrap_or_default();
+        if after.is_some_and(|after| time < after) {
+            continue;
+        }
+        if event
+            .as_deref()
+            .is_some_and(|event| payload["event"] != event)
+        {
+            continue;
+        }
+        shown += 1;
+        if json {
+            println!("{}", line);
+            continue;
+        }
+
+        // the scalar fields, the longer ones such as logs and model lists are in --json
+        let details = payload
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| !["event", "time", "actor", "node"].contains(&key.as_str()))
+            .filter_map(|(key, value)| match value {
+                Value::String(value) if !value.contains('\n') => Some(format!("{}={}", key, value)),
+                Value::Number(_) | Value::Bool(_) => Some(format!("{}={}", key, value)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        println!(
+            "{}  {:<10} {:<18} {}",
+            style(Utc::of(time).timestamp()).dim(),
+            payload["actor"].as_str().unwrap_or("?"),
+            style(payload["event"].as_str().unwrap_or("?")).bold(),
+            details.join(" ")
+        );
+    }
+    if shown == 0 && !json {
+        println!("No events recorded");
+    }
+
+    Ok(())
+}
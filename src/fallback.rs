@@ -0,0 +1,97 @@
+use anyhow::anyhow;
+use reqwest::{
+    blocking::{Client, RequestBuilder},
+    Method,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+
+// Header of the gateway's responses naming the backend that answered, `local`, `peer URL`,
+// `remote NAME` for a routing rule or `fallback NAME`
+pub const BACKEND: &str = "X-Gaia-Backend";
+
+// A remote OpenAI compatible provider, one of `gateway.fallback` tried in order when the backend
+// of a request is down or overloaded or the `remote` of a routing rule, e.g.
+// `{ name = "groq", url = "https://api.groq.com/openai/v1", api_key = "$GROQ_API_KEY", model = "llama-3.1-8b-instant" }`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    // what the responses it answers are marked with, the host of the url when not set
+    pub name: Option<String>,
+    // base url of its OpenAI API
+    pub url: String,
+    // key it asks for, `$NAME` reading it from the environment
+    pub api_key: Option<String>,
+    // model asked for in place of the one the request names
+    pub model: Option<String>,
+}
+impl RemoteConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            let host = self
+                .url
+                .split_once("://")
+                .map_or(&*self.url, |(_, rest)| rest);
+            host.split(['/', ':']).next().unwrap_or(host).to_string()
+        })
+    }
+
+    fn key(&self) -> anyhow::Result<Option<String>> {
+        self.api_key
+            .as_ref()
+            .map(|key| match key.strip_prefix('$') {
+                Some(name) => env::var(name).map_err(|_| {
+                    anyhow!("the key of {} names ${}, which is not set", self.url, name)
+                }),
+                None => Ok(key.clone()),
+            })
+            .transpose()
+    }
+
+    // A request to the path of the provider's OpenAI API, with its key
+    pub fn request(
+        &self,
+        http: &Client,
+        method: Method,
+        path: &str,
+    ) -> anyhow::Result<RequestBuilder> {
+        let url = format!(
+            "{}/{}",
+            self.url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let mut request = http.request(method, url);
+        if let Some(key) = self.key()? {
+            request = request.bearer_auth(key);
+        }
+
+        Ok(request)
+    }
+
+    // The request as the provider takes it, asking for its model
+    pub fn shape(&self, body: &mut Value) {
+        let Some(fields) = body.as_object_mut() else {
+            return;
+        };
+        if let Some(model) = &self.model {
+            fields.insert("model".to_string(), json!(model));
+        }
+    }
+
+    // `shape` for a body as read, passed on as it is when it is no JSON
+    pub fn body(&self, body: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(mut body) => {
+                self.shape(&mut body);
+                body.to_string().into_bytes()
+            }
+            Err(_) => body.to_vec(),
+        }
+    }
+}
+
+// Statuses of a backend that cannot answer now, rather than of a request it refuses
+pub fn is_unavailable(status: u16) -> bool {
+    matches!(status, 429 | 500 | 502 | 503 | 504)
+}
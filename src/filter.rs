@@ -0,0 +1,309 @@
+use crate::server;
+use anyhow::{anyhow, bail};
+use regex_automata::meta::Regex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+// Told to the client when a filter blocks without saying why
+const DEFAULT_MESSAGE: &str = "The content was blocked by a filter of this node";
+
+// A filter the gateway runs on the prompts before generation or on the replies after it, from
+// the `gateway.filters` of a node file, e.g.
+//
+//   [[gateway.filters]]
+//   stage = "request"
+//   deny = ["(?i)ignore (all )?previous instructions"]
+//
+//   [[gateway.filters]]
+//   stage = "response"
+//   replace = [{ pattern = "\\b\\d{3}-\\d{2}-\\d{4}\\b", with = "[SSN]" }]
+//   command = "python3 moderate.py"
+//
+// A command, or a wasm module run by WasmEdge, reads {"stage": ..., "text": ...} on stdin and
+// prints {"action": "allow"}, {"action": "block", "reason": ...} or
+// {"action": "rewrite", "text": ...}. One that fails blocks.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    pub stage: Stage,
+    // regular expressions blocking the text they match
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub replace: Vec<Replacement>,
+    pub command: Option<String>,
+    pub wasm: Option<PathBuf>,
+    // told to the client when the filter blocks
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Request,
+    Response,
+}
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Request => "request",
+            Stage::Response => "response",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replacement {
+    pub pattern: String,
+    pub with: String,
+}
+
+enum Hook {
+    Command(String),
+    Wasm(PathBuf),
+}
+
+struct Filter {
+    stage: Stage,
+    deny: Vec<Regex>,
+    replace: Vec<(Regex, String)>,
+    hook: Option<Hook>,
+    message: String,
+}
+impl Filter {
+    fn new(config: &FilterConfig, i: usize) -> anyhow::Result<Self> {
+        let compile = |pattern: &str| {
+            Regex::new(pattern)
+                .map_err(|e| anyhow!("gateway.filters[{}]: invalid regex '{}': {}", i, pattern, e))
+        };
+        let hook = match (&config.command, &config.wasm) {
+            (Some(_), Some(_)) => bail!(
+                "gateway.filters[{}]: set either command or wasm, not both",
+                i
+            ),
+            (Some(command), None) => Some(Hook::Command(command.clone())),
+            (None, Some(wasm)) => Some(Hook::Wasm(wasm.clone())),
+            (None, None) => None,
+        };
+        if config.deny.is_empty() && config.replace.is_empty() && hook.is_none() {
+            bail!(
+                "gateway.filters[{}] does nothing, add deny, replace, command or wasm",
+                i
+            );
+        }
+
+        Ok(Self {
+            stage: config.stage,
+            deny: config
+                .deny
+                .iter()
+                .map(|pattern| compile(pattern))
+                .collect::<anyhow::Result<_>>()?,
+            replace: config
+                .replace
+                .iter()
+                .map(|replacement| Ok((compile(&replacement.pattern)?, replacement.with.clone())))
+                .collect::<anyhow::Result<_>>()?,
+            hook,
+            message: config
+                .message
+                .clone()
+                .unwrap_or(DEFAULT_MESSAGE.to_string()),
+        })
+    }
+
+    // The text as the filter lets it through, or why it is blocked
+    fn apply(&self, text: &str) -> Result<String, String> {
+        if self.deny.iter().any(|regex| regex.is_match(text)) {
+            return Err(self.message.clone());
+        }
+        let mut text = text.to_string();
+        for (regex, with) in &self.replace {
+            let mut replaced = String::with_capacity(text.len());
+            let mut last = 0;
+            for found in regex.find_iter(&text) {
+                replaced.push_str(&text[last..found.start()]);
+                replaced.push_str(with);
+                last = found.end();
+            }
+            replaced.push_str(&text[last..]);
+            text = replaced;
+        }
+        let Some(hook) = &self.hook else {
+            return Ok(text);
+        };
+
+        let verdict = self.run(hook, &text).map_err(|e| {
+            tracing::warn!("a {} filter hook failed: {:#}", self.stage.name(), e);
+            self.message.clone()
+        })?;
+        match verdict["action"].as_str().unwrap_or("allow") {
+            "block" => Err(verdict["reason"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or(self.message.clone())),
+            "rewrite" => Ok(verdict["text"].as_str().map(String::from).unwrap_or(text)),
+            _ => Ok(text),
+        }
+    }
+
+    fn run(&self, hook: &Hook, text: &str) -> anyhow::Result<Value> {
+        let mut command = match hook {
+            Hook::Command(command) if cfg!(windows) => {
+                let mut shell = Command::new("cmd");
+                shell.arg("/C").arg(command);
+                shell
+            }
+            Hook::Command(command) => {
+                let mut shell = Command::new("sh");
+                shell.arg("-c").arg(command);
+                shell
+            }
+            Hook::Wasm(module) => {
+                let mut wasmedge = Command::new(server::wasmedge()?);
+                wasmedge.arg(module);
+                wasmedge
+            }
+        };
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = json!({ "stage": self.stage.name(), "text": text });
+        child
+            .stdin
+            .take()
+            .ok_or(anyhow!("No stdin to write the text to"))?
+            .write_all(input.to_string().as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("it exited with {}", output.status);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // printing nothing lets the text through
+        if stdout.trim().is_empty() {
+            return Ok(json!({ "action": "allow" }));
+        }
+
+        serde_json::from_str(stdout.trim()).map_err(|e| anyhow!("invalid verdict: {}", e))
+    }
+}
+
+// The filters of the gateway, in the order of the node file
+pub struct Filters(Vec<Filter>);
+impl Filters {
+    pub fn new(configs: &[FilterConfig]) -> anyhow::Result<Self> {
+        configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| Filter::new(config, i))
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+
+    // Whether replies are filtered, which holds them back until they are complete
+    pub fn on_responses(&self) -> bool {
+        self.0.iter().any(|filter| filter.stage == Stage::Response)
+    }
+
+    fn apply(&self, stage: Stage, text: &str) -> Result<String, String> {
+        let mut text = text.to_string();
+        for filter in self.0.iter().filter(|filter| filter.stage == stage) {
+            text = filter.apply(&text)?;
+        }
+        Ok(text)
+    }
+
+    // Filter the messages or prompt of an OpenAI request, the reason when one is blocked
+    pub fn request(&self, body: &mut Value) -> Result<(), String> {
+        if let Some(prompt) = body["prompt"].as_str() {
+            body["prompt"] = json!(self.apply(Stage::Request, prompt)?);
+        }
+        for message in body["messages"].as_array_mut().into_iter().flatten() {
+            match &mut message["content"] {
+                Value::String(content) => *content = self.apply(Stage::Request, content)?,
+                Value::Array(parts) => {
+                    for part in parts {
+                        if let Some(text) = part["text"].as_str() {
+                            part["text"] = json!(self.apply(Stage::Request, text)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Filter the choices of an OpenAI reply, those blocked are replaced by the reason and end
+    // with the `content_filter` finish reason
+    pub fn response(&self, body: &mut Value) {
+        for choice in body["choices"].as_array_mut().into_iter().flatten() {
+            let (field, text) = match (
+                choice["message"]["content"].as_str(),
+                choice["text"].as_str(),
+            ) {
+                (Some(content), _) => ("content", content.to_string()),
+                (None, Some(text)) => ("text", text.to_string()),
+                (None, None) => continue,
+            };
+            let filtered = match self.apply(Stage::Response, &text) {
+                Ok(filtered) => filtered,
+                Err(reason) => {
+                    choice["finish_reason"] = json!("content_filter");
+                    reason
+                }
+            };
+            match field {
+                "content" => choice["message"]["content"] = json!(filtered),
+                _ => choice["text"] = json!(filtered),
+            }
+        }
+    }
+}
+
+// The events of a stream carrying a whole chat reply, for streamed requests whose reply was
+// held back to be filtered
+pub fn as_events(response: &Value, include_usage: bool) -> Vec<u8> {
+    let mut events = String::new();
+    let mut event = |chunk: Value| events.push_str(&format!("data: {}\n\n", chunk));
+    let choices = response["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|choice| {
+            let mut delta = json!({ "role": "assistant", "content": choice["message"]["content"] });
+            if !choice["message"]["tool_calls"].is_null() {
+                delta["tool_calls"] = choice["message"]["tool_calls"].clone();
+            }
+            json!({ "index": choice["index"], "delta": delta, "finish_reason": choice["finish_reason"] })
+        })
+        .collect::<Vec<_>>();
+    event(json!({
+        "id": response["id"],
+        "object": "chat.completion.chunk",
+        "created": response["created"],
+        "model": response["model"],
+        "choices": choices,
+    }));
+    if include_usage {
+        event(json!({
+            "id": response["id"],
+            "object": "chat.completion.chunk",
+            "created": response["created"],
+            "model": response["model"],
+            "choices": [],
+            "usage": response["usage"],
+        }));
+    }
+    events.push_str("data: [DONE]\n\n");
+
+    events.into_bytes()
+}
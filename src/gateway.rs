@@ -0,0 +1,2034 @@
+use crate::anthropic;
+use crate::attachment;
+use crate::audit::{self, RequestLog, RequestLogConfig};
+use crate::embed::{self, EmbeddingArgs};
+use crate::events;
+use crate::fallback::{self, RemoteConfig};
+use crate::filter::{self, FilterConfig, Filters};
+use crate::memory;
+use crate::node;
+use crate::ollama_api;
+use crate::overflow::{self, Fitted, OverflowConfig};
+use crate::peers::{self, PeerConfig};
+use crate::quota::{self, ApiKey, ApiKeyConfig, Metered};
+use crate::routing::{self, RoutingRule, Target};
+use crate::server::{self, ServiceState};
+use crate::start::{self, StartArgs};
+use crate::telemetry;
+use crate::template::{self, ChatEvents, ChatTemplate, PromptTemplateType};
+use crate::websocket;
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, OnceLock, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::field;
+
+pub const DEFAULT_GATEWAY_PORT: u16 = 8000;
+
+// Largest request read, requests with attachments are at most a few megabytes
+const MAX_BODY: usize = 64 * 1024 * 1024;
+// Bytes of the request line and of each header, and headers of a request, at most
+const MAX_HEADER_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// Time a client has to send its request, and to take each write of the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+// Connections answered at once, those over it are refused with 503
+const MAX_CONNECTIONS: usize = 512;
+// Bytes of a response kept for the request log
+const MAX_CAPTURE: usize = 4 * 1024 * 1024;
+
+// Requests being answered through the gateway, by port of the service, set once it listens
+static IN_FLIGHT: OnceLock<Mutex<HashMap<u16, u64>>> = OnceLock::new();
+// The gateway answering new connections, replaced by `reload`
+static CURRENT: RwLock<Option<Arc<Gateway>>> = RwLock::new(None);
+// Connections being answered
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// The port `gaia daemon` answers OpenAI requests on, passing them to the service serving the
+// model they ask for, from the `gateway` section of a node file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // models loaded at the same time at most, requests for other models wait
+    #[serde(default = "default_max_loading")]
+    pub max_loading: usize,
+    // models loaded on their first request
+    #[serde(default)]
+    pub models: Vec<OnDemandModel>,
+    // also answers the Ollama API on this port, e.g. 11434, for tools speaking only that
+    #[serde(default)]
+    pub ollama_port: Option<u16>,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    pub request_log: Option<RequestLogConfig>,
+    pub context_overflow: Option<OverflowConfig>,
+    // how embeddings are shaped, requests sending `dimensions` are cut and normalized anyway
+    pub embeddings: Option<EmbeddingArgs>,
+    // run on the prompts before generation and on the replies after it, in order
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    // keys the clients send as `Authorization: Bearer KEY` or `x-api-key`, `$NAME` reading one
+    // from the environment, with the quotas of each; without any every request is let in
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    // other nodes answering for the models this one does not serve
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    // where the requests for the models matching each glob go, tried in order before the
+    // models the node serves
+    #[serde(default)]
+    pub routing: Vec<RoutingRule>,
+    // remote providers answering in order the requests the node cannot answer now, for a model
+    // it does not serve or whose backend is down or overloaded
+    #[serde(default)]
+    pub fallback: Vec<RemoteConfig>,
+}
+
+// How streamed replies pass through proxies such as nginx or Cloudflare, which may buffer them
+// or close a connection quiet for too long, from `gateway.streaming`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamingConfig {
+    // a comment is sent after this long without an event so that proxies keep the connection,
+    // 0 never sends one
+    #[serde(default = "default_heartbeat_secs")]
+    pub heartbeat_secs: u64,
+    // events are sent together at most this often, 0 sends each as it comes
+    #[serde(default)]
+    pub flush_interval_ms: u64,
+    // whether proxies may buffer the reply, nginx is told not to unless set
+    #[serde(default)]
+    pub proxy_buffering: bool,
+}
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_secs: default_heartbeat_secs(),
+            flush_interval_ms: 0,
+            proxy_buffering: false,
+        }
+    }
+}
+impl StreamingConfig {
+    // Headers of a streamed reply keeping proxies from holding it back
+    pub fn headers(&self) -> &'static str {
+        match self.proxy_buffering {
+            true => "",
+            false => "X-Accel-Buffering: no\r\nCache-Control: no-cache\r\n",
+        }
+    }
+}
+
+// Defaults and caps of the generation requests passing through, whatever the clients send, to
+// keep a shared node from being hogged, from `gateway.limits`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    // max_tokens of the requests sending none
+    pub default_max_tokens: Option<u64>,
+    // larger max_tokens are lowered to it
+    pub max_tokens: Option<u64>,
+    pub default_temperature: Option<f64>,
+    // higher temperatures are lowered to it
+    pub max_temperature: Option<f64>,
+    // seed of the requests sending none, so that their replies can be reproduced
+    pub default_seed: Option<u64>,
+    // tokens of the prompt and reply together, longer prompts are refused and max_tokens
+    // lowered to what is left
+    pub max_context_tokens: Option<u64>,
+}
+impl LimitsConfig {
+    fn is_set(&self) -> bool {
+        self.default_max_tokens.is_some()
+            || self.max_tokens.is_some()
+            || self.default_temperature.is_some()
+            || self.max_temperature.is_some()
+            || self.default_seed.is_some()
+            || self.max_context_tokens.is_some()
+    }
+
+    // Set the defaults and caps on the OpenAI request, why it is refused when its prompt is too
+    // long
+    pub fn apply(&self, body: &mut Value) -> Result<(), String> {
+        // newer clients send max_completion_tokens instead
+        let field = match body.get("max_completion_tokens") {
+            Some(_) => "max_completion_tokens",
+            None => "max_tokens",
+        };
+        let mut max_tokens = body[field].as_u64().or(self.default_max_tokens);
+        if let Some(cap) = self.max_tokens {
+            max_tokens = Some(max_tokens.map_or(cap, |max_tokens| max_tokens.min(cap)));
+        }
+        if let Some(context) = self.max_context_tokens {
+            let prompt = prompt_tokens(body);
+            if prompt >= context {
+                return Err(format!(
+                    "The prompt of about {} tokens is over the {} tokens a request may use on this node",
+                    prompt, context
+                ));
+            }
+            let left = context - prompt;
+            max_tokens = Some(max_tokens.map_or(left, |max_tokens| max_tokens.min(left)));
+        }
+        if let Some(max_tokens) = max_tokens {
+            body[field] = json!(max_tokens);
+        }
+
+        let mut temperature = body["temperature"].as_f64().or(self.default_temperature);
+        if let Some(cap) = self.max_temperature {
+            temperature = temperature.map(|temperature| temperature.min(cap));
+        }
+        if let Some(temperature) = temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if let Some(seed) = self.default_seed {
+            if body["seed"].is_null() {
+                body["seed"] = json!(seed);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Rough tokens of the messages or prompt of an OpenAI request
+fn prompt_tokens(body: &Value) -> u64 {
+    let mut tokens = body["prompt"]
+        .as_str()
+        .map_or(0, attachment::estimate_tokens);
+    for message in body["messages"].as_array().into_iter().flatten() {
+        match &message["content"] {
+            Value::String(content) => tokens += attachment::estimate_tokens(content),
+            Value::Array(parts) => {
+                for part in parts {
+                    tokens += part["text"].as_str().map_or(0, attachment::estimate_tokens);
+                }
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+// The body of a reply to the request, counted against the key of the request if it has one
+fn metered(body: Box<dyn Read + Send>, request: &Value) -> Box<dyn Read + Send> {
+    match quota::current() {
+        Some(id) => Box::new(Metered::new(
+            body,
+            id,
+            request["stream"] == true,
+            prompt_tokens(request),
+        )),
+        None => body,
+    }
+}
+
+// Whether the path is of a request generating text, those the limits and filters apply to
+fn is_generation(path: &str) -> bool {
+    is_path(path, "chat/completions") || is_path(path, "completions")
+}
+
+// Whether the path is the OpenAI route, e.g. `embeddings` for /v1/embeddings
+fn is_path(path: &str, route: &str) -> bool {
+    let path = path.split('?').next().unwrap_or_default();
+    let path = path.trim_matches('/');
+    path.strip_prefix("v1/").unwrap_or(path) == route
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnDemandModel {
+    // url or path, relative to the node file
+    pub model: String,
+    #[serde(deserialize_with = "node::deserialize_template")]
+    pub prompt_template: PromptTemplateType,
+    // Jinja file the prompts are rendered with, as `chat.chat_template`
+    pub chat_template: Option<String>,
+    pub name: Option<String>,
+    pub context_size: Option<u64>,
+}
+impl OnDemandModel {
+    // What requests ask for it by
+    pub fn name(&self) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| server::model_name(&self.model))
+    }
+}
+
+fn default_port() -> u16 {
+    DEFAULT_GATEWAY_PORT
+}
+
+fn default_max_loading() -> usize {
+    1
+}
+
+fn default_heartbeat_secs() -> u64 {
+    15
+}
+
+// Service serving the on-demand model, each in its own api-server
+fn service_name(model: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    let model = model
+        .chars()
+        .map(|c| if safe(c) { c } else { '-' })
+        .collect::<String>();
+    format!("{}@{}", server::API_SERVER, model)
+}
+
+fn is_on_demand(state: &ServiceState) -> bool {
+    state.name.starts_with(&format!("{}@", server::API_SERVER))
+}
+
+// The path and chat template of the model the service serves with a chat template
+fn templated(state: &ServiceState) -> Option<(String, String)> {
+    state
+        .models
+        .iter()
+        .find_map(|model| Some((model.path.clone(), model.chat_template.clone()?)))
+}
+
+// Listen on the port of the gateway and answer its requests in the background
+pub fn spawn(config: GatewayConfig) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))
+        .map_err(|e| anyhow!("The gateway cannot listen on port {}: {}", config.port, e))?;
+    let ollama = config
+        .ollama_port
+        .map(|port| {
+            TcpListener::bind(("0.0.0.0", port)).map_err(|e| {
+                anyhow!(
+                    "The gateway cannot answer the Ollama API on port {}: {}",
+                    port,
+                    e
+                )
+            })
+        })
+        .transpose()?;
+    println!(
+        "The gateway is listening at http://localhost:{}/v1, {} models load on demand",
+        config.port,
+        config.models.len()
+    );
+    if let Some(port) = config.ollama_port {
+        println!("It answers the Ollama API at http://localhost:{}", port);
+    }
+    IN_FLIGHT.get_or_init(Mutex::default);
+    let gateway = Gateway::new(config, Arc::default())?;
+    *CURRENT.write().unwrap() = Some(Arc::new(gateway));
+    if let Some(ollama) = ollama {
+        thread::spawn(move || accept(ollama));
+    }
+    thread::spawn(move || accept(listener));
+
+    Ok(())
+}
+
+// Answer new requests with the settings of the node file as it is now: keys, quotas, limits,
+// filters, streaming, the request log and the overflow policy. Requests under way finish with
+// the old ones. The ports, the on-demand models and the prompt cache stay as the gateway started
+// with them, returns those that changed.
+pub fn reload(mut config: GatewayConfig) -> anyhow::Result<Vec<&'static str>> {
+    let Some(old) = CURRENT.read().unwrap().clone() else {
+        bail!("the gateway is not running");
+    };
+    let mut restart = Vec::new();
+    if config.port != old.config.port {
+        restart.push("gateway.port");
+    }
+    if config.ollama_port != old.config.ollama_port {
+        restart.push("gateway.ollama_port");
+    }
+    if config.models != old.config.models || config.max_loading != old.config.max_loading {
+        restart.push("gateway.models");
+    }
+    config.port = old.config.port;
+    config.ollama_port = old.config.ollama_port;
+    config.models = old.config.models.clone();
+    config.max_loading = old.config.max_loading;
+
+    let gateway = Gateway::new(config, old.on_demand.clone())?;
+    *CURRENT.write().unwrap() = Some(Arc::new(gateway));
+
+    Ok(restart)
+}
+
+// Port of the running gateway and the models it loads on demand
+pub fn listening() -> Option<(u16, Vec<String>)> {
+    let gateway = CURRENT.read().unwrap().clone()?;
+    let models = gateway
+        .config
+        .models
+        .iter()
+        .map(OnDemandModel::name)
+        .collect();
+
+    Some((gateway.config.port, models))
+}
+
+fn accept(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!("gateway failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let Some(gateway) = CURRENT.read().unwrap().clone() else {
+            continue;
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(CLIENT_TIMEOUT))
+            .and(stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+        {
+            tracing::warn!("gateway failed to set up a connection: {}", e);
+            continue;
+        }
+        let Some(connection) = Connection::open() else {
+            tracing::warn!("gateway refused a connection, {} are open", MAX_CONNECTIONS);
+            let _ = respond_error(
+                &mut stream,
+                503,
+                "The gateway has too many connections open",
+            );
+            continue;
+        };
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(e) = gateway.serve(stream) {
+                tracing::warn!("gateway request failed: {:#}", e);
+            }
+        });
+    }
+}
+
+// Counts a connection being answered until it is dropped
+struct Connection;
+impl Connection {
+    fn open() -> Option<Self> {
+        CONNECTIONS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                (open < MAX_CONNECTIONS).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Requests the service on the port is answering, None when no gateway passes them on to know
+pub fn in_flight(port: u16) -> Option<u64> {
+    let in_flight = IN_FLIGHT.get()?.lock().unwrap();
+    Some(in_flight.get(&port).copied().unwrap_or(0))
+}
+
+// The body of a backend's answer, the request it keeps in flight and the backend that answered
+type Answer = (Box<dyn Read + Send>, Option<InFlight>, String);
+
+// Counts a request in flight until it is dropped
+struct InFlight(u16);
+impl InFlight {
+    fn new(port: u16) -> Self {
+        if let Some(in_flight) = IN_FLIGHT.get() {
+            *in_flight.lock().unwrap().entry(port).or_default() += 1;
+        }
+        Self(port)
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(in_flight) = IN_FLIGHT.get() {
+            let mut in_flight = in_flight.lock().unwrap();
+            if let Some(count) = in_flight.get_mut(&self.0) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
+
+// An HTTP request as read from the client
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+// Read a line of the head of a request, refusing one longer than a header may be
+fn read_header_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize, Unrouted> {
+    let read = reader
+        .take(MAX_HEADER_LINE as u64 + 1)
+        .read_line(line)
+        .map_err(|e| Unrouted(400, e.to_string()))?;
+    if read > MAX_HEADER_LINE {
+        return Err(Unrouted(
+            431,
+            format!("a header line is longer than {} bytes", MAX_HEADER_LINE),
+        ));
+    }
+
+    Ok(read)
+}
+
+impl Request {
+    fn read(reader: &mut impl BufRead) -> Result<Option<Self>, Unrouted> {
+        let malformed = |e: &dyn std::fmt::Display| Unrouted(400, e.to_string());
+        let mut line = String::new();
+        if read_header_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(Unrouted(
+                400,
+                format!("malformed request line '{}'", line.trim()),
+            ));
+        };
+        let (method, path) = (method.to_string(), path.to_string());
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            read_header_line(reader, &mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(Unrouted(431, format!("more than {} headers", MAX_HEADERS)));
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+
+        let mut request = Self {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        };
+        if request.header("transfer-encoding").is_some() {
+            return Err(Unrouted(
+                400,
+                "chunked requests are not supported, send a Content-Length".to_string(),
+            ));
+        }
+        let length = request
+            .header("content-length")
+            .map(|length| length.parse::<usize>())
+            .transpose()
+            .map_err(|e| malformed(&e))?
+            .unwrap_or(0);
+        if length > MAX_BODY {
+            return Err(Unrouted(
+                400,
+                format!("the request body of {} bytes is too large", length),
+            ));
+        }
+        // read as the bytes come rather than into a buffer of the length the client claims
+        reader
+            .take(length as u64)
+            .read_to_end(&mut request.body)
+            .map_err(|e| malformed(&e))?;
+        if request.body.len() < length {
+            return Err(Unrouted(
+                400,
+                format!(
+                    "the request body ended after {} of {} bytes",
+                    request.body.len(),
+                    length
+                ),
+            ));
+        }
+
+        Ok(Some(request))
+    }
+
+    // Replace the body, and its length in the headers
+    fn set_body(&mut self, body: Vec<u8>) {
+        for (name, value) in &mut self.headers {
+            if name.eq_ignore_ascii_case("content-length") {
+                *value = body.len().to_string();
+            }
+        }
+        self.body = body;
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The API key sent, as OpenAI clients or as Anthropic ones send it
+    fn key(&self) -> Option<&str> {
+        self.header("authorization")
+            .map(|value| value.trim())
+            .map(|value| value.strip_prefix("Bearer ").unwrap_or(value).trim())
+            .or(self.header("x-api-key").map(str::trim))
+    }
+
+    // The model the JSON body asks for
+    fn model(&self) -> Option<String> {
+        let body = serde_json::from_slice::<Value>(&self.body).ok()?;
+        body.get("model")?.as_str().map(String::from)
+    }
+
+    // Send the request to a peer or a fallback provider and its response back to the client, as
+    // it comes. With `held`, failing to reach it or a status telling it cannot answer now is
+    // returned rather than passed on, so that another backend can answer.
+    fn forward_remote(
+        &self,
+        mut upstream: reqwest::blocking::RequestBuilder,
+        backend: &str,
+        client: &mut TcpStream,
+        streaming: &StreamingConfig,
+        mut capture: Option<&mut Captured>,
+        held: bool,
+    ) -> anyhow::Result<Sent> {
+        let span = tracing::info_span!(
+            "gateway.backend",
+            otel.kind = "client",
+            backend,
+            status = field::Empty,
+            error = field::Empty,
+        )
+        .entered();
+        // it is sent its own key, and the length of the body as reqwest counts it
+        for (name, value) in &self.headers {
+            if ![
+                "host",
+                "content-length",
+                "connection",
+                "keep-alive",
+                "authorization",
+                "x-api-key",
+                "traceparent",
+                peers::FORWARDED,
+            ]
+            .iter()
+            .any(|skipped| name.eq_ignore_ascii_case(skipped))
+            {
+                upstream = upstream.header(name, value);
+            }
+        }
+        // the trace goes on in the peer's spans
+        if let Some(traceparent) = telemetry::traceparent() {
+            upstream = upstream.header("traceparent", traceparent);
+        }
+        let mut response = match upstream.send() {
+            Ok(response) => response,
+            Err(e) => {
+                let reason = format!("The {} did not answer: {}", backend, e);
+                span.record("error", reason.as_str());
+                return Ok(Sent::Unavailable(reason));
+            }
+        };
+
+        let status = response.status();
+        span.record("status", status.as_u16());
+        if held && fallback::is_unavailable(status.as_u16()) {
+            return Ok(Sent::Unavailable(format!(
+                "The {} answered {}",
+                backend, status
+            )));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("application/json")
+            .to_string();
+        let event_stream = content_type.contains("text/event-stream");
+        if let Some(capture) = capture.as_deref_mut() {
+            capture.status = Some(status.as_u16());
+            capture.event_stream = event_stream;
+        }
+        write!(
+            client,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}: {}\r\n{}Connection: close\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            content_type,
+            fallback::BACKEND,
+            backend,
+            match event_stream {
+                true => streaming.headers(),
+                false => "",
+            }
+        )?;
+        let mut buffer = vec![0; 8192];
+        loop {
+            let read = response.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            client.write_all(&buffer[..read])?;
+            client.flush()?;
+            if let Some(capture) = capture.as_deref_mut() {
+                capture.keep(&buffer[..read]);
+            }
+        }
+        client.shutdown(Shutdown::Write)?;
+
+        Ok(Sent::Answered)
+    }
+
+    // Send the request to the service and its response back to the client, as it comes so
+    // that streamed replies stream through. With `held`, as `forward_remote`.
+    fn forward(
+        &self,
+        port: u16,
+        client: &mut TcpStream,
+        streaming: &StreamingConfig,
+        mut capture: Option<&mut Captured>,
+        held: bool,
+    ) -> io::Result<Sent> {
+        let span = tracing::info_span!(
+            "gateway.backend",
+            otel.kind = "client",
+            backend = "local",
+            port,
+            status = field::Empty,
+            error = field::Empty,
+        )
+        .entered();
+        let mut service = match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(service) => service,
+            Err(e) => {
+                let reason = format!("The model on port {} did not answer: {}", port, e);
+                span.record("error", reason.as_str());
+                return Ok(Sent::Unavailable(reason));
+            }
+        };
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        for (name, value) in &self.headers {
+            // one request per connection, the response ends when the service closes it
+            if name.eq_ignore_ascii_case("connection") || name.eq_ignore_ascii_case("keep-alive") {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        service.write_all(head.as_bytes())?;
+        service.write_all(&self.body)?;
+
+        let mut reader = BufReader::new(service);
+        let mut head = String::new();
+        loop {
+            let read = reader.read_line(&mut head)?;
+            if read == 0 || head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+                break;
+            }
+        }
+        let has = |name: &str, value: &str| {
+            head.lines().any(|line| {
+                line.split_once(':').is_some_and(|(key, text)| {
+                    key.trim().eq_ignore_ascii_case(name)
+                        && text.to_ascii_lowercase().contains(value)
+                })
+            })
+        };
+        let event_stream = has("content-type", "text/event-stream");
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok());
+        span.record("status", status);
+        if held && status.is_some_and(fallback::is_unavailable) {
+            return Ok(Sent::Unavailable(format!(
+                "The model on port {} answered {}",
+                port,
+                status.unwrap_or_default()
+            )));
+        }
+        if let Some(capture) = capture.as_deref_mut() {
+            capture.status = status;
+            capture.event_stream = event_stream;
+        }
+        // marked as answered here, after the status line
+        let head = match head.find('\n') {
+            Some(line) => format!(
+                "{}{}: local\r\n{}",
+                &head[..=line],
+                fallback::BACKEND,
+                &head[line + 1..]
+            ),
+            None => head.clone(),
+        };
+        // replies of a known length are passed on as they are
+        if !event_stream || has("content-length", "") {
+            client.write_all(head.as_bytes())?;
+            let mut buffer = vec![0; 8192];
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                client.write_all(&buffer[..read])?;
+                if let Some(capture) = capture.as_deref_mut() {
+                    capture.keep(&buffer[..read]);
+                }
+            }
+            client.shutdown(Shutdown::Write)?;
+            return Ok(Sent::Answered);
+        }
+
+        let chunked = has("transfer-encoding", "chunked");
+        let end = head.trim_end().len();
+        let mut extra = streaming.headers().to_string();
+        if has("cache-control", "") {
+            extra = extra.replace("Cache-Control: no-cache\r\n", "");
+        }
+        client.write_all(format!("{}\r\n{}\r\n", &head[..end], extra).as_bytes())?;
+        relay_events(reader, chunked, client, streaming, capture)?;
+        client.shutdown(Shutdown::Write)?;
+
+        Ok(Sent::Answered)
+    }
+}
+
+// What became of a request sent to a backend
+enum Sent {
+    Answered,
+    // nothing was passed on to the client, why
+    Unavailable(String),
+}
+
+// A response as the request log keeps it
+#[derive(Default)]
+struct Captured {
+    status: Option<u16>,
+    event_stream: bool,
+    body: Vec<u8>,
+}
+impl Captured {
+    fn keep(&mut self, bytes: &[u8]) {
+        let room = MAX_CAPTURE.saturating_sub(self.body.len());
+        self.body.extend(&bytes[..bytes.len().min(room)]);
+    }
+}
+
+// Pass the events of a stream on to the client, flushing them every `flush_interval_ms` and
+// sending a comment when nothing came for `heartbeat_secs`
+fn relay_events(
+    mut reader: BufReader<TcpStream>,
+    chunked: bool,
+    client: &mut TcpStream,
+    streaming: &StreamingConfig,
+    mut capture: Option<&mut Captured>,
+) -> io::Result<()> {
+    // read by a thread of its own so that waiting for the next event can time out
+    let (pieces, received) = mpsc::channel::<io::Result<Vec<u8>>>();
+    thread::spawn(move || loop {
+        let piece = read_piece(&mut reader, chunked);
+        let done = !matches!(piece, Ok(ref bytes) if !bytes.is_empty());
+        if pieces.send(piece).is_err() || done {
+            return;
+        }
+    });
+
+    let heartbeat =
+        (streaming.heartbeat_secs > 0).then(|| Duration::from_secs(streaming.heartbeat_secs));
+    let flush_interval = Duration::from_millis(streaming.flush_interval_ms);
+    let mut writer = BufWriter::new(client);
+    let mut pending = false;
+    let mut flushed = Instant::now();
+    let mut sent = Instant::now();
+    // comments only go between events
+    let mut between_events = true;
+    loop {
+        let wait = match (pending, heartbeat) {
+            (true, _) => flush_interval.saturating_sub(flushed.elapsed()),
+            (false, Some(heartbeat)) => heartbeat.saturating_sub(sent.elapsed()),
+            (false, None) => Duration::from_secs(3600),
+        };
+        match received.recv_timeout(wait) {
+            Ok(Ok(piece)) if piece.is_empty() => break,
+            Ok(Ok(piece)) => {
+                write_piece(&mut writer, chunked, &piece)?;
+                if let Some(capture) = capture.as_deref_mut() {
+                    capture.keep(&piece);
+                }
+                between_events = piece.ends_with(b"\n\n");
+                pending = true;
+                sent = Instant::now();
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if pending && flushed.elapsed() >= flush_interval {
+            writer.flush()?;
+            pending = false;
+            flushed = Instant::now();
+        }
+        if heartbeat.is_some_and(|heartbeat| sent.elapsed() >= heartbeat) && between_events {
+            write_piece(&mut writer, chunked, b": keep-alive\n\n")?;
+            writer.flush()?;
+            sent = Instant::now();
+        }
+    }
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    writer.flush()
+}
+
+// The next bytes of a response body, empty at its end
+fn read_piece(reader: &mut BufReader<TcpStream>, chunked: bool) -> io::Result<Vec<u8>> {
+    if !chunked {
+        let mut piece = vec![0; 8192];
+        let read = reader.read(&mut piece)?;
+        piece.truncate(read);
+        return Ok(piece);
+    }
+    let mut size = String::new();
+    reader.read_line(&mut size)?;
+    let size = size.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size, 16)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed chunk size"))?;
+    let mut piece = vec![0; size];
+    reader.read_exact(&mut piece)?;
+    // the line ending the chunk, or the trailer after the last one
+    let mut end = String::new();
+    reader.read_line(&mut end)?;
+
+    Ok(piece)
+}
+
+fn write_piece(writer: &mut impl Write, chunked: bool, piece: &[u8]) -> io::Result<()> {
+    if chunked {
+        write!(writer, "{:x}\r\n", piece.len())?;
+        writer.write_all(piece)?;
+        writer.write_all(b"\r\n")
+    } else {
+        writer.write_all(piece)
+    }
+}
+
+// An OpenAI error response
+pub fn respond_error(client: &mut TcpStream, status: u16, message: &str) -> io::Result<()> {
+    let kind = match status {
+        400..=499 => "invalid_request_error",
+        _ => "server_error",
+    };
+    let body = json!({ "error": { "message": message, "type": kind } });
+    respond(client, status, &body)
+}
+
+pub fn respond(client: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    respond_with(client, status, "application/json", &body.to_string())
+}
+
+pub fn respond_with(
+    client: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    };
+    write!(
+        client,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+// Why a request cannot be routed, with the status to answer
+pub struct Unrouted(pub u16, pub String);
+
+// Where a request goes, a service of this node, a peer or the remote API of a routing rule
+pub enum Route {
+    Local(u16),
+    Peer(PeerConfig),
+    Remote(RemoteConfig),
+}
+
+// The response of a service to a request translated from another API, counted in flight until
+// it is dropped. Its body is held back and filtered when the gateway filters replies.
+pub struct Upstream {
+    pub body: Box<dyn Read + Send>,
+    // the backend that answered, as `fallback::BACKEND` names it
+    pub backend: String,
+    _in_flight: Option<InFlight>,
+}
+impl Upstream {
+    pub fn json(self) -> anyhow::Result<Value> {
+        Ok(serde_json::from_reader(self.body)?)
+    }
+}
+
+// The response once it is a success, else the message of its error
+fn success(response: reqwest::blocking::Response) -> Result<reqwest::blocking::Response, Unrouted> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response.text().unwrap_or_default();
+    // the message of an OpenAI error, or the body as it is
+    let message = serde_json::from_str::<Value>(&text)
+        .ok()
+        .and_then(|body| body["error"]["message"].as_str().map(String::from))
+        .unwrap_or(text);
+
+    Err(Unrouted(status.as_u16(), message))
+}
+
+pub struct Gateway {
+    config: GatewayConfig,
+    request_log: Option<RequestLog>,
+    filters: Option<Filters>,
+    // the API keys, read from the environment where they name a variable
+    keys: Vec<ApiKey>,
+    // kept across reloads
+    on_demand: Arc<OnDemand>,
+    // the chat templates of the models served with one, by model path and template, read again
+    // on reload
+    chat_templates: Mutex<HashMap<(String, String), Arc<ChatTemplate>>>,
+    // the model path and chat template of the services routed to with one, by port, noted when
+    // routing so that rendering reads no service state again
+    templated: Mutex<HashMap<u16, (String, String)>>,
+}
+
+// What the gateway knows of the on-demand models
+#[derive(Default)]
+struct OnDemand {
+    // when each on-demand service last got a request, by service name
+    used: Mutex<HashMap<String, Instant>>,
+    // one lock per model, so that the requests arriving while it loads load it once
+    loads: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    // models loading, at most `max_loading`
+    loading: Mutex<usize>,
+    slots: Condvar,
+}
+
+impl Gateway {
+    fn new(config: GatewayConfig, on_demand: Arc<OnDemand>) -> anyhow::Result<Self> {
+        let request_log = config
+            .request_log
+            .clone()
+            .map(RequestLog::new)
+            .transpose()?;
+        let filters = match config.filters.is_empty() {
+            true => None,
+            false => Some(Filters::new(&config.filters)?),
+        };
+        let keys = config
+            .api_keys
+            .iter()
+            .map(ApiKeyConfig::resolve)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if !keys.is_empty() {
+            quota::init(&keys);
+        }
+
+        Ok(Self {
+            config,
+            request_log,
+            filters,
+            keys,
+            on_demand,
+            chat_templates: Mutex::default(),
+            templated: Mutex::default(),
+        })
+    }
+
+    fn serve(&self, mut client: TcpStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(client.try_clone()?);
+        let request = match Request::read(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(Unrouted(status, message)) => {
+                respond_error(&mut client, status, &message)?;
+                return Ok(());
+            }
+        };
+        let span = tracing::info_span!(
+            "gateway.request",
+            otel.kind = "server",
+            method = %request.method,
+            path = %request.path,
+            model = field::Empty,
+            traceparent = request.header("traceparent"),
+        )
+        .entered();
+        tracing::info!(method = %request.method, path = %request.path, "gateway request");
+        let key = request.key();
+        let known = self
+            .keys
+            .iter()
+            .find(|known| Some(known.key.as_str()) == key);
+        if !self.keys.is_empty() && known.is_none() {
+            respond_error(
+                &mut client,
+                401,
+                "Invalid API key, send it as Authorization: Bearer KEY",
+            )?;
+            return Ok(());
+        }
+        let _forwarded = peers::mark(request.header(peers::FORWARDED).is_some());
+        let _metering = match known {
+            Some(known) => {
+                if let Err(body) = quota::admit(known) {
+                    respond(&mut client, 429, &body)?;
+                    return Ok(());
+                }
+                Some(quota::meter(known))
+            }
+            None => None,
+        };
+
+        if ollama_api::is_route(&request.path) {
+            return ollama_api::answer(self, &request, &mut client);
+        }
+        if anthropic::is_route(&request) {
+            return anthropic::answer(self, &request, &mut client);
+        }
+        if websocket::is_route(&request) {
+            return websocket::answer(self, &request, client);
+        }
+        if request.method == "GET" && request.path.trim_end_matches('/') == "/v1/models" {
+            respond(&mut client, 200, &self.models()?)?;
+            return Ok(());
+        }
+        if request.method == "POST" && is_path(&request.path, "embeddings") {
+            let body = serde_json::from_slice::<Value>(&request.body).unwrap_or_default();
+            if self.config.embeddings.is_some() || !body["dimensions"].is_null() {
+                return self.answer_embeddings(body, &mut client);
+            }
+        }
+        // told by the body rather than its content type, which clients may leave out, and a
+        // generation request failing to parse is refused rather than passed on unfiltered
+        if self.filters.is_some()
+            && request.method == "POST"
+            && (is_generation(&request.path)
+                || serde_json::from_slice::<Value>(&request.body).is_ok())
+        {
+            return self.answer_filtered(&request, &mut client);
+        }
+        if request.method == "POST"
+            && is_path(&request.path, "chat/completions")
+            && self.renders_prompt(&request)
+        {
+            return self.answer_filtered(&request, &mut client);
+        }
+        let mut request = request;
+        if !self.config.routing.is_empty() {
+            if let Ok(mut body) = serde_json::from_slice::<Value>(&request.body) {
+                if self.rename(&mut body) {
+                    request.set_body(body.to_string().into_bytes());
+                }
+            }
+        }
+        let prepared = self.config.limits.is_set() || self.config.context_overflow.is_some();
+        if prepared && request.method == "POST" && is_generation(&request.path) {
+            let mut body = match serde_json::from_slice::<Value>(&request.body) {
+                Ok(body) => body,
+                Err(e) => {
+                    respond_error(&mut client, 400, &format!("invalid JSON: {}", e))?;
+                    return Ok(());
+                }
+            };
+            if let Err(Unrouted(status, message)) = self.prepare(&request.path, &mut body) {
+                respond_error(&mut client, status, &message)?;
+                return Ok(());
+            }
+            request.set_body(body.to_string().into_bytes());
+        }
+        let model = request.model();
+        span.record("model", model.as_deref());
+        let route = self.route(model.as_deref());
+        // a model no service of the node serves, or failing to load, goes to the fallbacks
+        if let Err(Unrouted(status, message)) = &route {
+            if self.fallbacks(&request).is_empty() {
+                respond_error(&mut client, *status, message)?;
+                return Ok(());
+            }
+        }
+        let _in_flight = match &route {
+            Ok(Route::Local(port)) => Some(InFlight::new(*port)),
+            _ => None,
+        };
+        let log = self
+            .request_log
+            .as_ref()
+            .filter(|log| log.logs(request.key()));
+        let metered = quota::current();
+        let mut captured = (log.is_some() || metered.is_some()).then(Captured::default);
+        let started = Instant::now();
+        let forwarded = self.forward_routed(&request, route, &mut client, captured.as_mut());
+        if let (Some(id), Some(captured)) = (&metered, &captured) {
+            let prompt = serde_json::from_slice::<Value>(&request.body)
+                .map_or(0, |body| prompt_tokens(&body));
+            quota::record(
+                id,
+                quota::used_tokens(&captured.body, captured.event_stream, prompt),
+            );
+        }
+        if let (Some(log), Some(captured)) = (log, captured) {
+            log.record(
+                &request.method,
+                &request.path,
+                captured.status,
+                started.elapsed().as_secs_f64(),
+                &request.body,
+                audit::response_summary(&captured.body, captured.event_stream),
+            );
+        }
+
+        forwarded
+    }
+
+    // The fallback providers the request may go to, those of the gateway for the POST requests
+    // this node was to answer
+    fn fallbacks(&self, request: &Request) -> &[RemoteConfig] {
+        match request.method == "POST" && !peers::forwarded() {
+            true => &self.config.fallback,
+            false => &[],
+        }
+    }
+
+    // Send the request along its route, and to the fallback providers in order while the backend
+    // is down or overloaded
+    fn forward_routed(
+        &self,
+        request: &Request,
+        route: Result<Route, Unrouted>,
+        client: &mut TcpStream,
+        mut capture: Option<&mut Captured>,
+    ) -> anyhow::Result<()> {
+        let http = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+        let path = request.path.trim_start_matches('/');
+        let path = path.strip_prefix("v1/").unwrap_or(path);
+        let streaming = &self.config.streaming;
+        let fallbacks = self.fallbacks(request);
+
+        let held = !fallbacks.is_empty();
+        let sent = match route {
+            Ok(Route::Local(port)) => {
+                request.forward(port, client, streaming, capture.as_deref_mut(), held)?
+            }
+            Ok(Route::Peer(peer)) => {
+                let upstream = peer
+                    .request(&http, method.clone(), path)?
+                    .body(request.body.clone());
+                let backend = format!("peer {}", peer.url);
+                let sent = request.forward_remote(
+                    upstream,
+                    &backend,
+                    client,
+                    streaming,
+                    capture.as_deref_mut(),
+                    held,
+                )?;
+                if matches!(sent, Sent::Unavailable(_)) {
+                    peers::forget(&peer);
+                }
+                sent
+            }
+            Ok(Route::Remote(remote)) => {
+                let upstream = remote
+                    .request(&http, method.clone(), path)?
+                    .body(remote.body(&request.body));
+                let backend = format!("remote {}", remote.name());
+                request.forward_remote(
+                    upstream,
+                    &backend,
+                    client,
+                    streaming,
+                    capture.as_deref_mut(),
+                    held,
+                )?
+            }
+            Err(Unrouted(_, message)) => Sent::Unavailable(message),
+        };
+        let Sent::Unavailable(mut reason) = sent else {
+            return Ok(());
+        };
+
+        for (i, fallback) in fallbacks.iter().enumerate() {
+            tracing::warn!(
+                fallback = fallback.name(),
+                path = request.path,
+                "falling back: {}",
+                reason
+            );
+            let upstream = fallback
+                .request(&http, method.clone(), path)?
+                .body(fallback.body(&request.body));
+            let backend = format!("fallback {}", fallback.name());
+            let held = i + 1 < fallbacks.len();
+            match request.forward_remote(
+                upstream,
+                &backend,
+                client,
+                streaming,
+                capture.as_deref_mut(),
+                held,
+            )? {
+                Sent::Answered => return Ok(()),
+                Sent::Unavailable(why) => reason = why,
+            }
+        }
+        if let Some(capture) = capture {
+            capture.status = Some(502);
+        }
+
+        Ok(respond_error(client, 502, &reason)?)
+    }
+
+    // Answer the OpenAI request through `send`, which filters it and its reply and renders the
+    // prompt of a model with a chat template
+    fn answer_filtered(&self, request: &Request, client: &mut TcpStream) -> anyhow::Result<()> {
+        let body = match serde_json::from_slice::<Value>(&request.body) {
+            Ok(body) => body,
+            Err(e) => {
+                respond_error(client, 400, &format!("invalid JSON: {}", e))?;
+                return Ok(());
+            }
+        };
+        let path = request.path.trim_start_matches('/');
+        let path = path.strip_prefix("v1/").unwrap_or(path);
+        let log = self
+            .request_log
+            .as_ref()
+            .filter(|log| log.logs(request.key()));
+        let started = Instant::now();
+        let streamed = body["stream"] == true;
+        let mut captured = Captured {
+            event_stream: streamed,
+            ..Default::default()
+        };
+
+        match self.send(path, &body) {
+            Ok(mut upstream) => {
+                let content_type = match streamed {
+                    true => "text/event-stream",
+                    false => "application/json",
+                };
+                let extra = match streamed {
+                    true => self.config.streaming.headers(),
+                    false => "",
+                };
+                write!(
+                    client,
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n{}: {}\r\n{}Connection: close\r\n\r\n",
+                    content_type,
+                    fallback::BACKEND,
+                    upstream.backend,
+                    extra
+                )?;
+                captured.status = Some(200);
+                let mut buffer = vec![0; 8192];
+                loop {
+                    let read = upstream.body.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    client.write_all(&buffer[..read])?;
+                    client.flush()?;
+                    captured.keep(&buffer[..read]);
+                }
+                client.shutdown(Shutdown::Write)?;
+            }
+            Err(Unrouted(status, message)) => {
+                captured.status = Some(status);
+                captured.event_stream = false;
+                captured.keep(
+                    json!({ "error": { "message": message } })
+                        .to_string()
+                        .as_bytes(),
+                );
+                respond_error(client, status, &message)?;
+            }
+        }
+        if let Some(log) = log {
+            log.record(
+                &request.method,
+                &request.path,
+                captured.status,
+                started.elapsed().as_secs_f64(),
+                &request.body,
+                audit::response_summary(&captured.body, captured.event_stream),
+            );
+        }
+
+        Ok(())
+    }
+
+    // Answer the embeddings request, shaping the embeddings of the service
+    fn answer_embeddings(&self, body: Value, client: &mut TcpStream) -> anyhow::Result<()> {
+        // the service sends plain floats, shaped here
+        let mut upstream = body.clone();
+        if let Some(fields) = upstream.as_object_mut() {
+            fields.remove("dimensions");
+            fields.remove("encoding_format");
+        }
+        let mut response = match self.send("embeddings", &upstream).and_then(|upstream| {
+            upstream
+                .json()
+                .map_err(|e| Unrouted(502, format!("The model answered no JSON: {}", e)))
+        }) {
+            Ok(response) => response,
+            Err(Unrouted(status, message)) => {
+                respond_error(client, status, &message)?;
+                return Ok(());
+            }
+        };
+        let args = self.config.embeddings.clone().unwrap_or_default();
+        match embed::reshape(&mut response, &body, &args) {
+            Ok(()) => respond(client, 200, &response)?,
+            Err(e) => respond_error(client, 400, &e.to_string())?,
+        }
+
+        Ok(())
+    }
+
+    // Whether the chat request goes to a model whose prompts the gateway renders
+    fn renders_prompt(&self, request: &Request) -> bool {
+        let Ok(mut body) = serde_json::from_slice::<Value>(&request.body) else {
+            return false;
+        };
+        self.rename(&mut body);
+        match self.route(body["model"].as_str()) {
+            Ok(Route::Local(port)) => self.templated.lock().unwrap().contains_key(&port),
+            _ => false,
+        }
+    }
+
+    // The chat template of the chat model of the service on the port, none when the api-server
+    // renders its prompts
+    fn chat_template(&self, port: u16) -> Result<Option<Arc<ChatTemplate>>, Unrouted> {
+        let Some(key) = self.templated.lock().unwrap().get(&port).cloned() else {
+            return Ok(None);
+        };
+        if let Some(template) = self.chat_templates.lock().unwrap().get(&key) {
+            return Ok(Some(template.clone()));
+        }
+        let template = ChatTemplate::load(Path::new(&key.0), &key.1)
+            .map(Arc::new)
+            .map_err(|e| Unrouted(500, format!("{:#}", e)))?;
+        self.chat_templates
+            .lock()
+            .unwrap()
+            .insert(key, template.clone());
+
+        Ok(Some(template))
+    }
+
+    pub fn streaming(&self) -> &StreamingConfig {
+        &self.config.streaming
+    }
+
+    // The served models, followed by those loaded on demand that are not loaded yet
+    pub fn models(&self) -> anyhow::Result<Value> {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut data = Vec::new();
+        for state in server::load_all()? {
+            for model in &state.models {
+                data.push(json!({
+                    "id": model.name,
+                    "object": "model",
+                    "created": state.started,
+                    "owned_by": "gaia",
+                    "loaded": true,
+                }));
+            }
+        }
+        for model in &self.config.models {
+            let name = model.name();
+            if data.iter().all(|loaded| loaded["id"] != name.as_str()) {
+                data.push(json!({
+                    "id": name,
+                    "object": "model",
+                    "created": created,
+                    "owned_by": "gaia",
+                    "loaded": false,
+                }));
+            }
+        }
+        // a peer asking is told of this node's own models only
+        if !peers::forwarded() {
+            for peer in &self.config.peers {
+                for name in peers::models(peer) {
+                    if data.iter().all(|listed| listed["id"] != name.as_str()) {
+                        data.push(json!({
+                            "id": name,
+                            "object": "model",
+                            "created": created,
+                            "owned_by": "gaia",
+                            "loaded": true,
+                            "peer": peer.url,
+                        }));
+                    }
+                }
+            }
+        }
+        // the names routing rules take as they are, globs cannot be listed
+        for rule in &self.config.routing {
+            if rule.model.contains(['*', '?'])
+                || data
+                    .iter()
+                    .any(|listed| listed["id"] == rule.model.as_str())
+            {
+                continue;
+            }
+            let routed = match &rule.remote {
+                Some(remote) => format!("remote {}", remote.name()),
+                None => rule.to.clone().unwrap_or_default(),
+            };
+            data.push(json!({
+                "id": rule.model,
+                "object": "model",
+                "created": created,
+                "owned_by": "gaia",
+                "routed_to": routed,
+            }));
+        }
+
+        Ok(json!({ "object": "list", "data": data }))
+    }
+
+    // The service serving the model, loading it first when it is on demand, or else the peer
+    // serving it, once the routing rules are applied. Requests naming no model go to the
+    // api-server of `gaia start`.
+    pub fn route(&self, model: Option<&str>) -> Result<Route, Unrouted> {
+        let services = server::load_all().map_err(|e| Unrouted(500, format!("{:#}", e)))?;
+        let Some(model) = model else {
+            return services
+                .iter()
+                .find(|state| state.name == server::API_SERVER)
+                .or(services.first())
+                .map(|state| Route::Local(state.port))
+                .ok_or(Unrouted(500, "no model is loaded".to_string()));
+        };
+        let model = match routing::find(&self.config.routing, model) {
+            Some(Target::Remote(remote)) => return Ok(Route::Remote(remote.clone())),
+            Some(Target::Model(to)) => to,
+            None => model,
+        };
+
+        if let Some(state) = services
+            .iter()
+            .find(|state| state.models.iter().any(|served| served.name == model))
+        {
+            self.on_demand
+                .used
+                .lock()
+                .unwrap()
+                .insert(state.name.clone(), Instant::now());
+            self.note_template(state);
+            return Ok(Route::Local(state.port));
+        }
+        if let Some(on_demand) = self.config.models.iter().find(|m| m.name() == model) {
+            return self.load(on_demand).map(Route::Local).map_err(|e| {
+                Unrouted(
+                    500,
+                    format!("The model '{}' failed to load: {:#}", model, e),
+                )
+            });
+        }
+        if let Some(peer) = peers::find(&self.config.peers, model) {
+            return Ok(Route::Peer(peer.clone()));
+        }
+
+        Err(Unrouted(
+            404,
+            format!(
+                "The model '{}' is neither loaded nor in the models of the gateway{}",
+                model,
+                match self.config.peers.is_empty() {
+                    true => "",
+                    false => ", and no peer serves it",
+                }
+            ),
+        ))
+    }
+
+    // Send the OpenAI request to the service serving the model it names, for the APIs translated
+    // to it, through the filters of the gateway
+    pub fn send(&self, path: &str, body: &Value) -> Result<Upstream, Unrouted> {
+        let mut body = body.clone();
+        self.rename(&mut body);
+        self.prepare(path, &mut body)?;
+        // replies to filter are generated whole, and streamed afterwards when asked to
+        let filtered = self
+            .filters
+            .as_ref()
+            .filter(|filters| filters.on_responses() && is_generation(path));
+        let streamed = body["stream"] == true;
+        let include_usage = body["stream_options"]["include_usage"] == true;
+        if filtered.is_some() && streamed {
+            body["stream"] = json!(false);
+            if let Some(fields) = body.as_object_mut() {
+                fields.remove("stream_options");
+            }
+        }
+
+        let (response, in_flight, backend) = self.post(path, &body)?;
+        let Some(filters) = filtered else {
+            return Ok(Upstream {
+                body: metered(response, &body),
+                backend,
+                _in_flight: in_flight,
+            });
+        };
+        let mut reply = serde_json::from_reader::<_, Value>(response)
+            .map_err(|e| Unrouted(502, format!("The model answered no JSON: {}", e)))?;
+        filters.response(&mut reply);
+        let reply = match streamed {
+            true => filter::as_events(&reply, include_usage),
+            false => reply.to_string().into_bytes(),
+        };
+
+        Ok(Upstream {
+            body: metered(Box::new(io::Cursor::new(reply)), &body),
+            backend,
+            _in_flight: in_flight,
+        })
+    }
+
+    // Ask for the model the routing rule of the model asked for names, whether there is one
+    fn rename(&self, body: &mut Value) -> bool {
+        let to = body["model"].as_str().and_then(|model| {
+            match routing::find(&self.config.routing, model)? {
+                Target::Model(to) => Some(to.to_string()),
+                Target::Remote(_) => None,
+            }
+        });
+        match to {
+            Some(to) => {
+                body["model"] = json!(to);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Apply the filters, the context overflow policy and the limits to the OpenAI request
+    fn prepare(&self, path: &str, body: &mut Value) -> Result<(), Unrouted> {
+        if let Some(filters) = &self.filters {
+            filters
+                .request(body)
+                .map_err(|reason| Unrouted(400, reason))?;
+        }
+        if !is_generation(path) {
+            return Ok(());
+        }
+        if let Some(overflow) = &self.config.context_overflow {
+            self.fit(overflow, body)?;
+        }
+        self.config
+            .limits
+            .apply(body)
+            .map_err(|reason| Unrouted(400, reason))
+    }
+
+    // Fit the messages of the request in the context of the model it asks for
+    fn fit(&self, config: &OverflowConfig, body: &mut Value) -> Result<(), Unrouted> {
+        let Some(messages) = body["messages"].as_array() else {
+            return Ok(());
+        };
+        let model = body["model"].as_str().map(String::from);
+        let limits = &self.config.limits;
+        let mut context_size = model
+            .as_deref()
+            .and_then(|model| self.config.models.iter().find(|m| m.name() == model))
+            .and_then(|model| model.context_size)
+            .or(config.context_size)
+            .unwrap_or(crate::DEFAULT_CONTEXT_SIZE);
+        if let Some(cap) = limits.max_context_tokens {
+            context_size = context_size.min(cap);
+        }
+        let max_tokens = body["max_completion_tokens"]
+            .as_u64()
+            .or(body["max_tokens"].as_u64())
+            .or(limits.default_max_tokens)
+            .map(|max_tokens| {
+                limits
+                    .max_tokens
+                    .map_or(max_tokens, |cap| max_tokens.min(cap))
+            });
+
+        let mut messages = messages.clone();
+        let fitted = overflow::fit(
+            &mut messages,
+            overflow::budget(context_size, max_tokens),
+            config.policy,
+            |request| {
+                let body = json!({ "model": model, "messages": request, "stream": false });
+                let (response, _in_flight, _) = self
+                    .post("chat/completions", &body)
+                    .map_err(|Unrouted(_, message)| anyhow!(message))?;
+                let reply = serde_json::from_reader::<_, Value>(response)?;
+                reply["choices"][0]["message"]["content"]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or(anyhow!("the model answered no summary"))
+            },
+        )
+        .map_err(|e| Unrouted(400, e.to_string()))?;
+        if fitted != Fitted::Whole {
+            tracing::info!(?fitted, model, "fitted a conversation in the context");
+            body["messages"] = json!(messages);
+        }
+
+        Ok(())
+    }
+
+    // Post the request to the service serving the model, its response once it is a success with
+    // the backend that answered, the fallback providers answering while that one is down or
+    // overloaded
+    fn post(&self, path: &str, body: &Value) -> Result<Answer, Unrouted> {
+        let http = reqwest::blocking::Client::builder()
+            // generation may take long
+            .timeout(None)
+            .build()
+            .map_err(|e| Unrouted(500, e.to_string()))?;
+        let fallbacks = match peers::forwarded() {
+            true => &[],
+            false => self.config.fallback.as_slice(),
+        };
+        // as in `serve`, a model not served here goes to the fallbacks too
+        let posted = match self.route(body["model"].as_str()) {
+            Ok(route) => self.post_to(&http, &route, path, body),
+            Err(unrouted) if !fallbacks.is_empty() => Err(Unrouted(502, unrouted.1)),
+            Err(unrouted) => Err(unrouted),
+        };
+        let mut reason = match posted {
+            Ok(posted) => return Ok(posted),
+            Err(Unrouted(status, message))
+                if fallbacks.is_empty() || !fallback::is_unavailable(status) =>
+            {
+                return Err(Unrouted(status, message))
+            }
+            Err(unrouted) => unrouted,
+        };
+
+        for fallback in fallbacks {
+            tracing::warn!(
+                fallback = fallback.name(),
+                path,
+                "falling back: {}",
+                reason.1
+            );
+            let mut body = body.clone();
+            fallback.shape(&mut body);
+            let request = fallback
+                .request(&http, reqwest::Method::POST, path)
+                .map_err(|e| Unrouted(500, format!("{:#}", e)))?;
+            let backend = format!("fallback {}", fallback.name());
+            let _span =
+                tracing::info_span!("gateway.backend", otel.kind = "client", backend).entered();
+            match request.json(&body).send() {
+                Ok(response) => match success(response) {
+                    Ok(response) => return Ok((Box::new(response), None, backend)),
+                    Err(Unrouted(status, message)) if !fallback::is_unavailable(status) => {
+                        return Err(Unrouted(status, message))
+                    }
+                    Err(unrouted) => reason = unrouted,
+                },
+                Err(e) => reason = Unrouted(502, format!("The {} did not answer: {}", backend, e)),
+            }
+        }
+
+        Err(reason)
+    }
+
+    // Post the request along the route
+    fn post_to(
+        &self,
+        http: &reqwest::blocking::Client,
+        route: &Route,
+        path: &str,
+        body: &Value,
+    ) -> Result<Answer, Unrouted> {
+        let mut body = body.clone();
+        // a model with a chat template of its own is asked to complete the prompt rendered here
+        let template = match route {
+            Route::Local(port) if path == "chat/completions" => self.chat_template(*port)?,
+            _ => None,
+        };
+        let (streamed, include_usage) = (
+            body["stream"] == true,
+            body["stream_options"]["include_usage"] == true,
+        );
+        let path = match &template {
+            Some(template) => {
+                body = template
+                    .completion_request(&body)
+                    .map_err(|e| Unrouted(400, format!("{:#}", e)))?;
+                "completions"
+            }
+            None => path,
+        };
+        let (request, in_flight, backend) = match route {
+            Route::Local(port) => (
+                http.post(format!("http://127.0.0.1:{}/v1/{}", port, path)),
+                Some(InFlight::new(*port)),
+                "local".to_string(),
+            ),
+            Route::Peer(peer) => (
+                peer.request(http, reqwest::Method::POST, path)
+                    .map_err(|e| Unrouted(500, format!("{:#}", e)))?,
+                None,
+                format!("peer {}", peer.url),
+            ),
+            Route::Remote(remote) => {
+                remote.shape(&mut body);
+                (
+                    remote
+                        .request(http, reqwest::Method::POST, path)
+                        .map_err(|e| Unrouted(500, format!("{:#}", e)))?,
+                    None,
+                    format!("remote {}", remote.name()),
+                )
+            }
+        };
+        let span = tracing::info_span!(
+            "gateway.backend",
+            otel.kind = "client",
+            backend,
+            status = field::Empty,
+        )
+        .entered();
+        let response = request.json(&body).send().map_err(|e| {
+            if let Route::Peer(peer) = route {
+                peers::forget(peer);
+            }
+            Unrouted(502, format!("The model did not answer: {}", e))
+        })?;
+        span.record("status", response.status().as_u16());
+        let response = success(response)?;
+        if template.is_none() {
+            return Ok((Box::new(response), in_flight, backend));
+        }
+        let events = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if events {
+            return Ok((
+                Box::new(ChatEvents::new(Box::new(response))),
+                in_flight,
+                backend,
+            ));
+        }
+
+        // a backend answering streamed requests whole
+        let completion = response
+            .json::<Value>()
+            .map_err(|e| Unrouted(502, format!("The model answered no JSON: {}", e)))?;
+        let reply = template::chat_completion(&completion);
+        let reply = match streamed {
+            true => filter::as_events(&reply, include_usage),
+            false => reply.to_string().into_bytes(),
+        };
+
+        Ok((Box::new(io::Cursor::new(reply)), in_flight, backend))
+    }
+
+    // Load the model in an api-server of its own, waiting for a free slot when `max_loading`
+    // models are already loading
+    fn load(&self, model: &OnDemandModel) -> anyhow::Result<u16> {
+        let name = model.name();
+        let service = service_name(&name);
+        let lock = self
+            .on_demand
+            .loads
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .clone();
+        let queued = tracing::info_span!("gateway.queue", model = name).entered();
+        let _loading_it = lock.lock().unwrap();
+        // loaded by the request it waited for
+        if let Some(state) = server::load(&service)? {
+            self.note_template(&state);
+            return Ok(state.port);
+        }
+
+        {
+            let mut loading = self.on_demand.loading.lock().unwrap();
+            while *loading >= self.config.max_loading.max(1) {
+                loading = self.on_demand.slots.wait(loading).unwrap();
+            }
+            *loading += 1;
+        }
+        drop(queued);
+        let loaded = tracing::info_span!("gateway.load", model = name)
+            .in_scope(|| self.launch(model, &name, &service));
+        *self.on_demand.loading.lock().unwrap() -= 1;
+        self.on_demand.slots.notify_one();
+
+        let state = loaded?;
+        self.on_demand
+            .used
+            .lock()
+            .unwrap()
+            .insert(state.name.clone(), Instant::now());
+        self.note_template(&state);
+        Ok(state.port)
+    }
+
+    // Note whether the service on its port serves a model with a chat template
+    fn note_template(&self, state: &ServiceState) {
+        let mut templated = self.templated.lock().unwrap();
+        match self::templated(state) {
+            Some(key) => templated.insert(state.port, key),
+            None => templated.remove(&state.port),
+        };
+    }
+
+    fn launch(
+        &self,
+        model: &OnDemandModel,
+        name: &str,
+        service: &str,
+    ) -> anyhow::Result<ServiceState> {
+        println!("Loading {} on demand", name);
+        let context_size = model.context_size.unwrap_or(crate::DEFAULT_CONTEXT_SIZE);
+        // a model still to download is only sized once it is there
+        if Path::new(&model.model).is_file() {
+            self.make_room(Path::new(&model.model), context_size)?;
+        }
+        let args = StartArgs {
+            model: Some(model.model.clone()),
+            prompt_template: Some(model.prompt_template),
+            chat_template: model.chat_template.clone(),
+            reverse_prompt: None,
+            context_size: model.context_size,
+            port: server::free_port()?,
+            model_name: Some(name.to_string()),
+            embedding_model: None,
+            embedding_model_name: None,
+            embedding_context_size: start::DEFAULT_EMBEDDING_CONTEXT_SIZE,
+            whisper_model: None,
+            whisper_port: start::DEFAULT_WHISPER_PORT,
+            local_only: true,
+            dry_run: false,
+            strict_memory: false,
+            n_gpu_layers: None,
+            gpu_headroom: start::DEFAULT_GPU_HEADROOM,
+            device: None,
+            numa: None,
+            cpu_affinity: None,
+            runtime: None,
+            // nobody is there to confirm the preflight summary
+            yes: true,
+            startup_timeout: server::DEFAULT_STARTUP_TIMEOUT,
+            preset: None,
+            service: Some(service.to_string()),
+        };
+        start::command_start(args)?;
+        events::emit("model-loaded", json!({ "model": name, "service": service }));
+
+        server::load(service)?.ok_or(anyhow!("{} exited after loading", service))
+    }
+
+    // Unload the on-demand models used the longest ago until the model fits in memory
+    fn make_room(&self, path: &Path, context_size: u64) -> anyhow::Result<()> {
+        let needed = memory::estimate(path, context_size)?.total();
+        loop {
+            let Some(available) = memory::available() else {
+                return Ok(());
+            };
+            // layers offloaded to the GPU leave the memory
+            let available = available + memory::free_vram().unwrap_or(0);
+            if needed <= available {
+                return Ok(());
+            }
+
+            let used = self.on_demand.used.lock().unwrap().clone();
+            let Some(oldest) = server::load_all()?
+                .into_iter()
+                .filter(is_on_demand)
+                .min_by_key(|state| used.get(&state.name).copied())
+            else {
+                tracing::warn!(needed, available, "no model left to unload, loading anyway");
+                return Ok(());
+            };
+            println!(
+                "Unloading {} to make room, it was used the longest ago",
+                oldest.name
+            );
+            server::stop(&oldest)?;
+            self.on_demand.used.lock().unwrap().remove(&oldest.name);
+            events::emit(
+                "model-evicted",
+                json!({ "service": oldest.name, "needed": needed, "available": available }),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_to_the_chat_template_of_the_service() {
+        let service = |models: Value| -> ServiceState {
+            serde_json::from_value(json!({
+                "name": "api-server",
+                "pid": 1,
+                "port": 9090,
+                "models": models,
+                "log": "api-server.log",
+            }))
+            .unwrap()
+        };
+        let templated_chat = service(json!([
+            { "name": "nomic", "path": "/models/nomic.gguf", "kind": "embedding" },
+            { "name": "gemma", "path": "/models/gemma.gguf", "kind": "chat", "chat_template": "gguf" },
+        ]));
+        assert_eq!(
+            templated(&templated_chat),
+            Some(("/models/gemma.gguf".to_string(), "gguf".to_string()))
+        );
+        let prompted_chat = service(json!([
+            { "name": "llama", "path": "/models/llama.gguf", "kind": "chat" },
+        ]));
+        assert_eq!(templated(&prompted_chat), None);
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::bail;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+// Arrays longer than this, e.g. the vocabulary, are not kept
+const MAX_ARRAY_LEN: u64 = 1024;
+// The vocabulary, of which the special tokens are kept
+const TOKENS: &str = "tokenizer.ggml.tokens";
+
+// The metadata in the header of a gguf file
+#[derive(Debug, Clone)]
+pub struct Gguf {
+    pub metadata: BTreeMap<String, Value>,
+    // the text of the special tokens by name, e.g. `bos` for `tokenizer.ggml.bos_token_id`
+    pub special_tokens: BTreeMap<String, String>,
+}
+
+impl Gguf {
+    // Read the metadata only, the tensors are not touched
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let mut reader = Reader(BufReader::new(File::open(path)?));
+        let mut magic = [0; 4];
+        reader.0.read_exact(&mut magic)?;
+        if &magic != b"GGUF" {
+            bail!("{} is not a gguf file", path.display());
+        }
+        let version = reader.u32()?;
+        // version 1 counted with 32 bits
+        let count = |reader: &mut Reader| match version {
+            1 => reader.u32().map(u64::from),
+            _ => reader.u64(),
+        };
+        // the tensors come after the metadata
+        count(&mut reader)?;
+        let kv_count = count(&mut reader)?;
+
+        let mut metadata = BTreeMap::new();
+        let mut vocabulary = Vec::new();
+        for _ in 0..kv_count {
+            let key = reader.string(version)?;
+            let kind = reader.u32()?;
+            let value = reader.value(kind, version, key == TOKENS)?;
+            match value {
+                Value::Array(tokens) if key == TOKENS && tokens.len() as u64 > MAX_ARRAY_LEN => {
+                    metadata.insert(key, json!({ "len": tokens.len() }));
+                    vocabulary = tokens;
+                }
+                value => {
+                    metadata.insert(key, value);
+                }
+            }
+        }
+        if let Some(Value::Array(tokens)) = metadata.get(TOKENS) {
+            vocabulary = tokens.clone();
+        }
+        let special_tokens = metadata
+            .iter()
+            .filter_map(|(key, id)| {
+                let name = key
+                    .strip_prefix("tokenizer.ggml.")?
+                    .strip_suffix("_token_id")?;
+                let token = vocabulary.get(id.as_u64()? as usize)?.as_str()?;
+                Some((name.to_string(), token.to_string()))
+            })
+            .collect();
+
+        Ok(Self {
+            metadata,
+            special_tokens,
+        })
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("general.architecture")?.as_str()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.metadata.get(key)
+    }
+
+    // The Jinja template its chats are written in, as transformers would render them
+    pub fn chat_template(&self) -> Option<&str> {
+        self.get("tokenizer.chat_template")?.as_str()
+    }
+
+    // Name of the quantization most tensors use, as llama.cpp names its file types
+    pub fn file_type(&self) -> Option<&'static str> {
+        let name = match self.get("general.file_type")?.as_u64()? {
+            0 => "F32",
+            1 => "F16",
+            2 => "Q4_0",
+            3 => "Q4_1",
+            7 => "Q8_0",
+            8 => "Q5_0",
+            9 => "Q5_1",
+            10 => "Q2_K",
+            11 => "Q3_K_S",
+            12 => "Q3_K_M",
+            13 => "Q3_K_L",
+            14 => "Q4_K_S",
+            15 => "Q4_K_M",
+            16 => "Q5_K_S",
+            17 => "Q5_K_M",
+            18 => "Q6_K",
+            19 => "IQ2_XXS",
+            20 => "IQ2_XS",
+            21 => "Q2_K_S",
+            22 => "IQ3_XS",
+            23 => "IQ3_XXS",
+            24 => "IQ1_S",
+            25 => "IQ4_NL",
+            26 => "IQ3_S",
+            27 => "IQ3_M",
+            28 => "IQ2_S",
+            29 => "IQ2_M",
+            30 => "IQ4_XS",
+            31 => "IQ1_M",
+            32 => "BF16",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    // A number of the architecture, e.g. `block_count` for `llama.block_count`
+    pub fn arch_u64(&self, key: &str) -> Option<u64> {
+        let arch = self.architecture()?;
+        self.get(&format!("{}.{}", arch, key))?.as_u64()
+    }
+}
+
+struct Reader(BufReader<File>);
+impl Reader {
+    fn bytes<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut buffer = [0; N];
+        self.0.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn string(&mut self, version: u32) -> anyhow::Result<String> {
+        let len = match version {
+            1 => self.u32()? as u64,
+            _ => self.u64()?,
+        };
+        if len > 64 * 1024 * 1024 {
+            bail!("Invalid gguf string of {} bytes", len);
+        }
+        let mut buffer = vec![0; len as usize];
+        self.0.read_exact(&mut buffer)?;
+        // version 1 terminated strings with a nul
+        while buffer.last() == Some(&0) {
+            buffer.pop();
+        }
+        Ok(String::from_utf8_lossy(&buffer).to_string())
+    }
+
+    // A value, arrays of any length kept with `whole`
+    fn value(&mut self, kind: u32, version: u32, whole: bool) -> anyhow::Result<Value> {
+        Ok(match kind {
+            0 => json!(self.bytes::<1>()?[0]),
+            1 => json!(self.bytes::<1>()?[0] as i8),
+            2 => json!(u16::from_le_bytes(self.bytes()?)),
+            3 => json!(i16::from_le_bytes(self.bytes()?)),
+            4 => json!(self.u32()?),
+            5 => json!(i32::from_le_bytes(self.bytes()?)),
+            6 => json!(f32::from_le_bytes(self.bytes()?)),
+            7 => json!(self.bytes::<1>()?[0] != 0),
+            8 => json!(self.string(version)?),
+            9 => {
+                let item_kind = self.u32()?;
+                let len = match version {
+                    1 => self.u32()? as u64,
+                    _ => self.u64()?,
+                };
+                let kept = whole || len <= MAX_ARRAY_LEN;
+                let mut items = Vec::new();
+                for _ in 0..len {
+                    let item = self.value(item_kind, version, false)?;
+                    if kept {
+                        items.push(item);
+                    }
+                }
+                match kept {
+                    true => Value::Array(items),
+                    false => json!({ "len": len }),
+                }
+            }
+            10 => json!(self.u64()?),
+            11 => json!(i64::from_le_bytes(self.bytes()?)),
+            12 => json!(f64::from_le_bytes(self.bytes()?)),
+            _ => bail!("Unknown gguf value type {}", kind),
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use crate::models;
+use crate::paths;
+use reqwest::Url;
+use std::{
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
+
+// A file of a repository on the Hugging Face hub
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HubFile {
+    // `<owner>/<name>`
+    pub repo: String,
+    // branch, tag or commit
+    pub revision: String,
+    // path in the repository
+    pub path: String,
+}
+impl HubFile {
+    pub fn url(&self) -> String {
+        format!(
+            "https://huggingface.co/{}/resolve/{}/{}",
+            self.repo, self.revision, self.path
+        )
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+// `https://huggingface.co/<owner>/<name>/resolve/<revision>/<path>`, `blob` in place of
+// `resolve` being the page of the file
+pub fn parse_url(url: &str) -> Option<HubFile> {
+    let url = Url::parse(url).ok()?;
+    if url.host_str() != Some("huggingface.co") {
+        return None;
+    }
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    match segments.as_slice() {
+        [owner, name, "resolve" | "blob", revision, path @ ..] if !path.is_empty() => {
+            Some(HubFile {
+                repo: format!("{}/{}", owner, name),
+                revision: revision.to_string(),
+                path: path.join("/"),
+            })
+        }
+        _ => None,
+    }
+}
+
+// The cache shared by the huggingface_hub library, transformers and huggingface-cli
+pub fn hub_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("HF_HUB_CACHE") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("HF_HOME") {
+        return Some(PathBuf::from(dir).join("hub"));
+    }
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| paths::home().ok().map(|home| home.join(".cache")))?;
+    Some(cache.join("huggingface").join("hub"))
+}
+
+// The directory of the repository in the cache, e.g. `models--second-state--Llama-3-8B-GGUF`
+fn repo_dir(hub: &Path, repo: &str) -> PathBuf {
+    hub.join(format!("models--{}", repo.replace('/', "--")))
+}
+
+// The file in the hub cache, resolved to its blob
+pub fn cached(file: &HubFile) -> Option<PathBuf> {
+    let dir = repo_dir(&hub_dir()?, &file.repo);
+    // branches and tags are mapped to the commit they were downloaded at
+    let commit =
+        match file.revision.len() == 40 && file.revision.chars().all(|c| c.is_ascii_hexdigit()) {
+            true => file.revision.clone(),
+            false => fs::read_to_string(dir.join("refs").join(&file.revision))
+                .ok()?
+                .trim()
+                .to_string(),
+        };
+    let path = dir.join("snapshots").join(commit).join(&file.path);
+
+    // snapshots hold symlinks to the blobs
+    fs::canonicalize(path).ok().filter(|path| path.is_file())
+}
+
+// The hub file of a path in a snapshot of the cache
+fn hub_file(hub: &Path, path: &Path) -> Option<HubFile> {
+    let components = path
+        .strip_prefix(hub)
+        .ok()?
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    match components.as_slice() {
+        [repo, "snapshots", commit, path @ ..] if !path.is_empty() => {
+            let (owner, name) = repo.strip_prefix("models--")?.split_once("--")?;
+            Some(HubFile {
+                repo: format!("{}/{}", owner, name),
+                revision: commit.to_string(),
+                path: path.join("/"),
+            })
+        }
+        _ => None,
+    }
+}
+
+// Every gguf in the snapshots of the cache
+pub fn cached_files() -> Vec<(HubFile, PathBuf)> {
+    let Some(hub) = hub_dir() else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    let mut stack = vec![hub.clone()];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+            // the blobs are reached through the snapshots
+            if path.file_name().is_some_and(|name| name == "blobs") {
+                continue;
+            }
+            if path.is_dir() {
+                stack.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "gguf") {
+                if let Some(file) = hub_file(&hub, &path) {
+                    files.push((file, path));
+                }
+            }
+        }
+    }
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    files
+}
+
+// Link the file of the url into the models directory when the hub cache has it.
+// Returns its path in the models directory.
+pub fn reuse(url: &str) -> anyhow::Result<Option<PathBuf>> {
+    let Some(file) = parse_url(url) else {
+        return Ok(None);
+    };
+    let Some(blob) = cached(&file) else {
+        return Ok(None);
+    };
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(file.file_name());
+    if dest.exists() {
+        fs::remove_file(&dest)?;
+    }
+    let how = models::link(&blob, &dest)?;
+    tracing::info!(src = %blob.display(), dest = %dest.display(), how, "reused from the hub cache");
+    println!("{} {} from the Hugging Face cache", how, file.file_name());
+    models::record(&dest, url)?;
+
+    Ok(Some(dest))
+}
+
+// Link ggufs of the hub cache into the models directory and record where they came from,
+// every one of them when no path is given
+pub fn command_adopt(paths: Vec<PathBuf>) -> anyhow::Result<()> {
+    let hub = hub_dir();
+    let files = match paths.is_empty() {
+        true => cached_files(),
+        false => paths
+            .into_iter()
+            .map(|path| {
+                // keep the snapshot path, canonicalizing it would give the blob
+                let path = std::path::absolute(&path)?;
+                let file =
+                    hub.as_deref()
+                        .and_then(|hub| hub_file(hub, &path))
+                        .ok_or(anyhow::anyhow!(
+                            "{} is not in a snapshot of the Hugging Face cache",
+                            path.display()
+                        ))?;
+                Ok((file, path))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+    };
+    if files.is_empty() {
+        println!(
+            "No ggufs found in the Hugging Face cache{}",
+            hub.map(|hub| format!(" {}", hub.display()))
+                .unwrap_or_default()
+        );
+        return Ok(());
+    }
+
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    for (file, path) in files {
+        let blob = fs::canonicalize(&path)?;
+        let dest = dir.join(file.file_name());
+        match dest.exists() {
+            true if fs::canonicalize(&dest)? == blob || models::same_file(&dest, &blob) => {
+                println!("{} is already adopted", file.file_name())
+            }
+            true => println!(
+                "Skipping {} of {}, {} already exists",
+                file.path,
+                file.repo,
+                dest.display()
+            ),
+            false => {
+                let how = models::link(&blob, &dest)?;
+                models::record(&dest, &file.url())?;
+                println!("{} {} of {}", how, file.path, file.repo);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use crate::events;
+use crate::paths;
+use anyhow::bail;
+use console::style;
+use serde_json::json;
+use std::{env, fs, path::PathBuf, sync::OnceLock};
+
+// Name given for this run by the deprecated positional argument, it wins over the stored one
+static OVERRIDE: OnceLock<String> = OnceLock::new();
+
+// File in the config directory keeping the name set with `gaia node name set`
+pub const NAME_FILE: &str = "node-name";
+
+// Longest name, what a DNS label allows
+const MAX_LEN: usize = 63;
+
+pub fn init(name: Option<String>) {
+    if let Some(name) = name {
+        eprintln!(
+            "{}",
+            style(format!(
+                "The name argument is deprecated and goes away in the next release, use `gaia node name set {}`",
+                name
+            ))
+            .yellow()
+        );
+        let _ = OVERRIDE.set(name);
+    }
+}
+
+fn name_path() -> anyhow::Result<PathBuf> {
+    Ok(paths::config_dir()?.join(NAME_FILE))
+}
+
+// The machine's host name, what a node is called until it is named
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("gaia-node".to_string())
+}
+
+fn stored() -> Option<String> {
+    let name = fs::read_to_string(name_path().ok()?).ok()?;
+    Some(name.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+// What the node is called in logs, stats and events
+pub fn name() -> String {
+    OVERRIDE
+        .get()
+        .cloned()
+        .or_else(stored)
+        .unwrap_or_else(hostname)
+}
+
+fn validate(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > MAX_LEN {
+        bail!("A node name has 1 to {} characters", MAX_LEN);
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        bail!(
+            "Invalid node name '{}', use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+pub fn command_get() -> anyhow::Result<()> {
+    println!("{}", name());
+    if OVERRIDE.get().is_none() && stored().is_none() {
+        eprintln!(
+            "{}",
+            style("The host name, set another with `gaia node name set`").dim()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn command_set(name: &str) -> anyhow::Result<()> {
+    validate(name)?;
+    let path = name_path()?;
+    let old = stored();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, format!("{}\n", name))?;
+    events::emit("node-renamed", json!({ "from": old, "to": name }));
+    println!("The node is called {} from now on", name);
+
+    Ok(())
+}
@@ -0,0 +1,484 @@
+use crate::blob::Utc;
+use crate::events;
+use crate::server::{self, ServiceState};
+use crate::watchdog;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    net::{Shutdown, TcpListener, TcpStream},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// How often the ports of the stopped services are checked for a request
+const TICK: Duration = Duration::from_millis(200);
+// How often the services are checked for being idle
+const IDLE_CHECK: Duration = Duration::from_secs(10);
+
+// Set once the daemon is asked to stop, so the services it stops are not brought back
+static STOPPING: AtomicBool = AtomicBool::new(false);
+// Services stopped by the daemon, to start again, by name
+static PARKED: Mutex<BTreeMap<String, Parked>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Args)]
+pub struct IdleArgs {
+    #[arg(
+        long = "idle-timeout",
+        help = "Stop the services after this long without a request, e.g. 30m, and start them again on the next request",
+        value_name = "DURATION",
+        value_parser = events::parse_age
+    )]
+    pub timeout: Option<Duration>,
+}
+impl IdleArgs {
+    // The flags to hand on to the detached daemon
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(timeout) = self.timeout {
+            args.push(format!("--idle-timeout={}s", timeout.as_secs()));
+        }
+        args
+    }
+}
+
+// e.g. `{ cron: "0 18 * * mon-fri", action: stop }` in the `schedule` of a node file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleEntry {
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub cron: Cron,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    // start the services the schedule stopped
+    Start,
+    // stop the running services until a `start` entry, requests do not start them
+    Stop,
+}
+
+// Minute, hour, day of the month, month and day of the week, as crontab has them, in local
+// time. Each field is a set of bits, one per value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // crontab runs on either day when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+impl Cron {
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let fields = source.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            bail!(
+                "expected minute, hour, day of month, month and day of week, got '{}'",
+                source
+            );
+        };
+        const MONTHS: &[&str] = &[
+            "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+        ];
+        const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+        let mut weekday_bits = field(weekdays, 0, 7, WEEKDAYS, 0)?;
+        // 7 is Sunday too
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            source: source.to_string(),
+            minutes: field(minutes, 0, 59, &[], 0)?,
+            hours: field(hours, 0, 23, &[], 0)?,
+            days: field(days, 1, 31, &[], 0)?,
+            months: field(months, 1, 12, MONTHS, 1)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn matches(&self, time: &Utc) -> bool {
+        let has = |bits: u64, value: u64| bits & (1 << value) != 0;
+        let day = has(self.days, time.day);
+        let weekday = has(self.weekdays, time.weekday);
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute)
+            && has(self.hours, time.hour)
+            && has(self.months, time.month)
+            && day
+    }
+}
+
+// The bits of the values of a crontab field: `*`, `5`, `1-5`, `*/15`, `9-17/2`, `mon-fri` and
+// lists of them separated by commas. Names are numbered from `first`.
+fn field(spec: &str, min: u64, max: u64, names: &[&str], first: u64) -> anyhow::Result<u64> {
+    let value = |s: &str| -> anyhow::Result<u64> {
+        let lower = s.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(i) => i as u64 + first,
+            None => s
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not a number in '{}'", s, spec))?,
+        };
+        if value < min || value > max {
+            bail!("{} is out of {}-{} in '{}'", value, min, max, spec);
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u64>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or(anyhow!("'{}' is not a step in '{}'", step, spec))?,
+            ),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if from > to {
+            bail!("'{}' ends before it starts in '{}'", range, spec);
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+fn deserialize_cron<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Cron, D::Error> {
+    Cron::parse(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+// A service the daemon stopped, with the port it listens on for the next request when it
+// stopped for being idle
+struct Parked {
+    state: ServiceState,
+    reason: &'static str,
+    listener: Option<TcpListener>,
+}
+
+// The services the daemon stopped, with why
+pub fn parked() -> Vec<(ServiceState, String)> {
+    PARKED
+        .lock()
+        .unwrap()
+        .values()
+        .map(|parked| (parked.state.clone(), parked.reason.to_string()))
+        .collect()
+}
+
+// Forget the stopped services, freeing their ports for services launched otherwise
+pub fn forget() {
+    PARKED.lock().unwrap().clear();
+}
+
+// Keep the stopped services stopped while the daemon stops
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+    forget();
+}
+
+// Stop the idle services and follow the schedule in the background
+pub fn spawn(args: IdleArgs, schedule: Vec<ScheduleEntry>) {
+    if args.timeout.is_none() && schedule.is_empty() {
+        return;
+    }
+    tracing::info!(
+        timeout = ?args.timeout,
+        entries = schedule.len(),
+        "stopping idle services"
+    );
+    thread::spawn(move || {
+        let mut idle = Idle {
+            timeout: args.timeout,
+            schedule,
+            checked: None,
+            minute: None,
+            woken: BTreeMap::new(),
+        };
+        loop {
+            thread::sleep(TICK);
+            if STOPPING.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Err(e) = idle.tick() {
+                tracing::warn!("idle check failed: {:#}", e);
+            }
+        }
+    });
+}
+
+struct Idle {
+    timeout: Option<Duration>,
+    schedule: Vec<ScheduleEntry>,
+    checked: Option<Instant>,
+    // the last minute the schedule was followed for, in unix minutes
+    minute: Option<u64>,
+    // when a service was last started on a request, by name, its log may be older than that
+    woken: BTreeMap<String, SystemTime>,
+}
+impl Idle {
+    fn tick(&mut self) -> anyhow::Result<()> {
+        self.accept();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        if self.minute != Some(now / 60) {
+            self.minute = Some(now / 60);
+            self.follow_schedule(now)?;
+        }
+
+        if let Some(timeout) = self.timeout {
+            if self
+                .checked
+                .is_none_or(|checked| checked.elapsed() >= IDLE_CHECK)
+            {
+                self.checked = Some(Instant::now());
+                for state in server::load_all()? {
+                    let idle = self.idle_for(&state);
+                    if idle >= timeout {
+                        tracing::info!(service = %state.name, idle_secs = idle.as_secs(), "idle");
+                        println!(
+                            "{} has been idle for {}, stopping it until the next request",
+                            state.name,
+                            match idle.as_secs() {
+                                secs @ 0..120 => format!("{} seconds", secs),
+                                secs => format!("{} minutes", secs / 60),
+                            }
+                        );
+                        park(state, "idle", true)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // How long since the service last wrote to its log, which the api-server does on every request
+    fn idle_for(&self, state: &ServiceState) -> Duration {
+        let logged = fs::metadata(&state.log)
+            .and_then(|metadata| metadata.modified())
+            .unwrap_or(UNIX_EPOCH);
+        let started = UNIX_EPOCH + Duration::from_secs(state.started);
+        let woken = self.woken.get(&state.name).copied().unwrap_or(UNIX_EPOCH);
+        let last = logged.max(started).max(woken);
+
+        SystemTime::now().duration_since(last).unwrap_or_default()
+    }
+
+    // Start the services whose port got a request, handing the request over once they answer
+    fn accept(&mut self) {
+        let mut requested = Vec::new();
+        {
+            let parked = PARKED.lock().unwrap();
+            for (name, parked) in parked.iter() {
+                let Some(listener) = &parked.listener else {
+                    continue;
+                };
+                match listener.accept() {
+                    Ok((stream, _)) => requested.push((name.clone(), stream)),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => tracing::warn!(service = %name, "failed to accept a request: {}", e),
+                }
+            }
+        }
+
+        for (name, stream) in requested {
+            tracing::info!(service = %name, "request while stopped");
+            match wake(&name) {
+                Ok(Some(state)) => {
+                    self.woken.insert(name, SystemTime::now());
+                    thread::spawn(move || {
+                        if let Err(e) = forward(stream, state.port) {
+                            tracing::warn!("failed to hand over the request: {}", e);
+                        }
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!(service = %name, "failed to start again: {:#}", e),
+            }
+        }
+    }
+
+    // Run the entries of the schedule for this minute
+    fn follow_schedule(&mut self, now: u64) -> anyhow::Result<()> {
+        if self.schedule.is_empty() {
+            return Ok(());
+        }
+        let time = Utc::of(now.saturating_add_signed(utc_offset()));
+        let actions = self
+            .schedule
+            .iter()
+            .filter(|entry| entry.cron.matches(&time))
+            .map(|entry| (entry.action, entry.cron.source.clone()))
+            .collect::<Vec<_>>();
+
+        for (action, cron) in actions {
+            tracing::info!(?action, cron, "scheduled");
+            match action {
+                Action::Stop => {
+                    for state in server::load_all()? {
+                        println!("Stopping {} as scheduled ({})", state.name, cron);
+                        park(state, "schedule", false)?;
+                    }
+                    // requests do not start the services stopped for being idle either
+                    for parked in PARKED.lock().unwrap().values_mut() {
+                        parked.listener = None;
+                        parked.reason = "schedule";
+                    }
+                }
+                Action::Start => {
+                    let names = PARKED.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+                    for name in names {
+                        println!("Starting {} as scheduled ({})", name, cron);
+                        match wake(&name) {
+                            Ok(_) => {
+                                self.woken.insert(name, SystemTime::now());
+                            }
+                            Err(e) => {
+                                tracing::error!(service = %name, "failed to start: {:#}", e)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Stop the service, listening on its port for the next request when `on_request`
+fn park(state: ServiceState, reason: &'static str, on_request: bool) -> anyhow::Result<()> {
+    server::stop(&state)?;
+    events::emit(
+        "parked",
+        json!({ "service": state.name, "reason": reason, "on_request": on_request }),
+    );
+    let listener = match on_request {
+        true => match listen(state.port) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                tracing::warn!(port = state.port, "cannot listen for requests: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    PARKED.lock().unwrap().insert(
+        state.name.clone(),
+        Parked {
+            state,
+            reason,
+            listener,
+        },
+    );
+
+    Ok(())
+}
+
+fn listen(port: u16) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+// Launch the stopped service again and wait for it to answer, None when it is not stopped
+fn wake(name: &str) -> anyhow::Result<Option<ServiceState>> {
+    // dropping the listener frees the port for the service
+    let Some(Parked {
+        state, listener, ..
+    }) = PARKED.lock().unwrap().remove(name)
+    else {
+        return Ok(None);
+    };
+    drop(listener);
+    println!("Starting {} again", name);
+    let state = server::restart(&state)?;
+    if !watchdog::audio_only(&state) {
+        server::wait_ready(&state, Duration::from_secs(server::DEFAULT_STARTUP_TIMEOUT))?;
+    }
+
+    Ok(Some(state))
+}
+
+// Pass the connection through to the service until either side closes it
+fn forward(client: TcpStream, port: u16) -> io::Result<()> {
+    client.set_nonblocking(false)?;
+    let service = TcpStream::connect(("127.0.0.1", port))?;
+    let (mut client_read, mut service_write) = (client.try_clone()?, service.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut service_write);
+        let _ = service_write.shutdown(Shutdown::Write);
+    });
+    let (mut service_read, mut client_write) = (service, client);
+    let _ = io::copy(&mut service_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+
+    Ok(())
+}
+
+// Seconds local time is ahead of UTC, as `date` tells, so that DST changes are followed
+fn utc_offset() -> i64 {
+    let output = match cfg!(windows) {
+        true => Command::new("powershell")
+            .args(["-NoProfile", "-Command", "(Get-Date).ToString('zzz')"])
+            .output(),
+        false => Command::new("date").arg("+%z").output(),
+    };
+    let offset = output
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .replace(':', "")
+        })
+        .unwrap_or_default();
+    // e.g. +0200 or -0530
+    let (sign, digits) = match offset.split_at_checked(1) {
+        Some(("-", digits)) => (-1, digits),
+        Some(("+", digits)) => (1, digits),
+        _ => return 0,
+    };
+    let (Some(hours), Some(minutes)) = (
+        digits.get(..2).and_then(|h| h.parse::<i64>().ok()),
+        digits.get(2..4).and_then(|m| m.parse::<i64>().ok()),
+    ) else {
+        return 0;
+    };
+
+    sign * (hours * 3600 + minutes * 60)
+}
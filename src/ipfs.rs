@@ -0,0 +1,462 @@
+use crate::progress::Progress;
+use anyhow::{anyhow, bail};
+use reqwest::{blocking::Client, Url};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// Gateway the content is fetched through, overridden by `$GAIA_IPFS_GATEWAY`. Every block is
+// checked against its CID, so the gateway does not need to be trusted.
+const DEFAULT_GATEWAY: &str = "https://ipfs.io";
+
+// Multicodecs and multihashes the blocks of a file come in
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const SHA2_256: u64 = 0x12;
+
+// Bytes of a CAR section at most, a block with its CID. IPFS blocks are 2 MiB at most, a gateway
+// announcing more is refused before anything is allocated.
+const MAX_SECTION: u64 = 4 * 1024 * 1024;
+
+// Types of UnixFS nodes holding file content
+const UNIXFS_RAW: u64 = 0;
+const UNIXFS_FILE: u64 = 2;
+
+pub fn is_ipfs_url(s: &str) -> bool {
+    s.starts_with("ipfs://")
+}
+
+// Content identifier of a block: its codec and the sha2-256 digest of its bytes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Cid {
+    codec: u64,
+    digest: Vec<u8>,
+}
+impl Cid {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        // version 0, a base58 multihash always starting with Qm
+        if s.len() == 46 && s.starts_with("Qm") {
+            return Self::from_bytes(&mut base58_decode(s)?.as_slice());
+        }
+        let bytes = match s.split_at_checked(1) {
+            Some(("b", rest)) => base32_decode(rest)?,
+            Some(("z", rest)) => base58_decode(rest)?,
+            Some(("f", rest)) => hex_decode(rest)?,
+            _ => bail!(
+                "Unsupported CID '{}', expected Qm… or a base32 bafy… CID",
+                s
+            ),
+        };
+
+        Self::from_bytes(&mut bytes.as_slice())
+    }
+
+    // Read a binary CID, as found in links and CAR sections
+    fn from_bytes(bytes: &mut &[u8]) -> anyhow::Result<Self> {
+        let (version, codec) = match bytes.first() {
+            // version 0 is a bare dag-pb multihash
+            Some(0x12) => (0, DAG_PB),
+            _ => {
+                let version = read_varint(bytes)?;
+                (version, read_varint(bytes)?)
+            }
+        };
+        if version > 1 {
+            bail!("Unsupported CID version {}", version);
+        }
+        let hash = read_varint(bytes)?;
+        let len = read_varint(bytes)? as usize;
+        if hash != SHA2_256 || len != 32 {
+            bail!(
+                "Unsupported multihash {:#x}, only sha2-256 is verified",
+                hash
+            );
+        }
+        if bytes.len() < len {
+            bail!("Truncated CID");
+        }
+        let (digest, rest) = bytes.split_at(len);
+        *bytes = rest;
+
+        Ok(Self {
+            codec,
+            digest: digest.to_vec(),
+        })
+    }
+
+    fn verify(&self, block: &[u8]) -> anyhow::Result<()> {
+        if Sha256::digest(block).as_slice() != self.digest.as_slice() {
+            bail!("A block does not match its CID, the gateway sent corrupted content");
+        }
+
+        Ok(())
+    }
+}
+
+// The CID and the file name to save it as, `ipfs://<cid>?filename=<name>`
+fn parse_url(url: &str) -> anyhow::Result<(String, Option<String>)> {
+    let url = Url::parse(url)?;
+    let cid = url
+        .host_str()
+        .filter(|cid| !cid.is_empty())
+        .ok_or(anyhow!("Missing the CID in {}", url))?;
+    if !url.path().trim_matches('/').is_empty() {
+        bail!("Paths inside IPFS directories are not supported, give the CID of the file itself");
+    }
+    let name = url
+        .query_pairs()
+        .find(|(key, _)| key == "filename")
+        .map(|(_, name)| name.to_string());
+
+    Ok((cid.to_string(), name))
+}
+
+// Name of the downloaded file, `?filename=` or else the CID
+pub fn file_name(url: &str) -> anyhow::Result<String> {
+    let (cid, name) = parse_url(url)?;
+
+    Ok(name.unwrap_or(format!("{}.gguf", cid)))
+}
+
+// Download the file with the CID into the directory, checking every block against the CID
+// that links to it. Returns the path of the file.
+pub fn download(url: &str, dir: &Path) -> anyhow::Result<PathBuf> {
+    let (cid, _) = parse_url(url)?;
+    let root = Cid::parse(&cid)?;
+    let dest = dir.join(file_name(url)?);
+    let gateway = env::var("GAIA_IPFS_GATEWAY").unwrap_or(DEFAULT_GATEWAY.to_string());
+    let _span = tracing::info_span!("download", url, gateway).entered();
+
+    // a CAR holds the blocks themselves, a plain response would be impossible to check
+    let car_url = format!("{}/ipfs/{}?format=car", gateway.trim_end_matches('/'), cid);
+    let response = Client::builder()
+        .timeout(None)
+        .build()?
+        .get(&car_url)
+        .header(
+            "accept",
+            "application/vnd.ipld.car; version=1; order=dfs; dups=y",
+        )
+        .send()?
+        .error_for_status()?;
+    tracing::info!(dest = %dest.display(), "downloading");
+
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)?;
+    let name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(cid.clone());
+    let result = unpack(BufReader::new(response), root, &mut file, &name);
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, &dest)?;
+    tracing::info!(bytes = size, "downloaded");
+
+    Ok(dest)
+}
+
+// Write the file rooted at `root` from a CAR streamed in depth-first order. Returns its size.
+fn unpack(mut car: impl Read, root: Cid, file: &mut File, name: &str) -> anyhow::Result<u64> {
+    // the header only lists the roots, which were asked for
+    let header_len = read_stream_varint(&mut car)?;
+    skip(&mut car, header_len)?;
+
+    let mut progress = None;
+    // blocks still to be written, the next one on top
+    let mut pending = vec![root];
+    // where the content of each block already written went, for gateways leaving out repeats
+    let mut written: HashMap<Cid, (u64, u64)> = HashMap::new();
+    let mut offset = 0u64;
+    // a section read ahead, when the gateway left out a repeated block
+    let mut lookahead = None;
+    while let Some(expected) = pending.pop() {
+        let next = match lookahead.take() {
+            Some(section) => Some(section),
+            None => read_section(&mut car)?,
+        };
+        let (cid, block) = match next {
+            Some((cid, block)) if cid == expected => (cid, block),
+            next => {
+                let &(start, len) = written.get(&expected).ok_or(match &next {
+                    Some(_) => anyhow!(
+                        "The gateway sent the blocks out of order, it does not support order=dfs"
+                    ),
+                    None => anyhow!("The gateway ended the transfer before the last block"),
+                })?;
+                copy_within(file, start, len, offset)?;
+                offset += len;
+                lookahead = next;
+                continue;
+            }
+        };
+        cid.verify(&block)?;
+
+        let (data, links) = match cid.codec {
+            RAW => (block, Vec::new()),
+            DAG_PB => {
+                let node = PbNode::decode(&block)?;
+                let unixfs = UnixFs::decode(&node.data)?;
+                if !matches!(unixfs.kind, UNIXFS_RAW | UNIXFS_FILE) {
+                    bail!("The CID is not a file, e.g. a directory, give the CID of the file");
+                }
+                if progress.is_none() {
+                    progress = Some(Progress::new("download", name, "bytes", unixfs.size));
+                }
+                (unixfs.data, node.links)
+            }
+            codec => bail!("Unsupported block codec {:#x}", codec),
+        };
+        let progress =
+            progress.get_or_insert_with(|| Progress::new("download", name, "bytes", None));
+
+        // the data of a node comes before the content of its children
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&data)?;
+        progress.advance(data.len() as u64);
+        if links.is_empty() {
+            written.insert(cid, (offset, data.len() as u64));
+        }
+        offset += data.len() as u64;
+        pending.extend(links.into_iter().rev());
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    Ok(offset)
+}
+
+// Repeat bytes already written at another place of the file
+fn copy_within(file: &mut File, start: u64, len: u64, to: u64) -> anyhow::Result<()> {
+    let mut bytes = vec![0; len as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut bytes)?;
+    file.seek(SeekFrom::Start(to))?;
+    file.write_all(&bytes)?;
+
+    Ok(())
+}
+
+// The next CID and block of a CAR, None at its end
+fn read_section(car: &mut impl Read) -> anyhow::Result<Option<(Cid, Vec<u8>)>> {
+    let len = match read_stream_varint(car) {
+        Ok(len) => len,
+        Err(e) if is_eof(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_SECTION {
+        bail!(
+            "The gateway sent a block of {} bytes, more than IPFS allows",
+            len
+        );
+    }
+    let mut section = vec![0; len as usize];
+    car.read_exact(&mut section)?;
+    let mut rest = section.as_slice();
+    let cid = Cid::from_bytes(&mut rest)?;
+
+    Ok(Some((cid, rest.to_vec())))
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> anyhow::Result<()> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    if skipped != len {
+        bail!("Truncated CAR header");
+    }
+
+    Ok(())
+}
+
+// A dag-pb node, the protobuf holding the UnixFS data and the links to the children
+struct PbNode {
+    data: Vec<u8>,
+    links: Vec<Cid>,
+}
+impl PbNode {
+    fn decode(mut bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut node = PbNode {
+            data: Vec::new(),
+            links: Vec::new(),
+        };
+        while !bytes.is_empty() {
+            match read_field(&mut bytes)? {
+                (1, Field::Bytes(data)) => node.data = data.to_vec(),
+                (2, Field::Bytes(mut link)) => {
+                    while !link.is_empty() {
+                        if let (1, Field::Bytes(mut hash)) = read_field(&mut link)? {
+                            node.links.push(Cid::from_bytes(&mut hash)?);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(node)
+    }
+}
+
+// The UnixFS part of a dag-pb node
+struct UnixFs {
+    kind: u64,
+    data: Vec<u8>,
+    size: Option<u64>,
+}
+impl UnixFs {
+    fn decode(mut bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut unixfs = UnixFs {
+            kind: u64::MAX,
+            data: Vec::new(),
+            size: None,
+        };
+        while !bytes.is_empty() {
+            match read_field(&mut bytes)? {
+                (1, Field::Varint(kind)) => unixfs.kind = kind,
+                (2, Field::Bytes(data)) => unixfs.data = data.to_vec(),
+                (3, Field::Varint(size)) => unixfs.size = Some(size),
+                _ => {}
+            }
+        }
+
+        Ok(unixfs)
+    }
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Other,
+}
+
+// The number and value of the next protobuf field
+fn read_field<'a>(bytes: &mut &'a [u8]) -> anyhow::Result<(u64, Field<'a>)> {
+    let key = read_varint(bytes)?;
+    let field = match key & 7 {
+        0 => Field::Varint(read_varint(bytes)?),
+        1 | 5 => {
+            let len = if key & 7 == 1 { 8 } else { 4 };
+            *bytes = bytes.get(len..).ok_or(anyhow!("Truncated protobuf"))?;
+            Field::Other
+        }
+        2 => {
+            let len = read_varint(bytes)? as usize;
+            if bytes.len() < len {
+                bail!("Truncated protobuf");
+            }
+            let (value, rest) = bytes.split_at(len);
+            *bytes = rest;
+            Field::Bytes(value)
+        }
+        wire => bail!("Unsupported protobuf wire type {}", wire),
+    };
+
+    Ok((key >> 3, field))
+}
+
+fn read_varint(bytes: &mut &[u8]) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(anyhow!("Truncated varint"))?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    bail!("Varint too long")
+}
+
+fn read_stream_varint(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    bail!("Varint too long")
+}
+
+fn base58_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    const ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let mut carry = ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(anyhow!("Invalid base58 character '{}'", c as char))?
+            as u32;
+        for byte in bytes.iter_mut().rev() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    // leading ones are leading zero bytes
+    let zeros = s.bytes().take_while(|&c| c == b'1').count();
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes);
+
+    Ok(decoded)
+}
+
+fn base32_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => bail!("Invalid base32 character '{}'", c as char),
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+pub fn hex_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        bail!("Invalid hex '{}'", s);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex '{}'", s)))
+        .collect()
+}
@@ -0,0 +1,2552 @@
+use crate::blob::Utc;
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    rc::Rc,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Bytes a render produces at most and macro calls nested at most, the templates coming with
+// models of any origin
+const MAX_OUTPUT: usize = 16 * 1024 * 1024;
+const MAX_DEPTH: usize = 64;
+
+// A template in the subset of Jinja that chat templates are written in, such as the
+// `tokenizer.chat_template` of a gguf file. It renders as transformers renders chat templates,
+// with the newline after a block and the spaces before it on its line trimmed.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        // a single newline ending the source is not part of the template
+        let source = source
+            .strip_suffix('\n')
+            .map(|source| source.strip_suffix('\r').unwrap_or(source))
+            .unwrap_or(source);
+        let mut parser = Parser {
+            segments: lex(source)?,
+            at: 0,
+        };
+        let (nodes, end) = parser.nodes(&[])?;
+        if let Some((tag, tokens)) = end {
+            bail!("line {}: unexpected {{% {} %}}", tokens.line, tag);
+        }
+
+        Ok(Self { nodes })
+    }
+
+    // Render with the variables of a JSON object
+    pub fn render(&self, variables: &Value) -> anyhow::Result<String> {
+        let globals = match variables {
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(name, value)| (name.clone(), Val::from(value)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut renderer = Renderer {
+            scopes: vec![globals],
+            depth: 0,
+        };
+        let mut out = String::new();
+        renderer.nodes(&self.nodes, &mut out)?;
+
+        Ok(out)
+    }
+}
+
+// ---- lexing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Op(&'static str),
+}
+
+// longest first, so that `<=` is not read as `<` then `=`
+const OPS: &[&str] = &[
+    "==", "!=", "<=", ">=", "//", "**", "+", "-", "*", "/", "%", "~", "|", ".", ",", ":", "(", ")",
+    "[", "]", "{", "}", "=", "<", ">",
+];
+
+// The template as text between tags
+enum Segment {
+    Text(String),
+    // {{ ... }}
+    Output(Tokens),
+    // {% ... %}
+    Block(Tokens),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Opening {
+    Output,
+    Block,
+    Comment,
+}
+
+fn lex(source: &str) -> anyhow::Result<Vec<Segment>> {
+    let bytes = source.as_bytes();
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    let mut line = 1;
+    // how the last tag closed: `-` trims all the spaces after it, a block the newline after it
+    let mut trim_after = false;
+    let mut block_before = false;
+    loop {
+        let next = (pos..bytes.len().saturating_sub(1)).find_map(|i| match &bytes[i..i + 2] {
+            b"{{" => Some((i, Opening::Output)),
+            b"{%" => Some((i, Opening::Block)),
+            b"{#" => Some((i, Opening::Comment)),
+            _ => None,
+        });
+        let (mut from, mut to) = (pos, next.map_or(bytes.len(), |(start, _)| start));
+        line += source[pos..to].matches('\n').count();
+        if trim_after {
+            from = to - source[from..to].trim_start().len();
+        } else if block_before {
+            if source[from..to].starts_with("\r\n") {
+                from += 2;
+            } else if source[from..to].starts_with('\n') {
+                from += 1;
+            }
+        }
+        let Some((start, opening)) = next else {
+            segments.push(Segment::Text(source[from..to].to_string()));
+            return Ok(segments);
+        };
+
+        let marker = bytes.get(start + 2).copied();
+        if marker == Some(b'-') {
+            to = from + source[from..to].trim_end().len();
+        } else if opening != Opening::Output && marker != Some(b'+') {
+            // the spaces between the start of its line and a block
+            let line_start = source[from..to].rfind('\n').map_or(from, |i| from + i + 1);
+            let at_line_start = line_start > from || from == 0 || bytes[from - 1] == b'\n';
+            if at_line_start
+                && source[line_start..to]
+                    .bytes()
+                    .all(|b| b == b' ' || b == b'\t')
+            {
+                to = line_start;
+            }
+        }
+        if from < to {
+            segments.push(Segment::Text(source[from..to].to_string()));
+        }
+
+        let inner = start + 2 + usize::from(matches!(marker, Some(b'-') | Some(b'+')));
+        let (end, trimmed) = match opening {
+            Opening::Comment => {
+                let close = source[inner..]
+                    .find("#}")
+                    .map(|i| inner + i)
+                    .ok_or(anyhow!("line {}: unclosed comment", line))?;
+                (close + 2, close > inner && bytes[close - 1] == b'-')
+            }
+            _ => {
+                let (tokens, end, trimmed) = tokenize(source, inner, opening, line)?;
+                segments.push(match opening {
+                    Opening::Output => Segment::Output(tokens),
+                    _ => Segment::Block(tokens),
+                });
+                (end, trimmed)
+            }
+        };
+        line += source[start..end].matches('\n').count();
+        trim_after = trimmed;
+        block_before = opening != Opening::Output;
+        pos = end;
+    }
+}
+
+// The tokens of a tag from `i`, with where the tag ends and whether it ends with `-`
+fn tokenize(
+    source: &str,
+    mut i: usize,
+    opening: Opening,
+    line: usize,
+) -> anyhow::Result<(Tokens, usize, bool)> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    // braces of dicts, which `}}` may close
+    let mut braces = 0;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            bail!("line {}: unclosed tag", line);
+        }
+        let rest = &source[i..];
+        let close = match opening {
+            Opening::Block => Some("%}"),
+            _ if braces == 0 => Some("}}"),
+            _ => None,
+        };
+        if let Some(close) = close {
+            if rest.starts_with(close) {
+                return Ok((Tokens::new(tokens, line), i + 2, false));
+            }
+            if rest.starts_with('-') && rest[1..].starts_with(close) {
+                return Ok((Tokens::new(tokens, line), i + 3, true));
+            }
+        }
+
+        let c = bytes[i];
+        if c == b'\'' || c == b'"' {
+            let mut text = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                let Some((at, ch)) = chars.next() else {
+                    bail!("line {}: unclosed string", line);
+                };
+                match ch {
+                    '\\' => match chars.next().map(|(_, escaped)| escaped) {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some('r') => text.push('\r'),
+                        Some('0') => text.push('\0'),
+                        Some(escaped @ ('\\' | '\'' | '"')) => text.push(escaped),
+                        Some(other) => {
+                            text.push('\\');
+                            text.push(other);
+                        }
+                        None => bail!("line {}: unclosed string", line),
+                    },
+                    _ if ch == c as char => break at,
+                    _ => text.push(ch),
+                }
+            };
+            tokens.push(Token::Str(text));
+            i += end + 2;
+        } else if c.is_ascii_digit() {
+            let digits = rest
+                .find(|ch: char| !ch.is_ascii_digit() && ch != '_')
+                .unwrap_or(rest.len());
+            let fraction = rest[digits..]
+                .strip_prefix('.')
+                .filter(|after| after.starts_with(|ch: char| ch.is_ascii_digit()))
+                .map(|after| {
+                    1 + after
+                        .find(|ch: char| !ch.is_ascii_digit())
+                        .unwrap_or(after.len())
+                });
+            let mut len = digits + fraction.unwrap_or(0);
+            // 1e3, 2.5E-4
+            let exponent = rest[len..]
+                .strip_prefix(['e', 'E'])
+                .map(|after| after.strip_prefix(['+', '-']).unwrap_or(after))
+                .filter(|after| after.starts_with(|ch: char| ch.is_ascii_digit()))
+                .map(|after| {
+                    rest.len() - len - after.len()
+                        + after
+                            .find(|ch: char| !ch.is_ascii_digit())
+                            .unwrap_or(after.len())
+                });
+            len += exponent.unwrap_or(0);
+            let number = rest[..len].replace('_', "");
+            tokens.push(match fraction.or(exponent) {
+                Some(_) => Token::Float(number.parse()?),
+                None => Token::Int(
+                    number
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: {} is too large", line, number))?,
+                ),
+            });
+            i += len;
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let len = rest
+                .find(|ch: char| !ch.is_ascii_alphanumeric() && ch != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..len].to_string()));
+            i += len;
+        } else {
+            let op = OPS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "line {}: unexpected '{}'",
+                        line,
+                        rest.chars().next().unwrap_or_default()
+                    )
+                })?;
+            match *op {
+                "{" => braces += 1,
+                "}" => braces -= 1,
+                _ => {}
+            }
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+}
+
+// The tokens of a tag, read from the front
+#[derive(Debug, Clone)]
+struct Tokens {
+    tokens: Vec<Token>,
+    at: usize,
+    line: usize,
+}
+
+impl Tokens {
+    fn new(tokens: Vec<Token>, line: usize) -> Self {
+        Self {
+            tokens,
+            at: 0,
+            line,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<&Token> {
+        self.tokens.get(self.at + ahead)
+    }
+
+    fn next(&mut self) -> anyhow::Result<Token> {
+        let token = self
+            .tokens
+            .get(self.at)
+            .cloned()
+            .ok_or(anyhow!("line {}: unexpected end of tag", self.line))?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Token::Op(found)) if *found == op)
+    }
+
+    fn is_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(found)) if found == name)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        let found = self.is_op(op);
+        self.at += usize::from(found);
+        found
+    }
+
+    fn eat_name(&mut self, name: &str) -> bool {
+        let found = self.is_name(name);
+        self.at += usize::from(found);
+        found
+    }
+
+    fn expect_op(&mut self, op: &str) -> anyhow::Result<()> {
+        match self.eat_op(op) {
+            true => Ok(()),
+            false => Err(self.unexpected(&format!("'{}'", op))),
+        }
+    }
+
+    fn name(&mut self) -> anyhow::Result<String> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.at += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn done(&self) -> anyhow::Result<()> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.unexpected("the end of the tag")),
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> anyhow::Error {
+        match self.peek() {
+            Some(token) => anyhow!(
+                "line {}: expected {}, found {}",
+                self.line,
+                expected,
+                describe(token)
+            ),
+            None => anyhow!(
+                "line {}: expected {}, found the end of the tag",
+                self.line,
+                expected
+            ),
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Name(name) => format!("'{}'", name),
+        Token::Str(text) => format!("'{}'", text),
+        Token::Int(number) => number.to_string(),
+        Token::Float(number) => number.to_string(),
+        Token::Op(op) => format!("'{}'", op),
+    }
+}
+
+// ---- parsing
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Output(Expr),
+    // branches in order, then the else branch
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For(Box<ForLoop>),
+    Set(Target, Expr),
+    // {% set name %}...{% endset %}
+    Capture(String, Vec<Node>),
+    Macro(Arc<Macro>),
+    Break,
+    Continue,
+}
+
+#[derive(Debug, Clone)]
+struct ForLoop {
+    targets: Vec<String>,
+    iterable: Expr,
+    filter: Option<Expr>,
+    body: Vec<Node>,
+    // rendered when nothing is iterated
+    empty: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Name(String),
+    // an attribute of a namespace
+    Attribute(String, String),
+}
+
+#[derive(Debug)]
+struct Macro {
+    name: String,
+    params: Vec<(String, Option<Expr>)>,
+    body: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Const(Const),
+    Name(String),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    Attribute(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, [Option<Box<Expr>>; 3]),
+    Call(Box<Expr>, Args),
+    Filter(Box<Expr>, String, Args),
+    // the test, whether it is negated
+    Test(Box<Expr>, String, Args, bool),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    // `then if condition else otherwise`
+    Conditional(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+#[derive(Debug, Clone)]
+enum Const {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Args {
+    positional: Vec<Expr>,
+    named: Vec<(String, Expr)>,
+}
+
+struct Parser {
+    segments: Vec<Segment>,
+    at: usize,
+}
+
+// The block tag ending a body, such as `endif`, with the rest of its tokens
+type End = (String, Tokens);
+
+impl Parser {
+    // The nodes up to one of the block tags `ends`, returned with the rest of that tag
+    fn nodes(&mut self, ends: &[&str]) -> anyhow::Result<(Vec<Node>, Option<End>)> {
+        let mut nodes = Vec::new();
+        while self.at < self.segments.len() {
+            let segment =
+                std::mem::replace(&mut self.segments[self.at], Segment::Text(String::new()));
+            self.at += 1;
+            match segment {
+                Segment::Text(text) => nodes.push(Node::Text(text)),
+                Segment::Output(mut tokens) => {
+                    let expr = expression(&mut tokens)?;
+                    tokens.done()?;
+                    nodes.push(Node::Output(expr));
+                }
+                Segment::Block(mut tokens) => {
+                    let tag = tokens.name()?;
+                    if ends.contains(&tag.as_str()) {
+                        return Ok((nodes, Some((tag, tokens))));
+                    }
+                    nodes.extend(self.block(&tag, tokens)?);
+                }
+            }
+        }
+        match ends.first() {
+            Some(end) => bail!("missing {{% {} %}}", end),
+            None => Ok((nodes, None)),
+        }
+    }
+
+    // The nodes up to the block tag `end`
+    fn body(&mut self, end: &str) -> anyhow::Result<Vec<Node>> {
+        let (nodes, ended) = self.nodes(&[end])?;
+        if let Some((_, tokens)) = ended {
+            tokens.done()?;
+        }
+        Ok(nodes)
+    }
+
+    fn block(&mut self, tag: &str, mut tokens: Tokens) -> anyhow::Result<Vec<Node>> {
+        let node = match tag {
+            "if" => {
+                let mut branches = Vec::new();
+                let mut condition = expression(&mut tokens)?;
+                tokens.done()?;
+                loop {
+                    let (nodes, ended) = self.nodes(&["elif", "else", "endif"])?;
+                    branches.push((condition, nodes));
+                    let Some((end, mut tokens)) = ended else {
+                        unreachable!()
+                    };
+                    match end.as_str() {
+                        "elif" => {
+                            condition = expression(&mut tokens)?;
+                            tokens.done()?;
+                        }
+                        "else" => {
+                            tokens.done()?;
+                            break Node::If(branches, self.body("endif")?);
+                        }
+                        _ => {
+                            tokens.done()?;
+                            break Node::If(branches, Vec::new());
+                        }
+                    }
+                }
+            }
+            "for" => {
+                let mut targets = vec![tokens.name()?];
+                while tokens.eat_op(",") {
+                    targets.push(tokens.name()?);
+                }
+                if !tokens.eat_name("in") {
+                    return Err(tokens.unexpected("'in'"));
+                }
+                // the condition of the loop is no conditional expression
+                let iterable = or(&mut tokens)?;
+                let filter = match tokens.eat_name("if") {
+                    true => Some(expression(&mut tokens)?),
+                    false => None,
+                };
+                tokens.eat_name("recursive");
+                tokens.done()?;
+                let (body, ended) = self.nodes(&["else", "endfor"])?;
+                let empty = match ended {
+                    Some((end, tokens)) if end == "else" => {
+                        tokens.done()?;
+                        self.body("endfor")?
+                    }
+                    Some((_, tokens)) => {
+                        tokens.done()?;
+                        Vec::new()
+                    }
+                    None => unreachable!(),
+                };
+                Node::For(Box::new(ForLoop {
+                    targets,
+                    iterable,
+                    filter,
+                    body,
+                    empty,
+                }))
+            }
+            "set" => {
+                let name = tokens.name()?;
+                let target = match tokens.eat_op(".") {
+                    true => Target::Attribute(name, tokens.name()?),
+                    false => Target::Name(name),
+                };
+                if tokens.peek().is_none() {
+                    let Target::Name(name) = target else {
+                        return Err(tokens.unexpected("'='"));
+                    };
+                    return Ok(vec![Node::Capture(name, self.body("endset")?)]);
+                }
+                tokens.expect_op("=")?;
+                let value = expression(&mut tokens)?;
+                tokens.done()?;
+                Node::Set(target, value)
+            }
+            "macro" => {
+                let name = tokens.name()?;
+                tokens.expect_op("(")?;
+                let mut params = Vec::new();
+                while !tokens.eat_op(")") {
+                    let param = tokens.name()?;
+                    let default = match tokens.eat_op("=") {
+                        true => Some(expression(&mut tokens)?),
+                        false => None,
+                    };
+                    params.push((param, default));
+                    if !tokens.is_op(")") {
+                        tokens.expect_op(",")?;
+                    }
+                }
+                tokens.done()?;
+                Node::Macro(Arc::new(Macro {
+                    name,
+                    params,
+                    body: self.body("endmacro")?,
+                }))
+            }
+            "break" => {
+                tokens.done()?;
+                Node::Break
+            }
+            "continue" => {
+                tokens.done()?;
+                Node::Continue
+            }
+            // marks what the assistant generated, for training
+            "generation" => {
+                tokens.done()?;
+                return self.body("endgeneration");
+            }
+            _ => bail!("line {}: unsupported tag '{}'", tokens.line, tag),
+        };
+
+        Ok(vec![node])
+    }
+}
+
+fn expression(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let then = or(tokens)?;
+    if !tokens.eat_name("if") {
+        return Ok(then);
+    }
+    let condition = or(tokens)?;
+    let otherwise = match tokens.eat_name("else") {
+        true => Some(Box::new(expression(tokens)?)),
+        false => None,
+    };
+
+    Ok(Expr::Conditional(
+        Box::new(then),
+        Box::new(condition),
+        otherwise,
+    ))
+}
+
+fn or(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut left = and(tokens)?;
+    while tokens.eat_name("or") {
+        left = Expr::Or(Box::new(left), Box::new(and(tokens)?));
+    }
+    Ok(left)
+}
+
+fn and(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut left = not(tokens)?;
+    while tokens.eat_name("and") {
+        left = Expr::And(Box::new(left), Box::new(not(tokens)?));
+    }
+    Ok(left)
+}
+
+fn not(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    match tokens.eat_name("not") {
+        true => Ok(Expr::Not(Box::new(not(tokens)?))),
+        false => compare(tokens),
+    }
+}
+
+fn compare(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut left = binary(tokens, 0)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Token::Op(op @ ("==" | "!=" | "<" | ">" | "<=" | ">="))) => *op,
+            Some(Token::Name(name)) if name == "in" => "in",
+            Some(Token::Name(name))
+                if name == "not"
+                    && matches!(tokens.peek_at(1), Some(Token::Name(next)) if next == "in") =>
+            {
+                tokens.at += 1;
+                "not in"
+            }
+            _ => return Ok(left),
+        };
+        tokens.at += 1;
+        left = Expr::Binary(op, Box::new(left), Box::new(binary(tokens, 0)?));
+    }
+}
+
+// `~`, then `+` and `-`, then `*`, `/`, `//` and `%`, then `**`, each binding tighter
+const LEVELS: &[&[&str]] = &[&["~"], &["+", "-"], &["*", "/", "//", "%"], &["**"]];
+
+fn binary(tokens: &mut Tokens, level: usize) -> anyhow::Result<Expr> {
+    let Some(ops) = LEVELS.get(level) else {
+        return unary(tokens);
+    };
+    let mut left = binary(tokens, level + 1)?;
+    loop {
+        let op = match tokens.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => *op,
+            _ => return Ok(left),
+        };
+        tokens.at += 1;
+        left = Expr::Binary(op, Box::new(left), Box::new(binary(tokens, level + 1)?));
+    }
+}
+
+fn unary(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    if tokens.eat_op("-") {
+        return Ok(Expr::Negate(Box::new(unary(tokens)?)));
+    }
+    if tokens.eat_op("+") {
+        return unary(tokens);
+    }
+    let mut expr = postfix(tokens)?;
+    loop {
+        if tokens.eat_op("|") {
+            let mut name = tokens.name()?;
+            while tokens.eat_op(".") {
+                name = format!("{}.{}", name, tokens.name()?);
+            }
+            let args = match tokens.eat_op("(") {
+                true => args(tokens)?,
+                false => Args::default(),
+            };
+            expr = Expr::Filter(Box::new(expr), name, args);
+        } else if tokens.eat_name("is") {
+            let negated = tokens.eat_name("not");
+            let name = tokens.name()?;
+            let args = if tokens.eat_op("(") {
+                args(tokens)?
+            } else if takes_argument(tokens.peek()) {
+                Args {
+                    positional: vec![postfix(tokens)?],
+                    named: Vec::new(),
+                }
+            } else {
+                Args::default()
+            };
+            expr = Expr::Test(Box::new(expr), name, args, negated);
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
+// Whether the token after a test is its argument, as in `is divisibleby 3`
+fn takes_argument(token: Option<&Token>) -> bool {
+    match token {
+        Some(Token::Str(_) | Token::Int(_) | Token::Float(_)) => true,
+        Some(Token::Op(op)) => *op == "[" || *op == "{",
+        Some(Token::Name(name)) => !matches!(
+            name.as_str(),
+            "and" | "or" | "not" | "if" | "else" | "in" | "is" | "recursive"
+        ),
+        None => false,
+    }
+}
+
+fn postfix(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    let mut expr = primary(tokens)?;
+    loop {
+        if tokens.eat_op(".") {
+            let name = match tokens.next()? {
+                Token::Name(name) => name,
+                // `items.0`
+                Token::Int(index) => {
+                    expr = Expr::Index(Box::new(expr), Box::new(Expr::Const(Const::Int(index))));
+                    continue;
+                }
+                token => bail!(
+                    "line {}: expected a name, found {}",
+                    tokens.line,
+                    describe(&token)
+                ),
+            };
+            expr = Expr::Attribute(Box::new(expr), name);
+        } else if tokens.eat_op("[") {
+            let mut parts: [Option<Box<Expr>>; 3] = [None, None, None];
+            let mut part = 0;
+            loop {
+                if tokens.eat_op("]") {
+                    break;
+                }
+                if tokens.eat_op(":") {
+                    part += 1;
+                    if part > 2 {
+                        return Err(tokens.unexpected("']'"));
+                    }
+                    continue;
+                }
+                if parts[part].is_some() {
+                    return Err(tokens.unexpected("':' or ']'"));
+                }
+                parts[part] = Some(Box::new(expression(tokens)?));
+            }
+            expr = match (part, parts) {
+                (0, [Some(index), None, None]) => Expr::Index(Box::new(expr), index),
+                (0, _) => bail!("line {}: empty subscript", tokens.line),
+                (_, parts) => Expr::Slice(Box::new(expr), parts),
+            };
+        } else if tokens.eat_op("(") {
+            expr = Expr::Call(Box::new(expr), args(tokens)?);
+        } else {
+            return Ok(expr);
+        }
+    }
+}
+
+// The arguments of a call, after its `(`
+fn args(tokens: &mut Tokens) -> anyhow::Result<Args> {
+    let mut args = Args::default();
+    while !tokens.eat_op(")") {
+        let named = matches!(tokens.peek(), Some(Token::Name(_)))
+            && matches!(tokens.peek_at(1), Some(Token::Op("=")));
+        if named {
+            let name = tokens.name()?;
+            tokens.at += 1;
+            args.named.push((name, expression(tokens)?));
+        } else {
+            args.positional.push(expression(tokens)?);
+        }
+        if !tokens.is_op(")") {
+            tokens.expect_op(",")?;
+        }
+    }
+    Ok(args)
+}
+
+fn primary(tokens: &mut Tokens) -> anyhow::Result<Expr> {
+    Ok(match tokens.next()? {
+        Token::Name(name) => match name.as_str() {
+            "true" | "True" => Expr::Const(Const::Bool(true)),
+            "false" | "False" => Expr::Const(Const::Bool(false)),
+            "none" | "None" => Expr::Const(Const::None),
+            _ => Expr::Name(name),
+        },
+        Token::Str(mut text) => {
+            // adjacent strings are one
+            while let Some(Token::Str(next)) = tokens.peek() {
+                text.push_str(next);
+                tokens.at += 1;
+            }
+            Expr::Const(Const::Str(text))
+        }
+        Token::Int(number) => Expr::Const(Const::Int(number)),
+        Token::Float(number) => Expr::Const(Const::Float(number)),
+        Token::Op("(") => {
+            if tokens.eat_op(")") {
+                return Ok(Expr::List(Vec::new()));
+            }
+            let first = expression(tokens)?;
+            if tokens.eat_op(")") {
+                return Ok(first);
+            }
+            // a tuple, taken as a list
+            let mut items = vec![first];
+            while tokens.eat_op(",") && !tokens.is_op(")") {
+                items.push(expression(tokens)?);
+            }
+            tokens.expect_op(")")?;
+            Expr::List(items)
+        }
+        Token::Op("[") => {
+            let mut items = Vec::new();
+            while !tokens.eat_op("]") {
+                items.push(expression(tokens)?);
+                if !tokens.is_op("]") {
+                    tokens.expect_op(",")?;
+                }
+            }
+            Expr::List(items)
+        }
+        Token::Op("{") => {
+            let mut items = Vec::new();
+            while !tokens.eat_op("}") {
+                let key = expression(tokens)?;
+                tokens.expect_op(":")?;
+                items.push((key, expression(tokens)?));
+                if !tokens.is_op("}") {
+                    tokens.expect_op(",")?;
+                }
+            }
+            Expr::Dict(items)
+        }
+        token => bail!(
+            "line {}: expected a value, found {}",
+            tokens.line,
+            describe(&token)
+        ),
+    })
+}
+
+// ---- values
+
+#[derive(Debug, Clone)]
+enum Val {
+    Undefined,
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Rc<str>),
+    List(Rc<Vec<Val>>),
+    // in the order the keys were given
+    Map(Rc<Fields>),
+    // what `namespace()` makes, the one value that `set` changes in place
+    Namespace(Rc<RefCell<Fields>>),
+    Macro(Arc<Macro>),
+}
+
+// Names and their values, in order: the fields of a map, the variables of a scope and the named
+// arguments of a call
+type Fields = Vec<(String, Val)>;
+
+impl From<&Value> for Val {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => Val::None,
+            Value::Bool(value) => Val::Bool(*value),
+            Value::Number(number) => match number.as_i64() {
+                Some(number) => Val::Int(number),
+                None => Val::Float(number.as_f64().unwrap_or_default()),
+            },
+            Value::String(text) => Val::str(text),
+            Value::Array(items) => Val::List(Rc::new(items.iter().map(Val::from).collect())),
+            Value::Object(fields) => Val::Map(Rc::new(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Val::from(value)))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+impl Val {
+    fn str(text: &str) -> Self {
+        Val::Str(Rc::from(text))
+    }
+
+    fn list(items: Vec<Val>) -> Self {
+        Val::List(Rc::new(items))
+    }
+
+    fn is_undefined(&self) -> bool {
+        matches!(self, Val::Undefined)
+    }
+
+    fn truthy(&self) -> bool {
+        match self {
+            Val::Undefined | Val::None => false,
+            Val::Bool(value) => *value,
+            Val::Int(number) => *number != 0,
+            Val::Float(number) => *number != 0.0,
+            Val::Str(text) => !text.is_empty(),
+            Val::List(items) => !items.is_empty(),
+            Val::Map(fields) => !fields.is_empty(),
+            Val::Namespace(_) | Val::Macro(_) => true,
+        }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self {
+            Val::Int(number) => Some(*number as f64),
+            Val::Float(number) => Some(*number),
+            Val::Bool(value) => Some(f64::from(u8::from(*value))),
+            _ => None,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Val::Undefined => "undefined",
+            Val::None => "none",
+            Val::Bool(_) => "boolean",
+            Val::Int(_) => "integer",
+            Val::Float(_) => "float",
+            Val::Str(_) => "string",
+            Val::List(_) => "list",
+            Val::Map(_) => "mapping",
+            Val::Namespace(_) => "namespace",
+            Val::Macro(_) => "macro",
+        }
+    }
+
+    // What `{{ value }}` prints, as Python's str() would
+    fn display(&self) -> String {
+        match self {
+            Val::Undefined => String::new(),
+            Val::Str(text) => text.to_string(),
+            _ => self.repr(),
+        }
+    }
+
+    // As Python's repr()
+    fn repr(&self) -> String {
+        match self {
+            Val::Undefined => String::new(),
+            Val::None => "None".to_string(),
+            Val::Bool(true) => "True".to_string(),
+            Val::Bool(false) => "False".to_string(),
+            Val::Int(number) => number.to_string(),
+            Val::Float(number) => float(*number),
+            Val::Str(text) => {
+                let quote = match text.contains('\'') && !text.contains('"') {
+                    true => '"',
+                    false => '\'',
+                };
+                let mut repr = String::from(quote);
+                for c in text.chars() {
+                    match c {
+                        '\\' => repr.push_str("\\\\"),
+                        '\n' => repr.push_str("\\n"),
+                        '\r' => repr.push_str("\\r"),
+                        '\t' => repr.push_str("\\t"),
+                        _ if c == quote => {
+                            repr.push('\\');
+                            repr.push(c);
+                        }
+                        _ => repr.push(c),
+                    }
+                }
+                repr.push(quote);
+                repr
+            }
+            Val::List(items) => format!(
+                "[{}]",
+                items.iter().map(Val::repr).collect::<Vec<_>>().join(", ")
+            ),
+            Val::Map(fields) => format!(
+                "{{{}}}",
+                fields
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", Val::str(key).repr(), value.repr()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Val::Namespace(_) => "<Namespace>".to_string(),
+            Val::Macro(def) => format!("<Macro '{}'>", def.name),
+        }
+    }
+
+    // As Python's json.dumps with the arguments transformers gives it
+    fn json(&self, indent: Option<usize>, level: usize, out: &mut String) {
+        let (open, separator) = match indent {
+            Some(indent) => (
+                format!("\n{}", " ".repeat(indent * (level + 1))),
+                format!(",\n{}", " ".repeat(indent * (level + 1))),
+            ),
+            None => (String::new(), ", ".to_string()),
+        };
+        let close = match indent {
+            Some(indent) => format!("\n{}", " ".repeat(indent * level)),
+            None => String::new(),
+        };
+        match self {
+            Val::Undefined | Val::None => out.push_str("null"),
+            Val::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Val::Int(number) => out.push_str(&number.to_string()),
+            Val::Float(number) => out.push_str(&float(*number)),
+            Val::Str(text) => {
+                out.push('"');
+                for c in text.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        '\u{8}' => out.push_str("\\b"),
+                        '\u{c}' => out.push_str("\\f"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Val::List(items) if items.is_empty() => out.push_str("[]"),
+            Val::List(items) => {
+                out.push('[');
+                out.push_str(&open);
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(&separator);
+                    }
+                    item.json(indent, level + 1, out);
+                }
+                out.push_str(&close);
+                out.push(']');
+            }
+            Val::Map(fields) if fields.is_empty() => out.push_str("{}"),
+            Val::Map(fields) => {
+                out.push('{');
+                out.push_str(&open);
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(&separator);
+                    }
+                    Val::str(key).json(indent, level + 1, out);
+                    out.push_str(": ");
+                    value.json(indent, level + 1, out);
+                }
+                out.push_str(&close);
+                out.push('}');
+            }
+            Val::Namespace(fields) => {
+                Val::Map(Rc::new(fields.borrow().clone())).json(indent, level, out)
+            }
+            Val::Macro(_) => out.push_str("null"),
+        }
+    }
+
+    // The items of a for loop, the keys of a mapping and the characters of a string
+    fn items(&self) -> anyhow::Result<Vec<Val>> {
+        Ok(match self {
+            Val::Undefined | Val::None => Vec::new(),
+            Val::List(items) => items.to_vec(),
+            Val::Map(fields) => fields.iter().map(|(key, _)| Val::str(key)).collect(),
+            Val::Str(text) => text.chars().map(|c| Val::str(&c.to_string())).collect(),
+            other => bail!("cannot iterate over a {}", other.type_name()),
+        })
+    }
+
+    fn len(&self) -> anyhow::Result<usize> {
+        Ok(match self {
+            Val::Str(text) => text.chars().count(),
+            Val::List(items) => items.len(),
+            Val::Map(fields) => fields.len(),
+            Val::Undefined => 0,
+            other => bail!("a {} has no length", other.type_name()),
+        })
+    }
+
+    // `value.name`, and `value['name']` for a mapping
+    fn attribute(&self, name: &str) -> Val {
+        match self {
+            Val::Map(fields) => fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map_or(Val::Undefined, |(_, value)| value.clone()),
+            Val::Namespace(fields) => fields
+                .borrow()
+                .iter()
+                .find(|(key, _)| key == name)
+                .map_or(Val::Undefined, |(_, value)| value.clone()),
+            _ => Val::Undefined,
+        }
+    }
+
+    fn index(&self, key: &Val) -> Val {
+        let at = |len: usize, index: i64| {
+            let index = if index < 0 { len as i64 + index } else { index };
+            (0..len as i64).contains(&index).then_some(index as usize)
+        };
+        match (self, key) {
+            (Val::Map(_) | Val::Namespace(_), Val::Str(name)) => self.attribute(name),
+            (Val::List(items), Val::Int(index)) => {
+                at(items.len(), *index).map_or(Val::Undefined, |index| items[index].clone())
+            }
+            (Val::Str(text), Val::Int(index)) => {
+                let chars = text.chars().collect::<Vec<_>>();
+                at(chars.len(), *index)
+                    .map_or(Val::Undefined, |index| Val::str(&chars[index].to_string()))
+            }
+            _ => Val::Undefined,
+        }
+    }
+}
+
+// As Python prints floats, with a `.0` on whole numbers
+fn float(number: f64) -> String {
+    if number.is_finite() && number.fract() == 0.0 && number.abs() < 1e16 {
+        format!("{:.1}", number)
+    } else {
+        number.to_string()
+    }
+}
+
+fn equal(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::Undefined, Val::Undefined) | (Val::None, Val::None) => true,
+        (Val::Str(a), Val::Str(b)) => a == b,
+        (Val::List(a), Val::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| equal(a, b))
+        }
+        (Val::Map(a), Val::Map(b)) => {
+            a.len() == b.len()
+                && a.iter().all(|(key, value)| {
+                    b.iter()
+                        .any(|(other, found)| key == other && equal(value, found))
+                })
+        }
+        (Val::Namespace(a), Val::Namespace(b)) => Rc::ptr_eq(a, b),
+        (Val::Macro(a), Val::Macro(b)) => Arc::ptr_eq(a, b),
+        (Val::Int(a), Val::Int(b)) => a == b,
+        _ => match (a.number(), b.number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        },
+    }
+}
+
+fn compare_vals(a: &Val, b: &Val) -> anyhow::Result<Ordering> {
+    match (a, b) {
+        (Val::Int(a), Val::Int(b)) => Ok(a.cmp(b)),
+        (Val::Str(a), Val::Str(b)) => Ok(a.cmp(b)),
+        (Val::List(a), Val::List(b)) => {
+            for (a, b) in a.iter().zip(b.iter()) {
+                match compare_vals(a, b)? {
+                    Ordering::Equal => {}
+                    ordering => return Ok(ordering),
+                }
+            }
+            Ok(a.len().cmp(&b.len()))
+        }
+        _ => match (a.number(), b.number()) {
+            (Some(a), Some(b)) => Ok(a.total_cmp(&b)),
+            _ => bail!(
+                "cannot compare a {} with a {}",
+                a.type_name(),
+                b.type_name()
+            ),
+        },
+    }
+}
+
+fn contains(container: &Val, item: &Val) -> anyhow::Result<bool> {
+    Ok(match (container, item) {
+        (Val::Str(text), Val::Str(part)) => text.contains(&**part),
+        (Val::List(items), _) => items.iter().any(|found| equal(found, item)),
+        (Val::Map(_) | Val::Namespace(_), Val::Str(key)) => {
+            !container.attribute(key).is_undefined()
+        }
+        (Val::Map(_), _) | (Val::Undefined, _) | (Val::None, _) => false,
+        _ => bail!(
+            "cannot look for a {} in a {}",
+            item.type_name(),
+            container.type_name()
+        ),
+    })
+}
+
+fn arithmetic(op: &str, a: &Val, b: &Val) -> anyhow::Result<Val> {
+    if let (Val::Int(x), Val::Int(y)) = (a, b) {
+        let (x, y) = (*x, *y);
+        let overflow = || anyhow!("{} {} {} overflows", x, op, y);
+        return Ok(match op {
+            "+" => Val::Int(x.checked_add(y).ok_or_else(overflow)?),
+            "-" => Val::Int(x.checked_sub(y).ok_or_else(overflow)?),
+            "*" => Val::Int(x.checked_mul(y).ok_or_else(overflow)?),
+            "/" | "//" | "%" if y == 0 => bail!("division by zero"),
+            "/" => Val::Float(x as f64 / y as f64),
+            "//" => Val::Int(x.div_euclid(y) - i64::from(y < 0 && x.rem_euclid(y) != 0)),
+            // the sign of the divisor, as in Python
+            "%" => Val::Int(((x % y) + y) % y),
+            _ => match u32::try_from(y) {
+                Ok(y) => Val::Int(x.checked_pow(y).ok_or_else(overflow)?),
+                Err(_) => Val::Float((x as f64).powf(y as f64)),
+            },
+        });
+    }
+    match (op, a, b) {
+        ("+", Val::Str(x), Val::Str(y)) => return Ok(Val::str(&format!("{}{}", x, y))),
+        ("+", Val::List(x), Val::List(y)) => {
+            return Ok(Val::list(x.iter().chain(y.iter()).cloned().collect()))
+        }
+        ("*", Val::Str(text), Val::Int(times)) | ("*", Val::Int(times), Val::Str(text)) => {
+            let times = (*times).max(0) as usize;
+            if text.len().saturating_mul(times) > MAX_OUTPUT {
+                bail!("the template renders more than {} bytes", MAX_OUTPUT);
+            }
+            return Ok(Val::str(&text.repeat(times)));
+        }
+        ("%", Val::Str(format), values) => return Ok(Val::str(&percent_format(format, values)?)),
+        _ => {}
+    }
+    let (Some(x), Some(y)) = (a.number(), b.number()) else {
+        bail!(
+            "unsupported operands for {}: {} and {}",
+            op,
+            a.type_name(),
+            b.type_name()
+        );
+    };
+    Ok(Val::Float(match op {
+        "+" => x + y,
+        "-" => x - y,
+        "*" => x * y,
+        "/" | "//" | "%" if y == 0.0 => bail!("division by zero"),
+        "/" => x / y,
+        "//" => (x / y).floor(),
+        "%" => x - y * (x / y).floor(),
+        _ => x.powf(y),
+    }))
+}
+
+// Python's `"%s: %d" % (a, b)`, with the conversions templates use
+fn percent_format(format: &str, values: &Val) -> anyhow::Result<String> {
+    let values = match values {
+        Val::List(values) => values.to_vec(),
+        value => vec![value.clone()],
+    };
+    let mut values = values.iter();
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut precision = String::new();
+        if chars.next_if_eq(&'.').is_some() {
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                precision.push(digit);
+            }
+        }
+        let conversion = chars.next().ok_or(anyhow!("incomplete format"))?;
+        if conversion == '%' {
+            out.push('%');
+            continue;
+        }
+        let value = values
+            .next()
+            .ok_or(anyhow!("not enough arguments for format string"))?;
+        match conversion {
+            's' => out.push_str(&value.display()),
+            'r' => out.push_str(&value.repr()),
+            'd' | 'i' => match value.number() {
+                Some(number) => out.push_str(&(number.trunc() as i64).to_string()),
+                None => bail!("%{} format: a number is required", conversion),
+            },
+            'f' => match value.number() {
+                Some(number) => {
+                    out.push_str(&format!("{:.*}", precision.parse().unwrap_or(6), number))
+                }
+                None => bail!("%f format: a number is required"),
+            },
+            _ => bail!("unsupported format character '{}'", conversion),
+        }
+    }
+    Ok(out)
+}
+
+// ---- rendering
+
+enum Flow {
+    Next,
+    Break,
+    Continue,
+}
+
+struct Renderer {
+    // the variables of the template, then those of each loop and macro call within it
+    scopes: Vec<Fields>,
+    depth: usize,
+}
+
+impl Renderer {
+    fn lookup(&self, name: &str) -> Val {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().rev().find(|(key, _)| key == name))
+            .map_or(Val::Undefined, |(_, value)| value.clone())
+    }
+
+    fn assign(&mut self, name: &str, value: Val) {
+        let scope = self.scopes.last_mut().expect("the template's scope");
+        match scope.iter_mut().find(|(key, _)| key == name) {
+            Some((_, slot)) => *slot = value,
+            None => scope.push((name.to_string(), value)),
+        }
+    }
+
+    fn nodes(&mut self, nodes: &[Node], out: &mut String) -> anyhow::Result<Flow> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output(expr) => {
+                    let value = self.eval(expr)?;
+                    out.push_str(&value.display());
+                }
+                Node::If(branches, otherwise) => {
+                    let mut taken = None;
+                    for (condition, body) in branches {
+                        if self.eval(condition)?.truthy() {
+                            taken = Some(body);
+                            break;
+                        }
+                    }
+                    match self.nodes(taken.unwrap_or(otherwise), out)? {
+                        Flow::Next => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Node::For(for_loop) => self.for_loop(for_loop, out)?,
+                Node::Set(Target::Name(name), expr) => {
+                    let value = self.eval(expr)?;
+                    self.assign(name, value);
+                }
+                Node::Set(Target::Attribute(name, attribute), expr) => {
+                    let value = self.eval(expr)?;
+                    let Val::Namespace(fields) = self.lookup(name) else {
+                        bail!(
+                            "cannot set {}.{}, {} is no namespace",
+                            name,
+                            attribute,
+                            name
+                        );
+                    };
+                    let mut fields = fields.borrow_mut();
+                    match fields.iter_mut().find(|(key, _)| key == attribute) {
+                        Some((_, slot)) => *slot = value,
+                        None => fields.push((attribute.clone(), value)),
+                    }
+                }
+                Node::Capture(name, body) => {
+                    let mut captured = String::new();
+                    self.nodes(body, &mut captured)?;
+                    self.assign(name, Val::str(&captured));
+                }
+                Node::Macro(def) => self.assign(&def.name, Val::Macro(def.clone())),
+                Node::Break => return Ok(Flow::Break),
+                Node::Continue => return Ok(Flow::Continue),
+            }
+            if out.len() > MAX_OUTPUT {
+                bail!("the template renders more than {} bytes", MAX_OUTPUT);
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn for_loop(&mut self, for_loop: &ForLoop, out: &mut String) -> anyhow::Result<()> {
+        let mut items = self.eval(&for_loop.iterable)?.items()?;
+        self.scopes.push(Vec::new());
+        let rendered = (|| {
+            if let Some(filter) = &for_loop.filter {
+                let mut kept = Vec::new();
+                for item in items {
+                    self.bind(&for_loop.targets, &item)?;
+                    if self.eval(filter)?.truthy() {
+                        kept.push(item);
+                    }
+                }
+                items = kept;
+            }
+            if items.is_empty() {
+                self.nodes(&for_loop.empty, out)?;
+                return Ok(());
+            }
+            let length = items.len();
+            for (i, item) in items.iter().enumerate() {
+                self.scopes.last_mut().expect("the loop's scope").clear();
+                self.bind(&for_loop.targets, item)?;
+                let at = |index: Option<usize>| {
+                    index
+                        .and_then(|index| items.get(index))
+                        .cloned()
+                        .unwrap_or(Val::Undefined)
+                };
+                let fields = vec![
+                    ("index".to_string(), Val::Int(i as i64 + 1)),
+                    ("index0".to_string(), Val::Int(i as i64)),
+                    ("revindex".to_string(), Val::Int((length - i) as i64)),
+                    ("revindex0".to_string(), Val::Int((length - i - 1) as i64)),
+                    ("first".to_string(), Val::Bool(i == 0)),
+                    ("last".to_string(), Val::Bool(i + 1 == length)),
+                    ("length".to_string(), Val::Int(length as i64)),
+                    ("previtem".to_string(), at(i.checked_sub(1))),
+                    ("nextitem".to_string(), at(Some(i + 1))),
+                    ("depth".to_string(), Val::Int(1)),
+                    ("depth0".to_string(), Val::Int(0)),
+                ];
+                self.assign("loop", Val::Map(Rc::new(fields)));
+                if let Flow::Break = self.nodes(&for_loop.body, out)? {
+                    break;
+                }
+            }
+            Ok(())
+        })();
+        self.scopes.pop();
+        rendered
+    }
+
+    fn bind(&mut self, targets: &[String], item: &Val) -> anyhow::Result<()> {
+        if let [target] = targets {
+            self.assign(target, item.clone());
+            return Ok(());
+        }
+        let values = item.items()?;
+        if values.len() != targets.len() {
+            bail!(
+                "cannot unpack {} values into {}",
+                values.len(),
+                targets.join(", ")
+            );
+        }
+        for (target, value) in targets.iter().zip(values) {
+            self.assign(target, value);
+        }
+        Ok(())
+    }
+
+    fn eval(&mut self, expr: &Expr) -> anyhow::Result<Val> {
+        Ok(match expr {
+            Expr::Const(value) => match value {
+                Const::None => Val::None,
+                Const::Bool(value) => Val::Bool(*value),
+                Const::Int(number) => Val::Int(*number),
+                Const::Float(number) => Val::Float(*number),
+                Const::Str(text) => Val::str(text),
+            },
+            Expr::Name(name) => self.lookup(name),
+            Expr::List(items) => Val::list(
+                items
+                    .iter()
+                    .map(|item| self.eval(item))
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Expr::Dict(items) => {
+                let mut fields = Vec::new();
+                for (key, value) in items {
+                    let key = self.eval(key)?.display();
+                    let value = self.eval(value)?;
+                    fields.retain(|(found, _)| *found != key);
+                    fields.push((key, value));
+                }
+                Val::Map(Rc::new(fields))
+            }
+            Expr::Attribute(value, name) => self.eval(value)?.attribute(name),
+            Expr::Index(value, key) => {
+                let value = self.eval(value)?;
+                value.index(&self.eval(key)?)
+            }
+            Expr::Slice(value, [start, stop, step]) => {
+                let value = self.eval(value)?;
+                let mut bound = |part: &Option<Box<Expr>>| match part {
+                    Some(part) => match self.eval(part)? {
+                        Val::Int(number) => Ok(Some(number)),
+                        Val::None => Ok(None),
+                        other => bail!("slice indices must be integers, not {}", other.type_name()),
+                    },
+                    None => Ok(None),
+                };
+                let (start, stop, step) = (bound(start)?, bound(stop)?, bound(step)?);
+                slice(&value, start, stop, step)?
+            }
+            Expr::Call(callee, args) => self.call(callee, args)?,
+            Expr::Filter(value, name, args) => {
+                let value = self.eval(value)?;
+                let (positional, named) = self.args(args)?;
+                filter(name, value, &positional, &named)?
+            }
+            Expr::Test(value, name, args, negated) => {
+                let value = self.eval(value)?;
+                let (positional, _) = self.args(args)?;
+                Val::Bool(test(name, &value, &positional)? != *negated)
+            }
+            Expr::Not(value) => Val::Bool(!self.eval(value)?.truthy()),
+            Expr::Negate(value) => match self.eval(value)? {
+                Val::Int(number) => Val::Int(-number),
+                Val::Float(number) => Val::Float(-number),
+                other => bail!("cannot negate a {}", other.type_name()),
+            },
+            // the operand deciding, as in Python
+            Expr::And(a, b) => match self.eval(a)? {
+                a if !a.truthy() => a,
+                _ => self.eval(b)?,
+            },
+            Expr::Or(a, b) => match self.eval(a)? {
+                a if a.truthy() => a,
+                _ => self.eval(b)?,
+            },
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (self.eval(a)?, self.eval(b)?);
+                match *op {
+                    "==" => Val::Bool(equal(&a, &b)),
+                    "!=" => Val::Bool(!equal(&a, &b)),
+                    "<" => Val::Bool(compare_vals(&a, &b)?.is_lt()),
+                    ">" => Val::Bool(compare_vals(&a, &b)?.is_gt()),
+                    "<=" => Val::Bool(compare_vals(&a, &b)?.is_le()),
+                    ">=" => Val::Bool(compare_vals(&a, &b)?.is_ge()),
+                    "in" => Val::Bool(contains(&b, &a)?),
+                    "not in" => Val::Bool(!contains(&b, &a)?),
+                    "~" => Val::str(&format!("{}{}", a.display(), b.display())),
+                    op => arithmetic(op, &a, &b)?,
+                }
+            }
+            Expr::Conditional(then, condition, otherwise) => {
+                match (self.eval(condition)?.truthy(), otherwise) {
+                    (true, _) => self.eval(then)?,
+                    (false, Some(otherwise)) => self.eval(otherwise)?,
+                    (false, None) => Val::Undefined,
+                }
+            }
+        })
+    }
+
+    fn args(&mut self, args: &Args) -> anyhow::Result<(Vec<Val>, Fields)> {
+        let positional = args
+            .positional
+            .iter()
+            .map(|arg| self.eval(arg))
+            .collect::<anyhow::Result<_>>()?;
+        let named = args
+            .named
+            .iter()
+            .map(|(name, arg)| Ok((name.clone(), self.eval(arg)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok((positional, named))
+    }
+
+    fn call(&mut self, callee: &Expr, args: &Args) -> anyhow::Result<Val> {
+        let (positional, named) = self.args(args)?;
+        match callee {
+            Expr::Attribute(value, name) => {
+                let value = self.eval(value)?;
+                match value.attribute(name) {
+                    Val::Macro(def) => self.call_macro(&def, positional, named),
+                    _ => method(&value, name, &positional),
+                }
+            }
+            Expr::Name(name) => match self.lookup(name) {
+                Val::Macro(def) => self.call_macro(&def, positional, named),
+                Val::Undefined => function(name, &positional, named),
+                other => bail!("{} is a {}, not a function", name, other.type_name()),
+            },
+            other => match self.eval(other)? {
+                Val::Macro(def) => self.call_macro(&def, positional, named),
+                other => bail!("a {} is not a function", other.type_name()),
+            },
+        }
+    }
+
+    fn call_macro(
+        &mut self,
+        def: &Macro,
+        positional: Vec<Val>,
+        named: Fields,
+    ) -> anyhow::Result<Val> {
+        if self.depth >= MAX_DEPTH {
+            bail!("macros nest more than {} deep", MAX_DEPTH);
+        }
+        if positional.len() > def.params.len() {
+            bail!(
+                "{} takes {} arguments, {} given",
+                def.name,
+                def.params.len(),
+                positional.len()
+            );
+        }
+        let mut scope = Vec::new();
+        let mut positional = positional.into_iter();
+        for (param, default) in &def.params {
+            let value = match positional.next() {
+                Some(value) => value,
+                None => match named.iter().find(|(name, _)| name == param) {
+                    Some((_, value)) => value.clone(),
+                    None => match default {
+                        Some(default) => self.eval(default)?,
+                        None => Val::Undefined,
+                    },
+                },
+            };
+            scope.push((param.clone(), value));
+        }
+        self.scopes.push(scope);
+        self.depth += 1;
+        let mut out = String::new();
+        let rendered = self.nodes(&def.body, &mut out);
+        self.depth -= 1;
+        self.scopes.pop();
+        rendered?;
+
+        Ok(Val::str(&out))
+    }
+}
+
+fn slice(
+    value: &Val,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: Option<i64>,
+) -> anyhow::Result<Val> {
+    let step = step.unwrap_or(1);
+    if step == 0 {
+        bail!("slice step cannot be zero");
+    }
+    let pick = |len: usize| -> Vec<usize> {
+        let len = len as i64;
+        let clamp = |index: i64, low: i64, high: i64| {
+            let index = if index < 0 { index + len } else { index };
+            index.clamp(low, high)
+        };
+        let mut picked = Vec::new();
+        if step > 0 {
+            let mut i = start.map_or(0, |start| clamp(start, 0, len));
+            let stop = stop.map_or(len, |stop| clamp(stop, 0, len));
+            while i < stop {
+                picked.push(i as usize);
+                match i.checked_add(step) {
+                    Some(next) => i = next,
+                    None => break,
+                }
+            }
+        } else {
+            let mut i = start.map_or(len - 1, |start| clamp(start, -1, len - 1));
+            let stop = stop.map_or(-1, |stop| clamp(stop, -1, len - 1));
+            while i > stop {
+                picked.push(i as usize);
+                match i.checked_add(step) {
+                    Some(next) => i = next,
+                    None => break,
+                }
+            }
+        }
+        picked
+    };
+    Ok(match value {
+        Val::List(items) => Val::list(
+            pick(items.len())
+                .into_iter()
+                .map(|i| items[i].clone())
+                .collect(),
+        ),
+        Val::Str(text) => {
+            let chars = text.chars().collect::<Vec<_>>();
+            Val::str(
+                &pick(chars.len())
+                    .into_iter()
+                    .map(|i| chars[i])
+                    .collect::<String>(),
+            )
+        }
+        Val::Undefined | Val::None => Val::Undefined,
+        other => bail!("cannot slice a {}", other.type_name()),
+    })
+}
+
+fn text_arg(args: &[Val], at: usize) -> Option<String> {
+    match args.get(at) {
+        Some(Val::Str(text)) => Some(text.to_string()),
+        _ => None,
+    }
+}
+
+fn named_arg<'a>(named: &'a [(String, Val)], name: &str) -> Option<&'a Val> {
+    named
+        .iter()
+        .find(|(found, _)| found == name)
+        .map(|(_, value)| value)
+}
+
+// The functions templates call, besides their macros
+fn function(name: &str, args: &[Val], named: Fields) -> anyhow::Result<Val> {
+    Ok(match name {
+        "raise_exception" => bail!(
+            "the template raised: {}",
+            args.first().map(Val::display).unwrap_or_default()
+        ),
+        "namespace" => {
+            let mut fields = match args.first() {
+                Some(Val::Map(fields)) => fields.to_vec(),
+                _ => Vec::new(),
+            };
+            fields.extend(named);
+            Val::Namespace(Rc::new(RefCell::new(fields)))
+        }
+        "dict" => {
+            let mut fields = match args.first() {
+                Some(Val::Map(fields)) => fields.to_vec(),
+                _ => Vec::new(),
+            };
+            fields.extend(named);
+            Val::Map(Rc::new(fields))
+        }
+        "range" => {
+            let numbers = args
+                .iter()
+                .map(|arg| match arg {
+                    Val::Int(number) => Ok(*number),
+                    other => bail!("range takes integers, not {}", other.type_name()),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let (start, stop, step) = match numbers[..] {
+                [stop] => (0, stop, 1),
+                [start, stop] => (start, stop, 1),
+                [start, stop, step] if step != 0 => (start, stop, step),
+                _ => bail!("range takes 1 to 3 integers, the step not 0"),
+            };
+            let mut items = Vec::new();
+            let mut i = start;
+            while (step > 0 && i < stop) || (step < 0 && i > stop) {
+                items.push(Val::Int(i));
+                if items.len() > MAX_OUTPUT {
+                    bail!("range is too long");
+                }
+                match i.checked_add(step) {
+                    Some(next) => i = next,
+                    None => break,
+                }
+            }
+            Val::list(items)
+        }
+        // the date templates tell the model, in UTC
+        "strftime_now" => {
+            let format = text_arg(args, 0).unwrap_or_default();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs());
+            Val::str(&strftime(&Utc::of(now), &format))
+        }
+        _ => bail!("unknown function '{}'", name),
+    })
+}
+
+fn strftime(time: &Utc, format: &str) -> String {
+    const DAYS: [&str; 7] = [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ];
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+    let day = DAYS[time.weekday as usize];
+    let month = MONTHS[(time.month - 1) as usize];
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('d') => out.push_str(&format!("{:02}", time.day)),
+            Some('-') if chars.clone().next() == Some('d') => {
+                chars.next();
+                out.push_str(&time.day.to_string());
+            }
+            Some('m') => out.push_str(&format!("{:02}", time.month)),
+            Some('Y') => out.push_str(&time.year.to_string()),
+            Some('y') => out.push_str(&format!("{:02}", time.year % 100)),
+            Some('H') => out.push_str(&format!("{:02}", time.hour)),
+            Some('M') => out.push_str(&format!("{:02}", time.minute)),
+            Some('S') => out.push_str(&format!("{:02}", time.second)),
+            Some('b') => out.push_str(&month[..3]),
+            Some('B') => out.push_str(month),
+            Some('a') => out.push_str(&day[..3]),
+            Some('A') => out.push_str(day),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+// The methods of Python's strings and dicts that templates call
+fn method(value: &Val, name: &str, args: &[Val]) -> anyhow::Result<Val> {
+    let unknown = || anyhow!("a {} has no method '{}'", value.type_name(), name);
+    if let Val::Map(fields) = value {
+        return Ok(match name {
+            "items" => Val::list(
+                fields
+                    .iter()
+                    .map(|(key, value)| Val::list(vec![Val::str(key), value.clone()]))
+                    .collect(),
+            ),
+            "keys" => Val::list(fields.iter().map(|(key, _)| Val::str(key)).collect()),
+            "values" => Val::list(fields.iter().map(|(_, value)| value.clone()).collect()),
+            "get" => match value.index(args.first().unwrap_or(&Val::Undefined)) {
+                Val::Undefined => args.get(1).cloned().unwrap_or(Val::None),
+                found => found,
+            },
+            _ => return Err(unknown()),
+        });
+    }
+    let Val::Str(text) = value else {
+        return Err(unknown());
+    };
+    let chars = text_arg(args, 0);
+    let stripped = |c: char| match &chars {
+        Some(chars) => chars.contains(c),
+        None => c.is_whitespace(),
+    };
+    let affixes = || match args.first() {
+        Some(Val::Str(affix)) => vec![affix.to_string()],
+        Some(Val::List(affixes)) => affixes.iter().map(Val::display).collect(),
+        _ => Vec::new(),
+    };
+    Ok(match name {
+        "strip" => Val::str(text.trim_matches(stripped)),
+        "lstrip" => Val::str(text.trim_start_matches(stripped)),
+        "rstrip" => Val::str(text.trim_end_matches(stripped)),
+        "upper" => Val::str(&text.to_uppercase()),
+        "lower" => Val::str(&text.to_lowercase()),
+        "title" => Val::str(&title(text)),
+        "capitalize" => Val::str(&capitalize(text)),
+        "startswith" => Val::Bool(
+            affixes()
+                .iter()
+                .any(|affix| text.starts_with(affix.as_str())),
+        ),
+        "endswith" => Val::Bool(affixes().iter().any(|affix| text.ends_with(affix.as_str()))),
+        "replace" => {
+            let (from, to) = (
+                text_arg(args, 0).unwrap_or_default(),
+                text_arg(args, 1).unwrap_or_default(),
+            );
+            match args.get(2) {
+                Some(Val::Int(count)) if *count >= 0 => {
+                    Val::str(&text.replacen(&from, &to, *count as usize))
+                }
+                _ => Val::str(&text.replace(&from, &to)),
+            }
+        }
+        "split" | "rsplit" => {
+            let limit = match args.get(1) {
+                Some(Val::Int(limit)) if *limit >= 0 => Some(*limit as usize + 1),
+                _ => None,
+            };
+            let parts: Vec<String> = match (chars.as_deref(), limit, name) {
+                (None | Some(""), _, _) => {
+                    // runs of spaces, none empty
+                    let words = text.split_whitespace().collect::<Vec<_>>();
+                    match limit {
+                        Some(limit) if words.len() > limit && name == "split" => {
+                            let mut rest = text.trim_start();
+                            let mut parts = Vec::new();
+                            for _ in 1..limit {
+                                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                                parts.push(rest[..end].to_string());
+                                rest = rest[end..].trim_start();
+                            }
+                            parts.push(rest.to_string());
+                            parts
+                        }
+                        _ => words.into_iter().map(String::from).collect(),
+                    }
+                }
+                (Some(separator), Some(limit), "rsplit") => {
+                    let mut parts = text
+                        .rsplitn(limit, separator)
+                        .map(String::from)
+                        .collect::<Vec<_>>();
+                    parts.reverse();
+                    parts
+                }
+                (Some(separator), Some(limit), _) => {
+                    text.splitn(limit, separator).map(String::from).collect()
+                }
+                (Some(separator), None, _) => text.split(separator).map(String::from).collect(),
+            };
+            Val::list(parts.iter().map(|part| Val::str(part)).collect())
+        }
+        "splitlines" => Val::list(text.lines().map(Val::str).collect()),
+        "find" => Val::Int(
+            text.find(&text_arg(args, 0).unwrap_or_default())
+                .map_or(-1, |at| text[..at].chars().count() as i64),
+        ),
+        "count" => Val::Int(text.matches(&text_arg(args, 0).unwrap_or_default()).count() as i64),
+        "join" => Val::str(
+            &args
+                .first()
+                .map(Val::items)
+                .transpose()?
+                .unwrap_or_default()
+                .iter()
+                .map(Val::display)
+                .collect::<Vec<_>>()
+                .join(text),
+        ),
+        "isdigit" => Val::Bool(!text.is_empty() && text.chars().all(|c| c.is_ascii_digit())),
+        "isspace" => Val::Bool(!text.is_empty() && text.chars().all(char::is_whitespace)),
+        _ => return Err(unknown()),
+    })
+}
+
+fn title(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_word = false;
+    for c in text.chars() {
+        match in_word {
+            true => out.extend(c.to_lowercase()),
+            false => out.extend(c.to_uppercase()),
+        }
+        in_word = c.is_alphanumeric();
+    }
+    out
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+fn filter(name: &str, value: Val, args: &[Val], named: &[(String, Val)]) -> anyhow::Result<Val> {
+    let arg = |at: usize, name: &str| named_arg(named, name).or(args.get(at)).cloned();
+    // `attribute` of each item, dotted for nested ones
+    let attribute = |item: &Val, path: &str| {
+        path.split('.')
+            .fold(item.clone(), |value, key| match key.parse::<i64>() {
+                Ok(index) => value.index(&Val::Int(index)),
+                Err(_) => value.attribute(key),
+            })
+    };
+    Ok(match name {
+        "trim" => method(&Val::str(&value.display()), "strip", args)?,
+        "length" | "count" => Val::Int(value.len()? as i64),
+        "upper" | "lower" | "title" | "capitalize" => {
+            method(&Val::str(&value.display()), name, &[])?
+        }
+        "default" | "d" => {
+            let fallback = arg(0, "default_value").unwrap_or(Val::str(""));
+            let falsy = arg(1, "boolean").is_some_and(|boolean| boolean.truthy());
+            match value.is_undefined() || (falsy && !value.truthy()) {
+                true => fallback,
+                false => value,
+            }
+        }
+        "first" => value.items()?.into_iter().next().unwrap_or(Val::Undefined),
+        "last" => value.items()?.into_iter().last().unwrap_or(Val::Undefined),
+        "list" => Val::list(value.items()?),
+        "string" => Val::str(&value.display()),
+        "safe" => value,
+        "escape" | "e" => Val::str(
+            &value
+                .display()
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&#34;")
+                .replace('\'', "&#39;"),
+        ),
+        "join" => {
+            let separator = arg(0, "d").map(|d| d.display()).unwrap_or_default();
+            let items = value.items()?;
+            let items = match arg(1, "attribute") {
+                Some(path) => items
+                    .iter()
+                    .map(|item| attribute(item, &path.display()))
+                    .collect(),
+                None => items,
+            };
+            Val::str(
+                &items
+                    .iter()
+                    .map(Val::display)
+                    .collect::<Vec<_>>()
+                    .join(&separator),
+            )
+        }
+        "int" => match &value {
+            Val::Int(_) => value,
+            Val::Float(number) => Val::Int(number.trunc() as i64),
+            Val::Bool(flag) => Val::Int(i64::from(*flag)),
+            other => Val::Int(other.display().trim().parse().unwrap_or_else(|_| {
+                arg(0, "default").and_then(|d| d.number()).unwrap_or(0.0) as i64
+            })),
+        },
+        "float" => Val::Float(match &value {
+            Val::Str(text) => text.trim().parse().unwrap_or(0.0),
+            other => other.number().unwrap_or(0.0),
+        }),
+        "abs" => match value {
+            Val::Int(number) => Val::Int(number.abs()),
+            Val::Float(number) => Val::Float(number.abs()),
+            other => bail!("abs of a {}", other.type_name()),
+        },
+        "round" => {
+            let precision = arg(0, "precision").and_then(|p| p.number()).unwrap_or(0.0) as i32;
+            let scale = 10f64.powi(precision);
+            let number = value.number().unwrap_or(0.0) * scale;
+            let rounded = match arg(1, "method").map(|m| m.display()).as_deref() {
+                Some("floor") => number.floor(),
+                Some("ceil") => number.ceil(),
+                _ => number.round(),
+            };
+            Val::Float(rounded / scale)
+        }
+        "tojson" => {
+            let indent = arg(0, "indent").and_then(|indent| match indent {
+                Val::Int(indent) if indent >= 0 => Some(indent as usize),
+                _ => None,
+            });
+            let value = match named_arg(named, "sort_keys").is_some_and(|sort| sort.truthy()) {
+                true => sorted_keys(&value),
+                false => value,
+            };
+            let mut out = String::new();
+            value.json(indent, 0, &mut out);
+            Val::str(&out)
+        }
+        "replace" => method(&Val::str(&value.display()), "replace", args)?,
+        "format" => Val::str(&percent_format(
+            &value.display(),
+            &Val::list(args.to_vec()),
+        )?),
+        "indent" => {
+            let width = match arg(0, "width") {
+                Some(Val::Str(text)) => text.to_string(),
+                Some(width) => " ".repeat(width.number().unwrap_or(4.0) as usize),
+                None => " ".repeat(4),
+            };
+            let first = arg(1, "first").is_some_and(|first| first.truthy());
+            let blank = arg(2, "blank").is_some_and(|blank| blank.truthy());
+            let text = value.display();
+            let mut out = String::new();
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                }
+                if (i > 0 || first) && (blank || !line.trim().is_empty()) {
+                    out.push_str(&width);
+                }
+                out.push_str(line);
+            }
+            Val::str(&out)
+        }
+        "items" => method(&value, "items", &[])?,
+        "dictsort" => {
+            let Val::List(items) = method(&value, "items", &[])? else {
+                unreachable!()
+            };
+            let mut items = items.to_vec();
+            items.sort_by(|a, b| {
+                compare_vals(&a.index(&Val::Int(0)), &b.index(&Val::Int(0)))
+                    .unwrap_or(Ordering::Equal)
+            });
+            Val::list(items)
+        }
+        "reverse" => match value {
+            Val::Str(text) => Val::str(&text.chars().rev().collect::<String>()),
+            other => Val::list(other.items()?.into_iter().rev().collect()),
+        },
+        "unique" => {
+            let mut unique: Vec<Val> = Vec::new();
+            for item in value.items()? {
+                if !unique.iter().any(|found| equal(found, &item)) {
+                    unique.push(item);
+                }
+            }
+            Val::list(unique)
+        }
+        "sort" => {
+            let mut items = value.items()?;
+            let key = arg(2, "attribute").map(|path| path.display());
+            let keyed = |item: &Val| match &key {
+                Some(path) => attribute(item, path),
+                None => item.clone(),
+            };
+            let mut failed = None;
+            items.sort_by(|a, b| {
+                compare_vals(&keyed(a), &keyed(b)).unwrap_or_else(|e| {
+                    failed = Some(e);
+                    Ordering::Equal
+                })
+            });
+            if let Some(e) = failed {
+                return Err(e);
+            }
+            if arg(0, "reverse").is_some_and(|reverse| reverse.truthy()) {
+                items.reverse();
+            }
+            Val::list(items)
+        }
+        "sum" => {
+            let mut total = arg(1, "start").unwrap_or(Val::Int(0));
+            for item in value.items()? {
+                let item = match arg(0, "attribute") {
+                    Some(path) => attribute(&item, &path.display()),
+                    None => item,
+                };
+                total = arithmetic("+", &total, &item)?;
+            }
+            total
+        }
+        "min" | "max" => {
+            let mut best: Option<Val> = None;
+            for item in value.items()? {
+                best = Some(match best {
+                    Some(found) => {
+                        let ordering = compare_vals(&item, &found)?;
+                        match (name, ordering) {
+                            ("min", Ordering::Less) | ("max", Ordering::Greater) => item,
+                            _ => found,
+                        }
+                    }
+                    None => item,
+                });
+            }
+            best.unwrap_or(Val::Undefined)
+        }
+        "map" => {
+            let items = value.items()?;
+            Val::list(match (named_arg(named, "attribute"), args.split_first()) {
+                (Some(path), _) => {
+                    let default = named_arg(named, "default");
+                    items
+                        .iter()
+                        .map(|item| match attribute(item, &path.display()) {
+                            Val::Undefined => default.cloned().unwrap_or(Val::Undefined),
+                            found => found,
+                        })
+                        .collect()
+                }
+                (None, Some((Val::Str(filter_name), rest))) => items
+                    .into_iter()
+                    .map(|item| filter(filter_name, item, rest, &[]))
+                    .collect::<anyhow::Result<_>>()?,
+                _ => bail!("map needs a filter or an attribute"),
+            })
+        }
+        "select" | "reject" | "selectattr" | "rejectattr" => {
+            let by_attribute = name.ends_with("attr");
+            let keep = name.starts_with("select");
+            let (path, rest) = match by_attribute {
+                true => match args.split_first() {
+                    Some((path, rest)) => (Some(path.display()), rest),
+                    None => bail!("{} needs an attribute", name),
+                },
+                false => (None, args),
+            };
+            let mut kept = Vec::new();
+            for item in value.items()? {
+                let tested = match &path {
+                    Some(path) => attribute(&item, path),
+                    None => item.clone(),
+                };
+                let passed = match rest.split_first() {
+                    Some((test_name, test_args)) => test(&test_name.display(), &tested, test_args)?,
+                    None => tested.truthy(),
+                };
+                if passed == keep {
+                    kept.push(item);
+                }
+            }
+            Val::list(kept)
+        }
+        "attr" => value.attribute(&arg(0, "name").map(|n| n.display()).unwrap_or_default()),
+        _ => bail!("unknown filter '{}'", name),
+    })
+}
+
+fn sorted_keys(value: &Val) -> Val {
+    match value {
+        Val::Map(fields) => {
+            let mut fields = fields
+                .iter()
+                .map(|(key, value)| (key.clone(), sorted_keys(value)))
+                .collect::<Vec<_>>();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            Val::Map(Rc::new(fields))
+        }
+        Val::List(items) => Val::list(items.iter().map(sorted_keys).collect()),
+        other => other.clone(),
+    }
+}
+
+fn test(name: &str, value: &Val, args: &[Val]) -> anyhow::Result<bool> {
+    let other = || args.first().cloned().unwrap_or(Val::Undefined);
+    Ok(match name {
+        "defined" => !value.is_undefined(),
+        "undefined" => value.is_undefined(),
+        "none" => matches!(value, Val::None),
+        "boolean" => matches!(value, Val::Bool(_)),
+        "true" => matches!(value, Val::Bool(true)),
+        "false" => matches!(value, Val::Bool(false)),
+        "string" => matches!(value, Val::Str(_)),
+        "number" => matches!(value, Val::Int(_) | Val::Float(_)),
+        "integer" => matches!(value, Val::Int(_)),
+        "float" => matches!(value, Val::Float(_)),
+        "mapping" => matches!(value, Val::Map(_) | Val::Namespace(_)),
+        "sequence" | "iterable" => matches!(value, Val::List(_) | Val::Str(_) | Val::Map(_)),
+        "callable" => matches!(value, Val::Macro(_)),
+        "odd" | "even" => match value {
+            Val::Int(number) => (number % 2 != 0) == (name == "odd"),
+            other => bail!("{} of a {}", name, other.type_name()),
+        },
+        "divisibleby" => match (value, other()) {
+            (Val::Int(number), Val::Int(by)) if by != 0 => number % by == 0,
+            _ => bail!("divisibleby takes integers, the divisor not 0"),
+        },
+        "eq" | "equalto" | "==" | "sameas" => equal(value, &other()),
+        "ne" | "!=" => !equal(value, &other()),
+        "lt" | "lessthan" | "<" => compare_vals(value, &other())?.is_lt(),
+        "le" | "<=" => compare_vals(value, &other())?.is_le(),
+        "gt" | "greaterthan" | ">" => compare_vals(value, &other())?.is_gt(),
+        "ge" | ">=" => compare_vals(value, &other())?.is_ge(),
+        "in" => contains(&other(), value)?,
+        "lower" => matches!(value, Val::Str(text) if text.to_lowercase() == **text),
+        "upper" => matches!(value, Val::Str(text) if text.to_uppercase() == **text),
+        _ => bail!("unknown test '{}'", name),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, variables: Value) -> anyhow::Result<String> {
+        Template::parse(source)?.render(&variables)
+    }
+
+    fn conversation() -> Value {
+        json!([
+            { "role": "user", "content": "What is the capital of France?" },
+            { "role": "assistant", "content": " The capital of France is Paris. " },
+            { "role": "user", "content": "And of Germany?" },
+        ])
+    }
+
+    #[test]
+    fn renders_llama_3() {
+        let mut messages = conversation();
+        messages
+            .as_array_mut()
+            .unwrap()
+            .insert(0, json!({ "role": "system", "content": "Be brief." }));
+        let prompt = render(
+            include_str!("../fixtures/chat_templates/llama-3-chat.jinja"),
+            json!({
+                "messages": messages,
+                "bos_token": "<|begin_of_text|>",
+                "add_generation_prompt": true,
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nWhat is the capital of France?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nThe capital of France is Paris.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nAnd of Germany?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn renders_mistral_and_its_exceptions() {
+        let source = include_str!("../fixtures/chat_templates/mistral-instruct.jinja");
+        let tokens = |messages: Value| json!({ "messages": messages, "bos_token": "<s>", "eos_token": "</s>" });
+        assert_eq!(
+            render(source, tokens(conversation())).unwrap(),
+            "<s>[INST] What is the capital of France? [/INST] The capital of France is Paris. </s>\
+             [INST] And of Germany? [/INST]"
+        );
+
+        let system = json!([{ "role": "system", "content": "Be brief." }]);
+        let e = render(source, tokens(system)).unwrap_err();
+        assert!(
+            e.to_string().contains("Conversation roles must alternate"),
+            "{}",
+            e
+        );
+        let repeated = json!([
+            { "role": "user", "content": "Hi" },
+            { "role": "user", "content": "Hi again" },
+        ]);
+        let e = render(source, tokens(repeated)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the template raised: Conversation roles must alternate user/assistant/user/assistant/..."
+        );
+        let tool = json!([
+            { "role": "user", "content": "Hi" },
+            { "role": "tool", "content": "42" },
+        ]);
+        let e = render(source, tokens(tool)).unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("Only user and assistant roles are supported!"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn renders_qwen_with_tools() {
+        let tools = json!([{
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "The weather in a city",
+                "parameters": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } },
+                    "required": ["city"],
+                },
+            },
+        }]);
+        let messages = json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "Weather in Paris?" },
+            {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [{
+                    "type": "function",
+                    "function": { "name": "get_weather", "arguments": { "city": "Paris" } },
+                }],
+            },
+            { "role": "tool", "content": "sunny, 21°C" },
+        ]);
+        let prompt = render(
+            include_str!("../fixtures/chat_templates/chatml.jinja"),
+            json!({ "messages": messages, "tools": tools, "add_generation_prompt": true }),
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nBe brief.\n\n# Tools\n\nYou may call one or more functions to \
+             assist with the user query.\n\nYou are provided with function signatures within \
+             <tools></tools> XML tags:\n<tools>\n{\"type\": \"function\", \"function\": {\"name\": \
+             \"get_weather\", \"description\": \"The weather in a city\", \"parameters\": {\"type\": \
+             \"object\", \"properties\": {\"city\": {\"type\": \"string\"}}, \"required\": \
+             [\"city\"]}}}\n</tools>\n\nFor each function call, return a json object with function \
+             name and arguments within <tool_call></tool_call> XML tags:\n<tool_call>\n{\"name\": \
+             <function-name>, \"arguments\": <args-json-object>}\n</tool_call><|im_end|>\n\
+             <|im_start|>user\nWeather in Paris?<|im_end|>\n\
+             <|im_start|>assistant\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": \
+             {\"city\": \"Paris\"}}\n</tool_call><|im_end|>\n\
+             <|im_start|>user\n<tool_response>\nsunny, 21°C\n</tool_response><|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn renders_gemma_without_a_system_role() {
+        let source = include_str!("../fixtures/chat_templates/gemma-instruct.jinja");
+        let prompt = render(
+            source,
+            json!({ "messages": conversation(), "bos_token": "<bos>", "add_generation_prompt": true }),
+        )
+        .unwrap();
+        assert_eq!(
+            prompt,
+            "<bos><start_of_turn>user\nWhat is the capital of France?<end_of_turn>\n\
+             <start_of_turn>model\nThe capital of France is Paris.<end_of_turn>\n\
+             <start_of_turn>user\nAnd of Germany?<end_of_turn>\n\
+             <start_of_turn>model\n"
+        );
+
+        let system = json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "Hi" },
+        ]);
+        let e = render(source, json!({ "messages": system, "bos_token": "<bos>" })).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the template raised: System role not supported"
+        );
+    }
+
+    #[test]
+    fn bounds_string_repeats() {
+        assert_eq!(render("{{ 'ab' * 3 }}", json!({})).unwrap(), "ababab");
+        assert!(render("{{ 'ab' * 100000000 }}", json!({})).is_err());
+        assert!(render("{{ 'ab' * 9223372036854775807 }}", json!({})).is_err());
+    }
+
+    #[test]
+    fn slices_and_ranges_stop_at_the_largest_integer() {
+        let big = 9223372036854775807_i64;
+        assert_eq!(
+            render(&format!("{{{{ 'abc'[::{}] }}}}", big), json!({})).unwrap(),
+            "a"
+        );
+        assert_eq!(
+            render(&format!("{{{{ 'abc'[::-{}] }}}}", big), json!({})).unwrap(),
+            "c"
+        );
+        assert_eq!(
+            render(&format!("{{{{ 'abc'[1:{}] }}}}", big), json!({})).unwrap(),
+            "bc"
+        );
+        assert_eq!(
+            render(
+                &format!(
+                    "{{% for i in range({}, {}, 5) %}}{{{{ i }}}}{{% endfor %}}",
+                    big - 1,
+                    big
+                ),
+                json!({})
+            )
+            .unwrap(),
+            (big - 1).to_string()
+        );
+    }
+
+    #[test]
+    fn formats_with_percent_conversions() {
+        assert_eq!(
+            render(
+                "{{ '%s is %d, %.2f%%' | format(name, 3.7, 0.5) }}",
+                json!({ "name": "x" })
+            )
+            .unwrap(),
+            "x is 3, 0.50%"
+        );
+        assert_eq!(render("{{ '%s' % 'a' }}", json!({})).unwrap(), "a");
+        assert!(render("{{ '%s %s' | format('a') }}", json!({})).is_err());
+    }
+}
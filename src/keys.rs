@@ -0,0 +1,322 @@
+use crate::config;
+use crate::paths;
+use anyhow::bail;
+use ratatui::crossterm::event::{KeyCode as TermKey, KeyEvent as TermEvent, KeyModifiers};
+use rustyline::{KeyCode as LineKey, KeyEvent as LineEvent, Modifiers};
+use serde::{Deserialize, Deserializer};
+use std::str::FromStr;
+
+// Names of the file remapping the keys, in the config directory
+pub const FILES: [&str; 3] = ["keys.toml", "keys.yaml", "keys.yml"];
+
+// Keys of `gaia chat`, `gaia top` and `gaia tui`, from keys.toml in the config directory, e.g.
+//
+//   edit_mode = "vi"
+//   [chat]
+//   newline = ["alt+enter", "ctrl+j"]
+//   [tui]
+//   scroll_up = "ctrl+u"
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keys {
+    pub edit_mode: EditMode,
+    pub chat: ChatKeys,
+    pub tui: TuiKeys,
+}
+
+// How the chat input line is edited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatKeys {
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub submit: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub newline: Vec<Key>,
+    // stops the reply being generated, keeping what came so far
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub cancel: Vec<Key>,
+}
+impl Default for ChatKeys {
+    fn default() -> Self {
+        Self {
+            submit: keys(&["enter"]),
+            newline: keys(&["alt+enter"]),
+            cancel: keys(&["ctrl+c", "esc"]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuiKeys {
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub quit: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub up: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub down: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub scroll_up: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub scroll_down: Vec<Key>,
+    // back to the end of the log, following what is written to it
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub follow: Vec<Key>,
+    #[serde(deserialize_with = "deserialize_keys")]
+    pub next_tab: Vec<Key>,
+}
+impl Default for TuiKeys {
+    fn default() -> Self {
+        Self {
+            quit: keys(&["q", "esc"]),
+            up: keys(&["up", "k"]),
+            down: keys(&["down", "j"]),
+            scroll_up: keys(&["pageup"]),
+            scroll_down: keys(&["pagedown"]),
+            follow: keys(&["end"]),
+            next_tab: keys(&["tab"]),
+        }
+    }
+}
+impl TuiKeys {
+    // What the key pressed does, the keys bound to nothing are left to the screen
+    pub fn action(&self, event: &TermEvent) -> Option<Action> {
+        [
+            (&self.quit, Action::Quit),
+            (&self.up, Action::Up),
+            (&self.down, Action::Down),
+            (&self.scroll_up, Action::ScrollUp),
+            (&self.scroll_down, Action::ScrollDown),
+            (&self.follow, Action::Follow),
+            (&self.next_tab, Action::NextTab),
+        ]
+        .into_iter()
+        .find(|(keys, _)| keys.iter().any(|key| key.matches(event)))
+        .map(|(_, action)| action)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Up,
+    Down,
+    ScrollUp,
+    ScrollDown,
+    Follow,
+    NextTab,
+}
+
+// A key with its modifiers, written as e.g. `ctrl+j`, `alt+enter`, `pageup` or `q`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub name: Name,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Name {
+    Char(char),
+    Enter,
+    Esc,
+    Tab,
+    Backspace,
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    F(u8),
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let lower = text.trim().to_lowercase();
+        // `+` alone, or at the end as in `ctrl++`, is the key itself
+        let (modifiers, name) = match lower.strip_suffix("++") {
+            Some(modifiers) => (modifiers, "+"),
+            None => lower.rsplit_once('+').unwrap_or(("", &lower)),
+        };
+        let mut key = Key {
+            name: match name {
+                "enter" | "return" => Name::Enter,
+                "esc" | "escape" => Name::Esc,
+                "tab" => Name::Tab,
+                "backspace" => Name::Backspace,
+                "space" => Name::Char(' '),
+                "up" => Name::Up,
+                "down" => Name::Down,
+                "left" => Name::Left,
+                "right" => Name::Right,
+                "pageup" => Name::PageUp,
+                "pagedown" => Name::PageDown,
+                "home" => Name::Home,
+                "end" => Name::End,
+                name if name.chars().count() == 1 => Name::Char(name.chars().next().unwrap()),
+                name => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n @ 1..=12) => Name::F(n),
+                    _ => bail!("unknown key '{}' in '{}'", name, text),
+                },
+            },
+            ctrl: false,
+            alt: false,
+            shift: false,
+        };
+        for modifier in modifiers.split('+').filter(|modifier| !modifier.is_empty()) {
+            match modifier {
+                "ctrl" | "control" => key.ctrl = true,
+                "alt" | "meta" | "option" => key.alt = true,
+                "shift" => key.shift = true,
+                _ => bail!(
+                    "unknown modifier '{}' in '{}', use ctrl, alt or shift",
+                    modifier,
+                    text
+                ),
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (on, modifier) in [
+            (self.ctrl, "ctrl+"),
+            (self.alt, "alt+"),
+            (self.shift, "shift+"),
+        ] {
+            if on {
+                f.write_str(modifier)?;
+            }
+        }
+        match self.name {
+            Name::Char(' ') => f.write_str("space"),
+            Name::Char(c) => write!(f, "{}", c),
+            Name::F(n) => write!(f, "f{}", n),
+            name => f.write_str(&format!("{:?}", name).to_lowercase()),
+        }
+    }
+}
+
+impl Key {
+    // Whether the key pressed on a full screen is this one. Shift is part of the letter typed.
+    pub fn matches(&self, event: &TermEvent) -> bool {
+        let shifted = event.modifiers.contains(KeyModifiers::SHIFT);
+        let (name, shift) = match event.code {
+            TermKey::Char(c) => (Name::Char(c.to_ascii_lowercase()), c.is_ascii_uppercase()),
+            TermKey::Enter => (Name::Enter, shifted),
+            TermKey::Esc => (Name::Esc, shifted),
+            TermKey::Tab => (Name::Tab, shifted),
+            TermKey::BackTab => (Name::Tab, true),
+            TermKey::Backspace => (Name::Backspace, shifted),
+            TermKey::Up => (Name::Up, shifted),
+            TermKey::Down => (Name::Down, shifted),
+            TermKey::Left => (Name::Left, shifted),
+            TermKey::Right => (Name::Right, shifted),
+            TermKey::PageUp => (Name::PageUp, shifted),
+            TermKey::PageDown => (Name::PageDown, shifted),
+            TermKey::Home => (Name::Home, shifted),
+            TermKey::End => (Name::End, shifted),
+            TermKey::F(n) => (Name::F(n), shifted),
+            _ => return false,
+        };
+
+        name == self.name
+            && shift == self.shift
+            && self.ctrl == event.modifiers.contains(KeyModifiers::CONTROL)
+            && self.alt == event.modifiers.contains(KeyModifiers::ALT)
+    }
+
+    // The key as the chat input line sees it
+    pub fn line_event(&self) -> LineEvent {
+        let mut modifiers = Modifiers::NONE;
+        if self.ctrl {
+            modifiers |= Modifiers::CTRL;
+        }
+        if self.alt {
+            modifiers |= Modifiers::ALT;
+        }
+        if self.shift {
+            modifiers |= Modifiers::SHIFT;
+        }
+        let code = match self.name {
+            Name::Char(c) => return LineEvent::new(c, modifiers),
+            Name::Enter => LineKey::Enter,
+            Name::Esc => LineKey::Esc,
+            Name::Tab => LineKey::Tab,
+            Name::Backspace => LineKey::Backspace,
+            Name::Up => LineKey::Up,
+            Name::Down => LineKey::Down,
+            Name::Left => LineKey::Left,
+            Name::Right => LineKey::Right,
+            Name::PageUp => LineKey::PageUp,
+            Name::PageDown => LineKey::PageDown,
+            Name::Home => LineKey::Home,
+            Name::End => LineKey::End,
+            Name::F(n) => LineKey::F(n),
+        };
+        LineEvent(code, modifiers)
+    }
+}
+
+fn keys(names: &[&str]) -> Vec<Key> {
+    names
+        .iter()
+        .map(|name| name.parse().expect("default keys are valid"))
+        .collect()
+}
+
+// A key, or a list of keys doing the same
+fn deserialize_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Key>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    let names = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(name) => vec![name],
+        OneOrMany::Many(names) => names,
+    };
+    names
+        .iter()
+        .map(|name| name.parse::<Key>())
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(serde::de::Error::custom)
+}
+
+// The keys of keys.toml in the config directory, the defaults without one
+pub fn load() -> anyhow::Result<Keys> {
+    let dir = paths::config_dir()?;
+    match FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+    {
+        Some(path) => config::load(&path),
+        None => Ok(Keys::default()),
+    }
+}
+
+// The first of the keys, to tell in a hint
+pub fn first(keys: &[Key]) -> String {
+    keys.first()
+        .map(|key| key.to_string())
+        .unwrap_or("unbound".to_string())
+}
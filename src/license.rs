@@ -0,0 +1,215 @@
+use crate::error::{fail, ErrorKind};
+use crate::hf;
+use crate::models;
+use anyhow::anyhow;
+use clap::Args;
+use console::style;
+use serde_json::Value;
+use std::{
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+static ACCEPTED: OnceLock<bool> = OnceLock::new();
+
+// Licenses that put no restriction on using the model, as Hugging Face names them
+const PERMISSIVE: &[&str] = &[
+    "apache-2.0",
+    "mit",
+    "bsd",
+    "bsd-2-clause",
+    "bsd-3-clause",
+    "bsd-3-clause-clear",
+    "isc",
+    "zlib",
+    "unlicense",
+    "wtfpl",
+    "cc0-1.0",
+    "cc-by-2.0",
+    "cc-by-2.5",
+    "cc-by-3.0",
+    "cc-by-4.0",
+    "cc-by-sa-3.0",
+    "cc-by-sa-4.0",
+    "mpl-2.0",
+    "bsl-1.0",
+    "artistic-2.0",
+    "ecl-2.0",
+    "afl-3.0",
+    "postgresql",
+    "gpl-2.0",
+    "gpl-3.0",
+    "lgpl-2.1",
+    "lgpl-3.0",
+    "agpl-3.0",
+];
+// Lines of the LICENSE file shown before asking for acceptance
+const SUMMARY_LINES: usize = 15;
+
+#[derive(Debug, Clone, Args)]
+pub struct LicenseArgs {
+    #[arg(
+        long = "accept-license",
+        help = "Accept the license of models whose repository restricts their use, the acceptance is kept in the models manifest",
+        global = true
+    )]
+    pub accept_license: bool,
+}
+
+pub fn init(args: &LicenseArgs) {
+    let _ = ACCEPTED.set(args.accept_license);
+}
+
+// A restrictive license accepted for a download
+#[derive(Debug, Clone)]
+pub struct Acceptance {
+    pub license: String,
+    pub accepted: u64,
+}
+
+// The license of a Hugging Face repository: its id and the card's name and link for it
+struct License {
+    id: String,
+    name: Option<String>,
+    link: Option<String>,
+    gated: bool,
+}
+
+fn fetch(client: &reqwest::blocking::Client, repo: &str) -> anyhow::Result<Option<License>> {
+    let info: Value = client
+        .get(format!("https://huggingface.co/api/models/{}", repo))
+        .send()?
+        .error_for_status()?
+        .json()?;
+    let card = &info["cardData"];
+    // the card is authoritative, older repos only have the tag
+    let id = card["license"].as_str().map(String::from).or_else(|| {
+        info["tags"]
+            .as_array()?
+            .iter()
+            .find_map(|tag| tag.as_str()?.strip_prefix("license:").map(String::from))
+    });
+
+    Ok(id.map(|id| License {
+        id,
+        name: card["license_name"].as_str().map(String::from),
+        link: card["license_link"].as_str().map(String::from),
+        gated: info["gated"].as_str().is_some() || info["gated"] == true,
+    }))
+}
+
+// The first lines of the LICENSE file of the repository
+fn summary(client: &reqwest::blocking::Client, repo: &str) -> Option<String> {
+    ["LICENSE", "LICENSE.txt", "LICENSE.md"]
+        .iter()
+        .find_map(|file| {
+            client
+                .get(format!(
+                    "https://huggingface.co/{}/resolve/main/{}",
+                    repo, file
+                ))
+                .send()
+                .ok()?
+                .error_for_status()
+                .ok()?
+                .text()
+                .ok()
+        })
+        .map(|text| {
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .take(SUMMARY_LINES)
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+}
+
+// Whether a model of the repository was downloaded before under the license, with it accepted
+fn accepted_before(repo: &str, license: &str) -> bool {
+    models::manifest()
+        .unwrap_or_default()
+        .values()
+        .any(|entry| {
+            entry.license.as_deref() == Some(license)
+                && entry.license_accepted.is_some()
+                && hf::parse_url(&entry.url).is_some_and(|file| file.repo == repo)
+        })
+}
+
+// Before downloading from a Hugging Face repository with a restrictive license, show it and
+// refuse unless --accept-license is given. Returns the acceptance to record with the model.
+pub fn check(url: &str) -> anyhow::Result<Option<Acceptance>> {
+    let Some(file) = hf::parse_url(url) else {
+        return Ok(None);
+    };
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let license = match fetch(&client, &file.repo) {
+        Ok(Some(license)) => license,
+        Ok(None) => return Ok(None),
+        // the download tells whether the repository is reachable
+        Err(e) => {
+            tracing::warn!(repo = file.repo.as_str(), "cannot read the license: {}", e);
+            return Ok(None);
+        }
+    };
+    if PERMISSIVE.contains(&license.id.as_str()) {
+        return Ok(None);
+    }
+    let accepted = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    if accepted_before(&file.repo, &license.id) {
+        return Ok(Some(Acceptance {
+            license: license.id,
+            accepted,
+        }));
+    }
+
+    let name = match &license.name {
+        Some(name) => format!("{} ({})", license.id, name),
+        None => license.id.clone(),
+    };
+    eprintln!(
+        "{}",
+        style(format!(
+            "{} is released under the {} license",
+            file.repo, name
+        ))
+        .bold()
+    );
+    if license.gated {
+        eprintln!("The repository is gated, its terms must also be agreed to on Hugging Face");
+    }
+    if let Some(summary) = summary(&client, &file.repo) {
+        eprintln!("{}", style(summary).dim());
+    }
+    eprintln!(
+        "Read it in full at {}",
+        license.link.unwrap_or(format!(
+            "https://huggingface.co/{}/blob/main/LICENSE",
+            file.repo
+        ))
+    );
+    if ACCEPTED.get() != Some(&true) {
+        return Err(fail(
+            ErrorKind::Config,
+            anyhow!(
+                "The {} license restricts the use of {}, pass --accept-license to accept it",
+                license.id,
+                file.repo
+            ),
+        ));
+    }
+
+    Ok(Some(Acceptance {
+        license: license.id,
+        accepted,
+    }))
+}
+
+// Keep the acceptance beside the model in the manifest
+pub fn record(path: &Path, acceptance: &Acceptance) -> anyhow::Result<()> {
+    models::record_license(path, &acceptance.license, acceptance.accepted)
+}
@@ -0,0 +1,318 @@
+use crate::client::{ChatRequest, Client, ClientArgs, Message, SamplingArgs, Throughput};
+use crate::progress::Progress;
+use anyhow::anyhow;
+use clap::Args;
+use console::style;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Asked for when neither --prompt nor --prompt-file is given, long enough an answer to time
+const DEFAULT_PROMPT: &str =
+    "Explain in a few paragraphs how a hash map works and when to use one instead of a sorted tree.";
+// Tokens of each answer unless --max-tokens says otherwise, so that levels take similar work
+const DEFAULT_MAX_TOKENS: u64 = 128;
+
+#[derive(Debug, Clone, Args)]
+pub struct LoadtestArgs {
+    #[arg(
+        long = "concurrency",
+        help = "Requests in flight at once, one level after the other, e.g. 1,2,4,8",
+        value_name = "N,...",
+        value_delimiter = ',',
+        default_values_t = [1, 2, 4, 8],
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub concurrency: Vec<u32>,
+    #[arg(
+        short = 'n',
+        long = "requests",
+        help = "Requests sent at each level",
+        value_name = "N",
+        default_value_t = 32,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub requests: u32,
+    #[arg(
+        long = "prompt",
+        help = "Prompt of every request",
+        conflicts_with = "prompt_file"
+    )]
+    pub prompt: Option<String>,
+    #[arg(
+        short = 'f',
+        long = "prompt-file",
+        help = "File with the prompt of every request"
+    )]
+    pub prompt_file: Option<PathBuf>,
+    #[arg(
+        long = "no-stream",
+        help = "Wait for whole answers, the time to the first token is then not measured"
+    )]
+    pub no_stream: bool,
+    #[arg(
+        long = "warmup",
+        help = "Requests sent before the first level and not counted, loading the model and filling its caches",
+        value_name = "N",
+        default_value_t = 1
+    )]
+    pub warmup: u32,
+    #[arg(long = "json", help = "Print the results of the levels as JSON")]
+    pub json: bool,
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+    #[command(flatten)]
+    pub client: ClientArgs,
+}
+
+// What a request took, or why it failed
+struct Sample {
+    latency: Duration,
+    result: Result<Throughput, String>,
+}
+
+// The results of a concurrency level
+#[derive(Debug, Serialize)]
+struct Level {
+    concurrency: u32,
+    requests: u32,
+    errors: u32,
+    error_rate: f64,
+    // of the whole level, from the first request sent to the last answer
+    secs: f64,
+    requests_per_sec: f64,
+    // completion tokens of all the answers over the time of the level
+    tokens_per_sec: f64,
+    // seconds, of the requests that succeeded
+    latency: Option<Percentiles>,
+    first_token: Option<Percentiles>,
+    // the errors by message, the most common telling what gave way
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    error_kinds: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+impl Percentiles {
+    fn of(mut secs: Vec<f64>) -> Option<Self> {
+        if secs.is_empty() {
+            return None;
+        }
+        secs.sort_by(f64::total_cmp);
+        // the nearest rank
+        let at = |percent: f64| {
+            let rank = (percent / 100.0 * secs.len() as f64).ceil() as usize;
+            secs[rank.clamp(1, secs.len()) - 1]
+        };
+        Some(Self {
+            p50: at(50.0),
+            p90: at(90.0),
+            p99: at(99.0),
+            max: secs[secs.len() - 1],
+        })
+    }
+}
+
+// Send the requests of each level with as many in flight as it says, and report how the node
+// held up, to size the hardware before a rollout
+pub fn command_loadtest(args: LoadtestArgs) -> anyhow::Result<()> {
+    let prompt = match &args.prompt_file {
+        Some(path) => fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+        None => args
+            .prompt
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+    };
+    let request = ChatRequest {
+        model: args.client.model_name.clone(),
+        messages: vec![Message::new("user", prompt)],
+        stream: !args.no_stream,
+        sampling: SamplingArgs {
+            max_tokens: args.sampling.max_tokens.or(Some(DEFAULT_MAX_TOKENS)),
+            ..args.sampling.clone()
+        },
+        tools: None,
+        response_format: None,
+    };
+    let client = Client::new(&args.client.base_url)?;
+
+    if args.warmup > 0 {
+        if !args.json {
+            println!(
+                "{}",
+                style(format!("Warming up with {} requests", args.warmup)).dim()
+            );
+        }
+        for _ in 0..args.warmup {
+            // a node that cannot answer one request is not worth loading
+            send(&client, &request).result.map_err(|e| {
+                anyhow!(
+                    "The warm-up request to {} failed: {}",
+                    args.client.base_url,
+                    e
+                )
+            })?;
+        }
+    }
+
+    let mut levels = Vec::new();
+    if !args.json {
+        println!(
+            "{}",
+            style(format!(
+                "{:>5} {:>9} {:>7} {:>8} {:>9} {:>8} {:>8} {:>8} {:>8}",
+                "CONC", "REQUESTS", "ERRORS", "REQ/S", "TOKENS/S", "P50", "P90", "P99", "TTFT50"
+            ))
+            .bold()
+        );
+    }
+    for &concurrency in &args.concurrency {
+        let level = run_level(&client, &request, concurrency, args.requests);
+        if !args.json {
+            print_level(&level);
+        }
+        levels.push(level);
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&levels)?);
+        return Ok(());
+    }
+
+    for level in levels.iter().filter(|level| !level.error_kinds.is_empty()) {
+        println!(
+            "{}",
+            style(format!("Errors at concurrency {}:", level.concurrency)).red()
+        );
+        for (error, count) in &level.error_kinds {
+            println!("  {:>4}x {}", count, error);
+        }
+    }
+    // the level the node gets the most out of
+    if let Some(best) = levels
+        .iter()
+        .filter(|level| level.errors == 0)
+        .max_by(|a, b| a.tokens_per_sec.total_cmp(&b.tokens_per_sec))
+    {
+        println!(
+            "{}",
+            style(format!(
+                "Most tokens/s without errors at concurrency {}: {:.1}",
+                best.concurrency, best.tokens_per_sec
+            ))
+            .dim()
+        );
+    }
+
+    Ok(())
+}
+
+// Send the requests of a level, `concurrency` at a time
+fn run_level(client: &Client, request: &ChatRequest, concurrency: u32, requests: u32) -> Level {
+    let name = format!("concurrency {}", concurrency);
+    let progress = Mutex::new(Progress::new(
+        "loadtest",
+        &name,
+        "requests",
+        Some(requests as u64),
+    ));
+    let sent = AtomicU32::new(0);
+    let samples = Mutex::new(Vec::new());
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..concurrency.min(requests) {
+            scope.spawn(|| {
+                while sent.fetch_add(1, Ordering::Relaxed) < requests {
+                    let sample = send(client, request);
+                    samples.lock().unwrap().push(sample);
+                    progress.lock().unwrap().advance(1);
+                }
+            });
+        }
+    });
+    let secs = started.elapsed().as_secs_f64();
+    progress.into_inner().unwrap().finish();
+
+    let samples = samples.into_inner().unwrap();
+    let mut error_kinds = BTreeMap::new();
+    let mut latencies = Vec::new();
+    let mut first_tokens = Vec::new();
+    let mut tokens = 0;
+    for sample in &samples {
+        match &sample.result {
+            Ok(throughput) => {
+                latencies.push(sample.latency.as_secs_f64());
+                first_tokens.extend(throughput.first_token.map(|first| first.as_secs_f64()));
+                tokens += throughput.completion_tokens;
+            }
+            Err(e) => *error_kinds.entry(e.clone()).or_insert(0) += 1,
+        }
+    }
+    let errors = error_kinds.values().sum::<u32>();
+    let answered = samples.len() as u32 - errors;
+
+    Level {
+        concurrency,
+        requests,
+        errors,
+        error_rate: errors as f64 / requests as f64,
+        secs,
+        requests_per_sec: answered as f64 / secs,
+        tokens_per_sec: tokens as f64 / secs,
+        latency: Percentiles::of(latencies),
+        first_token: Percentiles::of(first_tokens),
+        error_kinds,
+    }
+}
+
+fn send(client: &Client, request: &ChatRequest) -> Sample {
+    let started = Instant::now();
+    let result = match request.stream {
+        true => client
+            .chat_stream(request, |_| ControlFlow::Continue(()))
+            .map(|(_, throughput)| throughput),
+        false => client.chat(request).map(|(_, throughput)| throughput),
+    };
+    Sample {
+        latency: started.elapsed(),
+        result: result.map_err(|e| format!("{:#}", e)),
+    }
+}
+
+fn print_level(level: &Level) {
+    let secs = |percentiles: &Option<Percentiles>, at: fn(&Percentiles) -> f64| {
+        percentiles.as_ref().map_or("-".to_string(), |percentiles| {
+            format!("{:.2}s", at(percentiles))
+        })
+    };
+    let line = format!(
+        "{:>5} {:>9} {:>7} {:>8.2} {:>9.1} {:>8} {:>8} {:>8} {:>8}",
+        level.concurrency,
+        level.requests,
+        level.errors,
+        level.requests_per_sec,
+        level.tokens_per_sec,
+        secs(&level.latency, |latency| latency.p50),
+        secs(&level.latency, |latency| latency.p90),
+        secs(&level.latency, |latency| latency.p99),
+        secs(&level.first_token, |first_token| first_token.p50),
+    );
+    match level.errors {
+        0 => println!("{}", line),
+        _ => println!("{}", style(line).red()),
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config;
+use crate::error::{fail, ErrorKind, Tag};
+use crate::hf;
+use crate::models;
+use crate::node::NodeConfig;
+use crate::ollama;
+use crate::paths;
+use crate::start;
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const LOCK_FILE: &str = "gaia.lock";
+const LOCK_VERSION: u32 = 1;
+
+// The exact files of the models of a node file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Lockfile {
+    version: u32,
+    #[serde(default, rename = "model")]
+    models: Vec<LockedModel>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedModel {
+    // url or path, as in the node file
+    pub url: String,
+    // Hugging Face repository of the url
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    pub file: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+// The lockfile beside the node file
+fn lock_path(file: &Path) -> PathBuf {
+    file.parent().unwrap_or(Path::new(".")).join(LOCK_FILE)
+}
+
+fn node_file(file: Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    match file {
+        Some(file) => Ok(file),
+        None => config::default_path(),
+    }
+}
+
+// Pull the models of the node file and record their digests in gaia.lock
+pub fn command_lock(file: Option<PathBuf>) -> anyhow::Result<()> {
+    let file = node_file(file)?;
+    let node = NodeConfig::load(&file).tag(ErrorKind::Config)?;
+
+    let mut models = Vec::new();
+    for url in node.models() {
+        let path = match start::is_remote(url) {
+            true => start::pull(url)?,
+            false => local_path(url)?,
+        };
+        models.push(LockedModel {
+            url: url.to_string(),
+            repo: hf::parse_url(url).map(|file| file.repo),
+            file: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .ok_or(anyhow!("{} is not a file", path.display()))?,
+            size: fs::metadata(&path)?.len(),
+            sha256: models::digest(&path)?,
+        });
+    }
+    let lock = Lockfile {
+        version: LOCK_VERSION,
+        models,
+    };
+    let path = lock_path(&file);
+    fs::write(
+        &path,
+        format!(
+            "# Written by `gaia lock`, `gaia pull --locked` fetches exactly these files\n{}",
+            toml::to_string_pretty(&lock)?
+        ),
+    )?;
+    println!("Locked {} models in {}", lock.models.len(), path.display());
+
+    Ok(())
+}
+
+// Pull the models of gaia.lock, or only the given one, refusing any that does not match it
+pub fn command_pull_locked(file: Option<PathBuf>, model: Option<String>) -> anyhow::Result<()> {
+    let path = lock_path(&node_file(file)?);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("{}: {}, write it with `gaia lock`", path.display(), e))
+        .tag(ErrorKind::Config)?;
+    let lock: Lockfile = config::parse(config::Format::Toml, &content)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))
+        .tag(ErrorKind::Config)?;
+    if lock.version > LOCK_VERSION {
+        bail!(
+            "{} is version {}, this gaia reads up to version {}",
+            path.display(),
+            lock.version,
+            LOCK_VERSION
+        );
+    }
+    let locked = match &model {
+        Some(model) => vec![lock
+            .models
+            .iter()
+            .find(|locked| &locked.url == model)
+            .cloned()
+            .ok_or(fail(
+                ErrorKind::Config,
+                anyhow!(
+                    "{} is not in {}, refusing to pull it",
+                    model,
+                    path.display()
+                ),
+            ))?],
+        None => lock.models,
+    };
+
+    for locked in locked {
+        let cached = paths::models_dir()?.join(&locked.file);
+        let path = match start::is_remote(&locked.url) {
+            // what was pulled before needs no download
+            true if matches(&cached, &locked)? => {
+                println!("{} is up to date", locked.file);
+                continue;
+            }
+            true => start::pull(&locked.url)?,
+            false => local_path(&locked.url)?,
+        };
+        if !matches(&path, &locked)? {
+            if start::is_remote(&locked.url) {
+                let _ = fs::remove_file(&path);
+            }
+            return Err(fail(
+                ErrorKind::Download,
+                anyhow!(
+                    "{} does not match {}: expected {} bytes with sha256 {}",
+                    locked.url,
+                    LOCK_FILE,
+                    locked.size,
+                    locked.sha256
+                ),
+            ));
+        }
+        println!("{} matches {}", locked.file, LOCK_FILE);
+    }
+
+    Ok(())
+}
+
+fn local_path(model: &str) -> anyhow::Result<PathBuf> {
+    match ollama::is_ollama_url(model) {
+        true => ollama::resolve(model),
+        false => Ok(PathBuf::from(model)),
+    }
+}
+
+// Whether the file has the size and digest of the lock
+fn matches(path: &Path, locked: &LockedModel) -> anyhow::Result<bool> {
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(false);
+    };
+    if metadata.len() != locked.size {
+        return Ok(false);
+    }
+
+    Ok(models::digest(path)? == locked.sha256)
+}
@@ -0,0 +1,320 @@
+use crate::crash;
+use crate::identity;
+use crate::telemetry;
+use anyhow::anyhow;
+use clap::{ArgAction, Args, ValueEnum};
+use console::style;
+use std::{env, io, sync::OnceLock};
+use tracing::{Level as Severity, Metadata};
+use tracing_subscriber::{
+    fmt::{
+        self,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        MakeWriter,
+    },
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+// Environment variable taking a filter such as `gaia=debug` or `gaia::rag=trace`, it wins over
+// -v and -q
+const LOG_ENV: &str = "GAIA_LOG";
+
+static LEVEL: OnceLock<Level> = OnceLock::new();
+// Where the log goes, once the logger runs
+static TARGET: OnceLock<LogTarget> = OnceLock::new();
+
+// What the node file's `log_level` may change once the logger runs
+struct Level {
+    // the filter of -v and -q, used when the node file sets none
+    default: String,
+    // -v, -q or GAIA_LOG were given, the node file does not override them
+    pinned: bool,
+    reload: Box<dyn Fn(EnvFilter) -> anyhow::Result<()> + Send + Sync>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct LogArgs {
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        help = "Log what gaia does to stderr, repeat for more detail",
+        action = ArgAction::Count,
+        global = true
+    )]
+    pub verbose: u8,
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        help = "Only log errors",
+        conflicts_with = "verbose",
+        global = true
+    )]
+    pub quiet: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LogTarget::Stderr,
+        env = "GAIA_LOG_TO",
+        help = "Where the log goes, syslog and journald keeping the priority of each line",
+        global = true
+    )]
+    pub log_to: LogTarget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTarget {
+    Stderr,
+    // the local syslog daemon, through /dev/log
+    Syslog,
+    // the systemd journal, with the node name in GAIA_NODE
+    Journald,
+}
+
+pub fn init(args: &LogArgs) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    // the libraries are only worth hearing from at the most verbose level
+    let default = match level {
+        "trace" => "trace".to_string(),
+        level => format!("warn,gaia={}", level),
+    };
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new(&default));
+
+    // the filter is the log's own, the spans exported with OTLP are not held back by it
+    let (filter, handle) = reload::Layer::new(filter);
+    let system_log = match args.log_to {
+        LogTarget::Stderr => None,
+        target => SystemLog::connect(target)
+            .map_err(|e| {
+                eprintln!(
+                    "{}",
+                    style(format!(
+                        "Cannot log to {}, logging to stderr: {}",
+                        target.to_possible_value().unwrap().get_name(),
+                        e
+                    ))
+                    .yellow()
+                )
+            })
+            .ok(),
+    };
+    let _ = TARGET.set(match system_log {
+        Some(_) => args.log_to,
+        None => LogTarget::Stderr,
+    });
+    // syslog and journald keep the time and host of each line themselves
+    let log = match system_log {
+        Some(system_log) => fmt::layer()
+            .with_writer(system_log)
+            .with_ansi(false)
+            .with_target(false)
+            .without_time()
+            .with_level(false)
+            .with_filter(filter)
+            .boxed(),
+        None => fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(console::colors_enabled_stderr())
+            .with_target(false)
+            .with_timer(NodeTime(identity::name()))
+            .with_filter(filter)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(log)
+        .with(telemetry::layer())
+        .with(crash::layer())
+        .init();
+    let _ = LEVEL.set(Level {
+        default,
+        pinned: args.quiet || args.verbose > 0 || env::var_os(LOG_ENV).is_some(),
+        reload: Box::new(move |filter| handle.reload(filter).map_err(|e| anyhow!("{}", e))),
+    });
+}
+
+// Log with the filter of the node file's `log_level`, or the default one when it sets none.
+// Returns whether it applies, -v, -q and GAIA_LOG winning over it.
+pub fn set_filter(filter: Option<&str>) -> anyhow::Result<bool> {
+    let Some(level) = LEVEL.get() else {
+        return Ok(false);
+    };
+    if level.pinned {
+        return Ok(false);
+    }
+    let filter = filter.unwrap_or(&level.default);
+    (level.reload)(EnvFilter::try_new(filter).map_err(|e| anyhow!("log_level: {}", e))?)?;
+
+    Ok(true)
+}
+
+// The time of each line followed by the node name, telling apart the logs of several nodes
+struct NodeTime(String);
+
+impl FormatTime for NodeTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        SystemTime.format_time(w)?;
+        write!(w, " {}", self.0)
+    }
+}
+
+// The arguments logging a process started by this one where this one logs, none for stderr
+// which the caller redirects
+pub fn to_args() -> Vec<String> {
+    TARGET
+        .get()
+        .filter(|target| **target != LogTarget::Stderr)
+        .and_then(|target| target.to_possible_value())
+        .map(|target| vec![format!("--log-to={}", target.get_name())])
+        .unwrap_or_default()
+}
+
+// The syslog daemon or the journal, each line sent as a datagram with its priority
+#[derive(Clone)]
+struct SystemLog {
+    target: LogTarget,
+    node: String,
+    #[cfg(unix)]
+    socket: std::sync::Arc<std::os::unix::net::UnixDatagram>,
+}
+
+impl SystemLog {
+    #[cfg(unix)]
+    fn connect(target: LogTarget) -> anyhow::Result<Self> {
+        use std::os::unix::net::UnixDatagram;
+
+        let paths: &[&str] = match target {
+            LogTarget::Journald => &["/run/systemd/journal/socket"],
+            // where Linux, macOS and the BSDs listen
+            _ => &["/dev/log", "/var/run/syslog", "/var/run/log"],
+        };
+        let socket = UnixDatagram::unbound()?;
+        let mut failed = None;
+        for path in paths {
+            match socket.connect(path) {
+                Ok(()) => {
+                    return Ok(Self {
+                        target,
+                        node: identity::name(),
+                        socket: std::sync::Arc::new(socket),
+                    })
+                }
+                Err(e) => failed = Some(anyhow!("{}: {}", path, e)),
+            }
+        }
+
+        Err(failed.unwrap_or(anyhow!("no socket to connect to")))
+    }
+
+    #[cfg(not(unix))]
+    fn connect(_target: LogTarget) -> anyhow::Result<Self> {
+        Err(anyhow!("only available on Unix"))
+    }
+
+    fn send(&self, datagram: &[u8]) {
+        #[cfg(unix)]
+        let _ = self.socket.send(datagram);
+        #[cfg(not(unix))]
+        let _ = datagram;
+    }
+}
+
+impl<'a> MakeWriter<'a> for SystemLog {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(6)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        // the severities of syslog
+        self.line(match *meta.level() {
+            Severity::ERROR => 3,
+            Severity::WARN => 4,
+            Severity::INFO => 6,
+            _ => 7,
+        })
+    }
+}
+
+impl SystemLog {
+    fn line(&self, priority: u8) -> Line {
+        Line {
+            log: self.clone(),
+            priority,
+            text: Vec::new(),
+        }
+    }
+}
+
+// A line of the log, sent once it is written whole
+struct Line {
+    log: SystemLog,
+    priority: u8,
+    text: Vec<u8>,
+}
+
+impl io::Write for Line {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.text.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.text);
+        let text = text.trim_end();
+        if text.is_empty() {
+            return;
+        }
+        let datagram = match self.log.target {
+            LogTarget::Journald => journal_entry(&[
+                ("PRIORITY", &self.priority.to_string()),
+                ("SYSLOG_IDENTIFIER", "gaia"),
+                ("SYSLOG_PID", &std::process::id().to_string()),
+                ("GAIA_NODE", &self.log.node),
+                ("MESSAGE", text),
+            ]),
+            // the user facility, as programs run by a user log
+            _ => format!(
+                "<{}>gaia[{}]: {}",
+                8 + self.priority,
+                std::process::id(),
+                text
+            )
+            .into_bytes(),
+        };
+        self.log.send(&datagram);
+    }
+}
+
+// The fields of an entry in the journal's native protocol, values spanning lines sent with
+// their length
+fn journal_entry(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    for (name, value) in fields {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
@@ -1,345 +1,1443 @@
+mod affinity;
+mod anthropic;
+mod attachment;
+mod audit;
+mod backup;
+mod batch;
+mod blob;
+mod bm25;
+mod card;
+mod chat;
+mod client;
+mod compare;
+mod config;
+mod conformance;
+mod context;
+mod cpu;
+mod crash;
+mod crawl;
+mod daemon;
+mod device;
+mod discover;
+mod document;
+mod embed;
+mod embedded;
+mod error;
+mod eval;
+mod events;
+mod fallback;
+mod filter;
+mod gateway;
+mod gguf;
+mod hf;
+mod identity;
+mod idle;
+mod ipfs;
+mod jinja;
+mod keys;
+mod license;
+mod loadtest;
+mod lock;
+mod logging;
+mod memory;
+mod migrate;
+mod models;
+mod node;
+mod notify;
+mod oci;
+mod ollama;
+mod ollama_api;
+mod overflow;
+mod paths;
+mod peers;
+mod preflight;
+mod preset;
+mod progress;
+mod prompt;
+mod qdrant;
+mod quota;
+mod rag;
+mod repl;
+mod routing;
+mod runtime;
+mod server;
+mod service;
+mod setup;
+mod signature;
+mod start;
+mod store;
+mod telemetry;
+mod template;
+mod term;
+mod throughput;
+mod tool;
+mod top;
+mod torrent;
+mod transcribe;
+mod tui;
+mod uninstall;
+mod watchdog;
+mod websocket;
+
 use anyhow::{anyhow, bail};
-use clap::{builder::EnumValueParser, Parser, Subcommand, ValueEnum};
-use console::style;
-use dialoguer::{console::Term, theme::ColorfulTheme, Select};
-use reqwest::Url;
-use std::fs::File;
-use std::io::copy;
+use attachment::AttachmentArgs;
+use clap::{CommandFactory, Parser, Subcommand};
+use client::{ClientArgs, SamplingArgs};
 use std::{
-    env,
     fs::{self},
     path::PathBuf,
-    str::FromStr,
 };
-use tokio::runtime::Runtime;
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
 struct Cli {
-    #[arg(default_value = "apepkuss")]
-    name: String,
+    // deprecated for `gaia node name set`, kept for one release
+    #[arg(hide = true)]
+    name: Option<String>,
+    #[command(flatten)]
+    log: logging::LogArgs,
+    #[command(flatten)]
+    term: term::TermArgs,
+    #[arg(
+        long = "error-format",
+        help = "How to print errors, json prints one object with the kind and exit code",
+        value_name = "FORMAT",
+        default_value = "text",
+        global = true
+    )]
+    error_format: error::ErrorFormat,
+    #[command(flatten)]
+    progress: progress::ProgressArgs,
+    #[command(flatten)]
+    notify: notify::NotifyArgs,
+    #[command(flatten)]
+    events: events::EventArgs,
+    #[command(flatten)]
+    signature: signature::SignatureArgs,
+    #[command(flatten)]
+    license: license::LicenseArgs,
+    // none sets up a node on the first run
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Debug, Clone, Subcommand)]
 enum Commands {
-    Start {
+    /// Start the api-server with a gguf model
+    Start(start::StartArgs),
+    /// Set up a node step by step: the model that fits this machine, its ports, an API key and
+    /// a service starting it at login, written to config.toml in the config directory
+    Setup {
+        #[arg(long = "force", help = "Replace the node file there without asking")]
+        force: bool,
+    },
+    /// Save the node files, keys, presets, prompts, chat history, model manifest and Qdrant
+    /// collections to one archive
+    Backup {
         #[arg(
-            short = 'm',
-            long = "model",
-            help = "Url to the gguf model",
-            ignore_case = true
+            short = 'o',
+            long = "output",
+            help = "Archive to write",
+            default_value = "node-backup.tar.zst"
         )]
-        model: Option<String>,
+        output: PathBuf,
+        #[arg(
+            long = "no-qdrant",
+            help = "Leave out the Qdrant collections, to be ingested again after restoring"
+        )]
+        no_qdrant: bool,
+        #[command(flatten)]
+        qdrant: qdrant::QdrantArgs,
+    },
+    /// Recreate a node from an archive written by `gaia backup`
+    Restore {
+        #[arg(help = "Archive written by `gaia backup`")]
+        file: PathBuf,
+        #[arg(long = "force", help = "Replace the files of the node set up here")]
+        force: bool,
+        #[command(flatten)]
+        qdrant: qdrant::QdrantArgs,
+    },
+    /// Import a gaianet-node install: its config, downloaded models, knowledge base and keys
+    Migrate {
+        #[arg(
+            long = "from",
+            help = "Directory of the gaianet-node install, defaults to ~/gaianet"
+        )]
+        from: Option<PathBuf>,
         #[arg(
             short = 'p',
             long = "prompt-template",
-            help = "Type of prompt template for the gguf model",
-            requires = "model",
-            value_parser = EnumValueParser::<PromptTemplateType>::new(),
+            help = "Prompt template to use in place of the one of the install"
+        )]
+        prompt_template: Option<template::PromptTemplateType>,
+        #[arg(long = "force", help = "Replace the node file in the config directory")]
+        force: bool,
+    },
+    /// What the node is called in logs, stats and events
+    Node {
+        #[command(subcommand)]
+        command: NodeCommand,
+    },
+    /// Start the node of a node file at login, with systemd or launchd
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Stop everything, remove the service, and remove the runtimes, models, caches, logs and
+    /// node files gaia keeps, once the list is confirmed
+    Uninstall {
+        #[arg(
+            long = "keep-models",
+            help = "Leave the models directory, to set gaia up again without downloading them"
+        )]
+        keep_models: bool,
+        #[arg(short = 'y', long = "yes", help = "Remove without asking to confirm")]
+        yes: bool,
+    },
+    /// Bring up the models and collections described by a node file
+    Serve {
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "TOML or YAML file describing the node, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+        #[arg(
+            short = 'y',
+            long = "yes",
+            help = "Start without asking to confirm the preflight summary"
+        )]
+        yes: bool,
+    },
+    /// Show the services launched by `gaia start` and the models they serve
+    Status,
+    /// Stop the services launched by `gaia start`
+    Stop,
+    /// Keep running, answering `status`, `stop`, `models load`, `reload` and `stats` over a local
+    /// socket, restart the services that stop answering, stop them when idle or as scheduled,
+    /// load the models of the gateway on demand and advertise the node on the local network
+    Daemon {
+        #[arg(
+            long = "detach",
+            help = "Run in the background, logging to daemon.log in the logs directory"
+        )]
+        detach: bool,
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "Node file whose schedule and gateway to follow, defaults to config.toml or config.yaml in the config directory when there is one",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+        #[command(flatten)]
+        watch: watchdog::WatchdogArgs,
+        #[command(flatten)]
+        idle: idle::IdleArgs,
+        #[arg(
+            long = "no-mdns",
+            help = "Do not advertise the node to `gaia discover` on the local network"
+        )]
+        no_mdns: bool,
+    },
+    /// List the peers of the node file, the nodes its gateway sends the requests for models it
+    /// does not serve, with the models each serves
+    Peers {
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "Node file whose gateway.peers to list, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// List the gaia nodes advertised on the local network, with their models and endpoints
+    Discover {
+        #[arg(
+            long = "timeout",
+            help = "Seconds to listen for nodes",
+            value_name = "SECS",
+            default_value_t = 3
+        )]
+        timeout: u64,
+        #[arg(long = "json", help = "Print the nodes as JSON")]
+        json: bool,
+    },
+    /// Manage the models served by the daemon and downloaded to the models directory
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Download a model into the models directory without starting it
+    Pull {
+        #[arg(
+            help = "Url of the model: http(s)://, s3://, gs://, az://, ipfs://, oci://, a magnet link or a .torrent file"
+        )]
+        model: Option<String>,
+        #[arg(
+            long = "locked",
+            help = "Only pull the models of gaia.lock beside the node file, failing on any file that does not match it"
+        )]
+        locked: bool,
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "Node file whose gaia.lock is used with --locked, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE",
+            requires = "locked"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// Pull the models of a node file and record their exact files in gaia.lock beside it
+    Lock {
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "TOML or YAML file describing the node, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
         )]
-        prompt_template: Option<PromptTemplateType>,
+        file: Option<PathBuf>,
+    },
+    /// Apply the node file to the running daemon without unloading the models: the gateway's
+    /// keys, quotas, limits and filters, the log level and the retrieval policies. SIGHUP does
+    /// the same.
+    Reload,
+    /// Show the uptime, requests and memory of the daemon and its services, and the token
+    /// throughput of the run and chat sessions
+    Stats,
+    /// Watch the CPU, memory and VRAM of each service, its requests in flight, tokens per
+    /// second and log, live until q is pressed
+    Top {
+        #[arg(
+            long = "interval",
+            help = "How often to refresh, e.g. 1s or 5s",
+            value_name = "DURATION",
+            default_value = "1s",
+            value_parser = events::parse_age
+        )]
+        interval: std::time::Duration,
+    },
+    /// Manage the node full screen: watch and restart its services, tail their logs, start the
+    /// models on disk and edit the node file
+    Tui {
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "Node file to edit, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// Print where gaia keeps its config, models, logs and state
+    Paths,
+    /// Check the prompt templates gaia knows
+    Templates {
+        #[command(subcommand)]
+        command: TemplatesCommand,
+    },
+    /// Keep a redacted report of each time gaia crashes, to attach to a bug report, off until
+    /// enabled
+    CrashReports {
+        #[command(subcommand)]
+        command: CrashReportsCommand,
+    },
+    /// List what was done to the node and by whom: starts, stops, crashes, downloads and changes
+    Events {
         #[arg(
-            short = 'r',
-            long = "reverse-prompt",
-            help = "Halt generation at PROMPT, return control",
-            requires = "model"
+            long = "since",
+            help = "Only list the events of this last while, e.g. 30m, 24h or 7d",
+            value_name = "AGE",
+            value_parser = events::parse_age
         )]
-        reverse_prompt: Option<String>,
+        since: Option<std::time::Duration>,
+        #[arg(
+            long = "event",
+            help = "Only list the events of this kind, e.g. started"
+        )]
+        event: Option<String>,
+        #[arg(
+            long = "json",
+            help = "Print each event as the JSON line it is recorded as"
+        )]
+        json: bool,
+    },
+    /// Report the usage of the API keys of the gateway against their quotas
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Manage the WasmEdge versions the models run with, each with its own ggml plugin
+    Runtime {
+        #[command(subcommand)]
+        command: RuntimeCommand,
+    },
+    /// Send a single prompt to the running model
+    Run {
+        #[arg(
+            help = "Prompt to send to the model",
+            required_unless_present_any = ["prompt_file", "prompt_name", "input"]
+        )]
+        prompt: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "prompt-file",
+            help = "File containing the prompt, may use {{name}} placeholders",
+            conflicts_with = "prompt"
+        )]
+        prompt_file: Option<PathBuf>,
+        #[arg(
+            short = 'n',
+            long = "prompt-name",
+            help = "Name of a prompt saved with `gaia prompts save`",
+            conflicts_with_all = ["prompt", "prompt_file"]
+        )]
+        prompt_name: Option<String>,
+        #[arg(
+            long = "var",
+            help = "Value for a {{name}} placeholder, as NAME=VALUE or NAME=@FILE",
+            value_name = "NAME=VALUE",
+            value_parser = prompt::parse_var
+        )]
+        vars: Vec<(String, String)>,
+        #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+        system_prompt: Option<String>,
         #[arg(
             short = 'c',
             long = "context-size",
-            help = "Prompt context size",
-            requires = "model"
+            help = "Context size of the running model, used to check attachments",
+            default_value_t = DEFAULT_CONTEXT_SIZE
         )]
-        context_size: Option<u64>,
+        context_size: u64,
+        #[command(flatten)]
+        attachments: AttachmentArgs,
+        #[command(flatten)]
+        docs: context::DocsArgs,
+        #[command(flatten)]
+        batch: batch::BatchArgs,
+        #[command(flatten)]
+        output: chat::OutputArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Chat with the running model interactively
+    Chat {
+        #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+        system_prompt: Option<String>,
+        #[arg(
+            long = "preset",
+            help = "Preset made with `gaia preset create` giving the system prompt, sampling parameters and model name, defaults to the one picked with `gaia preset use`; /preset NAME switches to another",
+            value_name = "NAME"
+        )]
+        preset: Option<String>,
+        #[command(flatten)]
+        tools: tool::ToolArgs,
+        #[command(flatten)]
+        context: overflow::ContextArgs,
+        #[command(flatten)]
+        attachments: AttachmentArgs,
+        #[command(flatten)]
+        docs: context::DocsArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Send a prompt to two models, or to one with two sets of parameters, and show the replies
+    /// side by side
+    Compare {
+        #[arg(
+            help = "Prompt to send to both",
+            required_unless_present = "prompt_file"
+        )]
+        prompt: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "prompt-file",
+            help = "File containing the prompt, may use {{name}} placeholders",
+            conflicts_with = "prompt"
+        )]
+        prompt_file: Option<PathBuf>,
+        #[arg(
+            long = "var",
+            help = "Value for a {{name}} placeholder, as NAME=VALUE or NAME=@FILE",
+            value_name = "NAME=VALUE",
+            value_parser = prompt::parse_var
+        )]
+        vars: Vec<(String, String)>,
+        #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+        system_prompt: Option<String>,
+        #[arg(
+            short = 'a',
+            long = "side-a",
+            help = "What the first side changes from the shared settings, e.g. model=llama-q4,temperature=0.2, with the keys model, base-url, temperature, top-p, max-tokens and seed",
+            value_name = "KEY=VALUE,...",
+            value_parser = compare::parse_side,
+            default_value = ""
+        )]
+        a: compare::Side,
+        #[arg(
+            short = 'b',
+            long = "side-b",
+            help = "What the second side changes from the shared settings, e.g. model=llama-q8",
+            value_name = "KEY=VALUE,...",
+            value_parser = compare::parse_side,
+            default_value = ""
+        )]
+        b: compare::Side,
+        #[command(flatten)]
+        judge: compare::JudgeArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Send concurrent chat requests to the node at rising concurrency levels and report the
+    /// latency percentiles, throughput and errors of each, to size the hardware of a rollout
+    Loadtest(loadtest::LoadtestArgs),
+    /// Run the cases of a suite against the running model, failing when any answer misses its
+    /// expectations
+    Eval {
+        #[arg(help = "TOML or YAML file with the prompts and what their answers should be")]
+        file: PathBuf,
+        #[arg(
+            long = "judge",
+            help = "Model grading the cases with judge criteria, overrides the one of the suite",
+            value_name = "MODEL"
+        )]
+        judge: Option<String>,
+        #[arg(
+            long = "report",
+            help = "Write the results of every case as JSON to this file",
+            value_name = "FILE"
+        )]
+        report: Option<PathBuf>,
+        #[command(flatten)]
+        baseline: eval::BaselineArgs,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Send the api-server requests covering the OpenAI API, chat, streaming, embeddings and
+    /// refused ones, and report where it answers otherwise
+    CheckApi {
+        #[arg(
+            long = "embedding-model",
+            help = "Model to request embeddings from, the check is skipped when the server has no embeddings endpoint",
+            value_name = "MODEL"
+        )]
+        embedding_model: Option<String>,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Print the embeddings of texts as JSON lines, shaped for the vector store they go to
+    Embed {
+        #[arg(help = "Texts to embed", required_unless_present = "input")]
+        texts: Vec<String>,
+        #[arg(
+            short = 'i',
+            long = "input",
+            help = "File with a text to embed on each line",
+            value_name = "FILE"
+        )]
+        input: Option<PathBuf>,
+        #[arg(
+            short = 'o',
+            long = "output",
+            help = "File the embeddings are written to rather than printed",
+            value_name = "FILE"
+        )]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        embedding: embed::EmbeddingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Transcribe an audio file with the whisper model started by `gaia start --whisper-model`
+    Transcribe {
+        #[arg(help = "Audio file to transcribe, e.g. a wav file")]
+        file: PathBuf,
+        #[arg(short = 'l', long = "language", help = "Spoken language, e.g. en")]
+        language: Option<String>,
+        #[arg(long = "translate", help = "Translate the speech into English")]
+        translate: bool,
+        #[arg(
+            long = "base-url",
+            help = "Base url of the whisper api-server",
+            default_value = transcribe::DEFAULT_AUDIO_BASE_URL
+        )]
+        base_url: String,
+    },
+    /// Manage the knowledge base used for retrieval-augmented generation
+    Rag {
+        #[command(subcommand)]
+        command: RagCommand,
+    },
+    /// Check the node file read by `gaia serve`
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage the library of saved prompts
+    Prompts {
+        #[command(subcommand)]
+        command: PromptsCommand,
+    },
+    /// Manage the presets tying a model to its prompt template, system prompt and sampling
+    /// parameters
+    Preset {
+        #[command(subcommand)]
+        command: PresetCommand,
     },
-    Stop,
 }
 
-const PROMPT_TEMPLATES: [&str; 20] = [
-    "llama-2-chat",
-    "mistral-instruct",
-    "mistrallite",
-    "openchat",
-    "codellama-instruct",
-    "human-asistant",
-    "vicuna-1.0-chat",
-    "vicuna-1.1-chat",
-    "vicuna-llava",
-    "chatml",
-    "baichuan-2",
-    "wizard-coder",
-    "zephyr",
-    "stablelm-zephyr",
-    "intel-neural",
-    "deepseek-chat",
-    "deepseek-coder",
-    "solar-instruct",
-    "phi-2-chat",
-    "phi-2-instruct",
-];
-
-#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
-pub enum PromptTemplateType {
-    Llama2Chat,
-    MistralInstruct,
-    MistralLite,
-    OpenChat,
-    CodeLlama,
-    CodeLlamaSuper,
-    HumanAssistant,
-    VicunaChat,
-    Vicuna11Chat,
-    VicunaLlava,
-    ChatML,
-    Baichuan2,
-    WizardCoder,
-    Zephyr,
-    StableLMZephyr,
-    IntelNeural,
-    DeepseekChat,
-    DeepseekCoder,
-    SolarInstruct,
-    Phi2Chat,
-    Phi2Instruct,
-    GemmaInstruct,
+#[derive(Debug, Clone, Subcommand)]
+enum ModelsCommand {
+    /// List the models on disk: downloaded ones, those of LM Studio, Jan and Ollama, and those in $GAIA_MODEL_DIRS
+    List,
+    /// Have the daemon start the api-server with a gguf model
+    Load(start::StartArgs),
+    /// Write an Ollama Modelfile for a model and install it in Ollama's models directory
+    ExportOllama(ollama::ExportArgs),
+    /// List the models downloaded by Ollama, or link one into the models directory
+    ImportOllama {
+        #[arg(help = "Ollama model to import, as NAME or NAME:TAG, lists them when omitted")]
+        name: Option<String>,
+    },
+    /// Link ggufs of the Hugging Face cache into the models directory instead of downloading them again
+    Adopt {
+        #[arg(help = "Files in the snapshots of the cache, every gguf there when omitted")]
+        paths: Vec<PathBuf>,
+    },
+    /// Replace identical models in the models directory by links to a single copy
+    Dedup,
+    /// Trust a PEM public key, ECDSA or RSA, to sign models with detached .sig files
+    Trust {
+        #[arg(help = "Public key, e.g. cosign.pub written by `cosign generate-key-pair`")]
+        key: PathBuf,
+        #[arg(long = "name", help = "Name of the key, defaults to its file name")]
+        name: Option<String>,
+    },
+    /// Check the detached signature of a model against the trusted keys
+    Verify {
+        #[arg(help = "Path of the model, or its name in the models directory")]
+        model: String,
+    },
+    /// Show the model card of a Hugging Face repository, e.g. for its prompt format
+    Card {
+        #[arg(
+            help = "Repository as OWNER/NAME, a Hugging Face url, or a model downloaded from one"
+        )]
+        model: String,
+        #[arg(long = "no-pager", help = "Print the card instead of paging it")]
+        no_pager: bool,
+    },
+    /// Share a model downloaded from a magnet link or torrent file with other peers
+    Seed {
+        #[arg(help = "Magnet link, or path or url of the .torrent file")]
+        source: String,
+        #[arg(
+            long = "port",
+            help = "Port to accept peers on",
+            default_value_t = torrent::DEFAULT_SEED_PORT
+        )]
+        port: u16,
+    },
 }
-impl FromStr for PromptTemplateType {
-    type Err = anyhow::Error;
 
-    fn from_str(template: &str) -> std::result::Result<Self, Self::Err> {
-        match template {
-            "llama-2-chat" => Ok(PromptTemplateType::Llama2Chat),
-            "mistral-instruct" => Ok(PromptTemplateType::MistralInstruct),
-            "mistrallite" => Ok(PromptTemplateType::MistralLite),
-            "codellama-instruct" => Ok(PromptTemplateType::CodeLlama),
-            "codellama-super-instruct" => Ok(PromptTemplateType::CodeLlamaSuper),
-            "belle-llama-2-chat" => Ok(PromptTemplateType::HumanAssistant),
-            "human-assistant" => Ok(PromptTemplateType::HumanAssistant),
-            "vicuna-1.0-chat" => Ok(PromptTemplateType::VicunaChat),
-            "vicuna-1.1-chat" => Ok(PromptTemplateType::Vicuna11Chat),
-            "vicuna-llava" => Ok(PromptTemplateType::VicunaLlava),
-            "chatml" => Ok(PromptTemplateType::ChatML),
-            "openchat" => Ok(PromptTemplateType::OpenChat),
-            "baichuan-2" => Ok(PromptTemplateType::Baichuan2),
-            "wizard-coder" => Ok(PromptTemplateType::WizardCoder),
-            "zephyr" => Ok(PromptTemplateType::Zephyr),
-            "stablelm-zephyr" => Ok(PromptTemplateType::StableLMZephyr),
-            "intel-neural" => Ok(PromptTemplateType::IntelNeural),
-            "deepseek-chat" => Ok(PromptTemplateType::DeepseekChat),
-            "deepseek-coder" => Ok(PromptTemplateType::DeepseekCoder),
-            "solar-instruct" => Ok(PromptTemplateType::SolarInstruct),
-            "phi-2-chat" => Ok(PromptTemplateType::Phi2Chat),
-            "phi-2-instruct" => Ok(PromptTemplateType::Phi2Instruct),
-            "gemma-instruct" => Ok(PromptTemplateType::GemmaInstruct),
-            _ => bail!(template.to_string()),
-        }
-    }
+#[derive(Debug, Clone, Subcommand)]
+enum NodeCommand {
+    /// Print or change the name of the node, the host name until one is set
+    Name {
+        #[command(subcommand)]
+        command: NameCommand,
+    },
 }
-impl std::fmt::Display for PromptTemplateType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            PromptTemplateType::Llama2Chat => write!(f, "llama-2-chat"),
-            PromptTemplateType::MistralInstruct => write!(f, "mistral-instruct"),
-            PromptTemplateType::MistralLite => write!(f, "mistrallite"),
-            PromptTemplateType::OpenChat => write!(f, "openchat"),
-            PromptTemplateType::CodeLlama => write!(f, "codellama-instruct"),
-            PromptTemplateType::HumanAssistant => write!(f, "human-asistant"),
-            PromptTemplateType::VicunaChat => write!(f, "vicuna-1.0-chat"),
-            PromptTemplateType::Vicuna11Chat => write!(f, "vicuna-1.1-chat"),
-            PromptTemplateType::VicunaLlava => write!(f, "vicuna-llava"),
-            PromptTemplateType::ChatML => write!(f, "chatml"),
-            PromptTemplateType::Baichuan2 => write!(f, "baichuan-2"),
-            PromptTemplateType::WizardCoder => write!(f, "wizard-coder"),
-            PromptTemplateType::Zephyr => write!(f, "zephyr"),
-            PromptTemplateType::StableLMZephyr => write!(f, "stablelm-zephyr"),
-            PromptTemplateType::IntelNeural => write!(f, "intel-neural"),
-            PromptTemplateType::DeepseekChat => write!(f, "deepseek-chat"),
-            PromptTemplateType::DeepseekCoder => write!(f, "deepseek-coder"),
-            PromptTemplateType::SolarInstruct => write!(f, "solar-instruct"),
-            PromptTemplateType::Phi2Chat => write!(f, "phi-2-chat"),
-            PromptTemplateType::Phi2Instruct => write!(f, "phi-2-instruct"),
-            PromptTemplateType::CodeLlamaSuper => write!(f, "codellama-super-instruct"),
-            PromptTemplateType::GemmaInstruct => write!(f, "gemma-instruct"),
-        }
-    }
+
+#[derive(Debug, Clone, Subcommand)]
+enum NameCommand {
+    /// Print the name of the node
+    Get,
+    /// Name the node
+    Set {
+        #[arg(help = "Letters, digits, '-', '_' and '.', up to 63 characters")]
+        name: String,
+    },
 }
 
-fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+#[derive(Debug, Clone, Subcommand)]
+enum ServiceCommand {
+    /// Install and enable the service
+    Install {
+        #[arg(
+            short = 'f',
+            long = "file",
+            help = "Node file the service brings up, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// Disable the service and remove it
+    Uninstall,
+}
 
-    match cli.command {
-        Commands::Start {
-            model,
-            prompt_template,
-            reverse_prompt,
-            context_size,
-        } => {
-            // gguf model
-            command_start(model, prompt_template, reverse_prompt, context_size);
+#[derive(Debug, Clone, Subcommand)]
+enum TemplatesCommand {
+    /// Render the same multi-turn conversation with every template and compare it with the
+    /// fixtures, failing on any difference in names, special tokens or spacing
+    Test {
+        #[arg(
+            long = "fixtures",
+            help = "Directory of the fixtures to compare with, one <template>.txt each, instead of those built in",
+            value_name = "DIR"
+        )]
+        fixtures: Option<PathBuf>,
+        #[arg(
+            long = "update",
+            help = "Write the renders to the fixtures directory instead of comparing",
+            requires = "fixtures"
+        )]
+        update: bool,
+    },
+    /// Render the same conversation with the Jinja chat template of a gguf model, as the gateway
+    /// prompts a model started with --chat-template
+    Render {
+        #[arg(help = "Path to the gguf model, whose special tokens the template is given")]
+        model: PathBuf,
+        #[arg(
+            long = "chat-template",
+            help = "Jinja file to render with instead of the template in the model",
+            value_name = "FILE"
+        )]
+        chat_template: Option<PathBuf>,
+    },
+}
 
-            // start Qdrant
+#[derive(Debug, Clone, Subcommand)]
+enum CrashReportsCommand {
+    /// Write a report each time gaia panics: its version, the OS, the backtrace and the last
+    /// lines logged, with keys, emails, the node name and the home directory left out
+    Enable {
+        #[arg(
+            long = "upload",
+            help = "Also POST each report as JSON to this url",
+            value_name = "URL"
+        )]
+        upload: Option<String>,
+    },
+    /// Stop writing reports, the reports written are kept
+    Disable,
+    /// Tell whether reports are written and list them, the newest last
+    List,
+    /// Print a report
+    Show {
+        #[arg(
+            help = "Report to print, as `gaia crash-reports list` names it, the newest when not given"
+        )]
+        id: Option<String>,
+    },
+}
 
-            // start api-server
-        }
-        Commands::Stop => {
-            // stop api-server
+#[derive(Debug, Clone, Subcommand)]
+enum KeysCommand {
+    /// Show the requests and tokens each API key of the gateway used today and this month
+    Usage {
+        #[arg(long = "json", help = "Print the usage as JSON")]
+        json: bool,
+    },
+}
 
-            // stop Qdrant
+#[derive(Debug, Clone, Subcommand)]
+enum RuntimeCommand {
+    /// List the installed versions, the one in use marked with *
+    List {
+        #[arg(long = "remote", help = "Also list the versions released on GitHub")]
+        remote: bool,
+    },
+    /// Install a version of WasmEdge and its ggml plugin next to the others
+    Install {
+        #[arg(help = "Version to install, e.g. 0.14.1, or latest")]
+        version: String,
+        #[arg(
+            long = "device",
+            help = "Device to install the ggml plugin for, defaults to the GPU found",
+            value_enum
+        )]
+        device: Option<device::Device>,
+    },
+    /// Launch the models with an installed version, or the wasmedge on PATH with `system`
+    Use {
+        #[arg(help = "Installed version, or system")]
+        version: String,
+    },
+}
 
-            unimplemented!("Stop command not implemented")
-        }
-    }
+#[derive(Debug, Clone, Subcommand)]
+enum ConfigCommand {
+    /// Report every problem in a node file at once, e.g. before deploying it
+    Validate {
+        #[arg(
+            help = "TOML or YAML file describing the node, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+    /// Upgrade a node file written for an older gaia, keeping a backup of it
+    Migrate {
+        #[arg(
+            help = "TOML or YAML file describing the node, defaults to config.toml or config.yaml in the config directory",
+            value_name = "FILE"
+        )]
+        file: Option<PathBuf>,
+    },
+}
 
-    Ok(())
+#[derive(Debug, Clone, Subcommand)]
+enum RagCommand {
+    /// Embed text files, PDFs, directories and web sites into a collection
+    Ingest {
+        #[arg(
+            help = "Files or directories to ingest",
+            required_unless_present = "urls"
+        )]
+        paths: Vec<PathBuf>,
+        #[arg(
+            long = "url",
+            help = "Site to crawl, or the url of its sitemap.xml",
+            value_name = "URL"
+        )]
+        urls: Vec<String>,
+        #[arg(
+            long = "depth",
+            help = "How many links away from the url to crawl",
+            default_value_t = rag::DEFAULT_CRAWL_DEPTH,
+            requires = "urls"
+        )]
+        depth: usize,
+        #[arg(
+            long = "max-pages",
+            help = "Maximum number of pages to crawl per url",
+            default_value_t = rag::DEFAULT_MAX_PAGES,
+            requires = "urls"
+        )]
+        max_pages: usize,
+        #[arg(
+            long = "chunk-size",
+            help = "Maximum number of characters per chunk",
+            default_value_t = rag::DEFAULT_CHUNK_SIZE
+        )]
+        chunk_size: usize,
+        #[command(flatten)]
+        rag: rag::RagArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Re-embed changed and added files and pages, and drop deleted ones
+    Reindex {
+        #[command(flatten)]
+        rag: rag::RagArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Save a snapshot of a collection to share it with other nodes
+    ExportSnapshot {
+        #[arg(help = "Name of the collection")]
+        collection: String,
+        #[arg(
+            short = 'o',
+            long = "output",
+            help = "File to write the snapshot to, defaults to <collection>.snapshot"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            long = "vector-store",
+            help = "Where the vectors are kept, defaults to the store the collection was created in"
+        )]
+        vector_store: Option<store::VectorStoreKind>,
+        #[command(flatten)]
+        qdrant: qdrant::QdrantArgs,
+    },
+    /// Create a collection from a snapshot saved on another node
+    ImportSnapshot {
+        #[arg(help = "Snapshot file, as saved by `gaia rag export-snapshot`")]
+        file: PathBuf,
+        #[command(flatten)]
+        rag: rag::RagArgs,
+    },
+    /// Show or set how many chunks are retrieved from a collection and what happens when none match
+    Policy {
+        #[command(flatten)]
+        policy: rag::PolicyArgs,
+        #[command(flatten)]
+        rag: rag::RagArgs,
+    },
+    /// Show what is stored in a collection, or in all of them
+    Stats {
+        #[arg(help = "Name of the collection, defaults to every ingested collection")]
+        collection: Option<String>,
+        #[arg(
+            long = "sample",
+            help = "Number of stored payloads to print",
+            default_value = "3"
+        )]
+        sample: usize,
+        #[arg(
+            long = "vector-store",
+            help = "Where the vectors are kept, defaults to the store the collection was created in"
+        )]
+        vector_store: Option<store::VectorStoreKind>,
+        #[command(flatten)]
+        qdrant: qdrant::QdrantArgs,
+    },
+    /// Search the collection for the chunks most similar to the query
+    Query {
+        #[arg(help = "Text to search for")]
+        query: String,
+        #[command(flatten)]
+        policy: rag::PolicyArgs,
+        #[arg(
+            long = "filter",
+            help = "Only search chunks whose payload field has the value, e.g. tags=api",
+            value_name = "KEY=VALUE",
+            value_parser = store::parse_condition
+        )]
+        filter: Vec<store::Condition>,
+        #[arg(
+            long = "mode",
+            help = "How to match the query against the chunks",
+            value_enum,
+            default_value_t = rag::SearchMode::Vector
+        )]
+        mode: rag::SearchMode,
+        #[command(flatten)]
+        rerank: rag::RerankArgs,
+        #[command(flatten)]
+        rag: rag::RagArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
 }
 
-fn command_start(
-    model: Option<String>,
-    prompt_template: Option<PromptTemplateType>,
-    reverse_prompt: Option<String>,
-    context_size: Option<u64>,
-) -> anyhow::Result<()> {
-    let gguf_model = match model {
-        Some(model) => {
-            println!("Model: {}", model);
-            "fake.gguf".to_string()
-        }
-        None => {
-            // check cached models
-            let cwd = env::current_dir().unwrap();
-            let entries = fs::read_dir(cwd).unwrap();
-            let mut cached_models = entries
-                .filter_map(|res| {
-                    res.ok().and_then(|e| {
-                        e.path()
-                            .file_name()
-                            .and_then(|n| n.to_str().map(|s| String::from(s)))
-                            .filter(|s| s.ends_with(".gguf"))
-                    })
-                })
-                .collect::<Vec<String>>();
+#[derive(Debug, Clone, Subcommand)]
+enum PromptsCommand {
+    /// Save a prompt to the library
+    Save {
+        #[arg(help = "Name of the prompt")]
+        name: String,
+        #[arg(
+            help = "Prompt text, may use {{name}} placeholders, {{{{ writing a literal {{",
+            required_unless_present = "prompt_file"
+        )]
+        prompt: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "prompt-file",
+            help = "File containing the prompt",
+            conflicts_with = "prompt"
+        )]
+        prompt_file: Option<PathBuf>,
+        #[arg(
+            short = 'd',
+            long = "description",
+            help = "Short description of the prompt"
+        )]
+        description: Option<String>,
+        #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+        system_prompt: Option<String>,
+        #[arg(
+            long = "var",
+            help = "Default value for a {{name}} placeholder, as NAME=VALUE or NAME=@FILE",
+            value_name = "NAME=VALUE",
+            value_parser = prompt::parse_var
+        )]
+        vars: Vec<(String, String)>,
+        #[arg(long = "model-name", help = "Preferred model to send the prompt to")]
+        model_name: Option<String>,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[arg(
+            long = "force",
+            help = "Overwrite an existing prompt with the same name"
+        )]
+        force: bool,
+    },
+    /// List the saved prompts
+    List,
+    /// Send a saved prompt to the running model
+    Use {
+        #[arg(help = "Name of the prompt")]
+        name: String,
+        #[arg(
+            long = "var",
+            help = "Value for a {{name}} placeholder, as NAME=VALUE or NAME=@FILE",
+            value_name = "NAME=VALUE",
+            value_parser = prompt::parse_var
+        )]
+        vars: Vec<(String, String)>,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Remove a saved prompt
+    Remove {
+        #[arg(help = "Name of the prompt")]
+        name: String,
+    },
+}
 
-            let mut selected = String::new();
-            if !cached_models.is_empty() {
-                cached_models.push("Or choose one from: https://huggingface.co/second-state?sort_models=modified#models or https://huggingface.co/models?sort=trending&search=gguf".to_string());
-                let selection = Select::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Select a chached model")
-                    .default(0)
-                    .items(&cached_models[..])
-                    .interact_opt()?;
+#[derive(Debug, Clone, Subcommand)]
+enum PresetCommand {
+    /// Save a preset
+    Create {
+        #[arg(help = "Name of the preset")]
+        name: String,
+        #[arg(
+            short = 'd',
+            long = "description",
+            help = "Short description of the preset"
+        )]
+        description: Option<String>,
+        #[arg(
+            short = 'm',
+            long = "model",
+            help = "Url or path to the gguf model, as `gaia start --model` takes it"
+        )]
+        model: Option<String>,
+        #[arg(
+            long = "model-name",
+            help = "Name the model is served and requested under"
+        )]
+        model_name: Option<String>,
+        #[arg(
+            short = 'p',
+            long = "prompt-template",
+            help = "Type of prompt template for the gguf model"
+        )]
+        prompt_template: Option<template::PromptTemplateType>,
+        #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+        system_prompt: Option<String>,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[arg(
+            long = "force",
+            help = "Overwrite an existing preset with the same name"
+        )]
+        force: bool,
+    },
+    /// List the presets, the one in use marked with *
+    List,
+    /// Use the preset for chats from now on, and for `gaia start` when no model is given
+    Use {
+        #[arg(help = "Name of the preset")]
+        name: String,
+    },
+    /// Remove a preset
+    Remove {
+        #[arg(help = "Name of the preset")]
+        name: String,
+    },
+}
 
-                selected = match selection {
-                    Some(idx) => cached_models[idx].clone(),
-                    _ => panic!("Fatal: No selection!"),
-                };
-            }
+const DEFAULT_CONTEXT_SIZE: u64 = 4096;
 
-            if selected.ends_with(".gguf") {
-                selected
-            } else {
-                // provide a model url to download
-                let model_url = dialoguer::Input::<String>::new()
-                    .with_prompt("Enter the model url")
-                    .interact()?;
+fn main() {
+    let cli = Cli::parse();
+    term::init(&cli.term);
+    identity::init(cli.name);
+    crash::init();
+    logging::init(&cli.log);
+    progress::init(&cli.progress);
+    notify::init(&cli.notify);
+    events::init(&cli.events);
+    signature::init(&cli.signature);
+    license::init(&cli.license);
 
-                // download the model from the url
-                download_model(model_url)?
-            }
-        }
+    let result = match cli.command {
+        Some(command) => run(command),
+        None => first_run(),
     };
+    if let Err(e) = result {
+        std::process::exit(error::report(&e, cli.error_format));
+    }
+}
 
-    let prompt_template: PromptTemplateType = match prompt_template {
-        Some(prompt_template) => {
-            let selection = Select::with_theme(&ColorfulTheme::default())
-                .with_prompt("Select a prompt template")
-                .default(0)
-                .items(&PROMPT_TEMPLATES[..])
-                .interact_opt()?;
+// Set up a node when there is none yet, otherwise show the help
+fn first_run() -> anyhow::Result<()> {
+    if setup::first_run()?.is_none() {
+        Cli::command().print_help()?;
+    }
 
-            match selection {
-                Some(idx) => {
-                    let x = PROMPT_TEMPLATES[idx];
-                    <PromptTemplateType as FromStr>::from_str(x)?
-                }
-                _ => panic!("Fatal: No selection!"),
-            }
-        }
-        None => {
-            let selection = Select::with_theme(&ColorfulTheme::default())
-                .with_prompt("Select a prompt template")
-                .default(0)
-                .items(&PROMPT_TEMPLATES[..])
-                .interact_opt()?;
+    Ok(())
+}
 
-            match selection {
-                Some(idx) => {
-                    let x = PROMPT_TEMPLATES[idx];
-                    <PromptTemplateType as FromStr>::from_str(x)?
-                }
-                _ => panic!("Fatal: No selection!"),
+fn run(command: Commands) -> anyhow::Result<()> {
+    match command {
+        Commands::Start(args) => start::command_start(args)?,
+        Commands::Setup { force } => setup::command_setup(force)?,
+        Commands::Backup {
+            output,
+            no_qdrant,
+            qdrant,
+        } => backup::command_backup(&output, no_qdrant, qdrant)?,
+        Commands::Restore {
+            file,
+            force,
+            qdrant,
+        } => backup::command_restore(&file, force, qdrant)?,
+        Commands::Migrate {
+            from,
+            prompt_template,
+            force,
+        } => migrate::command_migrate(from, prompt_template, force)?,
+        Commands::Node { command } => match command {
+            NodeCommand::Name { command } => match command {
+                NameCommand::Get => identity::command_get()?,
+                NameCommand::Set { name } => identity::command_set(&name)?,
+            },
+        },
+        Commands::Service { command } => match command {
+            ServiceCommand::Install { file } => service::command_install(file)?,
+            ServiceCommand::Uninstall => service::command_uninstall()?,
+        },
+        Commands::Uninstall { keep_models, yes } => uninstall::command_uninstall(keep_models, yes)?,
+        Commands::Serve { file, yes } => {
+            let file = match file {
+                Some(file) => Some(file),
+                None => setup::first_run()?,
+            };
+            node::command_serve(file, yes)?
+        }
+        Commands::Status => start::command_status()?,
+        Commands::Stop => start::command_stop()?,
+        Commands::Daemon {
+            detach,
+            file,
+            watch,
+            idle,
+            no_mdns,
+        } => daemon::command_daemon(detach, file, watch, idle, no_mdns)?,
+        Commands::Discover { timeout, json } => discover::command_discover(timeout, json)?,
+        Commands::Peers { file } => peers::command_list(file)?,
+        Commands::Models { command } => match command {
+            ModelsCommand::Load(args) => daemon::command_models_load(args)?,
+            ModelsCommand::List => models::command_list()?,
+            ModelsCommand::ExportOllama(args) => ollama::command_export(args)?,
+            ModelsCommand::ImportOllama { name } => ollama::command_import(name)?,
+            ModelsCommand::Adopt { paths } => hf::command_adopt(paths)?,
+            ModelsCommand::Dedup => models::command_dedup()?,
+            ModelsCommand::Trust { key, name } => signature::command_trust(key, name)?,
+            ModelsCommand::Verify { model } => signature::command_verify(model)?,
+            ModelsCommand::Card { model, no_pager } => card::command_card(model, no_pager)?,
+            ModelsCommand::Seed { source, port } => torrent::command_seed(source, port)?,
+        },
+        Commands::Pull {
+            model,
+            locked: true,
+            file,
+        } => lock::command_pull_locked(file, model)?,
+        Commands::Pull { model, .. } => start::command_pull(model.ok_or(anyhow!(
+            "Give the url of a model, or --locked to pull gaia.lock"
+        ))?)?,
+        Commands::Lock { file } => lock::command_lock(file)?,
+        Commands::Reload => daemon::command_reload()?,
+        Commands::Stats => daemon::command_stats()?,
+        Commands::Top { interval } => top::command_top(interval)?,
+        Commands::Tui { file } => tui::command_tui(file)?,
+        Commands::Paths => paths::command_paths()?,
+        Commands::Templates { command } => match command {
+            TemplatesCommand::Test { fixtures, update } => {
+                template::command_test(fixtures, update)?
+            }
+            TemplatesCommand::Render {
+                model,
+                chat_template,
+            } => template::command_render(&model, chat_template.as_deref())?,
+        },
+        Commands::CrashReports { command } => match command {
+            CrashReportsCommand::Enable { upload } => crash::command_enable(upload)?,
+            CrashReportsCommand::Disable => crash::command_disable()?,
+            CrashReportsCommand::List => crash::command_list()?,
+            CrashReportsCommand::Show { id } => crash::command_show(id)?,
+        },
+        Commands::Events { since, event, json } => events::command_events(since, event, json)?,
+        Commands::Keys { command } => match command {
+            KeysCommand::Usage { json } => quota::command_usage(json)?,
+        },
+        Commands::Runtime { command } => match command {
+            RuntimeCommand::List { remote } => runtime::command_list(remote)?,
+            RuntimeCommand::Install { version, device } => {
+                runtime::command_install(version, device)?
+            }
+            RuntimeCommand::Use { version } => runtime::command_use(version)?,
+        },
+        Commands::Run {
+            prompt,
+            prompt_file,
+            prompt_name,
+            vars,
+            system_prompt,
+            context_size,
+            attachments,
+            docs,
+            batch,
+            output,
+            sampling,
+            client,
+        } => {
+            let saved = prompt_name.as_deref().map(prompt::load_saved).transpose()?;
+            if batch.input.is_some() {
+                let (template, vars) = prompt::template(prompt_file, saved.as_ref(), vars)?;
+                let (client, sampling, system_prompt) =
+                    apply_saved(client, sampling, system_prompt, saved);
+                batch::command_batch(
+                    client,
+                    sampling,
+                    template,
+                    vars,
+                    system_prompt,
+                    batch,
+                    output.output,
+                )?
+            } else {
+                let prompt = prompt::resolve(prompt, prompt_file, saved.as_ref(), vars)?;
+                let retriever = context::Retriever::new(&docs, &client)?;
+                let (client, sampling, system_prompt) =
+                    apply_saved(client, sampling, system_prompt, saved);
+                chat::command_run(
+                    client,
+                    sampling,
+                    prompt,
+                    system_prompt,
+                    attachment::load(&attachments, context_size)?,
+                    retriever,
+                    output,
+                )?
             }
         }
-    };
-
-    // let directory = matches.value_of("directory").unwrap_or(".");
-
-    let cwd = env::current_dir().unwrap();
-
-    let entries = fs::read_dir(cwd).unwrap();
-
-    let mut files = entries
-        .filter_map(|res| {
-            res.ok().and_then(|e| {
-                e.path()
-                    .file_name()
-                    .and_then(|n| n.to_str().map(|s| String::from(s)))
-                    .filter(|s| s.ends_with(".gguf"))
-            })
-        })
-        .collect::<Vec<String>>();
-
-    // if files.is_empty() {
-    //     println!("No *.gguf files found in the current directory.");
-    //     return;
-    // }
-
-    // files.sort();
-
-    // let selection = Select::new()
-    //     .items(&files)
-    //     .default(0)
-    //     .interact_on_opt(&Term::stdout())
-    //     .unwrap();
-
-    // match selection {
-    //     Some(index) => println!("You selected: {}", files[index]),
-    //     None => println!("No file selected."),
-    // }
+        Commands::Chat {
+            system_prompt,
+            preset,
+            tools,
+            context,
+            attachments,
+            docs,
+            sampling,
+            client,
+        } => {
+            let retriever = context::Retriever::new(&docs, &client)?;
+            let given = preset::Preset {
+                model_name: client.model_name.clone(),
+                system_prompt,
+                params: sampling,
+                ..Default::default()
+            };
+            chat::command_chat(
+                client,
+                given,
+                preset::selected(preset.as_deref())?,
+                attachments,
+                tools,
+                retriever,
+                context,
+            )?
+        }
+        Commands::Compare {
+            prompt,
+            prompt_file,
+            vars,
+            system_prompt,
+            a,
+            b,
+            judge,
+            sampling,
+            client,
+        } => {
+            let prompt = prompt::resolve(prompt, prompt_file, None, vars)?;
+            compare::command_compare(client, sampling, prompt, system_prompt, a, b, judge)?
+        }
+        Commands::Eval {
+            file,
+            judge,
+            report,
+            baseline,
+            sampling,
+            client,
+        } => eval::command_eval(file, client, sampling, judge, report, baseline)?,
+        Commands::Loadtest(args) => loadtest::command_loadtest(args)?,
+        Commands::CheckApi {
+            embedding_model,
+            client,
+        } => conformance::command_check_api(client, embedding_model)?,
+        Commands::Embed {
+            texts,
+            input,
+            output,
+            embedding,
+            client,
+        } => embed::command_embed(client, texts, input, output, embedding)?,
+        Commands::Transcribe {
+            file,
+            language,
+            translate,
+            base_url,
+        } => transcribe::command_transcribe(&base_url, file, language, translate)?,
+        Commands::Rag { command } => match command {
+            RagCommand::Ingest {
+                paths,
+                urls,
+                depth,
+                max_pages,
+                chunk_size,
+                rag,
+                client,
+            } => {
+                let sites = urls
+                    .into_iter()
+                    .map(|url| rag::Site {
+                        url,
+                        depth,
+                        max_pages,
+                    })
+                    .collect();
+                rag::command_ingest(rag, client, paths, sites, chunk_size)?
+            }
+            RagCommand::Reindex { rag, client } => rag::command_reindex(rag, client)?,
+            RagCommand::ExportSnapshot {
+                collection,
+                output,
+                vector_store,
+                qdrant,
+            } => rag::command_export_snapshot(
+                rag::RagArgs {
+                    collection,
+                    vector_store,
+                    qdrant,
+                },
+                output,
+            )?,
+            RagCommand::ImportSnapshot { file, rag } => rag::command_import_snapshot(rag, &file)?,
+            RagCommand::Stats {
+                collection,
+                sample,
+                vector_store,
+                qdrant,
+            } => rag::command_stats(collection, vector_store, qdrant, sample)?,
+            RagCommand::Query {
+                query,
+                policy,
+                filter,
+                mode,
+                rerank,
+                rag,
+                client,
+            } => rag::command_query(rag, client, query, filter, mode, policy, rerank)?,
+            RagCommand::Policy { policy, rag } => rag::command_policy(rag, policy)?,
+        },
+        Commands::Config { command } => match command {
+            ConfigCommand::Validate { file } => node::command_validate(file)?,
+            ConfigCommand::Migrate { file } => node::command_migrate(file)?,
+        },
+        Commands::Prompts { command } => match command {
+            PromptsCommand::Save {
+                name,
+                prompt,
+                prompt_file,
+                description,
+                system_prompt,
+                vars,
+                model_name,
+                sampling,
+                force,
+            } => {
+                let prompt = match (prompt, prompt_file) {
+                    (Some(prompt), _) => prompt,
+                    (None, Some(path)) => fs::read_to_string(&path)
+                        .map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+                    (None, None) => bail!("No prompt given"),
+                };
+                let saved = prompt::SavedPrompt {
+                    description,
+                    prompt,
+                    system_prompt,
+                    model: model_name,
+                    vars: vars.into_iter().collect(),
+                    params: sampling,
+                };
+                prompt::command_prompts_save(&name, saved, force)?
+            }
+            PromptsCommand::List => prompt::command_prompts_list()?,
+            PromptsCommand::Use {
+                name,
+                vars,
+                sampling,
+                client,
+            } => {
+                let saved = prompt::load_saved(&name)?;
+                let prompt = prompt::resolve(None, None, Some(&saved), vars)?;
+                let (client, sampling, system_prompt) =
+                    apply_saved(client, sampling, None, Some(saved));
+                chat::command_run(
+                    client,
+                    sampling,
+                    prompt,
+                    system_prompt,
+                    Vec::new(),
+                    None,
+                    chat::OutputArgs::default(),
+                )?
+            }
+            PromptsCommand::Remove { name } => prompt::command_prompts_remove(&name)?,
+        },
+        Commands::Preset { command } => match command {
+            PresetCommand::Create {
+                name,
+                description,
+                model,
+                model_name,
+                prompt_template,
+                system_prompt,
+                sampling,
+                force,
+            } => {
+                let created = preset::Preset {
+                    description,
+                    model,
+                    model_name,
+                    prompt_template: prompt_template.map(|template| template.to_string()),
+                    system_prompt,
+                    params: sampling,
+                };
+                preset::command_create(&name, created, force)?
+            }
+            PresetCommand::List => preset::command_list()?,
+            PresetCommand::Use { name } => preset::command_use(&name)?,
+            PresetCommand::Remove { name } => preset::command_remove(&name)?,
+        },
+    }
 
     Ok(())
 }
 
-// Download the model from the given url
-fn download_model(url: String) -> anyhow::Result<String> {
-    let url = Url::parse(&url)?;
-    let response = reqwest::blocking::get(url)?;
-
-    // let mut filename = String::new();
-    let (mut dest, fname) = {
-        let fname = response
-            .url()
-            .path_segments()
-            .and_then(std::iter::Iterator::last)
-            .and_then(|name| if name.is_empty() { None } else { Some(name) })
-            .ok_or(anyhow!("No filename found in the url to download"))?;
-        (File::create(fname)?, fname.to_string())
-    };
-
-    let content = response.bytes()?;
-    copy(&mut content.as_ref(), &mut dest)?;
-
-    Ok(fname)
+// Settings of a run, taking the preferred model and parameters of a saved prompt unless overridden
+fn apply_saved(
+    client: ClientArgs,
+    sampling: SamplingArgs,
+    system_prompt: Option<String>,
+    saved: Option<prompt::SavedPrompt>,
+) -> (ClientArgs, SamplingArgs, Option<String>) {
+    match saved {
+        Some(saved) => (
+            ClientArgs {
+                model_name: client.model_name.or(saved.model),
+                ..client
+            },
+            sampling.or(&saved.params),
+            system_prompt.or(saved.system_prompt),
+        ),
+        None => (client, sampling, system_prompt),
+    }
 }
@@ -0,0 +1,259 @@
+use crate::gguf::Gguf;
+use std::{collections::HashMap, fs, path::Path, process::Command};
+
+pub const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+// Rough allowance for the compute buffers and the runtime itself
+const OVERHEAD: u64 = 512 * 1024 * 1024;
+// The KV cache is kept in f16
+const KV_BYTES: u64 = 2;
+
+// Memory a model needs once loaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Estimate {
+    pub weights: u64,
+    pub kv_cache: u64,
+    pub overhead: u64,
+}
+impl Estimate {
+    pub fn total(&self) -> u64 {
+        self.weights + self.kv_cache + self.overhead
+    }
+
+    fn add(self, other: Estimate) -> Estimate {
+        Estimate {
+            weights: self.weights + other.weights,
+            kv_cache: self.kv_cache + other.kv_cache,
+            overhead: self.overhead + other.overhead,
+        }
+    }
+}
+
+// The weights take the size of the file whatever their quantization, the KV cache grows with
+// the layers, the KV heads and the context
+pub fn estimate(path: &Path, context_size: u64) -> anyhow::Result<Estimate> {
+    let weights = fs::metadata(path)?.len();
+    // whisper models are not gguf, their state is small
+    let Ok(gguf) = Gguf::read(path) else {
+        return Ok(Estimate {
+            weights,
+            kv_cache: 0,
+            overhead: OVERHEAD,
+        });
+    };
+
+    Ok(Estimate {
+        weights,
+        kv_cache: kv_cache(&gguf, context_size).unwrap_or(0),
+        overhead: OVERHEAD,
+    })
+}
+
+pub fn kv_cache(gguf: &Gguf, context_size: u64) -> Option<u64> {
+    let layers = gguf.arch_u64("block_count")?;
+    let embedding = gguf.arch_u64("embedding_length")?;
+    let heads = gguf.arch_u64("attention.head_count")?.max(1);
+    // grouped-query attention shares the KV heads
+    let kv_heads = gguf.arch_u64("attention.head_count_kv").unwrap_or(heads);
+    let key_length = gguf
+        .arch_u64("attention.key_length")
+        .unwrap_or(embedding / heads);
+    let value_length = gguf
+        .arch_u64("attention.value_length")
+        .unwrap_or(embedding / heads);
+
+    Some(layers * context_size * kv_heads * (key_length + value_length) * KV_BYTES)
+}
+
+// Memory that can be taken without swapping
+#[cfg(target_os = "linux")]
+pub fn available() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kib = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+// The whole memory, shared with the GPU on Apple silicon
+#[cfg(target_os = "macos")]
+pub fn available() -> Option<u64> {
+    let output = Command::new("sysctl")
+        .args(["-n", "hw.memsize"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn available() -> Option<u64> {
+    None
+}
+
+// Free memory of the discrete GPUs, from nvidia-smi or rocm-smi
+pub fn free_vram() -> Option<u64> {
+    gpu().and_then(|(backend, free)| (backend != "Metal").then_some(free))
+}
+
+// GPU memory used by each process, from nvidia-smi, empty without a CUDA GPU
+pub fn vram_by_pid() -> HashMap<u32, u64> {
+    let Ok(output) = Command::new("nvidia-smi")
+        .args([
+            "--query-compute-apps=pid,used_memory",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+    else {
+        return HashMap::new();
+    };
+    let mut used = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((pid, mib)) = line.split_once(',') else {
+            continue;
+        };
+        if let (Ok(pid), Ok(mib)) = (pid.trim().parse::<u32>(), mib.trim().parse::<u64>()) {
+            // MiB, summed over the GPUs the process uses
+            *used.entry(pid).or_default() += mib * 1024 * 1024;
+        }
+    }
+    used
+}
+
+// The GPU backend and its free memory: CUDA from nvidia-smi, ROCm from rocm-smi, or Metal
+// sharing the memory of Apple silicon
+pub fn gpu() -> Option<(&'static str, u64)> {
+    if let Ok(output) = Command::new("nvidia-smi")
+        .args(["--query-gpu=memory.free", "--format=csv,noheader,nounits"])
+        .output()
+    {
+        if output.status.success() {
+            // MiB per GPU
+            let mib = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.trim().parse::<u64>().ok())
+                .sum::<u64>();
+            return Some(("CUDA", mib * 1024 * 1024));
+        }
+    }
+    if let Ok(output) = Command::new("rocm-smi")
+        .args(["--showmeminfo", "vram", "--json"])
+        .output()
+    {
+        if output.status.success() {
+            let cards: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+            let field = |card: &serde_json::Value, name: &str| {
+                card[name]
+                    .as_str()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(0)
+            };
+            let free = cards
+                .as_object()?
+                .values()
+                .map(|card| {
+                    field(card, "VRAM Total Memory (B)")
+                        .saturating_sub(field(card, "VRAM Total Used Memory (B)"))
+                })
+                .sum();
+            return Some(("ROCm", free));
+        }
+    }
+    // Metal lets the GPU take about three quarters of the memory
+    if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        return available().map(|total| ("Metal", total / 4 * 3));
+    }
+
+    None
+}
+
+// How many layers of the model fit in the free memory of the GPU, keeping `headroom` bytes
+// free. Returns the layers, all of them plus the output layer when everything fits, and the
+// decision to print.
+pub fn gpu_layers(path: &Path, context_size: u64, headroom: u64) -> anyhow::Result<(u64, String)> {
+    let gguf = Gguf::read(path)?;
+    let layers = gguf
+        .arch_u64("block_count")
+        .ok_or(anyhow::anyhow!(
+            "{} does not say how many layers it has",
+            path.display()
+        ))?
+        .max(1);
+    let Some((backend, free)) = gpu() else {
+        return Ok((0, "No GPU found, running on the CPU".to_string()));
+    };
+    let weights = fs::metadata(path)?.len();
+    let kv_cache = kv_cache(&gguf, context_size).unwrap_or(0);
+    // the output layer is about as large as a block
+    let per_layer = (weights / (layers + 1) + kv_cache / layers).max(1);
+    let budget = free.saturating_sub(headroom + OVERHEAD);
+    let fit = budget / per_layer;
+
+    let decision = |offloaded: u64| {
+        format!(
+            "Offloading {} of {} layers to the GPU ({}, {:.1} GiB free, {:.1} GiB kept free)",
+            offloaded.min(layers),
+            layers,
+            backend,
+            free as f64 / GIB,
+            headroom as f64 / GIB
+        )
+    };
+    match fit > layers {
+        true => Ok((layers + 1, decision(layers))),
+        false => Ok((fit, decision(fit))),
+    }
+}
+
+// Estimate the memory of the models, given with their context sizes
+pub fn estimate_all(models: &[(&Path, u64)]) -> anyhow::Result<Estimate> {
+    let mut total = Estimate::default();
+    for (path, context_size) in models {
+        total = total.add(estimate(path, *context_size)?);
+    }
+
+    Ok(total)
+}
+
+// Why the machine cannot hold the models, when it has less memory than they need
+pub fn shortfall(total: &Estimate) -> Option<String> {
+    // layers offloaded to the GPU leave the memory
+    let available = available()? + free_vram().unwrap_or(0);
+    tracing::info!(
+        needed = total.total(),
+        available,
+        weights = total.weights,
+        kv_cache = total.kv_cache,
+        "memory estimate"
+    );
+    if total.total() <= available {
+        return None;
+    }
+
+    Some(format!(
+        "The models need about {:.1} GiB ({:.1} GiB of weights, {:.1} GiB of KV cache), only {:.1} GiB is available. Lower --context-size or pick a smaller quantization.",
+        total.total() as f64 / GIB,
+        total.weights as f64 / GIB,
+        total.kv_cache as f64 / GIB,
+        available as f64 / GIB
+    ))
+}
+
+pub fn describe(estimate: &Estimate) -> String {
+    let mut line = format!(
+        "~{:.1} GiB ({:.1} GiB of weights, {:.1} GiB of KV cache)",
+        estimate.total() as f64 / GIB,
+        estimate.weights as f64 / GIB,
+        estimate.kv_cache as f64 / GIB
+    );
+    if let Some(available) = available() {
+        line.push_str(&format!(", {:.1} GiB available", available as f64 / GIB));
+    }
+    if let Some(vram) = free_vram() {
+        line.push_str(&format!(" and {:.1} GiB of free VRAM", vram as f64 / GIB));
+    }
+    line
+}
@@ -0,0 +1,337 @@
+use crate::config;
+use crate::events;
+use crate::models;
+use crate::node::{self, NodeConfig};
+use crate::paths;
+use crate::preset::{self, Preset};
+use crate::qdrant::{QdrantArgs, DEFAULT_QDRANT_URL};
+use crate::rag::{self, PolicyArgs, RagArgs};
+use crate::start;
+use crate::template::PromptTemplateType;
+use anyhow::{anyhow, bail};
+use console::style;
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// Name of the preset keeping the system prompt of the imported node
+const PRESET: &str = "gaianet";
+
+// Settings of gaianet-node that have no counterpart in gaia
+const NOT_CARRIED: &[&str] = &[
+    "domain",
+    "rag_prompt",
+    "rag_policy",
+    "chat_batch_size",
+    "embedding_batch_size",
+];
+
+// Where gaianet-node installs itself unless told otherwise
+fn default_dir() -> anyhow::Result<PathBuf> {
+    Ok(paths::home()?.join("gaianet"))
+}
+
+// The gaianet-node install in its default directory, if there is one
+pub fn found() -> Option<PathBuf> {
+    default_dir()
+        .ok()
+        .filter(|dir| dir.join("config.json").is_file())
+}
+
+// A setting of config.json, gaianet-node writes numbers as strings and unset ones as ""
+fn setting(config: &Value, key: &str) -> Option<String> {
+    match &config[key] {
+        Value::String(value) if !value.trim().is_empty() => Some(value.trim().to_string()),
+        Value::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn number<T: std::str::FromStr>(config: &Value, key: &str) -> anyhow::Result<Option<T>> {
+    setting(config, key)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| anyhow!("config.json: {} is not a number: '{}'", key, value))
+        })
+        .transpose()
+}
+
+// Link a model gaianet-node already downloaded into the models directory, so it is not fetched
+// again. Returns what the node file names the model by: its path, or the url when it is not on
+// disk.
+fn adopt(dir: &Path, url: &str) -> anyhow::Result<String> {
+    let name = start::url_file_name(url)?;
+    let src = dir.join(&name);
+    if !src.is_file() {
+        return Ok(url.to_string());
+    }
+    let models_dir = paths::models_dir()?;
+    fs::create_dir_all(&models_dir)?;
+    let dest = models_dir.join(&name);
+    if !models::same_file(&src, &dest) {
+        if dest.exists() {
+            fs::remove_file(&dest)?;
+        }
+        let how = models::link(&src, &dest)?;
+        println!("{} {} from {}", how, name, dir.display());
+    }
+    models::record(&dest, url)?;
+
+    Ok(dest.display().to_string())
+}
+
+// The snapshot gaianet-node downloaded for the url, next to its config or where it hands
+// snapshots to Qdrant. Archives are unpacked there, the unpacked file is the one looked for.
+fn snapshot_file(dir: &Path, url: &str, collection: &str) -> Option<PathBuf> {
+    let name = start::url_file_name(url).ok()?;
+    let name = name.strip_suffix(".tar.gz").unwrap_or(&name);
+    [
+        dir.join(name),
+        dir.join("qdrant/snapshots").join(collection).join(name),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+// Copy the keys and device id of the node, so it keeps its address
+fn keep_identity(dir: &Path) -> anyhow::Result<Option<String>> {
+    let Ok(content) = fs::read_to_string(dir.join("nodeid.json")) else {
+        return Ok(None);
+    };
+    let nodeid: Value =
+        serde_json::from_str(&content).map_err(|e| anyhow!("nodeid.json: {}", e))?;
+    let mut files = vec!["nodeid.json".to_string()];
+    files.extend(nodeid["keystore"].as_str().map(String::from));
+    files.extend(["deviceid.txt".to_string(), "frpc.toml".to_string()]);
+
+    let identity = paths::identity_dir()?;
+    fs::create_dir_all(&identity)?;
+    for file in files {
+        let src = dir.join(&file);
+        if src.is_file() {
+            fs::copy(&src, identity.join(&file))?;
+        }
+    }
+
+    Ok(Some(
+        nodeid["address"]
+            .as_str()
+            .unwrap_or("unknown address")
+            .to_string(),
+    ))
+}
+
+// Turn a gaianet-node install into a gaia node: its config.json into config.toml, its
+// downloaded models linked into the models directory, its knowledge base loaded into Qdrant,
+// its system prompt into a preset and its keys kept
+pub fn command_migrate(
+    from: Option<PathBuf>,
+    prompt_template: Option<PromptTemplateType>,
+    force: bool,
+) -> anyhow::Result<()> {
+    let dir = match from {
+        Some(dir) => dir,
+        None => default_dir()?,
+    };
+    let content = fs::read_to_string(dir.join("config.json")).map_err(|_| {
+        anyhow!(
+            "No gaianet-node install found in {}, give its directory with --from",
+            dir.display()
+        )
+    })?;
+    let gaianet: Value =
+        serde_json::from_str(&content).map_err(|e| anyhow!("config.json: {}", e))?;
+
+    let chat = setting(&gaianet, "chat").ok_or(anyhow!("config.json has no chat model"))?;
+    let template = match prompt_template {
+        Some(template) => template,
+        None => {
+            let name = setting(&gaianet, "prompt_template")
+                .ok_or(anyhow!("config.json has no prompt_template"))?;
+            node::parse_template(&name).map_err(|e| {
+                anyhow!(
+                    "config.json: {}, pick the template to use with --prompt-template",
+                    e
+                )
+            })?
+        }
+    };
+    let config_dir = paths::config_dir()?;
+    let path = config_dir.join("config.toml");
+    if !force {
+        if let Some(existing) = config::DEFAULT_FILES
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.is_file())
+        {
+            bail!(
+                "{} exists already, use --force to replace it",
+                existing.display()
+            );
+        }
+    }
+
+    println!(
+        "{}",
+        style(format!(
+            "Importing the gaianet-node install in {}",
+            dir.display()
+        ))
+        .bold()
+    );
+    let chat_model = adopt(&dir, &chat)?;
+    let mut node = toml::Table::new();
+    node.insert("version".into(), toml::Value::Integer(1));
+    if let Some(port) = number::<u16>(&gaianet, "llamaedge_port")? {
+        node.insert("port".into(), toml::Value::Integer(port.into()));
+    }
+    let mut chat_table = toml::Table::new();
+    chat_table.insert("model".into(), toml::Value::String(chat_model.clone()));
+    chat_table.insert(
+        "prompt_template".into(),
+        toml::Value::String(template.to_string()),
+    );
+    if let Some(name) = setting(&gaianet, "chat_name") {
+        chat_table.insert("name".into(), toml::Value::String(name));
+    }
+    if let Some(context_size) = number::<u32>(&gaianet, "chat_ctx_size")? {
+        chat_table.insert(
+            "context_size".into(),
+            toml::Value::Integer(context_size.into()),
+        );
+    }
+    if let Some(reverse_prompt) = setting(&gaianet, "reverse_prompt") {
+        chat_table.insert("reverse_prompt".into(), toml::Value::String(reverse_prompt));
+    }
+    node.insert("chat".into(), toml::Value::Table(chat_table));
+    if let Some(embedding) = setting(&gaianet, "embedding") {
+        let mut embedding_table = toml::Table::new();
+        embedding_table.insert(
+            "model".into(),
+            toml::Value::String(adopt(&dir, &embedding)?),
+        );
+        if let Some(name) = setting(&gaianet, "embedding_name") {
+            embedding_table.insert("name".into(), toml::Value::String(name));
+        }
+        if let Some(context_size) = number::<u32>(&gaianet, "embedding_ctx_size")? {
+            embedding_table.insert(
+                "context_size".into(),
+                toml::Value::Integer(context_size.into()),
+            );
+        }
+        node.insert("embedding".into(), toml::Value::Table(embedding_table));
+    }
+
+    fs::create_dir_all(&config_dir)?;
+    fs::write(&path, toml::to_string_pretty(&node)?)?;
+    NodeConfig::load(&path)?;
+    println!("Wrote {}", path.display());
+
+    // the node file has no system prompt, a preset carries it into chats
+    if let Some(system_prompt) = setting(&gaianet, "system_prompt") {
+        let preset = Preset {
+            description: setting(&gaianet, "description"),
+            model: Some(chat_model),
+            model_name: setting(&gaianet, "chat_name"),
+            prompt_template: Some(template.to_string()),
+            system_prompt: Some(system_prompt),
+            ..Default::default()
+        };
+        preset::command_create(PRESET, preset, true)?;
+        if preset::current()?.is_none() {
+            preset::command_use(PRESET)?;
+        }
+    }
+
+    if let Some(address) = keep_identity(&dir)? {
+        println!(
+            "Kept the keys of node {} in {}",
+            address,
+            paths::identity_dir()?.display()
+        );
+    }
+
+    if let Some(url) = setting(&gaianet, "snapshot") {
+        let collection = setting(&gaianet, "embedding_collection_name")
+            .unwrap_or(rag::DEFAULT_COLLECTION.to_string());
+        let rag = RagArgs {
+            collection: collection.clone(),
+            vector_store: None,
+            qdrant: QdrantArgs {
+                url: env::var("QDRANT_URL").unwrap_or(DEFAULT_QDRANT_URL.to_string()),
+                api_key: env::var("QDRANT_API_KEY").ok(),
+                ca_cert: None,
+            },
+        };
+        match snapshot_file(&dir, &url, &collection) {
+            Some(file) => import(rag, &file, &gaianet)?,
+            None => println!(
+                "{}",
+                style(format!(
+                    "The knowledge base was not found on disk, load it with `gaia rag import-snapshot` once downloaded from {}",
+                    url
+                ))
+                .yellow()
+            ),
+        }
+    }
+
+    let left = NOT_CARRIED
+        .iter()
+        .filter(|key| setting(&gaianet, key).is_some())
+        .copied()
+        .collect::<Vec<_>>();
+    if !left.is_empty() {
+        println!(
+            "{}",
+            style(format!("Not carried over: {}", left.join(", "))).dim()
+        );
+    }
+    events::emit(
+        "gaianet-migrated",
+        json!({ "from": dir.display().to_string(), "file": path.display().to_string() }),
+    );
+    println!("Start the node with `gaia serve`");
+
+    Ok(())
+}
+
+// Load the knowledge base into Qdrant with the retrieval settings of the node. Qdrant not
+// running leaves the command to load it later.
+fn import(rag: RagArgs, file: &Path, gaianet: &Value) -> anyhow::Result<()> {
+    let exists = rag::open_store(&rag).and_then(|store| store.collection_exists(&rag.collection));
+    match exists {
+        Ok(true) => println!(
+            "Collection '{}' is already in Qdrant, kept it",
+            rag.collection
+        ),
+        Ok(false) => rag::command_import_snapshot(rag.clone(), file)?,
+        Err(e) => {
+            println!(
+                "{} {}",
+                style("Could not load the knowledge base:").yellow(),
+                e
+            );
+            println!(
+                "Load it once Qdrant runs with `gaia rag import-snapshot {} --collection {}`",
+                file.display(),
+                rag.collection
+            );
+            return Ok(());
+        }
+    }
+
+    let policy = PolicyArgs {
+        top_k: number(gaianet, "qdrant_limit")?,
+        min_score: number(gaianet, "qdrant_score_threshold")?,
+        no_hit: None,
+    };
+    if policy.top_k.is_some() || policy.min_score.is_some() {
+        rag::command_policy(rag, policy)?;
+    }
+
+    Ok(())
+}
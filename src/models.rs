@@ -0,0 +1,433 @@
+use crate::ollama;
+use crate::paths;
+use crate::progress::{Progress, ProgressReader};
+use console::style;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+// Where the downloaded models came from, in the models directory
+const MANIFEST: &str = "manifest.json";
+
+// How deep to look for ggufs in another app's directory, LM Studio nests them as
+// `<publisher>/<repository>/<file>.gguf`
+const MAX_DEPTH: usize = 4;
+
+// Where a model on disk came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Gaia,
+    CurrentDir,
+    // a directory of another app, or one in `$GAIA_MODEL_DIRS`
+    Extra,
+    Ollama,
+}
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Gaia => write!(f, "gaia"),
+            Origin::CurrentDir => write!(f, "."),
+            Origin::Extra => write!(f, "extra"),
+            Origin::Ollama => write!(f, "ollama"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalModel {
+    // what `gaia start -m` takes: a path, or an ollama:// url
+    pub model: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub origin: Origin,
+}
+
+// Directories of other apps with ggufs, followed by those of `$GAIA_MODEL_DIRS`
+pub fn extra_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(home) = paths::home() {
+        for dir in [
+            // LM Studio, before and after 0.3
+            ".cache/lm-studio/models",
+            ".lmstudio/models",
+            // Jan
+            "jan/models",
+            "Library/Application Support/Jan/data/models",
+            "AppData/Roaming/Jan/data/models",
+        ] {
+            dirs.push(home.join(dir));
+        }
+    }
+    dirs.retain(|dir| dir.is_dir());
+    // separated like PATH
+    if let Some(value) = env::var_os("GAIA_MODEL_DIRS") {
+        dirs.extend(env::split_paths(&value).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs.dedup();
+    dirs
+}
+
+// The ggufs under the directory, sorted
+fn ggufs(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return found;
+    };
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() && depth > 0 {
+            found.extend(ggufs(&path, depth - 1));
+        } else if path.extension().is_some_and(|ext| ext == "gguf") {
+            found.push(path);
+        }
+    }
+    found.sort();
+    found
+}
+
+// The ggufs in the models directory, in the current directory, in the extra directories and
+// in Ollama
+pub fn local_models() -> anyhow::Result<Vec<LocalModel>> {
+    let mut models = Vec::new();
+    let mut scanned = vec![
+        (paths::models_dir()?, 0, Origin::Gaia),
+        (PathBuf::from("."), 0, Origin::CurrentDir),
+    ];
+    scanned.extend(
+        extra_dirs()
+            .into_iter()
+            .map(|dir| (dir, MAX_DEPTH, Origin::Extra)),
+    );
+    for (dir, depth, origin) in scanned {
+        for path in ggufs(&dir, depth) {
+            let model = match path.strip_prefix(".") {
+                Ok(name) => name.display().to_string(),
+                Err(_) => path.display().to_string(),
+            };
+            models.push(LocalModel {
+                model,
+                path: path.clone(),
+                size: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+                origin,
+            });
+        }
+    }
+    // the models Ollama already downloaded
+    models.extend(
+        ollama::list()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|model| LocalModel {
+                model: format!("ollama://{}", model.name),
+                path: model.blob,
+                size: model.size,
+                origin: Origin::Ollama,
+            }),
+    );
+
+    Ok(models)
+}
+
+pub fn command_list() -> anyhow::Result<()> {
+    let models = local_models()?;
+    if models.is_empty() {
+        println!("No models found in {}", paths::models_dir()?.display());
+    }
+    for model in &models {
+        println!(
+            "{:<7} {:>6.1} GiB  {}",
+            model.origin.to_string(),
+            model.size as f64 / (1024.0 * 1024.0 * 1024.0),
+            model.model
+        );
+    }
+    if !models.is_empty() {
+        // files linked to one another are counted once
+        let listed = models.iter().map(|model| model.size).sum::<u64>();
+        let mut seen = HashSet::new();
+        let on_disk = models
+            .iter()
+            .filter(|model| seen.insert(file_id(&model.path).unwrap_or_default()))
+            .map(|model| model.size)
+            .sum::<u64>();
+        println!(
+            "{:.1} GiB on disk, {:.1} GiB without deduplication",
+            on_disk as f64 / (1024.0 * 1024.0 * 1024.0),
+            listed as f64 / (1024.0 * 1024.0 * 1024.0)
+        );
+    }
+    let extra = extra_dirs();
+    if !extra.is_empty() {
+        let extra = extra
+            .iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>();
+        println!(
+            "{}",
+            style(format!("Also scanned {}", extra.join(", "))).dim()
+        );
+    }
+
+    Ok(())
+}
+
+// A model in the manifest, keyed by its file name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub url: String,
+    pub size: u64,
+    // filled in once the file is hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // restrictive license accepted with --accept-license, and when
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license_accepted: Option<u64>,
+}
+
+pub fn manifest_path() -> anyhow::Result<PathBuf> {
+    Ok(paths::models_dir()?.join(MANIFEST))
+}
+
+pub fn manifest() -> anyhow::Result<BTreeMap<String, Entry>> {
+    let path = manifest_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Remember the url a model in the models directory was fetched from
+pub fn record(path: &Path, url: &str) -> anyhow::Result<()> {
+    let Some(name) = path.file_name() else {
+        return Ok(());
+    };
+    let mut manifest = manifest()?;
+    manifest.insert(
+        name.to_string_lossy().to_string(),
+        Entry {
+            url: url.to_string(),
+            size: fs::metadata(path)?.len(),
+            sha256: None,
+            license: None,
+            license_accepted: None,
+        },
+    );
+    save(&manifest)
+}
+
+// The file in the models directory already fetched from the url, whole, as the manifest
+// recorded it
+pub fn downloaded(url: &str) -> anyhow::Result<Option<PathBuf>> {
+    let dir = paths::models_dir()?;
+    Ok(manifest()?
+        .into_iter()
+        .filter(|(_, entry)| entry.url == url)
+        .map(|(name, entry)| (dir.join(name), entry.size))
+        .find(|(path, size)| fs::metadata(path).is_ok_and(|meta| meta.len() == *size))
+        .map(|(path, _)| path))
+}
+
+// Keep the acceptance of the license of a model for audits
+pub fn record_license(path: &Path, license: &str, accepted: u64) -> anyhow::Result<()> {
+    let Some(name) = path.file_name() else {
+        return Ok(());
+    };
+    let mut manifest = manifest()?;
+    if let Some(entry) = manifest.get_mut(&*name.to_string_lossy()) {
+        entry.license = Some(license.to_string());
+        entry.license_accepted = Some(accepted);
+        save(&manifest)?;
+    }
+
+    Ok(())
+}
+
+pub fn save(manifest: &BTreeMap<String, Entry>) -> anyhow::Result<()> {
+    fs::write(manifest_path()?, serde_json::to_string_pretty(manifest)?)?;
+
+    Ok(())
+}
+
+// Share the file at another path: a hard link costs no space, a symlink works across disks.
+// Returns how it was shared.
+pub fn link(src: &Path, dest: &Path) -> anyhow::Result<&'static str> {
+    if fs::hard_link(src, dest).is_ok() {
+        return Ok("Linked");
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(src, dest)?;
+        Ok("Symlinked")
+    }
+    #[cfg(windows)]
+    {
+        fs::copy(src, dest)?;
+        Ok("Copied")
+    }
+}
+
+// Identifies the file a path links to, the same for all its hard links and symlinks
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).ok()?;
+        Some((metadata.dev(), metadata.ino()))
+    }
+    #[cfg(windows)]
+    {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        fs::canonicalize(path).ok()?.hash(&mut hasher);
+        Some((0, hasher.finish()))
+    }
+}
+
+// Whether both paths are links to the same file
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((file_id(a), file_id(b)), (Some(a), Some(b)) if a == b)
+}
+
+// sha256 of the file, with progress as it can take a while
+pub fn digest_file(path: &Path) -> anyhow::Result<String> {
+    let file = File::open(path)?;
+    let total = file.metadata()?.len();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut reader = ProgressReader::new(
+        BufReader::new(file),
+        Progress::new("hash", &name, "bytes", Some(total)),
+    );
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    reader.finish();
+
+    Ok(hex(&hasher.finalize()))
+}
+
+// sha256 of a model, kept in the manifest for the models of the models directory so they are
+// hashed once
+pub fn digest(path: &Path) -> anyhow::Result<String> {
+    let size = fs::metadata(path)?.len();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let in_models_dir = path.parent() == Some(paths::models_dir()?.as_path());
+    let mut manifest = manifest()?;
+    if let Some(Entry {
+        sha256: Some(sha256),
+        size: known,
+        ..
+    }) = manifest.get(&name)
+    {
+        if in_models_dir && *known == size {
+            return Ok(sha256.clone());
+        }
+    }
+
+    let digest = digest_file(path)?;
+    if let Some(entry) = manifest.get_mut(&name).filter(|_| in_models_dir) {
+        entry.size = size;
+        entry.sha256 = Some(digest.clone());
+        save(&manifest)?;
+    }
+
+    Ok(digest)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+// Replace the ggufs of the models directory having the same content by links to one copy.
+// Only files of the same size are hashed, `only` restricts it to the duplicates of a file.
+// Returns the bytes freed.
+pub fn dedup(only: Option<&Path>) -> anyhow::Result<u64> {
+    let dir = paths::models_dir()?;
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for path in ggufs(&dir, 0) {
+        // symlinks already share their target
+        if fs::symlink_metadata(&path)?.is_symlink() {
+            continue;
+        }
+        by_size
+            .entry(fs::metadata(&path)?.len())
+            .or_default()
+            .push(path);
+    }
+    let only_size = match only {
+        Some(path) => Some(fs::metadata(path)?.len()),
+        None => None,
+    };
+
+    let mut freed = 0;
+    for (size, paths) in by_size {
+        if paths.len() < 2 || size == 0 || only_size.is_some_and(|only| only != size) {
+            continue;
+        }
+        // one hash per file, however many links it has
+        let mut by_digest: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        let mut digests: HashMap<(u64, u64), String> = HashMap::new();
+        for path in paths {
+            let id = file_id(&path).unwrap_or_default();
+            let digest = match digests.get(&id) {
+                Some(digest) => digest.clone(),
+                None => {
+                    let digest = digest(&path)?;
+                    digests.insert(id, digest.clone());
+                    digest
+                }
+            };
+            by_digest.entry(digest).or_default().push(path);
+        }
+
+        for (_, mut paths) in by_digest {
+            paths.sort();
+            let (keep, others) = paths.split_first().expect("grouped paths are not empty");
+            for path in others {
+                if same_file(keep, path) {
+                    continue;
+                }
+                // link beside it first so the model is never missing
+                let mut temp = path.as_os_str().to_owned();
+                temp.push(".dedup");
+                let temp = PathBuf::from(temp);
+                let _ = fs::remove_file(&temp);
+                link(keep, &temp)?;
+                fs::rename(&temp, path)?;
+                tracing::info!(path = %path.display(), keep = %keep.display(), "deduplicated");
+                freed += size;
+            }
+        }
+    }
+
+    Ok(freed)
+}
+
+pub fn command_dedup() -> anyhow::Result<()> {
+    let freed = dedup(None)?;
+    match freed {
+        0 => println!("No duplicate models found"),
+        _ => println!(
+            "Freed {:.1} GiB by linking identical models",
+            freed as f64 / (1024.0 * 1024.0 * 1024.0)
+        ),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1027 @@
+use crate::affinity::{self, Numa};
+use crate::client::ClientArgs;
+use crate::config;
+use crate::error::{fail, ErrorKind, Tag};
+use crate::events;
+use crate::filter::Filters;
+use crate::gateway::GatewayConfig;
+use crate::idle::ScheduleEntry;
+use crate::jinja;
+use crate::ollama;
+use crate::preflight::Check;
+use crate::qdrant::{QdrantArgs, DEFAULT_QDRANT_URL};
+use crate::rag::{self, NoHit, PolicyArgs, RagArgs, Site};
+use crate::server::{self, ModelKind};
+use crate::start::{self, StartArgs};
+use crate::store::VectorStoreKind;
+use crate::telemetry::OtlpConfig;
+use crate::template::{PromptTemplateType, EMBEDDED_TEMPLATE};
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use console::style;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+// Upgrades of the node file, one per version. When a key is renamed, add a migration moving
+// it to its new name rather than accepting both.
+const NODE_MIGRATIONS: &[config::Migration] = &[
+    // version 1 only stamps the version
+    |_| Ok(()),
+];
+
+// Everything a node runs, as described by `gaia serve -f node.yaml`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    // layout of the file, older layouts are upgraded by `NODE_MIGRATIONS`
+    #[serde(default, rename = "version")]
+    _version: u32,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    // WasmEdge version installed with `gaia runtime install`, so upgrading another node's
+    // runtime leaves this one alone
+    pub runtime: Option<String>,
+    // placement of the chat backend on multi-socket machines, see `gaia start --numa`
+    pub numa: Option<Numa>,
+    pub cpu_affinity: Option<String>,
+    pub chat: ChatModel,
+    pub embedding: Option<EmbeddingModel>,
+    pub whisper: Option<WhisperModel>,
+    pub rag: Option<RagConfig>,
+    // when `gaia daemon` stops and starts the services
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+    pub gateway: Option<GatewayConfig>,
+    // filter such as `gaia=debug` for the daemon's log, -v, -q and GAIA_LOG win over it
+    pub log_level: Option<String>,
+    // collector the daemon exports its spans to, OTEL_EXPORTER_OTLP_ENDPOINT without one
+    pub otlp: Option<OtlpConfig>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChatModel {
+    // url or path, relative to the node file
+    pub model: String,
+    #[serde(deserialize_with = "deserialize_template")]
+    pub prompt_template: PromptTemplateType,
+    // Jinja file the gateway renders the prompts with in place of the prompt template, which
+    // the api-server keeps for the requests sent to it directly, relative to the node file, or
+    // `gguf` for the template in the model
+    pub chat_template: Option<String>,
+    pub name: Option<String>,
+    pub context_size: Option<u64>,
+    pub reverse_prompt: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingModel {
+    pub model: String,
+    pub name: Option<String>,
+    #[serde(default = "default_embedding_context_size")]
+    pub context_size: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WhisperModel {
+    pub model: String,
+    #[serde(default = "default_whisper_port")]
+    pub port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RagConfig {
+    #[serde(default, deserialize_with = "deserialize_vector_store")]
+    pub vector_store: Option<VectorStoreKind>,
+    #[serde(default = "default_qdrant_url")]
+    pub qdrant_url: String,
+    // `$NAME` reads the key from the environment, to keep it out of version control
+    pub qdrant_api_key: Option<String>,
+    pub qdrant_ca_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub collections: Vec<CollectionConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CollectionConfig {
+    pub name: String,
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub urls: Vec<String>,
+    #[serde(default = "default_depth")]
+    pub depth: usize,
+    #[serde(default = "default_max_pages")]
+    pub max_pages: usize,
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    pub top_k: Option<usize>,
+    pub min_score: Option<f32>,
+    #[serde(default, deserialize_with = "deserialize_no_hit")]
+    pub no_hit: Option<NoHit>,
+}
+
+impl CollectionConfig {
+    // The retrieval policy the file sets, if any
+    pub fn policy(&self) -> Option<PolicyArgs> {
+        let policy = PolicyArgs {
+            top_k: self.top_k,
+            min_score: self.min_score,
+            no_hit: self.no_hit,
+        };
+        (policy.top_k.is_some() || policy.min_score.is_some() || policy.no_hit.is_some())
+            .then_some(policy)
+    }
+}
+
+fn default_port() -> u16 {
+    start::DEFAULT_PORT
+}
+
+fn default_embedding_context_size() -> u64 {
+    start::DEFAULT_EMBEDDING_CONTEXT_SIZE
+}
+
+fn default_whisper_port() -> u16 {
+    start::DEFAULT_WHISPER_PORT
+}
+
+fn default_qdrant_url() -> String {
+    DEFAULT_QDRANT_URL.to_string()
+}
+
+fn default_depth() -> usize {
+    rag::DEFAULT_CRAWL_DEPTH
+}
+
+fn default_max_pages() -> usize {
+    rag::DEFAULT_MAX_PAGES
+}
+
+fn default_chunk_size() -> usize {
+    rag::DEFAULT_CHUNK_SIZE
+}
+
+// Accept the names of `--prompt-template` as well as the names the api-server uses
+pub fn parse_template(name: &str) -> Result<PromptTemplateType, String> {
+    <PromptTemplateType as FromStr>::from_str(name).or_else(|_| parse_enum("prompt template", name))
+}
+
+fn parse_enum<T: ValueEnum>(what: &str, name: &str) -> Result<T, String> {
+    T::from_str(name, true).map_err(|_| {
+        let names = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect::<Vec<_>>();
+        match config::closest(name, &names) {
+            Some(close) => format!("unknown {} '{}', did you mean '{}'?", what, name, close),
+            None => format!(
+                "unknown {} '{}', expected one of {}",
+                what,
+                name,
+                names.join(", ")
+            ),
+        }
+    })
+}
+
+pub fn deserialize_template<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<PromptTemplateType, D::Error> {
+    parse_template(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_vector_store<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<VectorStoreKind>, D::Error> {
+    parse_enum("vector store", &String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_no_hit<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NoHit>, D::Error> {
+    parse_enum("no_hit", &String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl NodeConfig {
+    // Read a node file, TOML or YAML depending on its extension. A file written for an older
+    // version is upgraded in memory only, `gaia config migrate` rewrites it.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let original: Value = config::load(path)?;
+        let mut node = original.clone();
+        let from = config::migrate(&mut node, NODE_MIGRATIONS)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let mut config: NodeConfig = match config::changed(&original, &node) {
+            false => config::load(path)?,
+            true => {
+                eprintln!(
+                    "{} is written for version {}, `gaia config migrate` upgrades it to {}",
+                    path.display(),
+                    from,
+                    NODE_MIGRATIONS.len()
+                );
+                serde_json::from_value(node).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            }
+        };
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        config.resolve_paths(dir);
+        config
+            .check()
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+        Ok(config)
+    }
+
+    // Make the paths in the file relative to the directory of the file
+    fn resolve_paths(&mut self, dir: &Path) {
+        let resolve = |model: &mut String| {
+            if !start::is_remote(model) && !ollama::is_ollama_url(model) {
+                *model = dir.join(&*model).display().to_string();
+            }
+        };
+        let resolve_template = |source: &mut String| {
+            if source != EMBEDDED_TEMPLATE {
+                *source = dir.join(&*source).display().to_string();
+            }
+        };
+        resolve(&mut self.chat.model);
+        if let Some(source) = &mut self.chat.chat_template {
+            resolve_template(source);
+        }
+        if let Some(embedding) = &mut self.embedding {
+            resolve(&mut embedding.model);
+        }
+        if let Some(whisper) = &mut self.whisper {
+            resolve(&mut whisper.model);
+        }
+        if let Some(gateway) = &mut self.gateway {
+            for model in &mut gateway.models {
+                resolve(&mut model.model);
+                if let Some(source) = &mut model.chat_template {
+                    resolve_template(source);
+                }
+            }
+            if let Some(log_dir) = gateway
+                .request_log
+                .as_mut()
+                .and_then(|log| log.dir.as_mut())
+            {
+                *log_dir = dir.join(&*log_dir);
+            }
+            for wasm in gateway.filters.iter_mut().filter_map(|f| f.wasm.as_mut()) {
+                *wasm = dir.join(&*wasm);
+            }
+        }
+        if let Some(rag) = &mut self.rag {
+            if let Some(cert) = &mut rag.qdrant_ca_cert {
+                *cert = dir.join(&*cert);
+            }
+            for collection in &mut rag.collections {
+                for path in &mut collection.paths {
+                    *path = dir.join(&*path);
+                }
+            }
+        }
+    }
+
+    // The collections of the file, each with the store it is kept in
+    pub fn collections(&self) -> anyhow::Result<Vec<(&CollectionConfig, RagArgs)>> {
+        let Some(rag_config) = &self.rag else {
+            return Ok(Vec::new());
+        };
+        let api_key = match &rag_config.qdrant_api_key {
+            Some(key) => match key.strip_prefix('$') {
+                Some(name) => Some(
+                    std::env::var(name)
+                        .map_err(|_| anyhow!("rag.qdrant_api_key: ${} is not set", name))?,
+                ),
+                None => Some(key.clone()),
+            },
+            None => None,
+        };
+
+        Ok(rag_config
+            .collections
+            .iter()
+            .map(|collection| {
+                let rag = RagArgs {
+                    collection: collection.name.clone(),
+                    vector_store: rag_config.vector_store,
+                    qdrant: QdrantArgs {
+                        url: rag_config.qdrant_url.clone(),
+                        api_key: api_key.clone(),
+                        ca_cert: rag_config.qdrant_ca_cert.clone(),
+                    },
+                };
+                (collection, rag)
+            })
+            .collect())
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if let Some(filter) = &self.log_level {
+            EnvFilter::try_new(filter).map_err(|e| anyhow!("log_level: {}", e))?;
+        }
+        if let Some(cpus) = &self.cpu_affinity {
+            affinity::parse_cpus(cpus).map_err(|e| anyhow!("cpu_affinity: {}", e))?;
+        }
+        if let Some(whisper) = &self.whisper {
+            if whisper.port == self.port {
+                bail!(
+                    "whisper.port and port are both {}, the servers need their own ports",
+                    self.port
+                );
+            }
+        }
+        if let Some(gateway) = &self.gateway {
+            if gateway.port == self.port {
+                bail!(
+                    "gateway.port and port are both {}, the gateway needs its own port",
+                    self.port
+                );
+            }
+            if let Some(ollama_port) = gateway.ollama_port {
+                if ollama_port == self.port || ollama_port == gateway.port {
+                    bail!(
+                        "gateway.ollama_port is {}, the port of a server already, the Ollama API needs its own port",
+                        ollama_port
+                    );
+                }
+            }
+            if let Some(request_log) = &gateway.request_log {
+                request_log.redactors()?;
+            }
+            Filters::new(&gateway.filters)?;
+            let limits = &gateway.limits;
+            if let (Some(default), Some(max)) = (limits.default_max_tokens, limits.max_tokens) {
+                if default > max {
+                    bail!(
+                        "gateway.limits.default_max_tokens is {}, over max_tokens of {}",
+                        default,
+                        max
+                    );
+                }
+            }
+            let mut key_names = Vec::new();
+            for name in gateway.api_keys.iter().filter_map(|key| key.name()) {
+                if key_names.contains(&name) {
+                    bail!("gateway.api_keys has two keys named '{}'", name);
+                }
+                key_names.push(name);
+            }
+            for rule in &gateway.routing {
+                rule.check()?;
+            }
+            let mut names = Vec::new();
+            for model in &gateway.models {
+                let name = model.name();
+                if names.contains(&name) {
+                    bail!(
+                        "gateway.models has two models named '{}', set `name` on one of them",
+                        name
+                    );
+                }
+                names.push(name);
+            }
+        }
+        if let Some(rag) = &self.rag {
+            if !rag.collections.is_empty() && self.embedding.is_none() {
+                bail!("rag.collections need an embedding model, add an `embedding` section");
+            }
+            for collection in &rag.collections {
+                if collection.paths.is_empty() && collection.urls.is_empty() {
+                    bail!(
+                        "rag collection '{}' has neither paths nor urls to ingest",
+                        collection.name
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The chat, embedding and whisper models, as urls or paths
+    pub fn models(&self) -> Vec<&str> {
+        let mut models = vec![self.chat.model.as_str()];
+        models.extend(self.embedding.as_ref().map(|e| e.model.as_str()));
+        models.extend(self.whisper.as_ref().map(|w| w.model.as_str()));
+        models
+    }
+
+    fn start_args(&self) -> StartArgs {
+        StartArgs {
+            model: Some(self.chat.model.clone()),
+            prompt_template: Some(self.chat.prompt_template),
+            chat_template: self.chat.chat_template.clone(),
+            reverse_prompt: self.chat.reverse_prompt.clone(),
+            context_size: self.chat.context_size,
+            port: self.port,
+            model_name: self.chat.name.clone(),
+            embedding_model: self.embedding.as_ref().map(|e| e.model.clone()),
+            embedding_model_name: self.embedding.as_ref().and_then(|e| e.name.clone()),
+            embedding_context_size: self
+                .embedding
+                .as_ref()
+                .map(|e| e.context_size)
+                .unwrap_or(start::DEFAULT_EMBEDDING_CONTEXT_SIZE),
+            whisper_model: self.whisper.as_ref().map(|w| w.model.clone()),
+            whisper_port: self
+                .whisper
+                .as_ref()
+                .map(|w| w.port)
+                .unwrap_or(start::DEFAULT_WHISPER_PORT),
+            local_only: self.gateway.is_some(),
+            dry_run: false,
+            strict_memory: false,
+            n_gpu_layers: None,
+            gpu_headroom: start::DEFAULT_GPU_HEADROOM,
+            device: None,
+            numa: self.numa,
+            cpu_affinity: self.cpu_affinity.clone(),
+            runtime: self.runtime.clone(),
+            yes: false,
+            startup_timeout: server::DEFAULT_STARTUP_TIMEOUT,
+            preset: None,
+            service: None,
+        }
+    }
+}
+
+// Bring up the node described by the file, then ingest its collections.
+// Running it again on a started node only brings the collections up to date.
+pub fn command_serve(file: Option<PathBuf>, yes: bool) -> anyhow::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => config::default_path()?,
+    };
+    let config = NodeConfig::load(&file).tag(ErrorKind::Config)?;
+    let collections = config.collections()?;
+
+    let base_url = format!("http://localhost:{}/v1", config.port);
+    match server::load(server::API_SERVER)? {
+        Some(state) => {
+            // downloaded models are served from a local copy, compare them by file name
+            let chat = state.models.iter().find(|m| m.kind == ModelKind::Chat);
+            if state.port != config.port
+                || chat.map(|m| server::model_name(&m.path))
+                    != Some(server::model_name(&config.chat.model))
+            {
+                bail!(
+                    "{} is already running with another setup, stop it with `gaia stop` first",
+                    state.name
+                );
+            }
+            println!("{} is already running (pid {})", state.name, state.pid);
+            if !collections.is_empty() {
+                let timeout = Duration::from_secs(server::DEFAULT_STARTUP_TIMEOUT);
+                server::wait_ready(&state, timeout)?;
+            }
+        }
+        None => {
+            let checks = collections
+                .iter()
+                .map(|(collection, rag)| collection_check(collection, rag))
+                .collect();
+            let mut args = config.start_args();
+            args.yes = yes;
+            start::start(args, checks)?
+        }
+    }
+
+    if collections.is_empty() {
+        return Ok(());
+    }
+
+    let embedding_model = server::load(server::API_SERVER)?.and_then(|state| {
+        state
+            .models
+            .into_iter()
+            .find(|model| model.kind == ModelKind::Embedding)
+            .map(|model| model.name)
+    });
+    for (collection, rag) in collections {
+        println!(
+            "{}",
+            style(format!("Syncing collection '{}'", collection.name)).bold()
+        );
+        let client = ClientArgs {
+            base_url: base_url.clone(),
+            model_name: embedding_model.clone(),
+        };
+        let sites = collection
+            .urls
+            .iter()
+            .map(|url| Site {
+                url: url.clone(),
+                depth: collection.depth,
+                max_pages: collection.max_pages,
+            })
+            .collect();
+        rag::command_ingest(
+            rag.clone(),
+            client,
+            collection.paths.clone(),
+            sites,
+            collection.chunk_size,
+        )?;
+
+        if let Some(policy) = collection.policy() {
+            rag::command_policy(rag, policy)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the vector store of the collection can be reached before anything is started
+fn collection_check(collection: &CollectionConfig, rag: &RagArgs) -> Check {
+    let exists = rag::open_store(rag).and_then(|store| store.collection_exists(&collection.name));
+    match exists {
+        Ok(true) => Check::new("collection", &collection.name),
+        Ok(false) => Check::new(
+            "collection",
+            format!("{} (created when ingesting)", collection.name),
+        ),
+        Err(e) => Check::new("collection", &collection.name).result(Err(e)),
+    }
+}
+
+// What a value in the node file must be
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Port,
+    Count,
+    Score,
+    Texts,
+    // url, or path to a file relative to the node file
+    Model,
+    File,
+    Files,
+    Template,
+    // `gguf`, or a Jinja file relative to the node file
+    ChatTemplate,
+    VectorStore,
+    NoHit,
+    Numa,
+    Cpus,
+    Section,
+    Sections,
+}
+
+// (key, kind, required) of each part of the node file
+const NODE_FIELDS: &[(&str, Kind, bool)] = &[
+    ("version", Kind::Count, false),
+    ("port", Kind::Port, false),
+    ("runtime", Kind::Text, false),
+    ("numa", Kind::Numa, false),
+    ("cpu_affinity", Kind::Cpus, false),
+    ("chat", Kind::Section, true),
+    ("embedding", Kind::Section, false),
+    ("whisper", Kind::Section, false),
+    ("rag", Kind::Section, false),
+    ("schedule", Kind::Sections, false),
+    ("gateway", Kind::Section, false),
+    ("log_level", Kind::Text, false),
+    ("otlp", Kind::Section, false),
+];
+const CHAT_FIELDS: &[(&str, Kind, bool)] = &[
+    ("model", Kind::Model, true),
+    ("prompt_template", Kind::Template, true),
+    ("chat_template", Kind::ChatTemplate, false),
+    ("name", Kind::Text, false),
+    ("context_size", Kind::Count, false),
+    ("reverse_prompt", Kind::Text, false),
+];
+const EMBEDDING_FIELDS: &[(&str, Kind, bool)] = &[
+    ("model", Kind::Model, true),
+    ("name", Kind::Text, false),
+    ("context_size", Kind::Count, false),
+];
+const WHISPER_FIELDS: &[(&str, Kind, bool)] =
+    &[("model", Kind::Model, true), ("port", Kind::Port, false)];
+const RAG_FIELDS: &[(&str, Kind, bool)] = &[
+    ("vector_store", Kind::VectorStore, false),
+    ("qdrant_url", Kind::Text, false),
+    ("qdrant_api_key", Kind::Text, false),
+    ("qdrant_ca_cert", Kind::File, false),
+    ("collections", Kind::Sections, false),
+];
+const COLLECTION_FIELDS: &[(&str, Kind, bool)] = &[
+    ("name", Kind::Text, true),
+    ("paths", Kind::Files, false),
+    ("urls", Kind::Texts, false),
+    ("depth", Kind::Count, false),
+    ("max_pages", Kind::Count, false),
+    ("chunk_size", Kind::Count, false),
+    ("top_k", Kind::Count, false),
+    ("min_score", Kind::Score, false),
+    ("no_hit", Kind::NoHit, false),
+];
+
+// Everything wrong with a node file, rather than only the first problem
+pub fn validate(path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut node: Value = match config::load(path) {
+        Ok(node) => node,
+        Err(e) => return Ok(vec![e.to_string()]),
+    };
+    // check the file as `gaia serve` would see it after upgrading it
+    if let Err(e) = config::migrate(&mut node, NODE_MIGRATIONS) {
+        return Ok(vec![format!("version: {}", e)]);
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let mut problems = Vec::new();
+    check_fields(&node, "", NODE_FIELDS, dir, &mut problems);
+    for (section, fields) in [
+        ("chat", CHAT_FIELDS),
+        ("embedding", EMBEDDING_FIELDS),
+        ("whisper", WHISPER_FIELDS),
+        ("rag", RAG_FIELDS),
+    ] {
+        if node[section].is_object() {
+            check_fields(&node[section], section, fields, dir, &mut problems);
+        }
+    }
+
+    let collections = node["rag"]["collections"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mut names = Vec::new();
+    for (i, collection) in collections.iter().enumerate() {
+        let at = format!("rag.collections[{}]", i);
+        if !collection.is_object() {
+            continue;
+        }
+        check_fields(collection, &at, COLLECTION_FIELDS, dir, &mut problems);
+        if collection["paths"].as_array().is_none_or(Vec::is_empty)
+            && collection["urls"].as_array().is_none_or(Vec::is_empty)
+        {
+            problems.push(format!("{}: add `paths` or `urls` to ingest", at));
+        }
+        if let Some(name) = collection["name"].as_str() {
+            if names.contains(&name) {
+                problems.push(format!("{}: collection '{}' is listed twice", at, name));
+            }
+            names.push(name);
+        }
+    }
+    if !collections.is_empty() && node.get("embedding").is_none() {
+        problems.push(
+            "rag.collections: collections need an embedding model, add an `embedding` section"
+                .to_string(),
+        );
+    }
+
+    let port = node["port"].as_u64().unwrap_or(start::DEFAULT_PORT as u64);
+    if node["whisper"].is_object() {
+        let whisper_port = node["whisper"]["port"]
+            .as_u64()
+            .unwrap_or(start::DEFAULT_WHISPER_PORT as u64);
+        if whisper_port == port {
+            problems.push(format!(
+                "whisper.port: {} is also the port of the api-server, give whisper its own port",
+                port
+            ));
+        }
+    }
+
+    // the sections without a table above are checked as `gaia serve` reads them
+    if let Some(gateway) = node.get("gateway") {
+        if let Err(e) = serde_json::from_value::<GatewayConfig>(gateway.clone()) {
+            problems.push(format!("gateway: {}", e));
+        }
+    }
+    if let Some(schedule) = node.get("schedule") {
+        if let Err(e) = serde_json::from_value::<Vec<ScheduleEntry>>(schedule.clone()) {
+            problems.push(format!("schedule: {}", e));
+        }
+    }
+    if let Some(otlp) = node.get("otlp") {
+        if let Err(e) = serde_json::from_value::<OtlpConfig>(otlp.clone()) {
+            problems.push(format!("otlp: {}", e));
+        }
+    }
+    if problems.is_empty() {
+        match serde_json::from_value::<NodeConfig>(node) {
+            Ok(mut config) => {
+                config.resolve_paths(dir);
+                if let Err(e) = config.check() {
+                    problems.push(e.to_string());
+                }
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+    }
+
+    Ok(problems)
+}
+
+fn check_fields(
+    value: &Value,
+    at: &str,
+    fields: &[(&str, Kind, bool)],
+    dir: &Path,
+    problems: &mut Vec<String>,
+) {
+    let key = |name: &str| match at {
+        "" => name.to_string(),
+        at => format!("{}.{}", at, name),
+    };
+    let Some(table) = value.as_object() else {
+        problems.push(format!("{}: expected a section of keys", at));
+        return;
+    };
+
+    let known = fields
+        .iter()
+        .map(|(name, _, _)| name.to_string())
+        .collect::<Vec<_>>();
+    for name in table.keys() {
+        if !known.contains(name) {
+            match config::closest(name, &known) {
+                Some(close) => problems.push(format!(
+                    "{}: unknown key, did you mean `{}`?",
+                    key(name),
+                    close
+                )),
+                None => problems.push(format!(
+                    "{}: unknown key, expected one of {}",
+                    key(name),
+                    known.join(", ")
+                )),
+            }
+        }
+    }
+
+    for (name, kind, required) in fields {
+        match table.get(*name) {
+            Some(field) => {
+                if let Err(problem) = check_kind(field, *kind, dir) {
+                    problems.push(format!("{}: {}", key(name), problem));
+                }
+            }
+            None if *required => problems.push(format!("{}: missing", key(name))),
+            None => {}
+        }
+    }
+}
+
+fn check_kind(value: &Value, kind: Kind, dir: &Path) -> Result<(), String> {
+    let text = || value.as_str().ok_or("expected a string".to_string());
+    let texts = || {
+        value
+            .as_array()
+            .filter(|items| items.iter().all(Value::is_string))
+            .map(|items| items.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+            .ok_or("expected a list of strings".to_string())
+    };
+    let exists = |file: &str| {
+        let path = dir.join(file);
+        match path.exists() {
+            true => Ok(()),
+            false => Err(format!("{} does not exist", path.display())),
+        }
+    };
+
+    match kind {
+        Kind::Text => text().map(drop),
+        Kind::Port => match value.as_u64() {
+            Some(1..=65535) => Ok(()),
+            _ => Err("expected a port between 1 and 65535".to_string()),
+        },
+        Kind::Count => match value.as_u64() {
+            Some(_) => Ok(()),
+            None => Err("expected a whole number".to_string()),
+        },
+        Kind::Score => match value.is_number() {
+            true => Ok(()),
+            false => Err("expected a number".to_string()),
+        },
+        Kind::Texts => texts().map(drop),
+        Kind::Model => {
+            let model = text()?;
+            match start::is_remote(model) || ollama::is_ollama_url(model) {
+                true => Ok(()),
+                false => exists(model),
+            }
+        }
+        Kind::File => exists(text()?),
+        Kind::Files => texts()?.into_iter().try_for_each(exists),
+        Kind::Template => parse_template(text()?).map(drop),
+        Kind::ChatTemplate => match text()? {
+            EMBEDDED_TEMPLATE => Ok(()),
+            file => {
+                exists(file)?;
+                let source = fs::read_to_string(dir.join(file)).map_err(|e| e.to_string())?;
+                jinja::Template::parse(&source)
+                    .map(drop)
+                    .map_err(|e| e.to_string())
+            }
+        },
+        Kind::VectorStore => parse_enum::<VectorStoreKind>("vector store", text()?).map(drop),
+        Kind::NoHit => parse_enum::<NoHit>("no_hit", text()?).map(drop),
+        Kind::Numa => parse_enum::<Numa>("numa", text()?).map(drop),
+        Kind::Cpus => affinity::parse_cpus(text()?)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        Kind::Section => match value.is_object() {
+            true => Ok(()),
+            false => Err("expected a section of keys".to_string()),
+        },
+        Kind::Sections => match value.as_array() {
+            Some(items) if items.iter().all(Value::is_object) => Ok(()),
+            _ => Err("expected a list of sections".to_string()),
+        },
+    }
+}
+
+// (key, kind, required) of each key of a section
+type Fields = &'static [(&'static str, Kind, bool)];
+
+// The sections whose keys can be set one at a time, with their fields
+const EDITABLE: &[(&str, Fields)] = &[
+    ("", NODE_FIELDS),
+    ("chat", CHAT_FIELDS),
+    ("embedding", EMBEDDING_FIELDS),
+    ("whisper", WHISPER_FIELDS),
+];
+
+// The keys of the node file holding a single value, e.g. chat.context_size, with their values
+// as text, empty when not set. Keys of the sections the file does not have are left out.
+pub fn settings(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let node: Value = config::load(path)?;
+    let mut settings = Vec::new();
+    for (section, fields) in EDITABLE {
+        let table = match *section {
+            "" => &node,
+            section => &node[section],
+        };
+        if !table.is_object() {
+            continue;
+        }
+        for (name, kind, _) in *fields {
+            if matches!(kind, Kind::Section | Kind::Sections) || *name == "version" {
+                continue;
+            }
+            let value = match &table[*name] {
+                Value::Null => String::new(),
+                Value::String(text) => text.clone(),
+                Value::Array(items) => items
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                value => value.to_string(),
+            };
+            let key = match *section {
+                "" => name.to_string(),
+                section => format!("{}.{}", section, name),
+            };
+            settings.push((key, value));
+        }
+    }
+
+    Ok(settings)
+}
+
+// Set a key of the node file to the text, removing it when the text is empty. The file is left
+// as it was when the change makes it invalid.
+pub fn set(path: &Path, key: &str, text: &str) -> anyhow::Result<()> {
+    let (section, name) = key.split_once('.').unwrap_or(("", key));
+    let (_, kind, required) = EDITABLE
+        .iter()
+        .find(|(editable, _)| *editable == section)
+        .and_then(|(_, fields)| fields.iter().find(|(field, ..)| *field == name))
+        .filter(|(_, kind, _)| !matches!(kind, Kind::Section | Kind::Sections))
+        .ok_or(anyhow!("{} is not a key that can be set", key))?;
+    let text = text.trim();
+    let value = match kind {
+        _ if text.is_empty() => Value::Null,
+        Kind::Port | Kind::Count => Value::from(
+            text.parse::<u64>()
+                .map_err(|_| anyhow!("{}: expected a whole number", key))?,
+        ),
+        Kind::Score => Value::from(
+            text.parse::<f64>()
+                .map_err(|_| anyhow!("{}: expected a number", key))?,
+        ),
+        Kind::Texts | Kind::Files => Value::from(
+            text.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>(),
+        ),
+        _ => Value::from(text),
+    };
+    if value.is_null() && *required {
+        bail!("{} is required", key);
+    }
+
+    let original = fs::read(path)?;
+    let mut node: Value = config::load(path)?;
+    let table = match section {
+        "" => node.as_object_mut(),
+        section => node[section].as_object_mut(),
+    }
+    .ok_or(anyhow!("{} has no `{}` section", path.display(), section))?;
+    match value {
+        Value::Null => table.shift_remove(name),
+        value => table.insert(name.to_string(), value),
+    };
+    config::write(path, &node)?;
+
+    let problems = validate(path)?;
+    if !problems.is_empty() {
+        fs::write(path, original)?;
+        bail!("{}", problems.join(", "));
+    }
+    events::emit(
+        "config-changed",
+        json!({ "file": path.display().to_string(), "key": key, "value": text }),
+    );
+
+    Ok(())
+}
+
+pub fn command_validate(file: Option<PathBuf>) -> anyhow::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => config::default_path()?,
+    };
+
+    let problems = validate(&file)?;
+    if problems.is_empty() {
+        println!("{} is valid", file.display());
+        let version = config::version(&config::load(&file)?)?;
+        if version < NODE_MIGRATIONS.len() as u32 {
+            println!(
+                "It is written for version {}, `gaia config migrate` upgrades it to {}",
+                version,
+                NODE_MIGRATIONS.len()
+            );
+        }
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{} {}", style("error:").red().bold(), problem);
+    }
+
+    Err(fail(
+        ErrorKind::Config,
+        anyhow!(
+            "Found {} problem{} in {}",
+            problems.len(),
+            if problems.len() == 1 { "" } else { "s" },
+            file.display()
+        ),
+    ))
+}
+
+pub fn command_migrate(file: Option<PathBuf>) -> anyhow::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => config::default_path()?,
+    };
+
+    match config::upgrade(&file, NODE_MIGRATIONS)? {
+        Some(from) => println!("{}", upgraded(&file, from)),
+        None => println!(
+            "{} needs no changes for version {}",
+            file.display(),
+            NODE_MIGRATIONS.len()
+        ),
+    }
+
+    Ok(())
+}
+
+fn upgraded(path: &Path, from: u32) -> String {
+    format!(
+        "Upgraded {} from version {} to {}, the original and its comments are kept in {}.v{}.bak",
+        path.display(),
+        from,
+        NODE_MIGRATIONS.len(),
+        path.display(),
+        from
+    )
+}
@@ -0,0 +1,96 @@
+use clap::Args;
+use std::{
+    process::{Command, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
+
+static SETTINGS: OnceLock<NotifyArgs> = OnceLock::new();
+
+#[derive(Debug, Clone, Args)]
+pub struct NotifyArgs {
+    #[arg(
+        long = "notify",
+        env = "GAIA_NOTIFY",
+        help = "Send a desktop notification when a download, ingestion or model warm-up finishes",
+        global = true
+    )]
+    pub notify: bool,
+    #[arg(
+        long = "notify-after",
+        help = "Only notify of the operations that took at least this long",
+        value_name = "SECS",
+        default_value_t = 30,
+        global = true
+    )]
+    pub after: u64,
+}
+
+pub fn init(args: &NotifyArgs) {
+    let _ = SETTINGS.set(args.clone());
+}
+
+// Tell the user a long operation is over, unless it was quick enough for them to be watching
+pub fn finished(title: &str, body: &str, took: Duration) {
+    let Some(settings) = SETTINGS.get().filter(|settings| settings.notify) else {
+        return;
+    };
+    if took < Duration::from_secs(settings.after) {
+        return;
+    }
+    let body = format!("{} (took {})", body, elapsed(took));
+    if let Err(e) = send(title, &body) {
+        tracing::debug!("failed to send a desktop notification: {}", e);
+    }
+}
+
+fn elapsed(took: Duration) -> String {
+    let secs = took.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// The title and body go through the environment, so nothing in them needs quoting
+fn send(title: &str, body: &str) -> std::io::Result<()> {
+    let mut command = match std::env::consts::OS {
+        "macos" => {
+            let mut command = Command::new("osascript");
+            command.args([
+                "-e",
+                r#"display notification (system attribute "GAIA_NOTIFY_BODY") with title (system attribute "GAIA_NOTIFY_TITLE")"#,
+            ]);
+            command
+        }
+        "windows" => {
+            let mut command = Command::new("powershell");
+            command.args([
+                "-NoProfile",
+                "-Command",
+                "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null; \
+                 $xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+                 $text = $xml.GetElementsByTagName('text'); \
+                 $text.Item(0).AppendChild($xml.CreateTextNode($env:GAIA_NOTIFY_TITLE)) > $null; \
+                 $text.Item(1).AppendChild($xml.CreateTextNode($env:GAIA_NOTIFY_BODY)) > $null; \
+                 [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('gaia').Show([Windows.UI.Notifications.ToastNotification]::new($xml))",
+            ]);
+            command
+        }
+        _ => {
+            let mut command = Command::new("notify-send");
+            command.args(["--app-name=gaia", title, body]);
+            command
+        }
+    };
+    command
+        .env("GAIA_NOTIFY_TITLE", title)
+        .env("GAIA_NOTIFY_BODY", body)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use crate::paths;
+use crate::progress::Progress;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+// Media types of the manifests asked for, OCI first
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+// Annotation ORAS gives each layer with the name of the file it was pushed from
+const TITLE: &str = "org.opencontainers.image.title";
+
+pub fn is_oci_url(s: &str) -> bool {
+    s.starts_with("oci://")
+}
+
+// `oci://<registry>/<repository>[:<tag>|@<digest>]`
+#[derive(Debug, Clone)]
+struct Reference {
+    registry: String,
+    repository: String,
+    // tag or digest
+    reference: String,
+}
+impl Reference {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("oci://")
+            .ok_or(anyhow!("Not an oci:// url: {}", url))?;
+        let (registry, path) = rest
+            .split_once('/')
+            .ok_or(anyhow!("Missing the repository in {}", url))?;
+        let (repository, reference) = match path.split_once('@') {
+            Some((repository, digest)) => (repository, digest),
+            None => match path.rsplit_once(':') {
+                Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+                _ => (path, "latest"),
+            },
+        };
+        if registry.is_empty() || repository.is_empty() || reference.is_empty() {
+            bail!("Invalid OCI reference {}", url);
+        }
+
+        Ok(Self {
+            registry: registry.to_string(),
+            repository: repository.to_string(),
+            reference: reference.to_string(),
+        })
+    }
+
+    fn base_url(&self) -> String {
+        // local registries rarely have a certificate
+        let scheme =
+            match self.registry.starts_with("localhost") || self.registry.starts_with("127.") {
+                true => "http",
+                false => "https",
+            };
+        format!("{}://{}/v2/{}", scheme, self.registry, self.repository)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    layers: Vec<Layer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Layer {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    annotations: std::collections::HashMap<String, String>,
+}
+impl Layer {
+    fn title(&self) -> Option<&str> {
+        self.annotations.get(TITLE).map(String::as_str)
+    }
+}
+
+// A registry session, holding the token of its auth challenge once answered
+struct Registry {
+    client: Client,
+    reference: Reference,
+    token: Option<String>,
+}
+impl Registry {
+    fn get(&mut self, url: &str, accept: &str) -> anyhow::Result<Response> {
+        let send = |token: &Option<String>| -> anyhow::Result<Response> {
+            let mut request: RequestBuilder = self.client.get(url).header(header::ACCEPT, accept);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            Ok(request.send()?)
+        };
+
+        let response = send(&self.token)?;
+        if response.status() != StatusCode::UNAUTHORIZED || self.token.is_some() {
+            return check(response);
+        }
+        let challenge = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        self.token = Some(self.authenticate(&challenge)?);
+
+        check(send(&self.token)?)
+    }
+
+    // Answer a `Bearer realm=…,service=…,scope=…` challenge with a token, sending the
+    // credentials of the registry when there are some
+    fn authenticate(&self, challenge: &str) -> anyhow::Result<String> {
+        let params = challenge
+            .strip_prefix("Bearer ")
+            .ok_or(anyhow!(
+                "{} asks for '{}' authentication, only bearer tokens are supported",
+                self.reference.registry,
+                challenge
+            ))?
+            .split(',')
+            .filter_map(|param| param.trim().split_once('='))
+            .map(|(key, value)| (key.to_string(), value.trim_matches('"').to_string()))
+            .collect::<Vec<_>>();
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+        let realm = param("realm").ok_or(anyhow!("The auth challenge has no realm"))?;
+        let scope =
+            param("scope").unwrap_or(format!("repository:{}:pull", self.reference.repository));
+
+        let mut query = vec![("scope", scope)];
+        if let Some(service) = param("service") {
+            query.push(("service", service));
+        }
+        let mut request = self.client.get(&realm).query(&query);
+        if let Some((username, password)) = credentials(&self.reference.registry) {
+            request = request.basic_auth(username, Some(password));
+        }
+        let token: Value = check(request.send()?)?.json()?;
+
+        token["token"]
+            .as_str()
+            .or(token["access_token"].as_str())
+            .map(String::from)
+            .ok_or(anyhow!("{} returned no token", realm))
+    }
+}
+
+fn check(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let url = response.url().clone();
+    // registries answer with {"errors": [{"code", "message"}]}
+    let body: Value = response.json().unwrap_or_default();
+    let message = body["errors"][0]["message"]
+        .as_str()
+        .map(String::from)
+        .unwrap_or(match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                "access denied, log in with `docker login` or set GAIA_OCI_USERNAME and GAIA_OCI_PASSWORD".to_string()
+            }
+            StatusCode::NOT_FOUND => "no such model or tag".to_string(),
+            _ => status.to_string(),
+        });
+
+    bail!("{}: {} ({})", url, message, status)
+}
+
+// Username and password for the registry, from the environment or the docker config
+fn credentials(registry: &str) -> Option<(String, String)> {
+    if let (Ok(username), Ok(password)) =
+        (env::var("GAIA_OCI_USERNAME"), env::var("GAIA_OCI_PASSWORD"))
+    {
+        return Some((username, password));
+    }
+
+    let config = env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .or_else(|| paths::home().ok().map(|home| home.join(".docker")))?
+        .join("config.json");
+    let config: Value = serde_json::from_str(&fs::read_to_string(config).ok()?).ok()?;
+    let auths = config["auths"].as_object()?;
+    // Docker Hub is kept under its legacy url
+    let auth = auths
+        .iter()
+        .find(|(host, _)| {
+            let host = host
+                .trim_start_matches("https://")
+                .trim_start_matches("http://");
+            host.split('/').next() == Some(registry)
+        })?
+        .1["auth"]
+        .as_str()?;
+    let decoded = String::from_utf8(STANDARD.decode(auth).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+
+    Some((username.to_string(), password.to_string()))
+}
+
+// Name of the downloaded file when the manifest does not give one
+pub fn file_name(url: &str) -> anyhow::Result<String> {
+    let reference = Reference::parse(url)?;
+    let name = reference
+        .repository
+        .rsplit('/')
+        .next()
+        .unwrap_or(&reference.repository);
+    let tag = reference
+        .reference
+        .strip_prefix("sha256:")
+        .map(|digest| &digest[..12.min(digest.len())])
+        .unwrap_or(&reference.reference);
+
+    Ok(format!("{}-{}.gguf", name, tag))
+}
+
+// Pull the gguf layer of the artifact into the directory, checking it against its digest.
+// Returns the path of the file.
+pub fn download(url: &str, dir: &Path) -> anyhow::Result<PathBuf> {
+    let reference = Reference::parse(url)?;
+    let _span = tracing::info_span!("download", url).entered();
+    let mut registry = Registry {
+        client: Client::builder().timeout(None).build()?,
+        reference: reference.clone(),
+        token: None,
+    };
+
+    let manifest: Manifest = registry
+        .get(
+            &format!("{}/manifests/{}", reference.base_url(), reference.reference),
+            MANIFEST_TYPES,
+        )?
+        .json()?;
+    // the layer holding the model: named like one, typed like one, or else the largest
+    let layer = manifest
+        .layers
+        .iter()
+        .find(|layer| layer.title().is_some_and(|title| title.ends_with(".gguf")))
+        .or_else(|| {
+            manifest
+                .layers
+                .iter()
+                .find(|layer| layer.media_type.contains("gguf"))
+        })
+        .or_else(|| manifest.layers.iter().max_by_key(|layer| layer.size))
+        .ok_or(anyhow!("{} has no layers", url))?;
+    let expected = layer
+        .digest
+        .strip_prefix("sha256:")
+        .ok_or(anyhow!(
+            "Unsupported digest {}, only sha256 is checked",
+            layer.digest
+        ))?
+        .to_string();
+
+    let name = match layer.title() {
+        Some(title)
+            if Path::new(title)
+                .file_name()
+                .is_some_and(|name| name == title) =>
+        {
+            title.to_string()
+        }
+        _ => file_name(url)?,
+    };
+    let dest = dir.join(&name);
+    tracing::info!(dest = %dest.display(), size = layer.size, digest = layer.digest, "downloading");
+
+    let mut response = registry.get(
+        &format!("{}/blobs/{}", reference.base_url(), layer.digest),
+        "*/*",
+    )?;
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let mut file = File::create(&partial)?;
+    let mut progress = Progress::new("download", &name, "bytes", Some(layer.size));
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = response.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        file.write_all(&buffer[..n])?;
+        progress.advance(n as u64);
+    }
+    progress.finish();
+
+    let actual = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    if actual != expected {
+        let _ = fs::remove_file(&partial);
+        bail!(
+            "The layer of {} has digest sha256:{}, the manifest says {}",
+            url,
+            actual,
+            layer.digest
+        );
+    }
+    fs::rename(&partial, &dest)?;
+    tracing::info!(bytes = layer.size, "downloaded");
+
+    Ok(dest)
+}
@@ -0,0 +1,409 @@
+use crate::client::SamplingArgs;
+use crate::models;
+use crate::paths;
+use crate::start;
+use crate::template::PromptTemplateType;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::style;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    #[arg(help = "Name of a model in the models directory, or path to a gguf file")]
+    pub model: String,
+    #[arg(
+        short = 'p',
+        long = "prompt-template",
+        help = "Prompt template of the model, turned into the Modelfile's TEMPLATE and stop tokens"
+    )]
+    pub prompt_template: PromptTemplateType,
+    #[arg(
+        long = "as",
+        help = "Name to give the model in Ollama, as NAME or NAME:TAG, defaults to the file name",
+        value_name = "NAME"
+    )]
+    pub name: Option<String>,
+    #[arg(short = 's', long = "system-prompt", help = "System prompt")]
+    pub system_prompt: Option<String>,
+    #[arg(short = 'c', long = "context-size", help = "Prompt context size")]
+    pub context_size: Option<u64>,
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+    #[arg(
+        short = 'o',
+        long = "output",
+        help = "File to write the Modelfile to, defaults to <name>.Modelfile"
+    )]
+    pub output: Option<PathBuf>,
+    #[arg(
+        long = "ollama-dir",
+        help = "Models directory of Ollama, defaults to $OLLAMA_MODELS or ~/.ollama/models"
+    )]
+    pub ollama_dir: Option<PathBuf>,
+}
+
+// Where Ollama keeps the models it pulled, unless `$OLLAMA_MODELS` says otherwise
+pub fn models_dir() -> anyhow::Result<PathBuf> {
+    match env::var_os("OLLAMA_MODELS") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(paths::home()?.join(".ollama").join("models")),
+    }
+}
+
+pub fn is_ollama_url(s: &str) -> bool {
+    s.starts_with("ollama://")
+}
+
+// A model pulled by Ollama
+#[derive(Debug, Clone)]
+pub struct OllamaModel {
+    // `name:tag`, with the namespace when it is not `library`
+    pub name: String,
+    // the gguf blob
+    pub blob: PathBuf,
+    pub size: u64,
+}
+
+// The models in Ollama's models directory, by name
+pub fn list() -> anyhow::Result<Vec<OllamaModel>> {
+    let dir = models_dir()?;
+    let mut models = Vec::new();
+    // manifests/<registry>/<namespace>/<name>/<tag>
+    for registry in read_dirs(&dir.join("manifests")) {
+        for namespace in read_dirs(&registry) {
+            for model in read_dirs(&namespace) {
+                let Ok(tags) = fs::read_dir(&model) else {
+                    continue;
+                };
+                for tag in tags.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+                    let Some(blob) = model_blob(&dir, &tag) else {
+                        continue;
+                    };
+                    let file_name = |path: &Path| {
+                        path.file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default()
+                    };
+                    let mut name = format!("{}:{}", file_name(&model), file_name(&tag));
+                    if file_name(&namespace) != "library" {
+                        name = format!("{}/{}", file_name(&namespace), name);
+                    }
+                    if file_name(&registry) != "registry.ollama.ai" {
+                        name = format!("{}/{}", file_name(&registry), name);
+                    }
+                    models.push(OllamaModel {
+                        name,
+                        size: fs::metadata(&blob).map(|m| m.len()).unwrap_or(0),
+                        blob,
+                    });
+                }
+            }
+        }
+    }
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(models)
+}
+
+fn read_dirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs
+}
+
+// The blob of the model layer of a manifest, if it is on disk
+fn model_blob(dir: &Path, manifest: &Path) -> Option<PathBuf> {
+    let manifest: Value = serde_json::from_str(&fs::read_to_string(manifest).ok()?).ok()?;
+    let digest = manifest["layers"]
+        .as_array()?
+        .iter()
+        .find(|layer| layer["mediaType"] == "application/vnd.ollama.image.model")?["digest"]
+        .as_str()?
+        .strip_prefix("sha256:")?
+        .to_string();
+
+    // older versions of Ollama kept the colon in the file name
+    [
+        blob_path(dir, &digest),
+        dir.join("blobs").join(format!("sha256:{}", digest)),
+    ]
+    .into_iter()
+    .find(|blob| blob.is_file())
+}
+
+// The blob of `ollama://name[:tag]`, used in place without copying it
+pub fn resolve(url: &str) -> anyhow::Result<PathBuf> {
+    let name = url.trim_start_matches("ollama://");
+    let name = match name.contains(':') {
+        true => name.to_string(),
+        false => format!("{}:latest", name),
+    };
+    let models = list()?;
+
+    models
+        .iter()
+        .find(|model| model.name == name)
+        .map(|model| model.blob.clone())
+        .ok_or(anyhow!(
+            "Ollama has no model {} in {}, see `gaia models import-ollama` for the ones it has",
+            name,
+            models_dir()?.display()
+        ))
+}
+
+// List the models of Ollama, or link one into the models directory
+pub fn command_import(name: Option<String>) -> anyhow::Result<()> {
+    let Some(name) = name else {
+        let models = list()?;
+        if models.is_empty() {
+            println!("No Ollama models found in {}", models_dir()?.display());
+            return Ok(());
+        }
+        for model in &models {
+            println!(
+                "{:<32} {:>8.1} GiB  {}",
+                model.name,
+                model.size as f64 / (1024.0 * 1024.0 * 1024.0),
+                style(model.blob.display()).dim()
+            );
+        }
+        println!(
+            "{}",
+            style("Start one in place with `gaia start -m ollama://<name>`, or import it with `gaia models import-ollama <name>`").dim()
+        );
+        return Ok(());
+    };
+
+    let name = match name.contains(':') {
+        true => name,
+        false => format!("{}:latest", name),
+    };
+    let blob = resolve(&name)?;
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(format!("{}.gguf", name.replace([':', '/'], "-")));
+    if dest.exists() {
+        bail!("{} already exists", dest.display());
+    }
+    let how = models::link(&blob, &dest)?;
+    println!("{} {} to {}", how, name, dest.display());
+
+    Ok(())
+}
+
+// Ollama names a model `name:tag`, lowercase
+fn split_name(name: &str) -> anyhow::Result<(String, String)> {
+    let (name, tag) = name.split_once(':').unwrap_or((name, "latest"));
+    let valid = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    };
+    if !valid(name) || !valid(tag) {
+        bail!(
+            "'{}:{}' is not an Ollama model name, use letters, digits, '.', '_' and '-'",
+            name,
+            tag
+        );
+    }
+
+    Ok((name.to_lowercase(), tag.to_string()))
+}
+
+fn blob_path(dir: &Path, digest: &str) -> PathBuf {
+    dir.join("blobs").join(format!("sha256-{}", digest))
+}
+
+// Store the bytes as a blob, returning its layer entry
+fn write_blob(dir: &Path, media_type: &str, bytes: &[u8]) -> anyhow::Result<Value> {
+    let digest = models::hex(&Sha256::digest(bytes));
+    fs::write(blob_path(dir, &digest), bytes)?;
+
+    Ok(json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{}", digest),
+        "size": bytes.len(),
+    }))
+}
+
+// Ollama parameters for the template and the sampling settings
+fn parameters(
+    template: PromptTemplateType,
+    sampling: &SamplingArgs,
+    context_size: Option<u64>,
+) -> Map<String, Value> {
+    let (_, stops) = template.ollama();
+    let mut params = Map::new();
+    params.insert("stop".to_string(), json!(stops));
+    if let Some(temperature) = sampling.temperature {
+        params.insert("temperature".to_string(), json!(temperature));
+    }
+    if let Some(top_p) = sampling.top_p {
+        params.insert("top_p".to_string(), json!(top_p));
+    }
+    if let Some(max_tokens) = sampling.max_tokens {
+        params.insert("num_predict".to_string(), json!(max_tokens));
+    }
+    if let Some(context_size) = context_size {
+        params.insert("num_ctx".to_string(), json!(context_size));
+    }
+
+    params
+}
+
+// A Modelfile string, quoted so newlines and quotes survive
+fn quote(s: &str) -> String {
+    match s.contains('\n') || s.contains('"') {
+        true => format!("\"\"\"{}\"\"\"", s),
+        false => format!("\"{}\"", s),
+    }
+}
+
+fn modelfile(
+    model: &Path,
+    template: PromptTemplateType,
+    params: &Map<String, Value>,
+    system_prompt: Option<&str>,
+) -> String {
+    let mut modelfile = format!(
+        "# Exported by gaia from {} with the {} prompt template\nFROM {}\nTEMPLATE {}\n",
+        model.display(),
+        template,
+        model.display(),
+        quote(template.ollama().0)
+    );
+    if let Some(system_prompt) = system_prompt {
+        let _ = writeln!(modelfile, "SYSTEM {}", quote(system_prompt));
+    }
+    for (name, value) in params {
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(s) => quote(&s),
+                value => value.to_string(),
+            };
+            let _ = writeln!(modelfile, "PARAMETER {} {}", name, value);
+        }
+    }
+
+    modelfile
+}
+
+// Write a Modelfile for the model and install it in Ollama's models directory, so that
+// `ollama run <name>` serves it without a copy when both live on the same disk
+pub fn command_export(args: ExportArgs) -> anyhow::Result<()> {
+    let ExportArgs {
+        model,
+        prompt_template,
+        name,
+        system_prompt,
+        context_size,
+        sampling,
+        output,
+        ollama_dir,
+    } = args;
+    let path = start::find_model(&model)?;
+    let (name, tag) = split_name(&name.unwrap_or_else(|| crate::server::model_name(&model)))?;
+    let dir = match ollama_dir {
+        Some(dir) => dir,
+        None => models_dir()?,
+    };
+    let params = parameters(prompt_template, &sampling, context_size);
+
+    let output = output.unwrap_or(PathBuf::from(format!("{}.Modelfile", name)));
+    fs::write(
+        &output,
+        modelfile(&path, prompt_template, &params, system_prompt.as_deref()),
+    )?;
+    println!("Wrote {}", output.display());
+
+    fs::create_dir_all(dir.join("blobs"))?;
+    println!("Hashing {}", path.display());
+    let digest = models::digest_file(&path)?;
+    let blob = blob_path(&dir, &digest);
+    if !blob.exists() {
+        // a hard link costs no space, other disks need a copy
+        if fs::hard_link(&path, &blob).is_err() {
+            println!("Copying the model into {}", blob.display());
+            fs::copy(&path, &blob)?;
+        }
+    }
+    let size = fs::metadata(&path)?.len();
+
+    let mut layers = vec![json!({
+        "mediaType": "application/vnd.ollama.image.model",
+        "digest": format!("sha256:{}", digest),
+        "size": size,
+    })];
+    layers.push(write_blob(
+        &dir,
+        "application/vnd.ollama.image.template",
+        prompt_template.ollama().0.as_bytes(),
+    )?);
+    if let Some(system_prompt) = &system_prompt {
+        layers.push(write_blob(
+            &dir,
+            "application/vnd.ollama.image.system",
+            system_prompt.as_bytes(),
+        )?);
+    }
+    layers.push(write_blob(
+        &dir,
+        "application/vnd.ollama.image.params",
+        serde_json::to_string(&params)?.as_bytes(),
+    )?);
+
+    let config = json!({
+        "model_format": "gguf",
+        "architecture": env::consts::ARCH,
+        "os": env::consts::OS,
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": layers.iter().map(|layer| layer["digest"].clone()).collect::<Vec<_>>(),
+        },
+    });
+    let config = write_blob(
+        &dir,
+        "application/vnd.docker.container.image.v1+json",
+        serde_json::to_string(&config)?.as_bytes(),
+    )?;
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": config,
+        "layers": layers,
+    });
+    let manifest_dir = dir
+        .join("manifests")
+        .join("registry.ollama.ai")
+        .join("library")
+        .join(&name);
+    fs::create_dir_all(&manifest_dir)?;
+    fs::write(manifest_dir.join(&tag), serde_json::to_string(&manifest)?)?;
+    println!(
+        "Installed {}:{} in {}, run it with `ollama run {}:{}`",
+        name,
+        tag,
+        dir.display(),
+        name,
+        tag
+    );
+
+    Ok(())
+}
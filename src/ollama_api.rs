@@ -0,0 +1,480 @@
+use crate::blob::Utc;
+use crate::gateway::{self, Gateway, Request, Unrouted};
+use crate::server::{self, ModelKind};
+use serde_json::{json, Map, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, TcpStream},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Version told to the tools asking, the API below is the one of this Ollama release
+const OLLAMA_VERSION: &str = "0.5.7";
+// Tag Ollama names models with when none is given
+const DEFAULT_TAG: &str = ":latest";
+
+// Options of an Ollama request and the OpenAI parameters they become
+const OPTIONS: [(&str, &str); 7] = [
+    ("temperature", "temperature"),
+    ("top_p", "top_p"),
+    ("num_predict", "max_tokens"),
+    ("stop", "stop"),
+    ("seed", "seed"),
+    ("frequency_penalty", "frequency_penalty"),
+    ("presence_penalty", "presence_penalty"),
+];
+
+// Whether the path is one of the Ollama API, the gateway answers it by translating it to OpenAI
+pub fn is_route(path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or_default();
+    path == "/" || path.starts_with("/api/")
+}
+
+pub fn answer(gateway: &Gateway, request: &Request, client: &mut TcpStream) -> anyhow::Result<()> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let body = match request.method.as_str() {
+        "POST" => match serde_json::from_slice::<Value>(&request.body) {
+            Ok(body) => body,
+            Err(e) => return Ok(respond_error(client, 400, &e.to_string())?),
+        },
+        _ => Value::Null,
+    };
+
+    let answered = match (request.method.as_str(), path) {
+        // tools check that Ollama is up with this
+        ("GET" | "HEAD", "/") => {
+            gateway::respond_with(client, 200, "text/plain", "Ollama is running")?;
+            return Ok(());
+        }
+        ("GET", "/api/version") => Ok(json!({ "version": OLLAMA_VERSION })),
+        ("GET", "/api/tags") => tags(gateway),
+        ("GET", "/api/ps") => ps(),
+        ("POST", "/api/show") => show(gateway, &body),
+        ("POST", "/api/generate") => generate(gateway, &body, client),
+        ("POST", "/api/chat") => chat(gateway, &body, client),
+        ("POST", "/api/embed") => embed(gateway, &body),
+        ("POST", "/api/embeddings") => embeddings(gateway, &body),
+        _ => Err(Unrouted(
+            404,
+            format!(
+                "{} {} is not answered, gaia manages its models with `gaia models`",
+                request.method, path
+            ),
+        )),
+    };
+    match answered {
+        // streamed replies are written already
+        Ok(Value::Null) => Ok(()),
+        Ok(response) => Ok(gateway::respond(client, 200, &response)?),
+        Err(Unrouted(status, message)) => Ok(respond_error(client, status, &message)?),
+    }
+}
+
+// An Ollama error response
+fn respond_error(client: &mut TcpStream, status: u16, message: &str) -> std::io::Result<()> {
+    gateway::respond(client, status, &json!({ "error": message }))
+}
+
+// The served model an Ollama name stands for, `llama3:latest` being `llama3`
+fn model_name(body: &Value) -> Result<String, Unrouted> {
+    let model = body["model"]
+        .as_str()
+        .or(body["name"].as_str())
+        .ok_or(Unrouted(400, "model is required".to_string()))?;
+
+    Ok(model.strip_suffix(DEFAULT_TAG).unwrap_or(model).to_string())
+}
+
+// How Ollama names a model, always with a tag
+fn tagged(name: &str) -> String {
+    match name.contains(':') {
+        true => name.to_string(),
+        false => format!("{}{}", name, DEFAULT_TAG),
+    }
+}
+
+fn now() -> String {
+    time(0)
+}
+
+// A unix time as Ollama writes it, now when it is unknown
+fn time(secs: u64) -> String {
+    let secs = match secs {
+        0 => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default(),
+        secs => secs,
+    };
+    Utc::of(secs).rfc3339()
+}
+
+fn internal(e: impl std::fmt::Display) -> Unrouted {
+    Unrouted(500, e.to_string())
+}
+
+fn details() -> Value {
+    json!({
+        "format": "gguf",
+        "family": "",
+        "families": null,
+        "parameter_size": "",
+        "quantization_level": "",
+    })
+}
+
+// An Ollama model entry, sized from its file when it is loaded
+fn model_entry(name: &str, path: Option<&str>, modified: u64) -> Value {
+    let size = path
+        .and_then(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    json!({
+        "name": tagged(name),
+        "model": tagged(name),
+        "modified_at": time(modified),
+        "size": size,
+        "digest": "",
+        "details": details(),
+    })
+}
+
+// The models of the gateway, loaded or loaded on demand
+fn tags(gateway: &Gateway) -> Result<Value, Unrouted> {
+    let services = server::load_all().map_err(internal)?;
+    let listed = gateway.models().map_err(internal)?;
+    let models = listed["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|model| {
+            let name = model["id"].as_str().unwrap_or_default();
+            let path = services
+                .iter()
+                .flat_map(|state| &state.models)
+                .find(|served| served.name == name)
+                .map(|served| served.path.as_str());
+            model_entry(name, path, model["created"].as_u64().unwrap_or_default())
+        })
+        .collect::<Vec<_>>();
+
+    Ok(json!({ "models": models }))
+}
+
+// The models loaded now
+fn ps() -> Result<Value, Unrouted> {
+    let mut models = Vec::new();
+    for state in server::load_all().map_err(internal)? {
+        if !server::is_running(state.pid) {
+            continue;
+        }
+        for model in &state.models {
+            let mut entry = model_entry(&model.name, Some(&model.path), state.started);
+            // loaded until gaia stops it
+            entry["expires_at"] = json!("9999-12-31T23:59:59Z");
+            entry["size_vram"] = json!(0);
+            models.push(entry);
+        }
+    }
+
+    Ok(json!({ "models": models }))
+}
+
+fn show(gateway: &Gateway, body: &Value) -> Result<Value, Unrouted> {
+    let name = model_name(body)?;
+    let listed = gateway.models().map_err(internal)?;
+    if !listed["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|model| model["id"] == name.as_str())
+    {
+        return Err(Unrouted(404, format!("model '{}' not found", name)));
+    }
+    let embedding = server::load_all()
+        .map_err(internal)?
+        .iter()
+        .flat_map(|state| &state.models)
+        .any(|model| model.name == name && model.kind == ModelKind::Embedding);
+
+    Ok(json!({
+        "modelfile": "",
+        "parameters": "",
+        "template": "",
+        "details": details(),
+        "model_info": {},
+        "capabilities": [if embedding { "embedding" } else { "completion" }],
+    }))
+}
+
+// An Ollama message, whose images are base64 without a type, as an OpenAI one
+fn message(role: &str, content: &str, images: &Value) -> Value {
+    let images = images.as_array().cloned().unwrap_or_default();
+    if images.is_empty() {
+        return json!({ "role": role, "content": content });
+    }
+    let mut parts = vec![json!({ "type": "text", "text": content })];
+    for image in images {
+        let image = image.as_str().unwrap_or_default();
+        parts.push(json!({
+            "type": "image_url",
+            "image_url": { "url": format!("data:image/png;base64,{}", image) },
+        }));
+    }
+    json!({ "role": role, "content": parts })
+}
+
+// The OpenAI chat request of an Ollama one
+fn chat_request(model: &str, messages: Vec<Value>, body: &Value, stream: bool) -> Value {
+    let mut request = json!({ "model": model, "messages": messages, "stream": stream });
+    for (option, parameter) in OPTIONS {
+        let value = &body["options"][option];
+        // -1 predicts until the model stops
+        if value.is_null() || (option == "num_predict" && value.as_i64().is_some_and(|n| n < 0)) {
+            continue;
+        }
+        request[parameter] = value.clone();
+    }
+    match &body["format"] {
+        Value::String(format) if format == "json" => {
+            request["response_format"] = json!({ "type": "json_object" })
+        }
+        schema @ Value::Object(_) => {
+            request["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": { "name": "format", "schema": schema },
+            })
+        }
+        _ => {}
+    }
+    if stream {
+        request["stream_options"] = json!({ "include_usage": true });
+    }
+
+    request
+}
+
+// Whether a reply goes on piece by piece, as Ollama does unless `stream` is false
+fn streams(body: &Value) -> bool {
+    body["stream"].as_bool().unwrap_or(true)
+}
+
+// The reply to /api/generate, a prompt with an optional system prompt
+fn generate(gateway: &Gateway, body: &Value, client: &mut TcpStream) -> Result<Value, Unrouted> {
+    let model = model_name(body)?;
+    let prompt = body["prompt"].as_str().unwrap_or_default();
+    // an empty prompt only loads the model
+    if prompt.is_empty() {
+        gateway.route(Some(&model))?;
+        return Ok(json!({
+            "model": tagged(&model),
+            "created_at": now(),
+            "response": "",
+            "done": true,
+            "done_reason": "load",
+        }));
+    }
+    let mut messages = Vec::new();
+    if let Some(system) = body["system"].as_str() {
+        messages.push(message("system", system, &Value::Null));
+    }
+    messages.push(message("user", prompt, &body["images"]));
+
+    complete(gateway, client, &model, messages, body, Reply::Generate)
+}
+
+// The reply to /api/chat, a conversation
+fn chat(gateway: &Gateway, body: &Value, client: &mut TcpStream) -> Result<Value, Unrouted> {
+    let model = model_name(body)?;
+    let messages = body["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|m| {
+            message(
+                m["role"].as_str().unwrap_or("user"),
+                m["content"].as_str().unwrap_or_default(),
+                &m["images"],
+            )
+        })
+        .collect::<Vec<_>>();
+    // no messages only load the model
+    if messages.is_empty() {
+        gateway.route(Some(&model))?;
+        return Ok(json!({
+            "model": tagged(&model),
+            "created_at": now(),
+            "message": { "role": "assistant", "content": "" },
+            "done": true,
+            "done_reason": "load",
+        }));
+    }
+
+    complete(gateway, client, &model, messages, body, Reply::Chat)
+}
+
+// What the text of a reply is sent in
+#[derive(Clone, Copy)]
+enum Reply {
+    Generate,
+    Chat,
+}
+impl Reply {
+    fn piece(self, model: &str, text: &str) -> Map<String, Value> {
+        let mut piece = Map::new();
+        piece.insert("model".to_string(), json!(tagged(model)));
+        piece.insert("created_at".to_string(), json!(now()));
+        match self {
+            Reply::Generate => piece.insert("response".to_string(), json!(text)),
+            Reply::Chat => piece.insert(
+                "message".to_string(),
+                json!({ "role": "assistant", "content": text }),
+            ),
+        };
+        piece.insert("done".to_string(), json!(false));
+        piece
+    }
+}
+
+// The counts and durations Ollama ends a reply with, in nanoseconds
+fn finish(piece: &mut Map<String, Value>, done_reason: &str, usage: &Value, started: Instant) {
+    let nanos = started.elapsed().as_nanos() as u64;
+    piece.insert("done".to_string(), json!(true));
+    piece.insert("done_reason".to_string(), json!(done_reason));
+    piece.insert("total_duration".to_string(), json!(nanos));
+    piece.insert("load_duration".to_string(), json!(0));
+    piece.insert(
+        "prompt_eval_count".to_string(),
+        json!(usage["prompt_tokens"].as_u64().unwrap_or_default()),
+    );
+    piece.insert(
+        "eval_count".to_string(),
+        json!(usage["completion_tokens"].as_u64().unwrap_or_default()),
+    );
+    piece.insert("eval_duration".to_string(), json!(nanos));
+}
+
+// Ask the model and answer as Ollama does, in one response or as lines of JSON as it streams
+fn complete(
+    gateway: &Gateway,
+    client: &mut TcpStream,
+    model: &str,
+    messages: Vec<Value>,
+    body: &Value,
+    reply: Reply,
+) -> Result<Value, Unrouted> {
+    let started = Instant::now();
+    let stream = streams(body);
+    let upstream = gateway.send(
+        "chat/completions",
+        &chat_request(model, messages, body, stream),
+    )?;
+
+    if !stream {
+        let response = upstream.json().map_err(internal)?;
+        let choice = &response["choices"][0];
+        let mut piece = reply.piece(
+            model,
+            choice["message"]["content"].as_str().unwrap_or_default(),
+        );
+        finish(
+            &mut piece,
+            choice["finish_reason"].as_str().unwrap_or("stop"),
+            &response["usage"],
+            started,
+        );
+        return Ok(Value::Object(piece));
+    }
+
+    let sent = (|| -> anyhow::Result<()> {
+        write!(
+            client,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n{}Connection: close\r\n\r\n",
+            gateway.streaming().headers()
+        )?;
+        let mut done_reason = "stop".to_string();
+        let mut usage = Value::Null;
+        for line in BufReader::new(upstream.body).lines() {
+            let line = line?;
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                break;
+            }
+            let chunk = serde_json::from_str::<Value>(data)?;
+            if !chunk["usage"].is_null() {
+                usage = chunk["usage"].clone();
+            }
+            for choice in chunk["choices"].as_array().into_iter().flatten() {
+                if let Some(reason) = choice["finish_reason"].as_str() {
+                    done_reason = reason.to_string();
+                }
+                if let Some(text) = choice["delta"]["content"].as_str() {
+                    let piece = reply.piece(model, text);
+                    writeln!(client, "{}", Value::Object(piece))?;
+                    client.flush()?;
+                }
+            }
+        }
+        let mut last = reply.piece(model, "");
+        finish(&mut last, &done_reason, &usage, started);
+        writeln!(client, "{}", Value::Object(last))?;
+        client.shutdown(Shutdown::Write)?;
+
+        Ok(())
+    })();
+    // the status is sent already, the client sees the stream end early
+    if let Err(e) = sent {
+        tracing::warn!("the Ollama reply stopped: {:#}", e);
+    }
+
+    Ok(Value::Null)
+}
+
+// /api/embed, embeddings of one input or many
+fn embed(gateway: &Gateway, body: &Value) -> Result<Value, Unrouted> {
+    let started = Instant::now();
+    let model = model_name(body)?;
+    let input = match &body["input"] {
+        Value::String(input) => vec![json!(input)],
+        Value::Array(inputs) => inputs.clone(),
+        _ => return Err(Unrouted(400, "input is required".to_string())),
+    };
+    let response = embeddings_of(gateway, &model, input)?;
+    let embeddings = response["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|data| data["embedding"].clone())
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "model": tagged(&model),
+        "embeddings": embeddings,
+        "total_duration": started.elapsed().as_nanos() as u64,
+        "load_duration": 0,
+        "prompt_eval_count": response["usage"]["prompt_tokens"].as_u64().unwrap_or_default(),
+    }))
+}
+
+// /api/embeddings, the older route with a single prompt
+fn embeddings(gateway: &Gateway, body: &Value) -> Result<Value, Unrouted> {
+    let model = model_name(body)?;
+    let prompt = body["prompt"].as_str().unwrap_or_default();
+    let response = embeddings_of(gateway, &model, vec![json!(prompt)])?;
+
+    Ok(json!({ "embedding": response["data"][0]["embedding"] }))
+}
+
+fn embeddings_of(gateway: &Gateway, model: &str, input: Vec<Value>) -> Result<Value, Unrouted> {
+    let upstream = gateway.send("embeddings", &json!({ "model": model, "input": input }))?;
+    let mut response = upstream.json().map_err(internal)?;
+    // in input order
+    if let Some(data) = response["data"].as_array_mut() {
+        data.sort_by_key(|data| data["index"].as_u64());
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,207 @@
+use crate::attachment;
+use anyhow::bail;
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// Starts the system note holding the summary of the turns taken out, summarized again along with
+// the next turns taken out
+const SUMMARY_NOTE: &str = "Summary of the earlier conversation:";
+// Tokens a message takes besides its content, for its role and the template around it
+const MESSAGE_OVERHEAD: u64 = 4;
+// Part of the budget kept for the summary when choosing the turns to summarize
+const SUMMARY_SHARE: u64 = 8;
+
+#[derive(Debug, Clone, Args)]
+pub struct ContextArgs {
+    #[arg(
+        short = 'c',
+        long = "context-size",
+        help = "Context size of the running model, used to check attachments and the length of the conversation",
+        default_value_t = crate::DEFAULT_CONTEXT_SIZE
+    )]
+    pub context_size: u64,
+    #[arg(
+        long = "context-overflow",
+        help = "What happens when the conversation outgrows the context: an error, dropping the oldest turns, or summarizing them into a system note",
+        value_name = "POLICY",
+        default_value = "error"
+    )]
+    pub overflow: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    #[default]
+    Error,
+    Truncate,
+    Summarize,
+}
+
+// What was done to the conversation to fit it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fitted {
+    Whole,
+    // messages dropped
+    Truncated(usize),
+    // messages summarized
+    Summarized(usize),
+}
+
+// Tokens left for the conversation once the reply has room, a quarter of the context unless
+// max_tokens says
+pub fn budget(context_size: u64, max_tokens: Option<u64>) -> u64 {
+    context_size.saturating_sub(max_tokens.unwrap_or(context_size / 4))
+}
+
+// Rough tokens of OpenAI messages
+pub fn tokens(messages: &[Value]) -> u64 {
+    messages.iter().map(message_tokens).sum()
+}
+
+fn message_tokens(message: &Value) -> u64 {
+    let calls = match &message["tool_calls"] {
+        Value::Null => 0,
+        calls => attachment::estimate_tokens(&calls.to_string()),
+    };
+    MESSAGE_OVERHEAD + attachment::estimate_tokens(&text(message)) + calls
+}
+
+fn text(message: &Value) -> String {
+    match &message["content"] {
+        Value::String(content) => content.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+// Make the OpenAI messages fit in the budget as the policy says, `summarize` answering the
+// messages asking for a summary. The system prompts and the last turn are always kept.
+pub fn fit(
+    messages: &mut Vec<Value>,
+    budget: u64,
+    policy: OverflowPolicy,
+    summarize: impl FnOnce(Vec<Value>) -> anyhow::Result<String>,
+) -> anyhow::Result<Fitted> {
+    let total = tokens(messages);
+    if total <= budget {
+        return Ok(Fitted::Whole);
+    }
+    if policy == OverflowPolicy::Error {
+        bail!(
+            "The conversation of about {} tokens is over the {} the context leaves it, start over or choose another context overflow policy",
+            total,
+            budget
+        );
+    }
+
+    let is_note =
+        |message: &Value| message["role"] == "system" && text(message).starts_with(SUMMARY_NOTE);
+    let mut note = messages.iter().find(|message| is_note(message)).cloned();
+    let system = messages
+        .iter()
+        .filter(|message| message["role"] == "system" && !is_note(message))
+        .cloned()
+        .collect::<Vec<_>>();
+    let turns = messages
+        .iter()
+        .filter(|message| message["role"] != "system")
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // the summary is not written yet, room is kept for it
+    let room = match policy {
+        OverflowPolicy::Summarize => budget - budget / SUMMARY_SHARE,
+        _ => budget.saturating_sub(note.as_ref().map_or(0, message_tokens)),
+    };
+    let mut kept = first_kept(&system, &turns, room)?;
+    let mut fitted = Fitted::Truncated(kept);
+    if policy == OverflowPolicy::Summarize && (kept > 0 || note.is_some()) {
+        let mut transcript = note.as_ref().map(text).unwrap_or_default();
+        for turn in &turns[..kept] {
+            transcript.push_str(&format!(
+                "\n{}: {}",
+                turn["role"].as_str().unwrap_or("user"),
+                text(turn)
+            ));
+        }
+        let request = vec![
+            json!({
+                "role": "system",
+                "content": "Summarize the conversation below in a few sentences, keeping the facts, names, numbers and decisions the rest of it may need. Reply with the summary alone.",
+            }),
+            json!({ "role": "user", "content": transcript.trim() }),
+        ];
+        match summarize(request) {
+            Ok(summary) => {
+                note = Some(json!({
+                    "role": "system",
+                    "content": format!("{} {}", SUMMARY_NOTE, summary.trim()),
+                }));
+                fitted = Fitted::Summarized(kept);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "failed to summarize the conversation, dropping its oldest turns instead: {:#}",
+                    e
+                );
+                note = None;
+            }
+        }
+        // a summary longer than the room kept for it takes more turns out
+        let room = budget.saturating_sub(note.as_ref().map_or(0, message_tokens));
+        let more = first_kept(&system, &turns[kept..], room)?;
+        if more > 0 {
+            kept += more;
+            fitted = Fitted::Truncated(kept);
+        }
+    }
+
+    *messages = system
+        .into_iter()
+        .chain(note)
+        .chain(turns.into_iter().skip(kept))
+        .collect();
+
+    Ok(fitted)
+}
+
+// Index of the first turn kept so that the system prompts and the turns from it fit in the
+// budget, turns starting at a message of the user so that no reply or tool result is left
+// without what it answers
+fn first_kept(system: &[Value], turns: &[Value], budget: u64) -> anyhow::Result<usize> {
+    let fixed = tokens(system);
+    let mut rest = tokens(turns);
+    for (i, turn) in turns.iter().enumerate() {
+        let starts = i == 0 || turn["role"] == "user";
+        if starts && fixed + rest <= budget {
+            return Ok(i);
+        }
+        rest -= message_tokens(turn);
+    }
+
+    let last = turns
+        .iter()
+        .rposition(|turn| turn["role"] == "user")
+        .unwrap_or(turns.len().saturating_sub(1));
+    bail!(
+        "The last message with the system prompt, about {} tokens, is over the {} the context leaves it",
+        fixed + tokens(&turns[last..]),
+        budget
+    )
+}
+
+// What the gateway does with conversations outgrowing the context of the model, from
+// `gateway.context_overflow`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverflowConfig {
+    pub policy: OverflowPolicy,
+    // context size of the served models, those loaded on demand have their own
+    pub context_size: Option<u64>,
+}
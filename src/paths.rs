@@ -0,0 +1,236 @@
+use crate::config;
+use crate::crash;
+use crate::identity;
+use crate::keys;
+use crate::lock;
+use crate::signature;
+use anyhow::anyhow;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+// Where each kind of file managed by gaia lives
+#[derive(Debug, Clone)]
+pub struct Dirs {
+    // node files, e.g. config.toml
+    pub config: PathBuf,
+    // files that can be downloaded again: models and wasm apps
+    pub cache: PathBuf,
+    // prompts and RAG collections
+    pub data: PathBuf,
+    // logs
+    pub state: PathBuf,
+    // state of the running services and the daemon socket
+    pub runtime: PathBuf,
+}
+impl Dirs {
+    // Everything in one directory, as `~/.gaia` and `$GAIA_HOME` are laid out
+    fn single(root: PathBuf) -> Self {
+        Self {
+            config: root.clone(),
+            cache: root.clone(),
+            data: root.clone(),
+            state: root.clone(),
+            runtime: root.join("run"),
+        }
+    }
+}
+
+pub fn home() -> anyhow::Result<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .ok_or(anyhow!("Cannot determine the home directory"))
+}
+
+// An absolute directory from the variable, relative values are ignored as the XDG spec says
+fn env_dir(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+}
+
+// `$GAIA_HOME` puts everything in one directory, an existing `~/.gaia` keeps being used so
+// upgrading moves no files, otherwise the platform's directories are used
+pub fn dirs() -> anyhow::Result<Dirs> {
+    if let Some(root) = env_dir("GAIA_HOME") {
+        return Ok(Dirs::single(root));
+    }
+    let home = home()?;
+    let legacy = home.join(".gaia");
+    if legacy.is_dir() {
+        return Ok(Dirs::single(legacy));
+    }
+
+    Ok(platform_dirs(&home))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_dirs(home: &Path) -> Dirs {
+    let xdg = |name: &str, default: &str| env_dir(name).unwrap_or(home.join(default)).join("gaia");
+    let state = xdg("XDG_STATE_HOME", ".local/state");
+    Dirs {
+        config: xdg("XDG_CONFIG_HOME", ".config"),
+        cache: xdg("XDG_CACHE_HOME", ".cache"),
+        data: xdg("XDG_DATA_HOME", ".local/share"),
+        // without a runtime directory the state survives reboots, stale entries are pruned
+        runtime: env_dir("XDG_RUNTIME_DIR")
+            .map(|dir| dir.join("gaia"))
+            .unwrap_or(state.join("run")),
+        state,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn platform_dirs(home: &Path) -> Dirs {
+    let support = home.join("Library/Application Support/gaia");
+    Dirs {
+        config: support.clone(),
+        cache: home.join("Library/Caches/gaia"),
+        data: support.clone(),
+        state: home.join("Library/Logs/gaia"),
+        runtime: support.join("run"),
+    }
+}
+
+#[cfg(windows)]
+fn platform_dirs(home: &Path) -> Dirs {
+    let roaming = env_dir("APPDATA")
+        .unwrap_or(home.join("AppData/Roaming"))
+        .join("gaia");
+    let local = env_dir("LOCALAPPDATA")
+        .unwrap_or(home.join("AppData/Local"))
+        .join("gaia");
+    Dirs {
+        config: roaming.clone(),
+        cache: local.join("cache"),
+        data: roaming,
+        state: local.clone(),
+        runtime: local.join("run"),
+    }
+}
+
+// Node files read when none is given
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.config)
+}
+
+// Downloaded models
+pub fn models_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.cache.join("models"))
+}
+
+pub fn prompts_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.data.join("prompts"))
+}
+
+// Presets made with `gaia preset create`
+pub fn presets_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.data.join("presets"))
+}
+
+// State of the running services
+pub fn run_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.runtime)
+}
+
+pub fn log_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.state.join("logs"))
+}
+
+// Reports of the crashes of gaia, once they are enabled
+pub fn crashes_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.state.join("crashes"))
+}
+
+// Lines typed into `gaia chat`, one entry per line
+pub fn history_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.state.join("chat_history"))
+}
+
+// What the API keys of the gateway used against their quotas
+pub fn key_usage_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.state.join("key_usage.json"))
+}
+
+// Downloaded wasm apps, e.g. llama-api-server.wasm
+pub fn apps_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.cache.join("apps"))
+}
+
+// wasi-nn plugins downloaded for the device, one directory per build and version
+pub fn plugins_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.cache.join("plugins"))
+}
+
+// Manifests of the RAG collections
+pub fn rag_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.data.join("rag"))
+}
+
+// WasmEdge versions installed with `gaia runtime install`
+pub fn runtimes_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.data.join("runtimes"))
+}
+
+// Keys and device id of a GaiaNet node, kept by `gaia migrate`
+pub fn identity_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs()?.data.join("identity"))
+}
+
+// Every file and directory gaia writes, what `gaia uninstall` removes and nothing else of the
+// directories above, which may be shared with other programs
+pub fn managed() -> anyhow::Result<Vec<PathBuf>> {
+    let config_dir = config_dir()?;
+    let mut managed = config::DEFAULT_FILES
+        .iter()
+        .chain(&keys::FILES)
+        .chain([
+            &lock::LOCK_FILE,
+            &identity::NAME_FILE,
+            &crash::SETTINGS_FILE,
+        ])
+        .map(|name| config_dir.join(name))
+        .collect::<Vec<_>>();
+    managed.extend([
+        signature::keys_dir()?,
+        models_dir()?,
+        apps_dir()?,
+        plugins_dir()?,
+        prompts_dir()?,
+        presets_dir()?,
+        rag_dir()?,
+        runtimes_dir()?,
+        identity_dir()?,
+        log_dir()?,
+        crashes_dir()?,
+        history_path()?,
+        key_usage_path()?,
+        run_dir()?,
+    ]);
+
+    Ok(managed)
+}
+
+pub fn command_paths() -> anyhow::Result<()> {
+    let dirs = dirs()?;
+    for (name, path) in [
+        ("config", dirs.config.clone()),
+        ("models", models_dir()?),
+        ("apps", apps_dir()?),
+        ("prompts", prompts_dir()?),
+        ("presets", presets_dir()?),
+        ("rag", rag_dir()?),
+        ("runtimes", runtimes_dir()?),
+        ("identity", identity_dir()?),
+        ("logs", log_dir()?),
+        ("crashes", crashes_dir()?),
+        ("history", history_path()?),
+        ("run", dirs.runtime.clone()),
+    ] {
+        println!("{:<8} {}", name, path.display());
+    }
+
+    Ok(())
+}
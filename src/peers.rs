@@ -0,0 +1,194 @@
+use crate::config;
+use crate::identity;
+use crate::node::NodeConfig;
+use anyhow::anyhow;
+use console::style;
+use reqwest::{
+    blocking::{Client, RequestBuilder},
+    Method,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    env,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Header of the requests a node forwards to a peer, naming the node. The peer answers them with
+// its own models and never forwards them again, so nodes peering each other do not loop.
+pub const FORWARDED: &str = "X-Gaia-Forwarded";
+
+// How long the models a peer listed are trusted before asking again
+const LIST_TTL: Duration = Duration::from_secs(30);
+// How long a peer has to list its models
+const LIST_TIMEOUT: Duration = Duration::from_secs(3);
+
+// The models each peer listed and when, by url
+static LISTED: Mutex<BTreeMap<String, (Instant, Vec<String>)>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    // the request the thread answers was forwarded by a peer
+    static FORWARDED_HERE: Cell<bool> = const { Cell::new(false) };
+}
+
+// Another node of `gateway.peers`, the requests for models this node does not serve are sent
+// to the first peer serving them, e.g. `{ url = "http://10.0.0.7:8000/v1", api_key = "$KEY" }`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    // base url of the peer's OpenAI API, its gateway's when it has one
+    pub url: String,
+    // key the peer's gateway asks for, `$NAME` reading it from the environment
+    pub api_key: Option<String>,
+}
+impl PeerConfig {
+    fn key(&self) -> anyhow::Result<Option<String>> {
+        self.api_key
+            .as_ref()
+            .map(|key| match key.strip_prefix('$') {
+                Some(name) => env::var(name).map_err(|_| {
+                    anyhow!(
+                        "gateway.peers: {} names ${}, which is not set",
+                        self.url,
+                        name
+                    )
+                }),
+                None => Ok(key.clone()),
+            })
+            .transpose()
+    }
+
+    // A request to the path of the peer's OpenAI API, with its key and marked as forwarded
+    pub fn request(
+        &self,
+        http: &Client,
+        method: Method,
+        path: &str,
+    ) -> anyhow::Result<RequestBuilder> {
+        let url = format!(
+            "{}/{}",
+            self.url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        let mut request = http
+            .request(method, url)
+            .header(FORWARDED, identity::name());
+        if let Some(key) = self.key()? {
+            request = request.bearer_auth(key);
+        }
+
+        Ok(request)
+    }
+
+    // Ask the peer for the models it serves itself
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let http = Client::builder().timeout(LIST_TIMEOUT).build()?;
+        let response = self.request(&http, Method::GET, "models")?.send()?;
+        if !response.status().is_success() {
+            return Err(anyhow!("it answered {}", response.status()));
+        }
+        let listed: Value = response.json()?;
+
+        Ok(listed["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|model| model["id"].as_str().map(String::from))
+            .collect())
+    }
+}
+
+// Models the peer serves, listed again once the last list is old. A peer that does not answer
+// serves none until then.
+pub fn models(peer: &PeerConfig) -> Vec<String> {
+    if let Some((at, models)) = LISTED.lock().unwrap().get(&peer.url) {
+        if at.elapsed() < LIST_TTL {
+            return models.clone();
+        }
+    }
+    let models = peer.list().unwrap_or_else(|e| {
+        tracing::warn!(
+            peer = peer.url,
+            "cannot list the models of the peer: {:#}",
+            e
+        );
+        Vec::new()
+    });
+    LISTED
+        .lock()
+        .unwrap()
+        .insert(peer.url.clone(), (Instant::now(), models.clone()));
+
+    models
+}
+
+// The first peer serving the model, none for requests a peer forwarded
+pub fn find<'a>(peers: &'a [PeerConfig], model: &str) -> Option<&'a PeerConfig> {
+    if forwarded() {
+        return None;
+    }
+    peers
+        .iter()
+        .find(|peer| models(peer).iter().any(|served| served == model))
+}
+
+// Forget what the peer listed, e.g. once it failed a request
+pub fn forget(peer: &PeerConfig) {
+    LISTED.lock().unwrap().remove(&peer.url);
+}
+
+// Mark the requests the thread answers as forwarded by a peer, until the guard is dropped
+pub fn mark(forwarded: bool) -> Marked {
+    FORWARDED_HERE.with(|here| here.set(forwarded));
+    Marked
+}
+
+pub struct Marked;
+impl Drop for Marked {
+    fn drop(&mut self) {
+        FORWARDED_HERE.with(|here| here.set(false));
+    }
+}
+
+pub fn forwarded() -> bool {
+    FORWARDED_HERE.with(|here| here.get())
+}
+
+// List the peers of the node file with the models each serves
+pub fn command_list(file: Option<PathBuf>) -> anyhow::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => config::default_path()?,
+    };
+    let peers = NodeConfig::load(&file)?
+        .gateway
+        .map(|gateway| gateway.peers)
+        .unwrap_or_default();
+    if peers.is_empty() {
+        println!("{} has no peers, add them to gateway.peers", file.display());
+        return Ok(());
+    }
+
+    for peer in &peers {
+        match peer.list() {
+            Ok(models) => println!(
+                "{} {} {}",
+                style(&peer.url).bold(),
+                style("up").green(),
+                models.join(", ")
+            ),
+            Err(e) => println!(
+                "{} {} {:#}",
+                style(&peer.url).bold(),
+                style("not answering").red(),
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use crate::term;
+use anyhow::bail;
+use console::style;
+
+// A line of the summary printed before launching the services
+pub struct Check {
+    item: String,
+    value: String,
+    problem: Option<Problem>,
+}
+
+enum Problem {
+    Warning(String),
+    // the error keeps its kind, so the exit code tells what failed
+    Failure(anyhow::Error),
+}
+
+impl Check {
+    pub fn new(item: &str, value: impl Into<String>) -> Self {
+        Self {
+            item: item.to_string(),
+            value: value.into(),
+            problem: None,
+        }
+    }
+
+    pub fn warn(mut self, message: impl Into<String>) -> Self {
+        self.problem = Some(Problem::Warning(message.into()));
+        self
+    }
+
+    // Failed when the check returned an error
+    pub fn result(mut self, result: anyhow::Result<()>) -> Self {
+        if let Err(e) = result {
+            self.problem = Some(Problem::Failure(e));
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+pub struct Preflight {
+    checks: Vec<Check>,
+}
+
+impl Preflight {
+    pub fn push(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    // Print the summary, then fail with the first failed check, or ask before going on
+    // unless `ask` is off
+    pub fn confirm(self, ask: bool) -> anyhow::Result<()> {
+        let width = self
+            .checks
+            .iter()
+            .map(|check| check.item.len())
+            .max()
+            .unwrap_or(0);
+        println!("{}", style("Preflight").bold());
+        let mut failure = None;
+        for check in self.checks {
+            println!("  {:<width$}  {}", check.item, check.value, width = width);
+            match check.problem {
+                Some(Problem::Warning(message)) => {
+                    println!("  {:<width$}  {} {}", "", style("!").yellow(), message)
+                }
+                Some(Problem::Failure(e)) => {
+                    println!("  {:<width$}  {} {}", "", style("✗").red(), e);
+                    failure.get_or_insert(e);
+                }
+                None => {}
+            }
+        }
+        if let Some(e) = failure {
+            return Err(e);
+        }
+
+        if ask && term::interactive() && !term::confirm("Start the services?", true)? {
+            bail!("Nothing was started");
+        }
+        Ok(())
+    }
+}
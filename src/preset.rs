@@ -0,0 +1,254 @@
+use crate::client::SamplingArgs;
+use crate::events;
+use crate::paths;
+use crate::template::PromptTemplateType;
+use anyhow::{anyhow, bail};
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fs, path::PathBuf, str::FromStr};
+
+// Name of the preset in use, kept next to the presets
+const CURRENT_FILE: &str = "current";
+
+// A model with the way to talk to it, applied as a whole by `gaia start --preset` and `/preset`
+// in chat, e.g. presets/writing.toml:
+//
+//   model = "Llama-3.2-3B-Instruct-Q5_K_M.gguf"
+//   model_name = "writer"
+//   prompt_template = "llama-3-chat"
+//   system_prompt = "You are a careful editor."
+//   [params]
+//   temperature = 0.9
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // url or path of the gguf model, as `gaia start --model` takes it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // name the model is served and requested under
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "SamplingArgs::is_unset")]
+    pub params: SamplingArgs,
+}
+impl Preset {
+    pub fn template(&self) -> anyhow::Result<Option<PromptTemplateType>> {
+        self.prompt_template
+            .as_deref()
+            .map(PromptTemplateType::from_str)
+            .transpose()
+    }
+
+    // The model name, system prompt and sampling parameters given on the command line, the
+    // preset filling what they leave unset
+    pub fn under(&self, given: &Preset) -> Preset {
+        Preset {
+            model_name: given.model_name.clone().or(self.model_name.clone()),
+            system_prompt: given.system_prompt.clone().or(self.system_prompt.clone()),
+            params: given.params.clone().or(&self.params),
+            ..self.clone()
+        }
+    }
+}
+
+fn preset_path(name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        || name.starts_with('.')
+        || name == CURRENT_FILE
+    {
+        bail!(
+            "Invalid preset name '{}', use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+
+    Ok(paths::presets_dir()?.join(format!("{}.toml", name)))
+}
+
+pub fn load(name: &str) -> anyhow::Result<Preset> {
+    let path = preset_path(name)?;
+    if !path.exists() {
+        bail!("No preset named '{}' found, see `gaia preset list`", name);
+    }
+    let content = fs::read_to_string(&path)?;
+    let preset: Preset =
+        toml::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    preset
+        .template()
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    Ok(preset)
+}
+
+// Name of the preset selected with `gaia preset use`
+pub fn current() -> anyhow::Result<Option<String>> {
+    let path = paths::presets_dir()?.join(CURRENT_FILE);
+    Ok(fs::read_to_string(path)
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty()))
+}
+
+// The preset named on the command line, or else the one in use
+pub fn selected(name: Option<&str>) -> anyhow::Result<Option<(String, Preset)>> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => match current()? {
+            Some(name) => name,
+            None => return Ok(None),
+        },
+    };
+    let preset = load(&name)?;
+
+    Ok(Some((name, preset)))
+}
+
+fn select(name: &str) -> anyhow::Result<()> {
+    let dir = paths::presets_dir()?;
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(CURRENT_FILE), format!("{}\n", name))?;
+    events::emit("preset-selected", json!({ "name": name }));
+
+    Ok(())
+}
+
+pub fn command_create(name: &str, preset: Preset, force: bool) -> anyhow::Result<()> {
+    if preset.model.is_none()
+        && preset.model_name.is_none()
+        && preset.prompt_template.is_none()
+        && preset.system_prompt.is_none()
+        && preset.params.is_unset()
+    {
+        bail!("Nothing to keep in the preset, give a model, a prompt template, a system prompt or sampling parameters");
+    }
+    let path = preset_path(name)?;
+    if path.exists() && !force {
+        bail!(
+            "A preset named '{}' already exists, use --force to overwrite it",
+            name
+        );
+    }
+
+    fs::create_dir_all(paths::presets_dir()?)?;
+    fs::write(&path, toml::to_string_pretty(&preset)?)?;
+    events::emit("preset-saved", json!({ "name": name }));
+    println!("Saved preset '{}' to {}", name, path.display());
+
+    Ok(())
+}
+
+// The presets, the one in use marked
+pub fn command_list() -> anyhow::Result<()> {
+    let dir = paths::presets_dir()?;
+    let mut names = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|res| {
+                res.ok().and_then(|e| {
+                    e.path()
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .and_then(|n| n.strip_suffix(".toml"))
+                        .map(String::from)
+                })
+            })
+            .collect::<Vec<String>>(),
+        Err(_) => Vec::new(),
+    };
+    if names.is_empty() {
+        println!("No presets, add one with `gaia preset create`");
+        return Ok(());
+    }
+
+    names.sort();
+    let current = current()?;
+    for name in names {
+        let mark = match current.as_deref() == Some(name.as_str()) {
+            true => "*",
+            false => " ",
+        };
+        match load(&name) {
+            Ok(preset) => {
+                let mut line = format!("{} {}", mark, style(&name).bold());
+                if let Some(description) = &preset.description {
+                    line.push_str(&format!("  {}", description));
+                }
+                let mut parts = Vec::new();
+                if let Some(model) = preset.model_name.as_ref().or(preset.model.as_ref()) {
+                    parts.push(format!("model: {}", model));
+                }
+                if let Some(template) = &preset.prompt_template {
+                    parts.push(format!("template: {}", template));
+                }
+                if !preset.params.is_unset() {
+                    parts.push(format!("params: {}", params_line(&preset.params)));
+                }
+                if !parts.is_empty() {
+                    line.push_str(&format!("  ({})", parts.join(", ")));
+                }
+                println!("{}", line);
+            }
+            Err(e) => println!("{} {}  {}", mark, style(&name).bold(), style(e).red()),
+        }
+    }
+
+    Ok(())
+}
+
+// The sampling parameters as `name=value` pairs
+fn params_line(params: &SamplingArgs) -> String {
+    match serde_json::to_value(params) {
+        Ok(serde_json::Value::Object(params)) => params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+// Use the preset for chats from now on, and for `gaia start` when no model is given
+pub fn command_use(name: &str) -> anyhow::Result<()> {
+    load(name)?;
+    select(name)?;
+    println!("Using the preset '{}'", name);
+
+    Ok(())
+}
+
+pub fn command_remove(name: &str) -> anyhow::Result<()> {
+    let path = preset_path(name)?;
+    if !path.exists() {
+        bail!("No preset named '{}' found", name);
+    }
+    fs::remove_file(&path)?;
+    if current()?.as_deref() == Some(name) {
+        fs::remove_file(paths::presets_dir()?.join(CURRENT_FILE))?;
+    }
+    events::emit("preset-removed", json!({ "name": name }));
+    println!("Removed preset '{}'", name);
+
+    Ok(())
+}
+
+// Once `gaia start --preset` applied it, chats use the preset too
+pub fn started(name: &str) -> anyhow::Result<()> {
+    if current()?.as_deref() != Some(name) {
+        select(name)?;
+        println!(
+            "{}",
+            style(format!("Chats use the preset '{}' from now on", name)).dim()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use crate::notify;
+use clap::{Args, ValueEnum};
+use serde_json::{json, Value};
+use std::{
+    io::{self, Read, Write},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+// Least time between two events of the same task, a GUI does not need more
+const INTERVAL: Duration = Duration::from_millis(250);
+
+static FORMAT: OnceLock<ProgressFormat> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    // the usual messages only
+    Text,
+    // also one JSON object per line on stdout for each step of long operations
+    Json,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ProgressArgs {
+    #[arg(
+        long = "progress",
+        help = "Also report the progress of downloads, ingestion and startup as JSON lines on stdout",
+        value_name = "FORMAT",
+        default_value = "text",
+        global = true
+    )]
+    pub progress: ProgressFormat,
+}
+
+pub fn init(args: &ProgressArgs) {
+    let _ = FORMAT.set(args.progress);
+}
+
+fn enabled() -> bool {
+    FORMAT.get() == Some(&ProgressFormat::Json)
+}
+
+// A one-off event, e.g. a step of the startup
+pub fn event(stage: &str, fields: Value) {
+    if !enabled() {
+        return;
+    }
+    let mut event = json!({ "stage": stage });
+    if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), fields) {
+        event.extend(fields);
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", event);
+    let _ = stdout.flush();
+}
+
+// A task counting up to a total, e.g. bytes of a download or files of an ingestion
+pub struct Progress {
+    stage: &'static str,
+    name: String,
+    unit: &'static str,
+    current: u64,
+    total: Option<u64>,
+    last: Option<Instant>,
+    started: Instant,
+}
+impl Progress {
+    pub fn new(stage: &'static str, name: &str, unit: &'static str, total: Option<u64>) -> Self {
+        let progress = Self {
+            stage,
+            name: name.to_string(),
+            unit,
+            current: 0,
+            total,
+            last: None,
+            started: Instant::now(),
+        };
+        progress.emit("start");
+
+        progress
+    }
+
+    pub fn advance(&mut self, n: u64) {
+        self.current += n;
+        if self.last.is_none_or(|last| last.elapsed() >= INTERVAL) {
+            self.last = Some(Instant::now());
+            self.emit("progress");
+        }
+    }
+
+    pub fn finish(self) {
+        self.emit("done");
+        let title = match self.stage {
+            "download" => "Download finished",
+            "ingest" => "Ingestion finished",
+            "batch" => "Batch finished",
+            _ => return,
+        };
+        notify::finished(title, &self.name, self.started.elapsed());
+    }
+
+    fn emit(&self, status: &str) {
+        let percent = self
+            .total
+            .filter(|total| *total > 0)
+            .map(|total| (self.current as f64 * 1000.0 / total as f64).round() / 10.0);
+        event(
+            self.stage,
+            json!({
+                "status": status,
+                "name": self.name,
+                "unit": self.unit,
+                "current": self.current,
+                "total": self.total,
+                "percent": percent,
+            }),
+        );
+    }
+}
+
+// Counts the bytes read through it
+pub struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Progress) -> Self {
+        Self { inner, progress }
+    }
+
+    pub fn finish(self) {
+        self.progress.finish();
+    }
+}
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,278 @@
+use crate::client::SamplingArgs;
+use crate::events;
+use crate::paths;
+use anyhow::{anyhow, bail};
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+};
+
+// Parse a `--var name=value` argument, where `name=@path` reads the value from a file
+pub fn parse_var(arg: &str) -> anyhow::Result<(String, String)> {
+    let (name, value) = arg
+        .split_once('=')
+        .ok_or(anyhow!("Expected NAME=VALUE or NAME=@FILE, got '{}'", arg))?;
+    let name = name.trim();
+    if name.is_empty() {
+        bail!("Variable name is empty in '{}'", arg);
+    }
+
+    let value = match value.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path, e))?,
+        None => value.to_string(),
+    };
+
+    Ok((name.to_string(), value))
+}
+
+// A piece of a prompt template
+enum Piece<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+// The template as text and placeholders. `{{{{` is a literal `{{`, and a `{{` never closed is
+// left as it is, so that prompts with code in them need no escaping.
+fn pieces(template: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        pieces.push(Piece::Text(&rest[..start]));
+        let after = &rest[start + 2..];
+        if let Some(escaped) = after.strip_prefix("{{") {
+            pieces.push(Piece::Text("{{"));
+            rest = escaped;
+            continue;
+        }
+        match after.find("}}") {
+            Some(end) => {
+                pieces.push(Piece::Placeholder(after[..end].trim()));
+                rest = &after[end + 2..];
+            }
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+// Replace every `{{name}}` placeholder in the template with the value of the variable
+pub fn render(template: &str, vars: &HashMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut missing = Vec::new();
+
+    for piece in pieces(template) {
+        match piece {
+            Piece::Text(text) => rendered.push_str(text),
+            Piece::Placeholder(name) => match vars.get(name) {
+                Some(value) => rendered.push_str(value),
+                None => {
+                    if !missing.contains(&name) {
+                        missing.push(name);
+                    }
+                }
+            },
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "Missing value for {}, pass them with --var NAME=VALUE",
+            missing
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(rendered)
+}
+
+// Resolve the prompt of `run` from the command line, a prompt file or the prompt library,
+// then substitute the variables
+pub fn resolve(
+    prompt: Option<String>,
+    prompt_file: Option<PathBuf>,
+    saved: Option<&SavedPrompt>,
+    vars: Vec<(String, String)>,
+) -> anyhow::Result<String> {
+    let template = match (prompt, prompt_file, saved) {
+        (Some(prompt), _, _) => prompt,
+        (None, Some(path), _) => {
+            fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+        }
+        (None, None, Some(saved)) => saved.prompt.clone(),
+        (None, None, None) => {
+            bail!("No prompt given, pass one or use --prompt-file or --prompt-name")
+        }
+    };
+
+    render(&template, &values(saved, vars))
+}
+
+// The template of a prompt file or saved prompt, filled for each row of `run --input`, with the
+// variables its rows start from
+pub fn template(
+    prompt_file: Option<PathBuf>,
+    saved: Option<&SavedPrompt>,
+    vars: Vec<(String, String)>,
+) -> anyhow::Result<(Option<String>, HashMap<String, String>)> {
+    let template = match (prompt_file, saved) {
+        (Some(path), _) => {
+            Some(fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", path.display(), e))?)
+        }
+        (None, Some(saved)) => Some(saved.prompt.clone()),
+        (None, None) => None,
+    };
+
+    Ok((template, values(saved, vars)))
+}
+
+// Variables given on the command line override the defaults of the saved prompt
+fn values(saved: Option<&SavedPrompt>, vars: Vec<(String, String)>) -> HashMap<String, String> {
+    let mut values: HashMap<String, String> = saved
+        .map(|saved| saved.vars.clone().into_iter().collect())
+        .unwrap_or_default();
+    values.extend(vars);
+    values
+}
+
+// A named prompt kept in the prompt library
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SavedPrompt {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    // preferred model to send the prompt to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    // default values of the placeholders
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "SamplingArgs::is_unset")]
+    pub params: SamplingArgs,
+}
+
+fn prompt_path(name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        || name.starts_with('.')
+    {
+        bail!(
+            "Invalid prompt name '{}', use letters, digits, '-', '_' and '.'",
+            name
+        );
+    }
+
+    Ok(paths::prompts_dir()?.join(format!("{}.toml", name)))
+}
+
+pub fn load_saved(name: &str) -> anyhow::Result<SavedPrompt> {
+    let path = prompt_path(name)?;
+    if !path.exists() {
+        bail!("No prompt named '{}' found, see `gaia prompts list`", name);
+    }
+    let content = fs::read_to_string(&path)?;
+
+    toml::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+pub fn command_prompts_save(name: &str, saved: SavedPrompt, force: bool) -> anyhow::Result<()> {
+    let path = prompt_path(name)?;
+    if path.exists() && !force {
+        bail!(
+            "A prompt named '{}' already exists, use --force to overwrite it",
+            name
+        );
+    }
+
+    fs::create_dir_all(paths::prompts_dir()?)?;
+    fs::write(&path, toml::to_string_pretty(&saved)?)?;
+    events::emit("prompt-saved", json!({ "name": name }));
+    println!("Saved prompt '{}' to {}", name, path.display());
+
+    Ok(())
+}
+
+pub fn command_prompts_list() -> anyhow::Result<()> {
+    let dir = paths::prompts_dir()?;
+    let mut names = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|res| {
+                res.ok().and_then(|e| {
+                    e.path()
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .and_then(|n| n.strip_suffix(".toml"))
+                        .map(String::from)
+                })
+            })
+            .collect::<Vec<String>>(),
+        Err(_) => Vec::new(),
+    };
+    if names.is_empty() {
+        println!("No saved prompts, add one with `gaia prompts save`");
+        return Ok(());
+    }
+
+    names.sort();
+    for name in names {
+        match load_saved(&name) {
+            Ok(saved) => {
+                let mut line = style(&name).bold().to_string();
+                if let Some(description) = &saved.description {
+                    line.push_str(&format!("  {}", description));
+                }
+                let placeholders = placeholders(&saved.prompt);
+                if !placeholders.is_empty() {
+                    line.push_str(&format!("  [{}]", placeholders.join(", ")));
+                }
+                if let Some(model) = &saved.model {
+                    line.push_str(&format!("  (model: {})", model));
+                }
+                println!("{}", line);
+            }
+            Err(e) => println!("{}  {}", style(&name).bold(), style(e).red()),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn command_prompts_remove(name: &str) -> anyhow::Result<()> {
+    let path = prompt_path(name)?;
+    if !path.exists() {
+        bail!("No prompt named '{}' found", name);
+    }
+    fs::remove_file(&path)?;
+    events::emit("prompt-removed", json!({ "name": name }));
+    println!("Removed prompt '{}'", name);
+
+    Ok(())
+}
+
+// Names of the placeholders used in the template, in order of appearance
+pub fn placeholders(template: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for piece in pieces(template) {
+        if let Piece::Placeholder(name) = piece {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names
+}
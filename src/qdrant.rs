@@ -0,0 +1,362 @@
+use crate::error::{fail, ErrorKind, Tag};
+use crate::store::{CollectionInfo, Condition, Point, ScoredPoint, VectorStore};
+use anyhow::{anyhow, bail};
+use clap::Args;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    fs::{self, File},
+    io::copy,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const DEFAULT_QDRANT_URL: &str = "http://localhost:6333";
+
+#[derive(Debug, Clone, Args)]
+pub struct QdrantArgs {
+    #[arg(
+        long = "qdrant-url",
+        help = "Url of the Qdrant server, use https for a remote one",
+        env = "QDRANT_URL",
+        default_value = DEFAULT_QDRANT_URL
+    )]
+    pub url: String,
+    #[arg(
+        long = "qdrant-api-key",
+        help = "API key of the Qdrant server",
+        value_name = "KEY",
+        env = "QDRANT_API_KEY",
+        hide_env_values = true
+    )]
+    pub api_key: Option<String>,
+    #[arg(
+        long = "qdrant-ca-cert",
+        help = "PEM certificate to trust for a Qdrant server with a self-signed certificate",
+        value_name = "FILE"
+    )]
+    pub ca_cert: Option<PathBuf>,
+}
+
+// Points are upserted in batches to keep the requests small
+const UPSERT_BATCH: usize = 64;
+
+// Points read per scroll request
+const SCROLL_PAGE: usize = 256;
+
+// Snapshots of large collections take a while to create and download
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Deserialize)]
+struct QdrantResponse<T> {
+    result: T,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotDescription {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrollResult {
+    points: Vec<ScrolledPoint>,
+    next_page_offset: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrolledPoint {
+    #[serde(default)]
+    payload: Value,
+}
+
+// Minimal client of the Qdrant REST API
+pub struct Qdrant {
+    url: String,
+    http: reqwest::blocking::Client,
+}
+impl Qdrant {
+    pub fn new(args: &QdrantArgs) -> anyhow::Result<Self> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60));
+        if let Some(api_key) = &args.api_key {
+            let mut headers = HeaderMap::new();
+            let mut value = HeaderValue::from_str(api_key)
+                .map_err(|_| anyhow!("The Qdrant API key is not a valid header value"))?;
+            value.set_sensitive(true);
+            headers.insert("api-key", value);
+            builder = builder.default_headers(headers);
+        }
+        if let Some(path) = &args.ca_cert {
+            let pem = fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        Ok(Self {
+            url: args.url.trim_end_matches('/').to_string(),
+            http: builder.build()?,
+        })
+    }
+
+    fn send(&self, request: reqwest::blocking::RequestBuilder) -> anyhow::Result<Value> {
+        let request = request.build()?;
+        let _span =
+            tracing::debug_span!("qdrant", method = %request.method(), url = %request.url())
+                .entered();
+        let response = self
+            .http
+            .execute(request)
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to reach Qdrant at {}, is it running? {}",
+                    self.url,
+                    e
+                )
+            })
+            .tag(ErrorKind::Unreachable)?;
+
+        self.check(response)
+    }
+
+    fn check(&self, response: reqwest::blocking::Response) -> anyhow::Result<Value> {
+        let status = response.status();
+        tracing::debug!(status = status.as_u16(), "response");
+        if status == 401 || status == 403 {
+            return Err(fail(
+                ErrorKind::Api,
+                anyhow!(
+                    "Qdrant at {} rejected the request ({}), check --qdrant-api-key",
+                    self.url,
+                    status
+                ),
+            ));
+        }
+        if !status.is_success() {
+            return Err(fail(
+                ErrorKind::Api,
+                anyhow!(
+                    "Qdrant responded with {}: {}",
+                    status,
+                    response.text().unwrap_or_default().trim()
+                ),
+            ));
+        }
+
+        Ok(response.json()?)
+    }
+}
+
+impl VectorStore for Qdrant {
+    fn collection_exists(&self, name: &str) -> anyhow::Result<bool> {
+        let response = self
+            .http
+            .get(format!("{}/collections/{}", self.url, name))
+            .send()
+            .map_err(|e| anyhow!("Failed to reach Qdrant at {}: {}", self.url, e))
+            .tag(ErrorKind::Unreachable)?;
+        if response.status() == 404 {
+            return Ok(false);
+        }
+        // anything else, e.g. a rejected API key, surfaces as an error
+        self.check(response)?;
+
+        Ok(true)
+    }
+
+    fn create_collection(&self, name: &str, dimension: usize) -> anyhow::Result<()> {
+        self.send(
+            self.http
+                .put(format!("{}/collections/{}", self.url, name))
+                .json(&json!({ "vectors": { "size": dimension, "distance": "Cosine" } })),
+        )?;
+
+        Ok(())
+    }
+
+    fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()> {
+        for batch in points.chunks(UPSERT_BATCH) {
+            let points = batch
+                .iter()
+                .map(|point| json!({ "id": point.id, "vector": point.vector, "payload": point.payload }))
+                .collect::<Vec<_>>();
+            self.send(
+                self.http
+                    .put(format!(
+                        "{}/collections/{}/points?wait=true",
+                        self.url, collection
+                    ))
+                    .json(&json!({ "points": points })),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn delete_matching(&self, collection: &str, key: &str, value: &str) -> anyhow::Result<()> {
+        self.send(
+            self.http
+                .post(format!(
+                    "{}/collections/{}/points/delete?wait=true",
+                    self.url, collection
+                ))
+                .json(
+                    &json!({ "filter": { "must": [{ "key": key, "match": { "value": value } }] } }),
+                ),
+        )?;
+
+        Ok(())
+    }
+
+    fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<ScoredPoint>> {
+        let mut body = json!({ "vector": vector, "limit": limit, "with_payload": true });
+        if !filter.is_empty() {
+            body["filter"] = filter_json(filter);
+        }
+        let response = self.send(
+            self.http
+                .post(format!(
+                    "{}/collections/{}/points/search",
+                    self.url, collection
+                ))
+                .json(&body),
+        )?;
+        let response: QdrantResponse<Vec<ScoredPoint>> = serde_json::from_value(response)?;
+
+        Ok(response.result)
+    }
+    fn info(&self, collection: &str) -> anyhow::Result<CollectionInfo> {
+        let response = self.send(
+            self.http
+                .get(format!("{}/collections/{}", self.url, collection)),
+        )?;
+        let result = &response["result"];
+
+        Ok(CollectionInfo {
+            points: result["points_count"].as_u64().unwrap_or(0),
+            dimension: result["config"]["params"]["vectors"]["size"]
+                .as_u64()
+                .unwrap_or(0) as usize,
+            // the REST API does not report the size on disk
+            disk_bytes: None,
+        })
+    }
+
+    fn scroll(
+        &self,
+        collection: &str,
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<Value>> {
+        let mut payloads = Vec::new();
+        let mut offset = None;
+        while payloads.len() < limit {
+            let mut body = json!({
+                "limit": SCROLL_PAGE.min(limit - payloads.len()),
+                "with_payload": true,
+                "with_vector": false,
+            });
+            if let Some(offset) = offset.take() {
+                body["offset"] = offset;
+            }
+            if !filter.is_empty() {
+                body["filter"] = filter_json(filter);
+            }
+            let response = self.send(
+                self.http
+                    .post(format!(
+                        "{}/collections/{}/points/scroll",
+                        self.url, collection
+                    ))
+                    .json(&body),
+            )?;
+            let response: QdrantResponse<ScrollResult> = serde_json::from_value(response)?;
+
+            payloads.extend(
+                response
+                    .result
+                    .points
+                    .into_iter()
+                    .map(|point| point.payload),
+            );
+            match response.result.next_page_offset {
+                Some(next) if !next.is_null() => offset = Some(next),
+                _ => break,
+            }
+        }
+
+        Ok(payloads)
+    }
+
+    fn export_snapshot(&self, collection: &str, dest: &Path) -> anyhow::Result<u64> {
+        let response = self.send(
+            self.http
+                .post(format!(
+                    "{}/collections/{}/snapshots?wait=true",
+                    self.url, collection
+                ))
+                .timeout(SNAPSHOT_TIMEOUT),
+        )?;
+        let snapshot: QdrantResponse<SnapshotDescription> = serde_json::from_value(response)?;
+        let url = format!(
+            "{}/collections/{}/snapshots/{}",
+            self.url, collection, snapshot.result.name
+        );
+
+        let mut response = self
+            .http
+            .get(&url)
+            .timeout(SNAPSHOT_TIMEOUT)
+            .send()
+            .map_err(|e| anyhow!("Failed to reach Qdrant at {}: {}", self.url, e))?;
+        if !response.status().is_success() {
+            bail!(
+                "Failed to download snapshot {}: {}",
+                snapshot.result.name,
+                response.status()
+            );
+        }
+        let partial = dest.with_extension("part");
+        let size = copy(&mut response, &mut File::create(&partial)?)?;
+        fs::rename(&partial, dest)?;
+
+        // the copy on the server is not needed once downloaded
+        self.send(self.http.delete(&url))?;
+
+        Ok(size)
+    }
+
+    fn import_snapshot(&self, collection: &str, src: &Path) -> anyhow::Result<()> {
+        let form = reqwest::blocking::multipart::Form::new()
+            .file("snapshot", src)
+            .map_err(|e| anyhow!("{}: {}", src.display(), e))?;
+        // the snapshot wins over whatever the collection held
+        self.send(
+            self.http
+                .post(format!(
+                    "{}/collections/{}/snapshots/upload?wait=true&priority=snapshot",
+                    self.url, collection
+                ))
+                .multipart(form)
+                .timeout(SNAPSHOT_TIMEOUT),
+        )?;
+
+        Ok(())
+    }
+}
+
+// Qdrant filter requiring all the conditions
+fn filter_json(filter: &[Condition]) -> Value {
+    let must = filter
+        .iter()
+        .map(|condition| json!({ "key": condition.key, "match": { "value": condition.value } }))
+        .collect::<Vec<_>>();
+
+    json!({ "must": must })
+}
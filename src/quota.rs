@@ -0,0 +1,447 @@
+use crate::attachment;
+use crate::audit;
+use crate::blob::Utc;
+use crate::models;
+use crate::paths;
+use anyhow::anyhow;
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Bytes of a response kept to find the tokens it used
+const MAX_METERED: usize = 4 * 1024 * 1024;
+// How often the counts are written to key_usage.json, rather than on every request
+const SAVE_EVERY: Duration = Duration::from_secs(5);
+
+// Usage of the keys by the hash of the key, set once the gateway listens with `gateway.api_keys`
+static USAGE: OnceLock<Mutex<BTreeMap<String, KeyUsage>>> = OnceLock::new();
+// Whether the counts changed since they were written
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // the hash of the key of the request the thread answers, its replies are counted against it
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// An API key of `gateway.api_keys`, alone or with the requests and tokens it may use, e.g.
+//
+//   api_keys = [
+//     "$ADMIN_KEY",
+//     { key = "$TEAM_KEY", name = "team", daily_requests = 1000, monthly_tokens = 2000000 },
+//   ]
+//
+// Days and months are counted in UTC.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ApiKeyConfig {
+    Key(String),
+    Limited(LimitedKey),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitedKey {
+    pub key: String,
+    // shown by `gaia keys usage` in place of the key
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub quota: Quota,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_requests: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_requests: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_tokens: Option<u64>,
+}
+
+// A key the gateway lets in, read from the environment where it names a variable
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    // how the key is reported, never the key itself
+    pub label: String,
+    // hash of the key its usage is kept under, labels of distinct keys may be the same
+    pub id: String,
+    pub quota: Quota,
+}
+
+impl ApiKeyConfig {
+    pub fn resolve(&self) -> anyhow::Result<ApiKey> {
+        let (key, name, quota) = match self {
+            ApiKeyConfig::Key(key) => (key, None, Quota::default()),
+            ApiKeyConfig::Limited(limited) => {
+                (&limited.key, limited.name.clone(), limited.quota.clone())
+            }
+        };
+        let key = match key.strip_prefix('$') {
+            Some(name) => {
+                env::var(name).map_err(|_| anyhow!("gateway.api_keys: ${} is not set", name))?
+            }
+            None => key.clone(),
+        };
+
+        Ok(ApiKey {
+            label: name.unwrap_or_else(|| mask(&key)),
+            id: models::hex(&Sha256::digest(key.as_bytes())),
+            key,
+            quota,
+        })
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ApiKeyConfig::Key(_) => None,
+            ApiKeyConfig::Limited(limited) => limited.name.as_deref(),
+        }
+    }
+}
+
+// The key with all but its ends hidden, e.g. gaia-1…9f3c
+fn mask(key: &str) -> String {
+    let chars = key.chars().collect::<Vec<_>>();
+    match chars.len() {
+        0..=10 => format!(
+            "…{}",
+            chars[chars.len().saturating_sub(2)..]
+                .iter()
+                .collect::<String>()
+        ),
+        len => format!(
+            "{}…{}",
+            chars[..6].iter().collect::<String>(),
+            chars[len - 4..].iter().collect::<String>()
+        ),
+    }
+}
+
+// What a key used, by day and month, kept in key_usage.json so restarts do not reset it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyUsage {
+    // the label of the key when it was last used
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub quota: Quota,
+    // UTC day and month the counts are of, e.g. 2026-10-15 and 2026-10
+    pub day: String,
+    pub month: String,
+    pub daily_requests: u64,
+    pub daily_tokens: u64,
+    pub monthly_requests: u64,
+    pub monthly_tokens: u64,
+    pub total_requests: u64,
+    pub total_tokens: u64,
+    // unix time of the last request
+    pub used: u64,
+}
+impl KeyUsage {
+    // Start the counts of a new day or month
+    fn roll(&mut self, now: &Utc) {
+        let day = format!("{}-{:02}-{:02}", now.year, now.month, now.day);
+        let month = format!("{}-{:02}", now.year, now.month);
+        if self.day != day {
+            self.day = day;
+            self.daily_requests = 0;
+            self.daily_tokens = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly_requests = 0;
+            self.monthly_tokens = 0;
+        }
+    }
+
+    // The first quota used up, as (period, what, limit, used)
+    fn exceeded(&self) -> Option<(&'static str, &'static str, u64, u64)> {
+        let quota = &self.quota;
+        [
+            (
+                "daily",
+                "requests",
+                quota.daily_requests,
+                self.daily_requests,
+            ),
+            (
+                "monthly",
+                "requests",
+                quota.monthly_requests,
+                self.monthly_requests,
+            ),
+            ("daily", "tokens", quota.daily_tokens, self.daily_tokens),
+            (
+                "monthly",
+                "tokens",
+                quota.monthly_tokens,
+                self.monthly_tokens,
+            ),
+        ]
+        .into_iter()
+        .find_map(|(period, what, limit, used)| {
+            limit
+                .filter(|limit| used >= *limit)
+                .map(|limit| (period, what, limit, used))
+        })
+    }
+}
+
+fn load() -> BTreeMap<String, KeyUsage> {
+    paths::key_usage_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(usage: &BTreeMap<String, KeyUsage>) {
+    let saved = paths::key_usage_path().and_then(|path| {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(usage)?)?;
+        Ok(())
+    });
+    if let Err(e) = saved {
+        tracing::warn!("failed to save the usage of the API keys: {:#}", e);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+// Keep the usage of the keys, with their quotas as the node file sets them now
+pub fn init(keys: &[ApiKey]) {
+    let usage = USAGE.get_or_init(|| {
+        thread::spawn(|| loop {
+            thread::sleep(SAVE_EVERY);
+            if CHANGED.swap(false, Ordering::Relaxed) {
+                let usage = USAGE.get().unwrap().lock().unwrap().clone();
+                save(&usage);
+            }
+        });
+        Mutex::new(load())
+    });
+    let mut usage = usage.lock().unwrap();
+    for key in keys {
+        let entry = usage.entry(key.id.clone()).or_default();
+        entry.label = key.label.clone();
+        entry.quota = key.quota.clone();
+    }
+    save(&usage);
+}
+
+// Count a request of the key, or the OpenAI error body to answer with 429 when one of its
+// quotas is used up
+pub fn admit(key: &ApiKey) -> Result<(), Value> {
+    let Some(usage) = USAGE.get() else {
+        return Ok(());
+    };
+    let now = now();
+    let utc = Utc::of(now);
+    let mut usage = usage.lock().unwrap();
+    let entry = usage.entry(key.id.clone()).or_default();
+    entry.label = key.label.clone();
+    entry.roll(&utc);
+    if let Some((period, what, limit, used)) = entry.exceeded() {
+        let resets = match period {
+            "daily" => now / 86400 * 86400 + 86400,
+            _ => {
+                let mut next = now / 86400 * 86400 + 86400;
+                while Utc::of(next).month == utc.month {
+                    next += 86400;
+                }
+                next
+            }
+        };
+        let resets = Utc::of(resets).rfc3339();
+        return Err(json!({
+            "error": {
+                "message": format!(
+                    "The API key '{}' used its {} {} {}, the quota resets at {}",
+                    key.label, limit, period, what, resets
+                ),
+                "type": "insufficient_quota",
+                "code": "quota_exceeded",
+                "quota": {
+                    "key": key.label,
+                    "period": period,
+                    "what": what,
+                    "limit": limit,
+                    "used": used,
+                    "resets_at": resets,
+                },
+            }
+        }));
+    }
+    entry.daily_requests += 1;
+    entry.monthly_requests += 1;
+    entry.total_requests += 1;
+    entry.used = now;
+    CHANGED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+// Count the tokens of a reply against the key with the hash
+pub fn record(id: &str, tokens: u64) {
+    let Some(usage) = USAGE.get() else {
+        return;
+    };
+    let mut usage = usage.lock().unwrap();
+    let entry = usage.entry(id.to_string()).or_default();
+    entry.roll(&Utc::of(now()));
+    entry.daily_tokens += tokens;
+    entry.monthly_tokens += tokens;
+    entry.total_tokens += tokens;
+    CHANGED.store(true, Ordering::Relaxed);
+}
+
+// Tokens a response used, as its usage tells or else estimated from the prompt and the reply
+pub fn used_tokens(body: &[u8], event_stream: bool, prompt: u64) -> u64 {
+    let summary = audit::response_summary(body, event_stream);
+    if let Some(total) = summary["usage"]["total_tokens"].as_u64() {
+        return total;
+    }
+    let reply = match event_stream {
+        true => summary["content"].as_str(),
+        false => summary["choices"][0]["message"]["content"]
+            .as_str()
+            .or(summary["choices"][0]["text"].as_str()),
+    };
+    prompt + reply.map_or(0, attachment::estimate_tokens)
+}
+
+// Replies sent while it is held are counted against the key
+pub struct Metering;
+impl Drop for Metering {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+pub fn meter(key: &ApiKey) -> Metering {
+    CURRENT.with(|current| *current.borrow_mut() = Some(key.id.clone()));
+    Metering
+}
+
+// The hash of the key the replies of this thread are counted against
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// The body of a reply, its tokens counted against the key once the reply is done with
+pub struct Metered {
+    inner: Box<dyn Read + Send>,
+    id: String,
+    event_stream: bool,
+    prompt: u64,
+    body: Vec<u8>,
+}
+impl Metered {
+    pub fn new(inner: Box<dyn Read + Send>, id: String, event_stream: bool, prompt: u64) -> Self {
+        Self {
+            inner,
+            id,
+            event_stream,
+            prompt,
+            body: Vec::new(),
+        }
+    }
+}
+impl Read for Metered {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let room = MAX_METERED.saturating_sub(self.body.len());
+        self.body.extend(&buf[..read.min(room)]);
+        Ok(read)
+    }
+}
+impl Drop for Metered {
+    fn drop(&mut self) {
+        record(
+            &self.id,
+            used_tokens(&self.body, self.event_stream, self.prompt),
+        );
+    }
+}
+
+// Show what each key used today, this month and in all, against its quotas
+pub fn command_usage(json: bool) -> anyhow::Result<()> {
+    let mut usage = load();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+    if usage.is_empty() {
+        println!("No API key was used, add them to gateway.api_keys in the node file");
+        return Ok(());
+    }
+
+    let utc = Utc::of(now());
+    println!(
+        "{}",
+        style(format!(
+            "{:<16} {:>20} {:>20} {:>20} {:>20}",
+            "KEY", "REQUESTS TODAY", "TOKENS TODAY", "REQUESTS MONTH", "TOKENS MONTH"
+        ))
+        .bold()
+    );
+    let cell = |used: u64, limit: Option<u64>| match limit {
+        Some(limit) => format!("{} / {}", used, limit),
+        None => used.to_string(),
+    };
+    for (id, key) in usage.iter_mut() {
+        // counts of an earlier day or month are shown as the new period starts them
+        key.roll(&utc);
+        let line = format!(
+            "{:<16} {:>20} {:>20} {:>20} {:>20}",
+            match key.label.as_str() {
+                "" => id,
+                label => label,
+            },
+            cell(key.daily_requests, key.quota.daily_requests),
+            cell(key.daily_tokens, key.quota.daily_tokens),
+            cell(key.monthly_requests, key.quota.monthly_requests),
+            cell(key.monthly_tokens, key.quota.monthly_tokens),
+        );
+        match key.exceeded() {
+            Some(_) => println!("{}", style(line).red()),
+            None => println!("{}", line),
+        }
+    }
+    let (requests, tokens) = usage.values().fold((0, 0), |(requests, tokens), key| {
+        (requests + key.total_requests, tokens + key.total_tokens)
+    });
+    println!(
+        "{}",
+        style(format!(
+            "{} requests and {} tokens in all",
+            requests, tokens
+        ))
+        .dim()
+    );
+
+    Ok(())
+}
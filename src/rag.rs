@@ -0,0 +1,1042 @@
+use crate::bm25;
+use crate::client::{Client, ClientArgs};
+use crate::crawl::{self, Page};
+use crate::document;
+use crate::events;
+use crate::paths;
+use crate::progress::Progress;
+use crate::qdrant::QdrantArgs;
+use crate::server::{self, ModelKind};
+use crate::store::{self, Condition, Point, ScoredPoint, VectorStore, VectorStoreKind};
+use anyhow::{anyhow, bail};
+use clap::{Args, ValueEnum};
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const DEFAULT_COLLECTION: &str = "default";
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+pub const DEFAULT_CRAWL_DEPTH: usize = 1;
+pub const DEFAULT_MAX_PAGES: usize = 100;
+pub const DEFAULT_TOP_K: usize = 5;
+
+// Number of chunks embedded per request
+const EMBED_BATCH: usize = 16;
+
+// Rank constant of reciprocal rank fusion, damping the weight of the top ranks
+const RRF_K: f32 = 60.0;
+
+// Number of payloads read to infer the payload schema for `rag stats`
+const SCHEMA_SAMPLE: usize = 256;
+
+#[derive(Debug, Clone, Args)]
+pub struct RagArgs {
+    #[arg(
+        long = "collection",
+        help = "Name of the collection",
+        default_value = DEFAULT_COLLECTION
+    )]
+    pub collection: String,
+    #[arg(
+        long = "vector-store",
+        help = "Where to keep the vectors, defaults to the store the collection was created in, or qdrant"
+    )]
+    pub vector_store: Option<VectorStoreKind>,
+    #[command(flatten)]
+    pub qdrant: QdrantArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SearchMode {
+    // similarity of the embeddings
+    Vector,
+    // BM25 over the chunk text
+    Keyword,
+    // both rankings fused, for queries mixing prose with exact identifiers
+    Hybrid,
+}
+
+// How many chunks to retrieve and what to do when none is relevant, kept per collection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetrievalPolicy {
+    pub top_k: usize,
+    // vector matches scoring below this are dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_score: Option<f32>,
+    #[serde(default)]
+    pub no_hit: NoHit,
+}
+impl Default for RetrievalPolicy {
+    fn default() -> Self {
+        Self {
+            top_k: DEFAULT_TOP_K,
+            min_score: None,
+            no_hit: NoHit::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoHit {
+    // send the prompt to the model without context
+    #[default]
+    Answer,
+    // reply that the documents do not cover the question, without asking the model
+    NotFound,
+}
+impl std::fmt::Display for NoHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoHit::Answer => f.pad("answer"),
+            NoHit::NotFound => f.pad("not-found"),
+        }
+    }
+}
+
+// Overrides of the retrieval policy of the collection
+#[derive(Debug, Clone, Default, Args)]
+pub struct PolicyArgs {
+    #[arg(short = 'k', long = "top-k", help = "Number of chunks to retrieve")]
+    pub top_k: Option<usize>,
+    #[arg(
+        long = "min-score",
+        help = "Minimum similarity of the vector matches, from -1 to 1",
+        allow_negative_numbers = true
+    )]
+    pub min_score: Option<f32>,
+    #[arg(
+        long = "no-hit",
+        help = "What to do when no chunk is relevant to a prompt",
+        value_enum
+    )]
+    pub no_hit: Option<NoHit>,
+}
+impl PolicyArgs {
+    pub fn apply(&self, policy: RetrievalPolicy) -> RetrievalPolicy {
+        RetrievalPolicy {
+            top_k: self.top_k.unwrap_or(policy.top_k),
+            min_score: self.min_score.or(policy.min_score),
+            no_hit: self.no_hit.unwrap_or(policy.no_hit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RerankArgs {
+    #[arg(
+        long = "rerank-model",
+        help = "Reranker model to rescore the retrieved chunks with, as named by the rerank server"
+    )]
+    pub rerank_model: Option<String>,
+    #[arg(
+        long = "rerank-top-n",
+        help = "Number of chunks to keep after reranking, defaults to all that were retrieved",
+        requires = "rerank_model"
+    )]
+    pub rerank_top_n: Option<usize>,
+    #[arg(
+        long = "rerank-url",
+        help = "Base url of the server with the /rerank endpoint, defaults to --base-url",
+        requires = "rerank_model"
+    )]
+    pub rerank_url: Option<String>,
+}
+
+// What has been ingested into a collection, kept in `rag/<collection>.json` of the data directory
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    // files and directories given to `rag ingest`
+    roots: Vec<PathBuf>,
+    // sites given to `rag ingest --url`, crawled again on every sync
+    #[serde(default)]
+    sites: Vec<Site>,
+    // manifests written before the embedded store existed are all in Qdrant
+    #[serde(default = "default_vector_store")]
+    vector_store: VectorStoreKind,
+    #[serde(default)]
+    policy: RetrievalPolicy,
+    chunk_size: usize,
+    // content hash and chunk count of every ingested file or page, by path or url
+    files: BTreeMap<String, FileEntry>,
+}
+
+impl Manifest {
+    fn new(vector_store: VectorStoreKind) -> Self {
+        Self {
+            roots: Vec::new(),
+            sites: Vec::new(),
+            vector_store,
+            policy: RetrievalPolicy::default(),
+            chunk_size: 0,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+fn default_vector_store() -> VectorStoreKind {
+    VectorStoreKind::Qdrant
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Site {
+    pub url: String,
+    pub depth: usize,
+    pub max_pages: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FileEntry {
+    hash: String,
+    chunks: usize,
+}
+
+// Something to index, under the name used as `source` in the payload
+struct Source {
+    name: String,
+    content: SourceContent,
+}
+
+enum SourceContent {
+    File(PathBuf),
+    Page(Page),
+}
+
+#[derive(Debug, Default)]
+struct SyncReport {
+    added: usize,
+    updated: usize,
+    removed: usize,
+    unchanged: usize,
+    chunks: usize,
+}
+
+fn manifest_path(collection: &str) -> anyhow::Result<PathBuf> {
+    Ok(paths::rag_dir()?.join(format!("{}.json", collection)))
+}
+
+fn load_manifest(collection: &str) -> anyhow::Result<Option<Manifest>> {
+    let path = manifest_path(collection)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(&path)?;
+    let manifest =
+        serde_json::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    Ok(Some(manifest))
+}
+
+fn save_manifest(collection: &str, manifest: &Manifest) -> anyhow::Result<()> {
+    fs::create_dir_all(paths::rag_dir()?)?;
+    fs::write(
+        manifest_path(collection)?,
+        serde_json::to_string_pretty(manifest)?,
+    )?;
+
+    Ok(())
+}
+
+// Ingest files, directories and sites into the collection, skipping what is already up to date
+pub fn command_ingest(
+    rag: RagArgs,
+    client: ClientArgs,
+    inputs: Vec<PathBuf>,
+    sites: Vec<Site>,
+    chunk_size: usize,
+) -> anyhow::Result<()> {
+    let mut manifest = match load_manifest(&rag.collection)? {
+        Some(manifest) => manifest,
+        None => Manifest::new(rag.vector_store.unwrap_or(VectorStoreKind::Qdrant)),
+    };
+    check_vector_store(&rag, &manifest)?;
+    if manifest.chunk_size != 0 && manifest.chunk_size != chunk_size {
+        bail!(
+            "Collection '{}' was ingested with a chunk size of {}, use the same size or a new collection",
+            rag.collection,
+            manifest.chunk_size
+        );
+    }
+    manifest.chunk_size = chunk_size;
+
+    for input in inputs {
+        let input = input
+            .canonicalize()
+            .map_err(|e| anyhow!("{}: {}", input.display(), e))?;
+        if !manifest.roots.contains(&input) {
+            manifest.roots.push(input);
+        }
+    }
+    for site in sites {
+        // a site ingested again takes the new depth and page limit
+        manifest.sites.retain(|existing| existing.url != site.url);
+        manifest.sites.push(site);
+    }
+
+    let report = sync(&rag, &client, &mut manifest)?;
+    print_report(&rag.collection, &report);
+
+    Ok(())
+}
+
+// Re-embed the files and pages that changed since they were ingested and drop the deleted ones
+pub fn command_reindex(rag: RagArgs, client: ClientArgs) -> anyhow::Result<()> {
+    let mut manifest = load_manifest(&rag.collection)?.ok_or(anyhow!(
+        "Nothing has been ingested into collection '{}', see `gaia rag ingest`",
+        rag.collection
+    ))?;
+    check_vector_store(&rag, &manifest)?;
+
+    let report = sync(&rag, &client, &mut manifest)?;
+    print_report(&rag.collection, &report);
+
+    Ok(())
+}
+
+pub fn command_query(
+    rag: RagArgs,
+    client: ClientArgs,
+    query: String,
+    filter: Vec<Condition>,
+    mode: SearchMode,
+    policy_args: PolicyArgs,
+    rerank_args: RerankArgs,
+) -> anyhow::Result<()> {
+    let store = open_store(&rag)?;
+    let policy = policy_args.apply(policy(&rag.collection)?);
+    let hits = retrieve(
+        store.as_ref(),
+        &rag.collection,
+        &client,
+        &query,
+        &filter,
+        mode,
+        &policy,
+    )?;
+    let hits = rerank(&rerank_args, &client, &query, hits)?;
+    if hits.is_empty() {
+        match filter.is_empty() {
+            true => println!("No results in collection '{}'", rag.collection),
+            false => println!(
+                "No results in collection '{}' match the filters",
+                rag.collection
+            ),
+        }
+        return Ok(());
+    }
+
+    for hit in hits {
+        let source = hit.payload["source"].as_str().unwrap_or("?");
+        let chunk = hit.payload["chunk"].as_u64().unwrap_or(0);
+        let text = hit.payload["text"].as_str().unwrap_or("");
+        let location = match hit.payload["page"].as_u64() {
+            Some(page) => format!("page {}, chunk {}", page, chunk),
+            None => format!("chunk {}", chunk),
+        };
+        println!(
+            "{} {} ({})",
+            style(format!("{:.3}", hit.score)).cyan(),
+            style(source).bold(),
+            location
+        );
+        println!("{}\n", text.trim());
+    }
+
+    Ok(())
+}
+
+// The chunks of the collection most relevant to the query
+pub fn retrieve(
+    store: &dyn VectorStore,
+    collection: &str,
+    client: &ClientArgs,
+    query: &str,
+    filter: &[Condition],
+    mode: SearchMode,
+    policy: &RetrievalPolicy,
+) -> anyhow::Result<Vec<ScoredPoint>> {
+    let top_k = policy.top_k;
+    let vector_search = |limit: usize| -> anyhow::Result<Vec<ScoredPoint>> {
+        let model = embedding_model(client)?;
+        let embedding = Client::new(&client.base_url)?
+            .embeddings(model.as_deref(), &[query.to_string()])?
+            .remove(0);
+        let mut hits = store.search(collection, &embedding, filter, limit)?;
+        // keyword scores are not similarities, the threshold only applies to vectors
+        if let Some(min_score) = policy.min_score {
+            hits.retain(|hit| hit.score >= min_score);
+        }
+        Ok(hits)
+    };
+
+    match mode {
+        SearchMode::Vector => vector_search(top_k),
+        SearchMode::Keyword => {
+            let mut hits = keyword_search(store, collection, query, filter)?;
+            hits.truncate(top_k);
+            Ok(hits)
+        }
+        SearchMode::Hybrid => {
+            // fuse deeper candidate lists, so a chunk ranked low by one method can still surface
+            let candidates = (top_k * 4).max(20);
+            let mut keyword = keyword_search(store, collection, query, filter)?;
+            keyword.truncate(candidates);
+            let vector = vector_search(candidates)?;
+
+            let mut fused: Vec<(String, ScoredPoint)> = Vec::new();
+            for ranking in [vector, keyword] {
+                for (rank, hit) in ranking.into_iter().enumerate() {
+                    let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+                    let key = format!("{}#{}", hit.payload["source"], hit.payload["chunk"]);
+                    match fused.iter_mut().find(|(existing, _)| *existing == key) {
+                        Some((_, existing)) => existing.score += score,
+                        None => fused.push((
+                            key,
+                            ScoredPoint {
+                                score,
+                                payload: hit.payload,
+                            },
+                        )),
+                    }
+                }
+            }
+            let mut hits = fused.into_iter().map(|(_, hit)| hit).collect::<Vec<_>>();
+            hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+            hits.truncate(top_k);
+            Ok(hits)
+        }
+    }
+}
+
+// Rescore the hits with the reranker model, if there is one, keeping the best
+pub fn rerank(
+    args: &RerankArgs,
+    client: &ClientArgs,
+    query: &str,
+    hits: Vec<ScoredPoint>,
+) -> anyhow::Result<Vec<ScoredPoint>> {
+    let model = match &args.rerank_model {
+        Some(model) => model,
+        None => return Ok(hits),
+    };
+    if hits.is_empty() {
+        return Ok(hits);
+    }
+
+    let documents = hits
+        .iter()
+        .map(|hit| hit.payload["text"].as_str().unwrap_or("").to_string())
+        .collect::<Vec<_>>();
+    let base_url = args.rerank_url.as_deref().unwrap_or(&client.base_url);
+    let scores = Client::new(base_url)?
+        .rerank(Some(model), query, &documents)
+        .map_err(|e| anyhow!("Reranking with {} failed: {}", model, e))?;
+
+    let mut hits = hits
+        .into_iter()
+        .zip(scores)
+        .map(|(hit, score)| ScoredPoint {
+            score,
+            payload: hit.payload,
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    if let Some(top_n) = args.rerank_top_n {
+        hits.truncate(top_n);
+    }
+
+    Ok(hits)
+}
+
+// BM25 ranking of every chunk of the collection that meets the conditions
+fn keyword_search(
+    store: &dyn VectorStore,
+    collection: &str,
+    query: &str,
+    filter: &[Condition],
+) -> anyhow::Result<Vec<ScoredPoint>> {
+    let payloads = store.scroll(collection, filter, usize::MAX)?;
+    let texts = payloads
+        .iter()
+        .map(|payload| payload["text"].as_str().unwrap_or(""))
+        .collect::<Vec<_>>();
+
+    Ok(bm25::rank(query, &texts)
+        .into_iter()
+        .map(|(index, score)| ScoredPoint {
+            score,
+            payload: payloads[index].clone(),
+        })
+        .collect())
+}
+
+// Show or change the retrieval policy of the collection
+pub fn command_policy(rag: RagArgs, args: PolicyArgs) -> anyhow::Result<()> {
+    if args.top_k == Some(0) {
+        bail!("--top-k must be at least 1");
+    }
+    let (policy, _) = update_policy(&rag.collection, &args)?;
+    print_policy(&policy);
+
+    Ok(())
+}
+
+// Change the retrieval policy of the collection where the args set it. Returns the policy and
+// whether it changed.
+pub fn update_policy(
+    collection: &str,
+    args: &PolicyArgs,
+) -> anyhow::Result<(RetrievalPolicy, bool)> {
+    let mut manifest = load_manifest(collection)?.ok_or(anyhow!(
+        "Nothing has been ingested into collection '{}', see `gaia rag ingest`",
+        collection
+    ))?;
+
+    let policy = args.apply(manifest.policy.clone());
+    let changed = policy != manifest.policy;
+    if changed {
+        manifest.policy = policy.clone();
+        save_manifest(collection, &manifest)?;
+        events::emit(
+            "policy-changed",
+            json!({
+                "collection": collection,
+                "top_k": policy.top_k,
+                "min_score": policy.min_score,
+                "no_hit": policy.no_hit.to_string(),
+            }),
+        );
+    }
+
+    Ok((policy, changed))
+}
+
+fn print_policy(policy: &RetrievalPolicy) {
+    println!("  {:<12}{}", "top k", policy.top_k);
+    let min_score = match policy.min_score {
+        Some(min_score) => format!("{}", min_score),
+        None => "none".to_string(),
+    };
+    println!("  {:<12}{}", "min score", min_score);
+    println!("  {:<12}{}", "no hit", policy.no_hit);
+}
+
+// Retrieval policy of the collection, the default one if it has no manifest
+pub fn policy(collection: &str) -> anyhow::Result<RetrievalPolicy> {
+    Ok(load_manifest(collection)?
+        .map(|manifest| manifest.policy)
+        .unwrap_or_default())
+}
+
+// Download a snapshot of the collection, to load it into the vector store of another node
+pub fn command_export_snapshot(rag: RagArgs, output: Option<PathBuf>) -> anyhow::Result<()> {
+    let store = open_store(&rag)?;
+    if !store.collection_exists(&rag.collection)? {
+        bail!("Collection '{}' does not exist", rag.collection);
+    }
+
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.snapshot", rag.collection)));
+    println!("Creating a snapshot of collection '{}'", rag.collection);
+    let size = store.export_snapshot(&rag.collection, &output)?;
+    println!(
+        "Saved the snapshot to {} ({:.1} MB)",
+        output.display(),
+        size as f64 / 1_000_000.0
+    );
+
+    Ok(())
+}
+
+// Create the collection from a snapshot saved by `gaia rag export-snapshot` on another node
+pub fn command_import_snapshot(rag: RagArgs, file: &Path) -> anyhow::Result<()> {
+    if !file.is_file() {
+        bail!("Snapshot {} not found", file.display());
+    }
+    let store = open_store(&rag)?;
+    if store.collection_exists(&rag.collection)? {
+        bail!(
+            "Collection '{}' already exists, import the snapshot under another --collection",
+            rag.collection
+        );
+    }
+
+    println!(
+        "Loading {} into collection '{}'",
+        file.display(),
+        rag.collection
+    );
+    store.import_snapshot(&rag.collection, file)?;
+    // nothing was ingested here, the manifest keeps the store and the policy
+    if load_manifest(&rag.collection)?.is_none() {
+        let kind = rag.vector_store.unwrap_or(VectorStoreKind::Qdrant);
+        save_manifest(&rag.collection, &Manifest::new(kind))?;
+    }
+    events::emit(
+        "snapshot-imported",
+        json!({ "collection": rag.collection, "file": file.display().to_string() }),
+    );
+    let info = store.info(&rag.collection)?;
+    println!(
+        "Collection '{}' holds {} vectors of dimension {}",
+        rag.collection, info.points, info.dimension
+    );
+
+    Ok(())
+}
+
+// Print what is stored in the collection, or in every collection with a manifest
+pub fn command_stats(
+    collection: Option<String>,
+    vector_store: Option<VectorStoreKind>,
+    qdrant: QdrantArgs,
+    sample: usize,
+) -> anyhow::Result<()> {
+    let collections = match collection {
+        Some(collection) => vec![collection],
+        None => manifest_names()?,
+    };
+    if collections.is_empty() {
+        println!("No collections yet, create one with `gaia rag ingest`");
+        return Ok(());
+    }
+
+    for (i, collection) in collections.into_iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let rag = RagArgs {
+            collection,
+            vector_store,
+            qdrant: qdrant.clone(),
+        };
+        print_stats(&rag, sample)?;
+    }
+
+    Ok(())
+}
+
+fn print_stats(rag: &RagArgs, sample: usize) -> anyhow::Result<()> {
+    let manifest = load_manifest(&rag.collection)?;
+    let kind = rag
+        .vector_store
+        .or(manifest.as_ref().map(|manifest| manifest.vector_store))
+        .unwrap_or(VectorStoreKind::Qdrant);
+    let store = store::open(kind, &rag.qdrant)?;
+
+    println!(
+        "{} ({})",
+        style(format!("Collection '{}'", rag.collection)).bold(),
+        kind
+    );
+    if !store.collection_exists(&rag.collection)? {
+        println!("  not found in the {} vector store", kind);
+        return Ok(());
+    }
+
+    let info = store.info(&rag.collection)?;
+    println!("  {:<12}{}", "vectors", info.points);
+    println!("  {:<12}{}", "dimension", info.dimension);
+    let disk = match info.disk_bytes {
+        Some(bytes) => format!("{:.1} MB", bytes as f64 / 1_000_000.0),
+        None => "unknown".to_string(),
+    };
+    println!("  {:<12}{}", "disk usage", disk);
+    if let Some(manifest) = &manifest {
+        let pages = manifest
+            .files
+            .keys()
+            .filter(|source| source.starts_with("http://") || source.starts_with("https://"))
+            .count();
+        println!("  {:<12}{}", "chunk size", manifest.chunk_size);
+        println!(
+            "  {:<12}{} files, {} pages",
+            "sources",
+            manifest.files.len() - pages,
+            pages
+        );
+        print_policy(&manifest.policy);
+    }
+
+    // the stores do not keep a schema, so infer one from the stored payloads
+    let payloads = store.scroll(&rag.collection, &[], SCHEMA_SAMPLE.max(sample))?;
+    let mut schema: BTreeMap<String, (BTreeSet<&str>, usize)> = BTreeMap::new();
+    for payload in &payloads {
+        if let Some(fields) = payload.as_object() {
+            for (key, value) in fields {
+                let entry = schema.entry(key.clone()).or_default();
+                entry.0.insert(json_type(value));
+                entry.1 += 1;
+            }
+        }
+    }
+    println!("  payload fields (from {} points):", payloads.len());
+    let width = schema.keys().map(|key| key.len()).max().unwrap_or(0);
+    for (key, (types, count)) in &schema {
+        println!(
+            "    {:<width$}  {:<8} {}/{}",
+            key,
+            types.iter().cloned().collect::<Vec<_>>().join("|"),
+            count,
+            payloads.len(),
+            width = width
+        );
+    }
+
+    if sample > 0 && !payloads.is_empty() {
+        println!("  sample payloads:");
+        for payload in payloads.iter().take(sample) {
+            let mut payload = payload.clone();
+            if let Some(text) = payload.get_mut("text") {
+                if let Some(preview) = text.as_str().map(|text| preview(text, 80)) {
+                    *text = Value::from(preview);
+                }
+            }
+            println!("    {}", payload);
+        }
+    }
+
+    Ok(())
+}
+
+fn json_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// The first characters of the text on one line
+fn preview(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= max_chars {
+        return line;
+    }
+
+    format!("{}...", line.chars().take(max_chars).collect::<String>())
+}
+
+// Collections that have a manifest in the rag directory
+// The collections kept in Qdrant, the embedded store keeps its vectors in the rag directory
+pub fn qdrant_collections() -> anyhow::Result<Vec<String>> {
+    let mut collections = Vec::new();
+    for name in manifest_names()? {
+        if load_manifest(&name)?.is_some_and(|m| m.vector_store == VectorStoreKind::Qdrant) {
+            collections.push(name);
+        }
+    }
+
+    Ok(collections)
+}
+
+fn manifest_names() -> anyhow::Result<Vec<String>> {
+    let entries = match fs::read_dir(paths::rag_dir()?) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut names = entries
+        .filter_map(|res| {
+            res.ok().and_then(|e| {
+                e.path()
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(".json"))
+                    .map(String::from)
+            })
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+
+    Ok(names)
+}
+
+// Bring the collection in line with the files under the roots and the pages of the sites
+fn sync(rag: &RagArgs, client: &ClientArgs, manifest: &mut Manifest) -> anyhow::Result<SyncReport> {
+    let store = store::open(manifest.vector_store, &rag.qdrant)?;
+    let embedder = Client::new(&client.base_url)?;
+    let model = embedding_model(client)?;
+
+    let mut files = Vec::new();
+    for root in &manifest.roots {
+        collect_files(root, &mut files)?;
+    }
+    files.sort();
+    files.dedup();
+    let mut sources = files
+        .into_iter()
+        .map(|file| Source {
+            name: file.display().to_string(),
+            content: SourceContent::File(file),
+        })
+        .collect::<Vec<_>>();
+
+    // pages that failed to download keep their vectors until the next sync
+    let mut unreachable = Vec::new();
+    for site in &manifest.sites {
+        println!("Crawling {}", site.url);
+        let crawled = crawl::crawl(&site.url, site.depth, site.max_pages)?;
+        unreachable.extend(crawled.failed);
+        for page in crawled.pages {
+            if !sources.iter().any(|source| source.name == page.url) {
+                sources.push(Source {
+                    name: page.url.clone(),
+                    content: SourceContent::Page(page),
+                });
+            }
+        }
+    }
+
+    let mut report = SyncReport::default();
+    let mut progress = Progress::new(
+        "ingest",
+        &rag.collection,
+        "sources",
+        Some(sources.len() as u64),
+    );
+
+    // drop the vectors of files and pages that are gone
+    let removed = manifest
+        .files
+        .keys()
+        .filter(|name| {
+            !sources.iter().any(|source| &source.name == *name) && !unreachable.contains(name)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for source in removed {
+        if store.collection_exists(&rag.collection)? {
+            store.delete_matching(&rag.collection, "source", &source)?;
+        }
+        manifest.files.remove(&source);
+        println!("{} {}", style("removed").red(), source);
+        report.removed += 1;
+        save_manifest(&rag.collection, manifest)?;
+    }
+
+    for Source {
+        name: source,
+        content,
+    } in sources
+    {
+        progress.advance(1);
+        let (hash, sections) = match &content {
+            SourceContent::File(file) => {
+                let bytes = fs::read(file).map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+                let hash = format!("{:x}", Sha256::digest(&bytes));
+                if is_unchanged(manifest, &source, &hash) {
+                    report.unchanged += 1;
+                    continue;
+                }
+                (hash, document::load(file, &bytes)?)
+            }
+            SourceContent::Page(page) => {
+                let hash = format!("{:x}", Sha256::digest(&page.bytes));
+                if is_unchanged(manifest, &source, &hash) {
+                    report.unchanged += 1;
+                    continue;
+                }
+                (hash, document::load_page(page)?)
+            }
+        };
+        let previous = manifest.files.get(&source).cloned();
+
+        // chunks never span sections, so each keeps the metadata of its page
+        let mut chunks = Vec::new();
+        for section in sections {
+            for chunk in chunk_text(&section.text, manifest.chunk_size) {
+                chunks.push((chunk, section.metadata.clone()));
+            }
+        }
+
+        let mut points = Vec::new();
+        for (batch_index, batch) in chunks.chunks(EMBED_BATCH).enumerate() {
+            let texts = batch
+                .iter()
+                .map(|(chunk, _)| chunk.clone())
+                .collect::<Vec<_>>();
+            let embeddings = embedder.embeddings(model.as_deref(), &texts)?;
+            for (i, ((chunk, metadata), vector)) in batch.iter().zip(embeddings).enumerate() {
+                let index = batch_index * EMBED_BATCH + i;
+                let mut payload = json!({ "source": source, "chunk": index, "text": chunk });
+                payload
+                    .as_object_mut()
+                    .expect("payload is an object")
+                    .extend(metadata.clone());
+                points.push(Point {
+                    id: point_id(&source, index),
+                    vector,
+                    payload,
+                });
+            }
+        }
+
+        if let Some(point) = points.first() {
+            if !store.collection_exists(&rag.collection)? {
+                store.create_collection(&rag.collection, point.vector.len())?;
+            }
+        }
+        if previous.is_some() && store.collection_exists(&rag.collection)? {
+            store.delete_matching(&rag.collection, "source", &source)?;
+        }
+        report.chunks += points.len();
+        store.upsert(&rag.collection, points)?;
+
+        manifest.files.insert(
+            source.clone(),
+            FileEntry {
+                hash,
+                chunks: chunks.len(),
+            },
+        );
+        // save as we go, so an interrupted run resumes where it stopped
+        save_manifest(&rag.collection, manifest)?;
+
+        if previous.is_some() {
+            println!("{} {}", style("updated").yellow(), source);
+            report.updated += 1;
+        } else {
+            println!("{} {}", style("added").green(), source);
+            report.added += 1;
+        }
+    }
+    save_manifest(&rag.collection, manifest)?;
+
+    progress.finish();
+
+    Ok(report)
+}
+
+// The store of the collection, as recorded when it was first ingested
+pub fn open_store(rag: &RagArgs) -> anyhow::Result<Box<dyn VectorStore>> {
+    let kind = match (rag.vector_store, load_manifest(&rag.collection)?) {
+        (Some(kind), _) => kind,
+        (None, Some(manifest)) => manifest.vector_store,
+        (None, None) => VectorStoreKind::Qdrant,
+    };
+
+    store::open(kind, &rag.qdrant)
+}
+
+fn check_vector_store(rag: &RagArgs, manifest: &Manifest) -> anyhow::Result<()> {
+    match rag.vector_store {
+        Some(vector_store) if vector_store != manifest.vector_store => bail!(
+            "Collection '{}' is kept in the {} vector store, use the same store or a new collection",
+            rag.collection,
+            manifest.vector_store
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn is_unchanged(manifest: &Manifest, source: &str, hash: &str) -> bool {
+    manifest
+        .files
+        .get(source)
+        .is_some_and(|entry| entry.hash == hash)
+}
+
+fn print_report(collection: &str, report: &SyncReport) {
+    println!(
+        "Collection '{}': {} added, {} updated, {} removed, {} unchanged ({} chunks embedded)",
+        collection, report.added, report.updated, report.removed, report.unchanged, report.chunks
+    );
+}
+
+// Embedding model to request, defaulting to the one served by `gaia start --embedding-model`
+pub fn embedding_model(client: &ClientArgs) -> anyhow::Result<Option<String>> {
+    if client.model_name.is_some() {
+        return Ok(client.model_name.clone());
+    }
+
+    Ok(server::load(server::API_SERVER)?.and_then(|state| {
+        state
+            .models
+            .into_iter()
+            .find(|model| model.kind == ModelKind::Embedding)
+            .map(|model| model.name)
+    }))
+}
+
+// Text and PDF files under the path, skipping hidden files and directories
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if !path.exists() {
+        // a root that was removed, its files get dropped from the collection
+        return Ok(());
+    }
+    if path.is_file() {
+        if is_supported_file(path) {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden {
+            collect_files(&entry.path(), files)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_supported_file(path: &Path) -> bool {
+    match fs::read(path) {
+        Ok(bytes) => document::is_supported(path, &bytes),
+        Err(_) => false,
+    }
+}
+
+// Split the text into chunks of at most `chunk_size` characters, preferring paragraph breaks
+fn chunk_text(text: &str, chunk_size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if !current.is_empty()
+            && current.chars().count() + paragraph.chars().count() + 2 > chunk_size
+        {
+            chunks.push(std::mem::take(&mut current));
+        }
+
+        if paragraph.chars().count() > chunk_size {
+            // a paragraph that does not fit on its own is cut into pieces
+            let chars = paragraph.chars().collect::<Vec<_>>();
+            for piece in chars.chunks(chunk_size) {
+                chunks.push(piece.iter().collect());
+            }
+            continue;
+        }
+
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+// Stable id of a chunk, so re-ingesting a file overwrites its points
+fn point_id(source: &str, chunk: usize) -> u64 {
+    let digest = Sha256::digest(format!("{}#{}", source, chunk).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+
+    u64::from_be_bytes(bytes)
+}
@@ -0,0 +1,195 @@
+use crate::keys::{EditMode, Key, Keys};
+use crate::paths;
+use crate::term;
+use ratatui::crossterm::{
+    event::{self, Event, KeyEventKind},
+    terminal,
+};
+use rustyline::{
+    config::Configurer, error::ReadlineError, history::DefaultHistory, Cmd, Config, Editor,
+    KeyCode, KeyEvent, Modifiers,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// Lines kept in the history file
+const MAX_HISTORY: usize = 1000;
+
+// Reads the lines of `gaia chat`: Up and Down walk the history of past sessions, Ctrl+R searches
+// it, Alt+Enter starts a new line and a paste is taken whole, newlines included. The keys are
+// those of keys.toml.
+pub struct LineEditor {
+    // None when the input is not a terminal, lines are then read as they come
+    editor: Option<Editor<(), DefaultHistory>>,
+    history: Option<PathBuf>,
+    cancel: Vec<Key>,
+}
+impl LineEditor {
+    pub fn new(keys: &Keys) -> anyhow::Result<Self> {
+        if !term::interactive() {
+            return Ok(Self {
+                editor: None,
+                history: None,
+                cancel: Vec::new(),
+            });
+        }
+
+        let config = Config::builder()
+            .edit_mode(edit_mode(keys.edit_mode))
+            .auto_add_history(true)
+            .max_history_size(MAX_HISTORY)?
+            .history_ignore_dups(true)?
+            // a line starting with a space stays out of the history, e.g. one with a secret
+            .history_ignore_space(true)
+            .bracketed_paste(true)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        // Enter starts a new line once another key submits
+        let enter = KeyEvent(KeyCode::Enter, Modifiers::NONE);
+        if !keys.chat.submit.iter().any(|key| key.line_event() == enter) {
+            editor.bind_sequence(enter, Cmd::Newline);
+        }
+        for key in &keys.chat.newline {
+            editor.bind_sequence(key.line_event(), Cmd::Newline);
+        }
+        for key in &keys.chat.submit {
+            editor.bind_sequence(key.line_event(), Cmd::AcceptLine);
+        }
+        let history = paths::history_path().ok();
+        if let Some(history) = &history {
+            // there is none before the first session
+            let _ = editor.load_history(history);
+        }
+
+        Ok(Self {
+            editor: Some(editor),
+            history,
+            cancel: keys.chat.cancel.clone(),
+        })
+    }
+
+    // The next message, None at the end of the input. Ctrl+C drops the line being typed.
+    pub fn read(&mut self, prompt: &str) -> anyhow::Result<Option<String>> {
+        let Some(editor) = &mut self.editor else {
+            return term::input(prompt, false);
+        };
+
+        match editor.readline(&format!("{}: ", prompt)) {
+            Ok(line) => {
+                if let Some(history) = &self.history {
+                    if let Some(dir) = history.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    // saved as it is typed, so that a session that crashes keeps its lines
+                    if let Err(e) = editor.append_history(history) {
+                        tracing::warn!("cannot save the history to {}: {}", history.display(), e);
+                    }
+                }
+                Ok(Some(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Switch between Emacs and Vi editing for the rest of the session
+    pub fn set_edit_mode(&mut self, mode: EditMode) {
+        if let Some(editor) = &mut self.editor {
+            editor.set_edit_mode(edit_mode(mode));
+        }
+    }
+
+    // Watch for the cancel keys until the reply has streamed in
+    pub fn watch(&self) -> Watch {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        // keys are only seen one by one in raw mode
+        if self.editor.is_none() || self.cancel.is_empty() || terminal::enable_raw_mode().is_err() {
+            return Watch {
+                cancelled,
+                done,
+                thread: None,
+            };
+        }
+
+        let keys = self.cancel.clone();
+        let thread = thread::spawn({
+            let cancelled = cancelled.clone();
+            let done = done.clone();
+            move || {
+                while !done.load(Ordering::Relaxed) {
+                    if !event::poll(Duration::from_millis(50)).unwrap_or(false) {
+                        continue;
+                    }
+                    if let Ok(Event::Key(key)) = event::read() {
+                        if key.kind == KeyEventKind::Press
+                            && keys.iter().any(|cancel| cancel.matches(&key))
+                        {
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        });
+
+        Watch {
+            cancelled,
+            done,
+            thread: Some(thread),
+        }
+    }
+}
+
+fn edit_mode(mode: EditMode) -> rustyline::EditMode {
+    match mode {
+        EditMode::Emacs => rustyline::EditMode::Emacs,
+        EditMode::Vi => rustyline::EditMode::Vi,
+    }
+}
+
+// The keyboard watched while a reply streams in
+pub struct Watch {
+    cancelled: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+    // None when the keyboard is not watched
+    thread: Option<JoinHandle<()>>,
+}
+impl Watch {
+    // Print a token of the reply, or stop the reply once a cancel key is pressed
+    pub fn print(&self, token: &str) -> ControlFlow<()> {
+        if self.cancelled() {
+            return ControlFlow::Break(());
+        }
+        match self.thread {
+            // raw mode leaves the carriage returns to the program
+            Some(_) => print!("{}", token.replace('\n', "\r\n")),
+            None => print!("{}", token),
+        }
+        let _ = io::stdout().flush();
+        ControlFlow::Continue(())
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+            let _ = terminal::disable_raw_mode();
+        }
+    }
+}
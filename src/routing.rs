@@ -0,0 +1,79 @@
+use crate::fallback::RemoteConfig;
+use anyhow::bail;
+use serde::Deserialize;
+
+// A rule of `gateway.routing`, sending the requests for the models matching a glob to a model of
+// this node or to a remote OpenAI API, so that clients keep the names they know, e.g.
+// `{ model = "gpt-4o*", to = "llama-3.1-8b" }`. The first rule matching applies.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingRule {
+    // names of the models asked for, `*` standing for any characters and `?` for one
+    pub model: String,
+    // model of the node answering them, served, on demand or of a peer
+    pub to: Option<String>,
+    // the remote API answering them instead
+    pub remote: Option<RemoteConfig>,
+}
+impl RoutingRule {
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.model.is_empty() {
+            bail!("gateway.routing has a rule without a model");
+        }
+        if self.to.is_some() == self.remote.is_some() {
+            bail!(
+                "gateway.routing: the rule for '{}' needs either `to` or `remote`",
+                self.model
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// Where the requests for a model go
+pub enum Target<'a> {
+    Model(&'a str),
+    Remote(&'a RemoteConfig),
+}
+
+// Where the first rule matching the model sends it, none without a rule for it
+pub fn find<'a>(rules: &'a [RoutingRule], model: &str) -> Option<Target<'a>> {
+    let rule = rules.iter().find(|rule| matches(&rule.model, model))?;
+    match (&rule.to, &rule.remote) {
+        (Some(to), _) => Some(Target::Model(to)),
+        (None, Some(remote)) => Some(Target::Remote(remote)),
+        (None, None) => None,
+    }
+}
+
+// Whether the name matches the glob
+fn matches(glob: &str, name: &str) -> bool {
+    let (glob, name) = (glob.as_bytes(), name.as_bytes());
+    // where the last `*` was and the name position it matched up to, to go back to
+    let mut star = None;
+    let (mut g, mut n) = (0, 0);
+    while n < name.len() {
+        match glob.get(g) {
+            Some(b'*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                // the `*` takes one more character
+                Some((at, matched)) => {
+                    g = at + 1;
+                    n = matched + 1;
+                    star = Some((at, n));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == b'*')
+}
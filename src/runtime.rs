@@ -0,0 +1,339 @@
+use crate::device::{self, Device};
+use crate::error::{fail, ErrorKind, Tag};
+use crate::events;
+use crate::paths;
+use crate::progress::{Progress, ProgressReader};
+use crate::server;
+use anyhow::{anyhow, bail};
+use console::style;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fs::{self, File},
+    io::copy,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+pub const RELEASES_URL: &str = "https://github.com/WasmEdge/WasmEdge/releases/download";
+const RELEASES_API: &str = "https://api.github.com/repos/WasmEdge/WasmEdge/releases";
+// Names the version picked with `gaia runtime use` in the runtimes directory
+const CURRENT_FILE: &str = "current";
+// The wasmedge found on PATH or in ~/.wasmedge
+const SYSTEM: &str = "system";
+
+// A WasmEdge version with its ggml plugin, unpacked in its own directory
+pub struct Runtime {
+    pub version: String,
+    dir: PathBuf,
+}
+impl Runtime {
+    fn new(version: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            version: version.to_string(),
+            dir: paths::runtimes_dir()?.join(version),
+        })
+    }
+
+    pub fn wasmedge(&self) -> PathBuf {
+        let exe = if cfg!(windows) {
+            "wasmedge.exe"
+        } else {
+            "wasmedge"
+        };
+        self.dir.join("bin").join(exe)
+    }
+
+    fn plugin_dir(&self) -> PathBuf {
+        self.dir.join("plugin")
+    }
+
+    fn is_installed(&self) -> bool {
+        self.wasmedge().is_file()
+    }
+
+    // The variables pointing the services launched with the runtime at its libraries and its
+    // plugin, set on their command rather than in the environment of gaia
+    pub fn env(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let var = match cfg!(target_os = "macos") {
+            true => "DYLD_LIBRARY_PATH",
+            false => "LD_LIBRARY_PATH",
+        };
+        // the ubuntu archives have lib, the manylinux ones lib64
+        let mut dirs = ["lib", "lib64"]
+            .iter()
+            .map(|lib| self.dir.join(lib))
+            .filter(|dir| dir.is_dir())
+            .collect::<Vec<_>>();
+        dirs.extend(env::split_paths(&env::var_os(var).unwrap_or_default()));
+
+        Ok(vec![
+            (
+                var.to_string(),
+                env::join_paths(dirs)?.to_string_lossy().to_string(),
+            ),
+            (
+                "WASMEDGE_PLUGIN_PATH".to_string(),
+                self.plugin_dir().display().to_string(),
+            ),
+        ])
+    }
+}
+
+// The runtime to launch the services with: the given version, else the one picked with
+// `gaia runtime use`, none for the system wasmedge
+pub fn selected(version: Option<&str>) -> anyhow::Result<Option<Runtime>> {
+    let version = match version {
+        Some(version) => version.to_string(),
+        None => match current()? {
+            Some(version) => version,
+            None => return Ok(None),
+        },
+    };
+    if version == SYSTEM {
+        return Ok(None);
+    }
+    let runtime = Runtime::new(&version)?;
+    if !runtime.is_installed() {
+        return Err(fail(
+            ErrorKind::Config,
+            anyhow!(
+                "WasmEdge {} is not installed, install it with `gaia runtime install {}`",
+                version,
+                version
+            ),
+        ));
+    }
+
+    Ok(Some(runtime))
+}
+
+fn current() -> anyhow::Result<Option<String>> {
+    let path = paths::runtimes_dir()?.join(CURRENT_FILE);
+    Ok(fs::read_to_string(path)
+        .ok()
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty()))
+}
+
+fn installed() -> anyhow::Result<Vec<Runtime>> {
+    let Ok(entries) = fs::read_dir(paths::runtimes_dir()?) else {
+        return Ok(Vec::new());
+    };
+    let mut versions = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    versions.sort();
+
+    let mut runtimes = Vec::new();
+    for version in versions {
+        let runtime = Runtime::new(&version)?;
+        if runtime.is_installed() {
+            runtimes.push(runtime);
+        }
+    }
+    Ok(runtimes)
+}
+
+fn github(url: &str) -> anyhow::Result<Value> {
+    reqwest::blocking::Client::new()
+        .get(url)
+        .header("User-Agent", "gaia")
+        .send()?
+        .error_for_status()
+        .map_err(|e| anyhow!("Cannot list the WasmEdge releases: {}", e))
+        .tag(ErrorKind::Api)?
+        .json()
+        .map_err(anyhow::Error::from)
+}
+
+// The installed versions, the one in use marked, and the released ones with --remote
+pub fn command_list(remote: bool) -> anyhow::Result<()> {
+    let current = current()?.unwrap_or(SYSTEM.to_string());
+    let mark = |version: &str| match version == current {
+        true => style("*").green().to_string(),
+        false => " ".to_string(),
+    };
+
+    let system = server::wasmedge()
+        .ok()
+        .and_then(|wasmedge| device::wasmedge_version(&wasmedge).ok());
+    match &system {
+        Some(version) => println!("{} {:<12} {}", mark(SYSTEM), SYSTEM, version),
+        None => println!(
+            "{} {:<12} {}",
+            mark(SYSTEM),
+            SYSTEM,
+            style("not installed").dim()
+        ),
+    }
+    for runtime in installed()? {
+        println!(
+            "{} {:<12} {}",
+            mark(&runtime.version),
+            runtime.version,
+            style(runtime.dir.display()).dim()
+        );
+    }
+
+    if remote {
+        println!("Released");
+        let releases = github(RELEASES_API)?;
+        let mut newest = None;
+        for release in releases.as_array().into_iter().flatten() {
+            let Some(tag) = release["tag_name"].as_str() else {
+                continue;
+            };
+            let pre = match release["prerelease"].as_bool() {
+                Some(true) => " (pre-release)",
+                _ => {
+                    newest = newest.max(parse_version(tag).map(|version| (version, tag)));
+                    ""
+                }
+            };
+            println!("  {}{}", tag, pre);
+        }
+
+        let in_use = match current.as_str() {
+            SYSTEM => system,
+            version => Some(version.to_string()),
+        };
+        if let (Some((latest, tag)), Some(in_use)) = (newest, in_use) {
+            if parse_version(&in_use).is_some_and(|version| version < latest) {
+                println!(
+                    "WasmEdge {} is available, install it with `gaia runtime install {}`",
+                    tag, tag
+                );
+                events::emit(
+                    "upgrade-available",
+                    json!({ "component": "wasmedge", "current": in_use, "latest": tag }),
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// `0.14.1` as numbers to compare, pre-releases such as `0.15.0-alpha.1` are not versions to
+// upgrade to
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    let version = (parts.next()??, parts.next()??, parts.next()??);
+
+    parts.next().is_none().then_some(version)
+}
+
+// Install WasmEdge and the ggml plugin for the device side by side with the other versions
+pub fn command_install(version: String, device: Option<Device>) -> anyhow::Result<()> {
+    let version = match version.as_str() {
+        "latest" => github(&format!("{}/latest", RELEASES_API))?["tag_name"]
+            .as_str()
+            .ok_or(anyhow!("The latest WasmEdge release has no tag"))?
+            .to_string(),
+        _ => version,
+    };
+    let runtime = Runtime::new(&version)?;
+    if runtime.is_installed() {
+        println!(
+            "WasmEdge {} is already installed in {}",
+            version,
+            runtime.dir.display()
+        );
+        return Ok(());
+    }
+    let device = device.unwrap_or_else(device::detect);
+    let (build, platform) = device::build(device)?;
+
+    // the archive holds one WasmEdge-<version>-<os> directory
+    let core = format!(
+        "{0}/{1}/WasmEdge-{1}-{2}.tar.gz",
+        RELEASES_URL, version, platform
+    );
+    unpack(&core, &runtime.dir, "wasmedge", 1)
+        .map_err(|e| anyhow!("Cannot install WasmEdge {} from {}: {}", version, core, e))
+        .tag(ErrorKind::Download)?;
+    let plugin = device::plugin_url(&version, build, platform);
+    if let Err(e) = unpack(&plugin, &runtime.plugin_dir(), "wasi-nn plugin", 0) {
+        let _ = fs::remove_dir_all(&runtime.dir);
+        return Err(anyhow!(
+            "Cannot install the wasi-nn plugin of WasmEdge {} from {}: {}",
+            version,
+            plugin,
+            e
+        ))
+        .tag(ErrorKind::Download);
+    }
+    events::emit(
+        "runtime-installed",
+        json!({ "version": version, "device": device.to_string() }),
+    );
+    println!(
+        "Installed WasmEdge {} for {} in {}",
+        version,
+        device,
+        runtime.dir.display()
+    );
+    if current()?.is_none() {
+        println!("Use it with `gaia runtime use {}`", version);
+    }
+
+    Ok(())
+}
+
+// Launch the services with the version from now on, `system` goes back to the wasmedge on PATH
+pub fn command_use(version: String) -> anyhow::Result<()> {
+    if version != SYSTEM {
+        selected(Some(&version))?;
+    }
+    let dir = paths::runtimes_dir()?;
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(CURRENT_FILE), format!("{}\n", version))?;
+    events::emit("runtime-selected", json!({ "version": version }));
+    match version.as_str() {
+        SYSTEM => println!("Using the system wasmedge"),
+        _ => println!("Using WasmEdge {}", version),
+    }
+
+    Ok(())
+}
+
+// Download a .tar.gz and unpack it into the directory, dropping the leading path components
+pub fn unpack(url: &str, dir: &Path, what: &str, strip: u32) -> anyhow::Result<()> {
+    let _span = tracing::info_span!("download", url).entered();
+    fs::create_dir_all(dir)?;
+    let archive = dir.join(".download.tar.gz");
+    let unpacked = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .and_then(|http| http.get(url).send())
+        .and_then(|response| response.error_for_status())
+        .map_err(anyhow::Error::from)
+        .and_then(|response| {
+            let total = response.content_length();
+            let progress = Progress::new("download", what, "bytes", total);
+            let mut reader = ProgressReader::new(response, progress);
+            copy(&mut reader, &mut File::create(&archive)?)?;
+            reader.finish();
+            Ok(())
+        })
+        .and_then(|_| {
+            let status = Command::new("tar")
+                .arg("-xzf")
+                .arg(&archive)
+                .arg(format!("--strip-components={}", strip))
+                .arg("-C")
+                .arg(dir)
+                .status()?;
+            match status.success() {
+                true => Ok(()),
+                false => bail!("tar exited with {}", status),
+            }
+        });
+    let _ = fs::remove_file(&archive);
+    if unpacked.is_err() {
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    unpacked
+}
@@ -0,0 +1,561 @@
+use crate::cpu;
+use crate::device::Device;
+use crate::error::{fail, ErrorKind, Tag};
+use crate::events;
+use crate::notify;
+use crate::paths;
+use crate::progress::{self, Progress, ProgressReader};
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{copy, Read, Seek, SeekFrom},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Seconds to wait for the api-server to load its models
+pub const DEFAULT_STARTUP_TIMEOUT: u64 = 300;
+// Markers of the steps of loading a model in the log of llama.cpp, in order
+const PHASES: &[(&str, &str)] = &[
+    ("llama_model_loader", "reading the model"),
+    ("load_tensors", "mapping weights"),
+    ("offload", "offloading layers to the GPU"),
+    ("llama_new_context_with_model", "allocating the KV cache"),
+    ("kv_cache", "allocating the KV cache"),
+    ("warm", "warming up"),
+    ("listening", "opening the port"),
+];
+
+// Variables the runtime and plugin picked at launch are passed in
+const LAUNCH_ENV: &[&str] = &[
+    "WASMEDGE_PLUGIN_PATH",
+    "LD_LIBRARY_PATH",
+    "DYLD_LIBRARY_PATH",
+];
+
+pub const API_SERVER: &str = "api-server";
+pub const WHISPER_SERVER: &str = "whisper-server";
+
+pub const LLAMA_API_SERVER_URL: &str =
+    "https://github.com/LlamaEdge/LlamaEdge/releases/latest/download/llama-api-server.wasm";
+pub const WHISPER_API_SERVER_URL: &str =
+    "https://github.com/LlamaEdge/whisper-api-server/releases/latest/download/whisper-api-server.wasm";
+
+// State of a service launched by `gaia start`, kept in `<name>.json` of the run directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceState {
+    pub name: String,
+    pub pid: u32,
+    pub port: u16,
+    pub models: Vec<ServedModel>,
+    pub log: PathBuf,
+    // unix time it was launched at, 0 for services launched by older versions
+    #[serde(default)]
+    pub started: u64,
+    // what the models compute on, unknown for services launched by older versions
+    #[serde(default)]
+    pub device: Option<Device>,
+    // program and arguments it was launched with, to relaunch it, empty for services launched
+    // by older versions
+    #[serde(default)]
+    pub command: Vec<String>,
+    // the variables pointing wasmedge at its libraries and plugin when it was launched
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServedModel {
+    // name the model is registered under in the api-server
+    pub name: String,
+    pub path: String,
+    pub kind: ModelKind,
+    // Jinja chat template the gateway renders its prompts with, `gguf` for the one in the model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_template: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelKind {
+    Chat,
+    Embedding,
+    Audio,
+}
+impl std::fmt::Display for ModelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModelKind::Chat => f.pad("chat"),
+            ModelKind::Embedding => f.pad("embedding"),
+            ModelKind::Audio => f.pad("audio"),
+        }
+    }
+}
+
+// Name to register a model under, the file name without the extension
+pub fn model_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(path)
+        .to_string()
+}
+
+// Where the output of the named service goes
+pub fn log_path(name: &str) -> anyhow::Result<PathBuf> {
+    Ok(paths::log_dir()?.join(format!("{}.log", name)))
+}
+
+fn state_path(name: &str) -> anyhow::Result<PathBuf> {
+    Ok(paths::run_dir()?.join(format!("{}.json", name)))
+}
+
+// Path to the wasmedge runtime, looked up on PATH and in the default install location
+pub fn wasmedge() -> anyhow::Result<PathBuf> {
+    let exe = if cfg!(windows) {
+        "wasmedge.exe"
+    } else {
+        "wasmedge"
+    };
+    if let Some(path) = env::var_os("PATH") {
+        for dir in env::split_paths(&path) {
+            let candidate = dir.join(exe);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+    if let Some(home) = env::var_os("HOME") {
+        let candidate = PathBuf::from(home).join(".wasmedge").join("bin").join(exe);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+    }
+
+    bail!("wasmedge not found, install it with: curl -sSf https://raw.githubusercontent.com/WasmEdge/WasmEdge/master/utils/install_v2.sh | bash")
+}
+
+// Path to a wasm app in the apps directory, downloading it first if needed
+pub fn wasm_app(file_name: &str, url: &str) -> anyhow::Result<PathBuf> {
+    let dir = paths::apps_dir()?;
+    let path = app_path(file_name)?;
+    if path.exists() {
+        return Ok(path);
+    }
+
+    println!("Downloading {}", url);
+    let _span = tracing::info_span!("download", url).entered();
+    fs::create_dir_all(&dir)?;
+    let partial = path.with_extension("part");
+    let bytes = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .and_then(|http| http.get(url).send())
+        .and_then(|response| response.error_for_status())
+        .map_err(anyhow::Error::from)
+        .and_then(|response| {
+            let total = response.content_length();
+            let progress = Progress::new("download", file_name, "bytes", total);
+            let mut reader = ProgressReader::new(response, progress);
+            let bytes = copy(&mut reader, &mut File::create(&partial)?)?;
+            reader.finish();
+            Ok(bytes)
+        })
+        .tag(ErrorKind::Download)?;
+    fs::rename(&partial, &path)?;
+    tracing::info!(bytes, path = %path.display(), "downloaded");
+
+    Ok(path)
+}
+
+pub fn app_path(file_name: &str) -> anyhow::Result<PathBuf> {
+    Ok(paths::apps_dir()?.join(file_name))
+}
+
+// Whether the api-server at the base url answers
+pub fn probe(base_url: &str) -> bool {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .and_then(|client| client.get(format!("{}/models", base_url)).send())
+        .map(|response| response.status().is_success())
+        .unwrap_or(false)
+}
+
+pub fn check_port(port: u16) -> anyhow::Result<()> {
+    TcpListener::bind(("0.0.0.0", port))
+        .map(|_| ())
+        .map_err(|e| {
+            fail(
+                ErrorKind::PortInUse,
+                anyhow!("Port {} is not available: {}", port, e),
+            )
+        })
+}
+
+// A port nothing listens on, picked by the system
+pub fn free_port() -> anyhow::Result<u16> {
+    Ok(TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port())
+}
+
+// WASI preopen covering the directory of the given file
+pub fn preopen(path: &Path) -> String {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    format!("{}:{}", dir.display(), dir.display())
+}
+
+// Launch a service in the background and record its state, `command` being the program and
+// its arguments
+pub fn spawn(
+    name: &str,
+    command: Vec<String>,
+    port: u16,
+    models: Vec<ServedModel>,
+    device: Option<Device>,
+    launch_env: &[(String, String)],
+) -> anyhow::Result<ServiceState> {
+    let _span = tracing::info_span!("spawn", service = name).entered();
+    if let Some(state) = load(name)? {
+        bail!(
+            "{} is already running (pid {}), stop it with `gaia stop`",
+            name,
+            state.pid
+        );
+    }
+    // those of the runtime and plugin picked for it over gaia's own
+    let mut env: BTreeMap<String, String> = LAUNCH_ENV
+        .iter()
+        .filter_map(|var| Some((var.to_string(), env::var(var).ok()?)))
+        .collect();
+    env.extend(launch_env.iter().cloned());
+
+    launch(ServiceState {
+        name: name.to_string(),
+        pid: 0,
+        port,
+        models,
+        log: log_path(name)?,
+        started: 0,
+        device,
+        command,
+        env,
+    })
+}
+
+// Stop the service if it still runs and launch it again the way it was launched
+pub fn restart(state: &ServiceState) -> anyhow::Result<ServiceState> {
+    let _span = tracing::info_span!("restart", service = %state.name).entered();
+    if state.command.is_empty() {
+        bail!(
+            "{} was launched by an older version of gaia and cannot be relaunched",
+            state.name
+        );
+    }
+    match is_running(state.pid) {
+        true => stop(state)?,
+        false => {
+            let _ = fs::remove_file(state_path(&state.name)?);
+        }
+    }
+
+    launch(state.clone())
+}
+
+// Run the command of the state in the background, filling in its pid and launch time
+fn launch(mut state: ServiceState) -> anyhow::Result<ServiceState> {
+    let name = state.name.as_str();
+    let (program, args) = state
+        .command
+        .split_first()
+        .ok_or(anyhow!("No command to launch {}", name))?;
+    let program = Path::new(program);
+    let port = state.port;
+
+    let log_dir = paths::log_dir()?;
+    fs::create_dir_all(&log_dir)?;
+    fs::create_dir_all(paths::run_dir()?)?;
+    let log = state.log.clone();
+    let stdout = File::create(&log)?;
+    let stderr = stdout.try_clone()?;
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .envs(&state.env)
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(stderr);
+    #[cfg(unix)]
+    {
+        // keep the service alive when the terminal goes away
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    tracing::info!(program = %program.display(), ?args, log = %log.display(), "launching");
+    progress::event("startup", json!({ "status": "launching", "service": name }));
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("Failed to launch {}: {}", program.display(), e))
+        .tag(ErrorKind::BackendCrash)?;
+    tracing::debug!(pid = child.id(), "launched, checking it stays up");
+
+    // catch services that fail right away, e.g. because of a bad model file
+    thread::sleep(Duration::from_millis(500));
+    if let Some(status) = child.try_wait()? {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            // SIGILL, the backend was built for instructions this CPU lacks
+            if status.signal() == Some(4) {
+                return Err(fail(
+                    ErrorKind::BackendCrash,
+                    anyhow!("{} exited with {}\n{}", name, status, cpu::explain_crash()),
+                ));
+            }
+        }
+        return Err(fail(
+            ErrorKind::BackendCrash,
+            anyhow!("{} exited with {}:\n{}", name, status, log_tail(&log, 2048)),
+        ));
+    }
+
+    state.pid = child.id();
+    state.started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    fs::write(state_path(name)?, serde_json::to_string_pretty(&state)?)?;
+    tracing::info!(pid = state.pid, port, "running");
+    events::emit(
+        "started",
+        json!({
+            "service": name,
+            "pid": state.pid,
+            "port": port,
+            "models": state.models,
+        }),
+    );
+    progress::event(
+        "startup",
+        json!({ "status": "running", "service": name, "pid": state.pid, "port": port }),
+    );
+
+    Ok(state)
+}
+
+// The furthest step of loading the log tells of
+fn phase(log: &str) -> Option<&'static str> {
+    log.lines().rev().find_map(|line| {
+        let line = line.to_lowercase();
+        PHASES
+            .iter()
+            .rev()
+            .find(|(marker, _)| line.contains(marker))
+            .map(|(_, phase)| *phase)
+    })
+}
+
+// Wait for the api-server to answer, telling what it is doing from its log. A service that
+// exits, or is still loading after the timeout, fails with the end of its log, and is stopped.
+pub fn wait_ready(state: &ServiceState, timeout: Duration) -> anyhow::Result<()> {
+    let base_url = format!("http://localhost:{}/v1", state.port);
+    progress::event("startup", json!({ "status": "waiting", "url": base_url }));
+    let started = Instant::now();
+    let mut last = None;
+    while !probe(&base_url) {
+        let log = log_tail(&state.log, 16 * 1024);
+        if !is_running(state.pid) {
+            events::emit(
+                "crashed",
+                json!({
+                    "service": state.name,
+                    "pid": state.pid,
+                    "reason": "exited while loading its models",
+                    "log": log_tail(&state.log, 2048),
+                }),
+            );
+            return Err(fail(
+                ErrorKind::BackendCrash,
+                anyhow!(
+                    "{} exited while loading its models:\n{}",
+                    state.name,
+                    log_tail(&state.log, 2048)
+                ),
+            ));
+        }
+        let phase = phase(&log);
+        if started.elapsed() > timeout {
+            let _ = stop(state);
+            return Err(fail(
+                ErrorKind::BackendCrash,
+                anyhow!(
+                    "{} was still {} after {} seconds and was stopped, raise --startup-timeout for large models. The end of its log:\n{}",
+                    state.name,
+                    phase.unwrap_or("starting"),
+                    timeout.as_secs(),
+                    log_tail(&state.log, 2048)
+                ),
+            ));
+        }
+        if phase.is_some() && phase != last {
+            tracing::info!(phase, "loading");
+            println!("  {}…", phase.unwrap_or_default());
+            progress::event("startup", json!({ "status": "loading", "phase": phase }));
+            last = phase;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    progress::event("startup", json!({ "status": "ready", "url": base_url }));
+    notify::finished(
+        "Model ready",
+        &format!("{} answers at {}", state.name, base_url),
+        started.elapsed(),
+    );
+
+    Ok(())
+}
+
+// State of the named service, if it is running
+pub fn load(name: &str) -> anyhow::Result<Option<ServiceState>> {
+    let path = state_path(name)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state: ServiceState = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    if !is_running(state.pid) {
+        // left behind by a service that died
+        fs::remove_file(&path)?;
+        return Ok(None);
+    }
+
+    Ok(Some(state))
+}
+
+// All running services
+pub fn load_all() -> anyhow::Result<Vec<ServiceState>> {
+    let mut states = Vec::new();
+    for name in names()? {
+        if let Some(state) = load(&name)? {
+            states.push(state);
+        }
+    }
+
+    Ok(states)
+}
+
+// The recorded services, those that died included, which `load` forgets
+pub fn recorded() -> anyhow::Result<Vec<ServiceState>> {
+    let mut states = Vec::new();
+    for name in names()? {
+        let Ok(json) = fs::read_to_string(state_path(&name)?) else {
+            continue;
+        };
+        states.push(serde_json::from_str(&json)?);
+    }
+
+    Ok(states)
+}
+
+// Names of the services with a state file
+fn names() -> anyhow::Result<Vec<String>> {
+    let dir = paths::run_dir()?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut names = entries
+        .filter_map(|res| {
+            res.ok().and_then(|e| {
+                e.path()
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(".json"))
+                    .map(String::from)
+            })
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+
+    Ok(names)
+}
+
+pub fn stop(state: &ServiceState) -> anyhow::Result<()> {
+    terminate(state.pid)?;
+    for _ in 0..50 {
+        if !is_running(state.pid) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let path = state_path(&state.name)?;
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    events::emit(
+        "stopped",
+        json!({ "service": state.name, "pid": state.pid }),
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+pub fn is_running(pid: u32) -> bool {
+    Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> anyhow::Result<()> {
+    let status = Command::new("kill").arg(pid.to_string()).status()?;
+    if !status.success() {
+        bail!("Failed to stop process {}", pid);
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> anyhow::Result<()> {
+    let status = Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .status()?;
+    if !status.success() {
+        bail!("Failed to stop process {}", pid);
+    }
+    Ok(())
+}
+
+// Last bytes of a log file
+pub fn log_tail(path: &Path, max_bytes: u64) -> String {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return String::new(),
+    };
+    let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let _ = file.seek(SeekFrom::Start(len.saturating_sub(max_bytes)));
+    let mut bytes = Vec::new();
+    let _ = file.read_to_end(&mut bytes);
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
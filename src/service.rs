@@ -0,0 +1,205 @@
+use crate::config;
+use crate::events;
+use crate::identity;
+use crate::paths;
+use anyhow::{anyhow, bail};
+use serde_json::json;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+// Name of the systemd unit and of the launchd agent
+const SYSTEMD_UNIT: &str = "gaia.service";
+const LAUNCHD_LABEL: &str = "org.gaianet.gaia";
+
+// Where the service is described for the service manager of this system, started at login
+pub fn unit_path() -> anyhow::Result<PathBuf> {
+    let home = paths::home()?;
+    match env::consts::OS {
+        "linux" => Ok(env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .unwrap_or(home.join(".config"))
+            .join("systemd/user")
+            .join(SYSTEMD_UNIT)),
+        "macos" => Ok(home
+            .join("Library/LaunchAgents")
+            .join(format!("{}.plist", LAUNCHD_LABEL))),
+        os => bail!(
+            "Installing a service is only supported with systemd and launchd, not on {}",
+            os
+        ),
+    }
+}
+
+// Bring up the node of the file at login, `gaia serve` starting its models before the daemon
+// follows its schedule and gateway. The unit runs with the PATH and $GAIA_HOME of this shell, so
+// it finds the same wasmedge and files.
+pub fn install(file: &Path) -> anyhow::Result<PathBuf> {
+    let exe = env::current_exe()?;
+    let file = fs::canonicalize(file).map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+    let path = unit_path()?;
+    let mut vars = vec![("PATH", env::var("PATH").unwrap_or_default())];
+    if let Ok(home) = env::var("GAIA_HOME") {
+        vars.push(("GAIA_HOME", home));
+    }
+
+    let (unit, manager) = match env::consts::OS {
+        "linux" => (systemd_unit(&exe, &file, &vars), ["systemctl", "--user"]),
+        _ => (launchd_plist(&exe, &file, &vars), ["launchctl", "load"]),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, unit)?;
+    let enabled = match env::consts::OS {
+        "linux" => {
+            run(&manager, &["daemon-reload"]).and_then(|_| run(&manager, &["enable", SYSTEMD_UNIT]))
+        }
+        _ => run(&manager, &["-w", &path.display().to_string()]),
+    };
+    if let Err(e) = enabled {
+        let _ = fs::remove_file(&path);
+        bail!("Could not enable the service: {}", e);
+    }
+    events::emit(
+        "service-installed",
+        json!({ "unit": path.display().to_string(), "file": file.display().to_string() }),
+    );
+
+    Ok(path)
+}
+
+// Disable the service and remove its unit, false when none is installed
+pub fn uninstall() -> anyhow::Result<bool> {
+    let path = unit_path()?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    // a manager that is gone or no longer knows the unit leaves only the file to remove
+    let disabled = match env::consts::OS {
+        "linux" => run(
+            &["systemctl", "--user"],
+            &["disable", "--now", SYSTEMD_UNIT],
+        ),
+        _ => run(
+            &["launchctl", "unload"],
+            &["-w", &path.display().to_string()],
+        ),
+    };
+    if let Err(e) = disabled {
+        tracing::warn!("failed to disable the service: {:#}", e);
+    }
+    fs::remove_file(&path)?;
+    if env::consts::OS == "linux" {
+        let _ = run(&["systemctl", "--user"], &["daemon-reload"]);
+    }
+    events::emit(
+        "service-uninstalled",
+        json!({ "unit": path.display().to_string() }),
+    );
+
+    Ok(true)
+}
+
+fn run(command: &[&str], args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new(command[0])
+        .args(&command[1..])
+        .args(args)
+        .status()
+        .map_err(|e| anyhow!("{}: {}", command[0], e))?;
+    if !status.success() {
+        bail!("{} {} failed", command.join(" "), args.join(" "));
+    }
+
+    Ok(())
+}
+
+fn systemd_unit(exe: &Path, file: &Path, vars: &[(&str, String)]) -> String {
+    let environment = vars
+        .iter()
+        .map(|(name, value)| format!("Environment=\"{}={}\"\n", name, value))
+        .collect::<String>();
+    format!(
+        "[Unit]
+Description=gaia node {node}
+After=network-online.target
+
+[Service]
+Type=simple
+{environment}ExecStartPre={exe} serve -f {file} -y
+ExecStart={exe} --log-to journald daemon -f {file}
+ExecStop={exe} stop
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        node = identity::name(),
+        environment = environment,
+        exe = exe.display(),
+        file = file.display()
+    )
+}
+
+fn launchd_plist(exe: &Path, file: &Path, vars: &[(&str, String)]) -> String {
+    let environment = vars
+        .iter()
+        .map(|(name, value)| format!("    <key>{}</key><string>{}</string>\n", name, value))
+        .collect::<String>();
+    // launchd has no step before the program, a shell runs both
+    let script = format!(
+        "'{exe}' serve -f '{file}' -y &amp;&amp; exec '{exe}' daemon -f '{file}'",
+        exe = exe.display(),
+        file = file.display()
+    );
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key><string>{label}</string>
+  <key>ProgramArguments</key>
+  <array>
+    <string>/bin/sh</string>
+    <string>-c</string>
+    <string>{script}</string>
+  </array>
+  <key>EnvironmentVariables</key>
+  <dict>
+{environment}  </dict>
+  <key>RunAtLoad</key><true/>
+</dict>
+</plist>
+"#,
+        label = LAUNCHD_LABEL,
+        script = script,
+        environment = environment
+    )
+}
+
+pub fn command_install(file: Option<PathBuf>) -> anyhow::Result<()> {
+    let file = match file {
+        Some(file) => file,
+        None => config::default_path()?,
+    };
+    let path = install(&file)?;
+    println!("Installed {}, the node starts at login", path.display());
+    match env::consts::OS {
+        "linux" => println!("Start it now with `systemctl --user start gaia`"),
+        _ => println!("It was started now"),
+    }
+
+    Ok(())
+}
+
+pub fn command_uninstall() -> anyhow::Result<()> {
+    match uninstall()? {
+        true => println!("Removed the service, the node no longer starts at login"),
+        false => println!("No service is installed"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,333 @@
+use crate::config;
+use crate::events;
+use crate::gateway::DEFAULT_GATEWAY_PORT;
+use crate::memory::{self, GIB};
+use crate::migrate;
+use crate::models;
+use crate::node::NodeConfig;
+use crate::paths;
+use crate::server;
+use crate::service;
+use crate::start::{self, DEFAULT_PORT};
+use crate::template::PROMPT_TEMPLATES;
+use crate::term;
+use anyhow::{anyhow, bail};
+use console::style;
+use serde_json::json;
+use std::{fs, path::PathBuf};
+
+// A model the wizard suggests, with the size of its file in GiB
+struct Suggestion {
+    name: &'static str,
+    url: &'static str,
+    template: &'static str,
+    size: f64,
+}
+
+// Smallest first, the largest that fits is suggested
+const SUGGESTIONS: &[Suggestion] = &[
+    Suggestion {
+        name: "Qwen2.5-0.5B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-0.5B-Instruct-GGUF/resolve/main/Qwen2.5-0.5B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 0.5,
+    },
+    Suggestion {
+        name: "Qwen2.5-1.5B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-1.5B-Instruct-GGUF/resolve/main/Qwen2.5-1.5B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 1.2,
+    },
+    Suggestion {
+        name: "Qwen2.5-3B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-3B-Instruct-GGUF/resolve/main/Qwen2.5-3B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 2.3,
+    },
+    Suggestion {
+        name: "Mistral-7B-Instruct-v0.3",
+        url: "https://huggingface.co/second-state/Mistral-7B-Instruct-v0.3-GGUF/resolve/main/Mistral-7B-Instruct-v0.3-Q5_K_M.gguf",
+        template: "mistral-instruct",
+        size: 4.8,
+    },
+    Suggestion {
+        name: "Qwen2.5-7B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-7B-Instruct-GGUF/resolve/main/Qwen2.5-7B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 5.1,
+    },
+    Suggestion {
+        name: "Qwen2.5-14B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-14B-Instruct-GGUF/resolve/main/Qwen2.5-14B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 9.8,
+    },
+    Suggestion {
+        name: "Qwen2.5-32B-Instruct",
+        url: "https://huggingface.co/second-state/Qwen2.5-32B-Instruct-GGUF/resolve/main/Qwen2.5-32B-Instruct-Q5_K_M.gguf",
+        template: "chatml",
+        size: 21.7,
+    },
+];
+// GiB a model takes besides its weights, for the KV cache of the default context and the runtime
+const ROOM: f64 = 1.5;
+
+// Offered when gaia runs with no node file yet: the path of the node file the wizard wrote, None
+// when there is a node file already, the input is not a terminal or the user declines
+pub fn first_run() -> anyhow::Result<Option<PathBuf>> {
+    let dir = paths::config_dir()?;
+    let exists = config::DEFAULT_FILES
+        .iter()
+        .any(|name| dir.join(name).is_file());
+    if exists || !term::interactive() {
+        return Ok(None);
+    }
+    if let Some(gaianet) = migrate::found() {
+        if term::confirm(
+            &format!(
+                "Found a gaianet-node install in {}, import it?",
+                gaianet.display()
+            ),
+            true,
+        )? {
+            migrate::command_migrate(Some(gaianet), None, false)?;
+            return Ok(Some(config::default_path()?));
+        }
+    }
+    if !term::confirm("No node is set up yet, set one up now?", true)? {
+        return Ok(None);
+    }
+
+    wizard()
+}
+
+pub fn command_setup(force: bool) -> anyhow::Result<()> {
+    if let Ok(path) = config::default_path() {
+        if !force && !term::confirm(&format!("Replace {}?", path.display()), false)? {
+            return Ok(());
+        }
+    }
+    wizard()?;
+
+    Ok(())
+}
+
+// Ask for the model, its ports, an API key and whether to run it as a service, then write the
+// node file read by `gaia serve`. Returns its path.
+fn wizard() -> anyhow::Result<Option<PathBuf>> {
+    println!("{}", style("Setting up a gaia node").bold());
+
+    // the GPU takes the model when there is one
+    let (backend, budget) = match memory::gpu() {
+        Some((backend, free)) => (Some(backend), Some(free)),
+        None => (None, memory::available()),
+    };
+    match (backend, budget) {
+        (Some(backend), Some(free)) => println!(
+            "Found a {} GPU with {:.1} GiB free",
+            backend,
+            free as f64 / GIB
+        ),
+        (None, Some(free)) => println!("Found {:.1} GiB of free memory", free as f64 / GIB),
+        _ => println!("Could not tell how much memory is free, pick a model that fits"),
+    }
+
+    let (model, template) = pick_model(budget.map(|free| free as f64 / GIB))?;
+    let port = ask_port("Port of the api-server", DEFAULT_PORT, &[])?;
+
+    let mut config = toml::Table::new();
+    config.insert("version".into(), toml::Value::Integer(1));
+    config.insert("port".into(), toml::Value::Integer(port.into()));
+    let mut chat = toml::Table::new();
+    chat.insert("model".into(), toml::Value::String(model));
+    chat.insert("prompt_template".into(), toml::Value::String(template));
+    config.insert("chat".into(), toml::Value::Table(chat));
+
+    let mut endpoint = format!("http://localhost:{}/v1", port);
+    let mut api_key = None;
+    if term::confirm(
+        "Ask clients for an API key? A gateway in front of the model checks it",
+        false,
+    )? {
+        let gateway_port = ask_port("Port of the gateway", DEFAULT_GATEWAY_PORT, &[port])?;
+        let key = new_key()?;
+        let mut gateway = toml::Table::new();
+        gateway.insert("port".into(), toml::Value::Integer(gateway_port.into()));
+        gateway.insert(
+            "api_keys".into(),
+            toml::Value::Array(vec![toml::Value::String(key.clone())]),
+        );
+        config.insert("gateway".into(), toml::Value::Table(gateway));
+        endpoint = format!("http://localhost:{}/v1", gateway_port);
+        api_key = Some(key);
+    }
+
+    let dir = paths::config_dir()?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("config.toml");
+    fs::write(&path, toml::to_string_pretty(&config)?)?;
+    NodeConfig::load(&path)?;
+    events::emit(
+        "setup-finished",
+        json!({ "file": path.display().to_string() }),
+    );
+    println!("Wrote {}", path.display());
+
+    let mut service = service::unit_path().is_ok()
+        && term::confirm("Start the node at login, as a service?", false)?;
+    if service {
+        match service::install(&path) {
+            Ok(unit) => println!("Installed {}", unit.display()),
+            Err(e) => {
+                eprintln!("{} {}", style("Error:").red(), e);
+                service = false;
+            }
+        }
+    }
+
+    println!();
+    println!("Clients connect to {}", style(&endpoint).bold());
+    if let Some(key) = &api_key {
+        println!(
+            "with the API key {}, keep it, it is only in the node file",
+            style(key).bold()
+        );
+    }
+    match (service, api_key.is_some()) {
+        (true, _) if std::env::consts::OS == "linux" => {
+            println!("Start the node now with `systemctl --user start gaia`")
+        }
+        (true, _) => {}
+        // the daemon runs the gateway
+        (false, true) => {
+            println!(
+                "Start the node with `gaia serve`, then the gateway with `gaia daemon --detach`"
+            )
+        }
+        (false, false) => println!("Start the node with `gaia serve`"),
+    }
+
+    Ok(Some(path))
+}
+
+// The model and its prompt template, a suggested model downloaded now if the user wants
+fn pick_model(budget: Option<f64>) -> anyhow::Result<(String, String)> {
+    let local = models::local_models().unwrap_or_default();
+    let fitting = SUGGESTIONS
+        .iter()
+        .filter(|suggestion| budget.is_none_or(|budget| suggestion.size + ROOM <= budget))
+        .collect::<Vec<_>>();
+    let suggested = fitting.last().map(|suggestion| suggestion.name);
+
+    let mut items = local
+        .iter()
+        .map(|model| {
+            format!(
+                "{} ({:.1} GiB, downloaded)",
+                model.model,
+                model.size as f64 / GIB
+            )
+        })
+        .collect::<Vec<_>>();
+    for suggestion in &fitting {
+        let mut item = format!("{} ({:.1} GiB)", suggestion.name, suggestion.size);
+        if Some(suggestion.name) == suggested {
+            item.push_str(", suggested for this machine");
+        }
+        items.push(item);
+    }
+    items.push("Another url or path".to_string());
+    let default = match (local.is_empty(), fitting.is_empty()) {
+        (true, false) => fitting.len() - 1,
+        _ => 0,
+    };
+    let Some(picked) = term::select("Model to serve", &items, default)? else {
+        bail!("No model picked");
+    };
+
+    if let Some(model) = local.get(picked) {
+        return Ok((model.model.clone(), pick_template()?));
+    }
+    if let Some(suggestion) = fitting.get(picked - local.len()) {
+        let model = match term::confirm(
+            &format!(
+                "Download {} ({:.1} GiB) now? Otherwise `gaia serve` downloads it",
+                suggestion.name, suggestion.size
+            ),
+            true,
+        )? {
+            true => start::pull(suggestion.url)?.display().to_string(),
+            false => suggestion.url.to_string(),
+        };
+        return Ok((model, suggestion.template.to_string()));
+    }
+    let model = term::input("Url or path of the gguf model", false)?
+        .filter(|model| !model.trim().is_empty())
+        .ok_or(anyhow!("No model given"))?;
+    let model = match start::is_remote(model.trim()) {
+        true => model.trim().to_string(),
+        false => fs::canonicalize(model.trim())
+            .map_err(|e| anyhow!("{}: {}", model.trim(), e))?
+            .display()
+            .to_string(),
+    };
+
+    Ok((model, pick_template()?))
+}
+
+fn pick_template() -> anyhow::Result<String> {
+    let templates = PROMPT_TEMPLATES.map(String::from);
+    let default = PROMPT_TEMPLATES
+        .iter()
+        .position(|template| *template == "chatml")
+        .unwrap_or_default();
+    match term::select("Prompt template of the model", &templates, default)? {
+        Some(picked) => Ok(templates[picked].clone()),
+        None => bail!("No prompt template picked"),
+    }
+}
+
+// A port that is free and not one of `taken`, asked again until it is
+fn ask_port(prompt: &str, default: u16, taken: &[u16]) -> anyhow::Result<u16> {
+    loop {
+        let Some(answer) = term::input(&format!("{} [{}]", prompt, default), true)? else {
+            bail!("No port given");
+        };
+        let port = match answer.trim() {
+            "" => default,
+            answer => match answer.parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => {
+                    eprintln!("{} '{}' is not a port", style("Error:").red(), answer);
+                    continue;
+                }
+            },
+        };
+        if taken.contains(&port) {
+            eprintln!(
+                "{} port {} is already used by the node",
+                style("Error:").red(),
+                port
+            );
+            continue;
+        }
+        match server::check_port(port) {
+            Ok(()) => return Ok(port),
+            Err(e) => eprintln!("{} {}", style("Error:").red(), e),
+        }
+    }
+}
+
+// A random API key, 32 hex digits after `gaia-`
+fn new_key() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 16];
+    openssl::rand::rand_bytes(&mut bytes)?;
+
+    Ok(format!(
+        "gaia-{}",
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    ))
+}
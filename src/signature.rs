@@ -0,0 +1,224 @@
+use crate::error::{fail, ErrorKind};
+use crate::events;
+use crate::paths;
+use crate::start;
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::Args;
+use console::style;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{Id, PKey, Public},
+    sign::Verifier,
+};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+static REQUIRED: OnceLock<bool> = OnceLock::new();
+
+#[derive(Debug, Clone, Args)]
+pub struct SignatureArgs {
+    #[arg(
+        long = "require-signed",
+        help = "Refuse models without a detached signature from a key trusted with `gaia models trust`",
+        env = "GAIA_REQUIRE_SIGNED",
+        global = true
+    )]
+    pub require_signed: bool,
+}
+
+pub fn init(args: &SignatureArgs) {
+    let _ = REQUIRED.set(args.require_signed);
+}
+
+fn required() -> bool {
+    REQUIRED.get() == Some(&true)
+}
+
+// Public keys, in PEM, that models may be signed with
+pub fn keys_dir() -> anyhow::Result<PathBuf> {
+    Ok(paths::config_dir()?.join("trusted-keys"))
+}
+
+// Only keys signing a digest can check a file of many gigabytes without reading it into memory
+fn load_key(path: &Path) -> anyhow::Result<PKey<Public>> {
+    let key = PKey::public_key_from_pem(&fs::read(path)?)
+        .map_err(|e| anyhow!("{} is not a PEM public key: {}", path.display(), e))?;
+    match key.id() {
+        Id::EC | Id::RSA => Ok(key),
+        _ => bail!(
+            "{} is not an ECDSA or RSA key, the ones cosign sign-blob and openssl dgst -sha256 sign with",
+            path.display()
+        ),
+    }
+}
+
+fn trusted_keys() -> anyhow::Result<Vec<(String, PKey<Public>)>> {
+    let Ok(entries) = fs::read_dir(keys_dir()?) else {
+        return Ok(Vec::new());
+    };
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pem"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            Ok((name, load_key(&path)?))
+        })
+        .collect()
+}
+
+// `<file>.sig` beside the model, else `<url>.sig` beside the download, kept beside the model
+fn signature(path: &Path, url: Option<&str>) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut sig_path = path.as_os_str().to_owned();
+    sig_path.push(".sig");
+    let sig_path = PathBuf::from(sig_path);
+    let raw = match (fs::read(&sig_path), url) {
+        (Ok(raw), _) => raw,
+        (Err(_), Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {
+            let mut sig_url = Url::parse(url)?;
+            sig_url.set_path(&format!("{}.sig", sig_url.path()));
+            let response = reqwest::blocking::get(sig_url)?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let raw = response.error_for_status()?.bytes()?.to_vec();
+            fs::write(&sig_path, &raw)?;
+            raw
+        }
+        _ => return Ok(None),
+    };
+
+    // cosign writes the signature in base64, openssl writes it raw
+    let text = String::from_utf8_lossy(&raw);
+    Ok(Some(STANDARD.decode(text.trim()).unwrap_or(raw)))
+}
+
+fn verify(path: &Path, key: &PKey<Public>, sig: &[u8]) -> anyhow::Result<bool> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buffer[..n])?;
+    }
+
+    Ok(verifier.verify(sig).unwrap_or(false))
+}
+
+// Check the detached signature of a model against the trusted keys. A signature matching
+// none of them is always refused, a missing one only with --require-signed. A model
+// downloaded from `url` is removed when its signature does not match.
+pub fn check(path: &Path, url: Option<&str>) -> anyhow::Result<()> {
+    let name = path.display();
+    let Some(sig) = signature(path, url)? else {
+        if required() {
+            return Err(fail(
+                ErrorKind::Untrusted,
+                anyhow!(
+                    "{} has no signature (.sig) and --require-signed is set",
+                    name
+                ),
+            ));
+        }
+        return Ok(());
+    };
+    let keys = trusted_keys()?;
+    if keys.is_empty() {
+        if required() {
+            return Err(fail(
+                ErrorKind::Untrusted,
+                anyhow!(
+                    "{} is signed but no key is trusted, add one with `gaia models trust`",
+                    name
+                ),
+            ));
+        }
+        eprintln!(
+            "{}",
+            style(format!(
+                "{} is signed, trust its key with `gaia models trust` to verify it",
+                name
+            ))
+            .dim()
+        );
+        return Ok(());
+    }
+
+    for (key_name, key) in &keys {
+        if verify(path, key, &sig)? {
+            tracing::info!(path = %name, key = key_name.as_str(), "signature verified");
+            println!("{} is signed by {}", name, key_name);
+            return Ok(());
+        }
+    }
+    if url.is_some() {
+        let _ = fs::remove_file(path);
+    }
+
+    Err(fail(
+        ErrorKind::Untrusted,
+        anyhow!(
+            "The signature of {} does not match any trusted key, the file may have been tampered with",
+            name
+        ),
+    ))
+}
+
+// Trust a public key to sign models
+pub fn command_trust(key: PathBuf, name: Option<String>) -> anyhow::Result<()> {
+    load_key(&key)?;
+    let name = match name {
+        Some(name) => name,
+        None => key
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(anyhow!("{} is not a file", key.display()))?,
+    };
+    let dir = keys_dir()?;
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(format!("{}.pem", name));
+    fs::copy(&key, &dest)?;
+    events::emit(
+        "key-trusted",
+        json!({ "name": name, "key": dest.display().to_string() }),
+    );
+    println!("Trusted {} as {}", key.display(), dest.display());
+
+    Ok(())
+}
+
+// Check the signature of a model on disk, given by path or by name in the models directory
+pub fn command_verify(model: String) -> anyhow::Result<()> {
+    let path = start::find_model(&model)?;
+    if trusted_keys()?.is_empty() {
+        return Err(fail(
+            ErrorKind::Untrusted,
+            anyhow!("No key is trusted, add one with `gaia models trust`"),
+        ));
+    }
+    if signature(&path, None)?.is_none() {
+        return Err(fail(
+            ErrorKind::Untrusted,
+            anyhow!("{} has no signature (.sig)", path.display()),
+        ));
+    }
+
+    check(&path, None)
+}
@@ -0,0 +1,1159 @@
+use crate::affinity::{self, Numa};
+use crate::blob;
+use crate::cpu;
+use crate::daemon;
+use crate::device::{self, Device};
+use crate::error::{ErrorKind, Tag};
+use crate::events;
+use crate::gguf::Gguf;
+use crate::hf;
+use crate::ipfs;
+use crate::license;
+use crate::memory;
+use crate::models;
+use crate::oci;
+use crate::ollama;
+use crate::paths;
+use crate::preflight::{Check, Preflight};
+use crate::preset;
+use crate::progress::{self, Progress, ProgressReader};
+use crate::runtime;
+use crate::server::{self, ModelKind, ServedModel, ServiceState};
+use crate::signature;
+use crate::template::{
+    self, ChatTemplate, PromptTemplateType, EMBEDDED_TEMPLATE, PROMPT_TEMPLATES,
+};
+use crate::term;
+use crate::torrent;
+use anyhow::{anyhow, bail};
+use clap::{builder::EnumValueParser, Args};
+use console::style;
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::File;
+use std::io::copy;
+use std::{
+    env,
+    fs::{self},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// MiB of VRAM left free for other programs with `--n-gpu-layers auto`
+pub const DEFAULT_GPU_HEADROOM: u64 = 1024;
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_EMBEDDING_CONTEXT_SIZE: u64 = 512;
+pub const DEFAULT_WHISPER_PORT: u16 = 8081;
+
+#[derive(Debug, Clone, Args, Serialize, Deserialize)]
+pub struct StartArgs {
+    #[arg(
+        short = 'm',
+        long = "model",
+        help = "Url or path to the gguf model, s3://, gs:// and az:// urls are read with the usual credentials, ipfs:// urls, magnet links and .torrent files are checked piece by piece, oci:// references are pulled from the registry, ollama://<name> uses a model of Ollama in place",
+        ignore_case = true
+    )]
+    pub model: Option<String>,
+    #[arg(
+        short = 'p',
+        long = "prompt-template",
+        help = "Type of prompt template for the gguf model",
+        requires = "model",
+        value_parser = EnumValueParser::<PromptTemplateType>::new(),
+    )]
+    pub prompt_template: Option<PromptTemplateType>,
+    #[arg(
+        long = "chat-template",
+        help = "Jinja chat template the gateway of `gaia daemon` renders the prompts of the chat model with, in place of the prompt template the api-server still uses for the requests sent to it directly, `gguf` taking the one in the model's file",
+        value_name = "gguf|FILE",
+        requires = "model"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_template: Option<String>,
+    #[arg(
+        short = 'r',
+        long = "reverse-prompt",
+        help = "Halt generation at PROMPT, return control",
+        requires = "model"
+    )]
+    pub reverse_prompt: Option<String>,
+    #[arg(
+        short = 'c',
+        long = "context-size",
+        help = "Prompt context size",
+        requires = "model"
+    )]
+    pub context_size: Option<u64>,
+    #[arg(
+        long = "port",
+        help = "Port of the api-server, 0 picks a free one",
+        default_value_t = DEFAULT_PORT
+    )]
+    pub port: u16,
+    #[arg(
+        long = "model-name",
+        help = "Name to serve the chat model under, defaults to the file name"
+    )]
+    pub model_name: Option<String>,
+    #[arg(
+        short = 'e',
+        long = "embedding-model",
+        help = "Url or path to a gguf embedding model to serve next to the chat model"
+    )]
+    pub embedding_model: Option<String>,
+    #[arg(
+        long = "embedding-model-name",
+        help = "Name to serve the embedding model under, defaults to the file name",
+        requires = "embedding_model"
+    )]
+    pub embedding_model_name: Option<String>,
+    #[arg(
+        long = "embedding-context-size",
+        help = "Context size of the embedding model",
+        default_value_t = DEFAULT_EMBEDDING_CONTEXT_SIZE,
+        requires = "embedding_model"
+    )]
+    pub embedding_context_size: u64,
+    #[arg(
+        long = "whisper-model",
+        help = "Url or path to a whisper ggml model to serve audio transcription"
+    )]
+    pub whisper_model: Option<String>,
+    #[arg(
+        long = "whisper-port",
+        help = "Port of the whisper api-server, 0 picks a free one",
+        default_value_t = DEFAULT_WHISPER_PORT
+    )]
+    pub whisper_port: u16,
+    #[arg(
+        long = "local-only",
+        help = "Listen on 127.0.0.1 only, leaving a gateway in front of the api-server the one way in"
+    )]
+    #[serde(default)]
+    pub local_only: bool,
+    #[arg(
+        long = "dry-run",
+        help = "Print what would be downloaded, launched and bound, then exit"
+    )]
+    pub dry_run: bool,
+    #[arg(
+        long = "strict-memory",
+        help = "Refuse to start when the models need more memory than the machine has, instead of warning"
+    )]
+    pub strict_memory: bool,
+    #[arg(
+        short = 'g',
+        long = "n-gpu-layers",
+        help = "Layers to offload to the GPU, auto fits as many as the free VRAM allows",
+        value_name = "N|auto"
+    )]
+    pub n_gpu_layers: Option<GpuLayers>,
+    #[arg(
+        long = "gpu-headroom",
+        help = "MiB of VRAM to leave free with --n-gpu-layers auto",
+        default_value_t = DEFAULT_GPU_HEADROOM,
+        value_name = "MIB"
+    )]
+    pub gpu_headroom: u64,
+    #[arg(
+        long = "device",
+        help = "Device to compute on, fetching the build of the wasi-nn plugin for it, defaults to the GPU found",
+        value_enum
+    )]
+    pub device: Option<Device>,
+    #[arg(
+        long = "numa",
+        help = "Place the backend on the NUMA nodes of a multi-socket machine, distribute interleaves its memory over all the nodes, isolate keeps it on one",
+        value_enum
+    )]
+    pub numa: Option<Numa>,
+    #[arg(
+        long = "cpu-affinity",
+        help = "CPUs the backend runs on, e.g. 0-15,32-47",
+        value_name = "CPUS",
+        value_parser = affinity::parse_cpus
+    )]
+    pub cpu_affinity: Option<String>,
+    #[arg(
+        long = "runtime",
+        help = "WasmEdge version installed with `gaia runtime install` to run the models with, defaults to the one picked with `gaia runtime use`",
+        value_name = "VERSION"
+    )]
+    pub runtime: Option<String>,
+    #[arg(
+        short = 'y',
+        long = "yes",
+        help = "Start without asking to confirm the preflight summary"
+    )]
+    pub yes: bool,
+    #[arg(
+        long = "startup-timeout",
+        help = "Seconds to wait for the api-server to load the models, 0 returns once it is launched",
+        default_value_t = server::DEFAULT_STARTUP_TIMEOUT,
+        value_name = "SECS"
+    )]
+    pub startup_timeout: u64,
+    #[arg(
+        long = "preset",
+        help = "Preset made with `gaia preset create` giving the model, its name and prompt template, chats using its system prompt and sampling parameters from then on; defaults to the one picked with `gaia preset use` when no model is given",
+        value_name = "NAME"
+    )]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    // service to launch the chat model as, api-server unless the gateway loads it on demand
+    #[arg(skip)]
+    #[serde(default)]
+    pub service: Option<String>,
+}
+
+// Sent to the daemon as written on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum GpuLayers {
+    Auto,
+    Count(u64),
+}
+impl TryFrom<String> for GpuLayers {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl From<GpuLayers> for String {
+    fn from(layers: GpuLayers) -> Self {
+        match layers {
+            GpuLayers::Auto => "auto".to_string(),
+            GpuLayers::Count(layers) => layers.to_string(),
+        }
+    }
+}
+impl FromStr for GpuLayers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(GpuLayers::Auto),
+            _ => s
+                .parse()
+                .map(GpuLayers::Count)
+                .map_err(|_| format!("expected a number of layers or auto, got '{}'", s)),
+        }
+    }
+}
+
+// What `start --dry-run` prints instead of doing it
+#[derive(Default)]
+struct Plan {
+    // (url, destination)
+    downloads: Vec<(String, String)>,
+    // (service, command line, port)
+    launches: Vec<(String, Vec<String>, u16)>,
+    // the address the services listen on
+    host: &'static str,
+    // unknown until the models are downloaded
+    memory: Option<memory::Estimate>,
+}
+
+pub fn command_start(args: StartArgs) -> anyhow::Result<()> {
+    // the preset in use only stands in for a model that is not given
+    let name = match (&args.preset, &args.model) {
+        (Some(name), _) => Some(name.clone()),
+        (None, None) => preset::current()?,
+        (None, Some(_)) => None,
+    };
+    let Some(name) = name else {
+        return start(args, Vec::new());
+    };
+
+    let preset = preset::load(&name)?;
+    let dry_run = args.dry_run;
+    let args = StartArgs {
+        model: args.model.or(preset.model.clone()),
+        prompt_template: args.prompt_template.or(preset.template()?),
+        model_name: args.model_name.or(preset.model_name.clone()),
+        ..args
+    };
+    println!("Using the preset '{}'", name);
+    start(args, Vec::new())?;
+    if !dry_run {
+        preset::started(&name)?;
+    }
+
+    Ok(())
+}
+
+// Start the services, with more lines for the preflight summary, e.g. the collections of a node
+pub fn start(args: StartArgs, checks: Vec<Check>) -> anyhow::Result<()> {
+    let StartArgs {
+        model,
+        prompt_template,
+        chat_template,
+        reverse_prompt,
+        context_size,
+        port,
+        model_name,
+        embedding_model,
+        embedding_model_name,
+        embedding_context_size,
+        whisper_model,
+        whisper_port,
+        local_only,
+        dry_run,
+        strict_memory,
+        n_gpu_layers,
+        gpu_headroom,
+        device,
+        numa,
+        cpu_affinity,
+        runtime,
+        yes,
+        startup_timeout,
+        preset: _,
+        service,
+    } = args;
+    let mut plan = Plan::default();
+    // behind a gateway, clients do not skip its keys, filters and limits by calling the backends
+    let host = match local_only {
+        true => "127.0.0.1",
+        false => "0.0.0.0",
+    };
+    plan.host = host;
+
+    let gguf_model = match model {
+        Some(model) => resolve_model(&model, dry_run, &mut plan)?,
+        None => {
+            // check cached models, downloaded ones and those in the current directory
+            let mut cached_models = cached_models()?;
+
+            let mut selected = String::new();
+            if !cached_models.is_empty() {
+                cached_models.push("Or choose one from: https://huggingface.co/second-state?sort_models=modified#models or https://huggingface.co/models?sort=trending&search=gguf".to_string());
+                let selection = term::select("Select a chached model", &cached_models, 0)?;
+
+                selected = match selection {
+                    Some(idx) => cached_models[idx].clone(),
+                    _ => bail!("No model selected"),
+                };
+            }
+
+            if selected.ends_with(".gguf") {
+                selected
+            } else if ollama::is_ollama_url(&selected) {
+                resolve_model(&selected, dry_run, &mut plan)?
+            } else {
+                // provide a model url to download
+                let model_url = term::input("Enter the model url", false)?
+                    .ok_or(anyhow!("No model url given"))?;
+
+                // download the model from the url
+                resolve_model(&model_url, dry_run, &mut plan)?
+            }
+        }
+    };
+
+    // the gateway renders the prompts of a model with a chat template, the api-server only being
+    // asked for completions of them
+    let chat_template = chat_template
+        .map(|source| match source == EMBEDDED_TEMPLATE {
+            true => Ok(source),
+            false => fs::canonicalize(&source)
+                .map(|path| path.display().to_string())
+                .map_err(|e| anyhow!("{}: {}", source, e)),
+        })
+        .transpose()?;
+    let prompt_template: PromptTemplateType = match prompt_template {
+        Some(prompt_template) => prompt_template,
+        // requests sent to the api-server itself, not through the gateway, are still rendered
+        // with it, so it is not guessed
+        None if chat_template.is_some() => bail!(
+            "--chat-template needs --prompt-template too, for the requests sent to the api-server directly"
+        ),
+        None => {
+            let templates = PROMPT_TEMPLATES.map(String::from);
+            let selection = term::select("Select a prompt template", &templates, 0)?;
+
+            match selection {
+                Some(idx) => {
+                    let x = PROMPT_TEMPLATES[idx];
+                    <PromptTemplateType as FromStr>::from_str(x)?
+                }
+                _ => bail!("No prompt template selected"),
+            }
+        }
+    };
+
+    // check everything that can be checked before launching anything
+    progress::event("startup", json!({ "status": "checking" }));
+    // the variables the services are launched with, gaia's environment being shared with the
+    // threads of the gateway that start on-demand models
+    let mut launch_env = Vec::new();
+    let (wasmedge, version) = match runtime::selected(runtime.as_deref())? {
+        Some(runtime) => {
+            launch_env = runtime.env()?;
+            println!("Using WasmEdge {}", runtime.version);
+            (runtime.wasmedge(), Some(runtime.version))
+        }
+        None => (server::wasmedge()?, None),
+    };
+    // a pinned device takes its build of the plugin, as does a CPU without AVX2 that the
+    // default build crashes on, unless a plugin is picked with $WASMEDGE_PLUGIN_PATH
+    let lacks_avx = !cpu::missing().is_empty()
+        && version.is_none()
+        && env::var_os("WASMEDGE_PLUGIN_PATH").is_none();
+    let pinned = device.or(lacks_avx.then_some(Device::Cpu));
+    if let Some(plugin) = pinned
+        .map(|device| match &version {
+            Some(version) => device::plugin(device, version),
+            None => device::plugin(device, &device::wasmedge_version(&wasmedge)?),
+        })
+        .transpose()?
+        .flatten()
+    {
+        if !plugin.dir.is_dir() {
+            match dry_run {
+                true => plan
+                    .downloads
+                    .push((plugin.url.clone(), plugin.dir.display().to_string())),
+                false => device::fetch(&plugin)?,
+            }
+        }
+        if pinned == Some(Device::Cpu) {
+            println!(
+                "This CPU lacks {}, using the wasi-nn plugin built without AVX",
+                cpu::missing().join(", ")
+            );
+        }
+        launch_env.retain(|(name, _)| name != "WASMEDGE_PLUGIN_PATH");
+        launch_env.push((
+            "WASMEDGE_PLUGIN_PATH".to_string(),
+            plugin.dir.display().to_string(),
+        ));
+    }
+    let device = pinned.unwrap_or_else(device::detect);
+    let (port, port_check) = pick_port("port", port)?;
+    let (whisper_port, whisper_port_check) = pick_port("whisper port", whisper_port)?;
+    let embedding_model = embedding_model
+        .map(|embedding_model| resolve_model(&embedding_model, dry_run, &mut plan))
+        .transpose()?;
+    let whisper_model = whisper_model
+        .map(|whisper_model| resolve_model(&whisper_model, dry_run, &mut plan))
+        .transpose()?;
+
+    // tell before the backend runs out of memory
+    let mut sized = vec![(
+        Path::new(&gguf_model),
+        context_size.unwrap_or(crate::DEFAULT_CONTEXT_SIZE),
+    )];
+    if let Some(embedding_model) = &embedding_model {
+        sized.push((Path::new(embedding_model), embedding_context_size));
+    }
+    if let Some(whisper_model) = &whisper_model {
+        sized.push((Path::new(whisper_model), 0));
+    }
+    if sized.iter().all(|(path, _)| path.is_file()) {
+        plan.memory = Some(memory::estimate_all(&sized)?);
+    }
+
+    // start api-server
+    let api_server = wasm_app(
+        "llama-api-server.wasm",
+        server::LLAMA_API_SERVER_URL,
+        dry_run,
+        &mut plan,
+    )?;
+    let mut models = vec![ServedModel {
+        name: model_name.unwrap_or_else(|| server::model_name(&gguf_model)),
+        path: gguf_model.clone(),
+        kind: ModelKind::Chat,
+        chat_template: chat_template.clone(),
+    }];
+    if let Some(embedding_model) = &embedding_model {
+        models.push(ServedModel {
+            name: embedding_model_name.unwrap_or_else(|| server::model_name(embedding_model)),
+            path: embedding_model.clone(),
+            kind: ModelKind::Embedding,
+            chat_template: None,
+        });
+    }
+    if models.len() > 1 && models[0].name == models[1].name {
+        bail!(
+            "The chat and embedding models are both named '{}', set --model-name or --embedding-model-name",
+            models[0].name
+        );
+    }
+
+    // the api-server takes comma-separated lists when serving several models
+    let preloads = models
+        .iter()
+        .enumerate()
+        .map(|(i, model)| {
+            let alias = if i == 0 { "default" } else { "embedding" };
+            format!("{}:GGML:AUTO:{}", alias, model.path)
+        })
+        .collect::<Vec<_>>();
+    let mut templates = vec![prompt_template.to_string()];
+    if embedding_model.is_some() {
+        templates.push("embedding".to_string());
+    }
+    let mut server_args = vec![
+        "--dir".to_string(),
+        ".:.".to_string(),
+        "--nn-preload".to_string(),
+        preloads.join(","),
+        api_server.display().to_string(),
+        "--prompt-template".to_string(),
+        templates.join(","),
+        "--model-name".to_string(),
+        models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        "--socket-addr".to_string(),
+        format!("{}:{}", host, port),
+    ];
+    if let Some(reverse_prompt) = reverse_prompt {
+        server_args.extend(["--reverse-prompt".to_string(), reverse_prompt]);
+    }
+    match (context_size, &embedding_model) {
+        (Some(context_size), None) => {
+            server_args.extend(["--ctx-size".to_string(), context_size.to_string()]);
+        }
+        (context_size, Some(_)) => {
+            let context_size = context_size.unwrap_or(crate::DEFAULT_CONTEXT_SIZE);
+            server_args.extend([
+                "--ctx-size".to_string(),
+                format!("{},{}", context_size, embedding_context_size),
+            ]);
+        }
+        (None, None) => {}
+    }
+
+    let n_gpu_layers = match n_gpu_layers {
+        Some(GpuLayers::Count(layers)) => Some(layers),
+        // nothing is offloaded on the CPU
+        _ if pinned == Some(Device::Cpu) => Some(0),
+        // the model is only there once downloaded
+        Some(GpuLayers::Auto) if Path::new(&gguf_model).is_file() => {
+            let (layers, decision) = memory::gpu_layers(
+                Path::new(&gguf_model),
+                context_size.unwrap_or(crate::DEFAULT_CONTEXT_SIZE),
+                gpu_headroom * 1024 * 1024,
+            )?;
+            println!("{}", decision);
+            Some(layers)
+        }
+        Some(GpuLayers::Auto) | None => None,
+    };
+    if let Some(layers) = n_gpu_layers {
+        server_args.extend(["--n-gpu-layers".to_string(), layers.to_string()]);
+    }
+
+    // audio model served next to the chat model
+    let whisper = match &whisper_model {
+        Some(whisper_model) => {
+            let whisper_server = wasm_app(
+                "whisper-api-server.wasm",
+                server::WHISPER_API_SERVER_URL,
+                dry_run,
+                &mut plan,
+            )?;
+            let whisper_path = PathBuf::from(whisper_model);
+            Some(vec![
+                "--dir".to_string(),
+                ".:.".to_string(),
+                "--dir".to_string(),
+                server::preopen(&whisper_path),
+                whisper_server.display().to_string(),
+                "--model".to_string(),
+                whisper_path.display().to_string(),
+                "--socket-addr".to_string(),
+                format!("{}:{}", host, whisper_port),
+            ])
+        }
+        None => None,
+    };
+
+    // numactl or taskset launch the backend where it is placed
+    let (program, server_args) =
+        affinity::wrap(numa, cpu_affinity.as_deref(), &wasmedge, server_args)?;
+
+    let mut preflight = Preflight::default();
+    let downloaded = |path: &str, check: Check| match Path::new(path).is_file() {
+        true => check,
+        false => check.warn("Downloaded when starting"),
+    };
+    let gguf = Gguf::read(Path::new(&gguf_model)).ok();
+    preflight.push(downloaded(&gguf_model, Check::new("model", &gguf_model)));
+    preflight.push(Check::new(
+        "quantization",
+        gguf.as_ref().and_then(Gguf::file_type).unwrap_or("unknown"),
+    ));
+    preflight.push(match &chat_template {
+        // rendering a conversation tells the template works before launching
+        Some(source) => Check::new("template", template::describe_source(source)).result(
+            match Path::new(&gguf_model).is_file() {
+                true => ChatTemplate::load(Path::new(&gguf_model), source)
+                    .and_then(|template| template.render_sample())
+                    .map(drop),
+                false => Ok(()),
+            },
+        ),
+        None => Check::new("template", prompt_template.to_string()),
+    });
+    let context = context_size.unwrap_or(crate::DEFAULT_CONTEXT_SIZE);
+    let trained = gguf
+        .as_ref()
+        .and_then(|gguf| gguf.arch_u64("context_length"));
+    preflight.push(match trained {
+        Some(trained) if context > trained => {
+            Check::new("context", context.to_string()).warn(format!(
+                "The model was trained on {} tokens, answers may degrade past them",
+                trained
+            ))
+        }
+        _ => Check::new("context", context.to_string()),
+    });
+    if let Some(embedding_model) = &embedding_model {
+        preflight.push(downloaded(
+            embedding_model,
+            Check::new(
+                "embedding",
+                format!("{} ({} tokens)", embedding_model, embedding_context_size),
+            ),
+        ));
+    }
+    if let Some(whisper_model) = &whisper_model {
+        preflight.push(downloaded(
+            whisper_model,
+            Check::new("whisper", whisper_model),
+        ));
+    }
+    preflight.push(Check::new(
+        "device",
+        match n_gpu_layers {
+            Some(layers) => format!("{} ({} layers offloaded)", device, layers),
+            None => device.to_string(),
+        },
+    ));
+    if let Some(placement) = affinity::describe(numa, cpu_affinity.as_deref()) {
+        preflight.push(Check::new("placement", placement));
+    }
+    preflight.push(port_check);
+    if whisper_model.is_some() {
+        preflight.push(whisper_port_check);
+    }
+    preflight.push(match &plan.memory {
+        Some(estimate) => {
+            let check = Check::new("memory", memory::describe(estimate));
+            match memory::shortfall(estimate) {
+                Some(shortfall) if strict_memory => check.result(Err(anyhow!(shortfall))),
+                Some(shortfall) => check.warn(shortfall),
+                None => check,
+            }
+        }
+        None => Check::new("memory", "known once the models are downloaded"),
+    });
+    for check in checks {
+        preflight.push(check);
+    }
+    preflight.confirm(!yes && !dry_run)?;
+
+    if dry_run {
+        let mut command = vec![program.display().to_string()];
+        command.extend(server_args);
+        plan.launches
+            .push((server::API_SERVER.to_string(), command, port));
+        if let Some(whisper_args) = whisper {
+            let mut command = vec![wasmedge.display().to_string()];
+            command.extend(whisper_args);
+            plan.launches
+                .push((server::WHISPER_SERVER.to_string(), command, whisper_port));
+        }
+        let template = match &chat_template {
+            Some(source) => template::describe_source(source),
+            None => prompt_template.to_string(),
+        };
+        return print_plan(&plan, &models, &template, whisper_model.as_deref());
+    }
+
+    let state = server::spawn(
+        service.as_deref().unwrap_or(server::API_SERVER),
+        [vec![program.display().to_string()], server_args].concat(),
+        port,
+        models,
+        Some(device),
+        &launch_env,
+    )?;
+    println!(
+        "Started {} at http://localhost:{}/v1, pid {}",
+        state.name, state.port, state.pid
+    );
+    for model in &state.models {
+        println!("  {} model: {} ({})", model.kind, model.name, model.path);
+    }
+    println!("  device: {}", device);
+    if startup_timeout > 0 {
+        let started = Instant::now();
+        server::wait_ready(&state, Duration::from_secs(startup_timeout))?;
+        println!("Ready in {}s", started.elapsed().as_secs());
+    }
+    banner(&state);
+
+    // start the audio model next to the chat model
+    if let (Some(whisper_model), Some(whisper_args)) = (whisper_model, whisper) {
+        let models = vec![ServedModel {
+            name: server::model_name(&whisper_model),
+            path: whisper_model.clone(),
+            kind: ModelKind::Audio,
+            chat_template: None,
+        }];
+        let state = match server::spawn(
+            server::WHISPER_SERVER,
+            [vec![wasmedge.display().to_string()], whisper_args].concat(),
+            whisper_port,
+            models,
+            None,
+            &launch_env,
+        ) {
+            Ok(state) => state,
+            Err(e) => {
+                // do not leave a half-started node behind
+                command_stop()?;
+                return Err(e);
+            }
+        };
+        println!(
+            "Started {} with {} at http://localhost:{}/v1, pid {}",
+            state.name, whisper_model, state.port, state.pid
+        );
+    }
+
+    Ok(())
+}
+
+// The port asked for, or a free one for 0, with its line of the preflight summary
+fn pick_port(item: &str, port: u16) -> anyhow::Result<(u16, Check)> {
+    if port != 0 {
+        return Ok((
+            port,
+            Check::new(item, port.to_string()).result(server::check_port(port)),
+        ));
+    }
+    let port = server::free_port()?;
+
+    Ok((
+        port,
+        Check::new(item, format!("{} (free port picked)", port)),
+    ))
+}
+
+// Where to connect, with a request to try the chat model
+fn banner(state: &ServiceState) {
+    let url = format!("http://localhost:{}/v1", state.port);
+    let Some(chat) = state
+        .models
+        .iter()
+        .find(|model| model.kind == ModelKind::Chat)
+    else {
+        return;
+    };
+    let body = json!({
+        "model": chat.name,
+        "messages": [{ "role": "user", "content": "Hello!" }],
+    });
+
+    println!();
+    println!("  {:<10}{}", style("Base URL").bold(), style(&url).cyan());
+    println!("  {:<10}{}", style("Model").bold(), chat.name);
+    if let Some(embedding) = state
+        .models
+        .iter()
+        .find(|model| model.kind == ModelKind::Embedding)
+    {
+        println!("  {:<10}{}", style("Embedding").bold(), embedding.name);
+    }
+    println!("  {}", style("Try it").bold());
+    println!(
+        "    curl {}/chat/completions -H 'Content-Type: application/json' -d '{}'",
+        url, body
+    );
+    println!();
+}
+
+pub fn command_status() -> anyhow::Result<()> {
+    // a running daemon knows, the PID files are all there is without one
+    let states = match daemon::status()? {
+        Some(services) => services
+            .into_iter()
+            .map(|service| (service.state, service.up, service.parked, service.in_flight))
+            .collect(),
+        None => server::load_all()?
+            .into_iter()
+            .map(|state| {
+                let up = server::probe(&format!("http://localhost:{}/v1", state.port));
+                (state, up, None, None)
+            })
+            .collect::<Vec<_>>(),
+    };
+    if states.is_empty() {
+        println!("Nothing is running, start a model with `gaia start`");
+        return Ok(());
+    }
+
+    for (state, up, parked, in_flight) in states {
+        let url = format!("http://localhost:{}/v1", state.port);
+        let health = match (up, parked.as_deref()) {
+            (_, Some("idle")) => style("stopped while idle, starts on the next request").yellow(),
+            (_, Some(_)) => style("stopped as scheduled").yellow(),
+            (true, None) => style("up").green(),
+            (false, None) => style("not responding").red(),
+        };
+        println!(
+            "{} (pid {}) {} {}",
+            style(&state.name).bold(),
+            state.pid,
+            url,
+            health
+        );
+        if let Some(device) = state.device {
+            println!("  {:<9} {}", "device", device);
+        }
+        if let Some(in_flight) = in_flight {
+            println!("  {:<9} {} in flight", "requests", in_flight);
+        }
+        for model in &state.models {
+            println!(
+                "  {:<9} {}  {}",
+                model.kind,
+                model.name,
+                style(&model.path).dim()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn command_stop() -> anyhow::Result<()> {
+    if let Some(result) = daemon::call("stop", json!({}))? {
+        for service in result["stopped"].as_array().into_iter().flatten() {
+            println!(
+                "Stopped {} (pid {})",
+                service["name"].as_str().unwrap_or("?"),
+                service["pid"]
+            );
+        }
+        println!("Stopped the daemon (pid {})", result["pid"]);
+        return Ok(());
+    }
+
+    let states = server::load_all()?;
+    if states.is_empty() {
+        println!("Nothing is running");
+        return Ok(());
+    }
+
+    for state in states {
+        server::stop(&state)?;
+        println!("Stopped {} (pid {})", state.name, state.pid);
+    }
+
+    Ok(())
+}
+
+// Download a model into the models directory without serving it
+pub fn command_pull(model: String) -> anyhow::Result<()> {
+    if !is_remote(&model) && !torrent::is_torrent(&model) {
+        bail!("{} is not an url, nothing to pull", model);
+    }
+    let path = pull(&model)?;
+    println!("Pulled {}", path.display());
+
+    Ok(())
+}
+
+// Download the model into the models directory, returns its path
+pub fn pull(model: &str) -> anyhow::Result<PathBuf> {
+    Ok(PathBuf::from(resolve_model(
+        model,
+        false,
+        &mut Plan::default(),
+    )?))
+}
+
+// Whether the model is fetched rather than read from a local path
+pub fn is_remote(model: &str) -> bool {
+    model.starts_with("http://")
+        || model.starts_with("https://")
+        || blob::is_blob_url(model)
+        || ipfs::is_ipfs_url(model)
+        || oci::is_oci_url(model)
+        || model.starts_with("magnet:")
+}
+
+// Local path of a model given as a path or an url, downloading it if needed
+fn resolve_model(model: &str, dry_run: bool, plan: &mut Plan) -> anyhow::Result<String> {
+    // served from Ollama's copy
+    if ollama::is_ollama_url(model) {
+        let path = ollama::resolve(model)?;
+        signature::check(&path, None)?;
+        return Ok(path.display().to_string());
+    }
+    if is_remote(model) || torrent::is_torrent(model) {
+        // fetched by an earlier start
+        if let Some(path) = models::downloaded(model)? {
+            if !dry_run {
+                signature::check(&path, Some(model))?;
+            }
+            return Ok(path.display().to_string());
+        }
+        if dry_run {
+            // named after the url, the server may still redirect to another name
+            let name = match model {
+                _ if ipfs::is_ipfs_url(model) => ipfs::file_name(model)?,
+                _ if torrent::is_torrent(model) => torrent::file_name(model)?,
+                _ if oci::is_oci_url(model) => oci::file_name(model)?,
+                _ => url_file_name(model)?,
+            };
+            let dest = paths::models_dir()?.join(name).display().to_string();
+            // linked from the Hugging Face cache instead
+            if hf::parse_url(model)
+                .and_then(|file| hf::cached(&file))
+                .is_none()
+            {
+                plan.downloads.push((model.to_string(), dest.clone()));
+            }
+            return Ok(dest);
+        }
+        let acceptance = license::check(model)?;
+        if let Some(path) = hf::reuse(model)? {
+            if let Some(acceptance) = &acceptance {
+                license::record(&path, acceptance)?;
+            }
+            signature::check(&path, Some(model))?;
+            return Ok(path.display().to_string());
+        }
+        let path = if blob::is_blob_url(model) {
+            download_blob(model)
+        } else if ipfs::is_ipfs_url(model) || torrent::is_torrent(model) || oci::is_oci_url(model) {
+            download_content_addressed(model)
+        } else {
+            download_model(model.to_string())
+        }
+        .tag(ErrorKind::Download)?;
+        models::record(Path::new(&path), model)?;
+        events::emit("model-downloaded", json!({ "url": model, "path": path }));
+        if let Some(acceptance) = &acceptance {
+            license::record(Path::new(&path), acceptance)?;
+        }
+        // the same file may already be there under another name
+        models::dedup(Some(Path::new(&path)))?;
+        signature::check(Path::new(&path), Some(model))?;
+
+        return Ok(path);
+    }
+    if !Path::new(model).is_file() {
+        bail!("Model file {} not found", model);
+    }
+    if !dry_run {
+        signature::check(Path::new(model), None)?;
+    }
+
+    Ok(model.to_string())
+}
+
+// The last segment of the url, decoded
+pub fn url_file_name(url: &str) -> anyhow::Result<String> {
+    let name = Url::parse(url)?
+        .path_segments()
+        .and_then(Iterator::last)
+        .filter(|name| !name.is_empty())
+        .ok_or(anyhow!("No filename found in the url to download"))?
+        .to_string();
+
+    Ok(percent_decode_str(&name).decode_utf8()?.to_string())
+}
+
+// Download the model from S3, GCS or Azure into the models directory
+fn download_blob(url: &str) -> anyhow::Result<String> {
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(url_file_name(url)?);
+    blob::download(url, &path)?;
+
+    Ok(path.display().to_string())
+}
+
+// Download the model from IPFS, BitTorrent or an OCI registry into the models directory, the
+// content is checked against the hash it is addressed by
+fn download_content_addressed(source: &str) -> anyhow::Result<String> {
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    let path = match source {
+        _ if ipfs::is_ipfs_url(source) => ipfs::download(source, &dir)?,
+        _ if oci::is_oci_url(source) => oci::download(source, &dir)?,
+        _ => torrent::download(source, &dir)?,
+    };
+
+    Ok(path.display().to_string())
+}
+
+// Download the model from the given url
+fn download_model(url: String) -> anyhow::Result<String> {
+    let _span = tracing::info_span!("download", %url).entered();
+    let url = Url::parse(&url)?;
+    // models are large, do not time out the download
+    let response = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()?
+        .get(url)
+        .send()?
+        .error_for_status()?;
+
+    let fname = response
+        .url()
+        .path_segments()
+        .and_then(std::iter::Iterator::last)
+        .and_then(|name| if name.is_empty() { None } else { Some(name) })
+        .ok_or(anyhow!("No filename found in the url to download"))?;
+    let dir = paths::models_dir()?;
+    fs::create_dir_all(&dir)?;
+    let dest = dir.join(fname);
+    let path = dest.display().to_string();
+    // under its own name only once whole, an interrupted download is not taken for the model
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    tracing::info!(
+        file = path.as_str(),
+        size = response.content_length(),
+        "downloading"
+    );
+    let total = response.content_length();
+    let mut reader =
+        ProgressReader::new(response, Progress::new("download", &path, "bytes", total));
+    let bytes = copy(&mut reader, &mut File::create(&partial)?)?;
+    reader.finish();
+    fs::rename(&partial, &dest)?;
+    tracing::info!(bytes, "downloaded");
+
+    Ok(path)
+}
+
+// Path of a model given by path, or by name in the models directory
+pub fn find_model(model: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(model);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let dir = paths::models_dir()?;
+    for candidate in [dir.join(model), dir.join(format!("{}.gguf", model))] {
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+    }
+
+    bail!(
+        "Model {} not found, give a path or the name of a model in {}",
+        model,
+        dir.display()
+    )
+}
+
+// The models on disk, see `models::local_models`
+fn cached_models() -> anyhow::Result<Vec<String>> {
+    Ok(models::local_models()?
+        .into_iter()
+        .map(|model| model.model)
+        .collect())
+}
+
+// Path to a wasm app, downloading it when missing unless it is a dry run
+fn wasm_app(file_name: &str, url: &str, dry_run: bool, plan: &mut Plan) -> anyhow::Result<PathBuf> {
+    if !dry_run {
+        return server::wasm_app(file_name, url);
+    }
+    let path = server::app_path(file_name)?;
+    if !path.exists() {
+        plan.downloads
+            .push((url.to_string(), path.display().to_string()));
+    }
+
+    Ok(path)
+}
+
+fn print_plan(
+    plan: &Plan,
+    models: &[ServedModel],
+    template: &str,
+    whisper_model: Option<&str>,
+) -> anyhow::Result<()> {
+    println!(
+        "{}",
+        style("Dry run, nothing was downloaded or launched").bold()
+    );
+
+    println!("Models");
+    for model in models {
+        println!("  {:<9} {} ({})", model.kind, model.name, model.path);
+    }
+    if let Some(whisper_model) = whisper_model {
+        println!(
+            "  {:<9} {} ({})",
+            ModelKind::Audio,
+            server::model_name(whisper_model),
+            whisper_model
+        );
+    }
+    println!("  {:<9} {}", "template", template);
+
+    if !plan.downloads.is_empty() {
+        println!("Downloads");
+        for (url, dest) in &plan.downloads {
+            println!("  {} -> {}", url, dest);
+        }
+    }
+
+    println!("Processes");
+    for (name, command, _) in &plan.launches {
+        println!(
+            "  {}, logging to {}",
+            name,
+            server::log_path(name)?.display()
+        );
+        println!(
+            "    {}",
+            command
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+
+    println!("Ports");
+    for (name, _, port) in &plan.launches {
+        println!("  {}:{} {}", plan.host, port, name);
+    }
+
+    Ok(())
+}
+
+// An argument as it would be typed in a shell
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-_./:,=@".contains(c))
+    {
+        return arg.to_string();
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
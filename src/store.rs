@@ -0,0 +1,131 @@
+use crate::embedded::EmbeddedStore;
+use crate::qdrant::{Qdrant, QdrantArgs};
+use anyhow::anyhow;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Point {
+    pub id: u64,
+    pub vector: Vec<f32>,
+    pub payload: Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScoredPoint {
+    pub score: f32,
+    #[serde(default)]
+    pub payload: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorStoreKind {
+    // a Qdrant server
+    Qdrant,
+    // files in the rag directory, searched in process
+    Embedded,
+}
+impl std::fmt::Display for VectorStoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VectorStoreKind::Qdrant => f.pad("qdrant"),
+            VectorStoreKind::Embedded => f.pad("embedded"),
+        }
+    }
+}
+
+// A payload field that must have the value, fields holding a list match any element
+#[derive(Debug, Clone)]
+pub struct Condition {
+    pub key: String,
+    pub value: Value,
+}
+impl Condition {
+    pub fn matches(&self, payload: &Value) -> bool {
+        match &payload[&self.key] {
+            Value::Array(values) => values.contains(&self.value),
+            value => value == &self.value,
+        }
+    }
+}
+
+// Parse a filter given as KEY=VALUE, numbers and booleans match as such
+pub fn parse_condition(s: &str) -> anyhow::Result<Condition> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(anyhow!("Expected KEY=VALUE, got '{}'", s))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(anyhow!("Missing the field name in '{}'", s));
+    }
+    let value = match value {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        value => match value.parse::<i64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::from(value),
+        },
+    };
+
+    Ok(Condition {
+        key: key.to_string(),
+        value,
+    })
+}
+
+// Size of a collection as reported by its store
+pub struct CollectionInfo {
+    pub points: u64,
+    pub dimension: usize,
+    // not every store can tell
+    pub disk_bytes: Option<u64>,
+}
+
+// Where the vectors of the collections live
+pub trait VectorStore {
+    fn collection_exists(&self, name: &str) -> anyhow::Result<bool>;
+
+    // Create an empty collection of vectors compared by cosine similarity
+    fn create_collection(&self, name: &str, dimension: usize) -> anyhow::Result<()>;
+
+    // Insert the points, replacing those with the same ids
+    fn upsert(&self, collection: &str, points: Vec<Point>) -> anyhow::Result<()>;
+
+    // Delete all points whose payload field matches the value
+    fn delete_matching(&self, collection: &str, key: &str, value: &str) -> anyhow::Result<()>;
+
+    // The points most similar to the vector that meet all the conditions, best first
+    fn search(
+        &self,
+        collection: &str,
+        vector: &[f32],
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<ScoredPoint>>;
+
+    fn info(&self, collection: &str) -> anyhow::Result<CollectionInfo>;
+
+    // Payloads of the first points of the collection that meet all the conditions
+    fn scroll(
+        &self,
+        collection: &str,
+        filter: &[Condition],
+        limit: usize,
+    ) -> anyhow::Result<Vec<Value>>;
+
+    // Write a snapshot of the collection to the file, returning its size in bytes
+    fn export_snapshot(&self, collection: &str, dest: &Path) -> anyhow::Result<u64>;
+
+    // Create the collection from a snapshot taken by the same kind of store
+    fn import_snapshot(&self, collection: &str, src: &Path) -> anyhow::Result<()>;
+}
+
+pub fn open(kind: VectorStoreKind, qdrant: &QdrantArgs) -> anyhow::Result<Box<dyn VectorStore>> {
+    Ok(match kind {
+        VectorStoreKind::Qdrant => Box::new(Qdrant::new(qdrant)?),
+        VectorStoreKind::Embedded => Box::new(EmbeddedStore::new()?),
+    })
+}
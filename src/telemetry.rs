@@ -0,0 +1,369 @@
+use crate::identity;
+use anyhow::anyhow;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Debug,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::filter_fn,
+    layer::Context,
+    registry::{LookupSpan, Registry},
+    Layer,
+};
+
+// Standard variable naming the collector when the node file sets none
+const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+// Spans sent together at most, and how long the first of them waits for the others
+const BATCH: usize = 512;
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where finished spans go, set once exporting starts
+static EXPORT: OnceLock<Sender<Value>> = OnceLock::new();
+
+// The collector `gaia daemon` sends the spans of the gateway and of the services it supervises
+// to, over OTLP/HTTP, from the `otlp` section of a node file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    // base url of the collector, e.g. http://localhost:4318, spans are posted to /v1/traces
+    pub endpoint: String,
+    // sent with each export, such as the key of a hosted backend, `$NAME` reading a value from
+    // the environment
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    // service.name of the spans, gaia when not set
+    pub service_name: Option<String>,
+}
+impl OtlpConfig {
+    fn resolved_headers(&self) -> anyhow::Result<Vec<(String, String)>> {
+        self.headers
+            .iter()
+            .map(|(name, value)| match value.strip_prefix('$') {
+                Some(variable) => env::var(variable)
+                    .map(|value| (name.clone(), value))
+                    .map_err(|_| {
+                        anyhow!(
+                            "otlp.headers.{} names ${}, which is not set",
+                            name,
+                            variable
+                        )
+                    }),
+                None => Ok((name.clone(), value.clone())),
+            })
+            .collect()
+    }
+}
+
+// What a span being recorded holds until it closes
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    start: u64,
+    fields: Fields,
+    error: bool,
+}
+
+// The fields of a span as OTLP attributes, besides those telling how to export it
+#[derive(Default)]
+struct Fields {
+    attributes: Vec<Value>,
+    // W3C trace context of the request the span answers, continuing the caller's trace
+    traceparent: Option<String>,
+    kind: Option<String>,
+}
+impl Fields {
+    fn push(&mut self, field: &Field, value: Value) {
+        self.attributes
+            .push(json!({ "key": field.name(), "value": value }));
+    }
+}
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "traceparent" => self.traceparent = Some(value.to_string()),
+            "otel.kind" => self.kind = Some(value.to_string()),
+            _ => self.push(field, json!({ "stringValue": value })),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        // 64-bit integers are strings in OTLP's JSON
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, json!({ "intValue": value.to_string() }));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, json!({ "doubleValue": value }));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, json!({ "boolValue": value }));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+// The layer recording gaia's spans, a no-op until `init` starts exporting
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // the spans of the libraries, reqwest's exporting these among them, are left out
+    Spans.with_filter(filter_fn(|meta| meta.target().starts_with("gaia")))
+}
+
+struct Spans;
+
+impl<S> Layer<S> for Spans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if EXPORT.get().is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+        let (trace_id, parent_id) =
+            match parent.or_else(|| fields.traceparent.as_deref().and_then(parse_traceparent)) {
+                Some((trace_id, parent_id)) => (trace_id, Some(parent_id)),
+                None => (random(), None),
+            };
+        let mut attributes = vec![json!({
+            "key": "code.namespace",
+            "value": { "stringValue": span.metadata().target() },
+        })];
+        attributes.append(&mut fields.attributes);
+        fields.attributes = attributes;
+
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: random(),
+            parent_id,
+            start: now(),
+            fields,
+            error: false,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut data.fields);
+            }
+        }
+    }
+
+    // an error logged within a span fails it
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        if let Some(span) = ctx.event_span(event) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.error = true;
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let (Some(export), Some(span)) = (EXPORT.get(), ctx.span(&id)) else {
+            return;
+        };
+        let Some(data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        let kind = match data.fields.kind.as_deref() {
+            Some("server") => 2,
+            Some("client") => 3,
+            _ => 1,
+        };
+        let mut exported = json!({
+            "traceId": hex(&data.trace_id),
+            "spanId": hex(&data.span_id),
+            "name": span.name(),
+            "kind": kind,
+            "startTimeUnixNano": data.start.to_string(),
+            "endTimeUnixNano": now().to_string(),
+            "attributes": data.fields.attributes,
+        });
+        if let Some(parent_id) = data.parent_id {
+            exported["parentSpanId"] = json!(hex(&parent_id));
+        }
+        if data.error {
+            exported["status"] = json!({ "code": 2 });
+        }
+        let _ = export.send(exported);
+    }
+}
+
+// Start sending the spans to the collector of the node file, or of OTEL_EXPORTER_OTLP_ENDPOINT
+// without one. Nothing is exported when neither is set.
+pub fn init(config: Option<&OtlpConfig>) -> anyhow::Result<()> {
+    let config = match config {
+        Some(config) => config.clone(),
+        None => match env::var(ENDPOINT_ENV) {
+            Ok(endpoint) if !endpoint.is_empty() => OtlpConfig {
+                endpoint,
+                headers: BTreeMap::new(),
+                service_name: None,
+            },
+            _ => return Ok(()),
+        },
+    };
+    let headers = config.resolved_headers()?;
+    let url = format!("{}/v1/traces", config.endpoint.trim_end_matches('/'));
+    let resource = json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": config.service_name.as_deref().unwrap_or("gaia") } },
+            { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+            { "key": "service.instance.id", "value": { "stringValue": identity::name() } },
+        ]
+    });
+
+    let (spans, received) = mpsc::channel();
+    if EXPORT.set(spans).is_err() {
+        return Ok(());
+    }
+    tracing::info!(url, "exporting spans with OTLP");
+    thread::spawn(move || export(&url, &headers, &resource, received));
+
+    Ok(())
+}
+
+// Post the spans as they close, in batches
+fn export(url: &str, headers: &[(String, String)], resource: &Value, spans: Receiver<Value>) {
+    let http = match reqwest::blocking::Client::builder()
+        .timeout(EXPORT_TIMEOUT)
+        .build()
+    {
+        Ok(http) => http,
+        Err(e) => {
+            tracing::warn!("cannot export spans: {}", e);
+            return;
+        }
+    };
+    while let Ok(first) = spans.recv() {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + BATCH_DELAY;
+        while batch.len() < BATCH {
+            match spans.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => batch.push(span),
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let body = json!({
+            "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{
+                    "scope": { "name": "gaia", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": batch,
+                }],
+            }],
+        });
+        let mut request = http.post(url).json(&body);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        // the collector being away loses these spans, not the next ones
+        match request.send() {
+            Ok(response) if !response.status().is_success() => {
+                tracing::warn!(
+                    url,
+                    "the collector refused the spans: {}",
+                    response.status()
+                )
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(url, "cannot export spans: {}", e),
+        }
+    }
+}
+
+// The trace and parent span ids of a `traceparent` header, `00-TRACE-SPAN-FLAGS`
+fn parse_traceparent(header: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = header.trim().split('-');
+    let (_version, trace, span) = (parts.next()?, parts.next()?, parts.next()?);
+    let trace_id = unhex(trace)?;
+    let span_id = unhex(span)?;
+    // all zeros is no id
+    (trace_id != [0; 16] && span_id != [0; 8]).then_some((trace_id, span_id))
+}
+
+fn unhex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    // ids only need to be unlikely to repeat, the time does when randomness is missing
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        for (byte, time) in bytes.iter_mut().zip(now().to_le_bytes().iter().cycle()) {
+            *byte = *time;
+        }
+    }
+    bytes
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64)
+}
+
+// The `traceparent` header continuing the trace of the current span in another node, none when
+// spans are not exported
+pub fn traceparent() -> Option<String> {
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let span = dispatch.downcast_ref::<Registry>()?.span(id)?;
+        let extensions = span.extensions();
+        let data = extensions.get::<SpanData>()?;
+        Some(format!(
+            "00-{}-{}-01",
+            hex(&data.trace_id),
+            hex(&data.span_id)
+        ))
+    })?
+}
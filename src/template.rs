@@ -0,0 +1,975 @@
+use crate::gguf::Gguf;
+use crate::jinja::Template;
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// What `--chat-template` and `chat_template` take for the template in the model's gguf file
+pub const EMBEDDED_TEMPLATE: &str = "gguf";
+
+pub const PROMPT_TEMPLATES: [&str; 22] = [
+    "llama-2-chat",
+    "mistral-instruct",
+    "mistrallite",
+    "openchat",
+    "codellama-instruct",
+    "codellama-super-instruct",
+    "human-assistant",
+    "vicuna-1.0-chat",
+    "vicuna-1.1-chat",
+    "vicuna-llava",
+    "chatml",
+    "baichuan-2",
+    "wizard-coder",
+    "zephyr",
+    "stablelm-zephyr",
+    "intel-neural",
+    "deepseek-chat",
+    "deepseek-coder",
+    "solar-instruct",
+    "phi-2-chat",
+    "phi-2-instruct",
+    "gemma-instruct",
+];
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum PromptTemplateType {
+    Llama2Chat,
+    MistralInstruct,
+    MistralLite,
+    OpenChat,
+    CodeLlama,
+    CodeLlamaSuper,
+    HumanAssistant,
+    VicunaChat,
+    Vicuna11Chat,
+    VicunaLlava,
+    ChatML,
+    Baichuan2,
+    WizardCoder,
+    Zephyr,
+    StableLMZephyr,
+    IntelNeural,
+    DeepseekChat,
+    DeepseekCoder,
+    SolarInstruct,
+    Phi2Chat,
+    Phi2Instruct,
+    GemmaInstruct,
+}
+impl FromStr for PromptTemplateType {
+    type Err = anyhow::Error;
+
+    fn from_str(template: &str) -> std::result::Result<Self, Self::Err> {
+        match template {
+            "llama-2-chat" => Ok(PromptTemplateType::Llama2Chat),
+            "mistral-instruct" => Ok(PromptTemplateType::MistralInstruct),
+            "mistrallite" => Ok(PromptTemplateType::MistralLite),
+            "codellama-instruct" => Ok(PromptTemplateType::CodeLlama),
+            "codellama-super-instruct" => Ok(PromptTemplateType::CodeLlamaSuper),
+            "belle-llama-2-chat" => Ok(PromptTemplateType::HumanAssistant),
+            "human-assistant" => Ok(PromptTemplateType::HumanAssistant),
+            "vicuna-1.0-chat" => Ok(PromptTemplateType::VicunaChat),
+            "vicuna-1.1-chat" => Ok(PromptTemplateType::Vicuna11Chat),
+            "vicuna-llava" => Ok(PromptTemplateType::VicunaLlava),
+            "chatml" => Ok(PromptTemplateType::ChatML),
+            "openchat" => Ok(PromptTemplateType::OpenChat),
+            "baichuan-2" => Ok(PromptTemplateType::Baichuan2),
+            "wizard-coder" => Ok(PromptTemplateType::WizardCoder),
+            "zephyr" => Ok(PromptTemplateType::Zephyr),
+            "stablelm-zephyr" => Ok(PromptTemplateType::StableLMZephyr),
+            "intel-neural" => Ok(PromptTemplateType::IntelNeural),
+            "deepseek-chat" => Ok(PromptTemplateType::DeepseekChat),
+            "deepseek-coder" => Ok(PromptTemplateType::DeepseekCoder),
+            "solar-instruct" => Ok(PromptTemplateType::SolarInstruct),
+            "phi-2-chat" => Ok(PromptTemplateType::Phi2Chat),
+            "phi-2-instruct" => Ok(PromptTemplateType::Phi2Instruct),
+            "gemma-instruct" => Ok(PromptTemplateType::GemmaInstruct),
+            _ => bail!(template.to_string()),
+        }
+    }
+}
+impl std::fmt::Display for PromptTemplateType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PromptTemplateType::Llama2Chat => write!(f, "llama-2-chat"),
+            PromptTemplateType::MistralInstruct => write!(f, "mistral-instruct"),
+            PromptTemplateType::MistralLite => write!(f, "mistrallite"),
+            PromptTemplateType::OpenChat => write!(f, "openchat"),
+            PromptTemplateType::CodeLlama => write!(f, "codellama-instruct"),
+            PromptTemplateType::HumanAssistant => write!(f, "human-assistant"),
+            PromptTemplateType::VicunaChat => write!(f, "vicuna-1.0-chat"),
+            PromptTemplateType::Vicuna11Chat => write!(f, "vicuna-1.1-chat"),
+            PromptTemplateType::VicunaLlava => write!(f, "vicuna-llava"),
+            PromptTemplateType::ChatML => write!(f, "chatml"),
+            PromptTemplateType::Baichuan2 => write!(f, "baichuan-2"),
+            PromptTemplateType::WizardCoder => write!(f, "wizard-coder"),
+            PromptTemplateType::Zephyr => write!(f, "zephyr"),
+            PromptTemplateType::StableLMZephyr => write!(f, "stablelm-zephyr"),
+            PromptTemplateType::IntelNeural => write!(f, "intel-neural"),
+            PromptTemplateType::DeepseekChat => write!(f, "deepseek-chat"),
+            PromptTemplateType::DeepseekCoder => write!(f, "deepseek-coder"),
+            PromptTemplateType::SolarInstruct => write!(f, "solar-instruct"),
+            PromptTemplateType::Phi2Chat => write!(f, "phi-2-chat"),
+            PromptTemplateType::Phi2Instruct => write!(f, "phi-2-instruct"),
+            PromptTemplateType::CodeLlamaSuper => write!(f, "codellama-super-instruct"),
+            PromptTemplateType::GemmaInstruct => write!(f, "gemma-instruct"),
+        }
+    }
+}
+impl PromptTemplateType {
+    // The Go template and stop tokens of an Ollama Modelfile doing what this template does
+    pub fn ollama(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            PromptTemplateType::Llama2Chat => (
+                "[INST] {{ if .System }}<<SYS>>\n{{ .System }}\n<</SYS>>\n\n{{ end }}{{ .Prompt }} [/INST] {{ .Response }} </s>",
+                &["[INST]", "[/INST]", "<<SYS>>", "<</SYS>>"],
+            ),
+            PromptTemplateType::MistralInstruct => (
+                "[INST] {{ if .System }}{{ .System }} {{ end }}{{ .Prompt }} [/INST]{{ .Response }}</s>",
+                &["[INST]", "[/INST]"],
+            ),
+            PromptTemplateType::MistralLite => (
+                "<|prompter|>{{ .Prompt }}</s><|assistant|>{{ .Response }}</s>",
+                &["<|prompter|>", "</s>"],
+            ),
+            PromptTemplateType::OpenChat => (
+                "GPT4 User: {{ .Prompt }}<|end_of_turn|>GPT4 Assistant: {{ .Response }}<|end_of_turn|>",
+                &["<|end_of_turn|>"],
+            ),
+            PromptTemplateType::CodeLlama => (
+                "{{ if .System }}<<SYS>>\n{{ .System }}\n<</SYS>>\n\n{{ end }}[INST] {{ .Prompt }} [/INST] {{ .Response }} </s>",
+                &["[INST]", "[/INST]"],
+            ),
+            PromptTemplateType::CodeLlamaSuper => (
+                "{{ if .System }}<s>Source: system\n\n {{ .System }} <step> {{ end }}Source: user\n\n {{ .Prompt }} <step> Source: assistant\nDestination: user\n\n {{ .Response }} <step> ",
+                &["<step>"],
+            ),
+            PromptTemplateType::HumanAssistant => (
+                "Human: {{ .Prompt }}\n\nAssistant: {{ .Response }}\n\n",
+                &["Human:"],
+            ),
+            PromptTemplateType::VicunaChat => (
+                "{{ if .System }}{{ .System }} {{ end }}USER: {{ .Prompt }} ASSISTANT: {{ .Response }} ",
+                &["USER:"],
+            ),
+            PromptTemplateType::Vicuna11Chat => (
+                "{{ if .System }}{{ .System }} {{ end }}USER: {{ .Prompt }}\nASSISTANT: {{ .Response }}</s>",
+                &["USER:"],
+            ),
+            PromptTemplateType::VicunaLlava => (
+                "{{ if .System }}{{ .System }}\n{{ end }}USER: <image>\n{{ .Prompt }}\nASSISTANT: {{ .Response }}</s>",
+                &["USER:"],
+            ),
+            PromptTemplateType::ChatML => (
+                "{{ if .System }}<|im_start|>system\n{{ .System }}<|im_end|>\n{{ end }}<|im_start|>user\n{{ .Prompt }}<|im_end|>\n<|im_start|>assistant\n{{ .Response }}<|im_end|>\n",
+                &["<|im_start|>", "<|im_end|>"],
+            ),
+            PromptTemplateType::Baichuan2 => (
+                "{{ if .System }}{{ .System }}\n\n{{ end }}用户:{{ .Prompt }}\n\n助手:{{ .Response }}\n\n",
+                &["用户:"],
+            ),
+            PromptTemplateType::WizardCoder => (
+                "{{ if .System }}{{ .System }}\n\n{{ end }}### Instruction:\n{{ .Prompt }}\n\n### Response:\n{{ .Response }}\n\n",
+                &["### Instruction:"],
+            ),
+            PromptTemplateType::Zephyr => (
+                "{{ if .System }}<|system|>\n{{ .System }}</s>\n{{ end }}<|user|>\n{{ .Prompt }}</s>\n<|assistant|>\n{{ .Response }}</s>\n",
+                &["<|system|>", "<|user|>", "<|assistant|>", "</s>"],
+            ),
+            PromptTemplateType::StableLMZephyr => (
+                "<|user|>\n{{ .Prompt }}<|endoftext|>\n<|assistant|>\n{{ .Response }}<|endoftext|>\n",
+                &["<|user|>", "<|endoftext|>"],
+            ),
+            PromptTemplateType::IntelNeural => (
+                "{{ if .System }}### System:\n{{ .System }}\n{{ end }}### User:\n{{ .Prompt }}\n### Assistant:\n{{ .Response }}\n",
+                &["### User:"],
+            ),
+            PromptTemplateType::DeepseekChat => (
+                "{{ if .System }}{{ .System }}\n\n{{ end }}User: {{ .Prompt }}\n\nAssistant: {{ .Response }}<｜end▁of▁sentence｜>",
+                &["User:", "<｜end▁of▁sentence｜>"],
+            ),
+            PromptTemplateType::DeepseekCoder => (
+                "{{ if .System }}{{ .System }}\n{{ end }}### Instruction:\n{{ .Prompt }}\n### Response:\n{{ .Response }}\n<|EOT|>\n",
+                &["### Instruction:", "<|EOT|>"],
+            ),
+            PromptTemplateType::SolarInstruct => (
+                "### User:\n{{ .Prompt }}\n\n### Assistant:\n{{ .Response }}\n\n",
+                &["### User:"],
+            ),
+            PromptTemplateType::Phi2Chat => ("Alice: {{ .Prompt }}\nBob: {{ .Response }}\n", &["Alice:"]),
+            PromptTemplateType::Phi2Instruct => (
+                "Instruct: {{ .Prompt }}\nOutput: {{ .Response }}\n",
+                &["Instruct:"],
+            ),
+            PromptTemplateType::GemmaInstruct => (
+                "<start_of_turn>user\n{{ if .System }}{{ .System }} {{ end }}{{ .Prompt }}<end_of_turn>\n<start_of_turn>model\n{{ .Response }}<end_of_turn>\n",
+                &["<start_of_turn>", "<end_of_turn>"],
+            ),
+        }
+    }
+}
+
+// The conversation every template renders in `gaia templates test`: a system prompt, an
+// exchange and a question for the model to answer
+const SYSTEM: &str = "You are a helpful assistant.";
+const EXCHANGES: &[(&str, Option<&str>)] = &[
+    (
+        "What is the capital of France?",
+        Some("The capital of France is Paris."),
+    ),
+    ("And of Germany?", None),
+];
+
+// The renders `gaia templates test` compares with, one per template
+const FIXTURES: &[(&str, &str)] = &[
+    (
+        "llama-2-chat",
+        include_str!("../fixtures/templates/llama-2-chat.txt"),
+    ),
+    (
+        "mistral-instruct",
+        include_str!("../fixtures/templates/mistral-instruct.txt"),
+    ),
+    (
+        "mistrallite",
+        include_str!("../fixtures/templates/mistrallite.txt"),
+    ),
+    (
+        "openchat",
+        include_str!("../fixtures/templates/openchat.txt"),
+    ),
+    (
+        "codellama-instruct",
+        include_str!("../fixtures/templates/codellama-instruct.txt"),
+    ),
+    (
+        "codellama-super-instruct",
+        include_str!("../fixtures/templates/codellama-super-instruct.txt"),
+    ),
+    (
+        "human-assistant",
+        include_str!("../fixtures/templates/human-assistant.txt"),
+    ),
+    (
+        "vicuna-1.0-chat",
+        include_str!("../fixtures/templates/vicuna-1.0-chat.txt"),
+    ),
+    (
+        "vicuna-1.1-chat",
+        include_str!("../fixtures/templates/vicuna-1.1-chat.txt"),
+    ),
+    (
+        "vicuna-llava",
+        include_str!("../fixtures/templates/vicuna-llava.txt"),
+    ),
+    ("chatml", include_str!("../fixtures/templates/chatml.txt")),
+    (
+        "baichuan-2",
+        include_str!("../fixtures/templates/baichuan-2.txt"),
+    ),
+    (
+        "wizard-coder",
+        include_str!("../fixtures/templates/wizard-coder.txt"),
+    ),
+    ("zephyr", include_str!("../fixtures/templates/zephyr.txt")),
+    (
+        "stablelm-zephyr",
+        include_str!("../fixtures/templates/stablelm-zephyr.txt"),
+    ),
+    (
+        "intel-neural",
+        include_str!("../fixtures/templates/intel-neural.txt"),
+    ),
+    (
+        "deepseek-chat",
+        include_str!("../fixtures/templates/deepseek-chat.txt"),
+    ),
+    (
+        "deepseek-coder",
+        include_str!("../fixtures/templates/deepseek-coder.txt"),
+    ),
+    (
+        "solar-instruct",
+        include_str!("../fixtures/templates/solar-instruct.txt"),
+    ),
+    (
+        "phi-2-chat",
+        include_str!("../fixtures/templates/phi-2-chat.txt"),
+    ),
+    (
+        "phi-2-instruct",
+        include_str!("../fixtures/templates/phi-2-instruct.txt"),
+    ),
+    (
+        "gemma-instruct",
+        include_str!("../fixtures/templates/gemma-instruct.txt"),
+    ),
+];
+
+// The chat templates in the gguf files of the models a template is for, with their bos and eos
+// tokens, the prompts of the Ollama template checked against. Ollama adds the bos token itself.
+const GGUF_TEMPLATES: &[(&str, &str, &str, &str)] = &[
+    (
+        "llama-2-chat",
+        include_str!("../fixtures/chat_templates/llama-2-chat.jinja"),
+        "<s>",
+        "</s>",
+    ),
+    (
+        "mistral-instruct",
+        include_str!("../fixtures/chat_templates/mistral-instruct.jinja"),
+        "<s>",
+        "</s>",
+    ),
+    (
+        "chatml",
+        include_str!("../fixtures/chat_templates/chatml.jinja"),
+        "",
+        "<|im_end|>",
+    ),
+    (
+        "zephyr",
+        include_str!("../fixtures/chat_templates/zephyr.jinja"),
+        "<s>",
+        "</s>",
+    ),
+    (
+        "deepseek-chat",
+        include_str!("../fixtures/chat_templates/deepseek-chat.jinja"),
+        "<｜begin▁of▁sentence｜>",
+        "<｜end▁of▁sentence｜>",
+    ),
+    (
+        "gemma-instruct",
+        include_str!("../fixtures/chat_templates/gemma-instruct.jinja"),
+        "<bos>",
+        "<eos>",
+    ),
+];
+
+impl PromptTemplateType {
+    // The prompt the template makes of a conversation, the way Ollama renders the template of a
+    // Modelfile: once per exchange, the system prompt in the first only, and up to where the
+    // model answers for the last exchange, the one without a reply
+    pub fn render(
+        &self,
+        system: Option<&str>,
+        exchanges: &[(&str, Option<&str>)],
+    ) -> anyhow::Result<String> {
+        let (template, _) = self.ollama();
+        let mut rendered = String::new();
+        for (i, (prompt, response)) in exchanges.iter().enumerate() {
+            let system = system.filter(|_| i == 0);
+            if !render_into(&mut rendered, template, system, prompt, *response)? {
+                break;
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+// Render the subset of Go templates Modelfiles use, `{{ .System }}`, `{{ .Prompt }}`,
+// `{{ .Response }}` and `{{ if .System }}...{{ end }}`. Returns false when it stopped at the
+// reply of the model, not given.
+fn render_into(
+    rendered: &mut String,
+    template: &str,
+    system: Option<&str>,
+    prompt: &str,
+    response: Option<&str>,
+) -> anyhow::Result<bool> {
+    // whether the text is written, in each `if` entered
+    let mut shown = vec![true];
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if shown.iter().all(|shown| *shown) {
+            rendered.push_str(&rest[..start]);
+        }
+        let end = rest[start..]
+            .find("}}")
+            .ok_or(anyhow!("unclosed action in {:?}", template))?;
+        let action = rest[start + 2..start + end].trim();
+        rest = &rest[start + end + 2..];
+
+        let value = |name: &str| match name {
+            ".System" => Ok(system.unwrap_or_default()),
+            ".Prompt" => Ok(prompt),
+            ".Response" => Ok(response.unwrap_or_default()),
+            _ => Err(anyhow!("unknown value {} in {:?}", name, template)),
+        };
+        match action.split_whitespace().collect::<Vec<_>>()[..] {
+            ["if", name] => shown.push(!value(name)?.is_empty()),
+            ["end"] if shown.len() > 1 => {
+                shown.pop();
+            }
+            ["end"] => bail!("`end` without `if` in {:?}", template),
+            [".Response"] if response.is_none() => return Ok(false),
+            [name] if shown.iter().all(|shown| *shown) => rendered.push_str(value(name)?),
+            [_] => {}
+            _ => bail!("unsupported action {{{{ {} }}}} in {:?}", action, template),
+        }
+    }
+    if shown.len() > 1 {
+        bail!("`if` without `end` in {:?}", template);
+    }
+    rendered.push_str(rest);
+
+    Ok(true)
+}
+
+// Render the same conversation with every template and compare with the fixtures, so that a
+// template edited by mistake, its special tokens above all, fails here rather than in a chat,
+// and with the chat template of the gguf files of its models where gaia has it. `update` writes
+// the renders to the fixtures directory instead.
+pub fn command_test(fixtures: Option<PathBuf>, update: bool) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    for template in PromptTemplateType::value_variants() {
+        let name = template.to_string();
+        let result = check(template, &name, fixtures.as_ref(), update);
+        match &result {
+            Ok(()) if update => println!("{} {}", style("wrote").green(), name),
+            Ok(()) => println!("{} {}", style("ok").green(), name),
+            Err(e) => {
+                println!("{} {}", style("FAIL").red().bold(), name);
+                for line in format!("{:#}", e).lines() {
+                    println!("     {}", line);
+                }
+            }
+        }
+        if result.is_err() {
+            failed.push(name);
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} templates failed: {}",
+            failed.len(),
+            PromptTemplateType::value_variants().len(),
+            failed.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn check(
+    template: &PromptTemplateType,
+    name: &str,
+    fixtures: Option<&PathBuf>,
+    update: bool,
+) -> anyhow::Result<()> {
+    // the name is what node files and the api-server are given, it has to read back
+    match <PromptTemplateType as FromStr>::from_str(name) {
+        Ok(parsed) if parsed == *template => {}
+        _ => bail!("'{}' does not read back as the same template", name),
+    }
+    if !PROMPT_TEMPLATES.contains(&name) {
+        bail!(
+            "'{}' is missing from the templates offered to pick from",
+            name
+        );
+    }
+    let rendered = template.render(Some(SYSTEM), EXCHANGES)?;
+
+    let path = fixtures.map(|dir| dir.join(format!("{}.txt", name)));
+    if let (Some(path), true) = (&path, update) {
+        fs::create_dir_all(path.parent().unwrap_or(path))?;
+        return fs::write(path, &rendered).map_err(|e| anyhow!("{}: {}", path.display(), e));
+    }
+    let expected = match &path {
+        Some(path) => fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+        None => FIXTURES
+            .iter()
+            .find(|(fixture, _)| *fixture == name)
+            .map(|(_, expected)| expected.to_string())
+            .ok_or(anyhow!("no fixture, add one with --fixtures DIR --update"))?,
+    };
+    if rendered != expected {
+        bail!(difference(&expected, &rendered, "the fixture"));
+    }
+
+    check_gguf(template, name)
+}
+
+// Render the conversation with the chat template in the gguf files of the models the template is
+// for, through the Jinja renderer the gateway uses, and compare, the bos tokens and the
+// whitespace ending the prompts aside. A chat template refusing the system prompt renders the
+// conversation without it, as the Ollama template then does.
+fn check_gguf(template: &PromptTemplateType, name: &str) -> anyhow::Result<()> {
+    let Some((_, source, bos, eos)) = GGUF_TEMPLATES.iter().find(|(gguf, ..)| *gguf == name) else {
+        return Ok(());
+    };
+    let jinja = Template::parse(source)?;
+    let render = |system: bool| {
+        let mut messages = Vec::new();
+        if system {
+            messages.push(json!({ "role": "system", "content": SYSTEM }));
+        }
+        for (prompt, response) in EXCHANGES {
+            messages.push(json!({ "role": "user", "content": prompt }));
+            if let Some(response) = response {
+                messages.push(json!({ "role": "assistant", "content": response }));
+            }
+        }
+        jinja.render(&json!({
+            "messages": messages,
+            "bos_token": bos,
+            "eos_token": eos,
+            "add_generation_prompt": true,
+        }))
+    };
+    let (expected, system) = match render(true) {
+        Ok(expected) => (expected, Some(SYSTEM)),
+        Err(_) => (render(false)?, None),
+    };
+    let rendered = template.render(system, EXCHANGES)?;
+
+    let normalize = |prompt: &str| match bos.is_empty() {
+        true => prompt.trim_end().to_string(),
+        false => prompt.replace(bos, "").trim_end().to_string(),
+    };
+    let (expected, rendered) = (normalize(&expected), normalize(&rendered));
+    if rendered != expected {
+        bail!(difference(&expected, &rendered, "the gguf chat template"));
+    }
+
+    Ok(())
+}
+
+// The first line of `rendered` that differs from `expected`, with the invisible characters shown
+fn difference(expected: &str, rendered: &str, what: &str) -> String {
+    let (expected_lines, rendered_lines) = (
+        expected.split_inclusive('\n').collect::<Vec<_>>(),
+        rendered.split_inclusive('\n').collect::<Vec<_>>(),
+    );
+    let line = (0..expected_lines.len().max(rendered_lines.len()))
+        .find(|&i| expected_lines.get(i) != rendered_lines.get(i))
+        .unwrap_or_default();
+    format!(
+        "line {} differs from {}\nexpected {:?}\n     got {:?}",
+        line + 1,
+        what,
+        expected_lines.get(line).copied().unwrap_or_default(),
+        rendered_lines.get(line).copied().unwrap_or_default()
+    )
+}
+
+// A Jinja chat template the gateway renders the prompts of a model with, in place of the prompt
+// template of the api-server, so that a model of a family gaia has no template for is prompted
+// as its makers wrote
+#[derive(Debug)]
+pub struct ChatTemplate {
+    template: Template,
+    // `bos_token` and the other special tokens of the model
+    tokens: BTreeMap<String, String>,
+    // whether the backend starts each prompt with the bos token, which the template writes too
+    adds_bos: bool,
+    // the tokens ending the turn of the model, where its completion stops
+    stops: Vec<String>,
+}
+
+// The end-of-turn tokens of the common chat formats, stops when the template writes them
+const END_OF_TURN: &[&str] = &[
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<end_of_turn>",
+    "<|end|>",
+    "<|endoftext|>",
+    "<|end_of_turn|>",
+    "<｜end▁of▁sentence｜>",
+];
+
+// Where the completion of a model prompted with the template text stops: its eos and eot tokens
+// and the end-of-turn tokens the template writes
+fn stops(tokens: &BTreeMap<String, String>, text: &str) -> Vec<String> {
+    let mut stops = Vec::<String>::new();
+    let ends = ["eos_token", "eot_token"]
+        .iter()
+        .filter_map(|name| tokens.get(*name).map(String::as_str))
+        .chain(END_OF_TURN.iter().copied().filter(|end| text.contains(end)));
+    for end in ends {
+        if !end.is_empty() && !stops.iter().any(|stop| stop == end) {
+            stops.push(end.to_string());
+        }
+    }
+    stops
+}
+
+impl ChatTemplate {
+    // The template `source` names for the model, a Jinja file or `gguf` for the one in the model
+    pub fn load(model: &Path, source: &str) -> anyhow::Result<Self> {
+        let gguf = Gguf::read(model)?;
+        let parse = |text: &str, from: &str| {
+            Template::parse(text).map_err(|e| anyhow!("the chat template of {}: {}", from, e))
+        };
+        let text = match source {
+            EMBEDDED_TEMPLATE => gguf
+                .chat_template()
+                .ok_or(anyhow!(
+                    "{} has no chat template, give it one in a Jinja file",
+                    model.display()
+                ))?
+                .to_string(),
+            file => fs::read_to_string(file).map_err(|e| anyhow!("{}: {}", file, e))?,
+        };
+        let template = match source {
+            EMBEDDED_TEMPLATE => parse(&text, &model.display().to_string())?,
+            file => parse(&text, file)?,
+        };
+        // as transformers names them
+        let tokens = gguf
+            .special_tokens
+            .iter()
+            .map(|(name, token)| {
+                let name = match name.as_str() {
+                    "unknown" => "unk",
+                    "padding" => "pad",
+                    "separator" => "sep",
+                    name => name,
+                };
+                (format!("{}_token", name), token.clone())
+            })
+            .collect::<BTreeMap<_, _>>();
+        let adds_bos = gguf.get("tokenizer.ggml.add_bos_token") != Some(&json!(false));
+        let stops = stops(&tokens, &text);
+
+        Ok(Self {
+            template,
+            tokens,
+            adds_bos,
+            stops,
+        })
+    }
+
+    // The prompt of the messages of an OpenAI chat request, up to where the model answers
+    pub fn prompt(&self, messages: &Value) -> anyhow::Result<String> {
+        let messages = messages
+            .as_array()
+            .ok_or(anyhow!("messages must be a list"))?
+            .iter()
+            .map(template_message)
+            .collect::<Vec<_>>();
+        let mut variables = json!({ "messages": messages, "add_generation_prompt": true });
+        for (name, token) in &self.tokens {
+            variables[name] = json!(token);
+        }
+        let prompt = self.template.render(&variables)?;
+
+        // the bos token once, not twice
+        match self.tokens.get("bos_token") {
+            Some(bos) if self.adds_bos && !bos.is_empty() => Ok(prompt
+                .strip_prefix(bos.as_str())
+                .unwrap_or(&prompt)
+                .to_string()),
+            _ => Ok(prompt),
+        }
+    }
+
+    // The prompt of the conversation `gaia templates test` renders
+    pub fn render_sample(&self) -> anyhow::Result<String> {
+        let mut messages = vec![json!({ "role": "system", "content": SYSTEM })];
+        for (prompt, response) in EXCHANGES {
+            messages.push(json!({ "role": "user", "content": prompt }));
+            if let Some(response) = response {
+                messages.push(json!({ "role": "assistant", "content": response }));
+            }
+        }
+        // the templates of models without a system role, such as Gemma's, refuse one
+        self.prompt(&json!(messages))
+            .or_else(|e| self.prompt(&json!(messages[1..])).map_err(|_| e))
+    }
+
+    // The completions request asking the api-server for the answer to an OpenAI chat request,
+    // stopping at the end of the turn of the model. Tools and response formats are refused, the
+    // completion is text the gateway does not parse into calls or check against a schema.
+    pub fn completion_request(&self, chat: &Value) -> anyhow::Result<Value> {
+        if chat["tools"]
+            .as_array()
+            .is_some_and(|tools| !tools.is_empty())
+            || !chat["functions"].is_null()
+        {
+            bail!("tools are not supported for a model prompted with its chat template");
+        }
+        if !matches!(&chat["tool_choice"], Value::Null) && chat["tool_choice"] != "none" {
+            bail!("tool_choice is not supported for a model prompted with its chat template");
+        }
+        if !matches!(&chat["response_format"], Value::Null)
+            && chat["response_format"]["type"] != "text"
+        {
+            bail!("response_format is not supported for a model prompted with its chat template");
+        }
+
+        let prompt = self.prompt(&chat["messages"])?;
+        let mut request = chat.clone();
+        if let Some(fields) = request.as_object_mut() {
+            for field in [
+                "messages",
+                "tools",
+                "tool_choice",
+                "parallel_tool_calls",
+                "response_format",
+            ] {
+                fields.remove(field);
+            }
+        }
+        request["prompt"] = json!(prompt);
+        // those of the request first
+        let mut stops = match &chat["stop"] {
+            Value::String(stop) => vec![stop.clone()],
+            Value::Array(stops) => stops
+                .iter()
+                .filter_map(|stop| stop.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        };
+        for end in &self.stops {
+            if !stops.contains(end) {
+                stops.push(end.clone());
+            }
+        }
+        if !stops.is_empty() {
+            request["stop"] = json!(stops);
+        }
+
+        Ok(request)
+    }
+}
+
+// Print the prompt the chat template of the model, or the one in `file`, makes of the
+// conversation `gaia templates test` renders
+pub fn command_render(model: &Path, file: Option<&Path>) -> anyhow::Result<()> {
+    let source = file.map_or(EMBEDDED_TEMPLATE.to_string(), |file| {
+        file.display().to_string()
+    });
+    let prompt = ChatTemplate::load(model, &source)?.render_sample()?;
+    println!("{}", style(describe_source(&source)).dim());
+    print!("{}", prompt);
+    println!("{}", style("<end of prompt>").dim());
+
+    Ok(())
+}
+
+// Where a chat template comes from, as the preflight summary tells it
+pub fn describe_source(source: &str) -> String {
+    match source {
+        EMBEDDED_TEMPLATE => "jinja, from the model".to_string(),
+        file => format!("jinja, {}", file),
+    }
+}
+
+// A message as chat templates expect it, its content a string when it is only text and the
+// arguments of its tool calls an object rather than JSON text
+fn template_message(message: &Value) -> Value {
+    let mut message = message.clone();
+    if let Some(parts) = message["content"].as_array() {
+        if parts.iter().all(|part| part["type"] == "text") {
+            let text = parts
+                .iter()
+                .filter_map(|part| part["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            message["content"] = json!(text);
+        }
+    }
+    if let Some(calls) = message.get_mut("tool_calls").and_then(Value::as_array_mut) {
+        for call in calls {
+            let arguments = &mut call["function"]["arguments"];
+            if let Some(parsed) = arguments
+                .as_str()
+                .and_then(|text| serde_json::from_str::<Value>(text).ok())
+            {
+                *arguments = parsed;
+            }
+        }
+    }
+    message
+}
+
+// The reply to a chat request of the completion answering it
+pub fn chat_completion(completion: &Value) -> Value {
+    let choices = completion["choices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|choice| {
+            json!({
+                "index": choice["index"],
+                "message": { "role": "assistant", "content": choice["text"] },
+                "finish_reason": choice["finish_reason"],
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "id": completion["id"],
+        "object": "chat.completion",
+        "created": completion["created"],
+        "model": completion["model"],
+        "choices": choices,
+        "usage": completion["usage"],
+    })
+}
+
+// The events of a streamed completion as those of the chat completion they answer, each as it
+// comes
+pub struct ChatEvents {
+    lines: BufReader<Box<dyn Read + Send>>,
+    // what is translated and not read yet
+    pending: Vec<u8>,
+    at: usize,
+    // the first delta of each choice tells the role
+    started: Vec<u64>,
+}
+
+impl ChatEvents {
+    pub fn new(completion: Box<dyn Read + Send>) -> Self {
+        Self {
+            lines: BufReader::new(completion),
+            pending: Vec::new(),
+            at: 0,
+            started: Vec::new(),
+        }
+    }
+
+    fn chunk(&mut self, completion: &Value) -> Value {
+        let choices = completion["choices"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|choice| {
+                let index = choice["index"].as_u64().unwrap_or_default();
+                let mut delta = json!({});
+                if !self.started.contains(&index) {
+                    self.started.push(index);
+                    delta["role"] = json!("assistant");
+                }
+                delta["content"] = choice["text"].clone();
+                json!({
+                    "index": index,
+                    "delta": delta,
+                    "finish_reason": choice["finish_reason"],
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut chunk = json!({
+            "id": completion["id"],
+            "object": "chat.completion.chunk",
+            "created": completion["created"],
+            "model": completion["model"],
+            "choices": choices,
+        });
+        if !completion["usage"].is_null() {
+            chunk["usage"] = completion["usage"].clone();
+        }
+        chunk
+    }
+}
+
+impl Read for ChatEvents {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.at == self.pending.len() {
+            let mut line = String::new();
+            if self.lines.read_line(&mut line)? == 0 {
+                return Ok(0);
+            }
+            let event = line
+                .trim_end()
+                .strip_prefix("data:")
+                .map(str::trim)
+                .filter(|data| *data != "[DONE]")
+                .and_then(|data| serde_json::from_str::<Value>(data).ok());
+            let line = match event {
+                Some(completion) => format!("data: {}\n", self.chunk(&completion)),
+                None => line,
+            };
+            self.pending = line.into_bytes();
+            self.at = 0;
+        }
+        let read = buf.len().min(self.pending.len() - self.at);
+        buf[..read].copy_from_slice(&self.pending[self.at..self.at + read]);
+        self.at += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, token)| (name.to_string(), token.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn stops_at_the_end_of_turn() {
+        let llama_3 = include_str!("../fixtures/chat_templates/llama-3-chat.jinja");
+        assert_eq!(
+            stops(
+                &tokens(&[
+                    ("bos_token", "<|begin_of_text|>"),
+                    ("eos_token", "<|end_of_text|>")
+                ]),
+                llama_3
+            ),
+            ["<|end_of_text|>", "<|eot_id|>"]
+        );
+        let gemma = include_str!("../fixtures/chat_templates/gemma-instruct.jinja");
+        assert_eq!(
+            stops(&tokens(&[("eos_token", "<eos>")]), gemma),
+            ["<eos>", "<end_of_turn>"]
+        );
+        // the eos token of Qwen is its end of turn, stopped at once
+        let chatml = include_str!("../fixtures/chat_templates/chatml.jinja");
+        assert_eq!(
+            stops(&tokens(&[("eos_token", "<|im_end|>")]), chatml),
+            ["<|im_end|>"]
+        );
+    }
+
+    #[test]
+    fn asks_for_the_completion_up_to_the_end_of_turn() {
+        let text = include_str!("../fixtures/chat_templates/gemma-instruct.jinja");
+        let tokens = tokens(&[("bos_token", "<bos>"), ("eos_token", "<eos>")]);
+        let template = ChatTemplate {
+            template: Template::parse(text).unwrap(),
+            stops: stops(&tokens, text),
+            tokens,
+            adds_bos: true,
+        };
+        let request = template
+            .completion_request(&json!({
+                "model": "gemma",
+                "messages": [{ "role": "user", "content": "Hi" }],
+                "stop": "END",
+                "max_tokens": 8,
+            }))
+            .unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "model": "gemma",
+                "stop": ["END", "<eos>", "<end_of_turn>"],
+                "max_tokens": 8,
+                "prompt": "<start_of_turn>user\nHi<end_of_turn>\n<start_of_turn>model\n",
+            })
+        );
+
+        let tools = json!({
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "tools": [{ "type": "function", "function": { "name": "f" } }],
+        });
+        assert!(template.completion_request(&tools).is_err());
+    }
+}
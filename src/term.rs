@@ -0,0 +1,122 @@
+use anyhow::bail;
+use clap::{Args, ValueEnum};
+use dialoguer::{
+    theme::{ColorfulTheme, SimpleTheme, Theme},
+    Confirm, Input, Select,
+};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    // color when writing to a terminal, unless NO_COLOR is set
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TermArgs {
+    #[arg(
+        long = "color",
+        help = "When to color the output",
+        value_name = "WHEN",
+        default_value = "auto",
+        global = true
+    )]
+    pub color: ColorChoice,
+}
+
+pub fn init(args: &TermArgs) {
+    let enabled = match args.color {
+        // console already leaves colors out when piped or when NO_COLOR is set
+        ColorChoice::Auto => return,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+// Whether prompts can be interactive, otherwise they read plain lines from stdin
+pub fn interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+fn theme() -> Box<dyn Theme> {
+    match console::colors_enabled_stderr() {
+        true => Box::new(ColorfulTheme::default()),
+        false => Box::new(SimpleTheme),
+    }
+}
+
+// A line read after printing the prompt, None at the end of the input
+fn read_line(prompt: &str) -> anyhow::Result<Option<String>> {
+    eprint!("{}: ", prompt);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    if io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+// A line of text from the user, None when the input has ended
+pub fn input(prompt: &str, allow_empty: bool) -> anyhow::Result<Option<String>> {
+    if !interactive() {
+        return read_line(prompt);
+    }
+
+    Ok(Some(
+        Input::<String>::with_theme(theme().as_ref())
+            .with_prompt(prompt)
+            .allow_empty(allow_empty)
+            .interact_text()?,
+    ))
+}
+
+// Index of the item the user picks, None when they pick nothing
+pub fn select(prompt: &str, items: &[String], default: usize) -> anyhow::Result<Option<usize>> {
+    if !interactive() {
+        for (i, item) in items.iter().enumerate() {
+            eprintln!("{:>3}) {}", i + 1, item);
+        }
+        let Some(line) = read_line(&format!("{} [{}]", prompt, default + 1))? else {
+            return Ok(None);
+        };
+        if line.trim().is_empty() {
+            return Ok(Some(default));
+        }
+        return match line.trim().parse::<usize>() {
+            Ok(n) if (1..=items.len()).contains(&n) => Ok(Some(n - 1)),
+            _ => bail!(
+                "Expected a number between 1 and {}, got '{}'",
+                items.len(),
+                line
+            ),
+        };
+    }
+
+    Ok(Select::with_theme(theme().as_ref())
+        .with_prompt(prompt)
+        .default(default)
+        .items(items)
+        .interact_opt()?)
+}
+
+pub fn confirm(prompt: &str, default: bool) -> anyhow::Result<bool> {
+    if !interactive() {
+        let hint = if default { "Y/n" } else { "y/N" };
+        let answer = read_line(&format!("{} [{}]", prompt, hint))?.unwrap_or_default();
+        return Ok(match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => true,
+            "n" | "no" => false,
+            _ => default,
+        });
+    }
+
+    Ok(Confirm::with_theme(theme().as_ref())
+        .with_prompt(prompt)
+        .default(default)
+        .interact()?)
+}
@@ -0,0 +1,171 @@
+use crate::client::Throughput;
+use crate::device;
+use crate::paths;
+use crate::server;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Totals of the replies of a `run` or `chat` session
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Session {
+    pub time: u64,
+    pub command: String,
+    pub model: Option<String>,
+    // WasmEdge version of the api-server, to tell regressions after an upgrade
+    pub runtime: Option<String>,
+    pub replies: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // spent generating after the first token, what tokens per second are counted over
+    pub generation_secs: f64,
+    // summed over the streamed replies, the others have no first token to time
+    pub first_token_secs: f64,
+    pub streamed: u64,
+}
+impl Session {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, throughput: &Throughput) {
+        self.model = throughput.model.clone().or(self.model.take());
+        self.replies += 1;
+        self.prompt_tokens += throughput.prompt_tokens.unwrap_or_default();
+        self.completion_tokens += throughput.completion_tokens;
+        let first_token = throughput.first_token.unwrap_or_default();
+        self.generation_secs += throughput.elapsed.saturating_sub(first_token).as_secs_f64();
+        if let Some(first_token) = throughput.first_token {
+            self.first_token_secs += first_token.as_secs_f64();
+            self.streamed += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Session) {
+        self.replies += other.replies;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.generation_secs += other.generation_secs;
+        self.first_token_secs += other.first_token_secs;
+        self.streamed += other.streamed;
+    }
+
+    pub fn tokens_per_sec(&self) -> Option<f64> {
+        (self.generation_secs > 0.0).then(|| self.completion_tokens as f64 / self.generation_secs)
+    }
+
+    // Mean time to the first token
+    fn first_token(&self) -> Option<f64> {
+        (self.streamed > 0).then(|| self.first_token_secs / self.streamed as f64)
+    }
+
+    // e.g. 12 prompt + 85 completion tokens, 23.4 tokens/s, first token after 0.41s
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} prompt + {} completion tokens",
+            self.prompt_tokens, self.completion_tokens
+        );
+        if let Some(rate) = self.tokens_per_sec() {
+            summary.push_str(&format!(", {:.1} tokens/s", rate));
+        }
+        if let Some(first_token) = self.first_token() {
+            summary.push_str(&format!(", first token after {:.2}s", first_token));
+        }
+        summary
+    }
+
+    // Print the totals and keep them for `gaia stats`, a session without replies is not worth it
+    pub fn finish(mut self) {
+        if self.replies == 0 {
+            return;
+        }
+        eprintln!("{}", style(self.summary()).dim());
+
+        self.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or_default();
+        self.runtime = runtime();
+        if let Err(e) = record(&self) {
+            tracing::warn!("cannot record the throughput of the session: {}", e);
+        }
+    }
+}
+
+// The sessions, one JSON object per line
+fn log_path() -> anyhow::Result<PathBuf> {
+    Ok(paths::log_dir()?.join("throughput.jsonl"))
+}
+
+// Version of the wasmedge the local api-server runs on
+pub fn runtime() -> Option<String> {
+    let state = server::load(server::API_SERVER).ok()??;
+    let wasmedge = state.command.first()?;
+    device::wasmedge_version(Path::new(wasmedge)).ok()
+}
+
+fn record(session: &Session) -> anyhow::Result<()> {
+    fs::create_dir_all(paths::log_dir()?)?;
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path()?)?;
+    log.write_all(format!("{}\n", serde_json::to_string(session)?).as_bytes())?;
+
+    Ok(())
+}
+
+// The sessions recorded since the time, in seconds since the epoch
+pub fn since(time: u64) -> anyhow::Result<Vec<Session>> {
+    let content = fs::read_to_string(log_path()?).unwrap_or_default();
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Session>(line).ok())
+        .filter(|session| session.time >= time)
+        .collect())
+}
+
+// Print the recorded sessions summed up by model and runtime
+pub fn print_totals() -> anyhow::Result<()> {
+    let content = fs::read_to_string(log_path()?).unwrap_or_default();
+    let mut totals = BTreeMap::<(String, String), (u64, Session)>::new();
+    for session in content
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Session>(line).ok())
+    {
+        let key = (
+            session.model.clone().unwrap_or("?".to_string()),
+            session.runtime.clone().unwrap_or("?".to_string()),
+        );
+        let (sessions, total) = totals.entry(key).or_default();
+        *sessions += 1;
+        total.merge(&session);
+    }
+    if totals.is_empty() {
+        println!("No run or chat session recorded yet");
+        return Ok(());
+    }
+
+    println!("run and chat sessions");
+    for ((model, runtime), (sessions, total)) in totals {
+        println!(
+            "  {} {}  {} sessions, {} replies, {}",
+            model,
+            style(format!("(wasmedge {})", runtime)).dim(),
+            sessions,
+            total.replies,
+            total.summary()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,239 @@
+use crate::client::ToolCall;
+use crate::prompt;
+use crate::term;
+use anyhow::{anyhow, bail};
+use clap::Args;
+use console::style;
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::PathBuf, process};
+
+// Tool output beyond this is cut off before being sent back to the model
+const MAX_OUTPUT_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct ToolArgs {
+    #[arg(
+        long = "tools",
+        help = "JSON file with OpenAI-style tool definitions",
+        value_name = "FILE"
+    )]
+    pub tools: Option<PathBuf>,
+    #[arg(
+        long = "allow-tool",
+        help = "Run the local command of this tool without asking, can be repeated, arguments starting with - are refused",
+        value_name = "NAME",
+        requires = "tools"
+    )]
+    pub allowed: Vec<String>,
+}
+
+// A tool definition, optionally bound to a local command.
+//
+// The tools file is an array of OpenAI tool definitions, each of which may carry an extra
+// `command` key with the argv to execute, e.g. `["git", "log", "-n", "{{count}}"]`, where
+// the placeholders are filled from the arguments of the tool call. Arguments starting with `-`
+// are refused, so that the model cannot pass options of its own to the command.
+#[derive(Debug, Clone)]
+pub struct Tool {
+    pub name: String,
+    definition: Value,
+    command: Option<Vec<String>>,
+}
+
+pub struct Tools {
+    tools: Vec<Tool>,
+    allowed: Vec<String>,
+}
+impl Tools {
+    pub fn load(args: &ToolArgs) -> anyhow::Result<Option<Self>> {
+        let path = match &args.tools {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let content = fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let entries: Vec<Value> = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("{}: expected an array of tools: {}", path.display(), e))?;
+
+        let mut tools = Vec::new();
+        for mut entry in entries {
+            let command = match entry.as_object_mut().and_then(|o| o.remove("command")) {
+                Some(command) => Some(
+                    serde_json::from_value::<Vec<String>>(command)
+                        .map_err(|e| anyhow!("{}: invalid command: {}", path.display(), e))?,
+                ),
+                None => None,
+            };
+            let name = entry
+                .pointer("/function/name")
+                .and_then(Value::as_str)
+                .ok_or(anyhow!("{}: tool without a function name", path.display()))?
+                .to_string();
+            if command.as_ref().is_some_and(|command| command.is_empty()) {
+                bail!(
+                    "{}: the command of tool '{}' is empty",
+                    path.display(),
+                    name
+                );
+            }
+
+            tools.push(Tool {
+                name,
+                definition: entry,
+                command,
+            });
+        }
+
+        for name in &args.allowed {
+            if !tools.iter().any(|tool| &tool.name == name) {
+                bail!("--allow-tool {}: no such tool in {}", name, path.display());
+            }
+        }
+
+        Ok(Some(Self {
+            tools,
+            allowed: args.allowed.clone(),
+        }))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.iter().map(|tool| tool.name.as_str()).collect()
+    }
+
+    // Definitions to pass to the server, without the local commands
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|tool| tool.definition.clone())
+            .collect()
+    }
+
+    // Handle a tool call of the model, returning the result to feed back into the conversation
+    pub fn call(&self, call: &ToolCall) -> anyhow::Result<String> {
+        println!(
+            "{} {}({})",
+            style("Tool call:").magenta().bold(),
+            call.function.name,
+            call.function.arguments
+        );
+
+        let tool = match self
+            .tools
+            .iter()
+            .find(|tool| tool.name == call.function.name)
+        {
+            Some(tool) => tool,
+            None => return Ok(format!("Error: unknown tool '{}'", call.function.name)),
+        };
+        let command = match &tool.command {
+            Some(command) => command,
+            None => {
+                // no local command, let the user answer on behalf of the tool
+                let result =
+                    term::input(&format!("Result of {}", tool.name), true)?.unwrap_or_default();
+                return Ok(if result.is_empty() {
+                    "The user declined to run the tool".to_string()
+                } else {
+                    result
+                });
+            }
+        };
+
+        // a malformed call is answered with what is wrong with it, for the model to call again
+        let arguments = match parse_arguments(&call.function.arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => return Ok(format!("Error: {}", e)),
+        };
+        let mut missing = Vec::new();
+        for name in command.iter().flat_map(|arg| prompt::placeholders(arg)) {
+            if !arguments.contains_key(&name) && !missing.contains(&name) {
+                missing.push(name);
+            }
+        }
+        if !missing.is_empty() {
+            return Ok(format!(
+                "Error: missing arguments {}",
+                missing
+                    .iter()
+                    .map(|name| format!("'{}'", name))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        let options = command
+            .iter()
+            .flat_map(|arg| prompt::placeholders(arg))
+            .filter(|name| arguments[name].starts_with('-'))
+            .collect::<Vec<_>>();
+        if let Some(name) = options.first() {
+            return Ok(format!(
+                "Error: argument '{}' starts with '-', which the command would take for an option",
+                name
+            ));
+        }
+        let argv = match command
+            .iter()
+            .map(|arg| prompt::render(arg, &arguments))
+            .collect::<anyhow::Result<Vec<String>>>()
+        {
+            Ok(argv) => argv,
+            Err(e) => return Ok(format!("Error: {}", e)),
+        };
+
+        if !self.allowed.contains(&tool.name)
+            && !term::confirm(&format!("Run `{}`?", argv.join(" ")), false)?
+        {
+            return Ok("The user declined to run the tool".to_string());
+        }
+
+        Ok(run(&argv))
+    }
+}
+
+fn parse_arguments(arguments: &str) -> anyhow::Result<HashMap<String, String>> {
+    if arguments.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let value: Value = serde_json::from_str(arguments)
+        .map_err(|e| anyhow!("Invalid tool call arguments '{}': {}", arguments, e))?;
+    let object = value.as_object().ok_or(anyhow!(
+        "Tool call arguments must be an object: {}",
+        arguments
+    ))?;
+
+    Ok(object
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (name.clone(), value)
+        })
+        .collect())
+}
+
+fn run(argv: &[String]) -> String {
+    let output = match process::Command::new(&argv[0]).args(&argv[1..]).output() {
+        Ok(output) => output,
+        Err(e) => return format!("Error: failed to run {}: {}", argv[0], e),
+    };
+
+    let mut result = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        result.push_str(&format!("\n[stderr]\n{}", stderr));
+    }
+    if !output.status.success() {
+        result.push_str(&format!("\n[{}]", output.status));
+    }
+    if result.len() > MAX_OUTPUT_BYTES {
+        let mut end = MAX_OUTPUT_BYTES;
+        while !result.is_char_boundary(end) {
+            end -= 1;
+        }
+        result.truncate(end);
+        result.push_str("\n[output truncated]");
+    }
+
+    result
+}
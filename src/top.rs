@@ -0,0 +1,364 @@
+use crate::daemon::{self, ServiceStatus, Stats};
+use crate::keys::{self, Action, TuiKeys};
+use crate::memory::{self, GIB};
+use crate::server::{self, ServiceState};
+use crate::throughput;
+use anyhow::bail;
+use ratatui::{
+    crossterm::event::{self, Event, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, IsTerminal},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Sessions that ended this long ago at most count into the tokens per second of a service
+const RATE_WINDOW_SECS: u64 = 60;
+// Bytes read from the end of the log of the selected service
+const LOG_BYTES: u64 = 256 * 1024;
+// Clock ticks per second the CPU times of /proc are counted in, the same on every Linux
+const CLOCK_TICKS: f64 = 100.0;
+
+// A service as the dashboard shows it
+struct Service {
+    status: ServiceStatus,
+    // percent of one core since the last refresh
+    cpu: Option<f64>,
+    rss: Option<u64>,
+    vram: Option<u64>,
+    in_flight: Option<u64>,
+    tokens_per_sec: Option<f64>,
+}
+
+pub struct Top {
+    services: Vec<Service>,
+    daemon: Option<Stats>,
+    gpu: Option<(&'static str, u64)>,
+    available: Option<u64>,
+    // CPU ticks of each process at the last refresh
+    ticks: HashMap<u32, (u64, Instant)>,
+    table: TableState,
+    // lines scrolled back from the end of the log
+    scroll: u16,
+}
+impl Top {
+    pub fn new() -> Self {
+        Self {
+            services: Vec::new(),
+            daemon: None,
+            gpu: None,
+            available: None,
+            ticks: HashMap::new(),
+            table: TableState::default(),
+            scroll: 0,
+        }
+    }
+
+    pub fn refresh(&mut self) -> anyhow::Result<()> {
+        let daemon = daemon::call("stats", json!({}))?
+            .map(serde_json::from_value::<Stats>)
+            .transpose()?;
+        let statuses = match daemon::status()? {
+            Some(statuses) => statuses,
+            None => server::load_all()?
+                .into_iter()
+                .map(|state| ServiceStatus {
+                    up: server::is_running(state.pid),
+                    state,
+                    parked: None,
+                    in_flight: None,
+                })
+                .collect(),
+        };
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            .saturating_sub(RATE_WINDOW_SECS);
+        let sessions = throughput::since(since)?;
+        let vram = memory::vram_by_pid();
+
+        let mut ticks = HashMap::new();
+        self.services = statuses
+            .into_iter()
+            .map(|status| {
+                let pid = status.state.pid;
+                let cpu = match (status.up, cpu_ticks(pid)) {
+                    (true, Some(now)) => {
+                        let cpu = self.ticks.get(&pid).map(|(before, at)| {
+                            let secs = at.elapsed().as_secs_f64().max(0.001);
+                            now.saturating_sub(*before) as f64 / CLOCK_TICKS / secs * 100.0
+                        });
+                        ticks.insert(pid, (now, Instant::now()));
+                        cpu
+                    }
+                    _ => None,
+                };
+                let mut served = throughput::Session::default();
+                for session in &sessions {
+                    let model = session.model.as_deref().unwrap_or_default();
+                    if status.state.models.iter().any(|m| m.name == model) {
+                        served.merge(session);
+                    }
+                }
+                let in_flight = daemon.as_ref().and_then(|daemon| {
+                    daemon
+                        .services
+                        .iter()
+                        .find(|service| service.name == status.state.name)
+                        .and_then(|service| service.in_flight)
+                });
+                Service {
+                    cpu,
+                    rss: status.up.then(|| daemon::rss_bytes(pid)).flatten(),
+                    vram: vram.get(&pid).copied(),
+                    in_flight,
+                    tokens_per_sec: served.tokens_per_sec(),
+                    status,
+                }
+            })
+            .collect();
+        self.ticks = ticks;
+        self.daemon = daemon;
+        self.gpu = memory::gpu();
+        self.available = memory::available();
+        if self
+            .table
+            .selected()
+            .is_none_or(|i| i >= self.services.len())
+        {
+            self.table.select((!self.services.is_empty()).then_some(0));
+        }
+
+        Ok(())
+    }
+
+    // The service the log pane shows
+    pub fn selected(&self) -> Option<&ServiceState> {
+        self.table
+            .selected()
+            .and_then(|i| self.services.get(i))
+            .map(|service| &service.status.state)
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame, area: Rect) {
+        let [header, table, log] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(self.services.len().max(1) as u16 + 3),
+            Constraint::Min(3),
+        ])
+        .areas(area);
+
+        frame.render_widget(Paragraph::new(self.header()), header);
+
+        let rows = self.services.iter().map(|service| {
+            let state = &service.status.state;
+            let (status, color) = match (&service.status.parked, service.status.up) {
+                (Some(_), _) => ("parked", Color::Yellow),
+                (None, true) => ("up", Color::Green),
+                (None, false) => ("down", Color::Red),
+            };
+            let models = state
+                .models
+                .iter()
+                .map(|model| model.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            Row::new(vec![
+                Cell::from(state.name.clone()),
+                Cell::from(status).style(Style::new().fg(color)),
+                Cell::from(state.pid.to_string()),
+                Cell::from(state.port.to_string()),
+                Cell::from(or_dash(service.cpu.map(|cpu| format!("{:.0}%", cpu)))),
+                Cell::from(or_dash(service.rss.map(size))),
+                Cell::from(or_dash(service.vram.map(size))),
+                Cell::from(or_dash(service.in_flight.map(|n| n.to_string()))),
+                Cell::from(or_dash(
+                    service.tokens_per_sec.map(|rate| format!("{:.1}", rate)),
+                )),
+                Cell::from(models),
+            ])
+        });
+        let widths = [
+            Constraint::Length(16),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(6),
+            Constraint::Length(6),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(7),
+            Constraint::Fill(1),
+        ];
+        let table_widget = Table::new(rows, widths)
+            .header(
+                Row::new([
+                    "SERVICE",
+                    "STATUS",
+                    "PID",
+                    "PORT",
+                    "CPU",
+                    "RAM",
+                    "VRAM",
+                    "IN FLIGHT",
+                    "TOK/S",
+                    "MODELS",
+                ])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+            )
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::new().borders(Borders::TOP).title(" services "));
+        frame.render_stateful_widget(table_widget, table, &mut self.table);
+
+        let (title, content) = match self.selected() {
+            Some(state) => (
+                format!(" {} ", state.log.display()),
+                server::log_tail(&state.log, LOG_BYTES),
+            ),
+            None => (" log ".to_string(), String::new()),
+        };
+        let lines = content.lines().collect::<Vec<_>>();
+        let height = log.height.saturating_sub(1) as usize;
+        self.scroll = self.scroll.min(lines.len().saturating_sub(height) as u16);
+        let end = lines.len() - self.scroll as usize;
+        let shown = lines[end.saturating_sub(height)..end]
+            .iter()
+            .map(|line| Line::raw(line.to_string()))
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(shown).block(Block::new().borders(Borders::TOP).title(title)),
+            log,
+        );
+    }
+
+    // e.g. my-node daemon pid 4242 up 3600s, 128 requests  memory 12.3 GiB available  CUDA 7.8 GiB free
+    fn header(&self) -> Line<'static> {
+        let mut spans = vec![match &self.daemon {
+            Some(daemon) => Span::raw(format!(
+                "{} daemon pid {} up {}s, {} requests",
+                daemon.node, daemon.pid, daemon.uptime_secs, daemon.requests
+            )),
+            None => Span::raw("no daemon, requests in flight are unknown").dim(),
+        }];
+        if let Some(available) = self.available {
+            spans.push(Span::raw(format!("  memory {} available", size(available))));
+        }
+        if let Some((backend, free)) = self.gpu {
+            spans.push(Span::raw(format!("  {} {} free", backend, size(free))));
+        }
+        Line::from(spans)
+    }
+
+    // Select a service with the arrows, scroll its log with the page keys
+    pub fn act(&mut self, action: Action) {
+        let selected = self.table.selected().unwrap_or(0);
+        match action {
+            Action::Up => {
+                self.table.select(Some(selected.saturating_sub(1)));
+                self.scroll = 0;
+            }
+            Action::Down => {
+                let last = self.services.len().saturating_sub(1);
+                self.table.select(Some((selected + 1).min(last)));
+                self.scroll = 0;
+            }
+            Action::ScrollUp => self.scroll = self.scroll.saturating_add(10),
+            Action::ScrollDown => self.scroll = self.scroll.saturating_sub(10),
+            Action::Follow => self.scroll = 0,
+            Action::Quit | Action::NextTab => {}
+        }
+    }
+}
+
+// CPU time the process has spent, user and system, in clock ticks from /proc on Linux
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the name in parentheses may hold spaces, the fields after it start with the state
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+
+    Some(utime + stime)
+}
+
+// e.g. 512 MiB or 7.8 GiB
+fn size(bytes: u64) -> String {
+    match bytes as f64 {
+        bytes if bytes < GIB => format!("{:.0} MiB", bytes / (1024.0 * 1024.0)),
+        bytes => format!("{:.1} GiB", bytes / GIB),
+    }
+}
+
+fn or_dash(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "-".to_string())
+}
+
+// Show the services of the node live until q is pressed, refreshing every `interval`
+pub fn command_top(interval: Duration) -> anyhow::Result<()> {
+    if !io::stdout().is_terminal() {
+        bail!("gaia top needs a terminal, use `gaia stats` for a snapshot");
+    }
+    let mut top = Top::new();
+    top.refresh()?;
+
+    let keys = keys::load()?.tui;
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut top, &keys, interval);
+    ratatui::restore();
+    result
+}
+
+fn run(
+    terminal: &mut DefaultTerminal,
+    top: &mut Top,
+    keys: &TuiKeys,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let help = format!(
+        "{} quit  {}/{} select a service  {}/{} scroll its log  {} follow",
+        keys::first(&keys.quit),
+        keys::first(&keys.up),
+        keys::first(&keys.down),
+        keys::first(&keys.scroll_up),
+        keys::first(&keys.scroll_down),
+        keys::first(&keys.follow)
+    );
+    let mut refreshed = Instant::now();
+    loop {
+        terminal.draw(|frame| {
+            let [main, footer] =
+                Layout::vertical([Constraint::Min(5), Constraint::Length(1)]).areas(frame.area());
+            top.draw(frame, main);
+            frame.render_widget(Paragraph::new(help.as_str()).dim(), footer);
+        })?;
+        let timeout = interval.saturating_sub(refreshed.elapsed());
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match keys.action(&key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(action) => top.act(action),
+                    None => {}
+                }
+            }
+        }
+        if refreshed.elapsed() >= interval {
+            top.refresh()?;
+            refreshed = Instant::now();
+        }
+    }
+}
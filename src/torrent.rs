@@ -0,0 +1,1336 @@
+use crate::ipfs::hex_decode;
+use crate::paths;
+use crate::progress::Progress;
+use anyhow::{anyhow, bail};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use reqwest::{blocking::Client, Url};
+use ring::{
+    digest::{digest, SHA1_FOR_LEGACY_USE_ONLY},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+pub const DEFAULT_SEED_PORT: u16 = 6881;
+
+// Pieces are asked for in blocks of this size, the size every client serves
+const BLOCK_SIZE: u64 = 16 * 1024;
+// requests sent to a peer before waiting for its answers
+const PIPELINE: usize = 16;
+// peers downloaded from at once
+const MAX_PEERS: usize = 8;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+// metadata of a magnet link, larger would not be a model
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// id peers use for the metadata extension in their messages to us
+const UT_METADATA: u8 = 1;
+
+// Message ids of the peer wire protocol
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const EXTENDED: u8 = 20;
+
+pub fn is_torrent(s: &str) -> bool {
+    s.starts_with("magnet:") || s.ends_with(".torrent")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+impl Bencode {
+    fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(decode_at(data, 0)?.0)
+    }
+
+    fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    fn str(&self) -> Option<&str> {
+        self.bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    fn list(&self) -> &[Bencode] {
+        match self {
+            Bencode::List(items) => items,
+            _ => &[],
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(n) => out.extend(format!("i{}e", n).bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend(format!("{}:", bytes.len()).bytes());
+                out.extend(bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode(out));
+                out.push(b'e');
+            }
+            Bencode::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    Bencode::Bytes(key.clone()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn dict<const N: usize>(entries: [(&str, Bencode); N]) -> Bencode {
+    Bencode::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+// Lists and dictionaries nested deeper are refused, peers could exhaust the stack otherwise
+const MAX_DEPTH: usize = 64;
+
+// The value starting at `pos` and the position after it
+fn decode_at(data: &[u8], pos: usize) -> anyhow::Result<(Bencode, usize)> {
+    decode_nested(data, pos, 0)
+}
+
+fn decode_nested(data: &[u8], pos: usize, depth: usize) -> anyhow::Result<(Bencode, usize)> {
+    if depth > MAX_DEPTH {
+        bail!("Invalid bencode, nested more than {} deep", MAX_DEPTH);
+    }
+    let truncated = || anyhow!("Invalid bencode, truncated at byte {}", pos);
+    match data.get(pos).ok_or_else(truncated)? {
+        b'i' => {
+            let end = find(data, pos, b'e').ok_or_else(truncated)?;
+            let n = std::str::from_utf8(&data[pos + 1..end])?.parse()?;
+            Ok((Bencode::Int(n), end + 1))
+        }
+        b'l' => {
+            let (mut items, mut pos) = (Vec::new(), pos + 1);
+            while *data.get(pos).ok_or_else(truncated)? != b'e' {
+                let (item, next) = decode_nested(data, pos, depth + 1)?;
+                items.push(item);
+                pos = next;
+            }
+            Ok((Bencode::List(items), pos + 1))
+        }
+        b'd' => {
+            let (mut entries, mut pos) = (BTreeMap::new(), pos + 1);
+            while *data.get(pos).ok_or_else(truncated)? != b'e' {
+                let (key, next) = decode_nested(data, pos, depth + 1)?;
+                let key = key
+                    .bytes()
+                    .ok_or(anyhow!("Invalid bencode, a key is not a string"))?;
+                let (value, next) = decode_nested(data, next, depth + 1)?;
+                entries.insert(key.to_vec(), value);
+                pos = next;
+            }
+            Ok((Bencode::Dict(entries), pos + 1))
+        }
+        b'0'..=b'9' => {
+            let colon = find(data, pos, b':').ok_or_else(truncated)?;
+            let len: usize = std::str::from_utf8(&data[pos..colon])?.parse()?;
+            let end = (colon + 1).checked_add(len).ok_or_else(truncated)?;
+            let bytes = data.get(colon + 1..end).ok_or_else(truncated)?;
+            Ok((Bencode::Bytes(bytes.to_vec()), end))
+        }
+        c => bail!(
+            "Invalid bencode, unexpected '{}' at byte {}",
+            *c as char,
+            pos
+        ),
+    }
+}
+
+fn find(data: &[u8], from: usize, byte: u8) -> Option<usize> {
+    data[from..]
+        .iter()
+        .position(|&b| b == byte)
+        .map(|i| from + i)
+}
+
+// The info dictionary of a torrent file as it was written, the info hash is taken over these bytes
+fn raw_info(torrent: &[u8]) -> anyhow::Result<&[u8]> {
+    if torrent.first() != Some(&b'd') {
+        bail!("Invalid torrent file");
+    }
+    let mut pos = 1;
+    while torrent.get(pos).is_some_and(|&b| b != b'e') {
+        let (key, start) = decode_at(torrent, pos)?;
+        let (_, end) = decode_at(torrent, start)?;
+        if key.bytes() == Some(b"info") {
+            return Ok(&torrent[start..end]);
+        }
+        pos = end;
+    }
+
+    bail!("The torrent file has no info section")
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hash = [0; 20];
+    hash.copy_from_slice(digest(&SHA1_FOR_LEGACY_USE_ONLY, data).as_ref());
+    hash
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// What a torrent holds: the files, and the hash of every piece of them laid end to end
+#[derive(Debug, Clone)]
+struct Metainfo {
+    info_hash: [u8; 20],
+    // the info dictionary, as hashed
+    info: Vec<u8>,
+    name: String,
+    piece_length: u64,
+    pieces: Vec<[u8; 20]>,
+    // paths relative to the torrent, with their lengths
+    files: Vec<(PathBuf, u64)>,
+    trackers: Vec<String>,
+}
+impl Metainfo {
+    fn parse(torrent: &[u8]) -> anyhow::Result<Self> {
+        let value = Bencode::decode(torrent)?;
+        let mut trackers = value
+            .get("announce-list")
+            .map(Bencode::list)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(Bencode::list)
+            .filter_map(Bencode::str)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if let Some(announce) = value.get("announce").and_then(Bencode::str) {
+            if !trackers.iter().any(|tracker| tracker == announce) {
+                trackers.insert(0, announce.to_string());
+            }
+        }
+
+        Self::from_info(raw_info(torrent)?.to_vec(), trackers)
+    }
+
+    fn from_info(info: Vec<u8>, trackers: Vec<String>) -> anyhow::Result<Self> {
+        let value = Bencode::decode(&info)?;
+        let invalid = |what: &str| anyhow!("Invalid torrent, bad or missing {}", what);
+        let name = value
+            .get("name")
+            .and_then(Bencode::str)
+            .filter(|name| is_plain(Path::new(name)))
+            .ok_or_else(|| invalid("name"))?
+            .to_string();
+        let piece_length = value
+            .get("piece length")
+            .and_then(Bencode::int)
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid("piece length"))? as u64;
+        let pieces = value
+            .get("pieces")
+            .and_then(Bencode::bytes)
+            .filter(|pieces| pieces.len() % 20 == 0)
+            .ok_or_else(|| invalid("pieces"))?
+            .chunks(20)
+            .map(|hash| hash.try_into().unwrap_or([0; 20]))
+            .collect::<Vec<_>>();
+
+        let files = match value.get("files") {
+            Some(files) => files
+                .list()
+                .iter()
+                .map(|file| {
+                    let length = file.get("length").and_then(Bencode::int);
+                    let path = file
+                        .get("path")
+                        .map(Bencode::list)
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|part| part.str().map(PathBuf::from))
+                        .collect::<Option<PathBuf>>()
+                        .filter(|path| is_plain(path));
+                    match (path, length) {
+                        (Some(path), Some(length)) if length >= 0 => Ok((path, length as u64)),
+                        _ => Err(invalid("file")),
+                    }
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![(
+                PathBuf::new(),
+                value
+                    .get("length")
+                    .and_then(Bencode::int)
+                    .filter(|&n| n >= 0)
+                    .ok_or_else(|| invalid("length"))? as u64,
+            )],
+        };
+
+        let meta = Self {
+            info_hash: sha1(&info),
+            info,
+            name,
+            piece_length,
+            pieces,
+            files,
+            trackers,
+        };
+        if (meta.size().div_ceil(piece_length)) as usize != meta.pieces.len() {
+            bail!("Invalid torrent, the pieces do not cover the files");
+        }
+
+        Ok(meta)
+    }
+
+    fn size(&self) -> u64 {
+        self.files.iter().map(|(_, length)| length).sum()
+    }
+
+    fn piece_size(&self, piece: usize) -> u64 {
+        let start = piece as u64 * self.piece_length;
+        self.piece_length.min(self.size() - start)
+    }
+
+    fn single_file(&self) -> bool {
+        self.files.len() == 1 && self.files[0].0.as_os_str().is_empty()
+    }
+
+    // A torrent file for the metainfo, kept to seed magnet downloads
+    fn to_torrent(&self) -> Vec<u8> {
+        let trackers = Bencode::List(
+            self.trackers
+                .iter()
+                .map(|tracker| Bencode::List(vec![Bencode::Bytes(tracker.as_bytes().to_vec())]))
+                .collect(),
+        );
+        let mut torrent = b"d13:announce-list".to_vec();
+        trackers.encode(&mut torrent);
+        torrent.extend(b"4:info");
+        torrent.extend(&self.info);
+        torrent.push(b'e');
+
+        torrent
+    }
+}
+
+// Paths in a torrent must stay inside the directory it is downloaded to
+fn is_plain(path: &Path) -> bool {
+    path.components().count() > 0
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+// A magnet link or a torrent file, before its metainfo is known
+struct Source {
+    info_hash: [u8; 20],
+    name: Option<String>,
+    trackers: Vec<String>,
+    metainfo: Option<Metainfo>,
+}
+impl Source {
+    fn open(client: &Client, source: &str) -> anyhow::Result<Self> {
+        if source.starts_with("magnet:") {
+            return Self::magnet(source);
+        }
+
+        let torrent = match source.starts_with("http://") || source.starts_with("https://") {
+            true => client
+                .get(source)
+                .send()?
+                .error_for_status()?
+                .bytes()?
+                .to_vec(),
+            false => fs::read(source).map_err(|e| anyhow!("{}: {}", source, e))?,
+        };
+        let meta = Metainfo::parse(&torrent)?;
+
+        Ok(Self {
+            info_hash: meta.info_hash,
+            name: Some(meta.name.clone()),
+            trackers: meta.trackers.clone(),
+            metainfo: Some(meta),
+        })
+    }
+
+    fn magnet(link: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(link)?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(match hash.len() {
+                            40 => hex_decode(hash)?,
+                            32 => base32_decode(hash)?,
+                            _ => bail!("Invalid info hash '{}' in the magnet link", hash),
+                        });
+                    }
+                }
+                "dn" => name = Some(value.to_string()),
+                "tr" => trackers.push(value.to_string()),
+                _ => {}
+            }
+        }
+        let info_hash = info_hash
+            .and_then(|hash| <[u8; 20]>::try_from(hash).ok())
+            .ok_or(anyhow!(
+                "The magnet link has no BitTorrent info hash (xt=urn:btih:…)"
+            ))?;
+
+        Ok(Self {
+            info_hash,
+            name,
+            trackers,
+            // kept from an earlier download of the same magnet link
+            metainfo: fs::read(torrent_path(&info_hash)?)
+                .ok()
+                .and_then(|torrent| Metainfo::parse(&torrent).ok()),
+        })
+    }
+}
+
+fn base32_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => bail!("Invalid base32 character '{}'", c as char),
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(bytes)
+}
+
+// Where the metainfo of a downloaded torrent is kept, to seed it later
+fn torrent_path(info_hash: &[u8; 20]) -> anyhow::Result<PathBuf> {
+    Ok(paths::models_dir()?
+        .join("torrents")
+        .join(format!("{}.torrent", hex(info_hash))))
+}
+
+// Name of the downloaded file or directory, as far as it is known without asking peers
+pub fn file_name(source: &str) -> anyhow::Result<String> {
+    if source.starts_with("magnet:") {
+        let source = Source::magnet(source)?;
+        return Ok(source.name.unwrap_or(hex(&source.info_hash)));
+    }
+    if let Ok(torrent) = fs::read(source) {
+        return Ok(Metainfo::parse(&torrent)?.name);
+    }
+
+    let name = source.rsplit('/').next().unwrap_or(source);
+    Ok(name.trim_end_matches(".torrent").to_string())
+}
+
+// The files of a torrent on disk, the pieces run across them
+struct Storage {
+    files: Vec<(PathBuf, u64)>,
+}
+impl Storage {
+    // A single file torrent is the file at `root`, the files of others are under it
+    fn new(meta: &Metainfo, root: &Path) -> Self {
+        let files = match meta.single_file() {
+            true => vec![(root.to_path_buf(), meta.files[0].1)],
+            false => meta
+                .files
+                .iter()
+                .map(|(path, length)| (root.join(path), *length))
+                .collect(),
+        };
+
+        Self { files }
+    }
+
+    fn create(&self) -> anyhow::Result<()> {
+        for (path, length) in &self.files {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() != *length {
+                file.set_len(*length)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Call `f` with each file, the offset in it and the range of the buffer it covers
+    fn each(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&Path, u64, std::ops::Range<usize>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let (mut start, mut done) = (0u64, 0usize);
+        for (path, length) in &self.files {
+            let end = start + length;
+            let at = offset + done as u64;
+            if done < len && at < end && at >= start {
+                let n = ((end - at) as usize).min(len - done);
+                f(path, at - start, done..done + n)?;
+                done += n;
+            }
+            start = end;
+        }
+        if done != len {
+            bail!("Read past the end of the torrent");
+        }
+
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.each(offset, buf.len(), |path, at, range| {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(at))?;
+            file.read_exact(&mut buf[range])?;
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.each(offset, data.len(), |path, at, range| {
+            let mut file = OpenOptions::new().write(true).open(path)?;
+            file.seek(SeekFrom::Start(at))?;
+            file.write_all(&data[range])?;
+            Ok(())
+        })
+    }
+
+    // Whether the piece on disk has the hash the torrent gives for it
+    fn check(&self, meta: &Metainfo, piece: usize) -> bool {
+        let mut data = vec![0; meta.piece_size(piece) as usize];
+        self.read_at(piece as u64 * meta.piece_length, &mut data)
+            .is_ok_and(|_| sha1(&data) == meta.pieces[piece])
+    }
+}
+
+// A random id, prefixed as clients identify themselves
+fn peer_id() -> anyhow::Result<[u8; 20]> {
+    let mut id = *b"-GA0100-000000000000";
+    SystemRandom::new()
+        .fill(&mut id[8..])
+        .map_err(|_| anyhow!("No randomness available for the peer id"))?;
+
+    Ok(id)
+}
+
+#[derive(Clone, Copy)]
+enum Event {
+    Started,
+    Completed,
+    Periodic,
+}
+
+struct Announce<'a> {
+    info_hash: &'a [u8; 20],
+    peer_id: &'a [u8; 20],
+    port: u16,
+    left: u64,
+    event: Event,
+}
+
+// Peers of the torrent and the seconds to wait before announcing again
+fn announce(
+    client: &Client,
+    tracker: &str,
+    announce: &Announce,
+) -> anyhow::Result<(Vec<SocketAddr>, u64)> {
+    let _span = tracing::debug_span!("announce", tracker).entered();
+    match tracker.split_once("://").map(|(scheme, _)| scheme) {
+        Some("http" | "https") => announce_http(client, tracker, announce),
+        Some("udp") => announce_udp(tracker, announce),
+        _ => bail!("Unsupported tracker {}", tracker),
+    }
+}
+
+fn announce_http(
+    client: &Client,
+    tracker: &str,
+    announce: &Announce,
+) -> anyhow::Result<(Vec<SocketAddr>, u64)> {
+    let event = match announce.event {
+        Event::Started => "&event=started",
+        Event::Completed => "&event=completed",
+        Event::Periodic => "",
+    };
+    let url = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1{}",
+        tracker,
+        if tracker.contains('?') { '&' } else { '?' },
+        percent_encode(announce.info_hash, NON_ALPHANUMERIC),
+        percent_encode(announce.peer_id, NON_ALPHANUMERIC),
+        announce.port,
+        announce.left,
+        event
+    );
+    let body = client
+        .get(url)
+        .timeout(TRACKER_TIMEOUT)
+        .send()?
+        .error_for_status()?
+        .bytes()?;
+    let response = Bencode::decode(&body)?;
+    if let Some(reason) = response.get("failure reason").and_then(Bencode::str) {
+        bail!("The tracker refused: {}", reason);
+    }
+
+    let mut peers = Vec::new();
+    match response.get("peers") {
+        Some(Bencode::Bytes(compact)) => peers.extend(compact_peers(compact)),
+        Some(Bencode::List(list)) => {
+            for peer in list {
+                let ip = peer.get("ip").and_then(Bencode::str);
+                let port = peer.get("port").and_then(Bencode::int);
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    if let Ok(mut addrs) = (ip, port as u16).to_socket_addrs() {
+                        peers.extend(addrs.next());
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    let interval = response
+        .get("interval")
+        .and_then(Bencode::int)
+        .unwrap_or(1800) as u64;
+
+    Ok((peers, interval))
+}
+
+fn compact_peers(compact: &[u8]) -> impl Iterator<Item = SocketAddr> + '_ {
+    compact.chunks_exact(6).map(|peer| {
+        let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+        SocketAddr::V4(SocketAddrV4::new(
+            ip,
+            u16::from_be_bytes([peer[4], peer[5]]),
+        ))
+    })
+}
+
+// The UDP tracker protocol, a connect exchange then the announce
+fn announce_udp(tracker: &str, announce: &Announce) -> anyhow::Result<(Vec<SocketAddr>, u64)> {
+    let url = Url::parse(tracker)?;
+    let host = url
+        .host_str()
+        .ok_or(anyhow!("Missing the host in {}", tracker))?;
+    let addr = (host, url.port().unwrap_or(80))
+        .to_socket_addrs()?
+        .find(SocketAddr::is_ipv4)
+        .ok_or(anyhow!("Cannot resolve {}", host))?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(TRACKER_TIMEOUT))?;
+    socket.connect(addr)?;
+
+    let mut transaction = [0; 4];
+    SystemRandom::new()
+        .fill(&mut transaction)
+        .map_err(|_| anyhow!("No randomness available"))?;
+    let mut request = 0x41727101980u64.to_be_bytes().to_vec();
+    request.extend(0u32.to_be_bytes());
+    request.extend(transaction);
+    socket.send(&request)?;
+    let mut response = [0; 1500];
+    let n = socket.recv(&mut response)?;
+    if n < 16 || response[..4] != [0, 0, 0, 0] || response[4..8] != transaction {
+        bail!("Unexpected answer from {}", tracker);
+    }
+    let connection = &response[8..16];
+
+    let event: u32 = match announce.event {
+        Event::Periodic => 0,
+        Event::Completed => 1,
+        Event::Started => 2,
+    };
+    let mut request = connection.to_vec();
+    request.extend(1u32.to_be_bytes());
+    request.extend(transaction);
+    request.extend(announce.info_hash);
+    request.extend(announce.peer_id);
+    request.extend(0u64.to_be_bytes());
+    request.extend(announce.left.to_be_bytes());
+    request.extend(0u64.to_be_bytes());
+    request.extend(event.to_be_bytes());
+    request.extend(0u32.to_be_bytes());
+    request.extend(transaction);
+    request.extend((-1i32).to_be_bytes());
+    request.extend(announce.port.to_be_bytes());
+    socket.send(&request)?;
+    let n = socket.recv(&mut response)?;
+    if n < 20 || response[..4] != [0, 0, 0, 1] || response[4..8] != transaction {
+        bail!("Unexpected answer from {}", tracker);
+    }
+    let interval = u32::from_be_bytes([response[8], response[9], response[10], response[11]]);
+
+    Ok((
+        compact_peers(&response[20..n]).collect(),
+        u64::from(interval),
+    ))
+}
+
+// Peers from every tracker that answers
+fn find_peers(client: &Client, trackers: &[String], announce_: &Announce) -> Vec<SocketAddr> {
+    let mut peers = Vec::new();
+    for tracker in trackers {
+        match announce(client, tracker, announce_) {
+            Ok((found, _)) => {
+                tracing::debug!(tracker, peers = found.len(), "announced");
+                for peer in found {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            Err(e) => tracing::warn!(tracker, "announce failed: {:#}", e),
+        }
+    }
+
+    peers
+}
+
+struct Message {
+    id: u8,
+    payload: Vec<u8>,
+}
+
+// A connection to another client
+struct Peer {
+    stream: TcpStream,
+    addr: SocketAddr,
+    // pieces it has
+    has: Vec<bool>,
+    choked: bool,
+    // id it takes for metadata messages, and the size of the metadata
+    ut_metadata: Option<u8>,
+    metadata_size: Option<usize>,
+}
+impl Peer {
+    fn connect(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        peer_id: &[u8; 20],
+        pieces: usize,
+    ) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(10))?;
+        let mut peer = Self::accept(stream, addr, pieces)?;
+        peer.stream.write_all(&handshake(info_hash, peer_id))?;
+        peer.read_handshake(info_hash)?;
+
+        Ok(peer)
+    }
+
+    fn accept(stream: TcpStream, addr: SocketAddr, pieces: usize) -> anyhow::Result<Self> {
+        stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+        stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+
+        Ok(Self {
+            stream,
+            addr,
+            has: vec![false; pieces],
+            choked: true,
+            ut_metadata: None,
+            metadata_size: None,
+        })
+    }
+
+    fn read_handshake(&mut self, info_hash: &[u8; 20]) -> anyhow::Result<()> {
+        let mut theirs = [0; 68];
+        self.stream.read_exact(&mut theirs)?;
+        if &theirs[..20] != b"\x13BitTorrent protocol" {
+            bail!("{} does not speak BitTorrent", self.addr);
+        }
+        if &theirs[28..48] != info_hash {
+            bail!("{} has another torrent", self.addr);
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let mut message = ((payload.len() + 1) as u32).to_be_bytes().to_vec();
+        message.push(id);
+        message.extend(payload);
+        self.stream.write_all(&message)?;
+
+        Ok(())
+    }
+
+    fn send_extended(&mut self, id: u8, message: &Bencode, data: &[u8]) -> anyhow::Result<()> {
+        let mut payload = vec![id];
+        message.encode(&mut payload);
+        payload.extend(data);
+        self.send(EXTENDED, &payload)
+    }
+
+    // The next message, keeping track of what the peer has and whether it chokes us
+    fn receive(&mut self) -> anyhow::Result<Message> {
+        loop {
+            let mut len = [0; 4];
+            self.stream.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            // keep-alive
+            if len == 0 {
+                continue;
+            }
+            if len > 2 * 1024 * 1024 {
+                bail!("{} sent a message of {} bytes", self.addr, len);
+            }
+            let mut message = vec![0; len];
+            self.stream.read_exact(&mut message)?;
+            let id = message[0];
+            let payload = message.split_off(1);
+
+            match id {
+                CHOKE => self.choked = true,
+                UNCHOKE => self.choked = false,
+                HAVE if payload.len() == 4 => {
+                    let piece =
+                        u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                    if let Some(has) = self.has.get_mut(piece as usize) {
+                        *has = true;
+                    }
+                }
+                BITFIELD => {
+                    for (piece, has) in self.has.iter_mut().enumerate() {
+                        *has = payload
+                            .get(piece / 8)
+                            .is_some_and(|byte| byte & (0x80 >> (piece % 8)) != 0);
+                    }
+                }
+                EXTENDED if payload.first() == Some(&0) => {
+                    let handshake = Bencode::decode(&payload[1..])?;
+                    self.ut_metadata = handshake
+                        .get("m")
+                        .and_then(|m| m.get("ut_metadata"))
+                        .and_then(Bencode::int)
+                        .and_then(|id| u8::try_from(id).ok())
+                        .filter(|&id| id != 0);
+                    self.metadata_size = handshake
+                        .get("metadata_size")
+                        .and_then(Bencode::int)
+                        .and_then(|size| usize::try_from(size).ok());
+                }
+                _ => {}
+            }
+
+            return Ok(Message { id, payload });
+        }
+    }
+}
+
+fn handshake(info_hash: &[u8; 20], peer_id: &[u8; 20]) -> Vec<u8> {
+    let mut handshake = b"\x13BitTorrent protocol".to_vec();
+    // we speak the extension protocol, for the metadata of magnet links
+    handshake.extend([0, 0, 0, 0, 0, 0x10, 0, 0]);
+    handshake.extend(info_hash);
+    handshake.extend(peer_id);
+    handshake
+}
+
+fn extended_handshake(metadata_size: Option<usize>) -> Bencode {
+    let mut handshake = dict([(
+        "m",
+        dict([("ut_metadata", Bencode::Int(UT_METADATA.into()))]),
+    )]);
+    if let (Some(size), Bencode::Dict(entries)) = (metadata_size, &mut handshake) {
+        entries.insert(b"metadata_size".to_vec(), Bencode::Int(size as i64));
+    }
+    handshake
+}
+
+// The info dictionary of a magnet link, asked from the peers and checked against its hash
+fn fetch_metadata(
+    peers: &[SocketAddr],
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+) -> anyhow::Result<Vec<u8>> {
+    for &addr in peers {
+        match fetch_metadata_from(addr, info_hash, peer_id) {
+            Ok(info) => return Ok(info),
+            Err(e) => tracing::debug!(%addr, "no metadata: {:#}", e),
+        }
+    }
+
+    bail!(
+        "None of the {} peers sent the metadata of the magnet link",
+        peers.len()
+    )
+}
+
+fn fetch_metadata_from(
+    addr: SocketAddr,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+) -> anyhow::Result<Vec<u8>> {
+    let mut peer = Peer::connect(addr, info_hash, peer_id, 0)?;
+    peer.send_extended(0, &extended_handshake(None), &[])?;
+    let (id, size) = loop {
+        let message = peer.receive()?;
+        if message.id == EXTENDED && message.payload.first() == Some(&0) {
+            match (peer.ut_metadata, peer.metadata_size) {
+                (Some(id), Some(size)) if size > 0 && size <= MAX_METADATA_SIZE => {
+                    break (id, size)
+                }
+                _ => bail!("{} does not share metadata", addr),
+            }
+        }
+    };
+
+    let mut info = vec![0; size];
+    let pieces = size.div_ceil(BLOCK_SIZE as usize);
+    for piece in 0..pieces {
+        let request = dict([
+            ("msg_type", Bencode::Int(0)),
+            ("piece", Bencode::Int(piece as i64)),
+        ]);
+        peer.send_extended(id, &request, &[])?;
+    }
+    let mut received = 0;
+    while received < pieces {
+        let message = peer.receive()?;
+        if message.id != EXTENDED || message.payload.first() != Some(&UT_METADATA) {
+            continue;
+        }
+        let (header, end) = decode_at(&message.payload, 1)?;
+        let piece = header.get("piece").and_then(Bencode::int).unwrap_or(-1);
+        match header.get("msg_type").and_then(Bencode::int) {
+            Some(1) if piece >= 0 && (piece as usize) < pieces => {
+                let start = piece as usize * BLOCK_SIZE as usize;
+                let data = &message.payload[end..];
+                info.get_mut(start..start + data.len())
+                    .ok_or(anyhow!("{} sent too much metadata", addr))?
+                    .copy_from_slice(data);
+                received += 1;
+            }
+            Some(2) => bail!("{} refused to send the metadata", addr),
+            _ => {}
+        }
+    }
+    if sha1(&info) != *info_hash {
+        bail!("{} sent metadata not matching the magnet link", addr);
+    }
+
+    Ok(info)
+}
+
+// Which pieces are on disk and which are being fetched
+struct Pieces {
+    done: Vec<bool>,
+    taken: Vec<bool>,
+}
+impl Pieces {
+    fn remaining(&self) -> usize {
+        self.done.iter().filter(|done| !**done).count()
+    }
+}
+
+// Download a magnet link or a torrent file into the directory, checking every piece against its
+// hash. Returns the path of the model: the file of a single file torrent, or else the largest
+// gguf among the files.
+pub fn download(source: &str, dir: &Path) -> anyhow::Result<PathBuf> {
+    let _span = tracing::info_span!("download", source).entered();
+    let client = Client::builder().timeout(None).build()?;
+    let source = Source::open(&client, source)?;
+    let peer_id = peer_id()?;
+    if source.trackers.is_empty() {
+        bail!("The torrent lists no trackers, gaia finds peers through trackers only");
+    }
+
+    let mut announce = Announce {
+        info_hash: &source.info_hash,
+        peer_id: &peer_id,
+        port: DEFAULT_SEED_PORT,
+        left: source.metainfo.as_ref().map(Metainfo::size).unwrap_or(1),
+        event: Event::Started,
+    };
+    let peers = find_peers(&client, &source.trackers, &announce);
+    if peers.is_empty() {
+        bail!("The trackers know no peers of the torrent, try again later");
+    }
+    tracing::info!(peers = peers.len(), "found peers");
+
+    let meta = match source.metainfo {
+        Some(meta) => meta,
+        None => {
+            let info = fetch_metadata(&peers, &source.info_hash, &peer_id)?;
+            Metainfo::from_info(info, source.trackers.clone())?
+        }
+    };
+    let torrent = torrent_path(&meta.info_hash)?;
+    fs::create_dir_all(torrent.parent().unwrap_or(dir))?;
+    fs::write(&torrent, meta.to_torrent())?;
+
+    let dest = dir.join(&meta.name);
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let storage = Storage::new(&meta, &partial);
+    storage.create()?;
+
+    // pieces of an interrupted download are kept
+    let done = (0..meta.pieces.len())
+        .map(|piece| storage.check(&meta, piece))
+        .collect::<Vec<_>>();
+    let have = done
+        .iter()
+        .enumerate()
+        .filter(|(_, done)| **done)
+        .map(|(piece, _)| meta.piece_size(piece))
+        .sum::<u64>();
+    tracing::info!(dest = %dest.display(), size = meta.size(), have, "downloading");
+    let mut progress = Progress::new("download", &meta.name, "bytes", Some(meta.size()));
+    progress.advance(have);
+    let progress = Mutex::new(progress);
+    let pieces = Mutex::new(Pieces {
+        taken: done.clone(),
+        done,
+    });
+    let queue = Mutex::new(peers);
+
+    thread::scope(|scope| {
+        for _ in 0..MAX_PEERS {
+            scope.spawn(|| loop {
+                let Some(addr) = queue.lock().ok().and_then(|mut queue| queue.pop()) else {
+                    return;
+                };
+                if pieces.lock().map(|pieces| pieces.remaining()).unwrap_or(0) == 0 {
+                    return;
+                }
+                let result = Peer::connect(addr, &meta.info_hash, &peer_id, meta.pieces.len())
+                    .and_then(|mut peer| {
+                        download_from(&mut peer, &meta, &storage, &pieces, &progress)
+                    });
+                if let Err(e) = result {
+                    tracing::debug!(%addr, "peer dropped: {:#}", e);
+                }
+            });
+        }
+    });
+
+    let remaining = pieces
+        .into_inner()
+        .map_err(|_| anyhow!("A download thread panicked"))?
+        .remaining();
+    if remaining > 0 {
+        bail!(
+            "{} of {} pieces could not be fetched from the peers, run it again to resume",
+            remaining,
+            meta.pieces.len()
+        );
+    }
+    progress
+        .into_inner()
+        .map_err(|_| anyhow!("A download thread panicked"))?
+        .finish();
+    fs::rename(&partial, &dest)?;
+    tracing::info!(bytes = meta.size(), "downloaded");
+
+    announce.left = 0;
+    announce.event = Event::Completed;
+    find_peers(&client, &meta.trackers, &announce);
+    println!(
+        "Downloaded {}, share it with `gaia models seed {}`",
+        meta.name,
+        torrent.display()
+    );
+
+    if meta.single_file() {
+        return Ok(dest);
+    }
+    meta.files
+        .iter()
+        .filter(|(path, _)| path.extension().is_some_and(|ext| ext == "gguf"))
+        .max_by_key(|(_, length)| *length)
+        .map(|(path, _)| dest.join(path))
+        .ok_or(anyhow!(
+            "The torrent has no gguf file, it is in {}",
+            dest.display()
+        ))
+}
+
+// Fetch pieces from the peer until it has none we need
+fn download_from(
+    peer: &mut Peer,
+    meta: &Metainfo,
+    storage: &Storage,
+    pieces: &Mutex<Pieces>,
+    progress: &Mutex<Progress>,
+) -> anyhow::Result<()> {
+    peer.send(INTERESTED, &[])?;
+    while peer.choked {
+        peer.receive()?;
+    }
+
+    loop {
+        let piece = {
+            let mut pieces = pieces
+                .lock()
+                .map_err(|_| anyhow!("A download thread panicked"))?;
+            let Some(piece) = (0..meta.pieces.len()).find(|&i| !pieces.taken[i] && peer.has[i])
+            else {
+                return Ok(());
+            };
+            pieces.taken[piece] = true;
+            piece
+        };
+
+        match fetch_piece(peer, meta, piece) {
+            Ok(data) => {
+                storage.write_at(piece as u64 * meta.piece_length, &data)?;
+                if let Ok(mut pieces) = pieces.lock() {
+                    pieces.done[piece] = true;
+                }
+                if let Ok(mut progress) = progress.lock() {
+                    progress.advance(data.len() as u64);
+                }
+            }
+            Err(e) => {
+                // leave the piece to another peer
+                if let Ok(mut pieces) = pieces.lock() {
+                    pieces.taken[piece] = false;
+                }
+                return Err(e);
+            }
+        }
+    }
+}
+
+fn fetch_piece(peer: &mut Peer, meta: &Metainfo, piece: usize) -> anyhow::Result<Vec<u8>> {
+    let size = meta.piece_size(piece);
+    let mut data = vec![0; size as usize];
+    let blocks = size.div_ceil(BLOCK_SIZE);
+    let (mut requested, mut received) = (0, 0);
+    while received < blocks {
+        while requested < blocks && requested - received < PIPELINE as u64 {
+            let begin = requested * BLOCK_SIZE;
+            let mut request = (piece as u32).to_be_bytes().to_vec();
+            request.extend((begin as u32).to_be_bytes());
+            request.extend((BLOCK_SIZE.min(size - begin) as u32).to_be_bytes());
+            peer.send(REQUEST, &request)?;
+            requested += 1;
+        }
+
+        let message = peer.receive()?;
+        match message.id {
+            CHOKE => bail!("{} choked us", peer.addr),
+            PIECE if message.payload.len() >= 8 => {
+                let payload = &message.payload;
+                let index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let begin = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                if index as usize != piece {
+                    continue;
+                }
+                let block = &payload[8..];
+                data.get_mut(begin as usize..begin as usize + block.len())
+                    .ok_or(anyhow!("{} sent a block outside the piece", peer.addr))?
+                    .copy_from_slice(block);
+                received += 1;
+            }
+            _ => {}
+        }
+    }
+    if sha1(&data) != meta.pieces[piece] {
+        bail!("{} sent a piece not matching its hash", peer.addr);
+    }
+
+    Ok(data)
+}
+
+// Share a downloaded torrent with other peers until interrupted
+pub fn command_seed(source: String, port: u16) -> anyhow::Result<()> {
+    let client = Client::builder().timeout(None).build()?;
+    let source = Source::open(&client, &source)?;
+    let meta = source.metainfo.ok_or(anyhow!(
+        "The metadata of the magnet link is not known, download it with `gaia start -m` first"
+    ))?;
+    let dest = paths::models_dir()?.join(&meta.name);
+    if !dest.exists() {
+        bail!(
+            "{} has not been downloaded, download it with `gaia start -m` first",
+            dest.display()
+        );
+    }
+    let storage = Storage::new(&meta, &dest);
+
+    println!("Checking the {} pieces of {}", meta.pieces.len(), meta.name);
+    if let Some(piece) = (0..meta.pieces.len()).find(|&piece| !storage.check(&meta, piece)) {
+        bail!(
+            "Piece {} of {} does not match the torrent, download it again",
+            piece,
+            dest.display()
+        );
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    let peer_id = peer_id()?;
+    println!(
+        "Seeding {} on port {}, press Ctrl-C to stop",
+        meta.name, port
+    );
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut announce = Announce {
+                info_hash: &meta.info_hash,
+                peer_id: &peer_id,
+                port,
+                left: 0,
+                event: Event::Started,
+            };
+            loop {
+                let interval = meta
+                    .trackers
+                    .iter()
+                    .filter_map(|tracker| self::announce(&client, tracker, &announce).ok())
+                    .map(|(_, interval)| interval)
+                    .min()
+                    .unwrap_or(1800);
+                announce.event = Event::Periodic;
+                thread::sleep(Duration::from_secs(interval.max(60)));
+            }
+        });
+
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let (meta, storage) = (&meta, &storage);
+            scope.spawn(move || {
+                let addr = stream
+                    .peer_addr()
+                    .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+                if let Err(e) = serve_peer(stream, addr, meta, storage, &peer_id) {
+                    tracing::debug!(%addr, "peer left: {:#}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+// Answer the requests of a peer for pieces and metadata
+fn serve_peer(
+    stream: TcpStream,
+    addr: SocketAddr,
+    meta: &Metainfo,
+    storage: &Storage,
+    peer_id: &[u8; 20],
+) -> anyhow::Result<()> {
+    let mut peer = Peer::accept(stream, addr, meta.pieces.len())?;
+    peer.read_handshake(&meta.info_hash)?;
+    peer.stream
+        .write_all(&handshake(&meta.info_hash, peer_id))?;
+    tracing::info!(%addr, "peer connected");
+    peer.send_extended(0, &extended_handshake(Some(meta.info.len())), &[])?;
+    let mut bitfield = vec![0xffu8; meta.pieces.len().div_ceil(8)];
+    if !meta.pieces.len().is_multiple_of(8) {
+        if let Some(last) = bitfield.last_mut() {
+            *last = 0xff << (8 - meta.pieces.len() % 8);
+        }
+    }
+    peer.send(BITFIELD, &bitfield)?;
+
+    loop {
+        let message = peer.receive()?;
+        let payload = &message.payload;
+        match message.id {
+            INTERESTED => peer.send(UNCHOKE, &[])?,
+            REQUEST if payload.len() == 12 => {
+                let field = |i: usize| {
+                    u32::from_be_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+                };
+                let (piece, begin, len) = (field(0), field(4), field(8));
+                if piece as usize >= meta.pieces.len()
+                    || len as u64 > 8 * BLOCK_SIZE
+                    || begin as u64 + len as u64 > meta.piece_size(piece as usize)
+                {
+                    bail!("{} asked for a block outside the torrent", addr);
+                }
+                let mut block = payload[..8].to_vec();
+                let at = block.len();
+                block.resize(at + len as usize, 0);
+                storage.read_at(
+                    piece as u64 * meta.piece_length + begin as u64,
+                    &mut block[at..],
+                )?;
+                peer.send(PIECE, &block)?;
+            }
+            EXTENDED if payload.first() == Some(&UT_METADATA) => {
+                let (request, _) = decode_at(payload, 1)?;
+                let piece = request.get("piece").and_then(Bencode::int).unwrap_or(-1);
+                let start = (piece.max(0) as usize).saturating_mul(BLOCK_SIZE as usize);
+                let (Some(id), Some(0), true) = (
+                    peer.ut_metadata,
+                    request.get("msg_type").and_then(Bencode::int),
+                    piece >= 0 && start < meta.info.len(),
+                ) else {
+                    continue;
+                };
+                let end = (start + BLOCK_SIZE as usize).min(meta.info.len());
+                let answer = dict([
+                    ("msg_type", Bencode::Int(1)),
+                    ("piece", Bencode::Int(piece)),
+                    ("total_size", Bencode::Int(meta.info.len() as i64)),
+                ]);
+                peer.send_extended(id, &answer, &meta.info[start..end])?;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+pub const DEFAULT_AUDIO_BASE_URL: &str = "http://localhost:8081/v1";
+
+#[derive(Debug, Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+// Transcribe an audio file with the whisper api-server
+pub fn command_transcribe(
+    base_url: &str,
+    file: PathBuf,
+    language: Option<String>,
+    translate: bool,
+) -> anyhow::Result<()> {
+    if !file.is_file() {
+        bail!("Audio file {} not found", file.display());
+    }
+
+    let mut form = reqwest::blocking::multipart::Form::new()
+        .file("file", &file)
+        .map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+    if let Some(language) = language {
+        form = form.text("language", language);
+    }
+
+    // whisper translates into English on a separate endpoint
+    let endpoint = if translate {
+        "audio/translations"
+    } else {
+        "audio/transcriptions"
+    };
+    let response = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()?
+        .post(format!("{}/{}", base_url.trim_end_matches('/'), endpoint))
+        .multipart(form)
+        .send()
+        .map_err(|e| {
+            anyhow!(
+                "Failed to reach the whisper api-server at {}, start it with `gaia start --whisper-model <model>`: {}",
+                base_url,
+                e
+            )
+        })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "The server responded with {}: {}",
+            status,
+            response.text().unwrap_or_default().trim()
+        );
+    }
+    let transcription: Transcription = response.json()?;
+    println!("{}", transcription.text.trim());
+
+    Ok(())
+}
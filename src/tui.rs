@@ -0,0 +1,459 @@
+use crate::config;
+use crate::keys::{self, Action, TuiKeys};
+use crate::memory::GIB;
+use crate::models::{self, LocalModel};
+use crate::node;
+use crate::server;
+use crate::top::Top;
+use crate::watchdog;
+use anyhow::{anyhow, bail};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use std::{
+    env,
+    io::{self, BufRead, IsTerminal},
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+// How often the services are refreshed
+const REFRESH: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Services,
+    Models,
+    Config,
+}
+impl Tab {
+    const ALL: [Tab; 3] = [Tab::Services, Tab::Models, Tab::Config];
+
+    fn title(self) -> &'static str {
+        match self {
+            Tab::Services => "services",
+            Tab::Models => "models",
+            Tab::Config => "config",
+        }
+    }
+}
+
+// What the next draw needs to do outside of the screen
+enum Leave {
+    Quit,
+    // leave the screen to run gaia with these arguments, e.g. `start -m <model>`
+    Run(Vec<String>),
+}
+
+struct Tui {
+    keys: TuiKeys,
+    tab: Tab,
+    top: Top,
+    models: Vec<LocalModel>,
+    model_table: TableState,
+    // the node file whose keys are edited, when there is one
+    file: Option<PathBuf>,
+    settings: Vec<(String, String)>,
+    setting_table: TableState,
+    // the value being typed for the selected key
+    editing: Option<String>,
+    // outcome of the last action, in place of the help until the next key
+    message: Option<(String, bool)>,
+}
+impl Tui {
+    fn rescan(&mut self) {
+        match models::local_models() {
+            Ok(models) => self.models = models,
+            Err(e) => self.fail(format!("Cannot list the models: {:#}", e)),
+        }
+        select_first(&mut self.model_table, self.models.len());
+    }
+
+    fn reload_settings(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        match node::settings(file) {
+            Ok(settings) => self.settings = settings,
+            Err(e) => self.fail(format!("{:#}", e)),
+        }
+        select_first(&mut self.setting_table, self.settings.len());
+    }
+
+    // The keys of the tab, as keys.toml binds them
+    fn help(&self) -> String {
+        let keys = &self.keys;
+        let select = format!(
+            "{}/{} select",
+            keys::first(&keys.up),
+            keys::first(&keys.down)
+        );
+        let common = format!(
+            "{} next  {} quit",
+            keys::first(&keys.next_tab),
+            keys::first(&keys.quit)
+        );
+        match self.tab {
+            Tab::Services => format!(
+                "{}  r restart  x stop  {}/{} scroll the log  {} follow  {}",
+                select,
+                keys::first(&keys.scroll_up),
+                keys::first(&keys.scroll_down),
+                keys::first(&keys.follow),
+                common
+            ),
+            Tab::Models => format!("{}  enter start  f5 rescan  {}", select, common),
+            Tab::Config => format!("{}  enter edit  {}", select, common),
+        }
+    }
+
+    fn done(&mut self, message: String) {
+        self.message = Some((message, true));
+    }
+
+    fn fail(&mut self, message: String) {
+        self.message = Some((message, false));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [tabs, main, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let mut spans = vec![Span::raw("gaia  ").bold()];
+        for (i, tab) in Tab::ALL.iter().enumerate() {
+            let label = format!(" {} {} ", i + 1, tab.title());
+            spans.push(match *tab == self.tab {
+                true => Span::raw(label).reversed(),
+                false => Span::raw(label),
+            });
+        }
+        frame.render_widget(Paragraph::new(Line::from(spans)), tabs);
+
+        match self.tab {
+            Tab::Services => self.top.draw(frame, main),
+            Tab::Models => self.draw_models(frame, main),
+            Tab::Config => self.draw_config(frame, main),
+        }
+
+        let footer_line = match (&self.editing, &self.message) {
+            (Some(value), _) => Line::from(vec![
+                Span::raw("new value: ").bold(),
+                Span::raw(value.clone()),
+                Span::raw("█"),
+                Span::raw("  enter save  esc cancel, empty removes the key").dim(),
+            ]),
+            (None, Some((message, true))) => Line::from(message.clone().green()),
+            (None, Some((message, false))) => Line::from(message.clone().red()),
+            (None, None) => Line::from(self.help().dim()),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+
+    fn draw_models(&mut self, frame: &mut Frame, area: Rect) {
+        let running = server::load_all().unwrap_or_default();
+        let rows = self.models.iter().map(|model| {
+            let path = model.path.display().to_string();
+            let serving = running
+                .iter()
+                .find(|state| state.models.iter().any(|served| served.path == path))
+                .map(|state| state.name.clone())
+                .unwrap_or_default();
+            Row::new(vec![
+                model.origin.to_string(),
+                format!("{:.1} GiB", model.size as f64 / GIB),
+                serving,
+                model.model.clone(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(7),
+                Constraint::Length(9),
+                Constraint::Length(16),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(
+            Row::new(["ORIGIN", "SIZE", "SERVED BY", "MODEL"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(
+            Block::new()
+                .borders(Borders::TOP)
+                .title(format!(" {} models on disk ", self.models.len())),
+        );
+        frame.render_stateful_widget(table, area, &mut self.model_table);
+    }
+
+    fn draw_config(&mut self, frame: &mut Frame, area: Rect) {
+        let Some(file) = &self.file else {
+            frame.render_widget(
+                Paragraph::new(
+                    "No node file, give one with `gaia tui -f` or write config.toml in the config directory",
+                )
+                .fg(Color::Yellow)
+                .block(Block::new().borders(Borders::TOP).title(" config ")),
+                area,
+            );
+            return;
+        };
+        let rows = self.settings.iter().map(|(key, value)| {
+            let value = match value.is_empty() {
+                true => Span::raw("not set").dim(),
+                false => Span::raw(value.clone()),
+            };
+            Row::new(vec![Line::from(key.clone()), Line::from(value)])
+        });
+        let table = Table::new(rows, [Constraint::Length(24), Constraint::Fill(1)])
+            .header(Row::new(["KEY", "VALUE"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(
+                Block::new()
+                    .borders(Borders::TOP)
+                    .title(format!(" {} ", file.display())),
+            );
+        frame.render_stateful_widget(table, area, &mut self.setting_table);
+    }
+
+    fn key(&mut self, event: KeyEvent) -> Option<Leave> {
+        if let Some(value) = &mut self.editing {
+            match event.code {
+                KeyCode::Char(c) => value.push(c),
+                KeyCode::Backspace => {
+                    value.pop();
+                }
+                KeyCode::Esc => self.editing = None,
+                KeyCode::Enter => self.save(),
+                _ => {}
+            }
+            return None;
+        }
+        self.message = None;
+
+        let current = Tab::ALL
+            .iter()
+            .position(|tab| *tab == self.tab)
+            .unwrap_or(0);
+        match (self.keys.action(&event), event.code) {
+            (Some(Action::Quit), _) => return Some(Leave::Quit),
+            (Some(Action::NextTab), _) => self.tab = Tab::ALL[(current + 1) % Tab::ALL.len()],
+            (_, KeyCode::BackTab) => {
+                self.tab = Tab::ALL[(current + Tab::ALL.len() - 1) % Tab::ALL.len()]
+            }
+            (None, KeyCode::Char(c @ '1'..='3')) => self.tab = Tab::ALL[c as usize - '1' as usize],
+            (action, code) => match self.tab {
+                Tab::Services => self.key_services(action, code),
+                Tab::Models => return self.key_models(action, code),
+                Tab::Config => self.key_config(action, code),
+            },
+        }
+        None
+    }
+
+    fn key_services(&mut self, action: Option<Action>, code: KeyCode) {
+        if let Some(action) = action {
+            self.top.act(action);
+            return;
+        }
+        let Some(state) = self.top.selected().cloned() else {
+            return;
+        };
+        match code {
+            KeyCode::Char('x') => match server::stop(&state) {
+                Ok(()) => self.done(format!("Stopped {} (pid {})", state.name, state.pid)),
+                Err(e) => self.fail(format!("Cannot stop {}: {:#}", state.name, e)),
+            },
+            KeyCode::Char('r') => {
+                let restarted = server::restart(&state).and_then(|restarted| {
+                    if !watchdog::audio_only(&restarted) {
+                        server::wait_ready(
+                            &restarted,
+                            Duration::from_secs(server::DEFAULT_STARTUP_TIMEOUT),
+                        )?;
+                    }
+                    Ok(restarted)
+                });
+                match restarted {
+                    Ok(restarted) => self.done(format!(
+                        "Restarted {} (pid {})",
+                        restarted.name, restarted.pid
+                    )),
+                    Err(e) => self.fail(format!("Cannot restart {}: {:#}", state.name, e)),
+                }
+            }
+            _ => return,
+        }
+        if let Err(e) = self.top.refresh() {
+            self.fail(format!("{:#}", e));
+        }
+    }
+
+    fn key_models(&mut self, action: Option<Action>, code: KeyCode) -> Option<Leave> {
+        match (action, code) {
+            (Some(Action::Up), _) => move_selection(&mut self.model_table, true, 0),
+            (Some(Action::Down), _) => {
+                move_selection(&mut self.model_table, false, self.models.len())
+            }
+            (None, KeyCode::F(5)) => self.rescan(),
+            (None, KeyCode::Enter) => {
+                let model = self
+                    .model_table
+                    .selected()
+                    .and_then(|i| self.models.get(i))?;
+                return Some(Leave::Run(vec![
+                    "start".to_string(),
+                    "-m".to_string(),
+                    model.model.clone(),
+                ]));
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn key_config(&mut self, action: Option<Action>, code: KeyCode) {
+        match (action, code) {
+            (Some(Action::Up), _) => move_selection(&mut self.setting_table, true, 0),
+            (Some(Action::Down), _) => {
+                move_selection(&mut self.setting_table, false, self.settings.len())
+            }
+            (None, KeyCode::Enter) => {
+                if let Some(i) = self.setting_table.selected() {
+                    self.editing = self.settings.get(i).map(|(_, value)| value.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Write the value being typed into the node file
+    fn save(&mut self) {
+        let (Some(value), Some(file)) = (self.editing.take(), self.file.clone()) else {
+            return;
+        };
+        let Some((key, _)) = self
+            .setting_table
+            .selected()
+            .and_then(|i| self.settings.get(i))
+            .cloned()
+        else {
+            return;
+        };
+        match node::set(&file, &key, &value) {
+            Ok(()) => {
+                self.done(format!(
+                    "Set {}, restart the node with `gaia serve` for it to apply",
+                    key
+                ));
+                self.reload_settings();
+            }
+            Err(e) => self.fail(format!("{} is unchanged: {:#}", key, e)),
+        }
+    }
+}
+
+fn select_first(table: &mut TableState, len: usize) {
+    if table.selected().is_none_or(|i| i >= len) {
+        table.select((len > 0).then_some(0));
+    }
+}
+
+// Move the selection a row up or down, within the `len` rows
+fn move_selection(table: &mut TableState, up: bool, len: usize) {
+    let selected = table.selected().unwrap_or(0);
+    match up {
+        true => table.select(Some(selected.saturating_sub(1))),
+        false => table.select(Some((selected + 1).min(len.saturating_sub(1)))),
+    }
+}
+
+// Browse the models on disk, start and stop services, tail their logs and edit the node file
+// without leaving the screen
+pub fn command_tui(file: Option<PathBuf>) -> anyhow::Result<()> {
+    if !io::stdout().is_terminal() {
+        bail!("gaia tui needs a terminal");
+    }
+    let file = match file {
+        Some(file) => Some(file),
+        None => config::default_path().ok(),
+    };
+    let mut tui = Tui {
+        keys: keys::load()?.tui,
+        tab: Tab::Services,
+        top: Top::new(),
+        models: Vec::new(),
+        model_table: TableState::default(),
+        file,
+        settings: Vec::new(),
+        setting_table: TableState::default(),
+        editing: None,
+        message: None,
+    };
+    tui.top.refresh()?;
+    tui.rescan();
+    tui.reload_settings();
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut tui);
+    ratatui::restore();
+    result
+}
+
+fn run(terminal: &mut DefaultTerminal, tui: &mut Tui) -> anyhow::Result<()> {
+    let mut refreshed = Instant::now();
+    loop {
+        terminal.draw(|frame| tui.draw(frame))?;
+        if event::poll(REFRESH.saturating_sub(refreshed.elapsed()))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match tui.key(key) {
+                    Some(Leave::Quit) => return Ok(()),
+                    Some(Leave::Run(args)) => {
+                        ratatui::restore();
+                        let outcome = gaia(&args);
+                        *terminal = ratatui::init();
+                        match outcome {
+                            Ok(()) => tui.done(format!("gaia {} done", args.join(" "))),
+                            Err(e) => tui.fail(format!("{:#}", e)),
+                        }
+                        tui.top.refresh()?;
+                        refreshed = Instant::now();
+                    }
+                    None => {}
+                }
+            }
+        }
+        if refreshed.elapsed() >= REFRESH {
+            tui.top.refresh()?;
+            refreshed = Instant::now();
+        }
+    }
+}
+
+// Run gaia on the plain terminal, so that it can ask what it needs to, then wait for the user
+// to read what it printed
+fn gaia(args: &[String]) -> anyhow::Result<()> {
+    println!("$ gaia {}", args.join(" "));
+    let status = Command::new(env::current_exe()?).args(args).status()?;
+    eprint!("\nPress Enter to go back to gaia tui ");
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!("gaia {} failed", args.join(" "))),
+    }
+}
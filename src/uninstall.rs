@@ -0,0 +1,123 @@
+use crate::daemon;
+use crate::memory::GIB;
+use crate::paths;
+use crate::server;
+use crate::service;
+use crate::start;
+use crate::term;
+use anyhow::bail;
+use console::style;
+use serde_json::json;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// Bytes under the path, the links themselves rather than what they point to
+fn size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+// What gaia keeps on this machine, the files and directories it manages that exist, the models
+// directory left out with `keep_models`. Anything else in its directories is left alone.
+fn items(keep_models: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let models = paths::models_dir()?;
+    let mut items: Vec<PathBuf> = Vec::new();
+    for path in paths::managed()? {
+        if fs::symlink_metadata(&path).is_err() || (keep_models && path == models) {
+            continue;
+        }
+        // listed once, inside another or not
+        if items.iter().any(|item| path.starts_with(item)) {
+            continue;
+        }
+        items.retain(|item| !item.starts_with(&path));
+        items.push(path);
+    }
+
+    Ok(items)
+}
+
+// Stop everything, remove the service and, once confirmed, every file gaia keeps
+pub fn command_uninstall(keep_models: bool, yes: bool) -> anyhow::Result<()> {
+    let items = items(keep_models)?;
+    let unit = service::unit_path().ok().filter(|unit| unit.exists());
+    let running = daemon::call("ping", json!({}))?.is_some() || !server::load_all()?.is_empty();
+    if items.is_empty() && unit.is_none() && !running {
+        println!("Nothing of gaia is left on this machine");
+        return Ok(());
+    }
+
+    println!("{}", style("gaia uninstall removes:").bold());
+    if running {
+        println!("  the daemon and the services it runs, stopped first");
+    }
+    if let Some(unit) = &unit {
+        println!("  {:>10}  {}", "service", unit.display());
+    }
+    let mut total = 0;
+    for item in &items {
+        let bytes = size(item);
+        total += bytes;
+        println!("  {:>6.1} GiB  {}", bytes as f64 / GIB, item.display());
+    }
+    if keep_models {
+        println!(
+            "{}",
+            style(format!(
+                "Keeping the models in {}",
+                paths::models_dir()?.display()
+            ))
+            .dim()
+        );
+    }
+    if !yes && !term::confirm(&format!("Remove {:.1} GiB?", total as f64 / GIB), false)? {
+        return Ok(());
+    }
+
+    if running {
+        start::command_stop()?;
+    }
+    if service::uninstall()? {
+        println!("Removed the service");
+    }
+    let mut failed = 0;
+    for item in &items {
+        let removed = match item.is_dir() && !item.is_symlink() {
+            true => fs::remove_dir_all(item),
+            false => fs::remove_file(item),
+        };
+        if let Err(e) = removed {
+            eprintln!("{} {}: {}", style("Error:").red(), item.display(), e);
+            failed += 1;
+        }
+    }
+    // the directories themselves, once empty
+    let dirs = paths::dirs()?;
+    for dir in [dirs.runtime, dirs.config, dirs.cache, dirs.data, dirs.state] {
+        let _ = fs::remove_dir(dir);
+    }
+    if failed > 0 {
+        bail!("{} items could not be removed", failed);
+    }
+
+    println!("Removed {:.1} GiB", total as f64 / GIB);
+    if let Ok(exe) = env::current_exe() {
+        println!("Remove gaia itself with `rm {}`", exe.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use crate::events;
+use crate::server::{self, ModelKind, ServiceState};
+use anyhow::{anyhow, bail};
+use clap::Args;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+// Set once the daemon is asked to stop, so the services it stops are not brought back
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Args)]
+pub struct WatchdogArgs {
+    #[arg(
+        long = "health-interval",
+        help = "Seconds between health checks of the services, 0 turns the watchdog off",
+        value_name = "SECS",
+        default_value_t = 30
+    )]
+    pub interval: u64,
+    #[arg(
+        long = "health-failures",
+        help = "Failed health checks in a row before a service is restarted",
+        value_name = "N",
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub failures: u32,
+    #[arg(
+        long = "alert-webhook",
+        env = "GAIA_ALERT_WEBHOOK",
+        help = "URL to POST a JSON alert to when a service fails",
+        value_name = "URL"
+    )]
+    pub webhook: Option<String>,
+    #[arg(
+        long = "alert-command",
+        env = "GAIA_ALERT_COMMAND",
+        help = "Shell command run with the JSON alert on stdin and a one-line summary in $GAIA_ALERT_SUBJECT when a service fails, e.g. to send an email",
+        value_name = "COMMAND"
+    )]
+    pub command: Option<String>,
+}
+impl WatchdogArgs {
+    // The flags to hand on to the detached daemon
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--health-interval={}", self.interval),
+            format!("--health-failures={}", self.failures),
+        ];
+        if let Some(webhook) = &self.webhook {
+            args.push(format!("--alert-webhook={}", webhook));
+        }
+        if let Some(command) = &self.command {
+            args.push(format!("--alert-command={}", command));
+        }
+        args
+    }
+}
+
+// Keep the watchdog from restarting the services the daemon is stopping
+pub fn stop() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+// Check the services in the background, restarting those that stop answering
+pub fn spawn(args: WatchdogArgs) {
+    if args.interval == 0 {
+        return;
+    }
+    tracing::info!(
+        interval = args.interval,
+        failures = args.failures,
+        "watching the services"
+    );
+    thread::spawn(move || {
+        let interval = Duration::from_secs(args.interval);
+        let mut watchdog = Watchdog {
+            args,
+            failures: HashMap::new(),
+            seen_up: HashSet::new(),
+        };
+        loop {
+            thread::sleep(interval);
+            if STOPPING.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Err(e) = watchdog.check() {
+                tracing::warn!("health check failed: {:#}", e);
+            }
+        }
+    });
+}
+
+struct Watchdog {
+    args: WatchdogArgs,
+    // failed checks in a row, by service
+    failures: HashMap<String, u32>,
+    // pids of the services seen answering, only those are restarted, not ones still loading or
+    // that never came up
+    seen_up: HashSet<u32>,
+}
+impl Watchdog {
+    fn check(&mut self) -> anyhow::Result<()> {
+        for state in server::recorded()? {
+            if STOPPING.load(Ordering::SeqCst) {
+                break;
+            }
+            let running = server::is_running(state.pid);
+            if running && healthy(&state) {
+                self.seen_up.insert(state.pid);
+                self.failures.remove(&state.name);
+                continue;
+            }
+            if !self.seen_up.contains(&state.pid) {
+                continue;
+            }
+
+            let failures = self.failures.entry(state.name.clone()).or_default();
+            *failures += 1;
+            // a process that died does not come back by itself
+            if running && *failures < self.args.failures {
+                tracing::warn!(service = %state.name, failures = *failures, "not answering");
+                continue;
+            }
+            let failures = *failures;
+            self.failures.remove(&state.name);
+            self.seen_up.remove(&state.pid);
+            self.recover(&state, running, failures);
+        }
+
+        Ok(())
+    }
+
+    // Restart the failed service and tell the alert channels how it went
+    fn recover(&mut self, state: &ServiceState, running: bool, failures: u32) {
+        let reason = match running {
+            true => format!("did not answer {} health checks in a row", failures),
+            false => "exited".to_string(),
+        };
+        tracing::error!(service = %state.name, pid = state.pid, reason, "restarting");
+        let _span = tracing::info_span!("watchdog.restart", service = %state.name).entered();
+        println!(
+            "{} (pid {}) {}, restarting it",
+            state.name, state.pid, reason
+        );
+        let log = server::log_tail(&state.log, 2048);
+
+        let restarted = server::restart(state).and_then(|restarted| {
+            if !audio_only(&restarted) {
+                server::wait_ready(
+                    &restarted,
+                    Duration::from_secs(server::DEFAULT_STARTUP_TIMEOUT),
+                )?;
+            }
+            Ok(restarted)
+        });
+        let mut alert = json!({
+            "event": "service_unhealthy",
+            "service": state.name,
+            "pid": state.pid,
+            "port": state.port,
+            "reason": reason,
+            "failures": failures,
+            "log": log,
+        });
+        let subject = match &restarted {
+            Ok(restarted) => {
+                self.seen_up.insert(restarted.pid);
+                alert["restarted"] = json!(true);
+                alert["new_pid"] = json!(restarted.pid);
+                println!("Restarted {}, pid {}", state.name, restarted.pid);
+                format!("gaia: {} {}, restarted it", state.name, reason)
+            }
+            Err(e) => {
+                tracing::error!(service = %state.name, "failed to restart: {:#}", e);
+                alert["restarted"] = json!(false);
+                alert["error"] = json!(format!("{:#}", e));
+                format!("gaia: {} {} and could not be restarted", state.name, reason)
+            }
+        };
+        events::emit(
+            "crashed",
+            json!({
+                "service": state.name,
+                "pid": state.pid,
+                "reason": reason,
+                "restarted": restarted.is_ok(),
+            }),
+        );
+        self.alert(&subject, &alert);
+    }
+
+    fn alert(&self, subject: &str, alert: &Value) {
+        if let Some(url) = &self.args.webhook {
+            let sent = reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .and_then(|http| http.post(url).json(alert).send())
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = sent {
+                tracing::warn!(url, "failed to send the alert: {}", e);
+            }
+        }
+        if let Some(command) = &self.args.command {
+            if let Err(e) = run(command, subject, alert) {
+                tracing::warn!(command, "failed to run the alert command: {:#}", e);
+            }
+        }
+    }
+}
+
+// Whisper servers have no health endpoint, they are only checked for being alive
+pub fn audio_only(state: &ServiceState) -> bool {
+    state
+        .models
+        .iter()
+        .all(|model| model.kind == ModelKind::Audio)
+}
+
+fn healthy(state: &ServiceState) -> bool {
+    audio_only(state) || server::probe(&format!("http://localhost:{}/v1", state.port))
+}
+
+fn run(command: &str, subject: &str, alert: &Value) -> anyhow::Result<()> {
+    let mut shell = match cfg!(windows) {
+        true => {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        }
+        false => {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        }
+    };
+    let mut child = shell
+        .arg(command)
+        .env("GAIA_ALERT_SUBJECT", subject)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or(anyhow!("No stdin to write the alert to"))?
+        .write_all(serde_json::to_string_pretty(alert)?.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("it exited with {}", status);
+    }
+
+    Ok(())
+}
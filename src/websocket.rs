@@ -0,0 +1,303 @@
+use crate::gateway::{self, Gateway, Request, Unrouted};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+// Path of the WebSocket route of the gateway. Each text message sent to it is an OpenAI chat
+// request, answered by messages of a `type`:
+//
+//   {"type": "start", "model": ...}
+//   {"type": "token", "content": ...} for each piece of the reply
+//   {"type": "done", "finish_reason": ..., "usage": ...}, `cancelled` as the reason when cut
+//   {"type": "error", "message": ...}
+//
+// {"type": "cancel"} stops the reply being generated. An `id` sent with a request comes back
+// in the messages answering it.
+pub const PATH: &str = "/v1/ws";
+
+// Appended to the key of the client to accept it, from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Largest message read, as large as a request to the gateway
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+pub fn is_route(request: &Request) -> bool {
+    let path = request.path.split('?').next().unwrap_or_default();
+    request.method == "GET" && path.trim_end_matches('/') == PATH
+}
+
+// What the client sent, as the reading thread passes it on
+enum Incoming {
+    Text(String),
+    Closed,
+}
+
+// Messages to the client, written by the reading thread too to answer pings
+#[derive(Clone)]
+struct Sender(Arc<Mutex<TcpStream>>);
+impl Sender {
+    fn frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend(payload);
+        let mut stream = self.0.lock().unwrap();
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+
+    fn send(&self, id: &Value, kind: &str, fields: Value) -> io::Result<()> {
+        let mut message = json!({ "type": kind });
+        if !id.is_null() {
+            message["id"] = id.clone();
+        }
+        for (key, value) in fields.as_object().into_iter().flatten() {
+            message[key] = value.clone();
+        }
+        self.frame(TEXT, message.to_string().as_bytes())
+    }
+}
+
+// Upgrade the connection and answer the chat requests sent over it until the client leaves
+pub fn answer(gateway: &Gateway, request: &Request, mut client: TcpStream) -> anyhow::Result<()> {
+    let upgrade = request.header("upgrade").unwrap_or_default();
+    let Some(key) = request.header("sec-websocket-key") else {
+        gateway::respond_error(&mut client, 400, "expected a WebSocket upgrade")?;
+        return Ok(());
+    };
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        gateway::respond_error(&mut client, 400, "expected a WebSocket upgrade")?;
+        return Ok(());
+    }
+    let accept = STANDARD.encode(openssl::sha::sha1(
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    ));
+    write!(
+        client,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept
+    )?;
+
+    let sender = Sender(Arc::new(Mutex::new(client.try_clone()?)));
+    let (incoming, messages) = mpsc::channel();
+    {
+        let sender = sender.clone();
+        let reader = client.try_clone()?;
+        thread::spawn(move || {
+            if let Err(e) = read_messages(reader, &sender, |message| incoming.send(message).is_ok())
+            {
+                tracing::debug!("WebSocket closed: {}", e);
+            }
+            let _ = incoming.send(Incoming::Closed);
+        });
+    }
+
+    while let Ok(Incoming::Text(text)) = messages.recv() {
+        if !reply(gateway, &sender, &messages, &text)? {
+            break;
+        }
+    }
+    let _ = client.shutdown(Shutdown::Both);
+
+    Ok(())
+}
+
+// Read the frames of the client, answering pings and putting fragmented messages together,
+// until it closes the connection or `pass` is refused
+fn read_messages(
+    mut reader: TcpStream,
+    sender: &Sender,
+    mut pass: impl FnMut(Incoming) -> bool,
+) -> io::Result<()> {
+    let mut message = Vec::new();
+    loop {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0; 8];
+                reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        if message.len() + len > MAX_MESSAGE {
+            sender.frame(CLOSE, &1009u16.to_be_bytes())?;
+            return Ok(());
+        }
+        let mut mask = [0; 4];
+        if masked {
+            reader.read_exact(&mut mask)?;
+        }
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+
+        match opcode {
+            PING => sender.frame(PONG, &payload)?,
+            PONG => {}
+            CLOSE => {
+                sender.frame(CLOSE, &payload)?;
+                return Ok(());
+            }
+            // text, binary or a continuation of one
+            _ => {
+                message.extend(payload);
+                if fin {
+                    let text = String::from_utf8_lossy(&message).into_owned();
+                    message.clear();
+                    if !pass(Incoming::Text(text)) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Answer a message of the client, false once it closed the connection
+fn reply(
+    gateway: &Gateway,
+    sender: &Sender,
+    messages: &Receiver<Incoming>,
+    text: &str,
+) -> anyhow::Result<bool> {
+    let mut body = match serde_json::from_str::<Value>(text) {
+        Ok(body @ Value::Object(_)) => body,
+        Ok(_) => {
+            sender.send(
+                &Value::Null,
+                "error",
+                json!({ "message": "expected a JSON object" }),
+            )?;
+            return Ok(true);
+        }
+        Err(e) => {
+            sender.send(&Value::Null, "error", json!({ "message": e.to_string() }))?;
+            return Ok(true);
+        }
+    };
+    let id = body["id"].clone();
+    match body["type"].as_str() {
+        None | Some("chat") => {}
+        // nothing is being generated
+        Some("cancel") => return Ok(true),
+        Some(kind) => {
+            sender.send(
+                &id,
+                "error",
+                json!({ "message": format!("unknown message type '{}'", kind) }),
+            )?;
+            return Ok(true);
+        }
+    }
+    if let Some(fields) = body.as_object_mut() {
+        fields.remove("type");
+        fields.remove("id");
+    }
+    body["stream"] = json!(true);
+    body["stream_options"] = json!({ "include_usage": true });
+
+    let upstream = match gateway.send("chat/completions", &body) {
+        Ok(upstream) => upstream,
+        Err(Unrouted(_, message)) => {
+            sender.send(&id, "error", json!({ "message": message }))?;
+            return Ok(true);
+        }
+    };
+    let mut started = false;
+    let mut finish_reason = Value::Null;
+    let mut usage = Value::Null;
+    for line in BufReader::new(upstream.body).lines() {
+        // dropping the response closes the connection, which stops the generation
+        loop {
+            match messages.try_recv() {
+                Ok(Incoming::Text(text)) => {
+                    let cancels = serde_json::from_str::<Value>(&text)
+                        .is_ok_and(|message| message["type"] == "cancel");
+                    if cancels {
+                        sender.send(
+                            &id,
+                            "done",
+                            json!({ "finish_reason": "cancelled", "usage": usage }),
+                        )?;
+                        return Ok(true);
+                    }
+                    sender.send(
+                        &id,
+                        "error",
+                        json!({ "message": "a reply is being generated, cancel it first" }),
+                    )?;
+                }
+                Ok(Incoming::Closed) | Err(TryRecvError::Disconnected) => return Ok(false),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        let line = line?;
+        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+            continue;
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let chunk = serde_json::from_str::<Value>(data)?;
+        if !started {
+            started = true;
+            sender.send(&id, "start", json!({ "model": chunk["model"] }))?;
+        }
+        if !chunk["usage"].is_null() {
+            usage = chunk["usage"].clone();
+        }
+        for choice in chunk["choices"].as_array().into_iter().flatten() {
+            if !choice["finish_reason"].is_null() {
+                finish_reason = choice["finish_reason"].clone();
+            }
+            if let Some(content) = choice["delta"]["content"].as_str() {
+                if !content.is_empty() {
+                    sender.send(&id, "token", json!({ "content": content }))?;
+                }
+            }
+        }
+    }
+    sender.send(
+        &id,
+        "done",
+        json!({ "finish_reason": finish_reason, "usage": usage }),
+    )?;
+
+    Ok(true)
+}